@@ -0,0 +1,103 @@
+//! Ids passengers and vehicles are known by in the analytics and everything it writes. Each
+//! controller numbers its own vehicles from 0, DRT buses by agent and fixed route buses by trip,
+//! so a mixed run or one switching controllers would have two vehicles under every id. Vehicle ids
+//! are moved into a range of their own for their fleet as the controllers publish them:
+//!
+//! | Fleet       | Analytics id          |
+//! |-------------|-----------------------|
+//! | DRT         | agent id              |
+//! | Fixed route | 1000000 + trip id     |
+//!
+//! Passengers are already numbered across the whole run, the controllers carry on from the same
+//! count when they switch or hand passengers over, so their ids are left as they are. Each run
+//! (including restarts) is written to files of its own, see `Analytics::start_new_run`.
+
+use crate::simulation::escalation::ServiceMode;
+
+use super::{AnalyticsPackage, PassengerAnalyticsEvent, VehicleAnalyticsEvent};
+
+// Vehicles each fleet can number before running into the next fleet's ids
+pub const FLEET_RANGE: u32 = 1_000_000;
+
+// Analytics id of vehicle `id` as numbered by the controller running `fleet`
+pub fn vehicle_id(fleet: ServiceMode, id: u32) -> u32 {
+    match fleet {
+        ServiceMode::Drt => id,
+        ServiceMode::FixedRoute => FLEET_RANGE + id,
+    }
+}
+
+// Fleet of a vehicle from its analytics id, and the id its controller knows it by
+pub fn vehicle_fleet(id: u32) -> (ServiceMode, u32) {
+    if id < FLEET_RANGE {
+        (ServiceMode::Drt, id)
+    } else {
+        (ServiceMode::FixedRoute, id - FLEET_RANGE)
+    }
+}
+
+impl AnalyticsPackage {
+    // The same event with its vehicle ids given as analytics ids, published by the controller
+    // running `fleet`
+    pub fn in_fleet(self, fleet: ServiceMode) -> Self {
+        let vehicle = |id: u32| vehicle_id(fleet, id);
+        match self {
+            AnalyticsPackage::PassengerEvent(event) => AnalyticsPackage::PassengerEvent(match event {
+                PassengerAnalyticsEvent::PickedUp { id, vehicle: picked_up_by, time } => PassengerAnalyticsEvent::PickedUp { id, vehicle: vehicle(picked_up_by), time },
+                PassengerAnalyticsEvent::Transferred { id, from, to, pos, time } => PassengerAnalyticsEvent::Transferred { id, from: vehicle(from), to: vehicle(to), pos, time },
+                PassengerAnalyticsEvent::BundleBoarded { bundle, vehicle: boarded_onto, boarded, pos, time } => {
+                    PassengerAnalyticsEvent::BundleBoarded { bundle, vehicle: vehicle(boarded_onto), boarded, pos, time }
+                }
+                event => event,
+            }),
+            AnalyticsPackage::VehicleEvent(mut event) => {
+                let id = match &mut event {
+                    VehicleAnalyticsEvent::MovementTick { id, .. }
+                    | VehicleAnalyticsEvent::PassengerPickup { id, .. }
+                    | VehicleAnalyticsEvent::PassengerDropoff { id, .. }
+                    | VehicleAnalyticsEvent::PassengerDeniedBoarding { id, .. }
+                    | VehicleAnalyticsEvent::LeftDepot { id, .. }
+                    | VehicleAnalyticsEvent::Parked { id, .. }
+                    | VehicleAnalyticsEvent::EdgeEntered { id, .. }
+                    | VehicleAnalyticsEvent::Repositioned { id, .. }
+                    | VehicleAnalyticsEvent::PickupAfterRepositioning { id, .. }
+                    | VehicleAnalyticsEvent::Planned { id, .. } => id,
+                };
+                *id = vehicle(*id);
+                AnalyticsPackage::VehicleEvent(event)
+            }
+            event => event,
+        }
+    }
+}
+
+// How a fleet is written in the outputs
+pub fn fleet_name(fleet: ServiceMode) -> &'static str {
+    match fleet {
+        ServiceMode::Drt => "DRT",
+        ServiceMode::FixedRoute => "Fixed Route",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vehicles_of_each_fleet_get_their_own_ids() {
+        assert_eq!(vehicle_id(ServiceMode::Drt, 5), 5);
+        assert_ne!(vehicle_id(ServiceMode::Drt, 5), vehicle_id(ServiceMode::FixedRoute, 5));
+        assert_eq!(vehicle_fleet(vehicle_id(ServiceMode::FixedRoute, 5)), (ServiceMode::FixedRoute, 5));
+        assert_eq!(vehicle_fleet(5), (ServiceMode::Drt, 5));
+
+        let picked_up = AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: 3, vehicle: 5, time: chrono::Utc::now() });
+        match picked_up.in_fleet(ServiceMode::FixedRoute) {
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id, vehicle, .. }) => assert_eq!((id, vehicle), (3, FLEET_RANGE + 5)),
+            _ => panic!("Event changed kind"),
+        }
+        match AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerPickup { id: 5, passenger_id: 3 }).in_fleet(ServiceMode::FixedRoute) {
+            AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerPickup { id, passenger_id }) => assert_eq!((id, passenger_id), (FLEET_RANGE + 5, 3)),
+            _ => panic!("Event changed kind"),
+        }
+    }
+}
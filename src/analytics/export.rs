@@ -0,0 +1,127 @@
+//! Writing the per-passenger and per-vehicle results in formats other than CSV, for loading
+//! into notebooks and dataframes without going through the analytics window.
+//!
+//! Every table is integer columns, with `None` for values an entity doesn't have (e.g. the
+//! decision latency of a passenger who never used DRT).
+
+use std::{fs, io::Write, path::Path};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    Parquet, // Only written when built with the `parquet` feature
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+pub struct Table {
+    pub columns: Vec<(&'static str, &'static str)>, // CSV header and the field name used by the other formats
+    pub rows: Vec<Vec<Option<i64>>>,
+}
+
+impl Table {
+    pub fn new(columns: Vec<(&'static str, &'static str)>) -> Self {
+        Table { columns, rows: Vec::new() }
+    }
+
+    pub fn push(&mut self, row: Vec<Option<i64>>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    pub fn write(&self, path: &Path, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+        match format {
+            OutputFormat::Csv => self.write_csv(path),
+            OutputFormat::Jsonl => self.write_jsonl(path),
+            OutputFormat::Parquet => self.write_parquet(path),
+        }
+    }
+
+    fn write_csv(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", self.columns.iter().map(|(header, _)| *header).collect::<Vec<_>>().join(","))?;
+        for row in &self.rows {
+            let values: Vec<String> = row.iter().map(|value| value.map(|v| v.to_string()).unwrap_or_default()).collect();
+            writeln!(file, "{}", values.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write_jsonl(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = fs::File::create(path)?;
+        for row in &self.rows {
+            let object: serde_json::Map<String, serde_json::Value> =
+                self.columns.iter().zip(row).map(|((_, field), value)| (field.to_string(), (*value).into())).collect();
+            writeln!(file, "{}", serde_json::Value::Object(object))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    fn write_parquet(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::Arc;
+
+        use parquet::{
+            data_type::Int64Type,
+            file::{properties::WriterProperties, writer::SerializedFileWriter},
+            schema::parser::parse_message_type,
+        };
+
+        let fields: String = self.columns.iter().map(|(_, field)| format!("OPTIONAL INT64 {};", field)).collect();
+        let schema = Arc::new(parse_message_type(&format!("message table {{ {} }}", fields))?);
+        let mut writer = SerializedFileWriter::new(fs::File::create(path)?, schema, Arc::new(WriterProperties::builder().build()))?;
+
+        // Everything goes in one row group, the tables are at most one row per passenger
+        let mut row_group = writer.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group.next_column()? {
+            let cells = self.rows.iter().map(|row| row[column]);
+            let values: Vec<i64> = cells.clone().flatten().collect();
+            let definitions: Vec<i16> = cells.map(|value| value.is_some() as i16).collect();
+            column_writer.typed::<Int64Type>().write_batch(&values, Some(&definitions), None)?;
+            column_writer.close()?;
+            column += 1;
+        }
+        row_group.close()?;
+        writer.close()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    fn write_parquet(&self, _path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Err("odbrs was built without the `parquet` feature".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jsonl_writes_missing_values_as_null() {
+        let mut table = Table::new(vec![("Passenger ID", "passenger_id"), ("Decision Latency", "decision_latency")]);
+        table.push(vec![Some(1), Some(4)]);
+        table.push(vec![Some(2), None]);
+
+        let path = std::env::temp_dir().join("odbrs-export-test.jsonl");
+        table.write(&path, OutputFormat::Jsonl).unwrap();
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines[0]["decision_latency"], 4);
+        assert!(lines[1]["decision_latency"].is_null());
+    }
+}
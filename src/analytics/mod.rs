@@ -1,11 +1,30 @@
-use std::{sync::mpsc::{Sender, Receiver}, collections::HashMap, io::Write, fs};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{RecvTimeoutError, Sender}, Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use eframe::NativeOptions;
+use chrono::{NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
 
-use crate::{Module, gui::analytics::{State, create_distributions}};
+use crate::{Module, event_bus::{DropPolicy, EventBus, Subscriber, Topic}, geometry::Crs, gui::{analytics::State, AppMessage}, simulation::{dyn_controller::{depots, time_windows::WindowViolation}, escalation::{Escalation, ServiceMode}, SimulationMessage, SimulationState}};
 
+use self::{export::{OutputFormat, Table}, plans::{PlannedRoute, DRIVEN_KEPT, PLANS_KEPT}, report::RunKpis, stop_rules::{StopRules, StopRulesConfig}, timeseries::Timeseries, zones::ZoneConfig};
+
+pub mod entity;
+pub mod export;
+pub mod plans;
+pub mod report;
+pub mod stop_rules;
+pub mod timeseries;
+pub mod zones;
+
+#[derive(Clone)]
 pub enum AnalyticsPackage {
-    None,
     PassengerEvent(PassengerAnalyticsEvent),
     VehicleEvent(VehicleAnalyticsEvent),
     SimulationEvent(SimulationAnalyticsEvent)
@@ -14,7 +33,6 @@ pub enum AnalyticsPackage {
 impl AnalyticsPackage {
     fn handle(&self, analytics: &mut Analytics) {
         match self {
-            AnalyticsPackage::None => {},
             AnalyticsPackage::PassengerEvent(event) =>  event.handle(analytics),
             AnalyticsPackage::VehicleEvent(event) => event.handle(analytics),
             AnalyticsPackage::SimulationEvent(event) => event.handle(analytics)
@@ -22,15 +40,54 @@ impl AnalyticsPackage {
     }
 }
 
+#[derive(Clone)]
 pub enum PassengerAnalyticsEvent {
     StartWalkingTick { id: u32 },
     EndWalkingTick { id: u32 },
     WaitingTick { id: u32, waiting_pos: (f64, f64) },
-    InTransitTick { id: u32 }
+    InTransitTick { id: u32 },
+    Requested { id: u32, time: chrono::DateTime<chrono::Utc>, origin: (f64, f64), destination: (f64, f64), desired_arrival: Option<chrono::DateTime<chrono::Utc>> },
+    PickedUp { id: u32, vehicle: u32, time: chrono::DateTime<chrono::Utc> },
+    DroppedOff { id: u32, time: chrono::DateTime<chrono::Utc> },
+    Assigned { id: u32, latency: i64 }, // First given to a vehicle, `latency` minutes after the request was made
+    RolledOver { id: u32, stop: u32, next_trip: Option<u32>, time: chrono::DateTime<chrono::Utc> }, // Left behind by a full bus
+    Transferred { id: u32, from: u32, to: u32, pos: (f64, f64), time: chrono::DateTime<chrono::Utc> }, // Dropped at `pos` by DRT bus `from` to change onto `to`
+    BundleBoarded { bundle: u32, vehicle: u32, boarded: u32, pos: (f64, f64), time: chrono::DateTime<chrono::Utc> }, // Members of a bundle getting on together at its shared pick up
+    WindowMissed { id: u32, violation: WindowViolation, time: chrono::DateTime<chrono::Utc> }, // A DRT passenger getting off outside their time window
+    Unserved { reason: UnservedReason, origin: (f64, f64), destination: (f64, f64) }, // Dropped before being given to a vehicle
+    Rejected { id: u32, reason: UnservedReason }, // A request turned down by the DRT rejection policy after being made
+}
+
+/// Why demand never completed its trip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum UnservedReason {
+    Policy, // Turned down by the demand policy before becoming a passenger
+    NoRoute, // No journey on the fixed route network, a gap in the service
+    Disconnected, // Origin and destination in parts of the road graph with no path between, a problem with the data
+    NotPickedUp, // Still waiting when the run ended
+    NotDroppedOff, // Still on board when the run ended
+    WaitedTooLong, // Rejected by the DRT rejection policy, no bus took it on in time
+    DetourTooLong, // Refused by the DRT rejection policy, the best ride on offer was too roundabout
+}
+
+impl UnservedReason {
+    pub fn description(&self) -> &str {
+        match self {
+            UnservedReason::Policy => "Rejected by demand policy",
+            UnservedReason::NoRoute => "No route found",
+            UnservedReason::Disconnected => "Not connected by road",
+            UnservedReason::NotPickedUp => "Never picked up",
+            UnservedReason::NotDroppedOff => "Not dropped off by the end",
+            UnservedReason::WaitedTooLong => "Rejected after waiting too long",
+            UnservedReason::DetourTooLong => "Refused for too long a detour",
+        }
+    }
 }
 
 impl PassengerAnalyticsEvent {
     fn handle(&self, analytics: &mut Analytics) {
+        analytics.timeseries.record_passenger(self);
+
         match self {
             PassengerAnalyticsEvent::WaitingTick { id, .. } => {
                 // println!("Analytics: Passenger {} is waiting at {:?}", id, waiting_pos);
@@ -45,19 +102,78 @@ impl PassengerAnalyticsEvent {
             },
             PassengerAnalyticsEvent::EndWalkingTick { id } => {
                 analytics.passenger_walking.entry(*id).and_modify(|e| e.1 += 1).or_insert((0, 1));
+            },
+            PassengerAnalyticsEvent::Requested { id, time, origin, destination, desired_arrival } => {
+                // Passengers moved between modes are requested again, the journey starts at the first
+                if !analytics.journeys.contains_key(id) {
+                    analytics.stop_rules.requested(1);
+                }
+                analytics.journeys.entry(*id).or_insert_with(|| Journey { requested: *time, origin: *origin, destination: *destination, desired_arrival: *desired_arrival, ..Default::default() });
+            },
+            PassengerAnalyticsEvent::PickedUp { id, vehicle, time } => {
+                if let Some(journey) = analytics.journeys.get_mut(id) {
+                    journey.picked_up.get_or_insert(*time);
+                    journey.vehicles.push(*vehicle);
+                }
+            },
+            PassengerAnalyticsEvent::DroppedOff { id, time } => {
+                // The last drop off ends the journey if there's a change of bus
+                if let Some(journey) = analytics.journeys.get_mut(id) {
+                    journey.dropped_off = Some(*time);
+                }
+            },
+            PassengerAnalyticsEvent::Assigned { id, latency } => {
+                analytics.passenger_decision.insert(*id, *latency);
+            },
+            PassengerAnalyticsEvent::RolledOver { stop, next_trip, time, .. } => {
+                let entry = analytics.roll_overs.entry((*stop, time.hour())).or_insert((0, 0));
+                entry.0 += 1;
+                if next_trip.is_none() {
+                    entry.1 += 1;
+                }
+            },
+            PassengerAnalyticsEvent::Transferred { id, from, to, pos, time } => {
+                analytics.transfers.push((*id, *time, *from, *to, *pos));
+            },
+            PassengerAnalyticsEvent::BundleBoarded { bundle, vehicle, boarded, pos, time } => {
+                analytics.bundle_boardings.push((*bundle, *time, *vehicle, *boarded, *pos));
+            },
+            PassengerAnalyticsEvent::WindowMissed { id, violation, time } => {
+                analytics.window_misses.push((*id, *time, *violation));
+            },
+            PassengerAnalyticsEvent::Rejected { id, reason } => {
+                if let Some(journey) = analytics.journeys.get_mut(id) {
+                    journey.rejected = Some(*reason);
+                }
+            },
+            PassengerAnalyticsEvent::Unserved { reason, origin, destination } => {
+                *analytics.unserved.entry(*reason).or_insert(0) += 1;
+                let areas = (analytics.area_of(*origin), analytics.area_of(*destination));
+                *analytics.unreachable.entry((areas.0, areas.1, *reason)).or_insert(0) += 1;
             }
         }
     }
 }
 
+#[derive(Clone)]
 pub enum VehicleAnalyticsEvent {
     MovementTick { id: u32, pos: (f64, f64) },
     PassengerPickup { id: u32, passenger_id: u32 },
-    PassengerDropoff { id: u32, passenger_id: u32 }
+    PassengerDropoff { id: u32, passenger_id: u32 },
+    PassengerDeniedBoarding { id: u32, passenger_id: u32, stop: u32 }, // Left at the stop because the vehicle was full
+    LeftDepot { id: u32, depot: u32, time: chrono::DateTime<chrono::Utc> }, // Starting its shift
+    Parked { id: u32, depot: u32, time: chrono::DateTime<chrono::Utc> }, // Back at its depot after its shift
+    EdgeEntered { id: u32, edge: u128, mode: ServiceMode }, // Driving onto the next road of its route
+    Repositioned { id: u32, node: u128, time: chrono::DateTime<chrono::Utc> }, // Empty, sent towards forecast demand
+    PickupAfterRepositioning { id: u32, passenger_id: u32 }, // First passenger picked up since it was repositioned
+    Planned { id: u32, passenger_id: u32, route: Vec<u128>, time: chrono::DateTime<chrono::Utc> }, // Roads planned when first given a passenger, see `plans`
 }
 
 impl VehicleAnalyticsEvent {
     fn handle(&self, analytics: &mut Analytics) {
+        analytics.metrics.record(self, analytics.tick_times.len() as u32);
+        analytics.timeseries.record_vehicle(self);
+
         match self {
             VehicleAnalyticsEvent::MovementTick { id, .. } => {
                 // println!("Analytics: Vehicle {} is at {:?}", id, pos);
@@ -71,62 +187,402 @@ impl VehicleAnalyticsEvent {
                 // println!("Analytics: Vehicle {} dropped off passenger {}", id, passenger_id);
                 analytics.vehicle_passengers.entry(*id).and_modify(|e| e.1 += 1).or_insert((0, 1));
             }
+            VehicleAnalyticsEvent::PassengerDeniedBoarding { id, .. } => {
+                *analytics.vehicle_denied.entry(*id).or_insert(0) += 1;
+            }
+            VehicleAnalyticsEvent::LeftDepot { id, depot, time } => {
+                analytics.depot_events.push((*depot, *time, *id, false));
+            }
+            VehicleAnalyticsEvent::Parked { id, depot, time } => {
+                analytics.depot_events.push((*depot, *time, *id, true));
+            }
+            VehicleAnalyticsEvent::EdgeEntered { edge, mode, .. } => {
+                let usage = analytics.edge_usage.entry(*edge).or_insert((0, 0));
+                match mode {
+                    ServiceMode::Drt => usage.0 += 1,
+                    ServiceMode::FixedRoute => usage.1 += 1,
+                }
+            }
+            VehicleAnalyticsEvent::Repositioned { id, node, time } => {
+                analytics.repositions.push((*id, *time, *node));
+            }
+            VehicleAnalyticsEvent::PickupAfterRepositioning { passenger_id, .. } => {
+                analytics.after_repositioning.insert(*passenger_id);
+            }
+            VehicleAnalyticsEvent::Planned { .. } => {}
         }
     }
 }
 
+/// One passenger's trip from making the request to being dropped off
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Journey {
+    pub requested: chrono::DateTime<chrono::Utc>,
+    pub origin: (f64, f64),
+    pub destination: (f64, f64),
+    pub picked_up: Option<chrono::DateTime<chrono::Utc>>, // First pick up
+    pub dropped_off: Option<chrono::DateTime<chrono::Utc>>, // Last drop off
+    pub vehicles: Vec<u32>, // Every vehicle ridden, in order
+    pub desired_arrival: Option<chrono::DateTime<chrono::Utc>>, // When they wanted to get there, if they said
+    pub rejected: Option<UnservedReason>, // Why the request was turned down, if it was
+}
+
+/// Running totals for one vehicle, kept up to date during the run so the GUI can show them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VehicleMetrics {
+    pub pickups: u64,
+    pub dropoffs: u64,
+    pub distance: f64, // Metres travelled
+    pub empty_distance: f64, // Metres travelled with nobody on board
+    pub occupancy: Vec<(u32, i64)>, // Passengers on board after each pick up or drop off, with the tick it happened
+    pub moving_ticks: u64, // Ticks the vehicle moved in
+    pub onboard_ticks: u64, // Passengers on board summed over the ticks the vehicle moved in
+    pub denied_boardings: u64, // Passengers turned away because the vehicle was full
+    pub driven: Vec<u128>, // Roads entered since the oldest plan kept, in order
+    pub plans: Vec<PlannedRoute>, // The latest routes planned for new passengers, oldest first
+    last_pos: Option<(f64, f64)>,
+}
+
+impl VehicleMetrics {
+    fn onboard(&self) -> i64 {
+        self.pickups as i64 - self.dropoffs as i64
+    }
+
+    // Passengers on board on average while the vehicle was moving
+    pub fn average_occupancy(&self) -> f64 {
+        if self.moving_ticks == 0 {
+            0.0
+        } else {
+            self.onboard_ticks as f64 / self.moving_ticks as f64
+        }
+    }
+
+    pub fn peak_occupancy(&self) -> i64 {
+        self.occupancy.iter().map(|(_, count)| *count).max().unwrap_or(0)
+    }
+
+    // Drop roads no kept plan is compared against, at most `DRIVEN_KEPT` left, moving the plans'
+    // starts back to match
+    fn forget_old_roads(&mut self) {
+        let oldest = self.plans.first().map_or(self.driven.len(), |plan| plan.driven_before);
+        let cut = oldest.max(self.driven.len().saturating_sub(DRIVEN_KEPT));
+        if cut == 0 {
+            return;
+        }
+        self.driven.drain(..cut);
+        self.plans.retain(|plan| plan.driven_before >= cut);
+        self.plans.iter_mut().for_each(|plan| plan.driven_before -= cut);
+    }
+
+    fn record(&mut self, event: &VehicleAnalyticsEvent, tick: u32, crs: Crs) {
+        match event {
+            VehicleAnalyticsEvent::MovementTick { pos, .. } => {
+                if let Some(last) = self.last_pos {
+                    let moved = crs.distance(*pos, last);
+                    self.distance += moved;
+                    if self.onboard() == 0 {
+                        self.empty_distance += moved;
+                    }
+                }
+                self.last_pos = Some(*pos);
+                self.moving_ticks += 1;
+                self.onboard_ticks += self.onboard().max(0) as u64;
+            }
+            VehicleAnalyticsEvent::PassengerPickup { .. } => {
+                self.pickups += 1;
+                self.occupancy.push((tick, self.onboard()));
+            }
+            VehicleAnalyticsEvent::PassengerDropoff { .. } => {
+                self.dropoffs += 1;
+                self.occupancy.push((tick, self.onboard()));
+            }
+            VehicleAnalyticsEvent::PassengerDeniedBoarding { .. } => self.denied_boardings += 1,
+            VehicleAnalyticsEvent::EdgeEntered { edge, .. } => {
+                self.driven.push(*edge);
+                self.forget_old_roads();
+            }
+            VehicleAnalyticsEvent::Planned { passenger_id, route, time, .. } => {
+                if self.plans.len() == PLANS_KEPT {
+                    self.plans.remove(0);
+                }
+                self.plans.push(PlannedRoute { passenger_id: *passenger_id, time: *time, edges: route.clone(), driven_before: self.driven.len() });
+                self.forget_old_roads();
+            }
+            VehicleAnalyticsEvent::LeftDepot { .. }
+            | VehicleAnalyticsEvent::Parked { .. }
+            | VehicleAnalyticsEvent::Repositioned { .. }
+            | VehicleAnalyticsEvent::PickupAfterRepositioning { .. } => {}
+        }
+    }
+}
+
+/// A passenger getting on or off a vehicle, kept for a moment so the map can flash where it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopEvent {
+    pub pos: (f64, f64),
+    pub boarding: bool, // Pick up if true, drop off if false
+    pub at: Instant, // Wall clock time the event arrived
+}
+
+// How long stop events are kept for the map
+pub const STOP_EVENT_LIFETIME: Duration = Duration::from_millis(1500);
+
+/// Read access to the per-entity metrics while the analytics run on their own thread
+#[derive(Clone, Default)]
+pub struct MetricsQuery {
+    vehicles: Arc<RwLock<HashMap<u32, VehicleMetrics>>>,
+    stop_events: Arc<RwLock<VecDeque<StopEvent>>>,
+    crs: Crs, // What the vehicle positions are in
+}
+
+impl MetricsQuery {
+    pub fn vehicle(&self, id: u32) -> Option<VehicleMetrics> {
+        self.vehicles.read().unwrap().get(&id).cloned()
+    }
+
+    // Every vehicle's metrics, in id order
+    pub fn all_vehicles(&self) -> Vec<(u32, VehicleMetrics)> {
+        let mut vehicles: Vec<_> = self.vehicles.read().unwrap().iter().map(|(id, metrics)| (*id, metrics.clone())).collect();
+        vehicles.sort_by_key(|(id, _)| *id);
+        vehicles
+    }
+
+    // Forget every vehicle, for a new run
+    fn clear(&self) {
+        self.vehicles.write().unwrap().clear();
+        self.stop_events.write().unwrap().clear();
+    }
+
+    // Pick ups and drop offs from the last STOP_EVENT_LIFETIME, oldest first
+    pub fn recent_stop_events(&self) -> Vec<StopEvent> {
+        self.stop_events.read().unwrap().iter().filter(|event| event.at.elapsed() < STOP_EVENT_LIFETIME).cloned().collect()
+    }
+
+    fn record(&self, event: &VehicleAnalyticsEvent, tick: u32) {
+        let id = match event {
+            VehicleAnalyticsEvent::MovementTick { id, .. }
+            | VehicleAnalyticsEvent::PassengerPickup { id, .. }
+            | VehicleAnalyticsEvent::PassengerDropoff { id, .. }
+            | VehicleAnalyticsEvent::PassengerDeniedBoarding { id, .. }
+            | VehicleAnalyticsEvent::LeftDepot { id, .. }
+            | VehicleAnalyticsEvent::Parked { id, .. }
+            | VehicleAnalyticsEvent::EdgeEntered { id, .. }
+            | VehicleAnalyticsEvent::Repositioned { id, .. }
+            | VehicleAnalyticsEvent::PickupAfterRepositioning { id, .. }
+            | VehicleAnalyticsEvent::Planned { id, .. } => *id,
+        };
+        let mut vehicles = self.vehicles.write().unwrap();
+        let metrics = vehicles.entry(id).or_default();
+        metrics.record(event, tick, self.crs);
+
+        // Passengers get on and off where the vehicle last moved to
+        let boarding = match event {
+            VehicleAnalyticsEvent::PassengerPickup { .. } => true,
+            VehicleAnalyticsEvent::PassengerDropoff { .. } => false,
+            _ => return,
+        };
+        if let Some(pos) = metrics.last_pos {
+            let mut stop_events = self.stop_events.write().unwrap();
+            while stop_events.front().map_or(false, |event| event.at.elapsed() >= STOP_EVENT_LIFETIME) {
+                stop_events.pop_front();
+            }
+            stop_events.push_back(StopEvent { pos, boarding, at: Instant::now() });
+        }
+    }
+}
+
+/// Parameters of a run, written next to its outputs so they can be told apart later
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RunMetadata {
+    pub static_only: bool,
+    #[serde(default)]
+    pub mixed: bool, // Both fleets ran, static_only is ignored
+    pub agent_count: usize,
+    pub demand_scale: f64,
+    pub seed: u64,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    #[serde(default = "default_tick_seconds")]
+    pub tick_seconds: u32, // Length of the ticks counted in the outputs, runs from before it was configurable used 60s
+    #[serde(default = "crate::simulation::walking::default_detour_factor")]
+    pub walk_detour_factor: f64, // Walks were this much longer than the straight line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_by_rule: Option<String>, // Stop rule which ended the run early, see `stop_rules`
+    #[serde(default)]
+    pub complete: bool, // Ran to the end time, false while running or if it was stopped part way
+}
+
+fn default_tick_seconds() -> u32 {
+    crate::simulation::DEFAULT_TICK_SECONDS
+}
+
+#[derive(Clone)]
 pub enum SimulationAnalyticsEvent {
-    TickTime { tick: u32, time: f64 }
+    RunStarted(RunMetadata),
+    TickTime { tick: u32, time: f64, sim_time: chrono::DateTime<chrono::Utc> }, // Processing time in seconds for the tick simulating `sim_time`
+    FleetChange { time: chrono::DateTime<chrono::Utc>, description: String },
+    Escalation { passenger_id: u32, escalation: Escalation },
+    DemandRejected { count: u64 }, // Demand the policy turned down during a tick
+    Critical { time: chrono::DateTime<chrono::Utc>, description: String }, // Something which points to a bug in the simulation
+    RunFinished { reached_end: bool }, // Reached the end time or was stopped, it may still be restarted
 }
 
 impl SimulationAnalyticsEvent {
     fn handle(&self, analytics: &mut Analytics) {
         match self {
-            SimulationAnalyticsEvent::TickTime { time, .. } => {
+            SimulationAnalyticsEvent::RunStarted(metadata) => {
+                // A restarted run gets its own outputs rather than adding to the last one's
+                if analytics.metadata.is_some() {
+                    analytics.start_new_run();
+                }
+                analytics.metadata = Some(metadata.clone());
+            },
+            SimulationAnalyticsEvent::TickTime { time, sim_time, .. } => {
                 // println!("Analytics: Tick {} took {} seconds", tick, time);
+                let tick_seconds = analytics.metadata.as_ref().map_or(crate::simulation::DEFAULT_TICK_SECONDS, |metadata| metadata.tick_seconds);
+                analytics.timeseries.end_tick(*sim_time, tick_seconds);
                 analytics.tick_times.push(*time);
                 analytics.avg_tick_time = analytics.tick_times.iter().sum::<f64>() / analytics.tick_times.len() as f64;
+                analytics.check_stop_rules();
+            },
+            SimulationAnalyticsEvent::FleetChange { time, description } => {
+                analytics.fleet_changes.push((time.to_rfc3339(), description.clone()));
+            },
+            SimulationAnalyticsEvent::Escalation { passenger_id, escalation } => {
+                analytics.escalations.push((*passenger_id, escalation.clone()));
+            },
+            SimulationAnalyticsEvent::DemandRejected { count } => {
+                *analytics.unserved.entry(UnservedReason::Policy).or_insert(0) += count;
+                analytics.stop_rules.rejected(*count);
+            },
+            SimulationAnalyticsEvent::Critical { time, description } => {
+                analytics.critical_events.push((time.to_rfc3339(), description.clone()));
+            }
+            SimulationAnalyticsEvent::RunFinished { reached_end } => {
+                // Written straight away so the outputs on disk are the whole run, marked as complete
+                if *reached_end {
+                    if let Some(metadata) = &mut analytics.metadata {
+                        metadata.complete = true;
+                    }
+                    // Only what's been handled so far, the loop this came from drains the rest
+                    analytics.write_files();
+                }
+                // For the GUI to compare against the other runs this session
+                if let (Some(gui), Some(metadata)) = (&analytics.gui, &analytics.metadata) {
+                    let kpis = analytics.kpis();
+                    gui.publish(AppMessage::RunFinished(Box::new((metadata.clone(), kpis.clone()))));
+                    if *reached_end {
+                        gui.publish(AppMessage::SimulationFinished(Box::new(kpis), analytics.output_dir.clone()));
+                    }
+                }
             }
         }
     }
 }
 
+const UNREACHABLE_SHOWN: usize = 5; // Pairs of areas listed in the summary, the output has them all
+
+// Whether demand with no route points to a fix in the data or in the service
+fn unreachable_cause(reason: UnservedReason) -> &'static str {
+    match reason {
+        UnservedReason::Disconnected => "data",
+        _ => "service gap",
+    }
+}
+
+const ANALYTICS_QUEUE: usize = 65536; // Events held before the simulation waits for the analytics to catch up
+
 pub struct Analytics {
-    tx: Sender<AnalyticsPackage>,
-    rx: Receiver<AnalyticsPackage>,
+    rx: Subscriber<AnalyticsPackage>, // Events published by the simulation and its agents
+
+    output_dir: PathBuf, // Where the CSVs are written
+    stamp: String, // Prefix of this run's output files, so flushes during the run replace the last one
+    flush_every: u32, // Ticks between writing the outputs while running live, 0 to only write at the end
+    format: OutputFormat, // Extra format the passenger and vehicle results are written in, next to the CSVs
+    zones: Vec<ZoneConfig>, // Areas the passenger KPIs are split between
+    crs: Crs, // What positions are in, the graph's
+    metrics: MetricsQuery, // Per-entity totals the GUI can look up during the run
+    metadata: Option<RunMetadata>, // Parameters of the run, sent by the simulation when it starts
+    stop_rules: StopRules, // Rules to end the run early by, checked every tick
+    sim_tx: Option<Sender<SimulationMessage>>, // Where to stop the simulation from, only given for headless runs
+    gui: Option<Topic<AppMessage>>, // Where finished runs' KPIs are sent for the session dashboard
 
     tick_times: Vec<f64>, // Ticks and the time it took to process them
     avg_tick_time: f64,
+    fleet_changes: Vec<(String, String)>, // Simulation time and description of fleet changes applied
+    critical_events: Vec<(String, String)>, // Simulation time and description of anything that looks like a bug
+    escalations: Vec<(u32, Escalation)>, // Passengers moved between fixed route and DRT
+    timeseries: Timeseries, // Network wide counts for every tick
+    roll_overs: HashMap<(u32, u32), (u32, u32)>, // Passengers left by a full bus at (stop, hour), and how many of those had no later trip on the route
+    transfers: Vec<(u32, chrono::DateTime<chrono::Utc>, u32, u32, (f64, f64))>, // Passengers changing DRT bus, when, from and to which bus and where
+    bundle_boardings: Vec<(u32, chrono::DateTime<chrono::Utc>, u32, u32, (f64, f64))>, // Bundles boarding, when, which bus, how many got on and where
+    window_misses: Vec<(u32, chrono::DateTime<chrono::Utc>, WindowViolation)>, // Passengers getting off outside their time window, when and how
+    depot_events: Vec<(u32, chrono::DateTime<chrono::Utc>, u32, bool)>, // Buses leaving (false) and parking at (true) a depot, when and which bus
+    repositions: Vec<(u32, chrono::DateTime<chrono::Utc>, u128)>, // DRT buses sent towards forecast demand, when and to which node
+    after_repositioning: HashSet<u32>, // Passengers who were the first a bus picked up after repositioning
 
     passenger_waits: HashMap<u32, u32>, // Ticks passenger (key) spent waiting
     passenger_travel: HashMap<u32, u32>, // Ticks passenger (key) spent in transit
     passenger_walking: HashMap<u32, (u64, u64)>, // Ticks passenger (key) spent walking from start, ticks spent walking to end
+    passenger_decision: HashMap<u32, i64>, // Minutes between passenger (key) making a request and it first being assigned to a vehicle
+    journeys: HashMap<u32, Journey>, // Timings and end points of each passenger's (key) trip
+    unserved: HashMap<UnservedReason, u64>, // Demand dropped before reaching a vehicle, by reason
+    unreachable: HashMap<(String, String, UnservedReason), u64>, // Demand with no route, by origin and destination area and why
     vehicle_travel: HashMap<u32, u32>, // Ticks vehicle (key) spent in transit
-    vehicle_passengers: HashMap<u32, (u64, u64)> // Number of passengers vehicle (key) picked up, dropped off
+    vehicle_passengers: HashMap<u32, (u64, u64)>, // Number of passengers vehicle (key) picked up, dropped off
+    vehicle_denied: HashMap<u32, u64>, // Passengers vehicle (key) left at stops because it was full
+    edge_usage: HashMap<u128, (u64, u64)>, // Times DRT and fixed route vehicles drove onto edge (key)
 
 }
 
 impl Default for Analytics {
     fn default() -> Self {
-        let (tx, rx) = std::sync::mpsc::channel::<AnalyticsPackage>();
+        // Replaced by a subscription to the simulation's bus in init
         Self {
-            rx,
-            tx,
+            rx: Topic::default().subscribe(1, DropPolicy::DropNewest),
+            output_dir: PathBuf::new(),
+            stamp: String::new(),
+            flush_every: 0,
+            format: OutputFormat::Csv,
+            zones: Vec::new(),
+            crs: Crs::default(),
+            metrics: MetricsQuery::default(),
+            metadata: None,
+            stop_rules: StopRules::default(),
+            sim_tx: None,
+            gui: None,
             tick_times: Vec::new(),
             avg_tick_time: 0.0,
+            fleet_changes: Vec::new(),
+            critical_events: Vec::new(),
+            escalations: Vec::new(),
+            timeseries: Timeseries::default(),
+            roll_overs: HashMap::new(),
+            transfers: Vec::new(),
+            bundle_boardings: Vec::new(),
+            window_misses: Vec::new(),
+            depot_events: Vec::new(),
+            repositions: Vec::new(),
+            after_repositioning: HashSet::new(),
             passenger_waits: HashMap::new(),
             passenger_travel: HashMap::new(),
             passenger_walking: HashMap::new(),
+            passenger_decision: HashMap::new(),
+            journeys: HashMap::new(),
+            unserved: HashMap::new(),
+            unreachable: HashMap::new(),
             vehicle_travel: HashMap::new(),
-            vehicle_passengers: HashMap::new()
+            vehicle_passengers: HashMap::new(),
+            vehicle_denied: HashMap::new(),
+            edge_usage: HashMap::new()
         }
     }
 }
 
 impl Module for Analytics {
-    type ReturnType = Sender<AnalyticsPackage>;
-    type Configuration = ();
-    type Parameters = ();
+    type ReturnType = ();
+    type Configuration = AnalyticsConfig;
+    type Parameters = EventBus;
 
     fn get_name(&self) -> &str {
         "Analytics"
@@ -134,17 +590,149 @@ impl Module for Analytics {
 
     fn init(
             &mut self,
-            _config: Self::Configuration,
-            _parameters: Self::Parameters,
+            config: Self::Configuration,
+            parameters: Self::Parameters,
         ) -> Result<Self::ReturnType, Box<dyn std::error::Error>> {
-            let tx = self.tx.clone();
-            Ok(tx)
+            fs::create_dir_all(&config.output_dir)?;
+            self.output_dir = config.output_dir;
+            self.flush_every = config.flush_every;
+            self.format = config.format;
+            self.zones = config.zones;
+            self.crs = config.crs;
+            self.metrics.crs = config.crs;
+            self.stop_rules = StopRules::new(config.stop_rules);
+            self.stamp = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S").to_string();
+            // Every event counts towards the results, so the simulation waits rather than dropping any
+            self.rx = parameters.subscribe(ANALYTICS_QUEUE, DropPolicy::Block);
+            self.gui = Some(parameters.topic());
+            Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalyticsConfig {
+    #[serde(default = "default_output_dir")]
+    pub output_dir: PathBuf,
+    #[serde(default = "default_flush_every")]
+    pub flush_every: u32, // Ticks between writing the outputs during the run, 0 to only write at the end
+    #[serde(default)]
+    pub format: OutputFormat, // jsonl or parquet to also write the results in that format, the CSVs are always written
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>, // Named areas to break the passenger KPIs down by, see `zones`
+    #[serde(default)]
+    pub prometheus: Option<String>, // Address to serve metrics on for monitoring, needs the `prometheus` feature
+    #[serde(default)]
+    pub stop_rules: StopRulesConfig, // When to give up on a headless run early, see `stop_rules`
+    #[serde(skip)]
+    pub crs: Crs, // What positions are in, set from the graph's config when it's loaded
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        AnalyticsConfig { output_dir: default_output_dir(), flush_every: default_flush_every(), format: OutputFormat::Csv, zones: Vec::new(), prometheus: None, stop_rules: StopRulesConfig::default(), crs: Crs::default() }
+    }
+}
+
+fn default_output_dir() -> PathBuf {
+    PathBuf::from("data/output")
+}
+
+fn default_flush_every() -> u32 {
+    60
+}
+
+/// Analytics running on its own thread while the simulation runs, so events don't pile up in
+/// the channel and a crash only loses what happened since the last flush
+pub struct LiveAnalytics {
+    handle: JoinHandle<Analytics>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LiveAnalytics {
+    // Stop consuming events and hand the analytics back. Anything left in the channel is picked
+    // up by the final write_outputs
+    pub fn finish(self) -> Analytics {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().expect("Analytics thread panicked")
     }
 }
 
 impl Analytics {
-    // loop trhough the rx channel buffer and process the messages to create the analytics profile 
+    pub fn query(&self) -> MetricsQuery {
+        self.metrics.clone()
+    }
+
+    // Let the stop rules end the run by finishing the simulation
+    pub fn set_sim_tx(&mut self, sim_tx: Sender<SimulationMessage>) {
+        self.sim_tx = Some(sim_tx);
+    }
+
+    // The stop rule that ended the run early, if one did
+    pub fn failed_by_rule(&self) -> Option<&str> {
+        self.metadata.as_ref().and_then(|metadata| metadata.failed_by_rule.as_deref())
+    }
+
+    fn check_stop_rules(&mut self) {
+        let tick_seconds = self.metadata.as_ref().map_or(crate::simulation::DEFAULT_TICK_SECONDS, |metadata| metadata.tick_seconds);
+        let Some(rule) = self.stop_rules.end_tick(tick_seconds) else { return };
+        let Some(sim_tx) = &self.sim_tx else { return };
+
+        println!("[Analytics] Stopping the run early, {}", rule);
+        if let Err(err) = sim_tx.send(SimulationMessage::ChangeState(SimulationState::Finished)) {
+            eprintln!("[Analytics] Couldn't stop the simulation {}", err);
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.failed_by_rule = Some(rule);
+        }
+    }
+
+    // Consume events on a new thread as they arrive, writing the outputs every `flush_every` ticks
+    pub fn spawn_live(mut self) -> LiveAnalytics {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+
+        let handle = thread::spawn(move || {
+            println!("[Analytics] Live analytics started, writing every {} ticks", self.flush_every);
+            let mut ticks = 0;
+            // The bus outlives the simulation so the subscription never disconnects, check for the stop instead
+            while !stop_thread.load(Ordering::SeqCst) {
+                match self.rx.recv_timeout(Duration::from_millis(200)) {
+                    Ok(package) => {
+                        let is_tick = matches!(package, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::TickTime { .. }));
+                        package.handle(&mut self);
+
+                        if is_tick && self.flush_every > 0 {
+                            ticks += 1;
+                            if ticks % self.flush_every == 0 {
+                                self.write_outputs();
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            self
+        });
+
+        LiveAnalytics { handle, stop }
+    }
+
+    // Write the outputs and then open the analytics window
     pub fn run(&mut self) -> () {
+        let output_paths = self.write_outputs();
+
+        let state = State::new(&self.output_dir, output_paths);
+        
+        match eframe::run_native("ODBRS_Analytics", state.native_options(), Box::new(|_cc| Box::new(state))) {
+            Ok(()) => (),
+            Err(err) => panic!("Error: {:?}", err),
+        }
+    }
+
+    // loop trhough the rx channel buffer and process the messages to create the analytics profile
+    // returns the vehicle and passenger CSV paths
+    pub fn write_outputs(&mut self) -> Vec<String> {
         loop {
             match self.rx.try_recv() {
                 Ok(package) => {
@@ -157,38 +745,604 @@ impl Analytics {
             }
         }
 
+        // Redrawn on the map each time the outputs are written
+        if let Some(gui) = &self.gui {
+            gui.publish(AppMessage::EdgeUsage(self.edge_usage()));
+        }
+        self.write_files()
+    }
+
+    // Write out the run so far and clear everything for the next one, keeping the channel and config
+    fn start_new_run(&mut self) {
+        self.write_files();
+        println!("[Analytics] New run started, previous run written with stamp {}", self.stamp);
+
+        let previous = std::mem::take(self);
+        previous.metrics.clear();
+        let mut stamp = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S").to_string();
+        if stamp == previous.stamp {
+            stamp.push_str("-restart");
+        }
+        *self = Analytics {
+            rx: previous.rx,
+            output_dir: previous.output_dir,
+            stamp,
+            flush_every: previous.flush_every,
+            format: previous.format,
+            zones: previous.zones,
+            metrics: previous.metrics,
+            stop_rules: StopRules::new(previous.stop_rules.config()),
+            sim_tx: previous.sim_tx,
+            gui: previous.gui,
+            crs: previous.crs,
+            ..Analytics::default()
+        };
+    }
+
+    fn write_files(&mut self) -> Vec<String> {
         // Write analytics out to file
         // TODO: write to file
 
         println!("Average Tick Time: {}", self.avg_tick_time);
+        if !self.passenger_decision.is_empty() {
+            let average = self.passenger_decision.values().sum::<i64>() as f64 / self.passenger_decision.len() as f64;
+            println!("Average Decision Latency: {:.1} minutes over {} requests", average, self.passenger_decision.len());
+        }
         println!("Analytics Sizes: \nPassengers with: \n\tWaits: {} \n\tTravel: {} \n\tWalking: {} \nVehicles with: \n\tTravel: {} \n\tPassengers: {}", self.passenger_waits.len(), self.passenger_travel.len(), self.passenger_walking.len(), self.vehicle_travel.len(), self.vehicle_passengers.len());
 
-        let output_path_passenger = format!(r#"data/output/{}-passenger-output.csv"#, chrono::Local::now().format("%Y-%m-%d-%H-%M-%S"));
-        let mut passenger_output_file = std::fs::File::create(&output_path_passenger).unwrap();
-        writeln!(&mut passenger_output_file, "Passenger ID,Waiting Ticks,Travel Ticks,Start Walking Ticks,End Walking Ticks").unwrap();
+        let stamp = &self.stamp;
+        let output_file = |name: &str| self.output_dir.join(format!("{}-{}.csv", stamp, name)).to_string_lossy().into_owned();
+
+        let mut passenger_table = Table::new(vec![
+            ("Passenger ID", "passenger_id"),
+            ("Waiting Ticks", "waiting_ticks"),
+            ("Travel Ticks", "travel_ticks"),
+            ("Start Walking Ticks", "start_walking_ticks"),
+            ("End Walking Ticks", "end_walking_ticks"),
+            ("Decision Latency", "decision_latency"),
+        ]);
         for (id, travel) in &self.passenger_travel {
             let wait = self.passenger_waits.get(id).unwrap_or(&0);
             let (walk_start, walk_end) = self.passenger_walking.get(id).unwrap_or(&(0,0));
-            writeln!(passenger_output_file, "{},{},{},{},{}", id, wait, travel, walk_start, walk_end).unwrap();
+            // Left empty for passengers who never went through a DRT assignment
+            let decision = self.passenger_decision.get(id).copied();
+            passenger_table.push(vec![Some(*id as i64), Some(*wait as i64), Some(*travel as i64), Some(*walk_start as i64), Some(*walk_end as i64), decision]);
         }
 
-        let output_path = format!(r#"data/output/{}-vehicle-output.csv"#, chrono::Local::now().format("%Y-%m-%d-%H-%M-%S"));
-        let mut vehicle_output_file = std::fs::File::create(&output_path).unwrap();
-        writeln!(vehicle_output_file, "Vehicle ID,Travel Ticks,Passengers Picked Up,Passengers Dropped Off").unwrap();
+        let mut vehicle_table = Table::new(vec![
+            ("Vehicle ID", "vehicle_id"),
+            ("Travel Ticks", "travel_ticks"),
+            ("Passengers Picked Up", "passengers_picked_up"),
+            ("Passengers Dropped Off", "passengers_dropped_off"),
+            ("Empty Distance", "empty_distance"),
+            ("Passengers Denied Boarding", "passengers_denied_boarding"),
+        ]);
+        let mut total_empty_distance = 0.0;
         for (id, travel) in &self.vehicle_travel {
             let (pickup, dropoff) = self.vehicle_passengers.get(id).unwrap_or(&(0,0));
-            writeln!(vehicle_output_file, "{},{},{},{}", id, travel, pickup, dropoff).unwrap();
+            let empty_distance = self.metrics.vehicle(*id).map(|metrics| metrics.empty_distance).unwrap_or(0.0);
+            total_empty_distance += empty_distance;
+            let denied = self.vehicle_denied.get(id).copied().unwrap_or(0);
+            vehicle_table.push(vec![Some(*id as i64), Some(*travel as i64), Some(*pickup as i64), Some(*dropoff as i64), Some(empty_distance.round() as i64), Some(denied as i64)]);
         }
+        println!("Empty Distance: {:.1} km over {} vehicles", total_empty_distance / 1000.0, self.vehicle_travel.len());
 
-        let tick_output_path = String::from(r#"data/output/simulation-last-output.csv"#);
+        // The analytics window reads the CSVs, so they're written whatever the format
+        let output_path_passenger = output_file("passenger-output");
+        let output_path = output_file("vehicle-output");
+        passenger_table.write(Path::new(&output_path_passenger), OutputFormat::Csv).unwrap();
+        vehicle_table.write(Path::new(&output_path), OutputFormat::Csv).unwrap();
+        if self.format != OutputFormat::Csv {
+            let extension = self.format.extension();
+            for (table, name) in [(&passenger_table, "passenger-output"), (&vehicle_table, "vehicle-output")] {
+                let path = self.output_dir.join(format!("{}-{}.{}", stamp, name, extension));
+                if let Err(err) = table.write(&path, self.format) {
+                    eprintln!("Analytics: Couldn't write {} {}", path.display(), err);
+                }
+            }
+        }
+
+        if let Some(metadata) = &self.metadata {
+            let metadata_path = self.output_dir.join(format!("{}-run.toml", stamp));
+            match toml::to_string(metadata) {
+                Ok(data) => fs::write(&metadata_path, data).unwrap(),
+                Err(err) => eprintln!("Analytics: Couldn't write run metadata {}", err),
+            }
+            // Tools reading the other formats are more likely to have a JSON parser than a TOML one
+            if self.format != OutputFormat::Csv {
+                let metadata_path = self.output_dir.join(format!("{}-run.json", stamp));
+                match serde_json::to_string_pretty(metadata) {
+                    Ok(data) => fs::write(&metadata_path, data).unwrap(),
+                    Err(err) => eprintln!("Analytics: Couldn't write run metadata {}", err),
+                }
+            }
+        }
+
+        let tick_output_path = self.output_dir.join("simulation-last-output.csv");
         fs::write(&tick_output_path, "ticktime\n".to_owned() + &self.tick_times.iter().map(|t| format!("{}\n", t)).collect::<String>()).unwrap();
 
-        let mut state = State::default();
-        create_distributions(&mut state, vec![output_path, output_path_passenger]);
-        
-        match eframe::run_native("ODBRS_Analytics", NativeOptions::default(), Box::new(|_cc| Box::new(state))) {
-            Ok(()) => (),
-            Err(err) => panic!("Error: {:?}", err),
+        // Kept out of the vehicle output as the analytics window only reads whole numbers
+        let metrics_output_path = output_file("vehicle-metrics");
+        let mut metrics_output_file = std::fs::File::create(&metrics_output_path).unwrap();
+        // Fleet columns are last so readers of outputs from before they were added still line up
+        writeln!(metrics_output_file, "Vehicle ID,Distance (km),Empty Distance (km),Average Occupancy,Peak Occupancy,Fleet,Fleet Vehicle ID").unwrap();
+        for (id, metrics) in self.metrics.all_vehicles() {
+            let (fleet, fleet_id) = entity::vehicle_fleet(id);
+            writeln!(metrics_output_file, "{},{:.3},{:.3},{:.2},{},{},{}", id, metrics.distance / 1000.0, metrics.empty_distance / 1000.0, metrics.average_occupancy(), metrics.peak_occupancy(), entity::fleet_name(fleet), fleet_id).unwrap();
+        }
+
+        let journey_output_path = output_file("journeys");
+        let mut journey_output_file = std::fs::File::create(&journey_output_path).unwrap();
+        writeln!(journey_output_file, "Passenger ID,Requested,Picked Up,Dropped Off,Origin X,Origin Y,Destination X,Destination Y,Vehicles").unwrap();
+        let mut journeys: Vec<_> = self.journeys.iter().collect();
+        journeys.sort_by_key(|(id, _)| **id);
+        let format_time = |time: Option<chrono::DateTime<chrono::Utc>>| time.map(|time| time.format("%H:%M:%S").to_string()).unwrap_or_default();
+        for (id, journey) in journeys {
+            // Vehicles are space separated so a change of bus stays in one column
+            let vehicles = journey.vehicles.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" ");
+            writeln!(journey_output_file, "{},{},{},{},{:.1},{:.1},{:.1},{:.1},{}", id, format_time(Some(journey.requested)), format_time(journey.picked_up), format_time(journey.dropped_off),
+                journey.origin.0, journey.origin.1, journey.destination.0, journey.destination.1, vehicles).unwrap();
+        }
+
+        let unserved = self.unserved_counts();
+        let unserved_output_path = output_file("unserved");
+        let mut unserved_output_file = std::fs::File::create(&unserved_output_path).unwrap();
+        writeln!(unserved_output_file, "Reason,Count").unwrap();
+        for (reason, count) in &unserved {
+            writeln!(unserved_output_file, "{},{}", reason.description(), count).unwrap();
+        }
+        println!("Unserved Demand: {}", unserved.iter().map(|(reason, count)| format!("{} {}", count, reason.description().to_lowercase())).collect::<Vec<_>>().join(", "));
+        let kpis = self.kpis();
+        if let Some(rate) = kpis.completion() {
+            println!("Service Rate: {:.1}% of {} requests delivered", rate, kpis.requests);
+        }
+
+        let unreachable = self.unreachable_areas();
+        if !unreachable.is_empty() {
+            let unreachable_output_path = output_file("unreachable");
+            let mut unreachable_output_file = std::fs::File::create(&unreachable_output_path).unwrap();
+            writeln!(unreachable_output_file, "Origin Area,Destination Area,Reason,Cause,Count").unwrap();
+            for ((origin, destination, reason), count) in &unreachable {
+                writeln!(unreachable_output_file, "\"{}\",\"{}\",{},{},{}", origin, destination, reason.description(), unreachable_cause(*reason), count).unwrap();
+            }
+            println!("Unreachable Demand, worst areas:");
+            for ((origin, destination, reason), count) in unreachable.iter().take(UNREACHABLE_SHOWN) {
+                println!("\t{} from {} to {} ({}, {})", count, origin, destination, reason.description().to_lowercase(), unreachable_cause(*reason));
+            }
+        }
+
+        if let Err(err) = self.timeseries.write(Path::new(&output_file("timeseries"))) {
+            eprintln!("Analytics: Couldn't write the time series {}", err);
+        }
+
+        if !self.zones.is_empty() {
+            let matrix = zones::zone_matrix(&self.zones, &self.journeys, &self.passenger_decision, &self.passenger_walking);
+            if let Err(err) = zones::write_matrix(&matrix, Path::new(&output_file("zones"))) {
+                eprintln!("Analytics: Couldn't write the zone matrix {}", err);
+            }
+        }
+
+        let arrivals = self.arrivals();
+        if !arrivals.is_empty() {
+            let arrival_output_path = output_file("arrivals");
+            let mut arrival_output_file = std::fs::File::create(&arrival_output_path).unwrap();
+            // Lateness is negative for early arrivals and empty for passengers who never got there
+            writeln!(arrival_output_file, "Passenger ID,Desired Arrival,Arrival,Minutes Late").unwrap();
+            let mut late = Vec::new();
+            for (id, desired, arrival) in &arrivals {
+                let minutes_late = arrival.map(|arrival| (arrival - *desired).num_seconds() as f64 / 60.0);
+                if let Some(minutes) = minutes_late.filter(|minutes| *minutes > 0.0) {
+                    late.push(minutes);
+                }
+                writeln!(arrival_output_file, "{},{},{},{}", id, format_time(Some(*desired)), format_time(*arrival), minutes_late.map(|minutes| format!("{:.1}", minutes)).unwrap_or_default()).unwrap();
+            }
+            let arrived = arrivals.iter().filter(|(_, _, arrival)| arrival.is_some()).count();
+            let average_late = if late.is_empty() { 0.0 } else { late.iter().sum::<f64>() / late.len() as f64 };
+            println!("Arrival Reliability: {} of {} passengers with a desired arrival got there on time, {} late by {:.1} minutes on average, {} never arrived",
+                arrived - late.len(), arrivals.len(), late.len(), average_late, arrivals.len() - arrived);
+        }
+
+        if !self.fleet_changes.is_empty() {
+            let fleet_output_path = output_file("fleet-changes");
+            let mut fleet_output_file = std::fs::File::create(&fleet_output_path).unwrap();
+            writeln!(fleet_output_file, "Time,Change").unwrap();
+            for (time, description) in &self.fleet_changes {
+                writeln!(fleet_output_file, "{},\"{}\"", time, description).unwrap();
+            }
+        }
+
+        if !self.critical_events.is_empty() {
+            println!("Critical Events: {}, see the critical output", self.critical_events.len());
+            let critical_output_path = output_file("critical");
+            let mut critical_output_file = std::fs::File::create(&critical_output_path).unwrap();
+            writeln!(critical_output_file, "Time,Event").unwrap();
+            for (time, description) in &self.critical_events {
+                writeln!(critical_output_file, "{},\"{}\"", time, description.replace('"', "'")).unwrap();
+            }
+        }
+
+        if !self.escalations.is_empty() {
+            let escalation_output_path = output_file("escalations");
+            let mut escalation_output_file = std::fs::File::create(&escalation_output_path).unwrap();
+            writeln!(escalation_output_file, "Passenger ID,Time,From,To").unwrap();
+            for (id, escalation) in &self.escalations {
+                writeln!(escalation_output_file, "{},{},{:?},{:?}", id, escalation.at.to_rfc3339(), escalation.from, escalation.to).unwrap();
+            }
+        }
+
+        if !self.roll_overs.is_empty() {
+            let roll_over_output_path = output_file("roll-overs");
+            let mut roll_over_output_file = std::fs::File::create(&roll_over_output_path).unwrap();
+            writeln!(roll_over_output_file, "Stop,Hour,Roll Overs,No Later Trip").unwrap();
+            let mut roll_overs: Vec<_> = self.roll_overs.iter().collect();
+            roll_overs.sort();
+            for ((stop, hour), (count, stranded)) in roll_overs {
+                writeln!(roll_over_output_file, "{},{},{},{}", stop, hour, count, stranded).unwrap();
+            }
+        }
+
+        if !self.transfers.is_empty() {
+            let transfer_output_path = output_file("transfers");
+            let mut transfer_output_file = std::fs::File::create(&transfer_output_path).unwrap();
+            writeln!(transfer_output_file, "Passenger ID,Time,From Vehicle,To Vehicle,X,Y").unwrap();
+            for (id, time, from, to, pos) in &self.transfers {
+                writeln!(transfer_output_file, "{},{},{},{},{},{}", id, time.to_rfc3339(), from, to, pos.0, pos.1).unwrap();
+            }
+        }
+
+        if !self.bundle_boardings.is_empty() {
+            let bundle_output_path = output_file("bundles");
+            let mut bundle_output_file = std::fs::File::create(&bundle_output_path).unwrap();
+            writeln!(bundle_output_file, "Bundle,Time,Vehicle,Boarded,X,Y").unwrap();
+            for (bundle, time, vehicle, boarded, pos) in &self.bundle_boardings {
+                writeln!(bundle_output_file, "{},{},{},{},{},{}", bundle, time.to_rfc3339(), vehicle, boarded, pos.0, pos.1).unwrap();
+            }
+        }
+
+        if !self.window_misses.is_empty() {
+            let late = self.window_misses.iter().filter(|(_, _, violation)| *violation == WindowViolation::LateDropoff).count();
+            println!("Time Windows Missed: {} dropped off late, {} rides too long", late, self.window_misses.len() - late);
+            let window_output_path = output_file("time-windows");
+            let mut window_output_file = std::fs::File::create(&window_output_path).unwrap();
+            writeln!(window_output_file, "Passenger ID,Time,Missed").unwrap();
+            for (id, time, violation) in &self.window_misses {
+                writeln!(window_output_file, "{},{},{:?}", id, time.to_rfc3339(), violation).unwrap();
+            }
         }
+
+        let edge_usage = self.edge_usage();
+        if !edge_usage.is_empty() {
+            let edge_output_path = output_file("edge-usage");
+            let mut edge_output_file = std::fs::File::create(&edge_output_path).unwrap();
+            writeln!(edge_output_file, "Edge ID,DRT Traversals,Fixed Route Traversals").unwrap();
+            for (edge, drt, fixed_route) in &edge_usage {
+                writeln!(edge_output_file, "{},{},{}", edge, drt, fixed_route).unwrap();
+            }
+            let both = edge_usage.iter().filter(|(_, drt, fixed_route)| *drt > 0 && *fixed_route > 0).count();
+            let drt = edge_usage.iter().filter(|(_, drt, _)| *drt > 0).count();
+            println!("Edge Usage: {} roads driven, {} by DRT, {} by fixed route buses, {} by both", edge_usage.len(), drt, edge_usage.len() - drt + both, both);
+        }
+
+        if !self.depot_events.is_empty() {
+            let depot_output_path = output_file("depots");
+            let mut depot_output_file = std::fs::File::create(&depot_output_path).unwrap();
+            writeln!(depot_output_file, "Depot,Time,Vehicle,Event,Buses Parked").unwrap();
+            let mut depots: Vec<u32> = self.depot_events.iter().map(|(depot, ..)| *depot).collect();
+            depots.sort();
+            depots.dedup();
+            println!("Depot Utilisation:");
+            for depot in depots {
+                let events: Vec<_> = self.depot_events.iter().filter(|event| event.0 == depot).map(|(_, time, vehicle, parking)| ((*time, *vehicle), *parking)).collect();
+                let (counts, peak) = depots::occupancy(&events);
+                for (((time, vehicle), parking), (_, parked)) in events.iter().zip(&counts) {
+                    writeln!(depot_output_file, "{},{},{},{},{}", depot, time.to_rfc3339(), vehicle, if *parking { "Parked" } else { "Left" }, parked).unwrap();
+                }
+                let parked = events.iter().filter(|(_, parking)| *parking).count();
+                println!("\tDepot {}: {} buses left, {} parked after their shift, up to {} parked at once", depot, events.len() - parked, parked, peak);
+            }
+        }
+
+        if !self.repositions.is_empty() {
+            let reposition_output_path = output_file("repositioning");
+            let mut reposition_output_file = std::fs::File::create(&reposition_output_path).unwrap();
+            writeln!(reposition_output_file, "Vehicle ID,Time,Node").unwrap();
+            for (vehicle, time, node) in &self.repositions {
+                writeln!(reposition_output_file, "{},{},{}", vehicle, time.to_rfc3339(), node).unwrap();
+            }
+            let buses: HashSet<u32> = self.repositions.iter().map(|(vehicle, ..)| *vehicle).collect();
+            let (after, rest) = self.repositioning_waits();
+            let average = |wait: Option<f64>| wait.map_or(String::from("-"), |minutes| format!("{:.1}", minutes));
+            println!(
+                "Repositioning: {} moves by {} buses, passengers picked up next waited {} minutes on average against {} for the rest",
+                self.repositions.len(),
+                buses.len(),
+                average(after),
+                average(rest)
+            );
+        }
+
+        vec![output_path, output_path_passenger]
+    }
+
+    // Average minutes waited by passengers a bus picked up first after repositioning, and by the
+    // rest of those picked up. None where nobody was
+    fn repositioning_waits(&self) -> (Option<f64>, Option<f64>) {
+        let (mut after, mut rest) = ((0.0, 0), (0.0, 0));
+        for (id, journey) in self.journeys.iter() {
+            let Some(picked_up) = journey.picked_up else { continue };
+            let total = if self.after_repositioning.contains(id) { &mut after } else { &mut rest };
+            *total = (total.0 + (picked_up - journey.requested).num_seconds() as f64 / 60.0, total.1 + 1);
+        }
+        let average = |(minutes, count): (f64, u32)| (count > 0).then(|| minutes / count as f64);
+        (average(after), average(rest))
+    }
+
+    // Times each edge was driven onto by DRT and by fixed route vehicles, busiest first
+    fn edge_usage(&self) -> Vec<(u128, u64, u64)> {
+        let mut usage: Vec<_> = self.edge_usage.iter().map(|(edge, (drt, fixed_route))| (*edge, *drt, *fixed_route)).collect();
+        usage.sort_by_key(|(edge, drt, fixed_route)| (std::cmp::Reverse(drt + fixed_route), *edge));
+        usage
+    }
+
+    // Passengers who gave a desired arrival with when they wanted to get there and when they did,
+    // counting the walk from the vehicle, sorted by id
+    fn arrivals(&self) -> Vec<(u32, chrono::DateTime<chrono::Utc>, Option<chrono::DateTime<chrono::Utc>>)> {
+        let tick_seconds = self.metadata.as_ref().map_or(crate::simulation::DEFAULT_TICK_SECONDS, |metadata| metadata.tick_seconds) as i64;
+        let mut arrivals: Vec<_> = self
+            .journeys
+            .iter()
+            .filter_map(|(id, journey)| {
+                let desired = journey.desired_arrival?;
+                let walk_end = self.passenger_walking.get(id).map_or(0, |walking| walking.1) as i64;
+                Some((*id, desired, journey.dropped_off.map(|dropped_off| dropped_off + chrono::Duration::seconds(walk_end * tick_seconds))))
+            })
+            .collect();
+        arrivals.sort_by_key(|(id, ..)| *id);
+        arrivals
+    }
+
+    // Headline numbers for the run so far, the same as a batch report reads back from the outputs
+    fn kpis(&self) -> RunKpis {
+        let metadata = self.metadata.clone().unwrap_or_default();
+        let mut kpis = RunKpis {
+            name: self.stamp.clone(),
+            static_only: metadata.static_only,
+            agents: metadata.agent_count,
+            demand_scale: metadata.demand_scale,
+            seconds: self.tick_times.iter().sum(),
+            result: metadata.failed_by_rule.map(|rule| format!("stopped: {}", rule)).unwrap_or_else(|| String::from("ok")),
+            seed: Some(metadata.seed),
+            requests: self.journeys.len() as u64,
+            unserved: self.unserved_counts().iter().map(|(_, count)| count).sum(),
+            ..Default::default()
+        };
+        let minutes = |from: chrono::DateTime<chrono::Utc>, to: chrono::DateTime<chrono::Utc>| (to - from).num_seconds() as f64 / 60.0;
+        for journey in self.journeys.values() {
+            if let Some(picked_up) = journey.picked_up {
+                kpis.picked_up += 1;
+                kpis.wait_minutes += minutes(journey.requested, picked_up);
+                if let Some(dropped_off) = journey.dropped_off {
+                    kpis.completed += 1;
+                    kpis.ride_minutes += minutes(picked_up, dropped_off);
+                }
+            }
+        }
+        for (_, metrics) in self.metrics.all_vehicles() {
+            kpis.vehicles += 1;
+            kpis.distance_km += metrics.distance / 1000.0;
+            kpis.empty_km += metrics.empty_distance / 1000.0;
+            kpis.occupancy += metrics.average_occupancy();
+        }
+        kpis
+    }
+
+    // Zone a point is in, or without zones the grid square it's in named by its south west corner
+    fn area_of(&self, point: (f64, f64)) -> String {
+        if !self.zones.is_empty() {
+            return zones::zone_of(&self.zones, point).to_string();
+        }
+        let size = if self.crs.is_geographic() { 0.01 } else { 1000.0 };
+        let corner = |value: f64| (value / size).floor() * size;
+        format!("{} {}", corner(point.0), corner(point.1))
+    }
+
+    // Demand with no route from one area to another, most first then by name
+    fn unreachable_areas(&self) -> Vec<((String, String, UnservedReason), u64)> {
+        let mut areas: Vec<_> = self.unreachable.iter().map(|(key, count)| (key.clone(), *count)).collect();
+        areas.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+        areas
+    }
+
+    // Demand dropped along the way plus journeys left unfinished, for every reason
+    fn unserved_counts(&self) -> Vec<(UnservedReason, u64)> {
+        let mut counts = self.unserved.clone();
+        for journey in self.journeys.values() {
+            let reason = match (journey.rejected, journey.picked_up, journey.dropped_off) {
+                (Some(reason), ..) => reason,
+                (None, None, _) => UnservedReason::NotPickedUp,
+                (None, Some(_), None) => UnservedReason::NotDroppedOff,
+                _ => continue,
+            };
+            *counts.entry(reason).or_insert(0) += 1;
+        }
+
+        [
+            UnservedReason::Policy,
+            UnservedReason::NoRoute,
+            UnservedReason::Disconnected,
+            UnservedReason::WaitedTooLong,
+            UnservedReason::DetourTooLong,
+            UnservedReason::NotPickedUp,
+            UnservedReason::NotDroppedOff,
+        ]
+            .into_iter()
+            .map(|reason| (reason, counts.get(&reason).copied().unwrap_or(0)))
+            .collect()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vehicle_metrics_follow_events() {
+        let query = MetricsQuery::default();
+        query.record(&VehicleAnalyticsEvent::MovementTick { id: 1, pos: (0.0, 0.0) }, 0);
+        query.record(&VehicleAnalyticsEvent::MovementTick { id: 1, pos: (30.0, 40.0) }, 1);
+        query.record(&VehicleAnalyticsEvent::PassengerPickup { id: 1, passenger_id: 7 }, 1);
+        query.record(&VehicleAnalyticsEvent::PassengerPickup { id: 1, passenger_id: 8 }, 2);
+        query.record(&VehicleAnalyticsEvent::PassengerDropoff { id: 1, passenger_id: 7 }, 4);
+
+        let metrics = query.vehicle(1).unwrap();
+        assert_eq!(metrics.distance, 50.0);
+        assert_eq!(metrics.empty_distance, 50.0);
+        assert_eq!((metrics.pickups, metrics.dropoffs), (2, 1));
+        assert_eq!(metrics.occupancy, vec![(1, 1), (2, 2), (4, 1)]);
+        assert_eq!(metrics.peak_occupancy(), 2);
+        let stop_events: Vec<_> = query.recent_stop_events().iter().map(|event| (event.pos, event.boarding)).collect();
+        assert_eq!(stop_events, vec![((30.0, 40.0), true), ((30.0, 40.0), true), ((30.0, 40.0), false)]);
+        // Empty for both moves, then one more with a passenger still on board
+        query.record(&VehicleAnalyticsEvent::MovementTick { id: 1, pos: (30.0, 50.0) }, 5);
+        assert_eq!(query.vehicle(1).unwrap().average_occupancy(), 1.0 / 3.0);
+        assert!(query.vehicle(2).is_none());
+    }
+
+    #[test]
+    fn roads_are_kept_from_the_oldest_plan() {
+        let query = MetricsQuery::default();
+        let enter = |edge| query.record(&VehicleAnalyticsEvent::EdgeEntered { id: 1, edge, mode: ServiceMode::Drt }, 0);
+        let plan = |passenger_id| query.record(&VehicleAnalyticsEvent::Planned { id: 1, passenger_id, route: vec![], time: chrono::Utc::now() }, 0);
+
+        // Nothing to compare against before the first plan
+        enter(1);
+        plan(1);
+        enter(2);
+        assert_eq!(query.vehicle(1).unwrap().driven, vec![2]);
+
+        // Forgetting the first plan forgets the roads only it was compared against
+        for i in 0..PLANS_KEPT as u32 {
+            enter(100 + i as u128);
+            plan(10 + i);
+        }
+        let metrics = query.vehicle(1).unwrap();
+        assert_eq!((metrics.plans[0].passenger_id, metrics.plans[0].driven_before), (10, 0));
+        assert_eq!(metrics.driven, (101..100 + PLANS_KEPT as u128).collect::<Vec<_>>());
+        assert_eq!(metrics.plans[1].driven_before, 1);
+
+        // However long it's been since
+        for edge in 0..DRIVEN_KEPT as u128 {
+            enter(edge);
+        }
+        let metrics = query.vehicle(1).unwrap();
+        assert_eq!(metrics.driven.len(), DRIVEN_KEPT);
+        assert_eq!(metrics.plans.len(), 1);
+        assert_eq!(metrics.plans[0].driven_before, 0);
+    }
+
+    #[test]
+    fn unserved_demand_is_counted_by_reason() {
+        let mut analytics = Analytics::default();
+        let time = chrono::Utc::now();
+        let events = [
+            AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::DemandRejected { count: 3 }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Unserved { reason: UnservedReason::NoRoute, origin: (0.0, 0.0), destination: (1.0, 1.0) }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 2, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 3, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: 3, vehicle: 9, time }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 4, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: 4, vehicle: 9, time }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { id: 4, time }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 5, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Rejected { id: 5, reason: UnservedReason::WaitedTooLong }),
+        ];
+        for event in events {
+            event.handle(&mut analytics);
+        }
+
+        assert_eq!(analytics.unserved_counts(), vec![
+            (UnservedReason::Policy, 3),
+            (UnservedReason::NoRoute, 1),
+            (UnservedReason::Disconnected, 0),
+            (UnservedReason::WaitedTooLong, 1),
+            (UnservedReason::DetourTooLong, 0),
+            (UnservedReason::NotPickedUp, 1),
+            (UnservedReason::NotDroppedOff, 1),
+        ]);
+    }
+
+    #[test]
+    fn edge_usage_is_split_by_fleet() {
+        let mut analytics = Analytics::default();
+        let entered = |id, edge, mode| AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::EdgeEntered { id, edge, mode });
+        for event in [entered(1, 10, ServiceMode::Drt), entered(2, 10, ServiceMode::FixedRoute), entered(1, 11, ServiceMode::Drt), entered(1, 10, ServiceMode::Drt)] {
+            event.handle(&mut analytics);
+        }
+        // Busiest first
+        assert_eq!(analytics.edge_usage(), vec![(10, 2, 1), (11, 1, 0)]);
+    }
+
+    #[test]
+    fn unreachable_demand_is_ranked_by_area() {
+        let mut analytics = Analytics::default();
+        let unserved = |reason, origin, destination| AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Unserved { reason, origin, destination });
+        let events = [
+            unserved(UnservedReason::NoRoute, (429_100.0, 287_900.0), (431_500.0, 287_100.0)),
+            unserved(UnservedReason::Disconnected, (100.0, 100.0), (2_500.0, 100.0)),
+            unserved(UnservedReason::Disconnected, (900.0, 200.0), (2_100.0, 900.0)),
+        ];
+        for event in events {
+            event.handle(&mut analytics);
+        }
+
+        // Grid squares named by their corner without zones, the worst pair first
+        let areas: Vec<_> = analytics.unreachable_areas().into_iter().map(|((origin, destination, reason), count)| (origin, destination, unreachable_cause(reason), count)).collect();
+        assert_eq!(areas, vec![
+            (String::from("0 0"), String::from("2000 0"), "data", 2),
+            (String::from("429000 287000"), String::from("431000 287000"), "service gap", 1),
+        ]);
+
+        analytics.zones = vec![ZoneConfig { name: String::from("West"), points: vec![(0.0, 0.0), (1000.0, 0.0), (1000.0, 1000.0), (0.0, 1000.0)] }];
+        assert_eq!(analytics.area_of((500.0, 500.0)), "West");
+        assert_eq!(analytics.area_of((2_500.0, 500.0)), zones::OUTSIDE);
+    }
+
+    #[test]
+    fn restarts_keep_the_config() {
+        let output_dir = std::env::temp_dir().join("odbrs-restart-test");
+        fs::create_dir_all(&output_dir).unwrap();
+        let mut analytics = Analytics { output_dir: output_dir.clone(), flush_every: 7, crs: Crs::Wgs84, ..Analytics::default() };
+        analytics.unserved.insert(UnservedReason::NoRoute, 1);
+        analytics.start_new_run();
+
+        // Only the run's results start again
+        assert!(analytics.unserved.is_empty());
+        assert_eq!((analytics.output_dir.clone(), analytics.flush_every, analytics.crs), (output_dir.clone(), 7, Crs::Wgs84));
+        // Unreachable demand is still put in lat/lon sized squares
+        assert_eq!(analytics.area_of((-1.515, 52.405)), analytics.area_of((-1.512, 52.401)));
+        assert_ne!(analytics.area_of((-1.515, 52.405)), analytics.area_of((-1.495, 52.405)));
+        let _ = fs::remove_dir_all(output_dir);
+    }
+
+    #[test]
+    fn arrival_counts_the_walk_from_the_vehicle() {
+        let mut analytics = Analytics::default();
+        let time = chrono::DateTime::parse_from_rfc3339("2026-01-05T08:00:00Z").unwrap().with_timezone(&chrono::Utc);
+        let desired_arrival = Some(time + chrono::Duration::minutes(30));
+        let events = [
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 1, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 2, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 3, time, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { id: 1, time: time + chrono::Duration::minutes(28) }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::EndWalkingTick { id: 1 }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::EndWalkingTick { id: 1 }),
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::EndWalkingTick { id: 1 }),
+        ];
+        for event in events {
+            event.handle(&mut analytics);
+        }
+
+        // Off the bus two minutes early but three minutes' walk from the door
+        assert_eq!(analytics.arrivals(), vec![(1, desired_arrival.unwrap(), Some(time + chrono::Duration::minutes(31))), (2, desired_arrival.unwrap(), None)]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Routes DRT buses planned against the roads they went on to drive, to see what the large
+//! neighbourhood search changed. A bus publishes its plan (`VehicleAnalyticsEvent::Planned`) when
+//! a passenger is first given to it, and the live metrics keep every road each vehicle enters, so
+//! the vehicle panel can lay a plan over what was driven after it.
+//!
+//! Reassignments by the search after the first aren't published, the plan is the one the
+//! passenger was promised, and nor are plans from a repair the search undoes. Only the last
+//! `PLANS_KEPT` plans of each bus are kept, and only the roads driven since the oldest of them, up
+//! to `DRIVEN_KEPT`.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+
+// Plans kept for each vehicle, the oldest forgotten first
+pub const PLANS_KEPT: usize = 20;
+
+// Roads kept for each vehicle to compare its plans against, plans from before them are forgotten
+pub const DRIVEN_KEPT: usize = 2000;
+
+/// The roads a bus planned to drive when it was given a passenger
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedRoute {
+    pub passenger_id: u32,
+    pub time: DateTime<Utc>,
+    pub edges: Vec<u128>, // In order, from the node the bus was heading to
+    pub driven_before: usize, // Roads the bus had entered before, where the comparison starts
+}
+
+/// A plan next to what was driven after it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteComparison {
+    pub planned: Vec<u128>,
+    pub driven: Vec<u128>, // Up to the end of the plan, or everything since if it hasn't got there
+    pub followed: usize, // Roads driven as planned before the first change
+}
+
+impl RouteComparison {
+    // Roads driven since `plan` was made, from every road the vehicle has entered in order
+    pub fn new(plan: &PlannedRoute, driven: &[u128]) -> Self {
+        let mut since: Vec<u128> = driven.get(plan.driven_before..).unwrap_or_default().to_vec();
+        // The bus carries on once the plan's done, so only compare up to its last road
+        if let Some(position) = plan.edges.last().and_then(|last| since.iter().position(|edge| edge == last)) {
+            since.truncate(position + 1);
+        }
+        let followed = plan.edges.iter().zip(since.iter()).take_while(|(planned, driven)| planned == driven).count();
+        RouteComparison { planned: plan.edges.clone(), driven: since, followed }
+    }
+
+    // Planned roads which weren't driven
+    pub fn dropped(&self) -> Vec<u128> {
+        let driven: HashSet<&u128> = self.driven.iter().collect();
+        self.planned.iter().filter(|edge| !driven.contains(edge)).copied().collect()
+    }
+
+    // Driven roads which weren't in the plan
+    pub fn detours(&self) -> Vec<u128> {
+        let planned: HashSet<&u128> = self.planned.iter().collect();
+        self.driven.iter().filter(|edge| !planned.contains(edge)).copied().collect()
+    }
+
+    // Whether everything driven so far has gone to plan
+    pub fn on_plan(&self) -> bool {
+        self.followed == self.driven.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn driving_is_compared_with_the_plan_from_when_it_was_made() {
+        let plan = PlannedRoute { passenger_id: 1, time: Utc::now(), edges: vec![10, 11, 12, 13], driven_before: 2 };
+
+        // Went as planned for two roads then round another way, finishing the plan and carrying on
+        let comparison = RouteComparison::new(&plan, &[1, 2, 10, 11, 20, 21, 13, 30]);
+        assert_eq!(comparison.driven, vec![10, 11, 20, 21, 13]);
+        assert_eq!(comparison.followed, 2);
+        assert_eq!(comparison.dropped(), vec![12]);
+        assert_eq!(comparison.detours(), vec![20, 21]);
+        assert!(!comparison.on_plan());
+
+        // Part way along and still on plan
+        let comparison = RouteComparison::new(&plan, &[1, 2, 10]);
+        assert_eq!((comparison.followed, comparison.on_plan()), (1, true));
+        assert_eq!(comparison.dropped(), vec![11, 12, 13]);
+    }
+}
@@ -0,0 +1,427 @@
+//! Comparison report for a batch of runs, for sharing results with people who don't have the tool.
+//!
+//! Reads `runs.csv` and each run's outputs in a batch directory and writes `report.md` and a
+//! self-contained `report.html` next to them: the batch settings, a KPI table for every run, one
+//! averaged over the repeats of each combination, and bar charts of the averages as PNGs (also
+//! embedded in the HTML). Written at the end of a batch, or again with `--report <BATCH_DIR>`.
+
+use std::{collections::BTreeMap, error::Error, fmt::Write as _, fs, path::{Path, PathBuf}};
+
+use chrono::{NaiveTime, Timelike};
+use image::{Rgb, RgbImage};
+
+use super::RunMetadata;
+
+const CHART_HEIGHT: u32 = 240;
+const BAR_WIDTH: u32 = 48;
+const BAR_GAP: u32 = 24;
+const CHART_MARGIN: u32 = 20;
+const BAR_COLOURS: [[u8; 3]; 4] = [[31, 119, 180], [255, 127, 14], [44, 160, 44], [214, 39, 40]];
+
+type ReadOutput = fn(&Path, &mut RunKpis) -> Result<(), Box<dyn Error>>;
+type Kpi = fn(&RunKpis) -> Option<f64>;
+
+/// Outcome of one run of the batch, read back from its output files
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunKpis {
+    pub name: String,
+    pub static_only: bool,
+    pub agents: usize,
+    pub demand_scale: f64,
+    pub seconds: f64, // Wall clock time the run took
+    pub result: String, // "ok" or the error it failed with
+    pub seed: Option<u64>,
+
+    pub requests: u64,
+    pub picked_up: u64,
+    pub completed: u64,
+    pub wait_minutes: f64, // Request to pick up, summed over those picked up
+    pub ride_minutes: f64, // Pick up to drop off, summed over those completed
+    pub vehicles: u64,
+    pub distance_km: f64,
+    pub empty_km: f64,
+    pub occupancy: f64, // Average passengers on board, summed over the vehicles
+    pub unserved: u64,
+}
+
+impl RunKpis {
+    pub fn completion(&self) -> Option<f64> {
+        ratio(self.completed as f64, self.requests as f64).map(|ratio| ratio * 100.0)
+    }
+
+    pub fn average_wait(&self) -> Option<f64> {
+        ratio(self.wait_minutes, self.picked_up as f64)
+    }
+
+    pub fn average_ride(&self) -> Option<f64> {
+        ratio(self.ride_minutes, self.completed as f64)
+    }
+
+    pub fn empty_share(&self) -> Option<f64> {
+        ratio(self.empty_km, self.distance_km).map(|ratio| ratio * 100.0)
+    }
+
+    pub fn average_occupancy(&self) -> Option<f64> {
+        ratio(self.occupancy, self.vehicles as f64)
+    }
+
+    // Label shared by the repeats of a combination
+    fn combination(&self) -> String {
+        format!("{} a{} s{}", if self.static_only { "static" } else { "dynamic" }, self.agents, self.demand_scale)
+    }
+}
+
+fn ratio(total: f64, count: f64) -> Option<f64> {
+    (count > 0.0).then(|| total / count)
+}
+
+fn cell(value: Option<f64>) -> String {
+    value.map(|value| format!("{:.1}", value)).unwrap_or_else(|| String::from("-"))
+}
+
+// Minutes from `from` to `to`, which is the next day if it's earlier on the clock
+fn minutes_between(from: NaiveTime, to: NaiveTime) -> f64 {
+    let (from, to) = (from.num_seconds_from_midnight() as f64, to.num_seconds_from_midnight() as f64);
+    let seconds = if to < from { to + 86_400.0 - from } else { to - from };
+    seconds / 60.0
+}
+
+// The newest output of a run with this suffix, a restarted run leaves the earlier ones behind
+fn latest_output(dir: &Path, suffix: &str) -> Option<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(suffix)))
+        .collect();
+    files.sort();
+    files.pop()
+}
+
+fn read_journeys(path: &Path, kpis: &mut RunKpis) -> Result<(), Box<dyn Error>> {
+    let time = |value: &str| NaiveTime::parse_from_str(value, "%H:%M:%S").ok();
+    for record in csv::Reader::from_path(path)?.records() {
+        let record = record?;
+        kpis.requests += 1;
+        let (requested, picked_up, dropped_off) = (time(&record[1]), time(&record[2]), time(&record[3]));
+        if let (Some(requested), Some(picked_up)) = (requested, picked_up) {
+            kpis.picked_up += 1;
+            kpis.wait_minutes += minutes_between(requested, picked_up);
+        }
+        if let (Some(picked_up), Some(dropped_off)) = (picked_up, dropped_off) {
+            kpis.completed += 1;
+            kpis.ride_minutes += minutes_between(picked_up, dropped_off);
+        }
+    }
+    Ok(())
+}
+
+fn read_vehicle_metrics(path: &Path, kpis: &mut RunKpis) -> Result<(), Box<dyn Error>> {
+    for record in csv::Reader::from_path(path)?.records() {
+        let record = record?;
+        kpis.vehicles += 1;
+        kpis.distance_km += record[1].parse::<f64>()?;
+        kpis.empty_km += record[2].parse::<f64>()?;
+        kpis.occupancy += record[3].parse::<f64>()?;
+    }
+    Ok(())
+}
+
+fn read_unserved(path: &Path, kpis: &mut RunKpis) -> Result<(), Box<dyn Error>> {
+    for record in csv::Reader::from_path(path)?.records() {
+        kpis.unserved += record?[1].parse::<u64>()?;
+    }
+    Ok(())
+}
+
+// Every run listed in the batch's runs.csv, with whatever outputs it left
+pub fn read_batch(batch_dir: &Path) -> Result<Vec<RunKpis>, Box<dyn Error>> {
+    let mut runs = Vec::new();
+    for record in csv::Reader::from_path(batch_dir.join("runs.csv"))?.records() {
+        let record = record?;
+        let mut kpis = RunKpis {
+            static_only: record[1].parse()?,
+            agents: record[2].parse()?,
+            demand_scale: record[3].parse()?,
+            name: record[4].to_string(),
+            seconds: record[5].parse()?,
+            result: record[6].to_string(),
+            ..Default::default()
+        };
+
+        let dir = batch_dir.join(&kpis.name);
+        let outputs: [(&str, ReadOutput); 3] = [
+            ("-journeys.csv", read_journeys),
+            ("-vehicle-metrics.csv", read_vehicle_metrics),
+            ("-unserved.csv", read_unserved),
+        ];
+        for (suffix, read) in outputs {
+            if let Some(path) = latest_output(&dir, suffix) {
+                if let Err(err) = read(&path, &mut kpis) {
+                    eprintln!("[Report] Couldn't read {:?}: {}", path, err);
+                }
+            }
+        }
+        if let Some(path) = latest_output(&dir, "-run.toml") {
+            let metadata: Option<RunMetadata> = fs::read_to_string(&path).ok().and_then(|data| toml::from_str(&data).ok());
+            kpis.seed = metadata.map(|metadata| metadata.seed);
+        }
+        runs.push(kpis);
+    }
+    Ok(runs)
+}
+
+// Repeats of the same settings added together, in the order the combinations were first run
+fn combine(runs: &[RunKpis]) -> Vec<(String, usize, RunKpis)> {
+    let mut combined: Vec<(String, usize, RunKpis)> = Vec::new();
+    for run in runs.iter().filter(|run| run.result == "ok") {
+        let label = run.combination();
+        let i = match combined.iter().position(|(existing, _, _)| *existing == label) {
+            Some(i) => i,
+            None => {
+                combined.push((label, 0, RunKpis { name: run.combination(), static_only: run.static_only, agents: run.agents, demand_scale: run.demand_scale, ..Default::default() }));
+                combined.len() - 1
+            }
+        };
+        let (_, repeats, total) = &mut combined[i];
+        *repeats += 1;
+        total.seconds += run.seconds;
+        total.requests += run.requests;
+        total.picked_up += run.picked_up;
+        total.completed += run.completed;
+        total.wait_minutes += run.wait_minutes;
+        total.ride_minutes += run.ride_minutes;
+        total.vehicles += run.vehicles;
+        total.distance_km += run.distance_km;
+        total.empty_km += run.empty_km;
+        total.occupancy += run.occupancy;
+        total.unserved += run.unserved;
+    }
+    combined
+}
+
+// Bars left to right in the order given, scaled to the largest. Labels go in the report, the image
+// crate can't draw text
+pub fn bar_chart(values: &[f64], path: &Path) -> Result<(), Box<dyn Error>> {
+    let width = CHART_MARGIN * 2 + values.len().max(1) as u32 * (BAR_WIDTH + BAR_GAP) - BAR_GAP;
+    let mut image = RgbImage::from_pixel(width, CHART_HEIGHT, Rgb([255, 255, 255]));
+    let plot_height = CHART_HEIGHT - CHART_MARGIN * 2;
+    let baseline = CHART_HEIGHT - CHART_MARGIN;
+
+    // Gridlines at every quarter of the largest value
+    for quarter in 0..=4 {
+        let y = baseline - plot_height * quarter / 4;
+        let shade = if quarter == 0 { 80 } else { 220 };
+        for x in CHART_MARGIN / 2..width - CHART_MARGIN / 2 {
+            image.put_pixel(x, y, Rgb([shade, shade, shade]));
+        }
+    }
+
+    let max = values.iter().copied().fold(0.0, f64::max);
+    for (i, value) in values.iter().enumerate() {
+        let height = if max > 0.0 { (value.max(0.0) / max * plot_height as f64).round() as u32 } else { 0 };
+        let left = CHART_MARGIN + i as u32 * (BAR_WIDTH + BAR_GAP);
+        for x in left..left + BAR_WIDTH {
+            for y in baseline - height..baseline {
+                image.put_pixel(x, y, Rgb(BAR_COLOURS[i % BAR_COLOURS.len()]));
+            }
+        }
+    }
+
+    image.save(path)?;
+    Ok(())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// A table as rows of cells, the first row the header
+struct Table(Vec<Vec<String>>);
+
+impl Table {
+    fn kpis<'a>(rows: impl Iterator<Item = (String, String, &'a RunKpis)>) -> Table {
+        let mut table = vec![
+            ["Run", "Repeats / Result", "Requests", "Completed %", "Wait (min)", "Ride (min)", "Vehicle km", "Empty %", "Occupancy", "Unserved"]
+                .iter().map(|header| header.to_string()).collect(),
+        ];
+        for (name, note, kpis) in rows {
+            table.push(vec![
+                name,
+                note,
+                kpis.requests.to_string(),
+                cell(kpis.completion()),
+                cell(kpis.average_wait()),
+                cell(kpis.average_ride()),
+                format!("{:.1}", kpis.distance_km),
+                cell(kpis.empty_share()),
+                cell(kpis.average_occupancy()),
+                kpis.unserved.to_string(),
+            ]);
+        }
+        Table(table)
+    }
+
+    fn markdown(&self) -> String {
+        let mut out = String::new();
+        for (i, row) in self.0.iter().enumerate() {
+            let _ = writeln!(out, "| {} |", row.iter().map(|cell| cell.replace('|', "/")).collect::<Vec<_>>().join(" | "));
+            if i == 0 {
+                let _ = writeln!(out, "|{}", "---|".repeat(row.len()));
+            }
+        }
+        out
+    }
+
+    fn html(&self) -> String {
+        let mut out = String::from("<table>\n");
+        for (i, row) in self.0.iter().enumerate() {
+            let tag = if i == 0 { "th" } else { "td" };
+            let cells: String = row.iter().map(|cell| format!("<{tag}>{}</{tag}>", escape(cell))).collect();
+            let _ = writeln!(out, "<tr>{}</tr>", cells);
+        }
+        out.push_str("</table>\n");
+        out
+    }
+}
+
+// Write report.md, report.html and the chart PNGs into the batch directory. `settings` describes
+// the batch, e.g. the sweep it ran
+pub fn write_report(batch_dir: &Path, settings: &[(String, String)]) -> Result<PathBuf, Box<dyn Error>> {
+    let runs = read_batch(batch_dir)?;
+    let combined = combine(&runs);
+
+    let per_run = Table::kpis(runs.iter().map(|run| {
+        let note = match run.seed {
+            Some(seed) if run.result == "ok" => format!("ok, seed {}", seed),
+            _ => run.result.clone(),
+        };
+        (run.name.clone(), note, run)
+    }));
+    let averaged = Table::kpis(combined.iter().map(|(label, repeats, total)| (label.clone(), repeats.to_string(), total)));
+    let mut settings_table = vec![vec![String::from("Setting"), String::from("Value")]];
+    settings_table.extend(settings.iter().map(|(name, value)| vec![name.clone(), value.clone()]));
+    let settings_table = Table(settings_table);
+
+    let labels: Vec<String> = combined.iter().map(|(label, _, _)| label.clone()).collect();
+    let charts: [(&str, &str, Kpi); 4] = [
+        ("completion", "Completed requests (%)", RunKpis::completion),
+        ("wait", "Average wait (minutes)", RunKpis::average_wait),
+        ("ride", "Average ride (minutes)", RunKpis::average_ride),
+        ("empty", "Empty running (% of vehicle km)", RunKpis::empty_share),
+    ];
+
+    let title = format!("Batch report: {}", batch_dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default());
+    let failed = runs.iter().filter(|run| run.result != "ok").count();
+    let summary = format!("{} runs, {} combinations, {} failed. Generated {}.", runs.len(), combined.len(), failed, chrono::Local::now().format("%Y-%m-%d %H:%M"));
+
+    let mut markdown = format!("# {}\n\n{}\n\n## Settings\n\n{}\n## Averaged over repeats\n\n{}\n", title, summary, settings_table.markdown(), averaged.markdown());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; margin-bottom: 1em; }} th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: right; }} th:first-child, td:first-child {{ text-align: left; }}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{}</p>\n<h2>Settings</h2>\n{}<h2>Averaged over repeats</h2>\n{}",
+        escape(&summary), settings_table.html(), averaged.html(), title = escape(&title)
+    );
+
+    let legend = labels.iter().enumerate().map(|(i, label)| format!("{}. {}", i + 1, label)).collect::<Vec<_>>().join(", ");
+    markdown.push_str(&format!("## Charts\n\nBars left to right: {}\n\n", legend));
+    html.push_str(&format!("<h2>Charts</h2>\n<p>Bars left to right: {}</p>\n", escape(&legend)));
+    for (name, caption, value) in charts {
+        let values: Vec<f64> = combined.iter().map(|(_, _, total)| value(total).unwrap_or(0.0)).collect();
+        let file = format!("report-{}.png", name);
+        let path = batch_dir.join(&file);
+        bar_chart(&values, &path)?;
+
+        let figures = values.iter().map(|value| format!("{:.1}", value)).collect::<Vec<_>>().join(", ");
+        markdown.push_str(&format!("### {}\n\n![{}]({})\n\n{}\n\n", caption, caption, file, figures));
+        html.push_str(&format!("<h3>{}</h3>\n<img alt=\"{}\" src=\"data:image/png;base64,{}\">\n<p>{}</p>\n", escape(caption), escape(caption), base64(&fs::read(&path)?), figures));
+    }
+
+    markdown.push_str(&format!("## Every run\n\n{}", per_run.markdown()));
+    html.push_str(&format!("<h2>Every run</h2>\n{}</body>\n</html>\n", per_run.html()));
+
+    // Runs of each combination for anyone checking the spread between repeats
+    let mut spread: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for run in runs.iter().filter(|run| run.result == "ok") {
+        spread.entry(run.combination()).or_default().push(cell(run.average_wait()));
+    }
+    if spread.values().any(|waits| waits.len() > 1) {
+        markdown.push_str("\n## Wait spread between repeats (min)\n\n");
+        for (label, waits) in spread {
+            markdown.push_str(&format!("- {}: {}\n", label, waits.join(", ")));
+        }
+    }
+
+    fs::write(batch_dir.join("report.md"), markdown)?;
+    let html_path = batch_dir.join("report.html");
+    fs::write(&html_path, html)?;
+    Ok(html_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64_matches_the_standard_padding() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b""), "");
+    }
+
+    #[test]
+    fn report_averages_the_repeats_of_a_batch() {
+        let dir = std::env::temp_dir().join(format!("odbrs-report-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("runs.csv"), "Run,Static,Agents,Demand Scale,Directory,Seconds,Result\n\
+            1,false,10,0.2,run-001,1.0,ok\n\
+            2,false,10,0.2,run-002,1.0,ok\n\
+            3,true,10,0.2,run-003,0.5,\"error: no network data\"\n").unwrap();
+
+        // Waits of 10 and 0 minutes with rides running past midnight, then a passenger never picked up
+        for (run, wait) in [("run-001", "23:50:00"), ("run-002", "23:40:00")] {
+            fs::create_dir_all(dir.join(run)).unwrap();
+            fs::write(dir.join(run).join("stamp-journeys.csv"), format!("Passenger ID,Requested,Picked Up,Dropped Off,Origin X,Origin Y,Destination X,Destination Y,Vehicles\n\
+                1,23:40:00,{},00:10:00,0,0,0,0,1\n\
+                2,23:45:00,,,0,0,0,0,\n", wait)).unwrap();
+            fs::write(dir.join(run).join("stamp-vehicle-metrics.csv"), "Vehicle ID,Distance (km),Empty Distance (km),Average Occupancy,Peak Occupancy\n1,10.000,2.500,1.50,2\n").unwrap();
+            fs::write(dir.join(run).join("stamp-unserved.csv"), "Reason,Count\nNever picked up,1\n").unwrap();
+        }
+
+        let runs = read_batch(&dir).unwrap();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0].wait_minutes, 10.0);
+        assert_eq!(runs[0].ride_minutes, 20.0);
+        assert_eq!(runs[0].completion(), Some(50.0));
+
+        let combined = combine(&runs);
+        assert_eq!(combined.len(), 1);
+        assert_eq!(combined[0].1, 2);
+        assert_eq!(combined[0].2.average_wait(), Some(5.0));
+        assert_eq!(combined[0].2.average_ride(), Some(25.0));
+        assert_eq!(combined[0].2.empty_share(), Some(25.0));
+
+        write_report(&dir, &[(String::from("Repeats"), String::from("2"))]).unwrap();
+        let markdown = fs::read_to_string(dir.join("report.md")).unwrap();
+        assert!(markdown.contains("| dynamic a10 s0.2 | 2 | 4 | 50.0 | 5.0 | 25.0 |"), "{}", markdown);
+        assert!(markdown.contains("error: no network data"));
+        assert!(fs::read_to_string(dir.join("report.html")).unwrap().contains("data:image/png;base64,iVBOR"));
+        assert!(dir.join("report-wait.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
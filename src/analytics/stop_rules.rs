@@ -0,0 +1,113 @@
+//! Rules which end a headless run early once it's clearly not going anywhere, so a sweep doesn't
+//! spend hours simulating a fleet far too small for its demand. Off unless a limit is given:
+//!
+//! ```toml
+//! [analytics.stop_rules]
+//! max_rejection_rate = 0.5 # Fraction of new demand turned down by the demand policy in a tick
+//! minutes = 30 # Simulated minutes in a row above the limit before the run is stopped
+//! ```
+//!
+//! A stopped run still writes its outputs, with the rule that stopped it as `failed_by_rule` in
+//! its run metadata. Runs with a window open are never stopped.
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct StopRulesConfig {
+    #[serde(default)]
+    pub max_rejection_rate: Option<f64>,
+    #[serde(default = "default_minutes")]
+    pub minutes: u32,
+}
+
+impl Default for StopRulesConfig {
+    fn default() -> Self {
+        StopRulesConfig { max_rejection_rate: None, minutes: default_minutes() }
+    }
+}
+
+fn default_minutes() -> u32 {
+    30
+}
+
+/// Counts for the tick in progress and how long the run has been breaking the rule
+#[derive(Debug, Default)]
+pub struct StopRules {
+    config: StopRulesConfig,
+    requested: u64, // New passengers this tick
+    rejected: u64, // Demand turned down this tick
+    streak_seconds: u32, // Simulated time in a row above the limit
+    fired: bool,
+}
+
+impl StopRules {
+    pub fn new(config: StopRulesConfig) -> Self {
+        StopRules { config, ..Default::default() }
+    }
+
+    pub fn config(&self) -> StopRulesConfig {
+        self.config
+    }
+
+    pub fn requested(&mut self, count: u64) {
+        self.requested += count;
+    }
+
+    pub fn rejected(&mut self, count: u64) {
+        self.rejected += count;
+    }
+
+    // Close off a tick `tick_seconds` long, giving the reason to stop the first time a rule is broken
+    pub fn end_tick(&mut self, tick_seconds: u32) -> Option<String> {
+        let (requested, rejected) = (self.requested, self.rejected);
+        self.requested = 0;
+        self.rejected = 0;
+
+        let limit = self.config.max_rejection_rate?;
+        if self.fired {
+            return None;
+        }
+        // A tick with no demand says nothing either way, so it neither adds to nor breaks the streak
+        let total = requested + rejected;
+        if total == 0 {
+            return None;
+        }
+        if rejected as f64 / total as f64 > limit {
+            self.streak_seconds += tick_seconds;
+        } else {
+            self.streak_seconds = 0;
+        }
+
+        if self.streak_seconds >= self.config.minutes * 60 {
+            self.fired = true;
+            return Some(format!("rejection rate above {} for {} minutes", limit, self.config.minutes));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stops_after_enough_minutes_in_a_row() {
+        let mut rules = StopRules::new(StopRulesConfig { max_rejection_rate: Some(0.5), minutes: 3 });
+        let mut tick = |requested, rejected| {
+            rules.requested(requested);
+            rules.rejected(rejected);
+            rules.end_tick(60)
+        };
+
+        assert_eq!(tick(1, 3), None);
+        assert_eq!(tick(1, 3), None);
+        // Back under the limit starts again, and a quiet tick doesn't count
+        assert_eq!(tick(3, 1), None);
+        assert_eq!(tick(1, 3), None);
+        assert_eq!(tick(0, 0), None);
+        assert_eq!(tick(0, 2), None);
+        assert_eq!(tick(1, 2), Some(String::from("rejection rate above 0.5 for 3 minutes")));
+        // Only reported once
+        assert_eq!(tick(0, 5), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Counts of what's happening across the network for every simulated minute, so the state of
+//! the run can be plotted over the day rather than only as per-passenger and per-vehicle totals.
+//!
+//! Ticks are added into the minute they start in, so a row means the same whatever the tick
+//! length: passengers waiting and in transit are the average over the minute, active vehicles
+//! any which moved during it. Ticks longer than a minute each get a row, at the minute they start.
+
+use std::{collections::HashSet, fs, io::Write, path::Path};
+
+use chrono::{DateTime, DurationRound, Utc};
+
+use super::{PassengerAnalyticsEvent, VehicleAnalyticsEvent};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeseriesRow {
+    pub time: DateTime<Utc>,
+    pub waiting: u32, // Passengers waiting to be picked up
+    pub in_transit: u32, // Passengers on a vehicle
+    pub active_vehicles: u32, // Vehicles which moved
+    pub completed_trips: u64, // Passengers dropped off since the start of the run
+}
+
+// The ticks added so far into the minute starting at `time`
+#[derive(Debug)]
+struct Minute {
+    time: DateTime<Utc>,
+    waiting_seconds: f64, // Passengers waiting times the seconds they waited for
+    in_transit_seconds: f64,
+    seconds: f64, // Simulated time of the ticks
+    active_vehicles: HashSet<u32>,
+    completed_trips: u64, // At the end of the last tick
+}
+
+#[derive(Debug, Default)]
+pub struct Timeseries {
+    pub rows: Vec<TimeseriesRow>, // Minutes which have finished
+    minute: Option<Minute>,
+    waiting: u32,
+    in_transit: u32,
+    active_vehicles: HashSet<u32>,
+    completed_trips: u64,
+}
+
+impl Timeseries {
+    // Passengers send one waiting or in transit event for every tick they spend in that state
+    pub fn record_passenger(&mut self, event: &PassengerAnalyticsEvent) {
+        match event {
+            PassengerAnalyticsEvent::WaitingTick { .. } => self.waiting += 1,
+            PassengerAnalyticsEvent::InTransitTick { .. } => self.in_transit += 1,
+            // Not when a vehicle drops someone off, they might only be changing vehicle
+            PassengerAnalyticsEvent::DroppedOff { .. } => self.completed_trips += 1,
+            _ => {}
+        }
+    }
+
+    pub fn record_vehicle(&mut self, event: &VehicleAnalyticsEvent) {
+        if let VehicleAnalyticsEvent::MovementTick { id, .. } = event {
+            self.active_vehicles.insert(*id);
+        }
+    }
+
+    // Close off the counts for the `tick_seconds` long tick simulating `time`
+    pub fn end_tick(&mut self, time: DateTime<Utc>, tick_seconds: u32) {
+        let start = time.duration_trunc(chrono::Duration::minutes(1)).unwrap_or(time);
+        if self.minute.as_ref().is_some_and(|minute| minute.time != start) {
+            let row = self.row().unwrap();
+            self.rows.push(row);
+            self.minute = None;
+        }
+
+        let minute = self.minute.get_or_insert_with(|| Minute {
+            time: start,
+            waiting_seconds: 0.0,
+            in_transit_seconds: 0.0,
+            seconds: 0.0,
+            active_vehicles: HashSet::new(),
+            completed_trips: 0,
+        });
+        let seconds = tick_seconds as f64;
+        minute.waiting_seconds += self.waiting as f64 * seconds;
+        minute.in_transit_seconds += self.in_transit as f64 * seconds;
+        minute.seconds += seconds;
+        minute.active_vehicles.extend(self.active_vehicles.drain());
+        minute.completed_trips = self.completed_trips;
+        self.waiting = 0;
+        self.in_transit = 0;
+    }
+
+    // Row for the minute still being added to, if there is one
+    fn row(&self) -> Option<TimeseriesRow> {
+        let minute = self.minute.as_ref()?;
+        let average = |total: f64| (total / minute.seconds.max(1.0)).round() as u32;
+        Some(TimeseriesRow {
+            time: minute.time,
+            waiting: average(minute.waiting_seconds),
+            in_transit: average(minute.in_transit_seconds),
+            active_vehicles: minute.active_vehicles.len() as u32,
+            completed_trips: minute.completed_trips,
+        })
+    }
+
+    // Every minute of the run so far, including the last even if it hasn't finished
+    pub fn all_rows(&self) -> Vec<TimeseriesRow> {
+        self.rows.iter().cloned().chain(self.row()).collect()
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "Time,Waiting Passengers,In Transit Passengers,Active Vehicles,Completed Trips")?;
+        for row in self.all_rows() {
+            writeln!(file, "{},{},{},{},{}", row.time.format("%H:%M"), row.waiting, row.in_transit, row.active_vehicles, row.completed_trips)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    #[test]
+    fn counts_reset_each_tick_but_trips_add_up() {
+        let mut timeseries = Timeseries::default();
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+
+        timeseries.record_passenger(&PassengerAnalyticsEvent::WaitingTick { id: 1, waiting_pos: (0.0, 0.0) });
+        timeseries.record_passenger(&PassengerAnalyticsEvent::InTransitTick { id: 2 });
+        timeseries.record_vehicle(&VehicleAnalyticsEvent::MovementTick { id: 5, pos: (0.0, 0.0) });
+        timeseries.record_vehicle(&VehicleAnalyticsEvent::MovementTick { id: 5, pos: (1.0, 0.0) });
+        timeseries.record_vehicle(&VehicleAnalyticsEvent::PassengerDropoff { id: 5, passenger_id: 3 });
+        timeseries.record_passenger(&PassengerAnalyticsEvent::DroppedOff { id: 3, time: start });
+        timeseries.end_tick(start, 60);
+
+        timeseries.record_passenger(&PassengerAnalyticsEvent::InTransitTick { id: 1 });
+        timeseries.end_tick(start + Duration::minutes(1), 60);
+
+        let counts: Vec<_> = timeseries.all_rows().iter().map(|row| (row.waiting, row.in_transit, row.active_vehicles, row.completed_trips)).collect();
+        assert_eq!(counts, vec![(1, 1, 1, 1), (0, 1, 0, 1)]);
+    }
+
+    // Passenger 1 waits all along, passenger 2 rides for the second minute on vehicle 5
+    fn run(timeseries: &mut Timeseries, start: DateTime<Utc>, tick_seconds: u32) {
+        for tick in 0..(180 / tick_seconds) {
+            let time = start + Duration::seconds((tick * tick_seconds) as i64);
+            timeseries.record_passenger(&PassengerAnalyticsEvent::WaitingTick { id: 1, waiting_pos: (0.0, 0.0) });
+            if (60..120).contains(&(tick * tick_seconds)) {
+                timeseries.record_passenger(&PassengerAnalyticsEvent::InTransitTick { id: 2 });
+                timeseries.record_vehicle(&VehicleAnalyticsEvent::MovementTick { id: 5, pos: (0.0, 0.0) });
+            }
+            timeseries.end_tick(time, tick_seconds);
+        }
+    }
+
+    #[test]
+    fn rows_are_per_minute_whatever_the_tick_length() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        let (mut half, mut whole) = (Timeseries::default(), Timeseries::default());
+        run(&mut half, start, 30);
+        run(&mut whole, start, 60);
+
+        assert_eq!(half.all_rows(), whole.all_rows());
+        let counts: Vec<_> = whole.all_rows().iter().map(|row| (row.time.format("%H:%M").to_string(), row.waiting, row.in_transit, row.active_vehicles)).collect();
+        assert_eq!(counts, vec![(String::from("08:00"), 1, 0, 0), (String::from("08:01"), 1, 1, 1), (String::from("08:02"), 1, 0, 0)]);
+    }
+}
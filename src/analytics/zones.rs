@@ -0,0 +1,172 @@
+//! Passenger KPIs split by the zones trips start and end in, so parts of the service design can
+//! be judged on their own (e.g. how well the town centre is served from outlying estates).
+//!
+//! Zones are named polygons in map coordinates, listed in the `[analytics]` config:
+//!
+//! ```toml
+//! [[analytics.zones]]
+//! name = "Town Centre"
+//! points = [[429000.0, 287000.0], [430500.0, 287000.0], [430500.0, 288200.0], [429000.0, 288200.0]]
+//! ```
+//!
+//! A point in more than one zone belongs to the first listed, points in none are `Outside`.
+
+use std::{collections::{BTreeMap, HashMap}, fs, io::Write, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::graph::query::point_in_polygon;
+
+use super::Journey;
+
+pub const OUTSIDE: &str = "Outside";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub points: Vec<(f64, f64)>, // Corners of the polygon in order, it's closed back to the first
+}
+
+impl ZoneConfig {
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        point_in_polygon(point, &self.points)
+    }
+}
+
+pub fn zone_of(zones: &[ZoneConfig], point: (f64, f64)) -> &str {
+    zones.iter().find(|zone| zone.contains(point)).map(|zone| zone.name.as_str()).unwrap_or(OUTSIDE)
+}
+
+/// Totals for the passengers travelling between one pair of zones
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZoneKpis {
+    pub requests: u64,
+    pub picked_up: u64,
+    pub completed: u64,
+    pub wait_minutes: i64, // Request to first pick up, summed over those picked up
+    pub ride_minutes: i64, // First pick up to last drop off, summed over those completed
+    pub decision_minutes: i64, // Request to first DRT assignment, summed over those assigned
+    pub decisions: u64,
+    pub walking_ticks: u64, // To and from the vehicle, summed over every passenger
+}
+
+impl ZoneKpis {
+    pub fn average_wait(&self) -> Option<f64> {
+        average(self.wait_minutes, self.picked_up)
+    }
+
+    pub fn average_ride(&self) -> Option<f64> {
+        average(self.ride_minutes, self.completed)
+    }
+
+    pub fn average_decision(&self) -> Option<f64> {
+        average(self.decision_minutes, self.decisions)
+    }
+
+    pub fn average_walking(&self) -> Option<f64> {
+        average(self.walking_ticks as i64, self.requests)
+    }
+}
+
+fn average(total: i64, count: u64) -> Option<f64> {
+    (count > 0).then(|| total as f64 / count as f64)
+}
+
+// KPIs for every (origin zone, destination zone) pair at least one journey was requested between
+pub fn zone_matrix(
+    zones: &[ZoneConfig],
+    journeys: &HashMap<u32, Journey>,
+    decisions: &HashMap<u32, i64>,
+    walking: &HashMap<u32, (u64, u64)>,
+) -> BTreeMap<(String, String), ZoneKpis> {
+    let mut matrix: BTreeMap<(String, String), ZoneKpis> = BTreeMap::new();
+    for (id, journey) in journeys {
+        let key = (zone_of(zones, journey.origin).to_string(), zone_of(zones, journey.destination).to_string());
+        let kpis = matrix.entry(key).or_default();
+        kpis.requests += 1;
+        if let Some(picked_up) = journey.picked_up {
+            kpis.picked_up += 1;
+            kpis.wait_minutes += (picked_up - journey.requested).num_minutes();
+            if let Some(dropped_off) = journey.dropped_off {
+                kpis.completed += 1;
+                kpis.ride_minutes += (dropped_off - picked_up).num_minutes();
+            }
+        }
+        if let Some(latency) = decisions.get(id) {
+            kpis.decisions += 1;
+            kpis.decision_minutes += latency;
+        }
+        if let Some((start, end)) = walking.get(id) {
+            kpis.walking_ticks += start + end;
+        }
+    }
+    matrix
+}
+
+pub fn write_matrix(matrix: &BTreeMap<(String, String), ZoneKpis>, path: &Path) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    writeln!(file, "Origin Zone,Destination Zone,Requests,Picked Up,Completed,Average Wait (min),Average Ride (min),Average Decision Latency (min),Average Walking Ticks")?;
+    let format = |value: Option<f64>| value.map(|v| format!("{:.1}", v)).unwrap_or_default();
+    for ((origin, destination), kpis) in matrix {
+        writeln!(
+            file,
+            "\"{}\",\"{}\",{},{},{},{},{},{},{}",
+            origin,
+            destination,
+            kpis.requests,
+            kpis.picked_up,
+            kpis.completed,
+            format(kpis.average_wait()),
+            format(kpis.average_ride()),
+            format(kpis.average_decision()),
+            format(kpis.average_walking())
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    #[test]
+    fn journeys_are_grouped_by_the_zones_they_start_and_end_in() {
+        let square = |name: &str, x: f64| ZoneConfig { name: name.to_string(), points: vec![(x, 0.0), (x + 10.0, 0.0), (x + 10.0, 10.0), (x, 10.0)] };
+        let zones = vec![square("Centre", 0.0), square("Hospital", 20.0)];
+        assert_eq!(zone_of(&zones, (5.0, 5.0)), "Centre");
+        assert_eq!(zone_of(&zones, (25.0, 9.0)), "Hospital");
+        assert_eq!(zone_of(&zones, (15.0, 5.0)), OUTSIDE);
+
+        let start = Utc::now();
+        let journey = |origin, destination, picked_up: Option<i64>, dropped_off: Option<i64>| Journey {
+            requested: start,
+            origin,
+            destination,
+            picked_up: picked_up.map(|m| start + Duration::minutes(m)),
+            dropped_off: dropped_off.map(|m| start + Duration::minutes(m)),
+            ..Default::default()
+        };
+        let journeys = HashMap::from([
+            (1, journey((1.0, 1.0), (21.0, 1.0), Some(4), Some(10))),
+            (2, journey((2.0, 2.0), (22.0, 2.0), Some(8), None)),
+            (3, journey((50.0, 50.0), (2.0, 2.0), None, None)),
+        ]);
+        let decisions = HashMap::from([(1, 2), (2, 4)]);
+        let walking = HashMap::from([(1, (3, 1)), (3, (2, 0))]);
+
+        let matrix = zone_matrix(&zones, &journeys, &decisions, &walking);
+        assert_eq!(matrix.len(), 2);
+
+        let centre_to_hospital = &matrix[&("Centre".to_string(), "Hospital".to_string())];
+        assert_eq!((centre_to_hospital.requests, centre_to_hospital.picked_up, centre_to_hospital.completed), (2, 2, 1));
+        assert_eq!(centre_to_hospital.average_wait(), Some(6.0));
+        assert_eq!(centre_to_hospital.average_ride(), Some(6.0));
+        assert_eq!(centre_to_hospital.average_decision(), Some(3.0));
+        assert_eq!(centre_to_hospital.average_walking(), Some(2.0));
+
+        let outside = &matrix[&(OUTSIDE.to_string(), "Centre".to_string())];
+        assert_eq!((outside.requests, outside.average_wait()), (1, None));
+    }
+}
@@ -0,0 +1,220 @@
+//! Batch experiments: run the simulation headless for every combination of a parameter sweep.
+//!
+//! The batch file is TOML, e.g.
+//!
+//! ```toml
+//! config = "data/config.toml"
+//! results_dir = "data/output/batch"
+//! repeats = 3
+//! seed = 42 # Seed of the first repeat, the config's [simulation] seed or a random one if not given
+//! start_time = "06:45:00"
+//! end_time = "19:45:00"
+//!
+//! [sweep]
+//! agent_counts = [50, 100, 200]
+//! demand_scales = [0.1, 0.2]
+//! static_only = [false, true]
+//! ```
+//!
+//! Repeats of a combination run with seeds counting up from the batch's, so every combination gets
+//! the same set of seeds and each repeat is a different draw. Each run writes its analytics into its
+//! own directory under `results_dir/<timestamp>/`, and `runs.csv` in the same place lists the
+//! parameters, seed and outcome of every run. Once the sweep is
+//! done `report.md` and `report.html` compare the runs, see [`crate::analytics::report`].
+
+use std::{error::Error, fs, io::Write, path::{Path, PathBuf}, time::Instant};
+
+use chrono::NaiveTime;
+use serde::Deserialize;
+
+use crate::{gui::onboarding::SettingOverrides, HeadlessRunner};
+
+#[derive(Deserialize, Debug)]
+pub struct BatchFile {
+    #[serde(default = "default_config")]
+    pub config: String, // Config file every run uses
+    #[serde(default = "default_results_dir")]
+    pub results_dir: String,
+    #[serde(default = "default_repeats")]
+    pub repeats: usize, // Runs of each combination
+    pub seed: Option<u64>, // Seed of the first repeat, the next repeat uses the one after and so on
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    #[serde(default)]
+    pub sweep: Sweep,
+}
+
+fn or_initial<T: Clone>(list: &[T], initial: T) -> Vec<T> {
+    if list.is_empty() { vec![initial] } else { list.to_vec() }
+}
+
+fn default_config() -> String {
+    String::from("data/config.toml")
+}
+
+fn default_results_dir() -> String {
+    String::from("data/output/batch")
+}
+
+fn default_repeats() -> usize {
+    1
+}
+
+// Empty lists use the onboarding default for that setting
+#[derive(Deserialize, Debug, Default)]
+pub struct Sweep {
+    #[serde(default)]
+    pub agent_counts: Vec<usize>,
+    #[serde(default)]
+    pub demand_scales: Vec<f64>,
+    #[serde(default)]
+    pub static_only: Vec<bool>,
+}
+
+pub struct BatchRunner {
+    batch: BatchFile,
+    source: String, // The batch file as written, kept with the results so the report can describe it
+    seed: u64, // Seed of each combination's first repeat
+}
+
+impl BatchRunner {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read_to_string(path)?;
+        let batch: BatchFile = toml::from_str(&data)?;
+        let seed = match batch.seed {
+            Some(seed) => seed,
+            None => crate::resource::peek_seed(Path::new(&batch.config))?.unwrap_or_else(rand::random),
+        };
+        Ok(BatchRunner { batch, source: data, seed })
+    }
+
+    // Settings for every run in the sweep, in the order they are run
+    pub fn runs(&self) -> Vec<SettingOverrides> {
+        let initial = SettingOverrides::initial();
+        let statics = or_initial(&self.batch.sweep.static_only, initial.is_static);
+        let scales = or_initial(&self.batch.sweep.demand_scales, initial.demand_scale);
+        let agents = or_initial(&self.batch.sweep.agent_counts, initial.num_agents);
+
+        let mut runs = Vec::new();
+        for &is_static in statics.iter() {
+            for &demand_scale in scales.iter() {
+                for &num_agents in agents.iter() {
+                    for repeat in 0..self.batch.repeats.max(1) {
+                        runs.push(SettingOverrides {
+                            is_static,
+                            num_agents,
+                            demand_scale,
+                            config_file_path: self.batch.config.clone(),
+                            start_time: self.batch.start_time.unwrap_or(initial.start_time),
+                            end_time: self.batch.end_time.unwrap_or(initial.end_time),
+                            objective: initial.objective,
+                            graph_key: None,
+                            seed: Some(self.seed.wrapping_add(repeat as u64)),
+                        });
+                    }
+                }
+            }
+        }
+        runs
+    }
+
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        let runs = self.runs();
+        let batch_dir = PathBuf::from(&self.batch.results_dir).join(chrono::Local::now().format("%Y-%m-%d-%H-%M-%S").to_string());
+        fs::create_dir_all(&batch_dir)?;
+        fs::write(batch_dir.join("batch.toml"), &self.source)?;
+
+        let mut index = fs::File::create(batch_dir.join("runs.csv"))?;
+        writeln!(index, "Run,Static,Agents,Demand Scale,Directory,Seconds,Result,Seed")?;
+
+        println!("[Batch] Running {} simulations into {:?}", runs.len(), batch_dir);
+
+        for (i, settings) in runs.into_iter().enumerate() {
+            let name = format!(
+                "run-{:03}-{}-a{}-s{}",
+                i + 1,
+                if settings.is_static { "static" } else { "dynamic" },
+                settings.num_agents,
+                settings.demand_scale
+            );
+            let run_dir = batch_dir.join(&name);
+            println!("[Batch] Starting {}", name);
+
+            let timer = Instant::now();
+            let result = HeadlessRunner::new(PathBuf::from(&self.batch.config), Some(settings.clone()), Some(run_dir))
+                .and_then(|runner| runner.run());
+
+            // A failed run is recorded and the rest of the sweep carries on
+            let result = match result {
+                Ok(None) => String::from("ok"),
+                Ok(Some(rule)) => {
+                    println!("[Batch] {} stopped early: {}", name, rule);
+                    format!("\"stopped: {}\"", rule)
+                }
+                Err(err) => {
+                    eprintln!("[Batch] {} failed: {}", name, err);
+                    format!("\"error: {}\"", err.to_string().replace('"', "'"))
+                }
+            };
+
+            writeln!(
+                index,
+                "{},{},{},{},{},{:.1},{},{}",
+                i + 1,
+                settings.is_static,
+                settings.num_agents,
+                settings.demand_scale,
+                name,
+                timer.elapsed().as_secs_f64(),
+                result,
+                settings.seed.unwrap_or_default()
+            )?;
+        }
+
+        println!("[Batch] Finished, results in {:?}", batch_dir);
+        drop(index);
+        match write_report(&batch_dir) {
+            Ok(path) => println!("[Batch] Report written to {:?}", path),
+            Err(err) => eprintln!("[Batch] Couldn't write the report: {}", err),
+        }
+        Ok(())
+    }
+}
+
+// Settings of the batch that ran into `batch_dir`, for the top of its report
+fn describe(batch: &BatchFile) -> Vec<(String, String)> {
+    let list = |values: Vec<String>| if values.is_empty() { String::from("onboarding default") } else { values.join(", ") };
+    let time = |time: Option<NaiveTime>| time.map(|time| time.to_string()).unwrap_or_else(|| String::from("onboarding default"));
+    vec![
+        (String::from("Config"), batch.config.clone()),
+        (String::from("Repeats"), batch.repeats.max(1).to_string()),
+        (String::from("Seed"), batch.seed.map_or_else(|| String::from("config or random, see runs.csv"), |seed| seed.to_string())),
+        (String::from("Start time"), time(batch.start_time)),
+        (String::from("End time"), time(batch.end_time)),
+        (String::from("Agent counts"), list(batch.sweep.agent_counts.iter().map(|n| n.to_string()).collect())),
+        (String::from("Demand scales"), list(batch.sweep.demand_scales.iter().map(|n| n.to_string()).collect())),
+        (String::from("Static only"), list(batch.sweep.static_only.iter().map(|n| n.to_string()).collect())),
+    ]
+}
+
+// (Re)write the comparison report of a finished batch, returning the HTML report's path
+pub fn write_report(batch_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let settings = match fs::read_to_string(batch_dir.join("batch.toml")) {
+        Ok(data) => describe(&toml::from_str(&data)?),
+        Err(_) => vec![(String::from("Batch file"), String::from("not saved with these results"))],
+    };
+    crate::analytics::report::write_report(batch_dir, &settings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repeats_run_with_their_own_seeds() {
+        let source = String::from("repeats = 3\n[sweep]\nagent_counts = [50, 100]");
+        let runner = BatchRunner { batch: toml::from_str(&source).unwrap(), source, seed: 42 };
+        let seeds: Vec<_> = runner.runs().iter().map(|run| (run.num_agents, run.seed.unwrap())).collect();
+        assert_eq!(seeds, vec![(50, 42), (50, 43), (50, 44), (100, 42), (100, 43), (100, 44)]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Command line arguments, so a run can be set up without the onboarding window
+
+use std::path::PathBuf;
+
+use chrono::NaiveTime;
+
+use crate::gui::onboarding::SettingOverrides;
+
+pub const USAGE: &str = "\
+Usage: odbrs [OPTIONS]
+       odbrs build-network [--config <PATH>]
+
+Options:
+  --static                    Use static (fixed route) agents
+  --dynamic                   Use dynamic (DRT) agents
+  --agents <N>                Number of dynamic agents
+  --demand-scale <SCALE>      Scale factor for generated demand
+  --start <HH:MM[:SS]>        Simulation start time
+  --end <HH:MM[:SS]>          Simulation end time
+  --config <PATH>             Config file (default data/config.toml)
+  --no-onboarding             Skip the onboarding window
+  --headless                  Run to the end time with no windows, only writing analytics
+  --export-time-expanded [DIR]  Write the static network as a time-expanded graph and exit
+  --batch <FILE>              Run every combination of a parameter sweep headless and exit
+  --report <BATCH_DIR>        Rewrite the comparison report of a finished batch and exit
+  --export-scenario <FILE>    Bundle the config and its demand images into one file and exit
+  --import-scenario <FILE>    Unpack a scenario bundle into data/ and exit
+  --export-graph <FILE>       Write the road graph as GeoJSON to edit or inspect in QGIS and exit
+  --bench-routing [PAIRS]     Time Dijkstra against A* on random routes (default 200) and exit
+  --rasterise-demand <CSV> [WIDTH]  Turn weighted points into a demand image (default 512px wide) and exit
+  --help                      Show this message
+
+Commands:
+  build-network               Convert the [gtfs] feed in the config into bus network data and exit
+
+Giving any of the setting options skips onboarding, settings which aren't given use the
+onboarding defaults. With no setting options and no onboarding the config file is used as is.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    ExportTimeExpanded(PathBuf),
+    Batch(PathBuf),
+    Report(PathBuf),
+    ExportScenario(PathBuf),
+    ImportScenario(PathBuf),
+    ExportGraph(PathBuf),
+    BenchRouting(usize),
+    RasteriseDemand(PathBuf, u32),
+    BuildNetwork,
+    Help,
+}
+
+#[derive(Debug, Clone)]
+pub struct CliOptions {
+    pub command: Command,
+    pub headless: bool,
+    pub onboarding: bool, // Show the onboarding window to pick the settings
+    pub overrides: Option<SettingOverrides>, // Settings given on the command line
+    pub config: Option<PathBuf>, // Config file given on the command line, kept apart so it doesn't fill in the other settings
+}
+
+impl CliOptions {
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter().peekable();
+
+        let mut command = Command::Run;
+        let mut headless = false;
+        let mut onboarding = true;
+        let mut overrides: Option<SettingOverrides> = None;
+        let mut config = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => command = Command::Help,
+                "build-network" => command = Command::BuildNetwork,
+                "--headless" => headless = true,
+                "--no-onboarding" => onboarding = false,
+                "--export-time-expanded" => {
+                    // The directory is optional so don't eat the next flag
+                    let dir = match args.peek() {
+                        Some(next) if !next.starts_with("--") => args.next().unwrap(),
+                        _ => String::from("data/output/time-expanded"),
+                    };
+                    command = Command::ExportTimeExpanded(PathBuf::from(dir));
+                }
+                "--bench-routing" => {
+                    let pairs = match args.peek() {
+                        Some(next) if !next.starts_with("--") => {
+                            let value = args.next().unwrap();
+                            value.parse().map_err(|_| format!("Invalid number of routes '{}'", value))?
+                        }
+                        _ => 200,
+                    };
+                    command = Command::BenchRouting(pairs);
+                }
+                "--rasterise-demand" => {
+                    let points = PathBuf::from(value(&arg, args.next())?);
+                    let width = match args.peek() {
+                        Some(next) if !next.starts_with("--") => {
+                            let value = args.next().unwrap();
+                            value.parse().map_err(|_| format!("Invalid image width '{}'", value))?
+                        }
+                        _ => crate::resource::demand_image::DEFAULT_WIDTH,
+                    };
+                    command = Command::RasteriseDemand(points, width);
+                }
+                "--batch" => command = Command::Batch(PathBuf::from(value(&arg, args.next())?)),
+                "--report" => command = Command::Report(PathBuf::from(value(&arg, args.next())?)),
+                "--export-scenario" => command = Command::ExportScenario(PathBuf::from(value(&arg, args.next())?)),
+                "--import-scenario" => command = Command::ImportScenario(PathBuf::from(value(&arg, args.next())?)),
+                "--export-graph" => command = Command::ExportGraph(PathBuf::from(value(&arg, args.next())?)),
+                "--static" => overrides.get_or_insert_with(SettingOverrides::initial).is_static = true,
+                "--dynamic" => overrides.get_or_insert_with(SettingOverrides::initial).is_static = false,
+                "--agents" => {
+                    let value = value(&arg, args.next())?;
+                    overrides.get_or_insert_with(SettingOverrides::initial).num_agents =
+                        value.parse().map_err(|_| format!("Invalid agent count '{}'", value))?;
+                }
+                "--demand-scale" => {
+                    let value = value(&arg, args.next())?;
+                    overrides.get_or_insert_with(SettingOverrides::initial).demand_scale =
+                        value.parse().map_err(|_| format!("Invalid demand scale '{}'", value))?;
+                }
+                "--start" => {
+                    let value = value(&arg, args.next())?;
+                    overrides.get_or_insert_with(SettingOverrides::initial).start_time = parse_time(&value)?;
+                }
+                "--end" => {
+                    let value = value(&arg, args.next())?;
+                    overrides.get_or_insert_with(SettingOverrides::initial).end_time = parse_time(&value)?;
+                }
+                "--config" => config = Some(PathBuf::from(value(&arg, args.next())?)),
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+
+        if headless || overrides.is_some() {
+            onboarding = false;
+        }
+        if let (Some(overrides), Some(config)) = (overrides.as_mut(), &config) {
+            overrides.config_file_path = config.to_string_lossy().into_owned();
+        }
+
+        Ok(CliOptions { command, headless, onboarding, overrides, config })
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.config.clone().unwrap_or_else(|| PathBuf::from(r#"data/config.toml"#))
+    }
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M"))
+        .map_err(|_| format!("Invalid time '{}', expected HH:MM or HH:MM:SS", value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliOptions, String> {
+        CliOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_shows_onboarding() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert!(options.onboarding);
+        assert!(options.overrides.is_none());
+    }
+
+    #[test]
+    fn settings_skip_onboarding_and_fill_defaults() {
+        let options = parse(&["--static", "--start", "07:30", "--demand-scale", "0.5"]).unwrap();
+        let overrides = options.overrides.unwrap();
+
+        assert!(!options.onboarding);
+        assert!(overrides.is_static);
+        assert_eq!(overrides.start_time, NaiveTime::from_hms_opt(7, 30, 0).unwrap());
+        assert_eq!(overrides.demand_scale, 0.5);
+        assert_eq!(overrides.num_agents, SettingOverrides::initial().num_agents);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        assert!(parse(&["--agents", "lots"]).is_err());
+        assert!(parse(&["--end"]).is_err());
+        assert!(parse(&["--fast"]).is_err());
+    }
+
+    #[test]
+    fn build_network_uses_the_given_config() {
+        let options = parse(&["build-network", "--config", "data/other.toml"]).unwrap();
+        assert_eq!(options.command, Command::BuildNetwork);
+        assert_eq!(options.config_path(), PathBuf::from("data/other.toml"));
+    }
+
+    #[test]
+    fn config_alone_keeps_the_config_files_settings() {
+        let options = parse(&["--config", "data/other.toml", "--no-onboarding"]).unwrap();
+        assert!(options.overrides.is_none());
+        assert_eq!(options.config_path(), PathBuf::from("data/other.toml"));
+
+        let options = parse(&["--config", "data/other.toml", "--agents", "20"]).unwrap();
+        assert_eq!(options.overrides.unwrap().config_file_path, "data/other.toml");
+    }
+}
@@ -0,0 +1,211 @@
+//! Publish/subscribe for the events the simulation sends out, so a new consumer (a recorder,
+//! status endpoint, alerting) subscribes to the topic it wants instead of needing its own
+//! sender threaded through the simulation and every agent.
+//!
+//! Each message type is its own topic. Every subscriber gets every event published after it
+//! subscribed, in a bounded queue with a drop policy saying what happens when it falls behind:
+//! the analytics can't lose events so they `Block` the publisher, the GUI only needs the latest
+//! state so it uses `DropOldest`. Commands sent *to* the simulation have a single receiver and
+//! stay on their own channel.
+
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{RecvTimeoutError, TryRecvError},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    #[default]
+    Block, // The publisher waits for room, nothing is lost
+    DropNewest, // The event being published is thrown away
+    DropOldest, // The oldest queued event makes room for it
+}
+
+struct Queue<T> {
+    items: Mutex<VecDeque<T>>,
+    changed: Condvar,
+    capacity: usize,
+    policy: DropPolicy,
+    dropped: AtomicU64,
+    closed: AtomicBool, // The subscriber has gone, so there's nobody to deliver to
+}
+
+impl<T> Queue<T> {
+    fn push(&self, event: T) {
+        let mut items = self.items.lock().unwrap();
+        if items.len() >= self.capacity {
+            match self.policy {
+                DropPolicy::Block => {
+                    while items.len() >= self.capacity && !self.closed.load(Ordering::SeqCst) {
+                        items = self.changed.wait(items).unwrap();
+                    }
+                }
+                DropPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                DropPolicy::DropOldest => {
+                    items.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        items.push_back(event);
+        self.changed.notify_all();
+    }
+}
+
+/// The receiving end of one subscription
+pub struct Subscriber<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> Subscriber<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let event = self.queue.items.lock().unwrap().pop_front();
+        self.queue.changed.notify_all();
+        event.ok_or(TryRecvError::Empty)
+    }
+
+    // Topics live as long as the bus, so this only ever times out rather than disconnecting
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut items = self.queue.items.lock().unwrap();
+        loop {
+            if let Some(event) = items.pop_front() {
+                self.queue.changed.notify_all();
+                return Ok(event);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            items = self.queue.changed.wait_timeout(items, deadline - now).unwrap().0;
+        }
+    }
+
+    // Events lost to the drop policy so far
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        // Wakes any publisher blocked on this queue
+        self.queue.closed.store(true, Ordering::SeqCst);
+        self.queue.changed.notify_all();
+    }
+}
+
+/// Every subscriber to one message type
+pub struct Topic<T> {
+    subscribers: Arc<Mutex<Vec<Arc<Queue<T>>>>>,
+}
+
+impl<T> Clone for Topic<T> {
+    fn clone(&self) -> Self {
+        Topic { subscribers: self.subscribers.clone() }
+    }
+}
+
+impl<T> std::fmt::Debug for Topic<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Topic({} subscribers)", self.subscribers.lock().unwrap().len())
+    }
+}
+
+impl<T> Default for Topic<T> {
+    fn default() -> Self {
+        Topic { subscribers: Arc::new(Mutex::new(Vec::new())) }
+    }
+}
+
+impl<T: Clone> Topic<T> {
+    pub fn publish(&self, event: T) {
+        // Not held while pushing, so a blocked queue doesn't stop anyone subscribing
+        let subscribers: Vec<_> = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|queue| !queue.closed.load(Ordering::SeqCst));
+            subscribers.clone()
+        };
+        if let Some((last, rest)) = subscribers.split_last() {
+            for queue in rest {
+                queue.push(event.clone());
+            }
+            last.push(event);
+        }
+    }
+
+    pub fn subscribe(&self, capacity: usize, policy: DropPolicy) -> Subscriber<T> {
+        let queue = Arc::new(Queue {
+            items: Mutex::new(VecDeque::new()),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        });
+        self.subscribers.lock().unwrap().push(queue.clone());
+        Subscriber { queue }
+    }
+}
+
+/// Topics for any number of message types, cheap to clone and share between threads
+#[derive(Clone, Default)]
+pub struct EventBus {
+    topics: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>>,
+}
+
+impl EventBus {
+    pub fn topic<T: Clone + Send + 'static>(&self) -> Topic<T> {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Topic::<T>::default()))
+            .downcast_ref::<Topic<T>>()
+            .expect("Topic stored under the wrong type")
+            .clone()
+    }
+
+    pub fn subscribe<T: Clone + Send + 'static>(&self, capacity: usize, policy: DropPolicy) -> Subscriber<T> {
+        self.topic::<T>().subscribe(capacity, policy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscribers_each_get_events_by_their_policy() {
+        let bus = EventBus::default();
+        let newest = bus.subscribe::<u32>(2, DropPolicy::DropOldest);
+        let oldest = bus.subscribe::<u32>(2, DropPolicy::DropNewest);
+        let other = bus.subscribe::<&'static str>(2, DropPolicy::Block);
+
+        let (numbers, ticks) = (bus.topic::<u32>(), bus.topic::<&'static str>());
+        for i in 0..4 {
+            numbers.publish(i);
+        }
+        ticks.publish("tick");
+
+        let drain = |subscriber: &Subscriber<u32>| std::iter::from_fn(|| subscriber.try_recv().ok()).collect::<Vec<_>>();
+        assert_eq!(drain(&newest), vec![2, 3]);
+        assert_eq!(drain(&oldest), vec![0, 1]);
+        assert_eq!((newest.dropped(), oldest.dropped()), (2, 2));
+        assert_eq!(other.try_recv(), Ok("tick"));
+
+        // Nobody left to deliver to, so a full blocking subscriber that's gone doesn't hold anything up
+        drop(other);
+        for _ in 0..4 {
+            ticks.publish("tick");
+        }
+    }
+}
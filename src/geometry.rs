@@ -0,0 +1,123 @@
+//! Distances between map coordinates. Graphs are in British National Grid metres (EPSG:27700)
+//! unless the graph config says otherwise. Graphs in latitude and longitude, e.g. imported from
+//! OpenStreetMap, are measured along the Earth's surface instead, so they work without being
+//! reprojected first:
+//!
+//! ```toml
+//! [graph]
+//! crs = "EPSG:4326" # Points are (longitude, latitude) in degrees
+//! ```
+//!
+//! The CRS goes with the graph (`Graph::crs`), and every distance in the simulation is measured
+//! by it. Bus stops, request logs and live vehicles are projected to match. Nearest node searches
+//! still compare coordinates directly, which is near enough at the scale of a town, but searches
+//! within so many metres of a point are widened to cover them in degrees and measured properly.
+
+use proj::Proj;
+use serde::{Deserialize, Serialize};
+
+use crate::units::Meters;
+
+const EARTH_RADIUS: f64 = 6_371_008.8; // Mean radius (m)
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Crs {
+    #[default]
+    #[serde(rename = "EPSG:27700")]
+    BritishNationalGrid,
+    #[serde(rename = "EPSG:4326")]
+    Wgs84,
+}
+
+impl Crs {
+    pub fn code(self) -> &'static str {
+        match self {
+            Crs::BritishNationalGrid => "EPSG:27700",
+            Crs::Wgs84 => "EPSG:4326",
+        }
+    }
+
+    pub fn is_geographic(self) -> bool {
+        self == Crs::Wgs84
+    }
+
+    // Metres between two map coordinates
+    pub fn distance(self, a: (f64, f64), b: (f64, f64)) -> f64 {
+        match self {
+            Crs::BritishNationalGrid => euclidean(a, b),
+            Crs::Wgs84 => haversine(a, b),
+        }
+    }
+
+    // The point `metres` from `from` on the way to `to`
+    pub fn towards(self, from: (f64, f64), to: (f64, f64), metres: Meters) -> (f64, f64) {
+        let length = Meters::between(self, from, to);
+        if length <= Meters::ZERO {
+            return from;
+        }
+        let fraction = metres / length;
+        (from.0 + (to.0 - from.0) * fraction, from.1 + (to.1 - from.1) * fraction)
+    }
+
+    // How far apart in coordinates points up to `metres` from `point` can be, for searching an
+    // index of coordinates. In degrees it's the longitude at that latitude, the wider of the two
+    pub fn span(self, metres: f64, point: (f64, f64)) -> f64 {
+        match self {
+            Crs::BritishNationalGrid => metres,
+            Crs::Wgs84 => (metres / (EARTH_RADIUS * point.1.to_radians().cos().max(f64::EPSILON))).to_degrees(),
+        }
+    }
+
+    // Projection from latitude and longitude onto a map in this CRS, None if it's in them already
+    pub fn from_wgs84(self) -> Result<Option<Proj>, String> {
+        match self {
+            Crs::Wgs84 => Ok(None),
+            crs => Proj::new_known_crs("EPSG:4326", crs.code(), None)
+                .map(Some)
+                .map_err(|err| format!("Couldn't set up the {} projection: {:?}", crs.code(), err)),
+        }
+    }
+}
+
+pub fn euclidean(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+// Great circle distance in metres between two (longitude, latitude) points in degrees
+pub fn haversine(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.1.to_radians(), b.1.to_radians());
+    let half_lat = (lat_b - lat_a) / 2.0;
+    let half_lon = (b.0 - a.0).to_radians() / 2.0;
+    let h = half_lat.sin().powi(2) + lat_a.cos() * lat_b.cos() * half_lon.sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+// A (longitude, latitude) point on the map, projected with the projection from `Crs::from_wgs84`
+pub fn onto_map(proj: Option<&Proj>, point: (f64, f64)) -> Result<(f64, f64), String> {
+    match proj {
+        Some(proj) => proj.convert(point).map_err(|err| format!("Couldn't project {:?} onto the map: {:?}", point, err)),
+        None => Ok(point),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn haversine_measures_along_the_earth() {
+        // A degree of latitude is about 111km anywhere, a degree of longitude shrinks going north
+        let degree = haversine((0.0, 52.0), (0.0, 53.0));
+        assert!((degree - 111_195.0).abs() < 1.0, "{}", degree);
+        let east = haversine((-1.5, 52.4), (-0.5, 52.4));
+        assert!((east - degree * 52.4_f64.to_radians().cos()).abs() < 100.0, "{}", east);
+        assert_eq!(haversine((-1.5, 52.4), (-1.5, 52.4)), 0.0);
+        assert_eq!(euclidean((0.0, 0.0), (30.0, 40.0)), 50.0);
+
+        // A search 1km round a point in degrees covers the kilometre east, the shortest way
+        let span = Crs::Wgs84.span(1000.0, (-1.5, 52.4));
+        assert!(haversine((-1.5, 52.4), (-1.5 + span, 52.4)) >= 999.0);
+        assert!(haversine((-1.5, 52.4), (-1.5, 52.4 + span)) > 1000.0);
+        assert_eq!(Crs::BritishNationalGrid.span(1000.0, (429000.0, 287000.0)), 1000.0);
+    }
+}
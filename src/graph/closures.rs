@@ -0,0 +1,59 @@
+//! Roads closed for a scenario, e.g. a town centre made bus free or no motorway running. Closed
+//! roads are still drawn but nothing is routed along them. Set in the graph config:
+//!
+//! ```toml
+//! [graph.closures]
+//! classes = ["Motorway"] # Every road of these classes
+//! areas = [[[429000.0, 287000.0], [430500.0, 287000.0], [430500.0, 288200.0]]] # Every road with any part inside
+//! ```
+
+use serde::Deserialize;
+
+use super::{EdgeClass, EdgeId, Graph};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ClosureConfig {
+    #[serde(default)]
+    pub classes: Vec<EdgeClass>,
+    #[serde(default)]
+    pub areas: Vec<Vec<(f64, f64)>>, // Polygons in map coordinates
+}
+
+impl Graph {
+    // Take the closed roads out of the adjacency so routes can't use them, giving how many were closed
+    pub(super) fn close_roads(&mut self, closures: &ClosureConfig) -> usize {
+        let mut closed: Vec<EdgeId> = closures.classes.iter().flat_map(|class| self.edges_by_class(class)).collect();
+        closed.extend(closures.areas.iter().flat_map(|area| self.edges_intersecting(area)));
+        closed.sort();
+        closed.dedup();
+
+        for edges in self.graph.adjacency.values_mut() {
+            edges.retain(|edge| closed.binary_search(edge).is_err());
+        }
+        closed.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::{route_finding::find_route, synthetic::grid};
+
+    use super::*;
+
+    #[test]
+    fn routes_go_round_closed_roads() {
+        // 5 x 5 junctions 100m apart, node ids counting along each row
+        let mut graph = grid(5, 100.0);
+        assert_eq!(find_route(&graph, 1, 2).len(), 2);
+
+        let across = vec![(120.0, -10.0), (180.0, -10.0), (180.0, 10.0), (120.0, 10.0)];
+        let closed = graph.close_roads(&ClosureConfig { classes: vec![], areas: vec![across] });
+        assert_eq!(closed, 1);
+        // Up, along and back down instead
+        assert_eq!(find_route(&graph, 1, 2).len(), 4);
+
+        // The synthetic roads have no class, so closing it closes all of them
+        graph.close_roads(&ClosureConfig { classes: vec![EdgeClass::default()], areas: vec![] });
+        assert!(graph.get_adjacency().values().all(Vec::is_empty));
+    }
+}
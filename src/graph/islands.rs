@@ -0,0 +1,88 @@
+//! Dropping the parts of the road graph which aren't connected to the rest. Cutting the graph to
+//! its bounds leaves roads which only joined up outside them, and closures can cut off more, so
+//! passengers there could never be routed anywhere else. Only the largest connected part is kept,
+//! after the closures, unless the islands are wanted:
+//!
+//! ```toml
+//! [graph]
+//! keep_islands = true
+//! ```
+//!
+//! Roads are driven both ways, so the connected parts are the strongly connected ones too. What
+//! was removed is printed as the graph loads.
+
+use std::collections::HashSet;
+
+use super::{route_finding, Graph, NodeId};
+
+/// What was removed to leave only the largest connected part
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Islands {
+    pub parts: usize,
+    pub nodes: usize,
+    pub edges: usize,
+    pub largest: usize, // Nodes in the biggest part removed
+}
+
+impl Graph {
+    // Remove every node and edge outside the connected part with the most nodes, the one with the
+    // lowest node id if there's a tie
+    pub(super) fn remove_islands(&mut self) -> Islands {
+        let labels = route_finding::components(self);
+        let mut sizes: Vec<usize> = Vec::new();
+        for label in labels.values() {
+            if *label >= sizes.len() {
+                sizes.resize(label + 1, 0);
+            }
+            sizes[*label] += 1;
+        }
+        let Some(keep) = (0..sizes.len()).max_by(|a, b| sizes[*a].cmp(&sizes[*b]).then(b.cmp(a))) else {
+            return Islands::default();
+        };
+        let removed: HashSet<NodeId> = labels.iter().filter(|(_, label)| **label != keep).map(|(node, _)| *node).collect();
+        if removed.is_empty() {
+            return Islands::default();
+        }
+
+        let edges = self.graph.edge_map.len();
+        self.graph.node_map.retain(|node, _| !removed.contains(node));
+        self.graph.adjacency.retain(|node, _| !removed.contains(node));
+        self.graph.edge_map.retain(|_, edge| !removed.contains(&edge.start_id) && !removed.contains(&edge.end_id));
+        let edge_map = &self.graph.edge_map;
+        self.simplified.retain_merged(|edge| edge_map.contains_key(&edge));
+
+        Islands {
+            parts: sizes.len() - 1,
+            nodes: removed.len(),
+            edges: edges - self.graph.edge_map.len(),
+            largest: sizes.iter().enumerate().filter(|(label, _)| *label != keep).map(|(_, size)| *size).max().unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::{route_finding::find_route, synthetic::grid, EdgeMeta, NodeMeta};
+
+    use super::*;
+
+    #[test]
+    fn only_the_largest_part_is_kept() {
+        // 3 x 3 junctions 100m apart, and a road on its own off to the side
+        let mut graph = grid(3, 100.0);
+        let edges = graph.get_edgelist().len();
+        for (id, point) in [(100, (1000.0, 0.0)), (101, (1100.0, 0.0))] {
+            graph.graph.node_map.insert(id, NodeMeta { point, id, ..Default::default() });
+        }
+        let points = vec![(1000.0, 0.0), (1100.0, 0.0)];
+        graph.graph.edge_map.insert(200, EdgeMeta { points, start_id: 100, end_id: 101, id: 200, length: 100.0, ..Default::default() });
+        graph.graph.adjacency.insert(100, vec![200]);
+        graph.graph.adjacency.insert(101, vec![200]);
+
+        assert_eq!(graph.remove_islands(), Islands { parts: 1, nodes: 2, edges: 1, largest: 2 });
+        assert_eq!((graph.get_nodelist().len(), graph.get_edgelist().len()), (9, edges));
+        assert!(!graph.get_adjacency().contains_key(&100));
+        assert_eq!(find_route(&graph, 0, 8).len(), 5);
+        assert_eq!(graph.remove_islands(), Islands::default());
+    }
+}
@@ -1,21 +1,30 @@
 use std::{sync::RwLock, collections::HashMap};
 
 use eframe::{
-    egui::{Ui, Response, Painter},
+    egui::{Ui, Response},
     epaint::{Shape, Stroke},
 };
 
+use chrono::NaiveTime;
 use serde::Deserialize;
 
-use crate::Module;
+use crate::{geometry::Crs, Module};
 
 pub use bounding::*;
 pub use types::*;
 
 pub mod bounding;
+pub mod closures;
+pub mod islands;
 pub mod transform;
 pub mod types;
+pub mod query;
 pub mod route_finding;
+pub mod simplify;
+pub mod spatial;
+pub mod speeds;
+pub mod synthetic;
+pub mod validate;
 
 /// Graph is the underlying data that the display and simulation use
 /// It's loaded with data by the resource loader
@@ -28,6 +37,12 @@ pub struct Graph {
     graph: AdjacencyList,
     transform: RwLock<transform::Transform>,
     config: GraphConfig,
+    landmarks: route_finding::Landmarks, // Precomputed for shortest_path_fast
+    node_index: spatial::KdTree<u128>, // Node positions for nearest_node
+    node_ids: Vec<u128>, // Every node in id order, for picking nodes at random the same way each run
+    components: HashMap<u128, usize>, // Which connected part of the graph each node is in
+    simplified: simplify::Simplified, // Edges merged while loading, for snapping onto the roads as they were
+    speeds: speeds::Speeds, // Road speeds by class and time of day
 }
 
 impl Module for Graph {
@@ -48,6 +63,39 @@ impl Module for Graph {
 
         self.graph = parameters;
         self.config = config;
+        self.speeds = speeds::Speeds::from_config(&self.config.speeds)?;
+
+        let problems = validate::check(&self.graph);
+        if !problems.is_empty() {
+            println!("\tGraph has {}", problems);
+            if self.config.repair {
+                validate::repair(&mut self.graph, &problems, self.config.crs);
+                println!("\tRepaired the graph, now {} nodes and {} edges", self.graph.node_map.len(), self.graph.edge_map.len());
+            } else if problems.is_fatal() {
+                return Err(format!("Graph has {}, set repair = true under [graph] to fix it while loading", problems).into());
+            }
+        }
+
+        if self.config.simplify {
+            let (nodes, edges) = (self.graph.node_map.len(), self.graph.edge_map.len());
+            self.simplified = simplify::simplify(&mut self.graph);
+            println!("\tSimplified the graph from {} nodes and {} edges to {} and {}", nodes, edges, self.graph.node_map.len(), self.graph.edge_map.len());
+        }
+
+        let closures = self.config.closures.clone();
+        if !closures.classes.is_empty() || !closures.areas.is_empty() {
+            println!("\tClosed {} roads", self.close_roads(&closures));
+        }
+
+        if !self.config.keep_islands {
+            let islands = self.remove_islands();
+            if islands.parts > 0 {
+                println!(
+                    "\tRemoved {} parts of the graph not connected to the rest, {} nodes and {} edges, the largest {} nodes",
+                    islands.parts, islands.nodes, islands.edges, islands.largest
+                );
+            }
+        }
 
         match self.transform.write() {
             Ok(mut transform) => {
@@ -58,6 +106,20 @@ impl Module for Graph {
             }
         };
 
+        self.node_ids = self.graph.node_map.keys().copied().collect();
+        self.node_ids.sort_unstable();
+        self.node_index = spatial::KdTree::build(self.graph.node_map.values().map(|node| (node.point, node.id)).collect());
+
+        self.components = route_finding::components(self);
+        let parts = self.components.values().max().map_or(0, |max| max + 1);
+        if parts > 1 {
+            println!("\tGraph is in {} disconnected parts, some requests can't be routed", parts);
+        }
+
+        let timer = std::time::Instant::now();
+        self.landmarks = route_finding::Landmarks::build(self, self.config.landmarks);
+        println!("\tBuilt {} routing landmarks in {:?}", self.landmarks.len(), timer.elapsed());
+
         // TODO: Build view & cache etc for the GUI
         println!("[{}] Initialised in {:?}", self.get_name(), time.elapsed());
 
@@ -76,6 +138,42 @@ pub struct GraphConfig {
 
     #[serde(default = "default_radius")]
     edge_thickness: f32,
+
+    // Landmarks precomputed for routing, more give faster queries but a slower start up
+    #[serde(default = "default_landmarks")]
+    landmarks: usize,
+
+    // Roads nothing is routed along, see `closures`
+    #[serde(default)]
+    closures: closures::ClosureConfig,
+
+    // Merge chains of nodes with only two roads into single edges, see `simplify`
+    #[serde(default)]
+    simplify: bool,
+
+    // Fix problems found checking the graph instead of stopping, see `validate`
+    #[serde(default)]
+    repair: bool,
+
+    // Keep the parts of the graph not connected to the largest, see `islands`
+    #[serde(default)]
+    keep_islands: bool,
+
+    // What the node and edge coordinates are in, see `geometry`
+    #[serde(default)]
+    pub crs: Crs,
+
+    // Whether routes are shortest by travel time or distance, see `route_finding::CostModel`
+    #[serde(default)]
+    routing: route_finding::CostModel,
+
+    // Road speeds by class and a congestion curve, see `speeds`
+    #[serde(default)]
+    speeds: speeds::SpeedConfig,
+}
+
+fn default_landmarks() -> usize {
+    8
 }
 
 fn default_radius() -> f32 {
@@ -96,36 +194,77 @@ impl Graph {
         &self.graph.adjacency
     }
 
+    pub fn get_node_ids(&self) -> &[u128] {
+        &self.node_ids
+    }
+
+    // What the node and edge coordinates are in, which everything measured on this graph is measured by
+    pub fn crs(&self) -> Crs {
+        self.config.crs
+    }
+
+    // Metres between two map coordinates
+    pub fn distance(&self, a: (f64, f64), b: (f64, f64)) -> f64 {
+        self.config.crs.distance(a, b)
+    }
+
+    // Closest node to a map coord, None if the graph is empty
+    pub fn nearest_node(&self, point: (f64, f64)) -> Option<u128> {
+        self.node_index.nearest(point).map(|(node, _)| node)
+    }
+
+    // Speed on `edge` at `time` over the default road speed, what vehicles scale their speed by
+    pub fn speed_factor(&self, edge: u128, time: NaiveTime) -> f64 {
+        self.graph.edge_map.get(&edge).map_or(1.0, |edge| self.speeds.factor(&edge.edge_class, time))
+    }
+
+    // How much of their usual speed everything makes at `time`
+    pub fn congestion(&self, time: NaiveTime) -> f64 {
+        self.speeds.congestion(time)
+    }
+
+    pub fn get_landmarks(&self) -> &route_finding::Landmarks {
+        &self.landmarks
+    }
+
+    // Shortest path using the landmarks built at init, in the same order as `route_finding::find_route` (dest first)
+    pub fn shortest_path_fast(&self, source: u128, dest: u128) -> Vec<u128> {
+        route_finding::find_route_alt(self, &self.landmarks, source, dest)
+    }
+
+    // Whether there's any path between two nodes, false if either isn't in the graph
+    pub fn connected(&self, a: u128, b: u128) -> bool {
+        matches!((self.components.get(&a), self.components.get(&b)), (Some(a), Some(b)) if a == b)
+    }
+
     pub fn get_transform(&self) -> &RwLock<transform::Transform> {
         &self.transform
     }
 
-    pub fn view(&self, response: &mut Response, painter: &mut Painter, ui: &mut Ui) {
+    // Apply this frame's drag/zoom input to the transform
+    pub fn update_transform(&self, response: &Response, ui: &Ui) {
         let drag_delta = response.drag_delta();
         let scroll_delta = ui.input(|i| i.zoom_delta()); //* 50.0; //ui.input().scroll_delta.y;
 
         match self.transform.try_write() {
             Ok(mut transform) => {
                 transform.set_map_offet(ui.max_rect().min);
+                transform.set_viewport(response.rect.size());
                 transform.drag(drag_delta);
                 transform.zoom(scroll_delta);
                 transform.scale(ui.available_width());
             }
             Err(err) => println!("{:?}", err),
         }
-        
-        painter.extend(self.create_paint_shapes())
     }
 
-    pub fn create_paint_shapes(&self) -> Vec<Shape> {
+    // Build the screen-space shapes of the graph for a given transform (can be a copy taken off the UI thread)
+    pub fn create_paint_shapes_with(&self, transform: &transform::Transform) -> Vec<Shape> {
         let mut shapes = Vec::with_capacity(self.graph.node_map.len() + self.graph.edge_map.len());
 
         for (_, node_meta) in self.graph.node_map.iter() {
             shapes.push(Shape::circle_filled(
-                self.transform
-                    .read()
-                    .unwrap()
-                    .map_to_screen(node_meta.point.0, node_meta.point.1),
+                transform.map_to_screen(node_meta.point.0, node_meta.point.1),
                 self.config.node_radius,
                 str_as_colour(&self.config.node_colour),
             ))
@@ -136,12 +275,7 @@ impl Graph {
                 edge_meta
                     .points
                     .iter()
-                    .map(|point| {
-                        self.transform
-                            .read()
-                            .unwrap()
-                            .map_to_screen(point.0, point.1)
-                    })
+                    .map(|point| transform.map_to_screen(point.0, point.1))
                     .collect(),
                 Stroke::new(self.config.edge_thickness, str_as_colour(&self.config.edge_colour)),
             ))
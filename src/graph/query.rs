@@ -0,0 +1,179 @@
+//! Looking up parts of the road graph by attribute or by area, for rules which apply to some of
+//! the roads (closures, speed changes by road class) or to an area (service areas, zones).
+//!
+//! Areas are polygons in map coordinates, given in the config the same way everywhere as a list
+//! of corners which is closed back to the first:
+//!
+//! ```toml
+//! polygon = [[429000.0, 287000.0], [430500.0, 287000.0], [430500.0, 288200.0]]
+//! ```
+//!
+//! Results are sorted by id so rules built on them apply in the same order every run.
+
+use super::{EdgeClass, EdgeId, Graph, NodeId};
+
+impl Graph {
+    // Edges of one road class
+    pub fn edges_by_class(&self, class: &EdgeClass) -> Vec<EdgeId> {
+        let mut edges: Vec<EdgeId> = self.get_edgelist().values().filter(|edge| edge.edge_class == *class).map(|edge| edge.id).collect();
+        edges.sort();
+        edges
+    }
+
+    // Nodes inside `polygon`
+    pub fn nodes_within(&self, polygon: &[(f64, f64)]) -> Vec<NodeId> {
+        let Some((low, high)) = bounding_box(polygon) else {
+            return Vec::new();
+        };
+        // Only the nodes near enough to be inside are tested against the polygon
+        let centre = ((low.0 + high.0) / 2.0, (low.1 + high.1) / 2.0);
+        let radius = (high.0 - low.0).hypot(high.1 - low.1) / 2.0;
+        let nodelist = self.get_nodelist();
+        let mut nodes: Vec<NodeId> = self.node_index.within(centre, radius).into_iter().filter(|node| point_in_polygon(nodelist[node].point, polygon)).collect();
+        nodes.sort();
+        nodes
+    }
+
+    // Edges with any part inside `polygon`, including ones crossing it without a node inside
+    pub fn edges_intersecting(&self, polygon: &[(f64, f64)]) -> Vec<EdgeId> {
+        let Some(area) = bounding_box(polygon) else {
+            return Vec::new();
+        };
+        let mut edges: Vec<EdgeId> = self
+            .get_edgelist()
+            .values()
+            .filter(|edge| bounding_box(&edge.points).is_some_and(|bounds| overlaps(bounds, area)) && line_intersects_polygon(&edge.points, polygon))
+            .map(|edge| edge.id)
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    // Closest road to `point` and how far it is (m), None if the graph is empty. Only roads
+    // meeting at a node up to twice as far as the closest node are looked at, so a long road
+    // bending round from further away can be missed
+    pub fn nearest_edge(&self, point: (f64, f64)) -> Option<(EdgeId, f64)> {
+        let (_, nearest) = self.node_index.nearest(point)?;
+        let mut edges: Vec<EdgeId> = self
+            .node_index
+            .within(point, nearest.sqrt() * 2.0)
+            .iter()
+            .filter_map(|node| self.get_adjacency().get(node))
+            .flatten()
+            .copied()
+            .collect();
+        edges.sort();
+        edges.dedup();
+        let edgelist = self.get_edgelist();
+        edges
+            .into_iter()
+            .filter_map(|edge| Some((edge, self.distance(point, closest_on_line(point, &edgelist.get(&edge)?.points)?))))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+// Point along a line through `points` closest to `point`, None for a line with no points
+fn closest_on_line(point: (f64, f64), points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let squared = |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2);
+    let mut closest = *points.first()?;
+    for segment in points.windows(2) {
+        let (start, end) = (segment[0], segment[1]);
+        let length = squared(start, end);
+        let along = if length == 0.0 { 0.0 } else { (((point.0 - start.0) * (end.0 - start.0) + (point.1 - start.1) * (end.1 - start.1)) / length).clamp(0.0, 1.0) };
+        let on = (start.0 + (end.0 - start.0) * along, start.1 + (end.1 - start.1) * along);
+        if squared(point, on) < squared(point, closest) {
+            closest = on;
+        }
+    }
+    Some(closest)
+}
+
+// Ray casting test, points exactly on the boundary may go either way
+pub fn point_in_polygon(point: (f64, f64), polygon: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+
+    for i in 0..polygon.len() {
+        let (xi, yi) = polygon[i];
+        let (xj, yj) = polygon[j];
+
+        if (yi > point.1) != (yj > point.1) && point.0 < (xj - xi) * (point.1 - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+
+    inside
+}
+
+// Whether a line through `points` has a point inside `polygon` or crosses one of its sides
+pub fn line_intersects_polygon(points: &[(f64, f64)], polygon: &[(f64, f64)]) -> bool {
+    if points.iter().any(|point| point_in_polygon(*point, polygon)) {
+        return true;
+    }
+    let sides = || (0..polygon.len()).map(|i| (polygon[i], polygon[(i + 1) % polygon.len()]));
+    points.windows(2).any(|segment| sides().any(|side| segments_cross((segment[0], segment[1]), side)))
+}
+
+// Segments touching at an end or lying along each other count as crossing
+fn segments_cross(a: ((f64, f64), (f64, f64)), b: ((f64, f64), (f64, f64))) -> bool {
+    let turn = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0);
+    let (d1, d2) = (turn(b.0, b.1, a.0), turn(b.0, b.1, a.1));
+    let (d3, d4) = (turn(a.0, a.1, b.0), turn(a.0, a.1, b.1));
+    if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+        return true;
+    }
+    let on = |p: (f64, f64), q: (f64, f64), r: (f64, f64), d: f64| d == 0.0 && r.0 >= p.0.min(q.0) && r.0 <= p.0.max(q.0) && r.1 >= p.1.min(q.1) && r.1 <= p.1.max(q.1);
+    on(b.0, b.1, a.0, d1) || on(b.0, b.1, a.1, d2) || on(a.0, a.1, b.0, d3) || on(a.0, a.1, b.1, d4)
+}
+
+// Lowest and highest corners of the points, None if there aren't any
+fn bounding_box(points: &[(f64, f64)]) -> Option<((f64, f64), (f64, f64))> {
+    let first = *points.first()?;
+    Some(points.iter().fold((first, first), |(low, high), point| ((low.0.min(point.0), low.1.min(point.1)), (high.0.max(point.0), high.1.max(point.1)))))
+}
+
+fn overlaps(((ax0, ay0), (ax1, ay1)): ((f64, f64), (f64, f64)), ((bx0, by0), (bx1, by1)): ((f64, f64), (f64, f64))) -> bool {
+    ax0 <= bx1 && bx0 <= ax1 && ay0 <= by1 && by0 <= ay1
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::synthetic::grid;
+
+    use super::*;
+
+    #[test]
+    fn finds_nodes_and_edges_in_an_area() {
+        // 5 x 5 junctions 100m apart, node ids counting along each row
+        let graph = grid(5, 100.0);
+        let square = [(50.0, 50.0), (250.0, 50.0), (250.0, 250.0), (50.0, 250.0)];
+        assert_eq!(graph.nodes_within(&square), vec![6, 7, 11, 12]);
+
+        // Between the first two nodes of the bottom row, crossing the road joining them
+        let across = [(120.0, -10.0), (180.0, -10.0), (180.0, 10.0), (120.0, 10.0)];
+        assert!(graph.nodes_within(&across).is_empty());
+        let edges = graph.edges_intersecting(&across);
+        assert_eq!(edges.len(), 1);
+        let edge = &graph.get_edgelist()[&edges[0]];
+        assert_eq!((edge.start_id, edge.end_id), (1, 2));
+
+        // The synthetic roads have no class
+        assert_eq!(graph.edges_by_class(&EdgeClass::default()).len(), graph.get_edgelist().len());
+        assert!(graph.edges_by_class(&EdgeClass::RoadA).is_empty());
+        assert!(graph.nodes_within(&[]).is_empty());
+    }
+
+    #[test]
+    fn finds_the_road_closest_to_a_point() {
+        let graph = grid(5, 100.0);
+        // Just above the middle of the road joining the first two nodes of the bottom row
+        let (edge, away) = graph.nearest_edge((50.0, 10.0)).unwrap();
+        let edge = &graph.get_edgelist()[&edge];
+        assert_eq!((edge.start_id, edge.end_id), (0, 1));
+        assert!((away - 10.0).abs() < 1e-9);
+
+        // Over a junction the distance is to the junction itself
+        assert!(graph.nearest_edge((200.0, 200.0)).unwrap().1 < 1e-9);
+    }
+}
@@ -1,16 +1,53 @@
-use std::{collections::{BinaryHeap, HashMap, VecDeque}, cmp::Ordering, sync::Arc};
+use std::{collections::{hash_map::Entry, BinaryHeap, HashMap, VecDeque}, cmp::Ordering, sync::Arc, time::{Duration, Instant}};
 
-use super::Graph;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+
+use super::{EdgeMeta, Graph};
+
+/// What the shortest route is shortest by, set with `routing` in the graph config:
+///
+/// ```toml
+/// [graph]
+/// routing = "Distance" # Fewest metres, ignoring road speeds
+/// ```
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CostModel {
+    #[default]
+    TravelTime, // Seconds along each road at its class speed, see `speeds`
+    Distance, // Metres along each road
+}
+
+impl Graph {
+    // What routing counts for driving along `edge`
+    pub fn edge_cost(&self, edge: &EdgeMeta) -> f64 {
+        match self.config.routing {
+            CostModel::TravelTime => edge.length / self.speeds.class_speed(&edge.edge_class),
+            CostModel::Distance => edge.length,
+        }
+    }
+
+    // Least any road costs per metre, so a straight line distance times this never overestimates
+    fn cost_per_metre(&self) -> f64 {
+        match self.config.routing {
+            CostModel::TravelTime => 1.0 / self.speeds.fastest(),
+            CostModel::Distance => 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
 struct State {
     node: u128,
-    dist: u32
+    dist: f64
 }
 
+impl Eq for State {}
+
 impl Ord for State {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.dist.cmp(&self.dist).then_with(|| self.node.cmp(&other.node))
+        other.dist.total_cmp(&self.dist).then_with(|| self.node.cmp(&other.node))
     }
 }
 
@@ -26,7 +63,7 @@ pub fn find_route(graph: &Graph, source: u128, dest: u128) -> Vec<u128> {
     let mut prev = HashMap::new();
     let mut heap = BinaryHeap::new();
 
-    distances.entry(source).and_modify(|e| *e = 0).or_insert(0);
+    distances.entry(source).and_modify(|e| *e = 0.0).or_insert(0.0);
     prev.entry(source).and_modify(|v| *v = source).or_insert(source);
 
     heap.push(State {
@@ -40,7 +77,7 @@ pub fn find_route(graph: &Graph, source: u128, dest: u128) -> Vec<u128> {
             break;
         }
 
-        let cost = *distances.entry(node).or_insert(u32::MAX);
+        let cost = *distances.entry(node).or_insert(f64::MAX);
 
         // Better way already exists
         if dist > cost {
@@ -52,10 +89,10 @@ pub fn find_route(graph: &Graph, source: u128, dest: u128) -> Vec<u128> {
 
             let next = State {
                 node: if e_start == node { e_end } else { e_start },
-                dist: dist + graph.get_edgelist()[edge].length as u32
+                dist: dist + graph.edge_cost(&graph.get_edgelist()[edge])
             };
 
-            let next_cost = *distances.entry(next.node).or_insert(u32::MAX);
+            let next_cost = *distances.entry(next.node).or_insert(f64::MAX);
             if next.dist < next_cost {
                 heap.push(next);
                 distances.entry(next.node)
@@ -84,12 +121,285 @@ pub fn find_route(graph: &Graph, source: u128, dest: u128) -> Vec<u128> {
     path
 }
 
+// A* search state, ordered so the heap pops the lowest cost + estimate first
+#[derive(Copy, Clone, PartialEq)]
+struct AStarState {
+    node: u128,
+    cost: f64, // Cost of the best path found to this node
+    estimate: f64, // cost + lower bound on the cost from here to the destination
+}
+
+impl Eq for AStarState {}
+
+impl Ord for AStarState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate).then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for AStarState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A* search for the shortest path between two nodes. Edges are at least as long as the straight
+// line between their ends, so the straight line at the fastest road's cost never overestimates and
+// the path found is as short as Dijkstra's. Returns the path in the same order as `find_route`, dest first
+pub fn find_route_astar(graph: &Graph, source: u128, dest: u128) -> Vec<u128> {
+    let dest_point = match graph.get_nodelist().get(&dest) {
+        Some(node) => node.point,
+        None => return vec![dest],
+    };
+    astar(graph, source, dest, |node| straight_line(graph, node, dest_point))
+}
+
+// A* guided by the landmark lower bound as well as the straight line distance (ALT)
+pub fn find_route_alt(graph: &Graph, landmarks: &Landmarks, source: u128, dest: u128) -> Vec<u128> {
+    let dest_point = match graph.get_nodelist().get(&dest) {
+        Some(node) => node.point,
+        None => return vec![dest],
+    };
+    let dest_index = landmarks.index.get(&dest).copied();
+    astar(graph, source, dest, |node| {
+        straight_line(graph, node, dest_point).max(landmarks.lower_bound(node, dest_index))
+    })
+}
+
+fn straight_line(graph: &Graph, node: &u128, point: (f64, f64)) -> f64 {
+    match graph.get_nodelist().get(node) {
+        Some(meta) => graph.distance(meta.point, point) * graph.cost_per_metre(),
+        None => 0.0,
+    }
+}
+
+// A* with the given heuristic, which must never overestimate the cost to dest
+fn astar<H: Fn(&u128) -> f64>(graph: &Graph, source: u128, dest: u128, heuristic: H) -> Vec<u128> {
+    let mut costs = HashMap::new();
+    let mut prev = HashMap::new();
+    let mut heap = BinaryHeap::new();
+
+    costs.insert(source, 0.0);
+    prev.insert(source, source);
+    heap.push(AStarState { node: source, cost: 0.0, estimate: heuristic(&source) });
+
+    while let Some(AStarState { node, cost, .. }) = heap.pop() {
+        // Found path
+        if node == dest {
+            break;
+        }
+
+        // Better way already exists
+        if cost > *costs.get(&node).unwrap_or(&f64::MAX) {
+            continue;
+        }
+
+        for edge in graph.get_adjacency().get(&node).into_iter().flatten() {
+            let edge_data = &graph.get_edgelist()[edge];
+            let next = if edge_data.start_id == node { edge_data.end_id } else { edge_data.start_id };
+            let next_cost = cost + graph.edge_cost(edge_data);
+
+            if next_cost < *costs.get(&next).unwrap_or(&f64::MAX) {
+                costs.insert(next, next_cost);
+                prev.insert(next, node);
+                heap.push(AStarState { node: next, cost: next_cost, estimate: next_cost + heuristic(&next) });
+            }
+        }
+    }
+
+    let mut path = Vec::new();
+    let mut prev_node = dest;
+
+    loop {
+        path.push(prev_node);
+
+        if prev.contains_key(&prev_node) && prev_node != source {
+            prev_node = prev[&prev_node];
+        } else {
+            break;
+        }
+    }
+
+    path
+}
+
+/// Shortest path costs from a few landmark nodes to every node, built once when the graph loads.
+/// By the triangle inequality |d(L, dest) - d(L, v)| is a lower bound on d(v, dest), which is
+/// usually much tighter than the straight line so A* expands far fewer nodes
+#[derive(Debug, Default)]
+pub struct Landmarks {
+    index: HashMap<u128, usize>, // Node id to position in the distance lists
+    landmarks: Vec<u128>,
+    distances: Vec<Vec<f64>>, // Per landmark, cost to each node (infinite if unreachable)
+}
+
+impl Landmarks {
+    // Pick landmarks spread over the graph, each the node furthest from those already picked
+    pub fn build(graph: &Graph, count: usize) -> Self {
+        let mut nodes: Vec<u128> = graph.get_nodelist().keys().copied().collect();
+        nodes.sort_unstable();
+        let index: HashMap<u128, usize> = nodes.iter().enumerate().map(|(i, node)| (*node, i)).collect();
+
+        let mut landmarks = Landmarks { index, landmarks: Vec::new(), distances: Vec::new() };
+        let mut next = match nodes.first() {
+            Some(node) => *node,
+            None => return landmarks,
+        };
+        // Closest landmark distance for every node
+        let mut nearest = vec![f64::INFINITY; nodes.len()];
+
+        for _ in 0..count.min(nodes.len()) {
+            let distances = landmarks.distances_from(graph, next);
+            for (near, dist) in nearest.iter_mut().zip(distances.iter()) {
+                *near = near.min(*dist);
+            }
+            landmarks.landmarks.push(next);
+            landmarks.distances.push(distances);
+
+            // Unreachable nodes are left to the straight line bound
+            let furthest = nearest
+                .iter()
+                .enumerate()
+                .filter(|(_, dist)| dist.is_finite() && **dist > 0.0)
+                .max_by(|a, b| a.1.total_cmp(b.1));
+            next = match furthest {
+                Some((i, _)) => nodes[i],
+                None => break,
+            };
+        }
+
+        landmarks
+    }
+
+    pub fn len(&self) -> usize {
+        self.landmarks.len()
+    }
+
+    // Dijkstra from one node to every other node
+    fn distances_from(&self, graph: &Graph, source: u128) -> Vec<f64> {
+        let mut distances = vec![f64::INFINITY; self.index.len()];
+        let mut heap = BinaryHeap::new();
+
+        distances[self.index[&source]] = 0.0;
+        heap.push(AStarState { node: source, cost: 0.0, estimate: 0.0 });
+
+        while let Some(AStarState { node, cost, .. }) = heap.pop() {
+            if cost > distances[self.index[&node]] {
+                continue;
+            }
+
+            for edge in graph.get_adjacency().get(&node).into_iter().flatten() {
+                let edge_data = &graph.get_edgelist()[edge];
+                let next = if edge_data.start_id == node { edge_data.end_id } else { edge_data.start_id };
+                let next_index = match self.index.get(&next) {
+                    Some(i) => *i,
+                    None => continue,
+                };
+                let next_cost = cost + graph.edge_cost(edge_data);
+
+                if next_cost < distances[next_index] {
+                    distances[next_index] = next_cost;
+                    heap.push(AStarState { node: next, cost: next_cost, estimate: next_cost });
+                }
+            }
+        }
+
+        distances
+    }
+
+    fn lower_bound(&self, node: &u128, dest_index: Option<usize>) -> f64 {
+        let (node_index, dest_index) = match (self.index.get(node), dest_index) {
+            (Some(node_index), Some(dest_index)) => (*node_index, dest_index),
+            _ => return 0.0,
+        };
+
+        self.distances
+            .iter()
+            .map(|distances| (distances[node_index], distances[dest_index]))
+            .filter(|(to_node, to_dest)| to_node.is_finite() && to_dest.is_finite())
+            .map(|(to_node, to_dest)| (to_dest - to_node).abs())
+            .fold(0.0, f64::max)
+    }
+}
+
+// Total edge length along a path of adjacent nodes
+pub fn path_length(path: &[u128], graph: &Graph) -> f64 {
+    path_sum(path, graph, |e| e.length)
+}
+
+// Total routing cost along a path of adjacent nodes, what the route finders minimise
+pub fn path_cost(path: &[u128], graph: &Graph) -> f64 {
+    path_sum(path, graph, |e| graph.edge_cost(e))
+}
+
+fn path_sum(path: &[u128], graph: &Graph, value: impl Fn(&EdgeMeta) -> f64) -> f64 {
+    path.windows(2).map(|pair| {
+        graph.get_adjacency().get(&pair[0]).into_iter().flatten()
+            .map(|e| &graph.get_edgelist()[e])
+            .filter(|e| e.start_id == pair[0] && e.end_id == pair[1] || e.start_id == pair[1] && e.end_id == pair[0])
+            .map(&value)
+            .fold(f64::MAX, f64::min)
+    }).sum()
+}
+
+// Time Dijkstra against A* and ALT on random node pairs, checking they agree on the path costs
+pub fn benchmark(graph: &Graph, pairs: usize, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut nodes: Vec<u128> = graph.get_nodelist().keys().copied().collect();
+    nodes.sort_unstable();
+    if nodes.is_empty() {
+        println!("[Routing] Graph has no nodes to benchmark");
+        return;
+    }
+
+    let pairs: Vec<(u128, u128)> = (0..pairs)
+        .map(|_| (nodes[rng.gen_range(0..nodes.len())], nodes[rng.gen_range(0..nodes.len())]))
+        .collect();
+
+    let mut dijkstra_time = Duration::ZERO;
+    let mut astar_time = Duration::ZERO;
+    let mut alt_time = Duration::ZERO;
+    let mut mismatches = 0;
+
+    for (source, dest) in pairs.iter() {
+        let timer = Instant::now();
+        let dijkstra = find_route(graph, *source, *dest);
+        dijkstra_time += timer.elapsed();
+
+        let timer = Instant::now();
+        let astar = find_route_astar(graph, *source, *dest);
+        astar_time += timer.elapsed();
+
+        let timer = Instant::now();
+        let alt = graph.shortest_path_fast(*source, *dest);
+        alt_time += timer.elapsed();
+
+        // Only allow for rounding in summing the edges in a different order
+        let dijkstra_cost = path_cost(&dijkstra, graph);
+        let tolerance = dijkstra_cost.abs() * 1e-9;
+        if (dijkstra_cost - path_cost(&astar, graph)).abs() > tolerance || (dijkstra_cost - path_cost(&alt, graph)).abs() > tolerance {
+            mismatches += 1;
+        }
+    }
+
+    println!("[Routing] {} random pairs", pairs.len());
+    println!("[Routing] Dijkstra: {:?} total, {:?} per route", dijkstra_time, dijkstra_time / pairs.len().max(1) as u32);
+    println!("[Routing] A*: {:?} total, {:?} per route", astar_time, astar_time / pairs.len().max(1) as u32);
+    println!("[Routing] ALT ({} landmarks): {:?} total, {:?} per route", graph.get_landmarks().len(), alt_time, alt_time / pairs.len().max(1) as u32);
+    println!(
+        "[Routing] Speedup A* {:.2}x, ALT {:.2}x, {} routes differed in cost",
+        dijkstra_time.as_secs_f64() / astar_time.as_secs_f64().max(f64::EPSILON),
+        dijkstra_time.as_secs_f64() / alt_time.as_secs_f64().max(f64::EPSILON),
+        mismatches
+    );
+}
+
 // approx distance (straight line) between two nodes
 pub fn find_distance(graph: &Graph, source: &u128, dest: &u128) -> u32 {
     let src = graph.get_nodelist()[source].point;
     let dest = graph.get_nodelist()[dest].point;
 
-    ((src.0 - dest.0).powi(2) + (src.1 - dest.1).powi(2)) as u32
+    graph.distance(src, dest) as u32
 }
 
 pub fn best_first_route(source: u128, mut nodes: Vec<u128>, graph: &Graph) -> Vec<u128> {
@@ -136,21 +446,6 @@ pub fn route_length(route: &VecDeque<u128>, graph: &Graph) -> u32 {
     length
 }
 
-pub fn closest_node(point: (f64, f64), graph: &Graph) -> u128 {
-    let mut closest = 0;
-    let mut dist = f64::MAX;
-
-    for (id, node) in graph.get_nodelist().iter() {
-        let d = (f64::abs(point.0 - node.point.0).powi(2) + f64::abs(point.1 - node.point.1).powi(2)).sqrt();
-        if d < dist {
-            dist = d;
-            closest = *id;
-        }
-    }
-
-    closest
-}
-
 // Given two edges find the shortest path between them, works by comparing which nodes are the closest pair
 // then return that path 
 pub fn best_first_edge_route(source_edge: u128, dest_edge: u128, graph: Arc<Graph>) -> Vec<u128> {
@@ -169,20 +464,151 @@ pub fn best_first_edge_route(source_edge: u128, dest_edge: u128, graph: Arc<Grap
     let enen = find_distance(&graph, &source_end, &dest_end);
 
     if stst < sten && stst < enst && stst < enen {
-        let mut route = find_route(&graph, source_start, dest_start);
+        let mut route = find_route_astar(&graph, source_start, dest_start);
         route.push(dest_end);
         route
     } else if sten < stst && sten < enst && sten < enen {
-        let mut route = find_route(&graph, source_start, dest_end);
+        let mut route = find_route_astar(&graph, source_start, dest_end);
         route.push(dest_start);
         route
     } else if enst < stst && enst < sten && enst < enen {
-        let mut route = find_route(&graph, source_end, dest_start);
+        let mut route = find_route_astar(&graph, source_end, dest_start);
         route.push(dest_end);
         route
     } else {
-        let mut route = find_route(&graph, source_end, dest_end);
+        let mut route = find_route_astar(&graph, source_end, dest_end);
         route.push(dest_start);
         route
     }
-}
\ No newline at end of file
+}
+
+// Label each node with the connected part of the graph it's in, nodes with the same label can
+// reach each other. Labels count up from the lowest node id so they're the same every run
+pub fn components(graph: &Graph) -> HashMap<u128, usize> {
+    let mut nodes: Vec<u128> = graph.get_nodelist().keys().copied().collect();
+    nodes.sort_unstable();
+
+    let mut labels = HashMap::with_capacity(nodes.len());
+    let mut queue = VecDeque::new();
+    let mut label = 0;
+    for start in nodes {
+        if labels.contains_key(&start) {
+            continue;
+        }
+        labels.insert(start, label);
+        queue.push_back(start);
+        while let Some(node) = queue.pop_front() {
+            for edge in graph.get_adjacency().get(&node).into_iter().flatten() {
+                let edge = &graph.get_edgelist()[edge];
+                let next = if edge.start_id == node { edge.end_id } else { edge.start_id };
+                if let Entry::Vacant(entry) = labels.entry(next) {
+                    entry.insert(label);
+                    queue.push_back(next);
+                }
+            }
+        }
+        label += 1;
+    }
+    labels
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        graph::{speeds::{ClassSpeed, SpeedConfig}, synthetic::grid_roads, EdgeClass, GraphConfig},
+        Module,
+    };
+
+    use super::*;
+
+    // 5x5 grid, 100m between nodes, with the edges along the middle row twice as long
+    fn grid_graph() -> Graph {
+        grid_graph_with(GraphConfig::default())
+    }
+
+    fn grid_graph_with(config: GraphConfig) -> Graph {
+        let mut adjlist = grid_roads(5, 100.0);
+        for edge in adjlist.edge_map.values_mut() {
+            // Node ids count along each row, so the middle row is 10 to 14
+            if (10..15).contains(&edge.start_id) && (10..15).contains(&edge.end_id) {
+                edge.length = 200.0;
+                edge.edge_class = EdgeClass::Motorway;
+            }
+        }
+
+        let mut graph = Graph::default();
+        graph.init(config, adjlist).unwrap();
+        graph
+    }
+
+    #[test]
+    fn astar_matches_dijkstra() {
+        let graph = grid_graph();
+        let mut nodes: Vec<u128> = graph.get_nodelist().keys().copied().collect();
+        nodes.sort_unstable();
+
+        for source in nodes.iter() {
+            for dest in nodes.iter() {
+                let dijkstra = find_route(&graph, *source, *dest);
+                let astar = find_route_astar(&graph, *source, *dest);
+                assert_eq!(astar.first(), Some(dest));
+                assert_eq!(astar.last(), Some(source));
+                assert_eq!(path_length(&astar, &graph), path_length(&dijkstra, &graph), "{} to {}", source, dest);
+            }
+        }
+    }
+
+    #[test]
+    fn alt_matches_dijkstra() {
+        let graph = grid_graph();
+        let landmarks = Landmarks::build(&graph, 4);
+        assert_eq!(landmarks.len(), 4);
+
+        let mut nodes: Vec<u128> = graph.get_nodelist().keys().copied().collect();
+        nodes.sort_unstable();
+        for source in nodes.iter() {
+            for dest in nodes.iter() {
+                let dijkstra = find_route(&graph, *source, *dest);
+                let alt = find_route_alt(&graph, &landmarks, *source, *dest);
+                assert_eq!(path_length(&alt, &graph), path_length(&dijkstra, &graph), "{} to {}", source, dest);
+            }
+        }
+    }
+
+    #[test]
+    fn astar_avoids_slow_edges() {
+        let graph = grid_graph();
+        // Along the middle row costs 800m, going round it via the row above costs 600m
+        let path = find_route_astar(&graph, 10, 14);
+        assert_eq!(path_length(&path, &graph), 600.0);
+    }
+
+    #[test]
+    fn routes_take_fast_roads_unless_routing_by_distance() {
+        // The long middle row is a motorway at three times the speed of the other roads
+        let speeds = SpeedConfig { default: 10.0, classes: vec![ClassSpeed { class: EdgeClass::Motorway, speed: 30.0 }], ..Default::default() };
+        let graph = grid_graph_with(GraphConfig { speeds: speeds.clone(), ..Default::default() });
+        for path in [find_route(&graph, 10, 14), find_route_astar(&graph, 10, 14), graph.shortest_path_fast(10, 14)] {
+            assert_eq!(path, vec![14, 13, 12, 11, 10]);
+            assert!((path_cost(&path, &graph) - 800.0 / 30.0).abs() < 1e-9);
+        }
+
+        let graph = grid_graph_with(GraphConfig { speeds, routing: CostModel::Distance, ..Default::default() });
+        for path in [find_route(&graph, 10, 14), find_route_astar(&graph, 10, 14), graph.shortest_path_fast(10, 14)] {
+            assert_eq!(path_length(&path, &graph), 600.0);
+        }
+    }
+
+    #[test]
+    fn closed_roads_can_split_the_graph() {
+        let mut graph = crate::graph::synthetic::grid(5, 100.0);
+        assert!(components(&graph).values().all(|label| *label == 0));
+
+        // Both roads out of the corner
+        let corner = vec![(-10.0, -10.0), (50.0, -10.0), (50.0, 50.0), (-10.0, 50.0)];
+        graph.close_roads(&crate::graph::closures::ClosureConfig { classes: vec![], areas: vec![corner] });
+        let labels = components(&graph);
+        assert_eq!(labels[&0], 0);
+        assert!(labels.iter().all(|(node, label)| (*node == 0) == (*label == 0)));
+    }
+}
@@ -0,0 +1,163 @@
+//! Collapsing chains of nodes with only two roads (bends in a road, or where one OS Open Roads link
+//! meets the next) into single edges, so routing searches a much smaller graph:
+//!
+//! ```toml
+//! [graph]
+//! simplify = true
+//! ```
+//!
+//! The merged edge keeps the whole polyline and the summed length, so buses drive and are drawn
+//! along the same roads. Roads of different classes aren't merged, nor chains which would leave two
+//! edges between the same nodes or a loop back to the same node. Closures apply to the merged
+//! edges, so an area closure shuts the whole chain it touches.
+//!
+//! Only the junctions and road ends are left to snap points onto. Stops are snapped onto the
+//! edges as they were before merging, through `Graph::snapping_edges`, then moved onto the edge
+//! they were merged into with `Graph::routing_edge`.
+
+use std::collections::HashMap;
+
+use super::{AdjacencyList, EdgeId, EdgeMeta, Graph, NodeId};
+
+/// What simplifying changed, for snapping onto the graph as it was loaded
+#[derive(Debug, Default)]
+pub struct Simplified {
+    original: HashMap<EdgeId, EdgeMeta>, // Edges which were merged, as loaded
+    merged_into: HashMap<EdgeId, EdgeId>, // Edge each of them is now part of
+}
+
+impl Simplified {
+    // Forget the edges which were merged into ones no longer in the graph
+    pub(super) fn retain_merged(&mut self, keep: impl Fn(EdgeId) -> bool) {
+        let merged_into = &self.merged_into;
+        self.original.retain(|edge, _| keep(merged_into.get(edge).copied().unwrap_or(*edge)));
+        self.merged_into.retain(|_, merged| keep(*merged));
+    }
+}
+
+// Merge every chain of two road nodes in `adjlist`, in node id order so the merged ids are the same
+// every run
+pub fn simplify(adjlist: &mut AdjacencyList) -> Simplified {
+    let mut simplified = Simplified::default();
+    let mut nodes: Vec<NodeId> = adjlist.node_map.keys().copied().collect();
+    nodes.sort();
+
+    for node in nodes {
+        let Some(&[first, second]) = adjlist.adjacency.get(&node).map(Vec::as_slice) else {
+            continue;
+        };
+        if first == second || adjlist.edge_map[&first].edge_class != adjlist.edge_map[&second].edge_class {
+            continue;
+        }
+        let (before, after) = (other_end(&adjlist.edge_map[&first], node), other_end(&adjlist.edge_map[&second], node));
+        let already_joined = adjlist.adjacency[&before].iter().any(|edge| other_end(&adjlist.edge_map[edge], before) == after);
+        if before == node || after == node || before == after || already_joined {
+            continue;
+        }
+
+        // Edges already merged have their pieces kept from before
+        for edge in [first, second] {
+            if !simplified.merged_into.contains_key(&edge) {
+                simplified.original.insert(edge, adjlist.edge_map[&edge].clone());
+            }
+        }
+
+        // The first edge is extended through the node and takes over the second one's end
+        let second_edge = adjlist.edge_map.remove(&second).unwrap();
+        let merged = adjlist.edge_map.get_mut(&first).unwrap();
+        if merged.end_id != node {
+            merged.points.reverse();
+            merged.start_id = merged.end_id;
+        }
+        let mut rest = second_edge.points;
+        if second_edge.end_id == node {
+            rest.reverse();
+        }
+        merged.points.extend(rest.into_iter().skip(1));
+        merged.end_id = after;
+        merged.length += second_edge.length;
+
+        adjlist.node_map.remove(&node);
+        adjlist.adjacency.remove(&node);
+        for edge in adjlist.adjacency.get_mut(&after).unwrap().iter_mut().filter(|edge| **edge == second) {
+            *edge = first;
+        }
+        simplified.merged_into.insert(second, first);
+        simplified.merged_into.insert(first, first);
+    }
+
+    // Edges merged more than once point along the chain of merges, so follow it to the end
+    let mut resolved = HashMap::with_capacity(simplified.merged_into.len());
+    for &edge in simplified.merged_into.keys() {
+        let mut merged = edge;
+        while simplified.merged_into[&merged] != merged {
+            merged = simplified.merged_into[&merged];
+        }
+        resolved.insert(edge, merged);
+    }
+    simplified.merged_into = resolved;
+    simplified
+}
+
+fn other_end(edge: &EdgeMeta, node: NodeId) -> NodeId {
+    if edge.start_id == node { edge.end_id } else { edge.start_id }
+}
+
+impl Graph {
+    // The edge `edge` of the graph as loaded is now part of, the same edge if it wasn't merged
+    pub fn routing_edge(&self, edge: EdgeId) -> EdgeId {
+        self.simplified.merged_into.get(&edge).copied().unwrap_or(edge)
+    }
+
+    // Every edge of the graph as it was loaded, for snapping points onto the road they're on
+    pub fn snapping_edges(&self) -> impl Iterator<Item = (&EdgeId, &EdgeMeta)> {
+        let original = &self.simplified.original;
+        original.iter().chain(self.get_edgelist().iter().filter(|(id, _)| !original.contains_key(id)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{graph::{default_landmarks, route_finding::find_route, GraphConfig}, Module};
+
+    use super::*;
+
+    // Four nodes in a line with a branch off the second, 100m apart
+    fn branching_line() -> AdjacencyList {
+        let mut adjlist = AdjacencyList::default();
+        let points = [(0.0, 0.0), (100.0, 0.0), (200.0, 0.0), (300.0, 0.0), (100.0, 100.0)];
+        for (id, point) in points.into_iter().enumerate() {
+            adjlist.node_map.insert(id as u128, crate::graph::NodeMeta { point, id: id as u128, ..Default::default() });
+        }
+        for (id, (start_id, end_id)) in [(0, 1), (2, 1), (2, 3), (1, 4)].into_iter().enumerate() {
+            let id = 10 + id as u128;
+            let points = vec![adjlist.node_map[&start_id].point, adjlist.node_map[&end_id].point];
+            adjlist.edge_map.insert(id, EdgeMeta { points, start_id, end_id, id, length: 100.0, ..Default::default() });
+            adjlist.adjacency.entry(start_id).or_default().push(id);
+            adjlist.adjacency.entry(end_id).or_default().push(id);
+        }
+        adjlist
+    }
+
+    #[test]
+    fn chains_are_merged_keeping_their_shape() {
+        let mut adjlist = branching_line();
+        let simplified = simplify(&mut adjlist);
+
+        // Only node 2 sits between two roads
+        assert_eq!(adjlist.node_map.len(), 4);
+        assert_eq!(adjlist.edge_map.len(), 3);
+        let merged = &adjlist.edge_map[&11];
+        assert_eq!((merged.start_id, merged.end_id, merged.length), (1, 3, 200.0));
+        assert_eq!(merged.points, vec![(100.0, 0.0), (200.0, 0.0), (300.0, 0.0)]);
+        assert_eq!(adjlist.adjacency[&3], vec![11]);
+        assert_eq!((simplified.merged_into[&12], simplified.original.len()), (11, 2));
+
+        let mut graph = Graph::default();
+        graph.init(GraphConfig { simplify: true, landmarks: default_landmarks(), ..Default::default() }, branching_line()).unwrap();
+        assert_eq!(find_route(&graph, 0, 3), vec![3, 1, 0]);
+        assert_eq!(graph.routing_edge(12), 11);
+        assert_eq!(graph.routing_edge(13), 13);
+        assert_eq!(graph.snapping_edges().count(), 4);
+    }
+}
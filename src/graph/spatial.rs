@@ -0,0 +1,128 @@
+//! 2D k-d tree over map coordinates, for closest node and closest stop lookups without
+//! scanning every point.
+//!
+//! The tree is stored in a single vector: each slice is split on its median point, alternating
+//! between the x and y axes, with the points before the median on the low side.
+
+#[derive(Debug, Clone, Default)]
+pub struct KdTree<T> {
+    points: Vec<((f64, f64), T)>,
+}
+
+impl<T: Copy + Ord> KdTree<T> {
+    pub fn build(mut points: Vec<((f64, f64), T)>) -> Self {
+        // Sorted first so the tree, and the order points at the same distance are found in, don't
+        // depend on the order they're given in
+        points.sort_unstable_by_key(|(_, value)| *value);
+        build_slice(&mut points, 0);
+        KdTree { points }
+    }
+
+    // Closest value to `point` and its squared distance
+    pub fn nearest(&self, point: (f64, f64)) -> Option<(T, f64)> {
+        let mut best = None;
+        nearest_in(&self.points, 0, point, &mut best);
+        best
+    }
+
+    // Every value within `radius` of `point`
+    pub fn within(&self, point: (f64, f64), radius: f64) -> Vec<T> {
+        let mut found = Vec::new();
+        within_in(&self.points, 0, point, radius * radius, &mut found);
+        found
+    }
+}
+
+fn coord(point: (f64, f64), axis: usize) -> f64 {
+    if axis == 0 { point.0 } else { point.1 }
+}
+
+fn distance_sq(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+fn build_slice<T>(slice: &mut [((f64, f64), T)], depth: usize) {
+    if slice.len() <= 1 {
+        return;
+    }
+    let axis = depth % 2;
+    let mid = slice.len() / 2;
+    slice.select_nth_unstable_by(mid, |a, b| coord(a.0, axis).total_cmp(&coord(b.0, axis)));
+
+    let (low, high) = slice.split_at_mut(mid);
+    build_slice(low, depth + 1);
+    build_slice(&mut high[1..], depth + 1);
+}
+
+fn nearest_in<T: Copy>(slice: &[((f64, f64), T)], depth: usize, point: (f64, f64), best: &mut Option<(T, f64)>) {
+    if slice.is_empty() {
+        return;
+    }
+    let mid = slice.len() / 2;
+    let (position, value) = slice[mid];
+
+    let dist = distance_sq(position, point);
+    if best.map_or(true, |(_, best_dist)| dist < best_dist) {
+        *best = Some((value, dist));
+    }
+
+    let axis = depth % 2;
+    let diff = coord(point, axis) - coord(position, axis);
+    let (near, far) = if diff < 0.0 { (&slice[..mid], &slice[mid + 1..]) } else { (&slice[mid + 1..], &slice[..mid]) };
+
+    nearest_in(near, depth + 1, point, best);
+    // The other side can only be closer if the splitting line is
+    if best.map_or(true, |(_, best_dist)| diff * diff < best_dist) {
+        nearest_in(far, depth + 1, point, best);
+    }
+}
+
+fn within_in<T: Copy>(slice: &[((f64, f64), T)], depth: usize, point: (f64, f64), radius_sq: f64, found: &mut Vec<T>) {
+    if slice.is_empty() {
+        return;
+    }
+    let mid = slice.len() / 2;
+    let (position, value) = slice[mid];
+
+    if distance_sq(position, point) <= radius_sq {
+        found.push(value);
+    }
+
+    let axis = depth % 2;
+    let diff = coord(point, axis) - coord(position, axis);
+    if diff < 0.0 || diff * diff <= radius_sq {
+        within_in(&slice[..mid], depth + 1, point, radius_sq, found);
+    }
+    if diff >= 0.0 || diff * diff <= radius_sq {
+        within_in(&slice[mid + 1..], depth + 1, point, radius_sq, found);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let points: Vec<((f64, f64), usize)> = (0..500).map(|i| ((rng.gen_range(0.0..1000.0), rng.gen_range(0.0..1000.0)), i)).collect();
+        let tree = KdTree::build(points.clone());
+
+        for _ in 0..200 {
+            let query = (rng.gen_range(-100.0..1100.0), rng.gen_range(-100.0..1100.0));
+
+            let closest = points.iter().map(|(p, _)| distance_sq(*p, query)).fold(f64::MAX, f64::min);
+            assert_eq!(tree.nearest(query).unwrap().1, closest);
+
+            let mut expected: Vec<usize> = points.iter().filter(|(p, _)| distance_sq(*p, query) <= 100.0 * 100.0).map(|(_, i)| *i).collect();
+            let mut found = tree.within(query, 100.0);
+            expected.sort_unstable();
+            found.sort_unstable();
+            assert_eq!(found, expected);
+        }
+
+        assert!(KdTree::<usize>::build(Vec::new()).nearest((0.0, 0.0)).is_none());
+    }
+}
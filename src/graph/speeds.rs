@@ -0,0 +1,179 @@
+//! How fast traffic moves on each road. Every road is driven at the same speed unless the graph
+//! config gives speeds by road class, and a congestion curve slowing everything at busy times:
+//!
+//! ```toml
+//! [graph.speeds]
+//! default = 13.4112 # m/s on roads of classes not listed, 30mph
+//! classes = [{ class = "Motorway", speed = 31.3 }, { class = "RoadA", speed = 17.9 }]
+//!
+//! [graph.speeds.congestion] # Hour of the day to how much of its usual speed traffic makes
+//! 7 = 1.0
+//! 8 = 0.6
+//! 10 = 1.0
+//! ```
+//!
+//! Congestion changes evenly between the hours listed, wrapping round midnight like the demand
+//! levels, so a single hour sets it all day. Vehicle speeds are how fast they go on a road at the
+//! default speed with no congestion, and are scaled from there on every other road. Routes are
+//! found by travel time at the class speeds, see `route_finding::CostModel`.
+
+use std::collections::BTreeMap;
+
+use chrono::{NaiveTime, Timelike};
+use serde::Deserialize;
+
+use crate::units::MetersPerSecond;
+
+use super::EdgeClass;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpeedConfig {
+    #[serde(default = "default_speed")]
+    pub default: f64,
+    #[serde(default)]
+    pub classes: Vec<ClassSpeed>,
+    #[serde(default)]
+    pub congestion: BTreeMap<String, f64>, // Hour of the day to a multiplier on every road's speed
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClassSpeed {
+    pub class: EdgeClass,
+    pub speed: f64, // m/s
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        SpeedConfig { default: default_speed(), classes: vec![], congestion: BTreeMap::new() }
+    }
+}
+
+// Speed of roads with none given and of vehicles on them, 30mph
+pub const DEFAULT_SPEED: MetersPerSecond = MetersPerSecond(13.4112);
+
+fn default_speed() -> f64 {
+    DEFAULT_SPEED.0
+}
+
+/// Road speeds checked and ready to look up while moving and routing
+#[derive(Debug, Clone)]
+pub struct Speeds {
+    default: f64,
+    classes: Vec<(EdgeClass, f64)>,
+    hourly: Option<[f64; 24]>, // Congestion at the start of each hour, None if there's none
+}
+
+impl Default for Speeds {
+    fn default() -> Self {
+        Speeds { default: default_speed(), classes: vec![], hourly: None }
+    }
+}
+
+impl Speeds {
+    pub fn from_config(config: &SpeedConfig) -> Result<Self, String> {
+        let valid = |speed: f64| speed.is_finite() && speed > 0.0;
+        if !valid(config.default) {
+            return Err(format!("Default road speed {} isn't a speed in m/s", config.default));
+        }
+        let mut classes: Vec<(EdgeClass, f64)> = Vec::new();
+        for ClassSpeed { class, speed } in config.classes.iter() {
+            if !valid(*speed) {
+                return Err(format!("Road speed {} for {:?} isn't a speed in m/s", speed, class));
+            }
+            if classes.iter().any(|(listed, _)| listed == class) {
+                return Err(format!("Road speed for {:?} is given more than once", class));
+            }
+            classes.push((class.clone(), *speed));
+        }
+
+        let mut points = Vec::new();
+        for (hour, multiplier) in config.congestion.iter() {
+            let hour = match hour.trim().parse::<usize>() {
+                Ok(hour) if hour < 24 => hour,
+                _ => return Err(format!("Congestion hour '{}' isn't an hour of the day from 0 to 23", hour)),
+            };
+            if !valid(*multiplier) {
+                return Err(format!("Congestion for hour {} is {}, it needs to be above 0", hour, multiplier));
+            }
+            if points.iter().any(|(listed, _)| *listed == hour) {
+                return Err(format!("Congestion hour {} is given more than once", hour));
+            }
+            points.push((hour, *multiplier));
+        }
+        points.sort_by_key(|(hour, _)| *hour);
+
+        let hourly = (!points.is_empty()).then(|| {
+            let mut hourly = [1.0; 24];
+            for (hour, level) in hourly.iter_mut().enumerate() {
+                let before = points.iter().rev().find(|(listed, _)| *listed <= hour).unwrap_or(points.last().unwrap());
+                let after = points.iter().find(|(listed, _)| *listed > hour).unwrap_or(&points[0]);
+                let span = match (after.0 + 24 - before.0) % 24 {
+                    0 => 24,
+                    span => span,
+                };
+                let through = ((hour + 24 - before.0) % 24) as f64 / span as f64;
+                *level = before.1 + (after.1 - before.1) * through;
+            }
+            hourly
+        });
+        Ok(Speeds { default: config.default, classes, hourly })
+    }
+
+    // How much of their usual speed everything makes at `time`, 1.0 without a congestion curve
+    pub fn congestion(&self, time: NaiveTime) -> f64 {
+        let Some(hourly) = &self.hourly else {
+            return 1.0;
+        };
+        let hour = time.hour() as usize;
+        let through = (time.minute() * 60 + time.second()) as f64 / 3600.0;
+        hourly[hour] + (hourly[(hour + 1) % 24] - hourly[hour]) * through
+    }
+
+    // Speed on a road of `class` at `time` over the default speed, what vehicle speeds are scaled by
+    pub fn factor(&self, class: &EdgeClass, time: NaiveTime) -> f64 {
+        self.class_speed(class) / self.default * self.congestion(time)
+    }
+
+    // Speed traffic makes on a road of `class` without any congestion (m/s)
+    pub fn class_speed(&self, class: &EdgeClass) -> f64 {
+        self.classes.iter().find(|(listed, _)| listed == class).map_or(self.default, |(_, speed)| *speed)
+    }
+
+    // Fastest road's speed, which nothing drives faster than
+    pub fn fastest(&self) -> f64 {
+        self.classes.iter().map(|(_, speed)| *speed).fold(self.default, f64::max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn roads_are_driven_at_their_class_speed_slowed_by_congestion() {
+        // Nothing configured drives every road at the default speed all day
+        let speeds = Speeds::default();
+        assert_eq!(speeds.factor(&EdgeClass::Motorway, at(8, 0)), 1.0);
+
+        let config = SpeedConfig {
+            default: 10.0,
+            classes: vec![ClassSpeed { class: EdgeClass::Motorway, speed: 30.0 }],
+            congestion: BTreeMap::from([(String::from("7"), 1.0), (String::from("8"), 0.5), (String::from("10"), 1.0)]),
+        };
+        let speeds = Speeds::from_config(&config).unwrap();
+        assert_eq!(speeds.factor(&EdgeClass::Motorway, at(3, 0)), 3.0);
+        assert_eq!(speeds.factor(&EdgeClass::RoadB, at(3, 0)), 1.0);
+        assert_eq!(speeds.factor(&EdgeClass::RoadB, at(7, 30)), 0.75);
+        assert_eq!(speeds.factor(&EdgeClass::Motorway, at(8, 0)), 1.5);
+        assert_eq!(speeds.congestion(at(9, 0)), 0.75);
+
+        let stopped = SpeedConfig { congestion: BTreeMap::from([(String::from("8"), 0.0)]), ..config.clone() };
+        assert!(Speeds::from_config(&stopped).is_err());
+        let twice = SpeedConfig { classes: vec![ClassSpeed { class: EdgeClass::RoadA, speed: 20.0 }; 2], ..config };
+        assert!(Speeds::from_config(&twice).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Small synthetic road networks for running the simulation without the Ordnance Survey data.
+//!
+//! A square grid of junctions joined by straight roads is enough to spawn buses, route
+//! passengers and run ticks, e.g. running a DRT fleet for 10 ticks without the GUI:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use chrono::Utc;
+//! # use odbrs::{graph::synthetic, simulation::{demand::Demand, dyn_controller::{self, DynamicController}, rng::SimRng, router::{DemandRouter, Router}, Controller}};
+//! let graph = Arc::new(synthetic::grid(5, 100.0));
+//! let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+//!
+//! let mut controller = DynamicController::default();
+//! controller.set_router(router.clone());
+//! controller.set_rng(SimRng::seeded(1));
+//! for _ in 0..3 {
+//!     controller.spawn_agent(graph.clone());
+//! }
+//!
+//! let demand = Demand((0.0, 0.0), (400.0, 400.0), Utc::now(), None);
+//! controller.accept_passenger(dyn_controller::demand_to_passenger(demand, router.as_ref(), 0));
+//! for _ in 0..10 {
+//!     controller.step(graph.clone(), Utc::now());
+//! }
+//! assert!(controller.snapshot_passengers().is_empty()); // Delivered
+//! ```
+
+use crate::Module;
+
+use super::{default_landmarks, AdjacencyList, EdgeMeta, Graph, GraphConfig, NodeMeta};
+
+// `size` x `size` junctions `spacing` metres apart, node ids counting along each row from the origin
+pub fn grid(size: u128, spacing: f64) -> Graph {
+    let config = GraphConfig { landmarks: default_landmarks(), ..Default::default() };
+    let mut graph = Graph::default();
+    graph.init(config, grid_roads(size, spacing)).expect("Synthetic graph failed to initialise");
+    graph
+}
+
+// The roads of `grid` before they're made into a graph, to change some of them first
+pub fn grid_roads(size: u128, spacing: f64) -> AdjacencyList {
+    let mut adjlist = AdjacencyList::default();
+    let id = |x: u128, y: u128| y * size + x;
+    for y in 0..size {
+        for x in 0..size {
+            adjlist.node_map.insert(id(x, y), NodeMeta { point: (x as f64 * spacing, y as f64 * spacing), id: id(x, y), ..Default::default() });
+        }
+    }
+
+    // Edge ids follow on from the node ids so the two never clash
+    let mut edge_id = size * size;
+    for y in 0..size {
+        for x in 0..size {
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if nx >= size || ny >= size {
+                    continue;
+                }
+                let (start_id, end_id) = (id(x, y), id(nx, ny));
+                let points = vec![adjlist.node_map[&start_id].point, adjlist.node_map[&end_id].point];
+                adjlist.edge_map.insert(edge_id, EdgeMeta { points, start_id, end_id, id: edge_id, length: spacing, ..Default::default() });
+                adjlist.adjacency.entry(start_id).or_insert_with(Vec::new).push(edge_id);
+                adjlist.adjacency.entry(end_id).or_insert_with(Vec::new).push(edge_id);
+                edge_id += 1;
+            }
+        }
+    }
+    adjlist
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use crate::simulation::{
+        demand::Demand,
+        dyn_controller::{self, DynamicController},
+        rng::SimRng,
+        router::{DemandRouter, Router},
+        Controller,
+    };
+
+    use crate::graph::route_finding::path_length;
+
+    use super::*;
+
+    #[test]
+    fn grid_is_connected() {
+        let graph = grid(4, 50.0);
+        assert_eq!(graph.get_nodelist().len(), 16);
+        assert_eq!(graph.get_edgelist().len(), 24);
+        assert_eq!(graph.nearest_node((140.0, 10.0)), Some(3));
+
+        // Every junction can be driven to from every other, the shortest way round the grid
+        for source in 0..16 {
+            for dest in 0..16 {
+                assert!(graph.connected(source, dest));
+                let path = graph.shortest_path_fast(source, dest);
+                assert_eq!((path.first(), path.last()), (Some(&dest), Some(&source)));
+                let blocks = |node: u128| ((node % 4) as f64, (node / 4) as f64);
+                let ((x1, y1), (x2, y2)) = (blocks(source), blocks(dest));
+                assert_eq!(path_length(&path, &graph), ((x1 - x2).abs() + (y1 - y2).abs()) * 50.0);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_ten_ticks_headless() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+
+        let mut controller = DynamicController::default();
+        controller.set_router(router.clone());
+        controller.set_rng(SimRng::seeded(1));
+        for _ in 0..3 {
+            controller.spawn_agent(graph.clone());
+        }
+
+        let now = Utc::now();
+        for (i, (source, dest)) in [((0.0, 0.0), (400.0, 400.0)), ((400.0, 0.0), (0.0, 300.0))].into_iter().enumerate() {
+            assert!(router.connected((source.0 as f64, source.1 as f64), (dest.0 as f64, dest.1 as f64)));
+            let demand = Demand(source, dest, now, None);
+            controller.accept_passenger(dyn_controller::demand_to_passenger(demand, router.as_ref(), i as u32));
+        }
+        assert_eq!(controller.snapshot_passengers().len(), 2);
+
+        // Ten minutes is long enough to drive across the grid and back
+        for _ in 0..10 {
+            controller.step(graph.clone(), now);
+        }
+        assert_eq!(controller.get_agents().len(), 3);
+        assert!(controller.snapshot_passengers().is_empty());
+    }
+}
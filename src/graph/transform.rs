@@ -2,9 +2,10 @@ use eframe::epaint::{pos2, CircleShape, PathShape, Pos2, Shape, TextShape, Vec2,
 
 use super::AdjacencyList;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Transform {
     pub map_offset: Vec2,
+    pub viewport: Vec2,
 
     pub dragx: f32,
     pub dragy: f32,
@@ -23,6 +24,7 @@ impl Transform {
 
         Transform {
             map_offset: vec2(0.0, 0.0),
+            viewport: vec2(0.0, 0.0),
             dragx: 0.0,
             dragy: 0.0,
             zoom: 1.0,
@@ -38,6 +40,22 @@ impl Transform {
         self.map_offset = map_offset.to_vec2();
     }
 
+    pub fn set_viewport(&mut self, viewport: Vec2) {
+        self.viewport = viewport;
+    }
+
+    // Map coord at the centre of the current viewport
+    pub fn centre(&self) -> (f64, f64) {
+        self.screen_to_map(pos2(self.viewport.x / 2.0, self.viewport.y / 2.0) + self.map_offset)
+    }
+
+    // Move the camera so the given map coord sits at the centre of the viewport with the given zoom
+    pub fn look_at(&mut self, centre: (f64, f64), zoom: f32) {
+        self.zoom = zoom.clamp(0.0001, 100.0);
+        self.dragx = ((centre.0 as f32 - self.left) * self.scale) - self.viewport.x / (2.0 * self.zoom);
+        self.dragy = ((self.top - centre.1 as f32) * self.scale) - self.viewport.y / (2.0 * self.zoom);
+    }
+
     // Drag the graph around
     pub fn drag(&mut self, drag_delta: Vec2) {
         self.dragx += drag_delta.x;
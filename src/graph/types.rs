@@ -37,7 +37,7 @@ pub struct EdgeMeta {
     pub length: f64
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EdgeClass {
     NotClassified,
     Unclassified,
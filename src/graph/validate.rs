@@ -0,0 +1,197 @@
+//! Checks on the road graph as it's loaded, before anything walks it. Every graph is checked, and
+//! problems which would panic later (edges to nodes which aren't there, nodes listing edges which
+//! aren't there or don't touch them) stop the load unless they're repaired while loading:
+//!
+//! ```toml
+//! [graph]
+//! repair = true
+//! ```
+//!
+//! Repairing drops edges to missing nodes, edges with no length even from their points, and edges
+//! which copy another between the same nodes along the same line (keeping the lowest id). The node
+//! edge lists are then rebuilt from the edges that are left, and nodes with no edges dropped.
+//! Without repairing, zero length and duplicate edges and nodes with no edges are only reported.
+//! Anything the repair leaves cut off is removed with the rest of the islands (see `islands`).
+
+use std::{collections::HashMap, fmt};
+
+use crate::geometry::Crs;
+
+use super::{AdjacencyList, EdgeId, EdgeMeta, NodeId};
+
+/// What's wrong with a graph
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Problems {
+    pub missing_nodes: Vec<EdgeId>, // Edges with an end not in the node list
+    pub bad_adjacency: Vec<(NodeId, EdgeId)>, // Listed at a node but not in the edge list, not touching it or listed twice (a loop back to it, once for each end)
+    pub zero_length: Vec<EdgeId>,
+    pub duplicates: Vec<EdgeId>, // The same road as an edge with a lower id
+    pub unconnected: Vec<NodeId>, // Nodes with no edges
+}
+
+impl Problems {
+    pub fn is_empty(&self) -> bool {
+        *self == Problems::default()
+    }
+
+    // Whether the simulation would panic on this graph
+    pub fn is_fatal(&self) -> bool {
+        !self.missing_nodes.is_empty() || !self.bad_adjacency.is_empty()
+    }
+}
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = [
+            (self.missing_nodes.len(), "edges to missing nodes"),
+            (self.bad_adjacency.len(), "bad node edge lists"),
+            (self.zero_length.len(), "zero length edges"),
+            (self.duplicates.len(), "duplicate edges"),
+            (self.unconnected.len(), "nodes with no edges"),
+        ];
+        let found: Vec<String> = counts.iter().filter(|(count, _)| *count > 0).map(|(count, what)| format!("{} {}", count, what)).collect();
+        if found.is_empty() {
+            write!(f, "no problems")
+        } else {
+            write!(f, "{}", found.join(", "))
+        }
+    }
+}
+
+// Everything wrong with `adjlist`, each list in id order
+pub fn check(adjlist: &AdjacencyList) -> Problems {
+    let mut problems = Problems::default();
+    let mut edges: Vec<&EdgeMeta> = adjlist.edge_map.values().collect();
+    edges.sort_by_key(|edge| edge.id);
+
+    let mut roads: HashMap<(NodeId, NodeId), Vec<&EdgeMeta>> = HashMap::new();
+    for edge in edges {
+        if !adjlist.node_map.contains_key(&edge.start_id) || !adjlist.node_map.contains_key(&edge.end_id) {
+            problems.missing_nodes.push(edge.id);
+        }
+        if edge.length <= 0.0 {
+            problems.zero_length.push(edge.id);
+        }
+        let ends = (edge.start_id.min(edge.end_id), edge.start_id.max(edge.end_id));
+        let same_road = roads.entry(ends).or_default();
+        if same_road.iter().any(|other| same_line(edge, other)) {
+            problems.duplicates.push(edge.id);
+        }
+        same_road.push(edge);
+    }
+
+    let mut nodes: Vec<&NodeId> = adjlist.node_map.keys().collect();
+    nodes.sort();
+    for node in nodes {
+        let listed = adjlist.adjacency.get(node).map_or(&[][..], Vec::as_slice);
+        if listed.is_empty() {
+            problems.unconnected.push(*node);
+        }
+        let mut seen: HashMap<EdgeId, usize> = HashMap::new();
+        for edge in listed {
+            let meta = adjlist.edge_map.get(edge);
+            let touches = meta.is_some_and(|meta| meta.start_id == *node || meta.end_id == *node);
+            // A loop is listed for both its ends when the lists are built from the edges
+            let ends_here = meta.map_or(1, |meta| (meta.start_id == *node) as usize + (meta.end_id == *node) as usize);
+            let times = seen.entry(*edge).or_default();
+            *times += 1;
+            if !touches || *times > ends_here {
+                problems.bad_adjacency.push((*node, *edge));
+            }
+        }
+    }
+    problems
+}
+
+// Along the same points, either way round
+fn same_line(a: &EdgeMeta, b: &EdgeMeta) -> bool {
+    a.points == b.points || a.points.iter().eq(b.points.iter().rev())
+}
+
+// Fix what `check` found in `adjlist`, lengths measured in `crs`, see the module docs
+pub fn repair(adjlist: &mut AdjacencyList, problems: &Problems, crs: Crs) {
+    for edge in problems.missing_nodes.iter().chain(problems.duplicates.iter()) {
+        adjlist.edge_map.remove(edge);
+    }
+    for edge in problems.zero_length.iter() {
+        let Some(meta) = adjlist.edge_map.get_mut(edge) else { continue };
+        meta.length = meta.points.windows(2).map(|pair| crs.distance(pair[0], pair[1])).sum();
+        if meta.length <= 0.0 {
+            adjlist.edge_map.remove(edge);
+        }
+    }
+
+    // In id order, so routing ties go the same way every run
+    let mut edges: Vec<&EdgeMeta> = adjlist.edge_map.values().collect();
+    edges.sort_by_key(|edge| edge.id);
+    adjlist.adjacency.clear();
+    for edge in edges {
+        adjlist.adjacency.entry(edge.start_id).or_default().push(edge.id);
+        if edge.end_id != edge.start_id {
+            adjlist.adjacency.entry(edge.end_id).or_default().push(edge.id);
+        }
+    }
+    let adjacency = &adjlist.adjacency;
+    adjlist.node_map.retain(|node, _| adjacency.contains_key(node));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::NodeMeta;
+
+    use super::*;
+
+    #[test]
+    fn problems_are_found_and_repaired() {
+        // A road 1 -- 2 -- 3, copied between 1 and 2, with a zero length road with no shape from 3
+        // to 4, one to a node that isn't there, a stray node and 3 listing a road it isn't on
+        let mut adjlist = AdjacencyList::default();
+        for (id, x) in [(1, 0.0), (2, 100.0), (3, 200.0), (4, 200.0), (5, 500.0)] {
+            adjlist.node_map.insert(id, NodeMeta { point: (x, 0.0), id, ..Default::default() });
+        }
+        let roads = [(10, 1, 2, 100.0), (11, 2, 3, 100.0), (12, 2, 1, 100.0), (13, 3, 4, 0.0), (14, 3, 9, 100.0)];
+        for (id, start_id, end_id, length) in roads {
+            let point = |node: NodeId| adjlist.node_map.get(&node).map_or((900.0, 0.0), |meta| meta.point);
+            let points = vec![point(start_id), point(end_id)];
+            adjlist.edge_map.insert(id, EdgeMeta { points, start_id, end_id, id, length, ..Default::default() });
+            adjlist.adjacency.entry(start_id).or_default().push(id);
+            adjlist.adjacency.entry(end_id).or_default().push(id);
+        }
+        adjlist.adjacency.get_mut(&3).unwrap().push(10);
+
+        let problems = check(&adjlist);
+        assert_eq!(problems.missing_nodes, vec![14]);
+        assert_eq!(problems.bad_adjacency, vec![(3, 10)]);
+        assert_eq!(problems.zero_length, vec![13]);
+        assert_eq!(problems.duplicates, vec![12]);
+        assert_eq!(problems.unconnected, vec![5]);
+        assert!(problems.is_fatal());
+
+        repair(&mut adjlist, &problems, Crs::default());
+        assert!(check(&adjlist).is_empty());
+        let mut edges: Vec<_> = adjlist.edge_map.keys().copied().collect();
+        edges.sort();
+        assert_eq!(edges, vec![10, 11]);
+        assert_eq!(adjlist.adjacency[&2], vec![10, 11]);
+        assert_eq!(adjlist.node_map.len(), 3);
+    }
+
+    #[test]
+    fn loops_are_listed_once_for_each_end() {
+        // A road 1 -- 2 with a loop back to 2, listed at 2 for both its ends
+        let mut adjlist = AdjacencyList::default();
+        for (id, x) in [(1, 0.0), (2, 100.0)] {
+            adjlist.node_map.insert(id, NodeMeta { point: (x, 0.0), id, ..Default::default() });
+        }
+        let loop_points = vec![(100.0, 0.0), (150.0, 50.0), (100.0, 100.0), (100.0, 0.0)];
+        adjlist.edge_map.insert(10, EdgeMeta { points: vec![(0.0, 0.0), (100.0, 0.0)], start_id: 1, end_id: 2, id: 10, length: 100.0, ..Default::default() });
+        adjlist.edge_map.insert(11, EdgeMeta { points: loop_points, start_id: 2, end_id: 2, id: 11, length: 270.0, ..Default::default() });
+        adjlist.adjacency.insert(1, vec![10]);
+        adjlist.adjacency.insert(2, vec![10, 11, 11]);
+        assert!(check(&adjlist).is_empty());
+
+        // But not a third time, or a road listed twice
+        adjlist.adjacency.insert(2, vec![10, 10, 11, 11, 11]);
+        assert_eq!(check(&adjlist).bad_adjacency, vec![(2, 10), (2, 11)]);
+    }
+}
@@ -1,28 +1,219 @@
-use eframe::egui::{Context, plot::{Plot, BarChart, Bar}, CentralPanel};
+use eframe::{egui::{Context, plot::{Plot, BarChart, Bar}, CentralPanel, SidePanel, ScrollArea, TextEdit}, NativeOptions};
 use csv::ReaderBuilder;
-use std::collections::HashMap;
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}};
+
+use crate::analytics::RunMetadata;
+
+use super::layout::{Layout, LAYOUT_PATH};
+
+const VEHICLE_OUTPUT: &str = "-vehicle-output.csv";
+const PASSENGER_OUTPUT: &str = "-passenger-output.csv";
 
 #[derive(Default)]
 pub struct State {
     distributions: Vec<(String, HashMap<u64, usize>)>,
     selected_distribution: Option<usize>,
+
+    runs: Vec<RunEntry>, // Runs found on disk, newest first
+    selected_run: Option<usize>,
+    open_path: String, // Directory (or output file) typed into the open box
+    message: String, // Result of the last open
+
+    layout: Layout, // Shared with the simulation window, for the UI scale and where this window was left
+    scale_applied: bool,
 }
 
 impl eframe::App for State {
+    fn on_close_event(&mut self) -> bool {
+        if let Err(err) = self.layout.save(Path::new(LAYOUT_PATH)) {
+            eprintln!("[Analytics] Couldn't save the window layout to {}: {}", LAYOUT_PATH, err);
+        }
+        true
+    }
+
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+        if !self.scale_applied {
+            self.layout.apply_scale(ctx, None);
+            self.scale_applied = true;
+        }
+        self.layout.remember_native("analytics", _frame);
+
+        show_runs(self, ctx);
         show_analytics(self, ctx, _frame);
     }
 }
 
+/// A finished run found on disk, its output files share a timestamp prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunEntry {
+    pub dir: PathBuf,
+    pub stamp: String,
+    pub label: String,
+    pub files: Vec<PathBuf>, // Vehicle and passenger outputs
+}
+
+impl State {
+    // Open on the run which just finished, listing the other runs in its output directory
+    pub fn new(output_dir: &Path, current: Vec<String>) -> Self {
+        let mut state = State {
+            open_path: output_dir.to_string_lossy().into_owned(),
+            runs: find_runs(output_dir, 3),
+            layout: Layout::load(Path::new(LAYOUT_PATH)),
+            ..Default::default()
+        };
+        state.selected_run = state.runs.iter().position(|run| run.files.iter().any(|file| current.iter().any(|c| Path::new(c) == file)));
+        create_distributions(&mut state, current);
+        state
+    }
+
+    // Open the window where it was left last time
+    pub fn native_options(&self) -> NativeOptions {
+        let mut options = NativeOptions::default();
+        self.layout.native_options("analytics", &mut options);
+        options
+    }
+
+    // Add the runs under a directory (or the run an output file belongs to) to the list
+    fn open(&mut self, path: &Path) {
+        let (dir, stamp) = if path.is_file() {
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            (path.parent().map(Path::to_path_buf).unwrap_or_default(), run_stamp(&name))
+        } else {
+            (path.to_path_buf(), None)
+        };
+
+        let found: Vec<RunEntry> = find_runs(&dir, 3)
+            .into_iter()
+            .filter(|run| stamp.is_none() || stamp.as_ref() == Some(&run.stamp))
+            .collect();
+        self.message = format!("Found {} runs in {}", found.len(), dir.display());
+
+        for run in found {
+            if !self.runs.contains(&run) {
+                self.runs.push(run);
+            }
+        }
+        self.runs.sort_by(|a, b| b.stamp.cmp(&a.stamp).then_with(|| a.dir.cmp(&b.dir)));
+        self.selected_run = None;
+    }
+
+    fn load_run(&mut self, i: usize) {
+        let files = self.runs[i].files.iter().map(|f| f.to_string_lossy().into_owned()).collect();
+        create_distributions(self, files);
+        self.selected_run = Some(i);
+        self.selected_distribution = None;
+    }
+}
+
+// Timestamp at the start of an output file name, if it is one
+fn run_stamp(file_name: &str) -> Option<String> {
+    [VEHICLE_OUTPUT, PASSENGER_OUTPUT]
+        .iter()
+        .find_map(|suffix| file_name.strip_suffix(suffix))
+        .map(String::from)
+}
+
+// Find runs in `dir` and its subdirectories (batch runs are nested), newest first
+pub fn find_runs(dir: &Path, depth: usize) -> Vec<RunEntry> {
+    let mut runs = Vec::new();
+    collect_runs(dir, dir, depth, &mut runs);
+    runs.sort_by(|a, b| b.stamp.cmp(&a.stamp).then_with(|| a.dir.cmp(&b.dir)));
+    runs
+}
+
+fn collect_runs(root: &Path, dir: &Path, depth: usize, runs: &mut Vec<RunEntry>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let mut files: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if depth > 0 {
+                collect_runs(root, &path, depth - 1, runs);
+            }
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(stamp) = run_stamp(&name) {
+            files.entry(stamp).or_insert_with(Vec::new).push(path);
+        }
+    }
+
+    for (stamp, mut files) in files {
+        files.sort();
+        let metadata = fs::read_to_string(dir.join(format!("{}-run.toml", stamp)))
+            .ok()
+            .and_then(|data| toml::from_str::<RunMetadata>(&data).ok());
+
+        let mut label = match metadata {
+            Some(m) => format!(
+                "{} {}, {} agents, demand x{}, seed {}, {}-{} in {}s ticks{}",
+                stamp,
+                if m.mixed { "mixed" } else if m.static_only { "static" } else { "dynamic" },
+                m.agent_count,
+                m.demand_scale,
+                m.seed,
+                m.start_time.format("%H:%M"),
+                m.end_time.format("%H:%M"),
+                m.tick_seconds,
+                m.failed_by_rule.map(|rule| format!(", stopped: {}", rule)).unwrap_or_default()
+            ),
+            None => format!("{} (no metadata)", stamp),
+        };
+        if let Ok(relative) = dir.strip_prefix(root) {
+            if relative != Path::new("") {
+                label = format!("{}: {}", relative.display(), label);
+            }
+        }
+
+        runs.push(RunEntry { dir: dir.to_path_buf(), stamp, label, files });
+    }
+}
+
 pub fn create_distributions(state: &mut State, paths: Vec<String>) {
     let mut distr = vec![];
     for path in paths {
-        let distributions = read_csv_file(&path).unwrap();
-        distr.extend(distributions.into_iter());
+        match read_csv_file(&path) {
+            Ok(distributions) => distr.extend(distributions.into_iter()),
+            Err(err) => state.message = format!("Couldn't read {}: {}", path, err),
+        }
     }
     state.distributions = distr;
 }
 
+// Past runs which can be opened in the viewer
+fn show_runs(state: &mut State, ctx: &Context) {
+    SidePanel::left("analytics_runs").default_width(320.0).show(ctx, |ui| {
+        ui.heading("Runs");
+        ui.horizontal(|ui| {
+            ui.add(TextEdit::singleline(&mut state.open_path).hint_text("Output directory or file"));
+            if ui.button("Open").clicked() {
+                let path = PathBuf::from(state.open_path.trim());
+                state.open(&path);
+            }
+        });
+        if !state.message.is_empty() {
+            ui.label(&state.message);
+        }
+        ui.separator();
+
+        let mut load = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            for (i, run) in state.runs.iter().enumerate() {
+                if ui.selectable_label(state.selected_run == Some(i), &run.label).clicked() {
+                    load = Some(i);
+                }
+            }
+        });
+        if let Some(i) = load {
+            state.load_run(i);
+        }
+    });
+}
+
 pub fn show_analytics(state: &mut State, ctx: &Context, _frame: &mut eframe::Frame) {
     // let distributions = read_csv_file("data/agent_distributions.csv").unwrap();
     
@@ -105,6 +296,10 @@ fn read_csv_file(file_path: &str) -> Result<Vec<(String, HashMap<u64, usize>)>,
         let record = result?;
         
         for (i, value) in record.iter().enumerate() {
+            // Empty cells are values the entity doesn't have, e.g. decision latency without DRT
+            if value.is_empty() {
+                continue;
+            }
             let freq_dist = &mut freq_dists.get_mut(i).unwrap().1;
             let num_value = value.parse::<u64>()?;
             let count = freq_dist.entry(num_value).or_insert(0);
@@ -169,4 +364,31 @@ fn calculate_mean_and_stdev(freq_dist: &HashMap<u64, usize>) -> Option<(f64, f64
     let stdev = variance.sqrt();
 
     Some((mean, stdev))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_nested_runs_with_metadata() {
+        let dir = std::env::temp_dir().join(format!("odbrs-runs-{}", std::process::id()));
+        let batch = dir.join("batch").join("run-001");
+        fs::create_dir_all(&batch).unwrap();
+
+        fs::write(dir.join("2023-01-01-10-00-00-vehicle-output.csv"), "").unwrap();
+        fs::write(dir.join("2023-01-01-10-00-00-passenger-output.csv"), "").unwrap();
+        fs::write(batch.join("2023-01-02-10-00-00-vehicle-output.csv"), "").unwrap();
+        let metadata = RunMetadata { agent_count: 50, seed: 3, ..Default::default() };
+        fs::write(batch.join("2023-01-02-10-00-00-run.toml"), toml::to_string(&metadata).unwrap()).unwrap();
+
+        let runs = find_runs(&dir, 3);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].stamp, "2023-01-02-10-00-00");
+        assert!(runs[0].label.contains("50 agents"), "{}", runs[0].label);
+        assert!(runs[0].label.contains("run-001"), "{}", runs[0].label);
+        assert_eq!(runs[1].files.len(), 2);
+        assert!(runs[1].label.contains("no metadata"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use std::{error::Error, fs, path::PathBuf, sync::Arc};
+
+use eframe::egui::{ComboBox, TextEdit, Ui};
+use serde::{Deserialize, Serialize};
+
+use crate::graph::Graph;
+
+use super::Control;
+
+/// A named map viewpoint which can be jumped back to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraBookmark {
+    pub name: String,
+    pub centre: (f64, f64), // Map coord at the centre of the view
+    pub zoom: f32,
+}
+
+// Wrapper so the bookmarks serialise as a toml table
+#[derive(Default, Serialize, Deserialize)]
+struct BookmarkFile {
+    #[serde(default)]
+    bookmarks: Vec<CameraBookmark>,
+}
+
+pub struct BookmarkControl {
+    graph: Arc<Graph>,
+    path: PathBuf,
+    bookmarks: Vec<CameraBookmark>,
+    selected: Option<usize>,
+    new_name: String,
+}
+
+impl BookmarkControl {
+    pub fn new(graph: Arc<Graph>, path: PathBuf, mut bookmarks: Vec<CameraBookmark>) -> Self {
+        // Bookmarks saved in previous sessions are added to the ones from the config
+        match load_bookmarks(&path) {
+            Ok(saved) => {
+                for bookmark in saved {
+                    if !bookmarks.iter().any(|b| b.name == bookmark.name) {
+                        bookmarks.push(bookmark);
+                    }
+                }
+            }
+            Err(err) => println!("[GUI] No saved bookmarks loaded from {:?}: {}", path, err),
+        }
+
+        BookmarkControl {
+            graph,
+            path,
+            bookmarks,
+            selected: None,
+            new_name: String::new(),
+        }
+    }
+
+    fn save_current(&mut self) {
+        let (centre, zoom) = match self.graph.get_transform().read() {
+            Ok(transform) => (transform.centre(), transform.zoom),
+            Err(err) => panic!("Unable to read transform: {}", err),
+        };
+
+        let name = self.new_name.trim().to_owned();
+        let bookmark = CameraBookmark { name: name.clone(), centre, zoom };
+
+        // Saving with an existing name overwrites that bookmark
+        match self.bookmarks.iter().position(|b| b.name == name) {
+            Some(i) => {
+                self.bookmarks[i] = bookmark;
+                self.selected = Some(i);
+            }
+            None => {
+                self.bookmarks.push(bookmark);
+                self.selected = Some(self.bookmarks.len() - 1);
+            }
+        }
+        self.new_name.clear();
+
+        if let Err(err) = save_bookmarks(&self.path, &self.bookmarks) {
+            eprintln!("[GUI] Couldn't save bookmarks to {:?}: {}", self.path, err);
+        }
+    }
+
+    fn jump_to(&self, i: usize) {
+        let bookmark = &self.bookmarks[i];
+        match self.graph.get_transform().write() {
+            Ok(mut transform) => transform.look_at(bookmark.centre, bookmark.zoom),
+            Err(err) => panic!("Unable to write transform: {}", err),
+        }
+    }
+}
+
+impl Control for BookmarkControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        let selected_text = self
+            .selected
+            .and_then(|i| self.bookmarks.get(i))
+            .map(|b| b.name.clone())
+            .unwrap_or_else(|| String::from("Jump to bookmark"));
+
+        let mut jump = None;
+        ComboBox::from_label("Bookmarks")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (i, bookmark) in self.bookmarks.iter().enumerate() {
+                    if ui.selectable_label(self.selected == Some(i), &bookmark.name).clicked() {
+                        jump = Some(i);
+                    }
+                }
+            });
+
+        if let Some(i) = jump {
+            self.selected = Some(i);
+            self.jump_to(i);
+        }
+
+        ui.horizontal(|ui| {
+            ui.add(TextEdit::singleline(&mut self.new_name).hint_text("Bookmark name").desired_width(120.0));
+            if ui.button("Save View").clicked() && !self.new_name.trim().is_empty() {
+                self.save_current();
+            }
+        });
+    }
+}
+
+fn load_bookmarks(path: &PathBuf) -> Result<Vec<CameraBookmark>, Box<dyn Error>> {
+    let data = fs::read_to_string(path)?;
+    let file: BookmarkFile = toml::from_str(&data)?;
+    Ok(file.bookmarks)
+}
+
+fn save_bookmarks(path: &PathBuf, bookmarks: &[CameraBookmark]) -> Result<(), Box<dyn Error>> {
+    let file = BookmarkFile { bookmarks: bookmarks.to_vec() };
+    fs::write(path, toml::to_string(&file)?)?;
+    Ok(())
+}
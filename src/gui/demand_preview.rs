@@ -0,0 +1,91 @@
+use eframe::{
+    egui::{Context, Grid, Window},
+    epaint::{pos2, Color32, Shape},
+};
+
+use crate::simulation::{demand::pregenerate::DemandPreview, display::DisplayLayer, SimulationMessage, SimulationState};
+
+use super::App;
+
+// The same as demand waiting to be handed out is drawn in
+const ORIGIN_COLOUR: Color32 = Color32::LIGHT_GREEN;
+const DESTINATION_COLOUR: Color32 = Color32::LIGHT_RED;
+
+// Summary of demand generated before the run, shown with its trips on the map until the run is
+// started so it can be checked first
+pub fn render_demand_preview(app: &mut App, ctx: &Context) {
+    let (preview, idle) = {
+        let state = app.state.borrow();
+        (state.demand_preview.clone(), state.sim_state.1 == SimulationState::Idle)
+    };
+    let Some(preview) = preview else {
+        return;
+    };
+
+    // Trips are only drawn while the run waits to start
+    {
+        let mut state = app.state.borrow_mut();
+        let showing = state.display_layers.contains_key(&DisplayLayer::DemandPreview);
+        if idle && state.show_demand_preview && !showing {
+            state.display_layers.insert(DisplayLayer::DemandPreview, trip_shapes(&preview));
+        } else if (!idle || !state.show_demand_preview) && showing {
+            state.display_layers.remove(&DisplayLayer::DemandPreview);
+        }
+    }
+    if !idle {
+        return;
+    }
+
+    Window::new("Pre-generated demand").default_width(300.0).show(ctx, |ui| {
+        let summary = &preview.summary;
+        ui.label(format!("{} requests in {}", summary.requests, preview.path.display()));
+        Grid::new("demand_preview_summary").striped(true).show(ui, |ui| {
+            let time = |time: Option<chrono::DateTime<chrono::Utc>>| time.map_or(String::from("-"), |time| time.format("%H:%M:%S").to_string());
+            let busiest = summary.busiest_hour().map_or(String::from("-"), |(hour, count)| format!("{} ({})", hour.format("%H:%M"), count));
+            let rows = [
+                ("First request", time(summary.first)),
+                ("Last request", time(summary.last)),
+                ("Busiest hour", busiest),
+                ("Average trip (km)", format!("{:.2}", summary.average_trip_km)),
+            ];
+            for (heading, value) in rows {
+                ui.label(heading);
+                ui.label(value);
+                ui.end_row();
+            }
+        });
+
+        ui.collapsing("Requests by hour", |ui| {
+            Grid::new("demand_preview_hours").striped(true).show(ui, |ui| {
+                for (hour, count) in summary.hourly.iter() {
+                    ui.label(hour.format("%H:%M").to_string());
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+            });
+        });
+
+        let mut state = app.state.borrow_mut();
+        ui.checkbox(&mut state.show_demand_preview, "Show trips on the map")
+            .on_hover_text("Green where each trip starts and red where it ends");
+        if ui.button("Start run").on_hover_text("Run the simulation with this demand").clicked() {
+            if let Err(err) = app.sim_tx.as_ref().unwrap().send(SimulationMessage::ChangeState(SimulationState::Running)) {
+                eprintln!("Send Error {:?}", err);
+            }
+        }
+    });
+}
+
+// Map space dots at each end of every trip
+fn trip_shapes(preview: &DemandPreview) -> Vec<Shape> {
+    preview
+        .trips
+        .iter()
+        .flat_map(|(origin, destination)| {
+            [
+                Shape::circle_filled(pos2(destination.0, destination.1), 1.5, DESTINATION_COLOUR),
+                Shape::circle_filled(pos2(origin.0, origin.1), 1.5, ORIGIN_COLOUR),
+            ]
+        })
+        .collect()
+}
@@ -0,0 +1,71 @@
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+use eframe::{
+    egui::Ui,
+    epaint::{pos2, Color32, Shape, Stroke},
+};
+
+use crate::{graph::Graph, simulation::display::DisplayLayer};
+
+use super::{AppState, Control};
+
+// Line widths (px) of the least and most driven roads
+const MIN_WIDTH: f32 = 1.0;
+const MAX_WIDTH: f32 = 8.0;
+const DRT_COLOUR: Color32 = Color32::from_rgb(0, 140, 255);
+const FIXED_ROUTE_COLOUR: Color32 = Color32::from_rgb(255, 140, 0);
+
+/// Overlay of how often the roads were driven this run, from the analytics. Thicker roads were
+/// driven more, blue by DRT and orange by the fixed route buses, shading between the two on
+/// roads both use
+pub struct EdgeUsageControl {
+    app_state: Rc<RefCell<AppState>>,
+    graph: Arc<Graph>,
+}
+
+impl EdgeUsageControl {
+    pub fn new(app_state: Rc<RefCell<AppState>>, graph: Arc<Graph>) -> Self {
+        EdgeUsageControl { app_state, graph }
+    }
+}
+
+impl Control for EdgeUsageControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        let mut state = self.app_state.borrow_mut();
+        ui.horizontal(|ui| {
+            let toggled = ui
+                .checkbox(&mut state.show_edge_usage, "Show road usage")
+                .on_hover_text("Thicker for roads driven more, blue for DRT and orange for fixed route buses. Updated when the analytics writes its outputs")
+                .changed();
+            if toggled {
+                let shapes = if state.show_edge_usage { usage_shapes(&self.graph, &state.edge_usage) } else { Vec::new() };
+                state.display_layers.insert(DisplayLayer::EdgeUsage, shapes);
+            }
+            if state.show_edge_usage {
+                ui.label(format!("{} roads driven", state.edge_usage.len()));
+            }
+        });
+    }
+}
+
+// Map space lines for each edge driven, given as (edge, DRT traversals, fixed route traversals)
+pub fn usage_shapes(graph: &Graph, usage: &[(u128, u64, u64)]) -> Vec<Shape> {
+    let busiest = usage.iter().map(|(_, drt, fixed_route)| drt + fixed_route).max().unwrap_or(0).max(1) as f32;
+    usage
+        .iter()
+        .filter_map(|(edge, drt, fixed_route)| {
+            let edge = graph.get_edgelist().get(edge)?;
+            let total = (drt + fixed_route) as f32;
+            // Square root so the quieter roads still show next to the busiest corridor
+            let width = MIN_WIDTH + (MAX_WIDTH - MIN_WIDTH) * (total / busiest).sqrt();
+            let colour = blend(FIXED_ROUTE_COLOUR, DRT_COLOUR, *drt as f32 / total.max(1.0));
+            let points = edge.points.iter().map(|(x, y)| pos2(*x as f32, *y as f32)).collect();
+            Some(Shape::line(points, Stroke::new(width, colour)))
+        })
+        .collect()
+}
+
+fn blend(from: Color32, to: Color32, amount: f32) -> Color32 {
+    let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+    Color32::from_rgb(channel(from.r(), to.r()), channel(from.g(), to.g()), channel(from.b(), to.b()))
+}
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use eframe::{
+    egui::{Align2, Context, Grid, Window},
+    epaint::vec2,
+};
+
+use crate::analytics::report::RunKpis;
+
+use super::{session_dashboard::cell, App};
+
+/// Results of a run which reached its end time, shown until dismissed or the run is restarted
+#[derive(Debug, Clone)]
+pub struct FinishedRun {
+    pub kpis: RunKpis,
+    pub output_dir: PathBuf, // Where the analytics wrote the run's outputs
+}
+
+// Completion window with the run's headline numbers. The analytics view opens once the
+// simulation window closes, so opening it closes this one
+pub fn render_finished_dialog(app: &mut App, ctx: &Context, frame: &mut eframe::Frame) {
+    let Some(finished) = &app.finished else {
+        return;
+    };
+
+    let mut dismissed = false;
+    Window::new("Simulation finished")
+        .anchor(Align2::CENTER_CENTER, vec2(0.0, 0.0))
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            let kpis = &finished.kpis;
+            ui.label(format!("The run reached its end time after {:.0}s", kpis.seconds));
+            Grid::new("finished_kpis").striped(true).show(ui, |ui| {
+                let rows = [
+                    ("Requests", kpis.requests.to_string()),
+                    ("Completed %", cell(kpis.completion())),
+                    ("Average wait (min)", cell(kpis.average_wait())),
+                    ("Average ride (min)", cell(kpis.average_ride())),
+                    ("Distance (km)", format!("{:.1}", kpis.distance_km)),
+                    ("Empty %", cell(kpis.empty_share())),
+                    ("Unserved", kpis.unserved.to_string()),
+                ];
+                for (heading, value) in rows {
+                    ui.label(heading);
+                    ui.label(value);
+                    ui.end_row();
+                }
+            });
+            ui.label(format!("Outputs written to {}", finished.output_dir.display()));
+
+            ui.horizontal(|ui| {
+                if ui.button("Open analytics").on_hover_text("Closes the simulation and opens the analytics view").clicked() {
+                    frame.close();
+                }
+                if ui.button("Close").clicked() {
+                    dismissed = true;
+                }
+            });
+        });
+
+    if dismissed {
+        app.finished = None;
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::mpsc::Sender;
+
+use chrono::NaiveTime;
+use eframe::egui::{ComboBox, DragValue, Ui};
+
+use crate::simulation::{dyn_controller::FleetParameter, FleetChange, SimulationMessage};
+
+use super::Control;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FleetParameterKind {
+    Capacity,
+    Speed,
+    FleetSize,
+}
+
+/// Schedule changes to the dynamic fleet (capacity, speed, number of buses) at a simulation time
+pub struct FleetControl {
+    sim_tx: Sender<SimulationMessage>,
+    kind: FleetParameterKind,
+    value: f64,
+    hour: u32,
+    minute: u32,
+}
+
+impl FleetControl {
+    pub fn new(sim_tx: Sender<SimulationMessage>) -> Self {
+        FleetControl {
+            sim_tx,
+            kind: FleetParameterKind::Capacity,
+            value: 20.0,
+            hour: 7,
+            minute: 0,
+        }
+    }
+
+    fn parameter(&self) -> FleetParameter {
+        match self.kind {
+            FleetParameterKind::Capacity => FleetParameter::Capacity(self.value.clamp(0.0, u8::MAX as f64) as u8),
+            FleetParameterKind::Speed => FleetParameter::Speed(self.value),
+            FleetParameterKind::FleetSize => FleetParameter::FleetSize(self.value.max(0.0) as usize),
+        }
+    }
+}
+
+impl Control for FleetControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        ui.label("Fleet Changes");
+
+        ui.horizontal(|ui| {
+            ComboBox::from_id_source("fleet_parameter")
+                .selected_text(format!("{:?}", self.kind))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.kind, FleetParameterKind::Capacity, "Capacity");
+                    ui.selectable_value(&mut self.kind, FleetParameterKind::Speed, "Speed (m/s)");
+                    ui.selectable_value(&mut self.kind, FleetParameterKind::FleetSize, "Fleet Size");
+                });
+            ui.add(DragValue::new(&mut self.value).clamp_range(0.0..=1000.0));
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("At");
+            ui.add(DragValue::new(&mut self.hour).clamp_range(0..=23).suffix("h"));
+            ui.add(DragValue::new(&mut self.minute).clamp_range(0..=59).suffix("m"));
+
+            if ui.button("Schedule").clicked() {
+                let change = FleetChange {
+                    at: NaiveTime::from_hms_opt(self.hour, self.minute, 0).unwrap(),
+                    parameter: self.parameter(),
+                };
+                match self.sim_tx.send(SimulationMessage::SetFleetParameter(change)) {
+                    Ok(()) => (),
+                    Err(err) => eprintln!("Send Error {:?}", err),
+                }
+            }
+        });
+    }
+}
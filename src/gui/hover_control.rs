@@ -1,21 +1,46 @@
-use std::sync::Arc;
+use std::{cell::RefCell, rc::Rc, sync::Arc};
 
 use eframe::epaint::{Pos2, pos2};
 
 use crate::graph::Graph;
 
-use super::Control;
+use super::{AppState, Control};
 
 
 pub struct HoverControl {
     last_pos: Pos2,
     mapped_pos: (f64, f64),
-    graph: Arc<Graph>
+    graph: Arc<Graph>,
+    app_state: Rc<RefCell<AppState>>,
+    nearest: Vec<String>, // What's closest to the mapped position, a line each, worked out when it moves
 }
 
 impl HoverControl {
-    pub fn new(graph: Arc<Graph>) -> Self {
-        HoverControl { last_pos: pos2(0.0, 0.0), mapped_pos: (0.0, 0.0), graph }
+    pub fn new(graph: Arc<Graph>, app_state: Rc<RefCell<AppState>>) -> Self {
+        HoverControl { last_pos: pos2(0.0, 0.0), mapped_pos: (0.0, 0.0), graph, app_state, nearest: Vec::new() }
+    }
+
+    // The node, road and stop closest to the mapped position, from the spatial indexes
+    fn find_nearest(&self) -> Vec<String> {
+        let point = self.mapped_pos;
+        let mut lines = Vec::new();
+
+        match self.graph.nearest_node(point) {
+            Some(node) => lines.push(format!("Node: {} ({:.0}m)", node, self.graph.distance(point, self.graph.get_nodelist()[&node].point))),
+            None => lines.push(String::from("Node: none")),
+        }
+        match self.graph.nearest_edge(point) {
+            Some((edge, away)) => lines.push(format!("Edge: {} {:?} ({:.0}m)", edge, self.graph.get_edgelist()[&edge].edge_class, away)),
+            None => lines.push(String::from("Edge: none")),
+        }
+        // Only runs with fixed route buses load the stops
+        if let Some(network_data) = self.app_state.borrow().network_data.as_ref() {
+            match network_data.nearest_stop(point).and_then(|(stop, _)| network_data.stops.get(&stop).map(|found| (stop, found))) {
+                Some((stop, found)) => lines.push(format!("Stop: {} {} ({:.0}m)", stop, found.stop_id, self.graph.distance(point, found.position()))),
+                None => lines.push(String::from("Stop: none served")),
+            }
+        }
+        lines
     }
 }
 
@@ -24,15 +49,22 @@ impl Control for HoverControl {
     fn view_control(&mut self, ui: &mut eframe::egui::Ui) {
 
         self.last_pos = ui.input(|i| i.pointer.hover_pos()).unwrap_or(self.last_pos);
-        
-        self.mapped_pos = match self.graph.get_transform().read() {
+
+        let mapped_pos = match self.graph.get_transform().read() {
             Ok(transform) => {
                 transform.screen_to_map(self.last_pos)
             },
             Err(err) => panic!("Unable to read transform: {}", err)
         };
+        if mapped_pos != self.mapped_pos || self.nearest.is_empty() {
+            self.mapped_pos = mapped_pos;
+            self.nearest = self.find_nearest();
+        }
 
         ui.label(format!("Pos: {:?}", self.last_pos));
         ui.label(format!("Map: {:?}", self.mapped_pos));
+        for line in self.nearest.iter() {
+            ui.label(line);
+        }
     }
 }
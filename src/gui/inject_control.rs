@@ -0,0 +1,30 @@
+use std::{cell::RefCell, rc::Rc};
+
+use eframe::egui::Ui;
+
+use super::{AppState, Control};
+
+/// Switch map clicks from selecting vehicles to requesting trips, origin first then destination
+pub struct InjectControl {
+    app_state: Rc<RefCell<AppState>>,
+}
+
+impl InjectControl {
+    pub fn new(app_state: Rc<RefCell<AppState>>) -> Self {
+        InjectControl { app_state }
+    }
+}
+
+impl Control for InjectControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        let mut state = self.app_state.borrow_mut();
+        ui.horizontal(|ui| {
+            if ui.checkbox(&mut state.inject_mode, "Click to add trips").changed() {
+                state.inject_origin = None;
+            }
+            if state.inject_mode {
+                ui.label(if state.inject_origin.is_some() { "Click the destination" } else { "Click the origin" });
+            }
+        });
+    }
+}
@@ -0,0 +1,185 @@
+//! Where the windows were left and how big the UI was drawn, kept between sessions in a small
+//! toml file so the app opens the way it was closed. The simulation and analytics windows share
+//! the file, each only writing over what it changed when it saves
+
+use std::{collections::{BTreeMap, BTreeSet}, error::Error, fs, path::Path};
+
+use eframe::{
+    egui::{Context, Window},
+    epaint::{pos2, vec2, Pos2, Vec2},
+    NativeOptions,
+};
+use serde::{Deserialize, Serialize};
+
+pub const LAYOUT_PATH: &str = "data/save/layout.toml";
+
+// Smallest and largest UI scale, and the step the scale buttons change it by
+pub const MIN_UI_SCALE: f32 = 0.5;
+pub const MAX_UI_SCALE: f32 = 3.0;
+pub const UI_SCALE_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindowRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl WindowRect {
+    fn new(pos: Pos2, size: Vec2) -> Self {
+        WindowRect { x: pos.x, y: pos.y, width: size.x, height: size.y }
+    }
+
+    fn pos(&self) -> Pos2 {
+        pos2(self.x, self.y)
+    }
+
+    fn size(&self) -> Vec2 {
+        vec2(self.width, self.height)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    pub ui_scale: Option<f32>, // Points per pixel picked in the GUI, wins over the config's
+    #[serde(default)]
+    pub native: BTreeMap<String, WindowRect>, // Application windows by name, position on screen and size
+    #[serde(default)]
+    pub windows: BTreeMap<String, WindowRect>, // Windows inside an application by name, position and content size
+    #[serde(skip)]
+    changed: Changed,
+}
+
+// What's been set since the layout was loaded
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Changed {
+    ui_scale: bool,
+    native: BTreeSet<String>,
+    windows: BTreeSet<String>,
+}
+
+impl Layout {
+    // The saved layout, or the default one if there isn't one yet
+    pub fn load(path: &Path) -> Layout {
+        let load = || -> Result<Layout, Box<dyn Error>> { Ok(toml::from_str(&fs::read_to_string(path)?)?) };
+        match load() {
+            Ok(layout) => layout,
+            Err(err) => {
+                println!("[GUI] No saved layout loaded from {:?}: {}", path, err);
+                Layout::default()
+            }
+        }
+    }
+
+    // Write what's changed since loading over the saved layout, leaving what another window saved
+    // in the meantime
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut saved = if path.exists() { Layout::load(path) } else { Layout::default() };
+        if self.changed.ui_scale {
+            saved.ui_scale = self.ui_scale;
+        }
+        for name in self.changed.native.iter() {
+            saved.native.insert(name.clone(), self.native[name]);
+        }
+        for name in self.changed.windows.iter() {
+            saved.windows.insert(name.clone(), self.windows[name]);
+        }
+        fs::write(path, toml::to_string(&saved)?)?;
+        Ok(())
+    }
+
+    // Open the application window where it was last session, or maximised to fit the screen the
+    // first time rather than at a size which may not fit
+    pub fn native_options(&self, name: &str, options: &mut NativeOptions) {
+        match self.native.get(name) {
+            Some(rect) => {
+                options.initial_window_pos = Some(rect.pos());
+                options.initial_window_size = Some(rect.size());
+            }
+            None => options.maximized = true,
+        }
+    }
+
+    pub fn remember_native(&mut self, name: &str, frame: &eframe::Frame) {
+        let info = frame.info().window_info;
+        if info.minimized || info.maximized || info.fullscreen {
+            return;
+        }
+        if let Some(pos) = info.position {
+            self.native.insert(name.to_string(), WindowRect::new(pos, info.size));
+            self.changed.native.insert(name.to_string());
+        }
+    }
+
+    // Put `window` where it was left last session, its own defaults are used the first time
+    pub fn place<'open>(&self, name: &str, window: Window<'open>) -> Window<'open> {
+        match self.windows.get(name) {
+            Some(rect) => window.default_pos(rect.pos()).default_size(rect.size()),
+            None => window,
+        }
+    }
+
+    // Where a window shown this frame is, `pos` its corner and `content` the size of what's inside
+    pub fn remember(&mut self, name: &str, pos: Pos2, content: Vec2) {
+        self.windows.insert(name.to_string(), WindowRect::new(pos, content));
+        self.changed.windows.insert(name.to_string());
+    }
+
+    // Draw at the saved scale, else the config's, else whatever suits the screen
+    pub fn apply_scale(&self, ctx: &Context, config_scale: Option<f32>) {
+        if let Some(scale) = self.ui_scale.or(config_scale) {
+            ctx.set_pixels_per_point(scale.clamp(MIN_UI_SCALE, MAX_UI_SCALE));
+        }
+    }
+
+    // Change the UI scale by `steps` and keep it for next time
+    pub fn step_scale(&mut self, ctx: &Context, steps: f32) {
+        let scale = (ctx.pixels_per_point() + steps * UI_SCALE_STEP).clamp(MIN_UI_SCALE, MAX_UI_SCALE);
+        self.ui_scale = Some((scale * 10.0).round() / 10.0);
+        self.changed.ui_scale = true;
+        ctx.set_pixels_per_point(self.ui_scale.unwrap());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layout_round_trips_through_the_file() {
+        let path = std::env::temp_dir().join("odbrs-layout-test.toml");
+        let _ = fs::remove_file(&path);
+
+        let mut layout = Layout { ui_scale: Some(1.5), ..Default::default() };
+        layout.changed.ui_scale = true;
+        layout.remember("map", pos2(20.0, 40.0), vec2(800.0, 600.0));
+        layout.native.insert(String::from("simulation"), WindowRect::new(pos2(0.0, 0.0), vec2(1280.0, 720.0)));
+        layout.changed.native.insert(String::from("simulation"));
+        layout.save(&path).unwrap();
+
+        // Another window opened before that was saved only writes over what it changes
+        let mut other = Layout::default();
+        other.native.insert(String::from("analytics"), WindowRect::new(pos2(10.0, 10.0), vec2(640.0, 480.0)));
+        other.changed.native.insert(String::from("analytics"));
+        other.save(&path).unwrap();
+
+        let saved = Layout::load(&path);
+        assert_eq!((saved.ui_scale, &saved.windows), (Some(1.5), &layout.windows));
+        assert_eq!(saved.native.keys().collect::<Vec<_>>(), vec!["analytics", "simulation"]);
+        fs::remove_file(&path).unwrap();
+
+        // A missing file starts from the defaults
+        assert_eq!(Layout::load(&path), Layout::default());
+
+        let mut options = NativeOptions::default();
+        layout.native_options("simulation", &mut options);
+        assert_eq!(options.initial_window_size, Some(vec2(1280.0, 720.0)));
+        let mut options = NativeOptions::default();
+        layout.native_options("analytics", &mut options);
+        assert!(options.maximized);
+    }
+}
@@ -0,0 +1,50 @@
+use std::{cell::RefCell, rc::Rc};
+
+use eframe::{egui::Ui, epaint::Color32};
+
+use crate::geometry::Crs;
+
+use super::{AppState, Control};
+
+/// Status of the GTFS-realtime feed, and how far its buses are from the simulated ones
+pub struct LiveControl {
+    app_state: Rc<RefCell<AppState>>,
+    crs: Crs, // The map's, which the distances are measured in
+}
+
+impl LiveControl {
+    pub fn new(app_state: Rc<RefCell<AppState>>, crs: Crs) -> Self {
+        LiveControl { app_state, crs }
+    }
+}
+
+impl Control for LiveControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        let mut state = self.app_state.borrow_mut();
+        let mut show = !state.hide_live;
+        if ui.checkbox(&mut show, "Show live buses").on_hover_text("Real vehicle positions from the GTFS-realtime feed, drawn as cyan squares").changed() {
+            state.hide_live = !show;
+        }
+
+        match &state.live_feed {
+            Some(feed) => {
+                let generated = feed.timestamp.map(|time| format!(", feed from {}", time.format("%H:%M:%S"))).unwrap_or_default();
+                ui.label(format!("{} live vehicles{}", feed.vehicles.len(), generated));
+
+                let mut offsets = feed.offsets(self.crs, &state.vehicles);
+                if !offsets.is_empty() {
+                    offsets.sort_by(|a, b| a.total_cmp(b));
+                    ui.label(format!("Median distance to a simulated bus: {:.0}m", offsets[offsets.len() / 2]))
+                        .on_hover_text("From each live vehicle to the closest simulated one, smaller when the simulation follows the timetable being run");
+                }
+            }
+            None => {
+                ui.label("Waiting for the live feed");
+            }
+        }
+
+        if let Some(err) = &state.live_error {
+            ui.colored_label(Color32::RED, format!("Feed error: {}", err));
+        }
+    }
+}
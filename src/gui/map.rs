@@ -1,61 +1,106 @@
-use eframe::{egui::{Context, Window, Frame, Sense}, epaint::{vec2, Shape, Stroke, Color32}};
+use eframe::{egui::{Align2, Context, FontId, Window, Frame, Sense}, epaint::{vec2, Color32, Rect, Stroke, Vec2}};
 
-use super::App;
+use crate::{analytics::STOP_EVENT_LIFETIME, simulation::SimulationMessage};
+
+use super::{App, render_prep::RenderJob};
+
+// Screen distance (px) within which a click picks a vehicle
+const PICK_RADIUS: f32 = 10.0;
+// Screen size (px) of a live vehicle's square
+const LIVE_SIZE: f32 = 8.0;
 
 pub fn render_map(app_state: &mut App, ctx: &Context, _frame: &mut eframe::Frame) {
-    Window::new("Simulation Map").default_size(vec2(800.0, 600.0))
+    let mut content = Vec2::ZERO;
+    let window = app_state.layout.place("map", Window::new("Simulation Map").default_size(vec2(800.0, 600.0)))
         .frame(Frame::window(&ctx.style())
             .fill(Color32::GRAY)
         )
         .show(ctx, |ui| {
+        content = ui.max_rect().size();
         
-        let (mut response, mut painter) = ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
+        let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
         
-        app_state.graph.view(&mut response, &mut painter, ui);
+        app_state.graph.update_transform(&response, ui);
+
+        let transform = app_state.graph.get_transform().read().unwrap().clone();
+
+        if let Some(render_prep) = app_state.render_prep.as_mut() {
+            // Rebuild the frame when the view moves, otherwise only at the map's update rate
+            if render_prep.transform_changed(&transform) || app_state.map_schedule.due() {
+                let state = app_state.state.borrow();
+                render_prep.submit(RenderJob {
+                    transform: transform.clone(),
+                    agents: state.display_layers.values().flatten().cloned().collect(),
+                    demand_gen: state.demand_gen.clone(),
+                    ctx: ctx.clone(),
+                });
+            }
 
-        let transform = app_state.graph.get_transform().read().unwrap();
+            painter.extend(render_prep.poll().clone());
+        }
 
-        painter.extend(app_state.state.borrow().agent_display_data.iter().map(|shp| {
-            transform.map_shape_to_screen(shp.clone())
-        }).collect::<Vec<_>>());
+        let mut state = app_state.state.borrow_mut();
 
-        // Draw demand data?
-        if let Some(demand_gen) = &app_state.state.borrow().demand_gen {
-            painter.extend(
-                demand_gen
-                    .get_demand_queue()
-                    .read()
-                    .expect("GUI Couldn't read demand_gen")
+        // In inject mode clicks pick a trip's origin then its destination and send it to the simulation
+        if state.inject_mode && response.clicked() {
+            if let Some(click) = response.interact_pointer_pos() {
+                let point = transform.screen_to_map(click);
+                match state.inject_origin.take() {
+                    None => state.inject_origin = Some(point),
+                    Some(origin) => match app_state.sim_tx.as_ref().unwrap().send(SimulationMessage::InjectDemand(origin, point)) {
+                        Ok(()) => (),
+                        Err(err) => eprintln!("Send Error {:?}", err),
+                    },
+                }
+            }
+        // Clicking (rather than dragging) selects the closest vehicle, or clears the selection
+        } else if response.clicked() {
+            if let Some(click) = response.interact_pointer_pos() {
+                state.selected_vehicle = state
+                    .vehicles
                     .iter()
-                    .map(|demand| {
-                        Shape::Vec(vec![
-                            // Shape::line_segment([
-                            //     self.graph.get_transform().read().unwrap().map_to_screen(demand.0.0 as _, demand.0.1 as _),
-                            //     self.graph.get_transform().read().unwrap().map_to_screen(demand.1.0 as _, demand.1.1 as _)
-                            // ], Stroke::new(1.5, Color32::LIGHT_GREEN)),
-                            Shape::circle_stroke(
-                                app_state.graph
-                                    .get_transform()
-                                    .read()
-                                    .unwrap()
-                                    .map_to_screen(demand.0 .0 as _, demand.0 .1 as _),
-                                1.0,
-                                Stroke::new(1.5, Color32::LIGHT_GREEN),
-                            ),
-                            Shape::circle_stroke(
-                                app_state.graph
-                                    .get_transform()
-                                    .read()
-                                    .unwrap()
-                                    .map_to_screen(demand.1 .0 as _, demand.1 .1 as _),
-                                1.0,
-                                Stroke::new(1.5, Color32::LIGHT_RED),
-                            ),
-                            //TODO: tidy up this lol
-                        ])
-                    })
-                    .collect::<Vec<_>>(),
-            )
+                    .map(|(id, pos)| (*id, transform.map_to_screen(pos.0, pos.1).distance(click)))
+                    .filter(|(_, distance)| *distance <= PICK_RADIUS)
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(id, _)| id);
+            }
+        }
+
+        // Flash pick ups (green) and drop offs (orange), growing and fading until they expire
+        let stop_events = app_state.metrics.recent_stop_events();
+        for event in &stop_events {
+            let fade = 1.0 - event.at.elapsed().as_secs_f32() / STOP_EVENT_LIFETIME.as_secs_f32();
+            let (r, g, b) = if event.boarding { (0, 200, 0) } else { (255, 140, 0) };
+            let colour = Color32::from_rgba_unmultiplied(r, g, b, (fade.clamp(0.0, 1.0) * 255.0) as u8);
+            painter.circle_stroke(transform.map_to_screen(event.pos.0, event.pos.1), 4.0 + 8.0 * (1.0 - fade), Stroke::new(2.0, colour));
+        }
+        if !stop_events.is_empty() {
+            ctx.request_repaint();
+        }
+
+        if let Some(origin) = state.inject_origin {
+            painter.circle_stroke(transform.map_to_screen(origin.0, origin.1), 6.0, Stroke::new(2.0, Color32::RED));
+        }
+
+        // Real buses from the live feed, cyan squares labelled with their route
+        if let Some(feed) = state.live_feed.as_ref().filter(|_| !state.hide_live) {
+            for vehicle in &feed.vehicles {
+                let pos = transform.map_to_screen(vehicle.pos.0, vehicle.pos.1);
+                painter.rect_stroke(Rect::from_center_size(pos, vec2(LIVE_SIZE, LIVE_SIZE)), 0.0, Stroke::new(2.0, Color32::from_rgb(0, 200, 255)));
+                if let Some(route) = &vehicle.route_id {
+                    painter.text(pos + vec2(LIVE_SIZE, 0.0), Align2::LEFT_CENTER, route, FontId::proportional(10.0), Color32::from_rgb(0, 120, 160));
+                }
+            }
+        }
+
+        if let Some(selected) = state.selected_vehicle {
+            if let Some((_, pos)) = state.vehicles.iter().find(|(id, _)| *id == selected) {
+                painter.circle_stroke(transform.map_to_screen(pos.0, pos.1), 8.0, Stroke::new(2.0, Color32::from_rgb(255, 0, 255)));
+            }
         }
     });
-}
\ No newline at end of file
+
+    if let Some(window) = window {
+        app_state.layout.remember("map", window.response.rect.min, content);
+    }
+}
@@ -1,33 +1,53 @@
 use std::{
     cell::RefCell,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{
-        mpsc::{Receiver, Sender},
+        atomic::AtomicBool,
+        mpsc::Sender,
         Arc,
     },
 };
 
 use chrono::{DateTime, Utc};
 use eframe::{
-    egui::{CentralPanel, Ui, TopBottomPanel, Frame},
-    epaint::{vec2, Shape},
+    egui::{self, Align, CentralPanel, Ui, TopBottomPanel, Frame},
+    epaint::Shape,
     NativeOptions,
 };
 use serde::Deserialize;
 
 use crate::{
+    analytics::{report::RunKpis, MetricsQuery, RunMetadata},
+    event_bus::Subscriber,
     graph::Graph,
-    simulation::{self, demand::DemandGenerator, SimulationMessage, SimulationState},
+    realtime::LiveFeed,
+    simulation::{self, demand::{pregenerate::DemandPreview, DemandGenerator}, display::DisplayLayer, static_controller::routes::NetworkData, ControllerSummary, SimulationMessage, SimulationState},
     Module,
 };
 
-use self::{hover_control::HoverControl, simulation_control::{SimulationControl, render_control}, map::render_map};
+use self::{layout::{Layout, LAYOUT_PATH}, hover_control::HoverControl, simulation_control::{SimulationControl, render_control}, map::render_map, bookmark_control::{BookmarkControl, CameraBookmark}, demand_preview::render_demand_preview, edge_usage_control::{usage_shapes, EdgeUsageControl}, finished_dialog::{render_finished_dialog, FinishedRun}, fleet_control::FleetControl, inject_control::InjectControl, live_control::LiveControl, render_prep::RenderPrep, schedule::PanelSchedule, session_dashboard::{render_session_dashboard, SessionDashboard}, watchdog::{render_watchdog, Watchdog}, walking_control::WalkingControl, vehicle_panel::render_vehicle_panel};
 
+mod bookmark_control;
+mod demand_preview;
+mod edge_usage_control;
+mod finished_dialog;
+mod fleet_control;
 mod hover_control;
+mod inject_control;
+mod live_control;
+pub mod layout;
+mod render_prep;
+mod schedule;
+mod session_dashboard;
 mod simulation_control;
+mod vehicle_panel;
+mod walking_control;
 pub mod onboarding;
 mod map;
 pub mod analytics;
+pub mod watchdog;
 
 /// Gui contains the GUI for the app obviously
 /// - Function for view of the app
@@ -49,10 +69,32 @@ pub struct App {
     state: Rc<RefCell<AppState>>,
 
     // Recieve messages passed in by other threads
-    rx: Option<Receiver<AppMessage>>,
+    rx: Option<Subscriber<AppMessage>>,
 
     // Send messages to the simulation thread
     sim_tx: Option<Sender<SimulationMessage>>,
+
+    // Worker preparing the map shapes off the UI thread
+    render_prep: Option<RenderPrep>,
+
+    // How often the map rebuilds its shapes when the view isn't moving
+    map_schedule: PanelSchedule,
+
+    // Warns when the simulation thread stops responding
+    watchdog: Watchdog,
+
+    // Live analytics for the selected vehicle
+    metrics: MetricsQuery,
+
+    // Results of the runs finished since the app opened, to compare between restarts
+    session: SessionDashboard,
+
+    // The run which just reached its end time, until the dialog is dismissed
+    finished: Option<FinishedRun>,
+
+    // Window positions and UI scale, saved when the app closes
+    layout: Layout,
+    scale_applied: bool,
 }
 
 impl Module for App {
@@ -75,16 +117,37 @@ impl Module for App {
         self.rx = Some(parameters.rx);
         self.sim_tx = Some(parameters.sim_tx);
 
+        self.render_prep = Some(RenderPrep::start(self.graph.clone()));
+        self.map_schedule = PanelSchedule::from_rate(self.config.map_update_rate);
+        self.watchdog = Watchdog::new(self.config.watchdog_seconds, parameters.force_stop);
+        self.metrics = parameters.metrics;
+        self.layout = Layout::load(Path::new(LAYOUT_PATH));
+
         self.controls = vec![Box::new(SimulationControl {
             app_state: self.state.clone(),
             sim_tx: self.sim_tx.clone().unwrap(),
-            state: simulation_control::ControlState::Paused,
             speed: 100,
+            schedule: PanelSchedule::from_rate(self.config.controls_update_rate),
+            status_text: String::new(),
         })];
 
         if self.config.hover_enabled {
             self.controls
-                .push(Box::new(HoverControl::new(self.graph.clone())));
+                .push(Box::new(HoverControl::new(self.graph.clone(), self.state.clone())));
+        }
+
+        self.controls.push(Box::new(BookmarkControl::new(
+            self.graph.clone(),
+            PathBuf::from(&self.config.bookmarks_path),
+            self.config.bookmarks.clone(),
+        )));
+
+        self.controls.push(Box::new(FleetControl::new(self.sim_tx.clone().unwrap())));
+        self.controls.push(Box::new(WalkingControl::new(self.sim_tx.clone().unwrap())));
+        self.controls.push(Box::new(InjectControl::new(self.state.clone())));
+        self.controls.push(Box::new(EdgeUsageControl::new(self.state.clone(), self.graph.clone())));
+        if parameters.live_vehicles {
+            self.controls.push(Box::new(LiveControl::new(self.state.clone(), self.graph.crs())));
         }
 
         Ok(println!(
@@ -98,49 +161,153 @@ impl Module for App {
 #[derive(Default, Clone, Deserialize)]
 pub struct GuiConfig {
     hover_enabled: bool,
+
+    // Camera bookmarks defined in the config, plus a file to persist ones saved in the GUI
+    #[serde(default)]
+    bookmarks: Vec<CameraBookmark>,
+    #[serde(default = "default_bookmarks_path")]
+    bookmarks_path: String,
+
+    // Refreshes per second of the map and control panels
+    #[serde(default = "default_map_update_rate")]
+    map_update_rate: f32,
+    #[serde(default = "default_controls_update_rate")]
+    controls_update_rate: f32,
+
+    // Seconds without an update from a running simulation before warning it's stuck
+    #[serde(default = "default_watchdog_seconds")]
+    watchdog_seconds: f32,
+
+    // Points per pixel to draw the UI at, e.g. 1.5 on a hi-DPI screen. The screen's own if not
+    // given, and a scale picked in the GUI is kept over this
+    ui_scale: Option<f32>,
+}
+
+fn default_map_update_rate() -> f32 {
+    30.0
+}
+
+fn default_controls_update_rate() -> f32 {
+    4.0
+}
+
+fn default_watchdog_seconds() -> f32 {
+    watchdog::DEFAULT_TIMEOUT_SECONDS
+}
+
+fn default_bookmarks_path() -> String {
+    String::from("data/save/bookmarks.toml")
 }
 
 pub struct AppParameters {
     pub graph: Arc<Graph>,
-    pub rx: Receiver<AppMessage>,
+    pub rx: Subscriber<AppMessage>, // Subscribed before the simulation starts so nothing is missed
     pub sim_tx: Sender<simulation::SimulationMessage>,
+    pub force_stop: Arc<AtomicBool>, // Set if the user force stops a stuck simulation
+    pub metrics: MetricsQuery, // Live per-vehicle analytics
+    pub live_vehicles: bool, // A GTFS-realtime feed is being polled for real vehicles to show
 }
 
 #[derive(Default, Debug)]
 pub struct AppState {
     pub sim_state: (DateTime<Utc>, SimulationState),
-    pub agent_display_data: Vec<Shape>,
+    pub display_layers: BTreeMap<DisplayLayer, Vec<Shape>>, // Drawn bottom layer first
     pub demand_gen: Option<Arc<DemandGenerator>>,
+    pub demand_preview: Option<Arc<DemandPreview>>, // The run's demand if it was generated before starting
+    pub show_demand_preview: bool,
+    pub tick_speed: u64, // Delay between ticks (ms) last sent to the simulation
+    pub vehicles: Vec<(u32, (f64, f64))>, // Analytics id and map position of each vehicle
+    pub fleet_summaries: Vec<(String, ControllerSummary)>, // Totals for each controller running
+    pub selected_vehicle: Option<u32>, // Vehicle clicked on the map, shown in the vehicle panel
+    pub show_plan: bool, // Lay a plan of the selected DRT bus over what it drove since
+    pub shown_plan: Option<u32>, // Passenger whose plan is compared, the latest if not picked
+    pub inject_mode: bool, // Map clicks add trips to the simulation instead of selecting vehicles
+    pub inject_origin: Option<(f64, f64)>, // Origin clicked for the next trip, waiting on its destination
+    pub live_feed: Option<LiveFeed>, // Real vehicle positions, if a GTFS-realtime feed is configured
+    pub live_error: Option<String>, // Why the last poll of the feed failed
+    pub hide_live: bool,
+    pub edge_usage: Vec<(u128, u64, u64)>, // Times each edge was driven by DRT and by fixed route buses, from the analytics
+    pub show_edge_usage: bool,
+    pub network_data: Option<Arc<NetworkData>>, // Stops and trips of the fixed route network, if the run has one
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AppMessage {
     // Placeholder(()),
-    // SimulationState(DateTime<Utc>, SimulationState),
-    SimulationStateWithAgents(DateTime<Utc>, SimulationState, Vec<Shape>),
+    SimulationState(DateTime<Utc>, SimulationState), // Sent every tick
+    DisplayLayer(DisplayLayer, Vec<Shape>), // Replaces everything drawn on this layer of the map
+    VehiclePositions(Vec<(u32, (f64, f64))>), // Analytics id and map position of each vehicle, for picking them on the map
     NoteDemandGen(Arc<DemandGenerator>),
+    DemandPreview(Arc<DemandPreview>), // The run's demand generated up front, to look over before starting it
+    FleetSummaries(Vec<(String, ControllerSummary)>), // Totals for each controller running, by name
+    LiveVehicles(Result<LiveFeed, String>), // Latest update of the GTFS-realtime feed, or why it failed
+    SimulationEnded, // The simulation thread has finished, or panicked, and won't send anything else
+    RunFinished(Box<(RunMetadata, RunKpis)>), // Parameters and results of a run which just ended, from the analytics
+    SimulationFinished(Box<RunKpis>, PathBuf), // Results of a run which reached its end time and where they were written, from the analytics
+    EdgeUsage(Vec<(u128, u64, u64)>), // Times each edge was driven by DRT and by fixed route buses so far, from the analytics
+    NetworkData(Arc<NetworkData>), // The fixed route network once it's loaded, for looking up stops
 }
 
 impl App {
-    pub(crate) fn start(self) -> Result<(), eframe::Error> {
+    pub fn start(self) -> Result<(), eframe::Error> {
         let mut options = NativeOptions::default();
-        options.initial_window_size = Some(vec2(1920.0, 1080.0));
+        self.layout.native_options("simulation", &mut options);
         eframe::run_native("odbrs", options, Box::new(|_cc| Box::new(self)))
     }
 
     fn handle_message(&mut self, msg: AppMessage) {
         // println!("[GUI] Thread handle message {:?}", msg);
         match msg {
-            AppMessage::SimulationStateWithAgents(u, st, agents) => {
-                let mut state = self.state.borrow_mut();
-                state.sim_state = (u, st);
-                state.agent_display_data = agents;
-                // println!("got agent pos {:?}", state.agent_pos[0]);
+            AppMessage::SimulationState(u, st) => {
+                self.watchdog.note_update();
+                // A restarted run is no longer finished
+                if st != SimulationState::Finished {
+                    self.finished = None;
+                }
+                self.state.borrow_mut().sim_state = (u, st);
+            }
+            AppMessage::DisplayLayer(layer, shapes) => {
+                self.state.borrow_mut().display_layers.insert(layer, shapes);
             }
             AppMessage::NoteDemandGen(demand_gen) => {
                 let mut state = self.state.borrow_mut();
                 state.demand_gen = Some(demand_gen);
-            } // _ => (), // TODO: Uncomment this if other variants added
+            }
+            AppMessage::NetworkData(network_data) => {
+                self.state.borrow_mut().network_data = Some(network_data);
+            }
+            AppMessage::DemandPreview(preview) => {
+                let mut state = self.state.borrow_mut();
+                state.demand_preview = Some(preview);
+                state.show_demand_preview = true;
+            }
+            AppMessage::VehiclePositions(vehicles) => {
+                self.state.borrow_mut().vehicles = vehicles;
+            }
+            AppMessage::FleetSummaries(summaries) => {
+                self.state.borrow_mut().fleet_summaries = summaries;
+            }
+            AppMessage::LiveVehicles(update) => {
+                let mut state = self.state.borrow_mut();
+                match update {
+                    Ok(feed) => {
+                        state.live_feed = Some(feed);
+                        state.live_error = None;
+                    }
+                    // Keep showing the last good update, it's better than nothing
+                    Err(err) => state.live_error = Some(err),
+                }
+            }
+            AppMessage::SimulationEnded => self.watchdog.note_disconnected(),
+            AppMessage::RunFinished(run) => self.session.add(run.0, run.1),
+            AppMessage::SimulationFinished(kpis, output_dir) => self.finished = Some(FinishedRun { kpis: *kpis, output_dir }),
+            AppMessage::EdgeUsage(usage) => {
+                let mut state = self.state.borrow_mut();
+                if state.show_edge_usage {
+                    state.display_layers.insert(DisplayLayer::EdgeUsage, usage_shapes(&self.graph, &usage));
+                }
+                state.edge_usage = usage;
+            }
         }
     }
 }
@@ -157,18 +324,42 @@ impl eframe::App for App {
             Err(err) => eprintln!("Couldn't send shutdown thread {:?}", err),
         };
 
+        if let Err(err) = self.layout.save(Path::new(LAYOUT_PATH)) {
+            eprintln!("[GUI] Couldn't save the window layout to {}: {}", LAYOUT_PATH, err);
+        }
+
+        let skipped = self.rx.as_ref().unwrap().dropped();
+        if skipped > 0 {
+            println!("[GUI] Skipped {} updates from the simulation while drawing fell behind", skipped);
+        }
+
         true
     }
 
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
-        match self.rx.as_ref().unwrap().try_recv() {
-            Ok(msg) => self.handle_message(msg),
-            Err(_) => (),
-        };
+        // Drain everything the simulation sent since the last frame
+        while let Ok(msg) = self.rx.as_ref().unwrap().try_recv() {
+            self.handle_message(msg);
+        }
+
+        if !self.scale_applied {
+            self.layout.apply_scale(ctx, self.config.ui_scale);
+            self.scale_applied = true;
+        }
+        self.layout.remember_native("simulation", _frame);
 
         TopBottomPanel::top("top_menu").show(ctx, |ui| {
             ui.horizontal_centered(|ui| {
                 ui.label("On Demand Bus Routing Simulator");
+                ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
+                    if ui.small_button("+").clicked() {
+                        self.layout.step_scale(ctx, 1.0);
+                    }
+                    ui.label(format!("UI scale {:.0}%", ctx.pixels_per_point() * 100.0));
+                    if ui.small_button("-").clicked() {
+                        self.layout.step_scale(ctx, -1.0);
+                    }
+                });
             });
         });
 
@@ -178,9 +369,15 @@ impl eframe::App for App {
         
         render_control(self, ctx, _frame);
         render_map(self, ctx, _frame);
+        render_vehicle_panel(self, ctx, _frame);
+        render_session_dashboard(self, ctx, _frame);
+        render_watchdog(self, ctx, _frame);
+        render_finished_dialog(self, ctx, _frame);
+        render_demand_preview(self, ctx);
 
+        // Only wake up again when the next panel is due, rather than repainting flat out
         if self.state.borrow().sim_state.1 == SimulationState::Running {
-            ctx.request_repaint();
+            ctx.request_repaint_after(self.map_schedule.remaining());
         }
     }
 }
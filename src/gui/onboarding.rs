@@ -1,7 +1,18 @@
-use std::{sync::Arc, cell::RefCell};
+use std::{sync::Arc, cell::RefCell, path::Path};
 
-use chrono::NaiveTime;
-use eframe::{egui::{CentralPanel, Frame, style::Margin, DragValue}, epaint::Color32};
+use chrono::{NaiveTime, Timelike};
+use eframe::{egui::{CentralPanel, ComboBox, Frame, style::Margin, DragValue}, epaint::Color32};
+
+use crate::{
+    resource::GraphChoice,
+    simulation::{demand::levels::DemandLevels, dyn_controller::{bus::DEFAULT_CAPACITY, objective::ObjectiveConfig}},
+};
+
+const RIDE_MINUTES: f64 = 20.0; // Rough time a DRT passenger spends on board, so each seat turns over 3 times an hour
+const TARGET_LOAD: f64 = 0.6; // Share of the seats a fleet sized for the peak hour fills, leaving room for detours
+
+// Graphs in a config and the key it's set to
+type Graphs = Result<(Vec<GraphChoice>, String), String>;
 
 pub struct Onboarding {
     setting_ref: Arc<RefCell<Result<SettingOverrides, ()>>>,
@@ -11,19 +22,48 @@ pub struct Onboarding {
     demand_scale: f64,
     start_time: Time,
     end_time: Time,
+    objective: Option<ObjectiveConfig>, // None keeps the config file's
+    demand_levels: Option<(String, Result<Option<DemandLevels>, String>)>, // Read from the config at this path, to size up the settings
+    graph_key: Option<String>, // None keeps the config file's
+    graphs: Option<(String, Graphs)>, // Read from the config at this path
 }
 
 impl Onboarding {
-    fn new(setting_ref: Arc<RefCell<Result<SettingOverrides, ()>>>) -> Self {
+    fn new(setting_ref: Arc<RefCell<Result<SettingOverrides, ()>>>, config_path: &Path) -> Self {
+        let initial = SettingOverrides::initial();
         Self {
             setting_ref,
-            is_static: false,
-            num_agents: 100,
-            demand_scale: 0.20,
-            start_time: Time { hour: 6, minute: 45, second: 0},
-            end_time: Time { hour: 19, minute: 45, second: 0 },
-            config_file_path: String::from("data/config.toml")
+            is_static: initial.is_static,
+            num_agents: initial.num_agents,
+            demand_scale: initial.demand_scale,
+            start_time: Time::from(initial.start_time),
+            end_time: Time::from(initial.end_time),
+            objective: initial.objective,
+            config_file_path: config_path.to_string_lossy().into_owned(),
+            demand_levels: None,
+            graph_key: initial.graph_key,
+            graphs: None,
+        }
+    }
+
+    // Demand levels in the config file, only read again when the path changes
+    fn demand_levels(&mut self) -> &Result<Option<DemandLevels>, String> {
+        let stale = self.demand_levels.as_ref().map_or(true, |(path, _)| *path != self.config_file_path);
+        if stale {
+            let levels = crate::resource::peek_demand_levels(Path::new(&self.config_file_path)).map_err(|err| err.to_string());
+            self.demand_levels = Some((self.config_file_path.clone(), levels));
+        }
+        &self.demand_levels.as_ref().unwrap().1
+    }
+
+    // Graphs in the config file, only read again when the path changes
+    fn graphs(&mut self) -> &Graphs {
+        let stale = !matches!(&self.graphs, Some((path, _)) if *path == self.config_file_path);
+        if stale {
+            let graphs = crate::resource::peek_graphs(Path::new(&self.config_file_path)).map_err(|err| err.to_string());
+            self.graphs = Some((self.config_file_path.clone(), graphs));
         }
+        &self.graphs.as_ref().unwrap().1
     }
 }
 
@@ -39,7 +79,7 @@ impl eframe::App for Onboarding {
                 
                 ui.separator();
                 ui.columns(3, |cols| {
-                    cols[0].label("Simulation Type: ");
+                    cols[0].label("Simulation Type: ").on_hover_text("Dynamic agents are DRT buses routed to passengers, static agents run the GTFS bus timetable");
                     cols[1].radio_value(&mut self.is_static, false, "Dynamic Agents");
                     cols[2].radio_value(&mut self.is_static, true, "Static Agents");    
                 });
@@ -47,54 +87,78 @@ impl eframe::App for Onboarding {
                 if !self.is_static {
                     ui.separator();
                     ui.columns(2, |cols| {
-                        cols[0].label("Number of agents: ");
+                        cols[0].label("Number of agents: ").on_hover_text(format!("DRT buses in the fleet, each with {} seats", DEFAULT_CAPACITY));
                         cols[1].add(eframe::egui::DragValue::new(&mut self.num_agents).speed(1).clamp_range(0..=500));
                     });
+                    ui.columns(2, |cols| {
+                        cols[0].label("Objective: ").on_hover_text("What the buses' assignments minimise, to compare operator policies on the same demand");
+                        let name = |objective: Option<ObjectiveConfig>| objective.map_or(String::from("As in config file"), |o| o.description());
+                        ComboBox::from_id_source("objective").selected_text(name(self.objective)).show_ui(&mut cols[1], |ui| {
+                            ui.selectable_value(&mut self.objective, None, name(None));
+                            for objective in ObjectiveConfig::ALL {
+                                ui.selectable_value(&mut self.objective, Some(objective), name(Some(objective)));
+                            }
+                        });
+                    });
                 }
 
                 ui.separator();
                 ui.columns(2, |cols| {
-                    cols[0].label("Start Time:");
+                    cols[0].label("Start Time:").on_hover_text("Time of day the simulation clock starts at");
                     cols[1].columns(3, |ui| {
-                        ui[0].add(DragValue::new(&mut self.start_time.hour).speed(1).clamp_range(0..=24).suffix("h"));
-                        ui[1].add(DragValue::new(&mut self.start_time.minute).speed(1).clamp_range(0..=60).suffix("m"));
-                        ui[2].add(DragValue::new(&mut self.start_time.second).speed(1).clamp_range(0..=60).suffix("s"));
+                        ui[0].add(DragValue::new(&mut self.start_time.hour).speed(1).clamp_range(0..=23).suffix("h"));
+                        ui[1].add(DragValue::new(&mut self.start_time.minute).speed(1).clamp_range(0..=59).suffix("m"));
+                        ui[2].add(DragValue::new(&mut self.start_time.second).speed(1).clamp_range(0..=59).suffix("s"));
                         
                     })
                 });
 
                 ui.columns(2, |cols| {
-                    cols[0].label("End Time:");
+                    cols[0].label("End Time:").on_hover_text("Time of day the run ends, an earlier time than the start runs on past midnight");
                     cols[1].columns(3, |ui| {
-                        ui[0].add(DragValue::new(&mut self.end_time.hour).speed(1).clamp_range(0..=24).suffix("h"));
-                        ui[1].add(DragValue::new(&mut self.end_time.minute).speed(1).clamp_range(0..=60).suffix("m"));
-                        ui[2].add(DragValue::new(&mut self.end_time.second).speed(1).clamp_range(0..=60).suffix("s"));
+                        ui[0].add(DragValue::new(&mut self.end_time.hour).speed(1).clamp_range(0..=23).suffix("h"));
+                        ui[1].add(DragValue::new(&mut self.end_time.minute).speed(1).clamp_range(0..=59).suffix("m"));
+                        ui[2].add(DragValue::new(&mut self.end_time.second).speed(1).clamp_range(0..=59).suffix("s"));
                         
                     })
                 });
                 
                 ui.separator();
                 ui.columns(2, |cols| {
-                    cols[0].label("Demand scale: ");
+                    cols[0].label("Demand scale: ").on_hover_text("Share of the config's per minute demand levels turned into passengers");
                     cols[1].add(eframe::egui::DragValue::new(&mut self.demand_scale).speed(0.01).clamp_range(0..=1));    
                 });
+                self.show_demand_hints(ui);
 
                 ui.separator();
                 ui.columns(2, |cols| {
-                    cols[0].label("Config file path: ");
+                    cols[0].label("Config file path: ").on_hover_text("TOML config with the graph, demand images and demand levels to use");
                     cols[1].add(eframe::egui::TextEdit::singleline(&mut self.config_file_path).hint_text("Path to config file"));
                 });
+                self.show_graph_picker(ui);
+
+                let (start_time, end_time) = (self.start_time.to_naive(), self.end_time.to_naive());
+                // An earlier end time runs on past midnight
+                let valid_times = end_time != start_time;
+                if !valid_times {
+                    ui.colored_label(Color32::LIGHT_RED, "End time must be different to the start time");
+                } else if end_time < start_time {
+                    ui.label("Runs on past midnight to the next day");
+                }
 
                 ui.separator();
                 ui.columns(4, |cols| {
-                    if cols[3].add(eframe::egui::Button::new("Start Sim")).clicked() {
+                    if cols[3].add_enabled(valid_times, eframe::egui::Button::new("Start Sim")).clicked() {
                         *self.setting_ref.borrow_mut() = Ok(SettingOverrides {
                             is_static: self.is_static,
                             num_agents: self.num_agents,
                             demand_scale: self.demand_scale,
-                            start_time: NaiveTime::from_hms(self.start_time.hour, self.start_time.minute, self.start_time.second),
-                            end_time: NaiveTime::from_hms(self.end_time.hour, self.end_time.minute, self.end_time.second),
-                            config_file_path: self.config_file_path.clone()
+                            start_time,
+                            end_time,
+                            objective: self.objective,
+                            config_file_path: self.config_file_path.clone(),
+                            graph_key: self.graph_key.clone(),
+                            seed: None,
                         });
                         frame.close();
                     }
@@ -112,14 +176,83 @@ impl eframe::App for Onboarding {
 }
 
 impl Onboarding {
-    pub fn run(settings_overrides: Arc<RefCell<Result<SettingOverrides, ()>>>) {
+    // Pick which of the config's graphs to run on
+    fn show_graph_picker(&mut self, ui: &mut eframe::egui::Ui) {
+        let (choices, configured) = match self.graphs() {
+            Ok(graphs) => graphs.clone(),
+            Err(err) => {
+                ui.colored_label(Color32::LIGHT_RED, format!("Couldn't read the areas from the config: {}", err));
+                return;
+            }
+        };
+        // A key picked from another config file
+        if self.graph_key.as_ref().is_some_and(|key| !choices.iter().any(|choice| &choice.key == key)) {
+            self.graph_key = None;
+        }
+
+        let as_configured = format!("As in config file ({})", configured);
+        let describe = |choice: &GraphChoice| {
+            let state = match (choice.configured, choice.saved) {
+                (true, true) => "saved",
+                (true, false) => "built on first run",
+                (false, _) => "saved, not in config",
+            };
+            format!("{} ({})", choice.key, state)
+        };
+        ui.columns(2, |cols| {
+            cols[0].label("Area: ").on_hover_text("Which of the config's [[defaults]] graphs, or the graphs saved in data/save/, to run on. Saved ones load straight away, the rest are built from the source data first");
+            let selected = choices.iter().find(|choice| Some(&choice.key) == self.graph_key.as_ref()).map_or(as_configured.clone(), describe);
+            ComboBox::from_id_source("graph").selected_text(selected).show_ui(&mut cols[1], |ui| {
+                ui.selectable_value(&mut self.graph_key, None, as_configured);
+                for choice in choices.iter() {
+                    ui.selectable_value(&mut self.graph_key, Some(choice.key.clone()), describe(choice));
+                }
+            });
+        });
+    }
+
+    // What the demand levels in the config come to with the chosen settings
+    fn show_demand_hints(&mut self, ui: &mut eframe::egui::Ui) {
+        let (scale, start, end, agents, is_static) = (self.demand_scale, self.start_time.to_naive(), self.end_time.to_naive(), self.num_agents, self.is_static);
+        let levels = match self.demand_levels() {
+            Ok(Some(levels)) => levels,
+            // Counts from a matrix or log aren't levels through the day to size the fleet against
+            Ok(None) => {
+                ui.label("The config's demand comes from an OD matrix or request log, so there are no hints for it");
+                return;
+            }
+            Err(err) => {
+                ui.colored_label(Color32::LIGHT_RED, format!("Couldn't read the demand levels from the config: {}", err));
+                return;
+            }
+        };
+        if levels.is_empty() {
+            ui.colored_label(Color32::LIGHT_RED, "The config has no demand levels, no passengers will be generated");
+            return;
+        }
+
+        let hints = DemandHints::new(levels, scale, start, end, agents, DEFAULT_CAPACITY);
+        ui.label(format!("About {:.0} requests over the run, peaking at {:.0} an hour", hints.total, hints.peak_per_hour));
+        if is_static {
+            return;
+        }
+        ui.label(format!("Suggested agents for the peak: {}", hints.suggested_agents))
+            .on_hover_text(format!("Assuming {:.0} minute rides and {:.0}% of the seats filled", RIDE_MINUTES, TARGET_LOAD * 100.0));
+        if hints.over_capacity {
+            ui.colored_label(Color32::LIGHT_RED, format!("{} agents can't carry the peak hour even with every seat full", agents));
+        }
+    }
+
+    // Starts with the config file at `config_path`, which can be changed in the window
+    pub fn run(settings_overrides: Arc<RefCell<Result<SettingOverrides, ()>>>, config_path: &Path) {
+        let config_path = config_path.to_path_buf();
         let mut options = eframe::NativeOptions::default();
-        options.initial_window_size = Some(eframe::egui::vec2(450.0, 300.0));
+        options.initial_window_size = Some(eframe::egui::vec2(450.0, 430.0)); // Room for the demand hints and area picker
         options.centered = true;
         options.resizable = false;
 
         match eframe::run_native("ODBRS Onboarding", options, 
-            Box::new(|_cc| Box::new(Onboarding::new(settings_overrides)))
+            Box::new(move |_cc| Box::new(Onboarding::new(settings_overrides, &config_path)))
         ) {
             Ok(_) => (),
             Err(e) => {
@@ -134,12 +267,103 @@ struct Time {
     second: u32
 }
 
-#[derive(Default, Clone)]
+impl Time {
+    fn to_naive(&self) -> NaiveTime {
+        NaiveTime::from_hms_opt(self.hour, self.minute, self.second).unwrap_or_default()
+    }
+}
+
+impl From<NaiveTime> for Time {
+    fn from(time: NaiveTime) -> Self {
+        Time { hour: time.hour(), minute: time.minute(), second: time.second() }
+    }
+}
+
+// What the demand levels come to over a run, and the DRT fleet they need
+#[derive(Debug, Clone, PartialEq)]
+struct DemandHints {
+    total: f64, // Requests expected over the whole run
+    peak_per_hour: f64, // Requests in the busiest hour of the run
+    suggested_agents: usize,
+    over_capacity: bool, // The peak needs more than every seat in the fleet
+}
+
+impl DemandHints {
+    fn new(levels: &DemandLevels, scale: f64, start: NaiveTime, end: NaiveTime, agents: usize, seats: u8) -> Self {
+        let start_minute = start.num_seconds_from_midnight() / 60;
+        let mut minutes = (end.num_seconds_from_midnight() / 60 + 24 * 60 - start_minute) % (24 * 60);
+        if minutes == 0 && end != start {
+            minutes = 24 * 60;
+        }
+
+        let time_of = |minute: u32| NaiveTime::from_num_seconds_from_midnight_opt((start_minute + minute) % (24 * 60) * 60, 0).unwrap();
+        let total = (0..minutes).map(|minute| levels.per_minute(time_of(minute)) * scale).sum();
+        let peak_per_hour = (0..minutes)
+            .map(|minute| levels.per_minute(time_of(minute)) * scale * 60.0)
+            .fold(0.0, f64::max);
+
+        let per_agent_hour = seats as f64 * 60.0 / RIDE_MINUTES;
+        DemandHints {
+            total,
+            peak_per_hour,
+            suggested_agents: (peak_per_hour / (per_agent_hour * TARGET_LOAD)).ceil() as usize,
+            over_capacity: peak_per_hour > agents as f64 * per_agent_hour,
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
 pub struct SettingOverrides {
     pub is_static: bool, // whether to use static (true) or dynamic agents (false)
     pub num_agents: usize, // number of dynamic agents to use
     pub demand_scale: f64, // scale factor for demand
     pub config_file_path: String, // path to the config file for the data
     pub start_time: NaiveTime,
-    pub end_time: NaiveTime
-}
\ No newline at end of file
+    pub end_time: NaiveTime,
+    pub objective: Option<ObjectiveConfig>, // What DRT assignments minimise, None keeps the config file's
+    pub graph_key: Option<String>, // Which `[[defaults]]` graph to run on, None keeps the config file's
+    pub seed: Option<u64>, // Random seed for the run, None keeps the config file's
+}
+
+impl SettingOverrides {
+    // The settings onboarding starts with, also used for anything not given on the command line
+    pub fn initial() -> Self {
+        SettingOverrides {
+            is_static: false,
+            num_agents: 100,
+            demand_scale: 0.20,
+            config_file_path: String::from("data/config.toml"),
+            start_time: NaiveTime::from_hms_opt(6, 45, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(19, 45, 0).unwrap(),
+            objective: None,
+            graph_key: None,
+            seed: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hints_follow_the_demand_in_the_window() {
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        // Levels are for the hours 1..=24, so 10 a minute from 07:00 to 08:00 and 2 otherwise
+        let mut levels = vec![2; 24];
+        levels[7] = 10;
+        let levels = DemandLevels::from_minute_demand(&levels);
+
+        let hints = DemandHints::new(&levels, 0.5, time(6), time(9), 10, 20);
+        assert_eq!(hints.total, (60.0 * 2.0 + 60.0 * 10.0 + 60.0 * 2.0) * 0.5);
+        assert_eq!(hints.peak_per_hour, 300.0);
+        // Each bus carries 60 an hour with every seat full, 36 at the target load
+        assert_eq!(hints.suggested_agents, 9);
+        assert!(!hints.over_capacity);
+        assert!(DemandHints::new(&levels, 0.5, time(6), time(9), 4, 20).over_capacity);
+
+        // Runs on past midnight rather than being empty
+        let hints = DemandHints::new(&levels, 1.0, time(23), time(1), 10, 20);
+        assert_eq!(hints.total, 2.0 * 120.0);
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use eframe::{
+    egui::Context,
+    epaint::{Color32, Shape, Stroke},
+};
+
+use crate::{
+    graph::{transform::Transform, Graph},
+    simulation::demand::DemandGenerator,
+};
+
+/// Everything the worker needs to lay out one frame of the map
+pub struct RenderJob {
+    pub transform: Transform,
+    pub agents: Vec<Shape>,
+    pub demand_gen: Option<Arc<DemandGenerator>>,
+    pub ctx: Context,
+}
+
+/// Worker thread which builds the screen-space shapes for the map so the
+/// UI thread only has to hand the finished frame to the painter
+pub struct RenderPrep {
+    job_tx: Sender<RenderJob>,
+    frame_rx: Receiver<(Transform, Vec<Shape>)>,
+    in_flight: bool,
+    last_submitted: Option<Transform>,
+    frame: Vec<Shape>,
+}
+
+impl RenderPrep {
+    pub fn start(graph: Arc<Graph>) -> Self {
+        let (job_tx, job_rx) = channel::<RenderJob>();
+        let (frame_tx, frame_rx) = channel();
+
+        thread::spawn(move || {
+            // Worker ends when the GUI drops its end of the job channel
+            while let Ok(mut job) = job_rx.recv() {
+                // Skip straight to the most recent job if several queued up
+                while let Ok(newer) = job_rx.try_recv() {
+                    job = newer;
+                }
+
+                let shapes = prepare_frame(&graph, &job);
+                if frame_tx.send((job.transform, shapes)).is_err() {
+                    break;
+                }
+                job.ctx.request_repaint();
+            }
+            println!("[GUI] Render prep worker stopped");
+        });
+
+        RenderPrep {
+            job_tx,
+            frame_rx,
+            in_flight: false,
+            last_submitted: None,
+            frame: Vec::new(),
+        }
+    }
+
+    // Whether the view has moved since the last job was sent
+    pub fn transform_changed(&self, transform: &Transform) -> bool {
+        self.last_submitted.as_ref() != Some(transform)
+    }
+
+    // Hand a job to the worker, unless it is still busy with the previous one
+    pub fn submit(&mut self, job: RenderJob) {
+        if self.in_flight {
+            return;
+        }
+
+        self.last_submitted = Some(job.transform.clone());
+        match self.job_tx.send(job) {
+            Ok(()) => self.in_flight = true,
+            Err(err) => eprintln!("[GUI] Couldn't send render job {:?}", err),
+        }
+    }
+
+    // Collect any finished frame and return the latest shapes to paint
+    pub fn poll(&mut self) -> &Vec<Shape> {
+        while let Ok((_, frame)) = self.frame_rx.try_recv() {
+            self.frame = frame;
+            self.in_flight = false;
+        }
+        &self.frame
+    }
+}
+
+fn prepare_frame(graph: &Graph, job: &RenderJob) -> Vec<Shape> {
+    let transform = &job.transform;
+    let mut shapes = graph.create_paint_shapes_with(transform);
+
+    shapes.extend(
+        job.agents
+            .iter()
+            .map(|shp| transform.map_shape_to_screen(shp.clone())),
+    );
+
+    // Draw demand data
+    if let Some(demand_gen) = &job.demand_gen {
+        let demand_queue = demand_gen
+            .get_demand_queue()
+            .read()
+            .expect("Render prep couldn't read demand_gen");
+
+        shapes.extend(demand_queue.iter().map(|demand| {
+            Shape::Vec(vec![
+                Shape::circle_stroke(
+                    transform.map_to_screen(demand.0 .0 as _, demand.0 .1 as _),
+                    1.0,
+                    Stroke::new(1.5, Color32::LIGHT_GREEN),
+                ),
+                Shape::circle_stroke(
+                    transform.map_to_screen(demand.1 .0 as _, demand.1 .1 as _),
+                    1.0,
+                    Stroke::new(1.5, Color32::LIGHT_RED),
+                ),
+            ])
+        }));
+    }
+
+    shapes
+}
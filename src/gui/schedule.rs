@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+/// Tracks how often a panel should refresh its content, so cheap panels don't
+/// have to rebuild every frame just because the map is being redrawn
+#[derive(Debug, Clone)]
+pub struct PanelSchedule {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl PanelSchedule {
+    // Create a schedule updating at the given rate (updates per second)
+    pub fn from_rate(rate: f32) -> Self {
+        let rate = if rate > 0.0 { rate } else { 1.0 };
+        PanelSchedule {
+            interval: Duration::from_secs_f32(1.0 / rate),
+            last: None,
+        }
+    }
+
+    // Returns true (and resets the timer) if the panel should refresh now
+    pub fn due(&mut self) -> bool {
+        match self.last {
+            Some(last) if last.elapsed() < self.interval => false,
+            _ => {
+                self.last = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    // How long until this panel next wants to refresh
+    pub fn remaining(&self) -> Duration {
+        match self.last {
+            Some(last) => self.interval.saturating_sub(last.elapsed()),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Default for PanelSchedule {
+    fn default() -> Self {
+        PanelSchedule::from_rate(30.0)
+    }
+}
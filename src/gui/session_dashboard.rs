@@ -0,0 +1,157 @@
+use eframe::egui::{Context, Grid, ScrollArea, Vec2, Window};
+
+use crate::analytics::{report::RunKpis, RunMetadata};
+
+use super::App;
+
+/// A run finished since the app was opened, numbered in the order they finished
+#[derive(Debug, Clone)]
+pub struct SessionRun {
+    pub number: usize,
+    pub metadata: RunMetadata,
+    pub kpis: RunKpis,
+}
+
+type Cell = fn(&SessionRun) -> String;
+type Key = fn(&SessionRun) -> Option<f64>;
+
+// Heading, cell text and the number it's sorted by, text columns sort by their text
+const COLUMNS: [(&str, Cell, Option<Key>); 14] = [
+    ("Run", |run| run.number.to_string(), Some(|run| Some(run.number as f64))),
+    ("Mode", |run| String::from(if run.metadata.mixed { "mixed" } else if run.metadata.static_only { "static" } else { "dynamic" }), None),
+    ("Agents", |run| run.metadata.agent_count.to_string(), Some(|run| Some(run.metadata.agent_count as f64))),
+    ("Demand", |run| format!("x{}", run.metadata.demand_scale), Some(|run| Some(run.metadata.demand_scale))),
+    ("Seed", |run| run.metadata.seed.to_string(), Some(|run| Some(run.metadata.seed as f64))),
+    ("Walk detour", |run| format!("{:.2}", run.metadata.walk_detour_factor), Some(|run| Some(run.metadata.walk_detour_factor))),
+    ("Result", |run| run.kpis.result.clone(), None),
+    ("Requests", |run| run.kpis.requests.to_string(), Some(|run| Some(run.kpis.requests as f64))),
+    ("Completed %", |run| cell(run.kpis.completion()), Some(|run| run.kpis.completion())),
+    ("Wait (min)", |run| cell(run.kpis.average_wait()), Some(|run| run.kpis.average_wait())),
+    ("Ride (min)", |run| cell(run.kpis.average_ride()), Some(|run| run.kpis.average_ride())),
+    ("Distance (km)", |run| format!("{:.1}", run.kpis.distance_km), Some(|run| Some(run.kpis.distance_km))),
+    ("Empty %", |run| cell(run.kpis.empty_share()), Some(|run| run.kpis.empty_share())),
+    ("Unserved", |run| run.kpis.unserved.to_string(), Some(|run| Some(run.kpis.unserved as f64))),
+];
+
+pub(super) fn cell(value: Option<f64>) -> String {
+    value.map(|value| format!("{:.1}", value)).unwrap_or_else(|| String::from("-"))
+}
+
+/// Every run finished in this window, so changes made between restarts can be compared without
+/// leaving the app. Forgotten when it closes, the outputs on disk are still there for that
+#[derive(Debug, Default)]
+pub struct SessionDashboard {
+    runs: Vec<SessionRun>,
+    sort: usize, // Column the table is sorted by
+    descending: bool,
+    hidden: bool, // Closed until the next run finishes
+}
+
+impl SessionDashboard {
+    pub fn add(&mut self, metadata: RunMetadata, kpis: RunKpis) {
+        self.runs.push(SessionRun { number: self.runs.len() + 1, metadata, kpis });
+        self.hidden = false;
+    }
+
+    // Clicking the column already sorted by turns the order round
+    fn sort_by(&mut self, column: usize) {
+        if self.sort == column {
+            self.descending = !self.descending;
+        } else {
+            self.sort = column;
+            self.descending = false;
+        }
+    }
+
+    // Runs in the order the table shows them, runs missing a number go last either way
+    fn sorted(&self) -> Vec<&SessionRun> {
+        let (_, text, key) = COLUMNS[self.sort];
+        let mut runs: Vec<&SessionRun> = self.runs.iter().collect();
+        runs.sort_by(|a, b| {
+            let order = match key {
+                Some(key) => match (key(a), key(b)) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (a, b) => return b.is_some().cmp(&a.is_some()),
+                },
+                None => text(a).cmp(&text(b)),
+            };
+            if self.descending { order.reverse() } else { order }
+        });
+        runs
+    }
+}
+
+// Table of this session's runs, once one has finished
+pub fn render_session_dashboard(app: &mut App, ctx: &Context, _frame: &mut eframe::Frame) {
+    if app.session.runs.is_empty() || app.session.hidden {
+        return;
+    }
+
+    let mut open = true;
+    let mut content = Vec2::ZERO;
+    let mut clicked = None;
+    let window = Window::new("Session Runs").id("session_dashboard".into()).default_width(600.0);
+    let window = app.layout.place("session", window).open(&mut open).show(ctx, |ui| {
+        content = ui.max_rect().size();
+        ui.label("Runs finished since the app opened, click a heading to sort");
+        ScrollArea::both().show(ui, |ui| {
+            Grid::new("session_runs").striped(true).show(ui, |ui| {
+                for (i, (heading, _, _)) in COLUMNS.iter().enumerate() {
+                    let arrow = match (app.session.sort == i, app.session.descending) {
+                        (true, false) => " ^",
+                        (true, true) => " v",
+                        (false, _) => "",
+                    };
+                    if ui.selectable_label(app.session.sort == i, format!("{}{}", heading, arrow)).clicked() {
+                        clicked = Some(i);
+                    }
+                }
+                ui.end_row();
+
+                for run in app.session.sorted() {
+                    for (_, text, _) in COLUMNS.iter() {
+                        ui.label(text(run));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    });
+
+    if let Some(window) = window {
+        app.layout.remember("session", window.response.rect.min, content);
+    }
+    if let Some(column) = clicked {
+        app.session.sort_by(column);
+    }
+    if !open {
+        app.session.hidden = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_sort_by_the_clicked_column() {
+        let mut session = SessionDashboard::default();
+        for (agents, completed) in [(10, 5), (30, 0), (20, 8)] {
+            let metadata = RunMetadata { agent_count: agents, ..Default::default() };
+            session.add(metadata, RunKpis { requests: if completed > 0 { 10 } else { 0 }, completed, ..Default::default() });
+        }
+        let numbers = |session: &SessionDashboard| session.sorted().iter().map(|run| run.number).collect::<Vec<_>>();
+        assert_eq!(numbers(&session), vec![1, 2, 3]);
+
+        session.sort_by(2);
+        assert_eq!(numbers(&session), vec![1, 3, 2]);
+        session.sort_by(2);
+        assert_eq!(numbers(&session), vec![2, 3, 1]);
+
+        // The run with no requests has no completion rate, and stays at the bottom
+        session.sort_by(8);
+        assert_eq!(numbers(&session), vec![1, 3, 2]);
+        session.sort_by(8);
+        assert_eq!(numbers(&session), vec![3, 1, 2]);
+    }
+}
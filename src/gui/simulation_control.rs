@@ -1,72 +1,69 @@
-use std::{cell::RefCell, rc::Rc, sync::mpsc::Sender};
+use std::{cell::RefCell, path::PathBuf, rc::Rc, sync::mpsc::Sender};
 
-use eframe::{egui::{Ui, Slider, Context, Window}, epaint::{vec2}};
+use eframe::{egui::{Ui, Slider, Context, Window}, epaint::{vec2, Vec2}};
 
 use crate::simulation::{SimulationMessage, SimulationState};
 
-use super::{AppState, Control, App};
+use super::{AppState, Control, App, schedule::PanelSchedule};
 
 pub struct SimulationControl {
     pub app_state: Rc<RefCell<AppState>>,
     pub sim_tx: Sender<SimulationMessage>,
-    pub state: ControlState,
     pub speed: u64,
+    pub schedule: PanelSchedule, // How often the status line is refreshed
+    pub status_text: String,
 }
 
-#[derive(PartialEq, Eq)]
-pub enum ControlState {
-    Running,
-    Paused,
-    Stopped,
+impl SimulationControl {
+    fn send(&self, msg: SimulationMessage) {
+        match self.sim_tx.send(msg) {
+            Ok(()) => (),
+            Err(err) => eprintln!("Send Error {:?}", err),
+        }
+    }
 }
 
 impl Control for SimulationControl {
     fn view_control(&mut self, ui: &mut Ui) {
-        ui.label(format!(
-            "Tick #{}. State: {:?}",
-            self.app_state.borrow().sim_state.0.format("%H:%M %d/%m/%Y"),
-            self.app_state.borrow().sim_state.1
-        ));
+        if self.schedule.due() {
+            self.status_text = format!(
+                "Tick #{}. State: {:?}",
+                self.app_state.borrow().sim_state.0.format("%H:%M %d/%m/%Y"),
+                self.app_state.borrow().sim_state.1
+            );
+        }
+        ui.label(&self.status_text);
+        for (name, summary) in &self.app_state.borrow().fleet_summaries {
+            ui.label(format!(
+                "{}: {} vehicles, {}/{} on board, {} queued",
+                name, summary.active_vehicles, summary.occupancy, summary.capacity, summary.queued_requests
+            ));
+        }
 
+        // Buttons follow the state the simulation last reported, which only changes once it's
+        // handled the message, so they can't get out of step with it
+        let state = self.app_state.borrow().sim_state.1;
         ui.horizontal(|ui| {
-            match self.state {
-                ControlState::Stopped => {},
-                ControlState::Paused => ui.columns(2, |ui| {
-                    if ui[0].button("Start").clicked() {
-                        self.state = ControlState::Running;
-                        match self.sim_tx
-                            .send(SimulationMessage::ChangeState(SimulationState::Running)) {
-                                Ok(()) => (),
-                                Err(err) => eprintln!("Send Error {:?}", err)
-                            }
+            match state {
+                SimulationState::Idle => {
+                    if ui.button("Start").clicked() {
+                        self.send(SimulationMessage::ChangeState(SimulationState::Running));
+                    }
+                }
+                SimulationState::Running | SimulationState::Paused => ui.columns(2, |ui| {
+                    let (label, next) = if state == SimulationState::Running { ("Pause", SimulationState::Paused) } else { ("Resume", SimulationState::Running) };
+                    if ui[0].button(label).clicked() {
+                        self.send(SimulationMessage::ChangeState(next));
                     }
                     if ui[1].button("Stop").clicked() {
-                        self.state = ControlState::Stopped;
-                        match self.sim_tx
-                            .send(SimulationMessage::ChangeState(SimulationState::Stopped)) {
-                                Ok(()) => (),
-                                Err(err) => eprintln!("Send Error {:?}", err)
-                            };
+                        self.send(SimulationMessage::ChangeState(SimulationState::Finished));
                     }
                 }),
-                ControlState::Running => ui.columns(2, |ui| {
-                    if ui[0].button("Pause").clicked() {
-                        self.state = ControlState::Paused;
-                        match self.sim_tx
-                            .send(SimulationMessage::ChangeState(SimulationState::Paused)) {
-                                Ok(()) => (),
-                                Err(err) => eprintln!("Send Error {:?}", err),
-                            }
-                    }
-                    if ui[1].button("Stop").clicked() {
-                        self.state = ControlState::Stopped;
-                        match self.sim_tx
-                            .send(SimulationMessage::ChangeState(SimulationState::Stopped)) {
-                                Ok(()) => (),
-                                Err(err) => eprintln!("Send Error {:?}", err)
-                            };
+                SimulationState::Finished => {
+                    if ui.button("Restart").clicked() {
+                        self.send(SimulationMessage::Restart);
                     }
-                })
+                }
             }
         });
 
@@ -75,20 +72,31 @@ impl Control for SimulationControl {
         let slider = Slider::new(&mut self.speed, 1..=10000).text("Tick Speed");
         let resp = ui.add(slider);
         if resp.changed() {
-            match self.sim_tx.send(SimulationMessage::ChangeSpeed(self.speed)) {
-                Ok(_) => (),
-                Err(err) => eprintln!("Send Error {:?}", err)
-            }
+            self.app_state.borrow_mut().tick_speed = self.speed;
+            self.send(SimulationMessage::ChangeSpeed(self.speed));
+        }
+
+        // Saved between ticks, resume it by setting `resume` in the simulation config
+        if state != SimulationState::Finished && ui.button("Save Snapshot").clicked() {
+            let time = self.app_state.borrow().sim_state.0;
+            let path = PathBuf::from(format!("data/save/snapshot-{}.cbor", time.format("%H-%M")));
+            self.send(SimulationMessage::SaveSnapshot(path));
         }
     }
 }
 
 pub fn render_control(app_state: &mut App, ctx: &Context, _frame: &mut eframe::Frame) {
-    Window::new("Simulation Controls").default_size(vec2(300.0, 500.0)).show(ctx, |ui| {
+    let mut content = Vec2::ZERO;
+    let window = app_state.layout.place("controls", Window::new("Simulation Controls").default_size(vec2(300.0, 500.0))).show(ctx, |ui| {
+        content = ui.max_rect().size();
         
         for (i, control) in app_state.controls.iter_mut().enumerate() {
             if i != 0 { ui.separator(); }
             control.view_control(ui);
         }
     });
+
+    if let Some(window) = window {
+        app_state.layout.remember("controls", window.response.rect.min, content);
+    }
 }
\ No newline at end of file
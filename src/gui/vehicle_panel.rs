@@ -0,0 +1,141 @@
+use eframe::{
+    egui::{
+        plot::{Line, Plot, PlotPoints},
+        ComboBox, Context, Ui, Vec2, Window,
+    },
+    epaint::{pos2, Color32, Shape, Stroke},
+};
+
+use crate::{
+    analytics::{entity, plans::RouteComparison, VehicleMetrics},
+    graph::Graph,
+    simulation::{display::DisplayLayer, escalation::ServiceMode},
+};
+
+use super::App;
+
+const FOLLOWED_COLOUR: Color32 = Color32::from_rgb(0, 200, 0);
+const DROPPED_COLOUR: Color32 = Color32::GRAY;
+const DETOUR_COLOUR: Color32 = Color32::from_rgb(230, 0, 0);
+
+// Live analytics for the vehicle selected on the map
+pub fn render_vehicle_panel(app: &mut App, ctx: &Context, _frame: &mut eframe::Frame) {
+    let selected = match app.state.borrow().selected_vehicle {
+        Some(id) => id,
+        None => {
+            app.state.borrow_mut().display_layers.remove(&DisplayLayer::RouteComparison);
+            return;
+        }
+    };
+
+    let mut open = true;
+    let mut content = Vec2::ZERO;
+    let (mut show_plan, mut shown_plan) = {
+        let state = app.state.borrow();
+        (state.show_plan, state.shown_plan)
+    };
+    let mut comparison_shapes = Vec::new();
+    let (fleet, fleet_id) = entity::vehicle_fleet(selected);
+    let window = Window::new(format!("{} vehicle {}", entity::fleet_name(fleet), fleet_id)).id("vehicle_panel".into()).default_width(300.0);
+    let window = app.layout.place("vehicle", window)
+        .open(&mut open)
+        .show(ctx, |ui| {
+            content = ui.max_rect().size();
+            let metrics = match app.metrics.vehicle(selected) {
+                Some(metrics) => metrics,
+                None => {
+                    ui.label("No analytics for this vehicle yet");
+                    return;
+                }
+            };
+
+            ui.label(format!("Distance: {:.1} km", metrics.distance / 1000.0));
+            ui.label(format!("Picked up: {}", metrics.pickups));
+            ui.label(format!("Dropped off: {}", metrics.dropoffs));
+            if metrics.denied_boardings > 0 {
+                ui.label(format!("Turned away when full: {}", metrics.denied_boardings));
+            }
+            ui.label(format!("Empty: {:.1} km", metrics.empty_distance / 1000.0));
+            ui.label(format!("Occupancy: {:.1} average, {} peak", metrics.average_occupancy(), metrics.peak_occupancy()));
+
+            ui.separator();
+            ui.label("Passengers on board by tick");
+            // Occupancy only changes at a pick up or drop off, so draw it as steps
+            let mut points = Vec::with_capacity(metrics.occupancy.len() * 2);
+            let mut onboard = 0.0;
+            for (tick, count) in metrics.occupancy.iter() {
+                points.push([*tick as f64, onboard]);
+                onboard = *count as f64;
+                points.push([*tick as f64, onboard]);
+            }
+            Plot::new("vehicle_occupancy")
+                .height(150.0)
+                .allow_scroll(false)
+                .show(ui, |plot_ui| plot_ui.line(Line::new(PlotPoints::new(points))));
+
+            if fleet == ServiceMode::Drt {
+                ui.separator();
+                comparison_shapes = compare_plan(ui, &metrics, &app.graph, &mut show_plan, &mut shown_plan);
+            }
+        });
+
+    if let Some(window) = window {
+        app.layout.remember("vehicle", window.response.rect.min, content);
+    }
+
+    let mut state = app.state.borrow_mut();
+    state.show_plan = show_plan;
+    state.shown_plan = shown_plan;
+    if !open {
+        state.selected_vehicle = None;
+    }
+    if open && show_plan && !comparison_shapes.is_empty() {
+        state.display_layers.insert(DisplayLayer::RouteComparison, comparison_shapes);
+    } else {
+        state.display_layers.remove(&DisplayLayer::RouteComparison);
+    }
+}
+
+// One of the bus's plans next to what it drove after, returning the map shapes if it's shown
+fn compare_plan(ui: &mut Ui, metrics: &VehicleMetrics, graph: &Graph, show_plan: &mut bool, shown_plan: &mut Option<u32>) -> Vec<Shape> {
+    ui.label("Planned against driven route");
+    let Some(latest) = metrics.plans.last() else {
+        ui.label("No passengers given to it yet");
+        return Vec::new();
+    };
+    let plan = shown_plan.and_then(|id| metrics.plans.iter().find(|plan| plan.passenger_id == id)).unwrap_or(latest);
+
+    let describe = |passenger: u32, time: chrono::DateTime<chrono::Utc>| format!("Passenger {} at {}", passenger, time.format("%H:%M:%S"));
+    let mut picked = plan.passenger_id;
+    ComboBox::from_id_source("vehicle_plan").selected_text(describe(plan.passenger_id, plan.time)).show_ui(ui, |ui| {
+        for plan in metrics.plans.iter().rev() {
+            ui.selectable_value(&mut picked, plan.passenger_id, describe(plan.passenger_id, plan.time));
+        }
+    });
+    if picked != plan.passenger_id {
+        *shown_plan = Some(picked);
+    }
+    ui.checkbox(show_plan, "Show on the map")
+        .on_hover_text("Green where it drove as planned, grey where the plan was dropped and red where it went instead");
+
+    let comparison = RouteComparison::new(metrics.plans.iter().find(|plan| plan.passenger_id == picked).unwrap_or(plan), &metrics.driven);
+    let (dropped, detours) = (comparison.dropped(), comparison.detours());
+    if comparison.on_plan() {
+        ui.label(format!("On plan, {} of {} roads driven", comparison.driven.len(), comparison.planned.len()));
+    } else {
+        ui.label(format!("Followed the plan for {} roads, then drove {} off it and left {} planned roads", comparison.followed, detours.len(), dropped.len()));
+    }
+
+    if !*show_plan {
+        return Vec::new();
+    }
+    let line = |edge: &u128, colour: Color32| {
+        let edge = graph.get_edgelist().get(edge)?;
+        Some(Shape::line(edge.points.iter().map(|(x, y)| pos2(*x as f32, *y as f32)).collect(), Stroke::new(4.0, colour)))
+    };
+    let followed = comparison.driven.iter().filter(|edge| !detours.contains(edge));
+    dropped.iter().filter_map(|edge| line(edge, DROPPED_COLOUR))
+        .chain(followed.filter_map(|edge| line(edge, FOLLOWED_COLOUR)))
+        .chain(detours.iter().filter_map(|edge| line(edge, DETOUR_COLOUR)))
+        .collect()
+}
@@ -0,0 +1,43 @@
+use std::sync::mpsc::Sender;
+
+use eframe::egui::{DragValue, Ui};
+
+use crate::simulation::SimulationMessage;
+
+use super::Control;
+
+/// Toggle the debug overlay of passenger walking legs
+pub struct WalkingControl {
+    sim_tx: Sender<SimulationMessage>,
+    enabled: bool,
+    every: u32, // Draw one in this many passengers
+}
+
+impl WalkingControl {
+    pub fn new(sim_tx: Sender<SimulationMessage>) -> Self {
+        WalkingControl { sim_tx, enabled: false, every: 10 }
+    }
+
+    fn send(&self) {
+        let every = if self.enabled { self.every.max(1) } else { 0 };
+        match self.sim_tx.send(SimulationMessage::SetWalkingOverlay(every)) {
+            Ok(()) => (),
+            Err(err) => eprintln!("Send Error {:?}", err),
+        }
+    }
+}
+
+impl Control for WalkingControl {
+    fn view_control(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            let toggled = ui.checkbox(&mut self.enabled, "Show walking legs").changed();
+            ui.label("1 in");
+            let resized = ui.add(DragValue::new(&mut self.every).clamp_range(1..=1000)).changed();
+            ui.label("passengers");
+
+            if toggled || (resized && self.enabled) {
+                self.send();
+            }
+        });
+    }
+}
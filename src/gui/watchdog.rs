@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use eframe::{
+    egui::{Align2, Context, Window},
+    epaint::{vec2, Color32},
+};
+
+use crate::simulation::{SimulationMessage, SimulationState};
+
+use super::App;
+
+pub const DEFAULT_TIMEOUT_SECONDS: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchdogStatus {
+    Ok,
+    Stalled(Duration), // Running but nothing heard for this long
+    Ended, // The thread went away while running, most likely a panic
+}
+
+/// Notices when the simulation thread stops sending state updates while it should be running,
+/// e.g. stuck in a search loop or killed by a panic
+#[derive(Debug)]
+pub struct Watchdog {
+    timeout: Duration,
+    last_update: Instant,
+    disconnected: bool,
+    force_stop: Arc<AtomicBool>, // Set when the user gives up on the thread, main then won't wait for it
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog::new(DEFAULT_TIMEOUT_SECONDS, Arc::default())
+    }
+}
+
+impl Watchdog {
+    pub fn new(timeout_seconds: f32, force_stop: Arc<AtomicBool>) -> Self {
+        Watchdog {
+            timeout: Duration::from_secs_f32(timeout_seconds.max(0.0)),
+            last_update: Instant::now(),
+            disconnected: false,
+            force_stop,
+        }
+    }
+
+    pub fn note_update(&mut self) {
+        self.last_update = Instant::now();
+    }
+
+    pub fn note_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+
+    // Give the simulation another full timeout before warning again
+    pub fn snooze(&mut self) {
+        self.last_update = Instant::now();
+    }
+
+    pub fn status(&self, state: SimulationState, tick_delay: Duration) -> WatchdogStatus {
+        self.check(state, tick_delay, self.last_update.elapsed())
+    }
+
+    fn check(&self, state: SimulationState, tick_delay: Duration, waited: Duration) -> WatchdogStatus {
+        // Only running simulations send updates
+        if state != SimulationState::Running {
+            return WatchdogStatus::Ok;
+        }
+        if self.disconnected {
+            return WatchdogStatus::Ended;
+        }
+        // A slow tick speed means a long sleep between updates
+        if waited > self.timeout + tick_delay {
+            WatchdogStatus::Stalled(waited)
+        } else {
+            WatchdogStatus::Ok
+        }
+    }
+
+    pub fn force_stop(&self) {
+        self.force_stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Warning window shown while the simulation looks stuck
+pub fn render_watchdog(app: &mut App, ctx: &Context, frame: &mut eframe::Frame) {
+    let (tick, state, tick_speed) = {
+        let state = app.state.borrow();
+        (state.sim_state.0, state.sim_state.1, state.tick_speed)
+    };
+
+    let status = app.watchdog.status(state, Duration::from_millis(tick_speed));
+    if status == WatchdogStatus::Ok {
+        return;
+    }
+
+    // Keep checking even though no messages are arriving to trigger a repaint
+    ctx.request_repaint_after(Duration::from_secs(1));
+
+    Window::new("Simulation not responding")
+        .anchor(Align2::CENTER_CENTER, vec2(0.0, 0.0))
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            match status {
+                WatchdogStatus::Stalled(waited) => {
+                    ui.colored_label(Color32::YELLOW, format!("No update from the simulation for {}s", waited.as_secs()));
+                }
+                WatchdogStatus::Ended => {
+                    ui.colored_label(Color32::LIGHT_RED, "The simulation thread ended unexpectedly, check the console for a panic");
+                }
+                WatchdogStatus::Ok => {}
+            }
+            ui.label(format!("Last tick: {}", tick.format("%H:%M %d/%m/%Y")));
+
+            ui.horizontal(|ui| {
+                if let WatchdogStatus::Stalled(_) = status {
+                    if ui.button("Keep waiting").clicked() {
+                        app.watchdog.snooze();
+                    }
+                }
+                if ui.button("Force stop").clicked() {
+                    println!("[Watchdog] Force stopping the simulation at {}", tick);
+                    if let Some(sim_tx) = app.sim_tx.as_ref() {
+                        // Only gets through if the thread recovers
+                        let _ = sim_tx.send(SimulationMessage::ShutdownThread);
+                    }
+                    app.watchdog.force_stop();
+                    frame.close();
+                }
+            });
+        });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_warns_while_running() {
+        let mut watchdog = Watchdog::new(10.0, Arc::default());
+        let long = Duration::from_secs(60);
+
+        assert_eq!(watchdog.check(SimulationState::Running, Duration::ZERO, Duration::from_secs(5)), WatchdogStatus::Ok);
+        assert_eq!(watchdog.check(SimulationState::Running, Duration::ZERO, long), WatchdogStatus::Stalled(long));
+        assert_eq!(watchdog.check(SimulationState::Running, long, long), WatchdogStatus::Ok);
+        assert_eq!(watchdog.check(SimulationState::Paused, Duration::ZERO, long), WatchdogStatus::Ok);
+
+        watchdog.note_disconnected();
+        assert_eq!(watchdog.check(SimulationState::Running, Duration::ZERO, Duration::ZERO), WatchdogStatus::Ended);
+        assert_eq!(watchdog.check(SimulationState::Finished, Duration::ZERO, Duration::ZERO), WatchdogStatus::Ok);
+    }
+}
@@ -0,0 +1,237 @@
+//! On demand bus simulation: a road graph, fixed route and DRT fleets moving on it, and the
+//! analytics of the passengers they carry. The `odbrs` binary drives it from the command line and
+//! GUI, see `graph::synthetic` for running it from code.
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, mpsc::{self, RecvTimeoutError, Sender}, Arc},
+    thread, time::Duration,
+};
+
+use event_bus::{DropPolicy, EventBus, Subscriber};
+use gui::{onboarding::SettingOverrides, AppMessage};
+use simulation::{SimulationMessage, SimulationState};
+
+pub mod graph;
+pub mod gui;
+pub mod resource;
+pub mod simulation;
+pub mod analytics;
+pub mod batch;
+pub mod cli;
+pub mod event_bus;
+pub mod geometry;
+pub mod realtime;
+pub mod units;
+#[cfg(feature = "prometheus")]
+mod telemetry;
+
+pub trait Module: Default {
+    type ReturnType;
+    type Configuration: Default;
+    type Parameters;
+
+    fn get_name(&self) -> &str;
+
+    fn init(
+        &mut self,
+        config: Self::Configuration,
+        parameters: Self::Parameters,
+    ) -> Result<Self::ReturnType, Box<dyn Error>>;
+}
+
+const GUI_QUEUE: usize = 256; // Messages for the display held before the oldest are dropped
+
+#[derive(Default)]
+pub struct Main {
+    pub resource_manager: resource::Resources,
+    pub gui: gui::App,
+    pub simulation: simulation::Simulation,
+    pub analytics: analytics::Analytics,
+    // The analytics once they're consuming events on their own thread
+    pub live_analytics: Option<analytics::LiveAnalytics>,
+    pub graph: Arc<graph::Graph>,
+
+    // Only kept when running headless, otherwise they belong to the GUI
+    sim_tx: Option<Sender<SimulationMessage>>,
+    gui_rx: Option<Subscriber<AppMessage>>,
+
+    // Set by the GUI watchdog if the user gives up on a stuck simulation thread
+    pub force_stop: Arc<AtomicBool>,
+}
+
+pub struct StartupOptions {
+    pub overrides: Option<SettingOverrides>, // Settings from onboarding, None uses the config file as is
+    pub headless: bool, // Don't set up the GUI
+    pub output_dir: Option<PathBuf>, // Where analytics are written, defaults to data/output
+}
+
+impl Module for Main {
+    type ReturnType = ();
+    type Configuration = PathBuf;
+    type Parameters = StartupOptions;
+
+    fn get_name(&self) -> &str {
+        "ODBRS -- Main"
+    }
+
+    fn init(
+        &mut self,
+        _config: Self::Configuration,
+        parameters: Self::Parameters,
+    ) -> Result<(), Box<dyn Error>> {
+        let timer = std::time::Instant::now();
+        println!("{} Starting Up", self.get_name());
+
+        let (gui, sim, gph, adjlist, demand, mut analytics_config) = self.resource_manager.init(_config, parameters.overrides)?;
+
+        let mut graph = graph::Graph::default();
+        graph.init(gph, adjlist)?;
+        self.graph = Arc::new(graph);
+
+        if let Some(output_dir) = parameters.output_dir {
+            analytics_config.output_dir = output_dir;
+        }
+        analytics_config.crs = self.graph.crs();
+        // Everything the simulation reports goes out on the bus, subscribe before it starts publishing
+        let events = EventBus::default();
+        if let Some(addr) = analytics_config.prometheus.take() {
+            serve_telemetry(&addr, &events)?;
+        }
+        self.analytics.init(analytics_config, events.clone())?;
+        // The display only needs the latest state, so a GUI that falls behind skips old frames
+        let gui_rx = events.subscribe(GUI_QUEUE, DropPolicy::DropOldest);
+
+        // Live buses are only there to compare against on the map, headless has nowhere to show them
+        let realtime = sim.gtfs.as_ref().and_then(|gtfs| gtfs.realtime.clone()).filter(|_| !parameters.headless);
+        let live_vehicles = realtime.is_some();
+        if let Some(realtime) = realtime {
+            realtime::start(realtime, self.graph.crs(), events.topic())?;
+        }
+
+        // Send stuff to the Simulation thread
+        let (sim_tx, sim_rx) = mpsc::channel();
+
+        // These two should be running on separate threads
+        self.simulation.init(
+            sim,
+            simulation::SimulationParameters {
+                graph: self.graph.clone(),
+                rx: sim_rx,
+                events,
+                demand,
+            },
+        )?;
+
+        // Stop rules only end headless runs, with a window open the user can stop it themselves
+        if parameters.headless {
+            self.analytics.set_sim_tx(sim_tx.clone());
+        }
+        let metrics = self.analytics.query();
+        self.live_analytics = Some(std::mem::take(&mut self.analytics).spawn_live());
+
+        if parameters.headless {
+            self.sim_tx = Some(sim_tx);
+            self.gui_rx = Some(gui_rx);
+        } else {
+            self.gui.init(
+                gui,
+                gui::AppParameters {
+                    graph: self.graph.clone(),
+                    rx: gui_rx,
+                    sim_tx: sim_tx.clone(),
+                    force_stop: self.force_stop.clone(),
+                    metrics,
+                    live_vehicles,
+                },
+            )?;
+        }
+
+        println!(
+            "{} Finished Start up in {:?}",
+            self.get_name(),
+            timer.elapsed()
+        );
+        Ok(())
+    }
+}
+
+/// Runs the simulation to the end time as fast as possible with no windows, then writes the analytics CSVs.
+/// Gives the stop rule that ended the run early, if one did
+pub struct HeadlessRunner {
+    odbrs: Main,
+}
+
+impl HeadlessRunner {
+    pub fn new(config: PathBuf, overrides: Option<SettingOverrides>, output_dir: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut odbrs = Main::default();
+        odbrs.init(config, StartupOptions { overrides, headless: true, output_dir })?;
+        Ok(HeadlessRunner { odbrs })
+    }
+
+    pub fn run(mut self) -> Result<Option<String>, Box<dyn Error>> {
+        let sim_tx = self.odbrs.sim_tx.take().expect("Main wasn't initialised headless");
+        let gui_rx = self.odbrs.gui_rx.take().expect("Main wasn't initialised headless");
+
+        // No tick delay, and start straight away
+        sim_tx.send(SimulationMessage::ChangeSpeed(0))?;
+        sim_tx.send(SimulationMessage::ChangeState(SimulationState::Running))?;
+
+        let mut simulation = std::mem::take(&mut self.odbrs.simulation);
+        let handle = thread::spawn(move || {
+            println!("Simulation Thread Started");
+            simulation.start();
+            println!("Simulation Thread Ended");
+        });
+
+        // Nothing to display the state updates on, but they tell us when the run is over.
+        // Ends once the simulation thread has finished and said so
+        let timeout = Duration::from_secs_f32(gui::watchdog::DEFAULT_TIMEOUT_SECONDS);
+        let mut last_tick = None;
+        loop {
+            match gui_rx.recv_timeout(timeout) {
+                Ok(AppMessage::SimulationEnded) => break,
+                Ok(AppMessage::SimulationState(time, state)) => {
+                    last_tick = Some(time);
+                    // Finished runs wait to be restarted, there's nothing to restart them here
+                    if state == SimulationState::Finished {
+                        sim_tx.send(SimulationMessage::ShutdownThread)?;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    eprintln!("[Watchdog] No update from the simulation for {:?}, last tick {:?}", timeout, last_tick);
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        handle.join().expect("Couldn't join the simulation thread");
+
+        println!("Writing analytics");
+        let mut analytics = finish_analytics(self.odbrs.live_analytics, self.odbrs.analytics);
+        let outputs = analytics.write_outputs();
+        println!("Analytics written to {:?}", outputs);
+
+        Ok(analytics.failed_by_rule().map(String::from))
+    }
+}
+
+// Stop the live analytics thread, if it was started, and get the analytics back
+pub fn finish_analytics(live: Option<analytics::LiveAnalytics>, analytics: analytics::Analytics) -> analytics::Analytics {
+    match live {
+        Some(live) => live.finish(),
+        None => analytics,
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn serve_telemetry(addr: &str, events: &EventBus) -> Result<(), Box<dyn Error>> {
+    telemetry::serve(addr, events)
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn serve_telemetry(_addr: &str, _events: &EventBus) -> Result<(), Box<dyn Error>> {
+    Err("odbrs was built without the `prometheus` feature".into())
+}
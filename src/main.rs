@@ -1,122 +1,79 @@
 use std::{
+    cell::RefCell,
     error::Error,
     path::PathBuf,
-    sync::{mpsc, Arc},
-    thread, cell::RefCell,
+    sync::{atomic::Ordering, Arc},
+    thread,
 };
 
-use gui::onboarding::SettingOverrides;
+use odbrs::{batch, cli, finish_analytics, graph, resource, simulation, HeadlessRunner, Main, Module, StartupOptions};
 
-use crate::analytics::AnalyticsPackage;
-
-mod graph;
-mod gui;
-mod resource;
-mod simulation;
-mod analytics;
-
-pub trait Module: Default {
-    type ReturnType;
-    type Configuration: Default;
-    type Parameters;
-
-    fn get_name(&self) -> &str;
-
-    fn init(
-        &mut self,
-        config: Self::Configuration,
-        parameters: Self::Parameters,
-    ) -> Result<Self::ReturnType, Box<dyn Error>>;
+// Load just the road graph and compare the route finding algorithms on it
+fn bench_routing(config: PathBuf, pairs: usize) -> Result<(), Box<dyn Error>> {
+    let (_, _, gph, adjlist, _, _) = resource::Resources::default().init(config, None)?;
+    let mut graph = graph::Graph::default();
+    graph.init(gph, adjlist)?;
+    graph::route_finding::benchmark(&graph, pairs, 0);
+    Ok(())
 }
 
-#[derive(Default)]
-struct Main {
-    pub resource_manager: resource::Resources,
-    pub gui: gui::App,
-    pub simulation: simulation::Simulation,
-    pub analytics: analytics::Analytics,
-    pub graph: Arc<graph::Graph>,
-}
+fn main() -> Result<(), Box<dyn Error>> {
 
-impl Module for Main {
-    type ReturnType = ();
-    type Configuration = PathBuf;
-    type Parameters = SettingOverrides;
+    let options = match cli::CliOptions::from_env() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    fn get_name(&self) -> &str {
-        "ODBRS -- Main"
+    match &options.command {
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        cli::Command::ExportTimeExpanded(dir) => return simulation::static_controller::time_expanded::export(&options.config_path(), dir),
+        cli::Command::Batch(path) => return batch::BatchRunner::load(path)?.run(),
+        cli::Command::Report(dir) => {
+            println!("[Batch] Report written to {:?}", batch::write_report(dir)?);
+            return Ok(());
+        }
+        cli::Command::ExportScenario(path) => return resource::scenario::export(&options.config_path(), path),
+        cli::Command::ImportScenario(path) => return resource::scenario::import(path),
+        cli::Command::ExportGraph(path) => return resource::export_graph(&options.config_path(), path),
+        cli::Command::BenchRouting(pairs) => return bench_routing(options.config_path(), *pairs),
+        cli::Command::RasteriseDemand(points, width) => return resource::demand_image::author(&options.config_path(), points, *width),
+        cli::Command::BuildNetwork => return resource::build_network(&options.config_path()),
+        cli::Command::Run => {}
     }
 
-    fn init(
-        &mut self,
-        _config: Self::Configuration,
-        parameters: Self::Parameters,
-    ) -> Result<(), Box<dyn Error>> {
-        let timer = std::time::Instant::now();
-        println!("{} Starting Up", self.get_name());
-
-        let (gui, sim, gph, adjlist, demand_resources) = self.resource_manager.init(_config, parameters)?;
-
-        let mut graph = graph::Graph::default();
-        graph.init(gph, adjlist)?;
-        self.graph = Arc::new(graph);
-
-        let analyticstx = self.analytics.init((), ())?;
-        analyticstx.send(AnalyticsPackage::None).unwrap();
-
-        // Send stuff to the Simulation thread
-        let (sim_tx, sim_rx) = mpsc::channel();
+    if options.headless {
+        if let Some(rule) = HeadlessRunner::new(options.config_path(), options.overrides, None)?.run()? {
+            println!("Run stopped early: {}", rule);
+        }
+        return Ok(());
+    }
 
-        // Send stuff to the GUI thread
-        let (gui_tx, gui_rx) = mpsc::channel();
+    let settings = if options.onboarding {
+        let settings_overrides = Arc::from(RefCell::new(Err(())));
 
-        // These two should be running on separate threads
-        self.simulation.init(
-            sim,
-            simulation::SimulationParameters {
-                graph: self.graph.clone(),
-                rx: sim_rx,
-                gui_tx: gui_tx.clone(),
-                analysis_tx: analyticstx,
-                demand_resources,
-            },
-        )?;
+        odbrs::gui::onboarding::Onboarding::run(settings_overrides.clone(), &options.config_path());
 
-        self.gui.init(
-            gui,
-            gui::AppParameters {
-                graph: self.graph.clone(),
-                rx: gui_rx,
-                sim_tx: sim_tx.clone(),
+        let settings = match &*settings_overrides.borrow() {
+            Ok(setting_overrides) => {
+                setting_overrides.clone()
             },
-        )?;
-
-        println!(
-            "{} Finished Start up in {:?}",
-            self.get_name(),
-            timer.elapsed()
-        );
-        Ok(())
-    }
-}
-
-fn main() -> Result<(), Box<dyn Error>> {
-
-    let settings_overrides = Arc::from(RefCell::new(Err(())));
-    
-    crate::gui::onboarding::Onboarding::run(settings_overrides.clone());
-    
-    let settings = match &*settings_overrides.borrow() {
-        Ok(setting_overrides) => {
-            setting_overrides.clone()
-        },
-        Err(_) => {
-            return Ok(()); // Exit the programs
-        }
+            Err(_) => {
+                return Ok(()); // Exit the programs
+            }
+        };
+        Some(settings)
+    } else {
+        options.overrides.clone()
     };
 
     let mut odbrs = Main::default();
-    odbrs.init(PathBuf::from(r#"data/config.toml"#), settings)?;
+    odbrs.init(options.config_path(), StartupOptions { overrides: settings, headless: false, output_dir: None })?;
 
     let handle = thread::spawn(move || {
         // Simulation start here in other thread
@@ -129,10 +86,15 @@ fn main() -> Result<(), Box<dyn Error>> {
     odbrs.gui.start()?;
     println!("GUI Thread Ended");
 
-    handle.join().expect("Couldn't join the simulation thread");
+    // A force stopped thread may never finish, so leave it behind
+    if odbrs.force_stop.load(Ordering::SeqCst) {
+        println!("Simulation was force stopped, not waiting for its thread");
+    } else {
+        handle.join().expect("Couldn't join the simulation thread");
+    }
 
     println!("Running analytics");
-    odbrs.analytics.run();
+    finish_analytics(odbrs.live_analytics, odbrs.analytics).run();
     println!("Analytics finished"); 
     
     Ok(())
@@ -0,0 +1,374 @@
+//! Live vehicle positions from a GTFS-realtime feed, drawn on the map alongside the simulated
+//! agents so the static buses can be checked against the buses really running. Turned on with a
+//! feed in the `[gtfs]` config:
+//!
+//! ```toml
+//! [gtfs.realtime]
+//! url = "https://example.org/gtfs-rt/vehicle-positions"
+//! poll_seconds = 30
+//! ```
+//!
+//! The feed is polled on its own thread and every update published to the GUI on the event bus.
+//! Fetching over HTTP needs the `gtfs-realtime` feature, without it `url` can still be a saved
+//! feed file, which is read again on every poll so a recording can be replayed.
+//!
+//! Only the parts of the protobuf needed for vehicle positions are decoded, by hand rather than
+//! pulling in a protobuf compiler for a handful of fields.
+
+use std::{error::Error, fs, thread, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::{event_bus::Topic, geometry::{onto_map, Crs}, gui::AppMessage};
+
+const MIN_POLL_SECONDS: f32 = 5.0; // Feeds generally ask not to be polled faster than this
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RealtimeConfig {
+    pub url: String, // Vehicle positions feed over http(s), or a saved feed file
+    #[serde(default = "default_poll_seconds")]
+    pub poll_seconds: f32,
+}
+
+fn default_poll_seconds() -> f32 {
+    30.0
+}
+
+/// One vehicle in the feed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveVehicle {
+    pub id: String, // The vehicle's own id, or the feed entity's if it doesn't give one
+    pub label: Option<String>, // Shown to passengers, e.g. the fleet number
+    pub route_id: Option<String>,
+    pub trip_id: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub bearing: Option<f32>, // Degrees clockwise from north
+    pub timestamp: Option<u64>, // When the position was measured, POSIX seconds
+    pub pos: (f64, f64), // Position on the map
+}
+
+/// Everything in one update of the feed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveFeed {
+    pub timestamp: Option<DateTime<Utc>>, // When the feed was generated
+    pub vehicles: Vec<LiveVehicle>,
+}
+
+impl LiveFeed {
+    // How far (m) each live vehicle is from the closest of `simulated` on a map in `crs`, a rough
+    // check of how well the simulated buses follow the real ones
+    pub fn offsets(&self, crs: Crs, simulated: &[(u32, (f64, f64))]) -> Vec<f64> {
+        self.vehicles
+            .iter()
+            .filter_map(|vehicle| {
+                simulated
+                    .iter()
+                    .map(|(_, position)| crs.distance(vehicle.pos, *position))
+                    .min_by(|a, b| a.total_cmp(b))
+            })
+            .collect()
+    }
+}
+
+// Start polling the feed, publishing every update (or why there wasn't one) to the GUI with the
+// vehicles projected onto a map in `crs`
+pub fn start(config: RealtimeConfig, crs: Crs, topic: Topic<AppMessage>) -> Result<(), Box<dyn Error>> {
+    println!("[Realtime] Polling {} every {}s", config.url, config.poll_seconds.max(MIN_POLL_SECONDS));
+    thread::Builder::new().name(String::from("gtfs-realtime")).spawn(move || poll(config, crs, topic))?;
+    Ok(())
+}
+
+fn poll(config: RealtimeConfig, crs: Crs, topic: Topic<AppMessage>) {
+    let proj = match crs.from_wgs84() {
+        Ok(proj) => proj,
+        Err(err) => {
+            topic.publish(AppMessage::LiveVehicles(Err(err)));
+            return;
+        }
+    };
+
+    loop {
+        let update = fetch(&config.url)
+            .and_then(|data| Ok(decode_feed(&data)?))
+            .map(|mut feed| {
+                for vehicle in feed.vehicles.iter_mut() {
+                    match onto_map(proj.as_ref(), (vehicle.longitude, vehicle.latitude)) {
+                        Ok(pos) => vehicle.pos = pos,
+                        Err(err) => eprintln!("[Realtime] Couldn't project vehicle {}: {}", vehicle.id, err),
+                    }
+                }
+                feed
+            })
+            .map_err(|err| err.to_string());
+
+        if let Err(err) = &update {
+            eprintln!("[Realtime] Couldn't update live vehicles from {}: {}", config.url, err);
+        }
+        topic.publish(AppMessage::LiveVehicles(update));
+        thread::sleep(Duration::from_secs_f32(config.poll_seconds.max(MIN_POLL_SECONDS)));
+    }
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        download(url)
+    } else {
+        Ok(fs::read(url)?)
+    }
+}
+
+#[cfg(feature = "gtfs-realtime")]
+fn download(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(reqwest::blocking::get(url)?.error_for_status()?.bytes()?.to_vec())
+}
+
+#[cfg(not(feature = "gtfs-realtime"))]
+fn download(_url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("odbrs was built without the `gtfs-realtime` feature".into())
+}
+
+// A field's value by its protobuf wire type
+#[derive(Debug, Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed64, // Doubles and fixed width integers, which none of the fields read are
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    fn string(self) -> Option<String> {
+        match self {
+            Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn float(self) -> Option<f32> {
+        match self {
+            Value::Fixed32(bits) => Some(f32::from_bits(bits)),
+            _ => None,
+        }
+    }
+
+    fn unsigned(self) -> Option<u64> {
+        match self {
+            Value::Varint(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn message(self) -> Option<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+fn varint(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or("Feed ends in the middle of a number")?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("Feed has a number longer than 64 bits"))
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    // A corrupt length can be anything, so it mustn't overflow past the end of the feed
+    let end = pos.checked_add(len).ok_or("Feed ends in the middle of a field")?;
+    let bytes = data.get(*pos..end).ok_or("Feed ends in the middle of a field")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+// Call `field` with the number and value of every field in a message, unknown ones included
+fn for_each_field<'a>(data: &'a [u8], mut field: impl FnMut(u64, Value<'a>)) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = varint(data, &mut pos)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(data, &mut pos)?),
+            1 => {
+                take(data, &mut pos, 8)?;
+                Value::Fixed64
+            }
+            2 => {
+                let len = varint(data, &mut pos)? as usize;
+                Value::Bytes(take(data, &mut pos, len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap())),
+            wire_type => return Err(format!("Feed has a field of unsupported wire type {}", wire_type)),
+        };
+        field(key >> 3, value);
+    }
+    Ok(())
+}
+
+// FeedMessage: header = 1 (timestamp = 3), entity = 2 (id = 1, vehicle = 4)
+pub fn decode_feed(data: &[u8]) -> Result<LiveFeed, String> {
+    let mut feed = LiveFeed::default();
+    let mut entities = Vec::new();
+    for_each_field(data, |number, value| match (number, value.message()) {
+        (1, Some(header)) => {
+            let _ = for_each_field(header, |number, value| {
+                if number == 3 {
+                    feed.timestamp = value.unsigned().and_then(|secs| Utc.timestamp_opt(secs as i64, 0).single());
+                }
+            });
+        }
+        (2, Some(entity)) => entities.push(entity),
+        _ => {}
+    })?;
+
+    for entity in entities {
+        let mut id = String::new();
+        let mut vehicle = None;
+        for_each_field(entity, |number, value| match number {
+            1 => id = value.string().unwrap_or_default(),
+            4 => vehicle = value.message(),
+            _ => {}
+        })?;
+        // Trip updates and alerts share the feed, only positions are wanted
+        if let Some(vehicle) = vehicle {
+            if let Some(mut decoded) = decode_vehicle(vehicle)? {
+                if decoded.id.is_empty() {
+                    decoded.id = id;
+                }
+                feed.vehicles.push(decoded);
+            }
+        }
+    }
+    Ok(feed)
+}
+
+// VehiclePosition: trip = 1, position = 2, timestamp = 5, vehicle = 8. None if it has no position
+fn decode_vehicle(data: &[u8]) -> Result<Option<LiveVehicle>, String> {
+    let mut vehicle = LiveVehicle::default();
+    let (mut trip, mut position, mut descriptor) = (None, None, None);
+    for_each_field(data, |number, value| match number {
+        1 => trip = value.message(),
+        2 => position = value.message(),
+        5 => vehicle.timestamp = value.unsigned(),
+        8 => descriptor = value.message(),
+        _ => {}
+    })?;
+
+    // TripDescriptor: trip_id = 1, route_id = 5
+    if let Some(trip) = trip {
+        for_each_field(trip, |number, value| match number {
+            1 => vehicle.trip_id = value.string(),
+            5 => vehicle.route_id = value.string(),
+            _ => {}
+        })?;
+    }
+    // VehicleDescriptor: id = 1, label = 2
+    if let Some(descriptor) = descriptor {
+        for_each_field(descriptor, |number, value| match number {
+            1 => vehicle.id = value.string().unwrap_or_default(),
+            2 => vehicle.label = value.string(),
+            _ => {}
+        })?;
+    }
+    // Position: latitude = 1, longitude = 2, bearing = 3
+    let Some(position) = position else { return Ok(None) };
+    let (mut latitude, mut longitude) = (None, None);
+    for_each_field(position, |number, value| match number {
+        1 => latitude = value.float(),
+        2 => longitude = value.float(),
+        3 => vehicle.bearing = value.float(),
+        _ => {}
+    })?;
+
+    match (latitude, longitude) {
+        (Some(latitude), Some(longitude)) => {
+            vehicle.latitude = latitude as f64;
+            vehicle.longitude = longitude as f64;
+            Ok(Some(vehicle))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn message(number: u64, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(number << 3 | 2, &mut out);
+        varint(data.len() as u64, &mut out);
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn float(number: u64, value: f32) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(number << 3 | 5, &mut out);
+        out.extend_from_slice(&value.to_le_bytes());
+        out
+    }
+
+    fn unsigned(number: u64, value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(number << 3, &mut out);
+        varint(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn decodes_vehicle_positions() {
+        let position = [float(1, 52.4), float(2, -1.5), float(3, 90.0)].concat();
+        let trip = [message(1, b"trip-7"), message(5, b"11A")].concat();
+        let vehicle = [message(1, &trip), message(2, &position), unsigned(5, 1_700_000_000), message(8, &message(2, b"Fleet 4"))].concat();
+        let entity = [message(1, b"entity-1"), unsigned(2, 0), message(4, &vehicle)].concat();
+        // A trip update only entity, which has no position to show
+        let trip_update = [message(1, b"entity-2"), message(3, &message(1, &trip))].concat();
+        let header = [message(1, b"2.0"), unsigned(3, 1_700_000_030)].concat();
+        let feed = [message(1, &header), message(2, &entity), message(2, &trip_update)].concat();
+
+        let feed = decode_feed(&feed).unwrap();
+        assert_eq!(feed.timestamp.unwrap().timestamp(), 1_700_000_030);
+        assert_eq!(feed.vehicles, vec![LiveVehicle {
+            id: String::from("entity-1"),
+            label: Some(String::from("Fleet 4")),
+            route_id: Some(String::from("11A")),
+            trip_id: Some(String::from("trip-7")),
+            latitude: 52.4_f32 as f64,
+            longitude: -1.5,
+            bearing: Some(90.0),
+            timestamp: Some(1_700_000_000),
+            pos: (0.0, 0.0),
+        }]);
+
+        assert!(decode_feed(&message(2, &entity)[..10]).is_err());
+
+        // A field claiming to be as long as can be
+        let mut huge = vec![2 << 3 | 2];
+        varint(u64::MAX, &mut huge);
+        assert_eq!(decode_feed(&huge).unwrap_err(), "Feed ends in the middle of a field");
+    }
+
+    #[test]
+    fn offsets_are_to_the_closest_simulated_vehicle() {
+        let live = |x, y| LiveVehicle { pos: (x, y), ..Default::default() };
+        let feed = LiveFeed { timestamp: None, vehicles: vec![live(0.0, 0.0), live(100.0, 0.0)] };
+        assert_eq!(feed.offsets(Crs::default(), &[(1, (3.0, 4.0)), (2, (100.0, 50.0))]), vec![5.0, 50.0]);
+        assert!(feed.offsets(Crs::default(), &[]).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Making demand images from point data instead of painting them by hand.
+//!
+//! Demand images cover the bounding box of the road graph, with red giving where trips start
+//! and blue where they end. A CSV of weighted points in map coordinates, e.g. population
+//! centroids as origins and workplaces as destinations, is summed into pixels and each channel
+//! scaled so the busiest pixel is 255:
+//!
+//! ```text
+//! x,y,weight,kind
+//! 429100.0,287300.0,1520,origin
+//! 431250.5,286900.0,800,destination
+//! ```
+//!
+//! `kind` can be `origin`, `destination` or `both` (the default).
+
+use std::{error::Error, fs, path::Path};
+
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{graph, Module};
+
+use super::{load_image::{DemandResourcesConfig, ImageSelection}, Resources};
+
+pub const DEFAULT_WIDTH: u32 = 512;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PointKind {
+    Origin,
+    Destination,
+    #[default]
+    Both,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WeightedPoint {
+    pub x: f64,
+    pub y: f64,
+    pub weight: f64,
+    #[serde(default)]
+    pub kind: PointKind,
+}
+
+// Sum the points into a `width` pixel wide image over `bounds` (left, right, top, bottom), keeping
+// the map's aspect ratio. Points outside the bounds are dropped, and any pixel with some weight
+// stays non-zero after scaling so no area loses all its demand
+pub fn rasterise(points: &[WeightedPoint], bounds: (f64, f64, f64, f64), width: u32) -> RgbImage {
+    let (left, right, top, bottom) = bounds;
+    let (map_width, map_height) = (right - left, top - bottom);
+    let height = ((width as f64 * map_height / map_width).round() as u32).max(1);
+
+    let mut origins = vec![0.0; (width * height) as usize];
+    let mut destinations = vec![0.0; (width * height) as usize];
+    for point in points {
+        if point.x < left || point.x > right || point.y < bottom || point.y > top || point.weight <= 0.0 {
+            continue;
+        }
+        // Row 0 is the top of the map, as in the demand generator
+        let px = (((point.x - left) / map_width * width as f64) as u32).min(width - 1);
+        let py = (((top - point.y) / map_height * height as f64) as u32).min(height - 1);
+        let i = (py * width + px) as usize;
+        if point.kind != PointKind::Destination {
+            origins[i] += point.weight;
+        }
+        if point.kind != PointKind::Origin {
+            destinations[i] += point.weight;
+        }
+    }
+
+    let scale = |weights: &[f64]| {
+        let max = weights.iter().copied().fold(0.0, f64::max);
+        weights.iter().map(|w| if *w > 0.0 { ((w / max * 255.0).round() as u8).max(1) } else { 0 }).collect::<Vec<u8>>()
+    };
+    let (origins, destinations) = (scale(&origins), scale(&destinations));
+
+    RgbImage::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        Rgb([origins[i], 0, destinations[i]])
+    })
+}
+
+// Serialises as the `[demand]` table of a config file
+#[derive(Serialize)]
+struct ConfigEntry {
+    demand: DemandResourcesConfig,
+}
+
+pub fn read_points(path: &Path) -> Result<Vec<WeightedPoint>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut points = Vec::new();
+    for point in reader.deserialize() {
+        points.push(point?);
+    }
+    Ok(points)
+}
+
+// Rasterise the points in `points_path` over the graph from the config, writing the image and a
+// `[demand]` config entry using it into `data/img/`
+pub fn author(config_path: &Path, points_path: &Path, width: u32) -> Result<(), Box<dyn Error>> {
+    let (_, _, _, adjlist, _, _) = Resources::default().init(config_path.to_path_buf(), None)?;
+    let points = read_points(points_path)?;
+    let image = rasterise(&points, graph::minimal_bounding(&adjlist), width);
+
+    let name = points_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_else(|| String::from("demand"));
+    let img_dir = Path::new("data/img");
+    fs::create_dir_all(img_dir)?;
+    image.save(img_dir.join(format!("{}.png", name)))?;
+
+    let config = DemandResourcesConfig {
+        paths: vec![format!("{}.png", name)],
+        select_by: ImageSelection::ConstantChoice(0),
+        minute_demand: vec![],
+        ..Default::default()
+    };
+    let entry = format!("# Add minute_demand levels for each hour before using\n{}", toml::to_string(&ConfigEntry { demand: config })?);
+    fs::write(img_dir.join(format!("{}.toml", name)), entry)?;
+
+    println!(
+        "[Resources] Wrote {}x{} demand image from {} points to {:?}, config entry in {:?}",
+        image.width(),
+        image.height(),
+        points.len(),
+        img_dir.join(format!("{}.png", name)),
+        img_dir.join(format!("{}.toml", name))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn points_land_in_the_right_pixels() {
+        let points = vec![
+            WeightedPoint { x: 10.0, y: 90.0, weight: 4.0, kind: PointKind::Origin },
+            WeightedPoint { x: 90.0, y: 10.0, weight: 1.0, kind: PointKind::Origin },
+            WeightedPoint { x: 60.0, y: 40.0, weight: 2.0, kind: PointKind::Both },
+            WeightedPoint { x: 500.0, y: 40.0, weight: 9.0, kind: PointKind::Both },
+        ];
+        // 100m square map, 4 pixels wide so 4 pixels high
+        let image = rasterise(&points, (0.0, 100.0, 100.0, 0.0), 4);
+        assert_eq!(image.dimensions(), (4, 4));
+
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(image.get_pixel(3, 3).0, [64, 0, 0]);
+        assert_eq!(image.get_pixel(2, 2).0, [128, 0, 255]);
+        let total: u32 = image.pixels().map(|p| p.0.iter().map(|c| *c as u32).sum::<u32>()).sum();
+        assert_eq!(total, 255 + 64 + 128 + 255);
+    }
+}
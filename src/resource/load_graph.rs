@@ -1,12 +1,13 @@
-use std::{error::Error, fs, path::PathBuf};
+use std::{error::Error, fs, path::{Path, PathBuf}};
 
+use serde_json::{json, Value};
 use shapefile::{
     dbase::{FieldValue, Record},
     Shape,
 };
 use uuid::Uuid;
 
-use crate::graph::{AdjacencyList, EdgeClass, EdgeMeta, NodeMeta, NodeType, self};
+use crate::{geometry::Crs, graph::{AdjacencyList, EdgeClass, EdgeMeta, NodeMeta, NodeType, self}};
 
 use super::GraphConfig;
 
@@ -93,11 +94,7 @@ pub(super) fn copy_to_file(list: &AdjacencyList, path: &PathBuf) -> Result<(), B
 fn parse_node_record(shp: Shape, record: Record) -> Option<NodeMeta> {
     let id = get_record_uuid("identifier", &record)?.as_u128();
     let node_type = match record.get("formOfNode")? {
-        FieldValue::Character(Some(data)) => match data.as_str() {
-            "road end" => NodeType::RoadEnd,
-            "junction" => NodeType::Junction,
-            _ => NodeType::Unknown(data.to_owned()),
-        },
+        FieldValue::Character(Some(data)) => parse_node_type(data),
         value => panic!("Form of Node was not a string {:?} got {:?}", id, value),
     };
 
@@ -126,16 +123,7 @@ fn parse_edge_record(shp: Shape, record: Record) -> Option<EdgeMeta> {
     let id = get_record_uuid("identifier", &record)?.as_u128();
 
     let edge_class = match record.get("class")? {
-        FieldValue::Character(Some(data)) => match data.as_str() {
-            "Unclassified" => EdgeClass::Unclassified,
-            "Classified Unnumbered" => EdgeClass::ClassifiedUnnumbered,
-            "Unknown" => EdgeClass::Unknown(data.clone()),
-            "B Road" => EdgeClass::RoadB,
-            "Not Classified" => EdgeClass::NotClassified,
-            "A Road" => EdgeClass::RoadA,
-            "Motorway" => EdgeClass::Motorway,
-            _ => EdgeClass::Unknown(data.to_owned()),
-        },
+        FieldValue::Character(Some(data)) => parse_edge_class(data),
         _ => EdgeClass::Unknown(String::from("Invalid Value Type")),
     };
 
@@ -171,6 +159,48 @@ fn parse_edge_record(shp: Shape, record: Record) -> Option<EdgeMeta> {
     })
 }
 
+// Node type from its OS formOfNode name
+fn parse_node_type(name: &str) -> NodeType {
+    match name {
+        "road end" => NodeType::RoadEnd,
+        "junction" => NodeType::Junction,
+        _ => NodeType::Unknown(name.to_owned()),
+    }
+}
+
+fn node_type_name(node_type: &NodeType) -> &str {
+    match node_type {
+        NodeType::RoadEnd => "road end",
+        NodeType::Junction => "junction",
+        NodeType::Unknown(name) => name,
+    }
+}
+
+// Road class from its OS class name
+fn parse_edge_class(name: &str) -> EdgeClass {
+    match name {
+        "Unclassified" => EdgeClass::Unclassified,
+        "Classified Unnumbered" => EdgeClass::ClassifiedUnnumbered,
+        "B Road" => EdgeClass::RoadB,
+        "Not Classified" => EdgeClass::NotClassified,
+        "A Road" => EdgeClass::RoadA,
+        "Motorway" => EdgeClass::Motorway,
+        _ => EdgeClass::Unknown(name.to_owned()),
+    }
+}
+
+fn edge_class_name(class: &EdgeClass) -> &str {
+    match class {
+        EdgeClass::Unclassified => "Unclassified",
+        EdgeClass::ClassifiedUnnumbered => "Classified Unnumbered",
+        EdgeClass::RoadB => "B Road",
+        EdgeClass::NotClassified => "Not Classified",
+        EdgeClass::RoadA => "A Road",
+        EdgeClass::Motorway => "Motorway",
+        EdgeClass::Unknown(name) => name,
+    }
+}
+
 // Get a uuid from a shapefile record (if it's there)
 fn get_record_uuid(field_name: &str, record: &Record) -> Option<Uuid> {
     match record.get(field_name)? {
@@ -178,3 +208,182 @@ fn get_record_uuid(field_name: &str, record: &Record) -> Option<Uuid> {
         _ => None,
     }
 }
+
+// GeoJSON, so networks can be looked over or edited in QGIS and loaded back in with
+// `source = "geojson"`. Nodes are Points and edges LineStrings, in map coordinates with the
+// graph's CRS named the old way QGIS still reads. Ids are strings as they don't fit in a JSON
+// number, classes and node types use the OS names:
+//
+// { "type": "Feature", "geometry": { "type": "LineString", "coordinates": [[x, y], ...] },
+//   "properties": { "id": "12", "start_id": "3", "end_id": "4", "class": "A Road", "length": 120.5 } }
+pub fn to_geojson(list: &AdjacencyList, crs: Crs) -> Value {
+    let mut nodes: Vec<&NodeMeta> = list.node_map.values().collect();
+    nodes.sort_by_key(|node| node.id);
+    let mut edges: Vec<&EdgeMeta> = list.edge_map.values().collect();
+    edges.sort_by_key(|edge| edge.id);
+
+    let features: Vec<Value> = nodes
+        .into_iter()
+        .map(|node| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [node.point.0, node.point.1] },
+                "properties": { "id": node.id.to_string(), "node_type": node_type_name(&node.node_type) },
+            })
+        })
+        .chain(edges.into_iter().map(|edge| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": edge.points.iter().map(|point| [point.0, point.1]).collect::<Vec<_>>() },
+                "properties": {
+                    "id": edge.id.to_string(),
+                    "start_id": edge.start_id.to_string(),
+                    "end_id": edge.end_id.to_string(),
+                    "class": edge_class_name(&edge.edge_class),
+                    "length": edge.length,
+                },
+            })
+        }))
+        .collect();
+
+    let crs = crs.code().replace("EPSG:", "urn:ogc:def:crs:EPSG::");
+    json!({
+        "type": "FeatureCollection",
+        "crs": { "type": "name", "properties": { "name": crs } },
+        "features": features,
+    })
+}
+
+// Adjacency list from GeoJSON laid out like `to_geojson`'s. Edges without a length are measured in `crs`,
+// nodes without a type are a junction or road end by how many edges meet there, and features
+// which are neither Points nor LineStrings are skipped
+pub fn from_geojson(value: &Value, crs: Crs) -> Result<AdjacencyList, String> {
+    let features = value["features"].as_array().ok_or("GeoJSON has no features")?;
+    let point = |coordinates: &Value| -> Option<(f64, f64)> { Some((coordinates[0].as_f64()?, coordinates[1].as_f64()?)) };
+
+    let mut list = AdjacencyList::default();
+    let mut node_types = Vec::new();
+    for feature in features {
+        match feature["geometry"]["type"].as_str() {
+            Some("Point") => {
+                let id = feature_id(feature, "id")?;
+                let point = point(&feature["geometry"]["coordinates"]).ok_or_else(|| format!("Node {} has no coordinates", id))?;
+                let node_type = feature["properties"]["node_type"].as_str().map(parse_node_type);
+                list.node_map.insert(id, NodeMeta { point, id, node_type: node_type.clone().unwrap_or_default() });
+                node_types.push((id, node_type.is_some()));
+            }
+            Some("LineString") => {
+                let id = feature_id(feature, "id")?;
+                let points: Vec<(f64, f64)> = feature["geometry"]["coordinates"]
+                    .as_array()
+                    .map(|coordinates| coordinates.iter().filter_map(point).collect())
+                    .unwrap_or_default();
+                if points.len() < 2 {
+                    return Err(format!("Edge {} needs at least two points", id));
+                }
+                let properties = &feature["properties"];
+                let edge = EdgeMeta {
+                    start_id: feature_id(feature, "start_id")?,
+                    end_id: feature_id(feature, "end_id")?,
+                    id,
+                    edge_class: properties["class"].as_str().map(parse_edge_class).unwrap_or_default(),
+                    length: properties["length"].as_f64().unwrap_or_else(|| points.windows(2).map(|pair| crs.distance(pair[0], pair[1])).sum()),
+                    points,
+                };
+                list.edge_map.insert(id, edge);
+            }
+            _ => {}
+        }
+    }
+
+    for (id, edge) in list.edge_map.iter() {
+        for node in [edge.start_id, edge.end_id] {
+            if !list.node_map.contains_key(&node) {
+                return Err(format!("Edge {} runs from or to node {} which isn't in the GeoJSON", id, node));
+            }
+            list.adjacency.entry(node).or_insert_with(Vec::new).push(*id);
+        }
+    }
+    for (id, typed) in node_types {
+        if !typed {
+            let edges = list.adjacency.get(&id).map_or(0, Vec::len);
+            list.node_map.get_mut(&id).unwrap().node_type = if edges == 1 { NodeType::RoadEnd } else { NodeType::Junction };
+        }
+    }
+    Ok(list)
+}
+
+// An id property of a feature, written as a string or as a number if it's small enough
+fn feature_id(feature: &Value, key: &str) -> Result<u128, String> {
+    let value = &feature["properties"][key];
+    value
+        .as_str()
+        .map(str::to_owned)
+        .or_else(|| value.as_u64().map(|id| id.to_string()))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| format!("Feature {} has no {} (a whole number or a string of one)", feature["properties"], key))
+}
+
+pub fn write_geojson(list: &AdjacencyList, crs: Crs, path: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string(&to_geojson(list, crs))?)?;
+    Ok(())
+}
+
+// GeoJSON graph at `path` in `crs`, cut to the graph config's bounds like the other sources
+pub(super) fn from_geojson_file(config: &GraphConfig, crs: Crs, path: &Path) -> Result<AdjacencyList, Box<dyn Error>> {
+    let timer = std::time::Instant::now();
+    let value: Value = serde_json::from_slice(&fs::read(path)?)?;
+    let list = from_geojson(&value, crs)?;
+    println!("\tLoaded Graph from GeoJSON {:?} in {:?}", path, timer.elapsed());
+    Ok(graph::bind_adjacencylist(list, config.left, config.right, config.top, config.bottom))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn graphs_round_trip_through_geojson() {
+        // Ids as big as the OS uuids
+        let (a, b, c) = (u128::MAX - 2, u128::MAX - 1, 7);
+        let mut list = AdjacencyList::default();
+        for (id, point, node_type) in [(a, (0.0, 0.0), NodeType::Junction), (b, (100.0, 0.0), NodeType::RoadEnd), (c, (0.0, 50.0), NodeType::Unknown(String::from("pseudo")))] {
+            list.node_map.insert(id, NodeMeta { point, id, node_type });
+        }
+        for (id, start_id, end_id, edge_class) in [(1, a, b, EdgeClass::RoadA), (2, a, c, EdgeClass::Unknown(String::from("Private Road")))] {
+            let points = vec![list.node_map[&start_id].point, list.node_map[&end_id].point];
+            list.edge_map.insert(id, EdgeMeta { points, start_id, end_id, id, edge_class, length: 99.5 });
+        }
+
+        let text = serde_json::to_string(&to_geojson(&list, Crs::default())).unwrap();
+        let back = from_geojson(&serde_json::from_str(&text).unwrap(), Crs::default()).unwrap();
+        for (id, node) in list.node_map.iter() {
+            let read = &back.node_map[id];
+            assert_eq!((read.point, node_type_name(&read.node_type)), (node.point, node_type_name(&node.node_type)));
+        }
+        for (id, edge) in list.edge_map.iter() {
+            let read = &back.edge_map[id];
+            assert_eq!((&read.points, read.start_id, read.end_id, &read.edge_class, read.length), (&edge.points, edge.start_id, edge.end_id, &edge.edge_class, edge.length));
+        }
+        assert_eq!(back.adjacency[&a].len(), 2);
+
+        // Drawn by hand without types or lengths, with whole number ids
+        let drawn = json!({ "features": [
+            { "geometry": { "type": "Point", "coordinates": [0.0, 0.0] }, "properties": { "id": 1 } },
+            { "geometry": { "type": "Point", "coordinates": [30.0, 40.0] }, "properties": { "id": 2 } },
+            { "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0], [30.0, 40.0]] }, "properties": { "id": 3, "start_id": 1, "end_id": 2 } },
+        ] });
+        let drawn = from_geojson(&drawn, Crs::default()).unwrap();
+        assert_eq!(drawn.edge_map[&3].length, 50.0);
+        assert!(matches!(drawn.node_map[&1].node_type, NodeType::RoadEnd));
+
+        // Edges need their nodes
+        let loose = json!({ "features": [
+            { "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0], [1.0, 1.0]] }, "properties": { "id": 1, "start_id": 5, "end_id": 6 } },
+        ] });
+        assert!(from_geojson(&loose, Crs::default()).is_err());
+    }
+}
@@ -4,17 +4,30 @@ use std::error::Error;
 use image::{RgbImage, DynamicImage};
 use serde::{Serialize, Deserialize};
 
+use crate::{analytics::zones::ZoneConfig, geometry::Crs, simulation::demand::{levels::{DemandLevels, DemandLevelsConfig}, od_matrix::{OdMatrix, OdMatrixConfig}, request_log::{RequestLog, RequestLogConfig}, source::{DemandSourceKind, LoadedDemand}}};
+
 #[derive(Default, Debug)]
 pub struct DemandResources {
     image_data: HashMap<u8, Arc<Box<ImageData>>>,
     selection: ImageSelection,
-    demand_levels: Vec<u8>
+    demand_levels: DemandLevels
 }
 
 impl DemandResources {
 
     pub fn new(selection: ImageSelection) -> Self {
-        DemandResources { image_data: HashMap::new(), selection, demand_levels: vec![] }
+        DemandResources { image_data: HashMap::new(), selection, demand_levels: DemandLevels::default() }
+    }
+
+    // Resources from images already in memory, keyed in the order given
+    pub fn from_images(selection: ImageSelection, images: Vec<ImageData>, demand_levels: DemandLevels) -> Self {
+        let mut demand_resources = DemandResources::new(selection);
+        for (key, mut image) in images.into_iter().enumerate() {
+            image.calculate_max_weight();
+            demand_resources.image_data.insert(key as u8, Arc::from(Box::new(image)));
+        }
+        demand_resources.demand_levels = demand_levels;
+        demand_resources
     }
 
     pub fn get_images(&self) -> &HashMap<u8, Arc<Box<ImageData>>> {
@@ -25,7 +38,7 @@ impl DemandResources {
         &self.selection
     }
 
-    pub fn get_demand_levels(&self) -> &Vec<u8> {
+    pub fn get_demand_levels(&self) -> &DemandLevels {
         &self.demand_levels
     }
 }
@@ -91,27 +104,63 @@ impl Default for ImageSelection {
 #[derive(Serialize, Deserialize, Default, Debug
 )]
 pub struct DemandResourcesConfig {
+    #[serde(default)]
+    pub source: DemandSourceKind, // Demand images (the default), an OD matrix or a request log
+    #[serde(default)]
     pub paths: Vec<String>, // Map of path keys and paths
+    #[serde(default)]
     pub select_by: ImageSelection,
-    pub minute_demand: Vec<u8>
+    #[serde(default)]
+    pub minute_demand: Vec<u8>, // Requests per minute for the hours 1..=24
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub levels: Option<DemandLevelsConfig>, // Requests per hour through the day, used instead of minute_demand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub od_matrix: Option<OdMatrixConfig>, // Needed for `source = "od_matrix"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_log: Option<RequestLogConfig>, // Needed for `source = "request_log"`
+}
+
+// Load whichever source of demand is configured, the OD matrix falls back on `zones` if it has none
+// of its own. Points given in latitude and longitude are projected onto a map in `crs`
+pub fn load_demand(config: DemandResourcesConfig, zones: &[ZoneConfig], crs: Crs) -> Result<LoadedDemand, Box<dyn Error>> {
+    if config.levels.is_some() && config.source != DemandSourceKind::Image {
+        println!("[Demand] [demand.levels] is only used by demand images, the {:?} source has its own counts", config.source);
+    }
+    match config.source {
+        DemandSourceKind::Image => Ok(LoadedDemand::Images(load_images(config)?)),
+        DemandSourceKind::OdMatrix => {
+            let od_matrix = config.od_matrix.as_ref().ok_or("source = \"od_matrix\" needs a [demand.od_matrix] section")?;
+            Ok(LoadedDemand::OdMatrix(OdMatrix::load(od_matrix, zones)?))
+        }
+        DemandSourceKind::RequestLog => {
+            let request_log = config.request_log.as_ref().ok_or("source = \"request_log\" needs a [demand.request_log] section")?;
+            Ok(LoadedDemand::RequestLog(RequestLog::load(request_log, crs)?))
+        }
+    }
 }
 
 pub fn load_images(config: DemandResourcesConfig) -> Result<DemandResources, Box<dyn Error>> {
-    let mut demand_resources = DemandResources::new(config.select_by);
-    
-    let mut key = 0;
+    let levels = demand_levels(&config)?;
+    let mut images = Vec::new();
     for path in config.paths {
         let img = image::io::Reader::open(format!("./data/img/{}", path))?.decode()?;
-        let mut img = ImageData::new(img);
-        img.calculate_max_weight();
-
-        demand_resources.image_data.insert(key, Arc::from(Box::new(img)));
-        key += 1;
+        images.push(ImageData::new(img));
     }
 
-    demand_resources.demand_levels = config.minute_demand;
+    Ok(DemandResources::from_images(config.select_by, images, levels))
+}
 
-    Ok(demand_resources)
+// The `[demand.levels]` if there are any, otherwise `minute_demand`
+pub fn demand_levels(config: &DemandResourcesConfig) -> Result<DemandLevels, Box<dyn Error>> {
+    match &config.levels {
+        Some(levels) => {
+            if !config.minute_demand.is_empty() {
+                println!("[Demand] Both minute_demand and [demand.levels] are given, using [demand.levels]");
+            }
+            DemandLevels::load(levels)
+        }
+        None => Ok(DemandLevels::from_minute_demand(&config.minute_demand)),
+    }
 }
 
 // TODO: Add fallback image for 0 demand which would not generate anything!!
\ No newline at end of file
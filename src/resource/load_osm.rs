@@ -0,0 +1,182 @@
+//! Road graph from an OpenStreetMap extract, for places without Ordnance Survey data. Chosen in
+//! the resources config instead of the shapefiles, with the `[[defaults]]` graph of `key` giving
+//! the bounds as usual:
+//!
+//! ```toml
+//! [resources]
+//! source = "osm"
+//! osm_src = "data/osm/west-midlands-latest.osm.pbf"
+//! key = "coventry"
+//! ```
+//!
+//! Reading the PBF needs the `osm` feature. Ways tagged as a road cars can drive on become edges,
+//! split at every node another road shares so junctions join up, with the OSM node ids as node ids.
+//! Points are projected from latitude and longitude onto the map in the graph's CRS (see
+//! `geometry`), so the bounds are in map coordinates too. Roads are driven both ways like the
+//! shapefile roads, one way tags aren't read. The graph is saved as `<key>-osm.bin` and loaded
+//! from there next time.
+
+use std::{collections::HashMap, error::Error, path::Path};
+
+use proj::Proj;
+
+use crate::{
+    geometry::{onto_map, Crs},
+    graph::{self, AdjacencyList, EdgeClass, EdgeMeta, NodeMeta, NodeType},
+};
+
+use super::GraphConfig;
+
+/// A way with a highway tag, before its nodes have positions
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmWay {
+    pub id: i64,
+    pub highway: String, // Value of its highway tag
+    pub nodes: Vec<i64>,
+}
+
+// Ways with a highway tag, and the (longitude, latitude) of the nodes they use
+type Extract = (Vec<OsmWay>, HashMap<i64, (f64, f64)>);
+
+// Road class of a highway tag, None for ways which aren't roads for cars (paths, cycleways...)
+pub fn road_class(highway: &str) -> Option<EdgeClass> {
+    match highway {
+        "motorway" | "motorway_link" => Some(EdgeClass::Motorway),
+        "trunk" | "trunk_link" | "primary" | "primary_link" => Some(EdgeClass::RoadA),
+        "secondary" | "secondary_link" => Some(EdgeClass::RoadB),
+        "tertiary" | "tertiary_link" => Some(EdgeClass::ClassifiedUnnumbered),
+        "unclassified" => Some(EdgeClass::Unclassified),
+        "residential" | "living_street" | "service" | "road" => Some(EdgeClass::NotClassified),
+        _ => None,
+    }
+}
+
+// Road graph of the ways, split into edges at junctions, from node positions in (longitude,
+// latitude) projected with `proj` (see `Crs::from_wgs84`) and measured in `crs`. Ways using a node with no
+// position, which extracts cut at their edge can have, end at the last node there is
+pub fn build(ways: &[OsmWay], positions: &HashMap<i64, (f64, f64)>, proj: Option<&Proj>, crs: Crs) -> Result<AdjacencyList, String> {
+    let roads: Vec<(&OsmWay, EdgeClass)> = ways.iter().filter_map(|way| Some((way, road_class(&way.highway)?))).collect();
+
+    // Nodes used more than once, by two roads or twice by the same one, are where roads join
+    let mut uses: HashMap<i64, u32> = HashMap::new();
+    for (way, _) in roads.iter() {
+        for node in way.nodes.iter() {
+            *uses.entry(*node).or_insert(0) += 1;
+        }
+    }
+
+    let mut adjlist = AdjacencyList::default();
+    let mut points: HashMap<i64, (f64, f64)> = HashMap::new();
+    for (way, class) in roads {
+        let known: Vec<i64> = way.nodes.iter().copied().take_while(|node| positions.contains_key(node)).collect();
+        for node in known.iter() {
+            if !points.contains_key(node) {
+                points.insert(*node, onto_map(proj, positions[node])?);
+            }
+        }
+
+        let mut start = 0;
+        let mut piece = 0;
+        for (at, node) in known.iter().enumerate().skip(1) {
+            if at + 1 < known.len() && uses[node] < 2 {
+                continue;
+            }
+            let line: Vec<(f64, f64)> = known[start..=at].iter().map(|node| points[node]).collect();
+            let id = ((way.id as u128) << 32) | piece;
+            let edge = EdgeMeta {
+                length: line.windows(2).map(|pair| crs.distance(pair[0], pair[1])).sum(),
+                points: line,
+                start_id: known[start] as u128,
+                end_id: *node as u128,
+                id,
+                edge_class: class.clone(),
+            };
+            adjlist.adjacency.entry(edge.start_id).or_insert_with(Vec::new).push(id);
+            adjlist.adjacency.entry(edge.end_id).or_insert_with(Vec::new).push(id);
+            adjlist.edge_map.insert(id, edge);
+            start = at;
+            piece += 1;
+        }
+    }
+
+    for (id, edges) in adjlist.adjacency.iter() {
+        let node_type = if edges.len() == 1 { NodeType::RoadEnd } else { NodeType::Junction };
+        adjlist.node_map.insert(*id, NodeMeta { point: points[&(*id as i64)], id: *id, node_type });
+    }
+    Ok(adjlist)
+}
+
+// Road graph from the extract at `path` projected into `crs`, cut to the graph config's bounds
+pub(super) fn from_pbf(config: &GraphConfig, crs: Crs, path: &Path) -> Result<AdjacencyList, Box<dyn Error>> {
+    let timer = std::time::Instant::now();
+    let (ways, positions) = read_pbf(path)?;
+    let proj = crs.from_wgs84()?;
+    let adjlist = build(&ways, &positions, proj.as_ref(), crs)?;
+    println!("\tRead {} ways from {:?} into {} roads in {:?}", ways.len(), path, adjlist.edge_map.len(), timer.elapsed());
+    Ok(graph::bind_adjacencylist(adjlist, config.left, config.right, config.top, config.bottom))
+}
+
+#[cfg(feature = "osm")]
+fn read_pbf(path: &Path) -> Result<Extract, Box<dyn Error>> {
+    use std::collections::HashSet;
+
+    use osmpbf::{Element, ElementReader};
+
+    // Ways first, so only the positions of nodes on them are kept from the second read
+    let mut ways = Vec::new();
+    ElementReader::from_path(path)?.for_each(|element| {
+        if let Element::Way(way) = element {
+            if let Some((_, highway)) = way.tags().find(|(key, _)| *key == "highway") {
+                ways.push(OsmWay { id: way.id(), highway: highway.to_owned(), nodes: way.refs().collect() });
+            }
+        }
+    })?;
+    let wanted: HashSet<i64> = ways.iter().flat_map(|way| way.nodes.iter().copied()).collect();
+
+    let mut positions = HashMap::new();
+    ElementReader::from_path(path)?.for_each(|element| {
+        let (id, position) = match element {
+            Element::Node(node) => (node.id(), (node.lon(), node.lat())),
+            Element::DenseNode(node) => (node.id(), (node.lon(), node.lat())),
+            _ => return,
+        };
+        if wanted.contains(&id) {
+            positions.insert(id, position);
+        }
+    })?;
+    Ok((ways, positions))
+}
+
+#[cfg(not(feature = "osm"))]
+fn read_pbf(path: &Path) -> Result<Extract, Box<dyn Error>> {
+    Err(format!("Reading the OpenStreetMap extract {:?} needs odbrs built with the osm feature", path).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ways_are_split_into_roads_at_junctions() {
+        // A main road east through 1, 2, 3 crossed at 2 by a side street from 4 to 5, with a
+        // footpath and a node cut off by the edge of the extract
+        let positions = HashMap::from([(1, (0.0, 0.0)), (2, (100.0, 0.0)), (3, (200.0, 0.0)), (4, (100.0, -50.0)), (5, (100.0, 50.0))]);
+        let ways = vec![
+            OsmWay { id: 10, highway: String::from("primary"), nodes: vec![1, 2, 3] },
+            OsmWay { id: 11, highway: String::from("residential"), nodes: vec![4, 2, 5, 6] },
+            OsmWay { id: 12, highway: String::from("footway"), nodes: vec![1, 4] },
+        ];
+        let adjlist = build(&ways, &positions, None, Crs::default()).unwrap();
+
+        let mut edges: Vec<_> = adjlist.edge_map.values().map(|edge| (edge.start_id, edge.end_id, edge.edge_class.clone())).collect();
+        edges.sort_by_key(|(start, end, _)| (*start, *end));
+        assert_eq!(edges, vec![(1, 2, EdgeClass::RoadA), (2, 3, EdgeClass::RoadA), (2, 5, EdgeClass::NotClassified), (4, 2, EdgeClass::NotClassified)]);
+        assert!(matches!(adjlist.node_map[&2].node_type, NodeType::Junction));
+        assert!(matches!(adjlist.node_map[&1].node_type, NodeType::RoadEnd));
+        assert_eq!(adjlist.adjacency[&2].len(), 4);
+        assert!(!adjlist.node_map.contains_key(&6));
+
+        let main = &adjlist.edge_map[&(10 << 32)];
+        assert_eq!(main.length, 100.0);
+    }
+}
@@ -1,17 +1,22 @@
-use std::{fs, path::PathBuf};
+use std::{fs, path::{Path, PathBuf}};
 
 use crate::{
+    analytics,
     graph::{self, AdjacencyList},
     gui::{self, onboarding::SettingOverrides},
-    resource::load_image::load_images,
-    simulation, Module,
+    resource::load_image::load_demand,
+    simulation::{self, demand::{levels::DemandLevels, source::{DemandSourceKind, LoadedDemand}}, static_controller::routes::{self, GtfsBounds, GtfsConfig}, walking::WalkingConfig},
+    Module,
 };
 use serde::Deserialize;
 
-use self::load_image::{DemandResources, DemandResourcesConfig};
+use self::load_image::DemandResourcesConfig;
 
+pub mod demand_image;
 pub mod load_graph;
 pub mod load_image;
+pub mod load_osm;
+pub mod scenario;
 
 /// Resources contains the methods for loading and converting data from disk
 /// - Configuration
@@ -30,9 +35,10 @@ impl Module for Resources {
         <simulation::Simulation as Module>::Configuration,
         <graph::Graph as Module>::Configuration,
         AdjacencyList,
-        DemandResources,
+        LoadedDemand,
+        <analytics::Analytics as Module>::Configuration,
     );
-    type Parameters = SettingOverrides;
+    type Parameters = Option<SettingOverrides>; // None uses the config file as is
 
     fn get_name(&self) -> &str {
         "Resources"
@@ -46,35 +52,56 @@ impl Module for Resources {
         let time = std::time::Instant::now();
         
         
-        let path = if parameters.config_file_path != "" {
-            PathBuf::from(parameters.config_file_path)
-        } else {
-            _config
+        let path = match &parameters {
+            Some(overrides) if overrides.config_file_path != "" => PathBuf::from(&overrides.config_file_path),
+            _ => _config,
         };
 
         let data = fs::read(path)?;
-        let config_file: ConfigFile = toml::from_str(std::str::from_utf8(&data)?)?;
+        let mut config_file: ConfigFile = toml::from_str(std::str::from_utf8(&data)?)?;
+        let picked = parameters.as_ref().and_then(|parameters| parameters.graph_key.clone()).filter(|key| *key != config_file.resources.graph_key);
+        if let Some(key) = picked.clone() {
+            config_file.resources.graph_key = key;
+        }
         let graph = match self.load_graph(&config_file) {
             Some(graph) => Ok(graph),
             None => Err("Error in loading graph"),
         }?;
 
+        // Bounds to build the bus network with if it's missing, the error can wait until it's needed
+        let mut gtfs = config_file.gtfs.clone();
+        gtfs.bounds = Self::gtfs_bounds(&config_file, Some(&graph)).ok();
+        // The bus network saved for the configured graph is cut to that graph's area, so another
+        // graph picked in onboarding has its own
+        if let Some(key) = picked.filter(|_| config_file.gtfs.bounds.is_none() && config_file.gtfs.graph.is_none()) {
+            gtfs.output = network_output(&gtfs.output, &key);
+            println!("[Resources] Using the bus network for {} at {:?}", key, gtfs.output);
+        }
+
         let mut sim_cfg = config_file.simulation;
+        sim_cfg.gtfs = Some(gtfs);
 
-        sim_cfg.static_only = parameters.is_static;
-        sim_cfg.dyn_agent_count = parameters.num_agents;
-        sim_cfg.demand_scale = parameters.demand_scale;
-        sim_cfg.start_time = Some(parameters.start_time);
-        sim_cfg.end_time = Some(parameters.end_time);
+        if let Some(parameters) = parameters {
+            sim_cfg.static_only = parameters.is_static;
+            sim_cfg.dyn_agent_count = parameters.num_agents;
+            sim_cfg.demand_scale = parameters.demand_scale;
+            sim_cfg.start_time = Some(parameters.start_time);
+            sim_cfg.end_time = Some(parameters.end_time);
+            if let Some(objective) = parameters.objective {
+                sim_cfg.objective = objective;
+            }
+            if let Some(seed) = parameters.seed {
+                sim_cfg.seed = Some(seed);
+            }
+        }
 
         let gui_cfg = config_file.app;
+        let demand = load_demand(config_file.demand, &config_file.analytics.zones, config_file.graph.crs)?;
         let gph_cfg = config_file.graph;
 
-        let demand_images = load_images(config_file.demand)?;
-
         println!("[{}] Initialised in {:?}", self.get_name(), time.elapsed());
 
-        Ok((gui_cfg, sim_cfg, gph_cfg, graph, demand_images))
+        Ok((gui_cfg, sim_cfg, gph_cfg, graph, demand, config_file.analytics))
     }
 }
 
@@ -86,24 +113,51 @@ struct ConfigFile {
     pub graph: <graph::Graph as Module>::Configuration,
     pub defaults: Vec<GraphConfig>,
     pub demand: DemandResourcesConfig,
+    #[serde(default)]
+    pub analytics: <analytics::Analytics as Module>::Configuration,
+    #[serde(default)]
+    pub gtfs: GtfsConfig,
 }
 
 // Stores the config for this resource module
 #[derive(Default, Deserialize)]
 struct ResourceConfig {
+    #[serde(default)]
+    pub source: GraphSource,
+
     // WHERE ARE SHAPEFILE
+    #[serde(default)]
     pub shapefile_src: String,
 
+    // OpenStreetMap extract (.osm.pbf) read with `source = "osm"`, see `load_osm`
+    #[serde(default)]
+    pub osm_src: String,
+
+    // Graph read with `source = "geojson"`, e.g. one written by --export-graph and edited
+    #[serde(default)]
+    pub geojson_src: String,
+
     #[serde(rename = "key")]
     pub graph_key: String,
 }
 
+// Where the road graph is built from when there's no saved copy of it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum GraphSource {
+    #[default]
+    Shapefile, // Ordnance Survey Open Roads RoadLink and RoadNode shapefiles
+    Osm, // An OpenStreetMap extract
+    GeoJson, // Nodes and edges laid out like `load_graph::to_geojson` writes them
+}
+
 // Stores a particular saved graph configuration
 #[derive(Default, Deserialize)]
 struct GraphConfig {
     // Name to identify this saved config as
     pub key: String,
-    // Two letter OS code to identify the file
+    // Two letter OS code to identify the file, not needed for OpenStreetMap graphs
+    #[serde(default)]
     pub os_code: [char; 2],
     // Left bound of the area
     pub left: f64,
@@ -116,21 +170,59 @@ struct GraphConfig {
 }
 
 impl Resources {
-    fn save_file_name(config: &GraphConfig) -> String {
-        format!(
-            "{}-{}.bin",
-            config.key,
-            config.os_code.iter().collect::<String>()
-        )
+    // Box the bus network is cut to: the one given, else the named graph's, else the loaded graph's.
+    // A saved graph with no [[defaults]] entry is cut to the box around `loaded`
+    fn gtfs_bounds(config: &ConfigFile, loaded: Option<&AdjacencyList>) -> Result<GtfsBounds, String> {
+        if let Some(bounds) = config.gtfs.bounds {
+            return Ok(bounds);
+        }
+        let key = config.gtfs.graph.as_ref().unwrap_or(&config.resources.graph_key);
+        match (config.defaults.iter().find(|graph| &graph.key == key), loaded) {
+            (Some(graph), _) => Ok(GtfsBounds { left: graph.left, right: graph.right, top: graph.top, bottom: graph.bottom }),
+            (None, Some(loaded)) if config.gtfs.graph.is_none() => {
+                let (left, right, top, bottom) = graph::bounding::minimal_bounding(loaded);
+                Ok(GtfsBounds { left, right, top, bottom })
+            }
+            _ => Err(format!("No [[defaults]] graph with key '{}' to take the GTFS bounds from", key)),
+        }
+    }
+
+    // Where the graph is saved once it's built from the source files
+    fn save_file_path(config: &GraphConfig, source: GraphSource) -> PathBuf {
+        let mut save_file_path = PathBuf::from(SAVE_DIR);
+        save_file_path.push(Self::save_file_name(config, source));
+        save_file_path
+    }
+
+    fn save_file_name(config: &GraphConfig, source: GraphSource) -> String {
+        match source {
+            GraphSource::Shapefile => format!(
+                "{}-{}.bin",
+                config.key,
+                config.os_code.iter().collect::<String>()
+            ),
+            GraphSource::Osm => format!("{}-osm.bin", config.key),
+            GraphSource::GeoJson => format!("{}-geojson.bin", config.key),
+        }
     }
 
     // Load data from source files or whatever into a list of adjacencies
     fn load_graph(&self, config: &ConfigFile) -> Option<AdjacencyList> {
         let key = &config.resources.graph_key;
-        let configuration = config.defaults.iter().find(|config| &config.key == key)?;
+        let Some(configuration) = config.defaults.iter().find(|config| &config.key == key) else {
+            // Only saved, with no source area to build it from
+            let (_, path) = saved_graphs(config.resources.source).into_iter().find(|(saved, _)| saved == key)?;
+            return load_graph::from_file(&path).map_err(|err| eprintln!("[Resources] Couldn't load the saved graph {:?}: {:?}", path, err)).ok();
+        };
 
-        let mut save_file_path = PathBuf::from("data/save/");
-        save_file_path.push(Self::save_file_name(configuration));
+        // Edited in other tools, so read every time rather than saved
+        if config.resources.source == GraphSource::GeoJson {
+            return Self::build_graph(config, configuration)
+                .map_err(|err| eprintln!("[Resources] Couldn't build the graph {}", err))
+                .ok();
+        }
+
+        let save_file_path = Self::save_file_path(configuration, config.resources.source);
 
         // Test for pre-comp source file
         if save_file_path.exists() {
@@ -143,11 +235,14 @@ impl Resources {
                 }
             }
         } else {
-            // Else fetch OS file and convert to adj lists
-            let adjlist = load_graph::from_shapefiles(
-                configuration,
-                &PathBuf::from(&config.resources.shapefile_src),
-            )?;
+            // Else fetch OS or OSM file and convert to adj lists
+            let adjlist = match Self::build_graph(config, configuration) {
+                Ok(adjlist) => adjlist,
+                Err(err) => {
+                    eprintln!("[Resources] Couldn't build the graph {}", err);
+                    return None;
+                }
+            };
 
             load_graph::copy_to_file(&adjlist, &save_file_path)
                 .expect("Error saving adj list out to file");
@@ -155,4 +250,180 @@ impl Resources {
             Some(adjlist)
         }
     }
+
+    // Build the graph from the source files of the configured source
+    fn build_graph(config: &ConfigFile, configuration: &GraphConfig) -> Result<AdjacencyList, Box<dyn std::error::Error>> {
+        match config.resources.source {
+            GraphSource::Shapefile => load_graph::from_shapefiles(configuration, &PathBuf::from(&config.resources.shapefile_src))
+                .ok_or_else(|| format!("from the OS shapefiles in {:?}", config.resources.shapefile_src).into()),
+            GraphSource::Osm => load_osm::from_pbf(configuration, config.graph.crs, Path::new(&config.resources.osm_src)),
+            GraphSource::GeoJson => load_graph::from_geojson_file(configuration, config.graph.crs, Path::new(&config.resources.geojson_src)),
+        }
+    }
+}
+
+// Write the config's road graph as GeoJSON, to look over or edit in QGIS
+pub fn export_graph(config_path: &Path, output: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let data = fs::read(config_path)?;
+    let config_file: ConfigFile = toml::from_str(std::str::from_utf8(&data)?)?;
+    let graph = Resources::default().load_graph(&config_file).ok_or("Error in loading graph")?;
+
+    load_graph::write_geojson(&graph, config_file.graph.crs, output)?;
+    println!("[Resources] Wrote {} nodes and {} edges to {:?}", graph.node_map.len(), graph.edge_map.len(), output);
+    Ok(())
+}
+
+// Convert the config's GTFS feed into the network data the fixed route buses run on
+pub fn build_network(config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let data = fs::read(config_path)?;
+    let config_file: ConfigFile = toml::from_str(std::str::from_utf8(&data)?)?;
+    let bounds = Resources::gtfs_bounds(&config_file, None)?;
+
+    let timer = std::time::Instant::now();
+    println!("[Resources] Building the bus network from {:?}", config_file.gtfs.path);
+    routes::load_routes(&config_file.gtfs, bounds, config_file.graph.crs)?;
+    println!("[Resources] Wrote the bus network to {:?} in {:?}", config_file.gtfs.output, timer.elapsed());
+    Ok(())
+}
+
+const SAVE_DIR: &str = "data/save/"; // Where graphs built from the source files are saved
+
+// Key of the graph saved in `file_name` by `source`, None if it isn't one. GeoJSON graphs are read
+// every time so are never saved
+fn saved_key(file_name: &str, source: GraphSource) -> Option<String> {
+    let name = file_name.strip_suffix(".bin")?;
+    let key = match source {
+        GraphSource::Shapefile => name.rsplit_once('-').filter(|(_, os_code)| os_code.chars().count() == 2)?.0,
+        GraphSource::Osm => name.strip_suffix("-osm")?,
+        GraphSource::GeoJson => return None,
+    };
+    Some(key.to_string()).filter(|key| !key.is_empty())
+}
+
+// Keys and files of the graphs saved by `source`
+fn saved_graphs(source: GraphSource) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(SAVE_DIR) else {
+        return Vec::new();
+    };
+    let mut saved: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((saved_key(entry.file_name().to_str()?, source)?, entry.path())))
+        .collect();
+    saved.sort();
+    saved
+}
+
+// The network data for graph `key` when it isn't the configured one, next to the configured file
+fn network_output(output: &Path, key: &str) -> PathBuf {
+    let stem = output.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    match output.extension() {
+        Some(extension) => output.with_file_name(format!("{}-{}.{}", stem, key, extension.to_string_lossy())),
+        None => output.with_file_name(format!("{}-{}", stem, key)),
+    }
+}
+
+/// A graph onboarding can pick as the area to run on
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphChoice {
+    pub key: String,
+    pub saved: bool, // Already built from the configured source and saved, so it loads quickly
+    pub configured: bool, // In the config's `[[defaults]]`, otherwise only found saved in `data/save/`
+}
+
+// The graphs a config can run on and the key it's set to, so onboarding can offer them without
+// loading any: its `[[defaults]]`, then any other graphs saved from the same source
+pub fn peek_graphs(config_path: &Path) -> Result<(Vec<GraphChoice>, String), Box<dyn std::error::Error>> {
+    #[derive(Default, Deserialize)]
+    struct Peek {
+        #[serde(default)]
+        resources: ResourceConfig,
+        #[serde(default)]
+        defaults: Vec<GraphConfig>,
+    }
+
+    let data = fs::read(config_path)?;
+    let peek: Peek = toml::from_str(std::str::from_utf8(&data)?)?;
+    let source = peek.resources.source;
+    let saved = saved_graphs(source);
+    let mut choices: Vec<_> = peek
+        .defaults
+        .iter()
+        .map(|graph| GraphChoice { key: graph.key.clone(), saved: saved.iter().any(|(_, path)| *path == Resources::save_file_path(graph, source)), configured: true })
+        .collect();
+    for (key, _) in saved {
+        if !choices.iter().any(|choice| choice.key == key) {
+            choices.push(GraphChoice { key, saved: true, configured: false });
+        }
+    }
+    Ok((choices, peek.resources.graph_key))
+}
+
+// Just how passengers walk in a config, for exports which don't run the simulation
+pub fn peek_walking(config_path: &Path) -> Result<WalkingConfig, Box<dyn std::error::Error>> {
+    #[derive(Default, Deserialize)]
+    struct Simulation {
+        #[serde(default)]
+        walking: WalkingConfig,
+    }
+    #[derive(Default, Deserialize)]
+    struct Peek {
+        #[serde(default)]
+        simulation: Simulation,
+    }
+
+    let data = fs::read(config_path)?;
+    let peek: Peek = toml::from_str(std::str::from_utf8(&data)?)?;
+    Ok(peek.simulation.walking)
+}
+
+// Just the random seed a config sets, if it sets one
+pub fn peek_seed(config_path: &Path) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    #[derive(Default, Deserialize)]
+    struct Simulation {
+        seed: Option<u64>,
+    }
+    #[derive(Default, Deserialize)]
+    struct Peek {
+        #[serde(default)]
+        simulation: Simulation,
+    }
+
+    let data = fs::read(config_path)?;
+    let peek: Peek = toml::from_str(std::str::from_utf8(&data)?)?;
+    Ok(peek.simulation.seed)
+}
+
+// Just the demand levels from a config, so onboarding can size things up without loading the graph.
+// None if the demand comes from an OD matrix or request log, which have their own counts
+pub fn peek_demand_levels(config_path: &Path) -> Result<Option<DemandLevels>, Box<dyn std::error::Error>> {
+    #[derive(Default, Deserialize)]
+    struct Peek {
+        #[serde(default)]
+        demand: DemandResourcesConfig,
+    }
+
+    let data = fs::read(config_path)?;
+    let peek: Peek = toml::from_str(std::str::from_utf8(&data)?)?;
+    if peek.demand.source != DemandSourceKind::Image {
+        return Ok(None);
+    }
+    load_image::demand_levels(&peek.demand).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn saved_graphs_are_named_by_key() {
+        assert_eq!(saved_key("leamington-SP.bin", GraphSource::Shapefile).as_deref(), Some("leamington"));
+        assert_eq!(saved_key("south-warwick-SP.bin", GraphSource::Shapefile).as_deref(), Some("south-warwick"));
+        assert_eq!(saved_key("leamington-osm.bin", GraphSource::Osm).as_deref(), Some("leamington"));
+        assert_eq!(saved_key("leamington-osm.bin", GraphSource::Shapefile), None);
+        assert_eq!(saved_key("leamington-SP.bin", GraphSource::Osm), None);
+        assert_eq!(saved_key("network.bin", GraphSource::Shapefile), None);
+
+        let output = Path::new("data/network_data.bin");
+        assert_eq!(network_output(output, "coventry"), PathBuf::from("data/network_data-coventry.bin"));
+    }
 }
@@ -0,0 +1,175 @@
+//! Scenario bundles: a single file holding everything needed to share a scenario with someone
+//! who already has the base data.
+//!
+//! A bundle holds the config file (simulation settings, fleet changes, service area polygons
+//! and so on) and the demand images it refers to. The road graph, shapefiles and GTFS network
+//! data are left out as they're large and the same for everyone. It's stored as CBOR like the
+//! other save files.
+//!
+//! Importing writes the config to `data/scenarios/<name>.toml` and the images into `data/img/`,
+//! so the scenario can be run with `--config data/scenarios/<name>.toml`.
+
+use std::{
+    error::Error,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ScenarioBundle {
+    pub version: u32,
+    pub name: String, // Name the config is imported as
+    pub config: String, // Contents of the config file
+    pub images: Vec<BundleFile>, // Demand images, paths relative to the image directory
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BundleFile {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+// Only the bits of the config file which refer to other files
+#[derive(Deserialize)]
+struct BundledConfig {
+    demand: BundledDemand,
+}
+
+#[derive(Deserialize)]
+struct BundledDemand {
+    paths: Vec<String>,
+}
+
+impl ScenarioBundle {
+    // Collect the config at `config_path` and the demand images it uses from `img_dir`
+    pub fn from_config(config_path: &Path, img_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let config = fs::read_to_string(config_path)?;
+        let bundled: BundledConfig = toml::from_str(&config)?;
+
+        let mut images = Vec::with_capacity(bundled.demand.paths.len());
+        for path in bundled.demand.paths {
+            let data = fs::read(img_dir.join(&path)).map_err(|err| format!("Couldn't read demand image {}: {}", path, err))?;
+            images.push(BundleFile { path, data });
+        }
+
+        let name = config_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("scenario"));
+
+        Ok(ScenarioBundle { version: BUNDLE_VERSION, name, config, images })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes)?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(path)?;
+        let bundle = ciborium::de::from_reader::<ScenarioBundle, _>(data.as_slice())?;
+        if bundle.version != BUNDLE_VERSION {
+            return Err(format!("Unsupported scenario bundle version {}", bundle.version).into());
+        }
+        Ok(bundle)
+    }
+
+    // Write the bundle out under `data_dir`, returning the path of the imported config.
+    // Existing images are only replaced if they're the same, so an import can't clobber another scenario
+    pub fn unpack(&self, data_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        check_relative(&self.name)?;
+        for image in self.images.iter() {
+            check_relative(&image.path)?;
+            let existing = data_dir.join("img").join(&image.path);
+            if existing.exists() && fs::read(&existing)? != image.data {
+                return Err(format!("A different demand image already exists at {:?}", existing).into());
+            }
+        }
+
+        for image in self.images.iter() {
+            let path = data_dir.join("img").join(&image.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, &image.data)?;
+        }
+
+        let config_path = data_dir.join("scenarios").join(format!("{}.toml", self.name));
+        fs::create_dir_all(data_dir.join("scenarios"))?;
+        fs::write(&config_path, &self.config)?;
+
+        Ok(config_path)
+    }
+}
+
+// Paths in a bundle come from someone else so mustn't escape the data directory
+fn check_relative(path: &str) -> Result<(), Box<dyn Error>> {
+    let safe = !path.is_empty() && Path::new(path).components().all(|component| matches!(component, Component::Normal(_)));
+    if safe {
+        Ok(())
+    } else {
+        Err(format!("Scenario bundle contains an unsafe path '{}'", path).into())
+    }
+}
+
+// Bundle the config at `config_path` into the file at `bundle_path`
+pub fn export(config_path: &Path, bundle_path: &Path) -> Result<(), Box<dyn Error>> {
+    let bundle = ScenarioBundle::from_config(config_path, Path::new("data/img"))?;
+    bundle.write(bundle_path)?;
+    println!("[Scenario] Exported {} with {} demand images to {:?}", bundle.name, bundle.images.len(), bundle_path);
+    Ok(())
+}
+
+// Unpack the bundle at `bundle_path` into the data directory
+pub fn import(bundle_path: &Path) -> Result<(), Box<dyn Error>> {
+    let bundle = ScenarioBundle::read(bundle_path)?;
+    let config_path = bundle.unpack(Path::new("data"))?;
+    println!("[Scenario] Imported {}, run it with --config {}", bundle.name, config_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bundle_round_trip() {
+        let dir = std::env::temp_dir().join(format!("odbrs-scenario-{}", std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("img")).unwrap();
+        fs::write(src.join("img").join("peak.png"), [1, 2, 3]).unwrap();
+        fs::write(src.join("peak.toml"), "[demand]\npaths = [\"peak.png\"]\n").unwrap();
+
+        let bundle = ScenarioBundle::from_config(&src.join("peak.toml"), &src.join("img")).unwrap();
+        bundle.write(&dir.join("peak.scenario")).unwrap();
+        let read = ScenarioBundle::read(&dir.join("peak.scenario")).unwrap();
+        assert_eq!(read, bundle);
+
+        let dest = dir.join("dest");
+        let config_path = read.unpack(&dest).unwrap();
+        assert_eq!(config_path, dest.join("scenarios").join("peak.toml"));
+        assert_eq!(fs::read(dest.join("img").join("peak.png")).unwrap(), vec![1, 2, 3]);
+
+        // A different image with the same name isn't overwritten
+        fs::write(dest.join("img").join("peak.png"), [4]).unwrap();
+        assert!(read.unpack(&dest).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsafe_paths_are_rejected() {
+        assert!(check_relative("../config.toml").is_err());
+        assert!(check_relative("/etc/passwd").is_err());
+        assert!(check_relative("nested/peak.png").is_ok());
+    }
+}
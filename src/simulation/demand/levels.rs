@@ -0,0 +1,154 @@
+//! How much demand there is through the day. The demand images take it from `minute_demand`, 24
+//! requests per minute for the hours 1..=24, or from a `[demand.levels]` table of requests per
+//! hour for just the hours that matter:
+//!
+//! ```toml
+//! [demand.levels]
+//! 7 = 600.0 # Requests an hour at 07:00
+//! 9 = 240.0
+//! 17 = 540.0
+//! ```
+//!
+//! or read from a CSV with `hour,requests` columns:
+//!
+//! ```toml
+//! [demand.levels]
+//! path = "data/demand/levels.csv"
+//! ```
+//!
+//! Each level is the rate at the start of its hour, changing evenly to the next level listed and
+//! wrapping round midnight, so a single level is used all day. Levels are checked when loaded.
+
+use std::{collections::BTreeMap, error::Error, path::PathBuf};
+
+use chrono::{NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum DemandLevelsConfig {
+    File { path: PathBuf },
+    Hours(BTreeMap<String, f64>), // Hour of the day to requests an hour
+}
+
+/// One row of the levels file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct LevelRow {
+    pub hour: String,
+    pub requests: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DemandLevels {
+    hourly: [f64; 24], // Requests an hour at the start of each hour
+    interpolate: bool, // Change evenly through the hour to the next, otherwise steps on the hour
+}
+
+impl DemandLevels {
+    // Levels from `minute_demand`, which hold for the whole hour
+    pub fn from_minute_demand(levels: &[u8]) -> Self {
+        let mut hourly = [0.0; 24];
+        for (hour, level) in hourly.iter_mut().enumerate() {
+            // Listed for the hours 1..=24, so midnight uses the last one
+            *level = levels.get((hour + 23) % 24).copied().unwrap_or(0) as f64 * 60.0;
+        }
+        DemandLevels { hourly, interpolate: false }
+    }
+
+    // Levels for some of the hours, filling in the rest between them
+    pub fn from_hours(levels: &[(String, f64)]) -> Result<Self, String> {
+        let mut points = Vec::new();
+        for (hour, requests) in levels {
+            let hour = match hour.trim().parse::<usize>() {
+                Ok(hour) if hour < 24 => hour,
+                _ => return Err(format!("Demand level hour '{}' isn't an hour of the day from 0 to 23", hour)),
+            };
+            if !requests.is_finite() || *requests < 0.0 {
+                return Err(format!("Demand level for hour {} is {}, it needs to be a number of requests", hour, requests));
+            }
+            if points.iter().any(|(listed, _)| *listed == hour) {
+                return Err(format!("Demand level hour {} is given more than once", hour));
+            }
+            points.push((hour, *requests));
+        }
+        if points.is_empty() {
+            return Err(String::from("[demand.levels] has no levels"));
+        }
+        points.sort_by_key(|(hour, _)| *hour);
+
+        let mut hourly = [0.0; 24];
+        for (hour, level) in hourly.iter_mut().enumerate() {
+            let before = points.iter().rev().find(|(listed, _)| *listed <= hour).unwrap_or(points.last().unwrap());
+            let after = points.iter().find(|(listed, _)| *listed > hour).unwrap_or(&points[0]);
+            let span = match (after.0 + 24 - before.0) % 24 {
+                0 => 24,
+                span => span,
+            };
+            let through = ((hour + 24 - before.0) % 24) as f64 / span as f64;
+            *level = before.1 + (after.1 - before.1) * through;
+        }
+        Ok(DemandLevels { hourly, interpolate: true })
+    }
+
+    pub fn load(config: &DemandLevelsConfig) -> Result<Self, Box<dyn Error>> {
+        let levels = match config {
+            DemandLevelsConfig::Hours(hours) => hours.iter().map(|(hour, requests)| (hour.clone(), *requests)).collect::<Vec<_>>(),
+            DemandLevelsConfig::File { path } => {
+                let mut levels = Vec::new();
+                for row in csv::Reader::from_path(path)?.deserialize::<LevelRow>() {
+                    let row = row?;
+                    levels.push((row.hour, row.requests));
+                }
+                levels
+            }
+        };
+        Ok(DemandLevels::from_hours(&levels)?)
+    }
+
+    // Requests each minute at `time`
+    pub fn per_minute(&self, time: NaiveTime) -> f64 {
+        let hour = time.hour() as usize;
+        let level = if self.interpolate {
+            let through = (time.minute() * 60 + time.second()) as f64 / 3600.0;
+            self.hourly[hour] + (self.hourly[(hour + 1) % 24] - self.hourly[hour]) * through
+        } else {
+            self.hourly[hour]
+        };
+        level / 60.0
+    }
+
+    // No demand at any time of day
+    pub fn is_empty(&self) -> bool {
+        self.hourly.iter().all(|level| *level == 0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn levels_change_evenly_between_the_hours_given() {
+        let levels = DemandLevels::from_hours(&[(String::from("7"), 600.0), (String::from("9"), 240.0), (String::from("19"), 0.0)]).unwrap();
+        assert_eq!(levels.per_minute(at(7, 0)), 10.0);
+        assert_eq!(levels.per_minute(at(8, 0)), 7.0);
+        assert_eq!(levels.per_minute(at(8, 30)), 5.5);
+        assert_eq!(levels.per_minute(at(14, 0)), 2.0);
+        // Round midnight from nothing at 19:00 back up to 07:00
+        assert_eq!(levels.per_minute(at(1, 0)), 5.0);
+
+        // minute_demand steps on the hour, starting from 01:00
+        let mut minute_demand = vec![2; 24];
+        minute_demand[7] = 10;
+        let levels = DemandLevels::from_minute_demand(&minute_demand);
+        assert_eq!((levels.per_minute(at(7, 59)), levels.per_minute(at(8, 0)), levels.per_minute(at(8, 59))), (2.0, 10.0, 10.0));
+
+        assert!(DemandLevels::from_hours(&[(String::from("24"), 1.0)]).is_err());
+        assert!(DemandLevels::from_hours(&[(String::from("8"), -1.0)]).is_err());
+        assert!(DemandLevels::from_hours(&[]).is_err());
+    }
+}
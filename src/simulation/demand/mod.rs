@@ -1,17 +1,26 @@
 use std::{
     collections::VecDeque,
     sync::{
-        mpsc::{sync_channel, SyncSender},
-        Arc, RwLock,
+        mpsc::{sync_channel, Receiver, SyncSender},
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
     },
 };
 
-use chrono::{DateTime, Utc, Timelike};
-use rand::Rng;
+use chrono::{DateTime, Duration, Utc};
 
-use crate::{graph::Graph, resource::load_image::{DemandResources, ImageSelection, ImageData}};
+use crate::graph::Graph;
 
-use super::static_controller::routes::NetworkData;
+use self::{policy::DemandPolicy, source::{DemandSource, LoadedDemand}};
+
+use super::{rng::SimRng, static_controller::routes::NetworkData, DEFAULT_TICK_SECONDS};
+
+pub mod levels;
+pub mod od_matrix;
+pub mod policy;
+pub mod pregenerate;
+pub mod request_log;
+pub mod source;
 
 const TICK_DEMAND: usize = 10; // 108
 
@@ -22,24 +31,33 @@ enum DemandThreadMessage {
 
 #[derive(Debug)]
 pub struct DemandGenerator {
-    resources: DemandResources,
+    source: Box<dyn DemandSource>,
     bounds: (f32, f32, f32, f32),
     thread_gen_tx: SyncSender<DemandThreadMessage>,
     demand_queue: RwLock<VecDeque<Demand>>,
+    policy: Box<dyn DemandPolicy>,
+    rng: Mutex<SimRng>,
+    rejected: AtomicU64, // Demand not made because the policy turned it down, since last taken
+    clamped: AtomicU64, // Points which fell just outside the map and were moved onto its edge, since last taken
+    dropped: AtomicU64, // Demand with no point to place on the map, since last taken
+    carried: Mutex<f64>, // Fraction of a demand left over from earlier ticks, so short ticks don't round demand away
+    tick_seconds: AtomicU64, // Simulated time each tick covers, the window recorded requests are replayed from
 }
 
+// Origin, destination, when the request was made and when they want to arrive, if they said
 #[derive(Debug, PartialEq, Clone)]
-pub struct Demand(pub (f32, f32), pub (f32, f32), pub DateTime<Utc>);
+pub struct Demand(pub (f32, f32), pub (f32, f32), pub DateTime<Utc>, pub Option<DateTime<Utc>>);
 
 impl DemandGenerator {
 
     // Send a ticks worth of demand request to the demand generator
     pub fn _tick(&self, time: DateTime<Utc>) {
-        self._send_demand_request(*self.resources.get_demand_levels().get(time.hour() as usize - 1).unwrap() as usize, time);
+        self._send_demand_request(self.get_demand_level(&time) as usize, time);
     }
 
-    pub fn get_demand_level(&self, time: &DateTime<Utc>) -> usize {
-        *self.resources.get_demand_levels().get(time.hour() as usize - 1).unwrap() as usize
+    // Trips requested per minute at `time`, before the demand scale
+    pub fn get_demand_level(&self, time: &DateTime<Utc>) -> f64 {
+        self.source.per_minute(time)
     }
 
     // Send a given amount of demand to the demand generator thread
@@ -66,19 +84,58 @@ impl DemandGenerator {
         }
     }
 
+    // Reseed the generator for a snapshot, returning the seed to resume from
+    pub fn checkpoint_rng(&self) -> u64 {
+        self.rng.lock().unwrap().checkpoint()
+    }
+
+    // Also called when a run starts, so nothing is carried over from the last one
+    pub fn set_rng(&self, rng: SimRng) {
+        *self.rng.lock().unwrap() = rng;
+        *self.carried.lock().unwrap() = 0.0;
+    }
+
+    pub fn set_tick_seconds(&self, seconds: u32) {
+        self.tick_seconds.store(seconds as u64, Ordering::Relaxed);
+    }
+
+    // Number of demands the policy rejected since this was last called
+    pub fn take_rejected(&self) -> u64 {
+        self.rejected.swap(0, Ordering::Relaxed)
+    }
+
+    // Generated points clamped onto the map and demands dropped for not having a point since this
+    // was last called
+    pub fn take_out_of_bounds(&self) -> (u64, u64) {
+        (self.clamped.swap(0, Ordering::Relaxed), self.dropped.swap(0, Ordering::Relaxed))
+    }
+
     pub fn get_demand_queue(&self) -> &RwLock<VecDeque<Demand>> {
         &self.demand_queue
     }
 
-    // Creates a demand generator and runs a thread which does the actual generation
-    pub fn start(resources: DemandResources, graph: Arc<Graph>, data: Result<Arc<Graph>, Arc<NetworkData>>) -> Arc<DemandGenerator> {
+    fn new(source: Box<dyn DemandSource>, bounds: (f32, f32, f32, f32), policy: Box<dyn DemandPolicy>, rng: SimRng) -> (DemandGenerator, Receiver<DemandThreadMessage>) {
         let (tx, rx) = sync_channel(1);
         let demand_gen = DemandGenerator {
-            resources,
-            bounds: DemandGenerator::get_transform_info(graph), 
+            source,
+            bounds,
             thread_gen_tx: tx,
             demand_queue: RwLock::new(VecDeque::new()),
+            policy,
+            rng: Mutex::new(rng),
+            rejected: AtomicU64::new(0),
+            clamped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            carried: Mutex::new(0.0),
+            tick_seconds: AtomicU64::new(DEFAULT_TICK_SECONDS as u64),
         };
+        (demand_gen, rx)
+    }
+
+    // Creates a demand generator for whichever source was configured and runs a thread which does
+    // the actual generation
+    pub fn start(demand: LoadedDemand, graph: Arc<Graph>, data: Result<Arc<Graph>, Arc<NetworkData>>, policy: Box<dyn DemandPolicy>, rng: SimRng) -> Arc<DemandGenerator> {
+        let (demand_gen, rx) = DemandGenerator::new(demand.into_source(), DemandGenerator::get_transform_info(graph), policy, rng);
 
         let demand_gen = Arc::from(demand_gen);
         let demand_gen_ref = demand_gen.clone();
@@ -117,7 +174,9 @@ impl DemandGenerator {
                                 // TODO: probably some funky interactions with dates and times here!
                                 if started && buffer.len() < 9 * TICK_DEMAND / 10 {
                                     // buffer about 90% of the demand on a tick (roughly)
-                                    buffer.push_back(demand_gen_ref.generate_random_pixel(&last_time));
+                                    if let Some(demand) = demand_gen_ref.generate_one(&last_time) {
+                                        buffer.push_back(demand);
+                                    }
                                 }
                             }
                         }
@@ -129,178 +188,222 @@ impl DemandGenerator {
         demand_gen
     }
 
-    // Selects the right image based on numerous factors
-    pub fn select_image(&self, time: &DateTime<Utc>) -> Arc<Box<ImageData>> {
-        match self.resources.get_selection() {
-            ImageSelection::ConstantChoice(i) => {
-                self.resources.get_images().get(i).expect("Wrong key in selection").clone()
-            },
-            ImageSelection::RandomChoice => {
-                let i = rand::thread_rng().gen_range(0..self.resources.get_images().len() as u8);
-                self.resources.get_images().get(&i).expect("Couldn't randomise selection").clone()
-            },
-            ImageSelection::TimeBasedChoice(map) => {
-                let i = map.get(time.hour() as usize).expect("Couldn't get time based index");
-                
-                // println!("time {:?} choice {:?}", time.hour(), i);
-                self.resources.get_images().get(&i).expect("Couldn't select based on time").clone()
-            }
-        }
-    }
-
-    // Generates a singular demand
-    pub fn generate_random_pixel(&self, time: &DateTime<Utc>) -> Demand {
-        let image = self.select_image(time);
-
-        let mut r_pix = None;
-        let mut g_pix = None;
-        let mut b_pix = None;
-
-        let (r_w, g_w, b_w) = image.get_max_weight();
-
-        // println!("image max weight {:?} {:?} {:?}", r_w, g_w, b_w);
+    // Generates a singular demand, None if the source gave nowhere to put the source or destination
+    pub fn generate_one(&self, time: &DateTime<Utc>) -> Option<Demand> {
+        let sampled = {
+            let mut rng = match self.rng.lock() {
+                Ok(rng) => rng,
+                Err(err) => panic!("Error locking demand rng {}", err),
+            };
+            self.source.sample(time, self.bounds, &mut rng)
+        };
 
-        let mut rng_r = rand::thread_rng().gen_range(0..if r_w > 0 { r_w } else { 1 });
-        let mut rng_g = rand::thread_rng().gen_range(0..if g_w > 0 { g_w } else { 1 });
-        let mut rng_b = rand::thread_rng().gen_range(0..if b_w > 0 { b_w } else { 1 });
+        // No point means e.g. an image with no weight in that channel, rather than a point at the map's corner
+        let Some((source, dest)) = sampled else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let (source, dest) = self.onto_map(source, dest)?;
 
-        for (i, pix) in image.get_image().pixels().enumerate() {
-            if rng_r > 0 { rng_r = match rng_r.checked_sub(pix.0[0] as u64) {
-                Some(a) => a,
-                None => { r_pix = Some(i); 0 }
-            } }
+        Some(Demand(source, dest, *time, None))
+    }
 
-            if rng_g > 0 { rng_g = match rng_g.checked_sub(pix.0[1] as u64) {
-                Some(a) => a,
-                None => { g_pix = Some(i); 0 }
-            } }
-            
-            if rng_b > 0 { rng_b = match rng_b.checked_sub(pix.0[2] as u64) {
-                Some(a) => a,
-                None => { b_pix = Some(i); 0 }
-            } }
+    // Where trips starting at `time` are expected over a `cells` x `cells` grid across the map, see
+    // `DemandSource::forecast`
+    pub fn forecast(&self, time: &DateTime<Utc>, cells: usize) -> Vec<((f64, f64), f64)> {
+        self.source
+            .forecast(time, self.bounds, cells)
+            .into_iter()
+            .filter_map(|(point, weight)| Some((point64(clamp_to_bounds(point, self.bounds)?), weight)))
+            .collect()
+    }
 
-            if rng_r <= 0 && rng_g <= 0 && rng_b <= 0 {
-                break;
+    // Clamps both ends onto the map, None (and counted as dropped) if either has no position at all
+    fn onto_map(&self, source: (f32, f32), dest: (f32, f32)) -> Option<((f32, f32), (f32, f32))> {
+        match (clamp_to_bounds(source, self.bounds), clamp_to_bounds(dest, self.bounds)) {
+            (Some(clamped_source), Some(clamped_dest)) => {
+                let clamped = (clamped_source != source) as u64 + (clamped_dest != dest) as u64;
+                self.clamped.fetch_add(clamped, Ordering::Relaxed);
+                Some((clamped_source, clamped_dest))
+            }
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                None
             }
         }
-
-        let width = image.get_width() as usize;
-
-        let map_width = self.bounds.1 - self.bounds.0;
-        let map_height = self.bounds.3 - self.bounds.2;
-
-        let mut source = (self.bounds.0, self.bounds.3);
-        let mut dest = (self.bounds.0, self.bounds.3);
-    
-        if let Some(r) = r_pix {
-            let r_x_y = (r % width, r / width);
-            // println!("Gen: random red value: {:?}", r_x_y);
-            source = (
-                (r_x_y.0 as f32 + rand::thread_rng().gen_range(0.0..1.0_f32)) *  (map_width as f32 / width as f32) + self.bounds.0,
-                (r_x_y.1 as f32 + rand::thread_rng().gen_range(0.0..1.0_f32)) * -(map_height as f32 / image.get_height() as f32) + self.bounds.3
-            )
-        }
-        
-        if let Some(g) = g_pix {
-            let _g_x_y = (g % width, g / width);
-            // println!("Gen: random green value: {:?}", _g_x_y);
-        }
-
-        if let Some(b) = b_pix {
-            let b_x_y = (b % width, b / width);
-            // println!("Gen: random blue value: {:?}", b_x_y);
-            dest = (
-                (b_x_y.0 as f32 + rand::thread_rng().gen_range(0.0..1.0_f32)) *  (map_width as f32 / width as f32) + self.bounds.0,
-                (b_x_y.1 as f32 + rand::thread_rng().gen_range(0.0..1.0_f32)) * -(map_height as f32 / image.get_height() as f32) + self.bounds.3
-            )
-        }
-
-        // println!("Gen Pixel Src={:?} Dest={:?}", source, dest);
-        if source == (0.0, 0.0) || dest == (0.0, 0.0) {
-            println!("Generated a 0,0 source {:?} dest {:?}", source, dest);
-        }
-
-        return Demand(source, dest, DateTime::<Utc>::MIN_UTC);
     }
 
     // Generates an amount of demand
     pub fn generate_amount(&self, amount: usize, time: &DateTime<Utc>, data: Result<Arc<Graph>, Arc<NetworkData>>) -> VecDeque<Demand> {
         let mut vec = VecDeque::with_capacity(amount);
         let mut attempts = 0; // limit number of failed generation attempts to keep it fast
+        let mut turned_down = false;
 
         while vec.len() < amount && attempts < 10 {
             // println!("Generating demand {}/{}", vec.len(), amount);
-            let demand = self.generate_random_pixel(time);
-            if should_accept_demand(&demand, data.clone()) {
+            let Some(demand) = self.generate_one(time) else {
+                attempts += 1;
+                continue;
+            };
+            if self.policy.accept(&demand, &data) {
                 vec.push_back(demand);
                 attempts = 0; // reset attempts after successful generation
             } else {
+                turned_down = true;
                 attempts += 1; // increment attempts after failed generation
                 continue;
             }
         }
+
+        // Each retry resamples the same demand, so it's what was asked for but not made that's counted
+        if turned_down {
+            self.rejected.fetch_add((amount - vec.len()) as u64, Ordering::Relaxed);
+        }
         vec
     }
 
+    // Demand levels are per minute, `scale` also covers the tick length. Sources replaying recorded
+    // requests give every one made during the tick instead, unscaled
     pub fn generate_scaled_amount(&self, scale: f64, time: &DateTime<Utc>, data: Result<Arc<Graph>, Arc<NetworkData>>) -> VecDeque<Demand> {
-        let amount = (self.get_demand_level(time) as f64 * scale) as usize;
+        let tick_end = *time + Duration::seconds(self.tick_seconds.load(Ordering::Relaxed) as i64);
+        if let Some(recorded) = self.source.recorded(time, &tick_end) {
+            return self.replay(recorded, &data);
+        }
+
+        let amount = {
+            let mut carried = self.carried.lock().unwrap();
+            let exact = self.get_demand_level(time) * scale + *carried;
+            *carried = exact.fract();
+            exact as usize
+        };
         self.generate_amount(amount, time, data)
     }
-}
-
-const HUMAN_WALKING_SPEED: f64 = 1.4; // m/s // TODO: is this consistent?
-
-// Returns true if the demand should be rejected because it's more than 15 min from any bus-stop
-pub fn should_accept_demand(demand: &Demand, data: Result<Arc<Graph>, Arc<NetworkData>>) -> bool {
-    match data {
-        Ok(graph) => {
-            let mut min_src_dist = f64::MAX;
-            let mut min_dest_dist = f64::MAX;
-            
-            for (_, node) in graph.get_nodelist() {
-                let src_dist = distance(node.point, point64(demand.0));
-                let dest_dst = distance(node.point, point64(demand.1));
-                
-                if src_dist < min_src_dist {
-                    min_src_dist = src_dist;
-                }
 
-                if dest_dst < min_dest_dist {
-                    min_dest_dist = dest_dst;
-                }
-            }
-            
-            min_dest_dist / HUMAN_WALKING_SPEED < 15.0 * 60.0 && min_src_dist / HUMAN_WALKING_SPEED < 15.0 * 60.0
-        },
-        Err(network) => {
-            let mut min_src_dist = f64::MAX;
-            let mut min_dest_dist = f64::MAX;
-            
-            for (_, stop) in network.stops.iter() {
-                let src_dist = distance(stop.position(), point64(demand.0));
-                let dest_dist = distance(stop.position(), point64(demand.1));
-
-                if src_dist < min_src_dist {
-                    min_src_dist = src_dist;
+    // Recorded requests which land on the map and pass the policy, keeping the times they were made
+    fn replay(&self, recorded: Vec<Demand>, data: &Result<Arc<Graph>, Arc<NetworkData>>) -> VecDeque<Demand> {
+        recorded
+            .into_iter()
+            .filter_map(|Demand(source, dest, time, arrival)| self.onto_map(source, dest).map(|(source, dest)| Demand(source, dest, time, arrival)))
+            .filter(|demand| {
+                let accepted = self.policy.accept(demand, data);
+                if !accepted {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
                 }
+                accepted
+            })
+            .collect()
+    }
+}
 
-                if dest_dist < min_dest_dist {
-                    min_dest_dist = dest_dist;
-                }
-            }
+// Map coord of a point `jitter` of the way across the pixel at index `pixel` of a `width` x `height`
+// image stretched over `bounds` (left, right, bottom, top), image rows running down from the top
+fn pixel_to_map(pixel: usize, width: usize, height: usize, jitter: (f32, f32), bounds: (f32, f32, f32, f32)) -> (f32, f32) {
+    let (left, right, bottom, top) = bounds;
+    let (x, y) = (pixel % width, pixel / width);
+    (
+        left + (x as f32 + jitter.0) * (right - left) / width as f32,
+        top - (y as f32 + jitter.1) * (top - bottom) / height as f32,
+    )
+}
 
-            min_dest_dist / HUMAN_WALKING_SPEED < 15.0 * 60.0 && min_src_dist / HUMAN_WALKING_SPEED < 15.0 * 60.0
-        }
+// Points just outside from float error go on the map's edge, None for ones with no position at all
+fn clamp_to_bounds(point: (f32, f32), bounds: (f32, f32, f32, f32)) -> Option<(f32, f32)> {
+    let (left, right, bottom, top) = bounds;
+    if !(point.0.is_finite() && point.1.is_finite()) || left > right || bottom > top {
+        return None;
     }
+    Some((point.0.clamp(left, right), point.1.clamp(bottom, top)))
 }
 
-fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
-    let xs = (a.0 - b.0).abs();
-    let ys = (a.1 - b.1).abs();
-    xs.hypot(ys)
-}
 
 fn point64((a, b): (f32, f32)) -> (f64, f64) {
     (a as f64, b as f64)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use crate::resource::load_image::{DemandResources, ImageData, ImageSelection};
+
+    use super::{levels::DemandLevels, policy::{AlwaysAccept, DemandPolicy}, source::ImageSource, *};
+
+    const BOUNDS: (f32, f32, f32, f32) = (425174.28, 439679.25, 273637.59, 286113.25); // The West Midlands in metres
+
+    fn generator(image: RgbImage, bounds: (f32, f32, f32, f32)) -> DemandGenerator {
+        let resources = DemandResources::from_images(ImageSelection::ConstantChoice(0), vec![ImageData::new(DynamicImage::ImageRgb8(image))], DemandLevels::default());
+        DemandGenerator::new(Box::new(ImageSource::new(resources)), bounds, Box::new(AlwaysAccept), SimRng::seeded(7)).0
+    }
+
+    fn inside((x, y): (f32, f32), (left, right, bottom, top): (f32, f32, f32, f32)) -> bool {
+        left <= x && x <= right && bottom <= y && y <= top
+    }
+
+    #[test]
+    fn generated_demand_stays_on_the_map() {
+        // Weight in the corners so the edges of the map get picked
+        let image = RgbImage::from_fn(16, 8, |x, y| if (x == 0 || x == 15) && (y == 0 || y == 7) { Rgb([255, 0, 255]) } else { Rgb([1, 0, 1]) });
+        let generator = generator(image, BOUNDS);
+
+        let demand = generator.generate_amount(2000, &Utc::now(), Err(Arc::new(NetworkData::default())));
+        assert_eq!(demand.len(), 2000);
+        for Demand(source, dest, ..) in demand.iter() {
+            assert!(inside(*source, BOUNDS), "source {:?} off the map", source);
+            assert!(inside(*dest, BOUNDS), "dest {:?} off the map", dest);
+        }
+        assert_eq!(generator.take_out_of_bounds().1, 0);
+
+        // The top left pixel is at the top left of the map, the bottom right one at the bottom right
+        let (left, right, bottom, top) = BOUNDS;
+        assert_eq!(pixel_to_map(0, 16, 8, (0.0, 0.0), BOUNDS), (left, top));
+        let corner = pixel_to_map(16 * 8 - 1, 16, 8, (1.0, 1.0), BOUNDS);
+        assert!((corner.0 - right).abs() < 0.1 && (corner.1 - bottom).abs() < 0.1);
+    }
+
+    #[test]
+    fn demand_without_a_place_is_dropped_and_counted() {
+        // No blue anywhere so there's never a destination
+        let generator = generator(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])), BOUNDS);
+        assert_eq!(generator.generate_one(&Utc::now()), None);
+        assert_eq!(generator.generate_amount(5, &Utc::now(), Err(Arc::new(NetworkData::default()))).len(), 0);
+        assert_eq!(generator.take_out_of_bounds(), (0, 11));
+        assert_eq!(generator.take_out_of_bounds(), (0, 0));
+
+        // Bounds of a graph with no points are inside out
+        let generator = self::generator(RgbImage::from_pixel(4, 4, Rgb([255, 0, 255])), (f32::MAX, f32::MIN, f32::MAX, f32::MIN));
+        assert_eq!(generator.generate_one(&Utc::now()), None);
+
+        assert_eq!(clamp_to_bounds((BOUNDS.1 + 0.01, BOUNDS.2 - 0.01), BOUNDS), Some((BOUNDS.1, BOUNDS.2)));
+        assert_eq!(clamp_to_bounds((f32::NAN, 0.0), BOUNDS), None);
+    }
+
+    #[derive(Debug)]
+    struct RejectAll;
+
+    impl DemandPolicy for RejectAll {
+        fn accept(&self, _demand: &Demand, _data: &Result<Arc<Graph>, Arc<NetworkData>>) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn rejected_demand_is_counted_once() {
+        let resources = DemandResources::from_images(ImageSelection::ConstantChoice(0), vec![ImageData::new(DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 0, 255]))))], DemandLevels::default());
+        let generator = DemandGenerator::new(Box::new(ImageSource::new(resources)), BOUNDS, Box::new(RejectAll), SimRng::seeded(7)).0;
+
+        // Ten tries at the first before giving up, but only the five asked for weren't made
+        assert_eq!(generator.generate_amount(5, &Utc::now(), Err(Arc::new(NetworkData::default()))).len(), 0);
+        assert_eq!(generator.take_rejected(), 5);
+    }
+
+    #[test]
+    fn forecast_sums_trip_starts_over_cells() {
+        // Trips start from the top left and bottom right pixels, twice as many from the top left
+        let image = RgbImage::from_fn(4, 4, |x, y| match (x, y) {
+            (0, 0) => Rgb([200, 0, 255]),
+            (3, 3) => Rgb([100, 0, 255]),
+            _ => Rgb([0, 0, 255]),
+        });
+        let generator = generator(image, (0.0, 400.0, 0.0, 400.0));
+        assert_eq!(generator.forecast(&Utc::now(), 2), vec![((50.0, 350.0), 200.0), ((350.0, 50.0), 100.0)]);
+    }
+}
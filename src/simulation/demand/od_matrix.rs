@@ -0,0 +1,241 @@
+//! Demand from an origin-destination matrix, trips between zones for each hour of the day, as
+//! transport models and travel surveys usually give it. Chosen in the `[demand]` config:
+//!
+//! ```toml
+//! [demand]
+//! source = "od_matrix"
+//!
+//! [demand.od_matrix]
+//! path = "data/demand/od.csv" # Or a .parquet file, when built with the `parquet` feature
+//!
+//! [[demand.od_matrix.zones]] # The [analytics] zones are used if none are given
+//! name = "Town Centre"
+//! points = [[429000.0, 287000.0], [430500.0, 287000.0], [430500.0, 288200.0], [429000.0, 288200.0]]
+//! ```
+//!
+//! The matrix has a row for each hour and pair of zones with trips between them, hours 0 to 23
+//! being the hour the trips start in:
+//!
+//! ```text
+//! hour,origin,destination,trips
+//! 8,Town Centre,Hospital,42.5
+//! 8,Hospital,Town Centre,12
+//! ```
+//!
+//! Each trip picks a pair in proportion to its trips that hour, then a random point inside each
+//! zone. The demand scale applies on top, the same as for demand images.
+
+use std::{collections::HashMap, error::Error, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Timelike, Utc};
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::analytics::zones::ZoneConfig;
+
+use super::source::DemandSource;
+
+const PLACE_ATTEMPTS: usize = 50; // Random points tried in a zone's bounding box before giving up on it
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OdMatrixConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+}
+
+/// One row of the matrix file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OdRow {
+    pub hour: u32,
+    pub origin: String,
+    pub destination: String,
+    pub trips: f64,
+}
+
+// Pairs of zones (by index) with trips between them during one hour
+#[derive(Debug, Default)]
+struct HourTrips {
+    pairs: Vec<(usize, usize)>,
+    cumulative: Vec<f64>, // Running total of trips up to and including each pair
+}
+
+impl HourTrips {
+    fn total(&self) -> f64 {
+        self.cumulative.last().copied().unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct OdMatrix {
+    zones: Vec<ZoneConfig>,
+    hours: Vec<HourTrips>, // 24 of them, from midnight
+}
+
+impl OdMatrix {
+    // Zones in the rows must all be in `zones`, rows for an hour past 23 or with no trips are errors
+    pub fn new(zones: Vec<ZoneConfig>, rows: Vec<OdRow>) -> Result<Self, String> {
+        let index: HashMap<&str, usize> = zones.iter().enumerate().map(|(i, zone)| (zone.name.as_str(), i)).collect();
+        let mut hours: Vec<HourTrips> = (0..24).map(|_| HourTrips::default()).collect();
+
+        for row in rows {
+            let zone = |name: &str| index.get(name).copied().ok_or_else(|| format!("OD matrix zone '{}' isn't one of the configured zones", name));
+            let (origin, destination) = (zone(&row.origin)?, zone(&row.destination)?);
+            let hour = hours.get_mut(row.hour as usize).ok_or_else(|| format!("OD matrix hour {} isn't between 0 and 23", row.hour))?;
+            if !(row.trips >= 0.0 && row.trips.is_finite()) {
+                return Err(format!("OD matrix has {} trips from {} to {} at hour {}", row.trips, row.origin, row.destination, row.hour));
+            }
+            if row.trips > 0.0 {
+                let total = hour.total() + row.trips;
+                hour.pairs.push((origin, destination));
+                hour.cumulative.push(total);
+            }
+        }
+
+        Ok(OdMatrix { zones, hours })
+    }
+
+    pub fn load(config: &OdMatrixConfig, default_zones: &[ZoneConfig]) -> Result<Self, Box<dyn Error>> {
+        let zones = if config.zones.is_empty() { default_zones.to_vec() } else { config.zones.clone() };
+        if zones.is_empty() {
+            return Err("An OD matrix needs zones, in [[demand.od_matrix.zones]] or [[analytics.zones]]".into());
+        }
+
+        let rows = match config.path.extension().and_then(|ext| ext.to_str()) {
+            Some("parquet") => read_parquet(&config.path)?,
+            _ => csv::Reader::from_path(&config.path)?.deserialize().collect::<Result<Vec<OdRow>, _>>()?,
+        };
+        let matrix = OdMatrix::new(zones, rows)?;
+        println!(
+            "[Demand] Loaded an OD matrix of {} trips between {} zones from {:?}",
+            matrix.hours.iter().map(HourTrips::total).sum::<f64>(),
+            matrix.zones.len(),
+            config.path
+        );
+        Ok(matrix)
+    }
+
+    // A random point inside the zone, None if none of the tries landed in it
+    fn place(&self, zone: usize, rng: &mut StdRng) -> Option<(f32, f32)> {
+        let zone = &self.zones[zone];
+        let (min_x, max_x) = zone.points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p.0), max.max(p.0)));
+        let (min_y, max_y) = zone.points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p.1), max.max(p.1)));
+        if !(min_x < max_x && min_y < max_y) {
+            return None;
+        }
+
+        (0..PLACE_ATTEMPTS)
+            .map(|_| (rng.gen_range(min_x..max_x), rng.gen_range(min_y..max_y)))
+            .find(|point| zone.contains(*point))
+            .map(|(x, y)| (x as f32, y as f32))
+    }
+}
+
+impl DemandSource for OdMatrix {
+    fn per_minute(&self, time: &DateTime<Utc>) -> f64 {
+        self.hours[time.hour() as usize].total() / 60.0
+    }
+
+    fn sample(&self, time: &DateTime<Utc>, _bounds: (f32, f32, f32, f32), rng: &mut StdRng) -> Option<((f32, f32), (f32, f32))> {
+        let hour = &self.hours[time.hour() as usize];
+        if hour.pairs.is_empty() {
+            return None;
+        }
+        let draw = rng.gen_range(0.0..hour.total());
+        let pair = hour.cumulative.partition_point(|total| *total <= draw).min(hour.pairs.len() - 1);
+        let (origin, destination) = hour.pairs[pair];
+        Some((self.place(origin, rng)?, self.place(destination, rng)?))
+    }
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet(path: &Path) -> Result<Vec<OdRow>, Box<dyn Error>> {
+    use parquet::{file::reader::{FileReader, SerializedFileReader}, record::Field};
+
+    // Numbers may have been written as any width of integer or float
+    fn number(field: &Field) -> Option<f64> {
+        match field {
+            Field::Byte(v) => Some(*v as f64),
+            Field::Short(v) => Some(*v as f64),
+            Field::Int(v) => Some(*v as f64),
+            Field::Long(v) => Some(*v as f64),
+            Field::UByte(v) => Some(*v as f64),
+            Field::UShort(v) => Some(*v as f64),
+            Field::UInt(v) => Some(*v as f64),
+            Field::ULong(v) => Some(*v as f64),
+            Field::Float(v) => Some(*v as f64),
+            Field::Double(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+    let mut rows = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let (mut hour, mut origin, mut destination, mut trips) = (None, None, None, None);
+        for (name, field) in row.get_column_iter() {
+            match (name.as_str(), field) {
+                ("hour", field) => hour = number(field),
+                ("origin", Field::Str(zone)) => origin = Some(zone.clone()),
+                ("destination", Field::Str(zone)) => destination = Some(zone.clone()),
+                ("trips", field) => trips = number(field),
+                _ => {}
+            }
+        }
+        match (hour, origin, destination, trips) {
+            (Some(hour), Some(origin), Some(destination), Some(trips)) => rows.push(OdRow { hour: hour as u32, origin, destination, trips }),
+            _ => return Err(format!("OD matrix row {} needs hour, origin, destination and trips columns", rows.len() + 1).into()),
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet(_path: &Path) -> Result<Vec<OdRow>, Box<dyn Error>> {
+    Err("odbrs was built without the `parquet` feature".into())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use crate::simulation::rng::SimRng;
+
+    use super::*;
+
+    fn square(name: &str, x: f64) -> ZoneConfig {
+        ZoneConfig { name: name.to_string(), points: vec![(x, 0.0), (x + 100.0, 0.0), (x + 100.0, 100.0), (x, 100.0)] }
+    }
+
+    fn row(hour: u32, origin: &str, destination: &str, trips: f64) -> OdRow {
+        OdRow { hour, origin: origin.to_string(), destination: destination.to_string(), trips }
+    }
+
+    #[test]
+    fn trips_follow_the_matrix() {
+        let zones = vec![square("A", 0.0), square("B", 1000.0)];
+        let matrix = OdMatrix::new(zones.clone(), vec![row(8, "A", "B", 90.0), row(8, "B", "A", 30.0), row(9, "A", "A", 0.0)]).unwrap();
+
+        let eight = Utc.with_ymd_and_hms(2023, 3, 1, 8, 30, 0).unwrap();
+        let nine = Utc.with_ymd_and_hms(2023, 3, 1, 9, 0, 0).unwrap();
+        assert_eq!(matrix.per_minute(&eight), 2.0);
+        assert_eq!(matrix.per_minute(&nine), 0.0);
+
+        let mut rng = SimRng::seeded(3);
+        let bounds = (0.0, 1100.0, 0.0, 100.0);
+        let mut a_to_b = 0;
+        for _ in 0..1000 {
+            let (origin, destination) = matrix.sample(&eight, bounds, &mut rng).unwrap();
+            let (from, to) = (zones.iter().position(|z| z.contains((origin.0 as f64, origin.1 as f64))), zones.iter().position(|z| z.contains((destination.0 as f64, destination.1 as f64))));
+            assert!(matches!((from, to), (Some(0), Some(1)) | (Some(1), Some(0))), "{:?} to {:?}", origin, destination);
+            a_to_b += (from == Some(0)) as u32;
+        }
+        // Three quarters of the trips go from A to B
+        assert!((700..800).contains(&a_to_b), "{} of 1000 from A to B", a_to_b);
+        assert_eq!(matrix.sample(&nine, bounds, &mut rng), None);
+
+        assert!(OdMatrix::new(zones.clone(), vec![row(8, "A", "C", 1.0)]).is_err());
+        assert!(OdMatrix::new(zones, vec![row(24, "A", "B", 1.0)]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Rules deciding whether a generated demand should be turned into a passenger at all
+
+use std::{fmt::Debug, sync::Arc};
+
+use serde::Deserialize;
+
+use crate::{graph::{query::point_in_polygon, Graph}, simulation::{static_controller::routes::NetworkData, walking::WalkingConfig}, units::{Meters, Seconds}};
+
+use super::{point64, Demand};
+
+pub trait DemandPolicy: Send + Sync + Debug {
+    // Returns true if the demand should be kept
+    fn accept(&self, demand: &Demand, data: &Result<Arc<Graph>, Arc<NetworkData>>) -> bool;
+}
+
+/// Accept everything the generator produces
+#[derive(Debug)]
+pub struct AlwaysAccept;
+
+impl DemandPolicy for AlwaysAccept {
+    fn accept(&self, _demand: &Demand, _data: &Result<Arc<Graph>, Arc<NetworkData>>) -> bool {
+        true
+    }
+}
+
+/// Reject demand which is more than a walk of `max_walk_minutes` from any node (dynamic) or bus stop (static)
+#[derive(Debug)]
+pub struct WalkDistance {
+    pub max_walk_minutes: f64,
+    pub walking: WalkingConfig,
+}
+
+impl DemandPolicy for WalkDistance {
+    fn accept(&self, demand: &Demand, data: &Result<Arc<Graph>, Arc<NetworkData>>) -> bool {
+        // Distance to the closest node or stop, infinite if there are none
+        let closest = |point: (f64, f64)| -> Meters {
+            match data {
+                Ok(graph) => graph
+                    .nearest_node(point)
+                    .and_then(|node| graph.get_nodelist().get(&node))
+                    .map_or(Meters(f64::INFINITY), |node| Meters::between(graph.crs(), node.point, point)),
+                Err(network) => network
+                    .nearest_stop(point)
+                    .and_then(|(stop, _)| network.stops.get(&stop))
+                    .map_or(Meters(f64::INFINITY), |stop| Meters::between(network.crs(), stop.position(), point)),
+            }
+        };
+
+        let min_src_dist = closest(point64(demand.0));
+        let min_dest_dist = closest(point64(demand.1));
+
+        let max_walk = Seconds::from_minutes(self.max_walk_minutes);
+        self.walking.seconds(min_dest_dist) < max_walk && self.walking.seconds(min_src_dist) < max_walk
+    }
+}
+
+/// Only accept demand which starts and ends inside the service area polygon
+#[derive(Debug)]
+pub struct ServiceArea {
+    pub polygon: Vec<(f64, f64)>,
+}
+
+impl DemandPolicy for ServiceArea {
+    fn accept(&self, demand: &Demand, _data: &Result<Arc<Graph>, Arc<NetworkData>>) -> bool {
+        point_in_polygon(point64(demand.0), &self.polygon) && point_in_polygon(point64(demand.1), &self.polygon)
+    }
+}
+
+/// Selects the acceptance policy from the simulation config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum DemandPolicyConfig {
+    #[serde(alias = "walk")]
+    WalkDistance { max_walk_minutes: f64 },
+    #[serde(alias = "area")]
+    ServiceArea { polygon: Vec<(f64, f64)> },
+    #[serde(alias = "always")]
+    AlwaysAccept,
+}
+
+impl Default for DemandPolicyConfig {
+    fn default() -> Self {
+        DemandPolicyConfig::WalkDistance { max_walk_minutes: 15.0 }
+    }
+}
+
+impl DemandPolicyConfig {
+    pub fn build(&self, walking: WalkingConfig) -> Box<dyn DemandPolicy> {
+        match self {
+            DemandPolicyConfig::WalkDistance { max_walk_minutes } => Box::new(WalkDistance { max_walk_minutes: *max_walk_minutes, walking }),
+            DemandPolicyConfig::ServiceArea { polygon } => Box::new(ServiceArea { polygon: polygon.clone() }),
+            DemandPolicyConfig::AlwaysAccept => Box::new(AlwaysAccept),
+        }
+    }
+
+    // Say how much of the road graph a service area covers, as one given in the wrong coordinates
+    // rejects everything
+    pub fn check(&self, graph: &Graph) {
+        if let DemandPolicyConfig::ServiceArea { polygon } = self {
+            let nodes = graph.nodes_within(polygon).len();
+            println!("[Demand] Service area covers {} junctions and {} roads", nodes, graph.edges_intersecting(polygon).len());
+            if nodes == 0 {
+                eprintln!("[Demand] No junctions are inside the service area, is it in map coordinates?");
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Generating the whole run's demand before it starts, so it can be looked over first and every
+//! run compared gets exactly the same requests. Turned on with a file to keep the demand in:
+//!
+//! ```toml
+//! [simulation]
+//! pregenerate_demand = "data/demand/pregenerated.csv"
+//! ```
+//!
+//! The demand is drawn tick by tick from the run's start to its end with the run's seed, demand
+//! scale and policy, as it would've been while running, then written to the file in the request
+//! log format (see [`super::request_log`]) and replayed from there. If the file is already there
+//! it's replayed as it is, so runs of other controllers or fleets use the same demand. Delete it
+//! to generate again, e.g. after changing the seed or the times.
+//!
+//! The GUI shows a summary and the trips on the map until the run is started.
+
+use std::{error::Error, fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}, sync::Arc};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+
+use crate::{geometry::Crs, graph::Graph, simulation::{rng::SimRng, static_controller::routes::NetworkData}};
+
+use super::{
+    point64,
+    policy::DemandPolicy,
+    request_log::{RequestLog, RequestLogConfig},
+    source::LoadedDemand,
+    Demand, DemandGenerator,
+};
+
+/// The run demand is generated for, as the simulation would step through it
+#[derive(Debug, Clone, Copy)]
+pub struct RunWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub tick_seconds: u32,
+    pub scale: f64, // Demand scale for each tick, covering its length
+}
+
+/// What the pre-generated demand comes to, shown before the run is started
+#[derive(Debug, Clone, PartialEq)]
+pub struct DemandSummary {
+    pub requests: usize,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+    pub hourly: Vec<(DateTime<Utc>, usize)>, // Requests made in each hour with any, by the hour's start
+    pub average_trip_km: f64, // Straight line from origin to destination
+}
+
+impl DemandSummary {
+    // Summary of `demand` on a map in `crs`
+    pub fn new(demand: &[Demand], crs: Crs) -> Self {
+        let mut hourly: Vec<(DateTime<Utc>, usize)> = Vec::new();
+        for Demand(_, _, time, _) in demand {
+            let hour = time.duration_trunc(Duration::hours(1)).unwrap_or(*time);
+            match hourly.iter_mut().find(|(listed, _)| *listed == hour) {
+                Some((_, count)) => *count += 1,
+                None => hourly.push((hour, 1)),
+            }
+        }
+        hourly.sort_by_key(|(hour, _)| *hour);
+
+        let total_km: f64 = demand.iter().map(|Demand(origin, destination, ..)| crs.distance(point64(*origin), point64(*destination)) / 1000.0).sum();
+        DemandSummary {
+            requests: demand.len(),
+            first: demand.iter().map(|demand| demand.2).min(),
+            last: demand.iter().map(|demand| demand.2).max(),
+            hourly,
+            average_trip_km: if demand.is_empty() { 0.0 } else { total_km / demand.len() as f64 },
+        }
+    }
+
+    // The hour with the most requests and how many
+    pub fn busiest_hour(&self) -> Option<(DateTime<Utc>, usize)> {
+        self.hourly.iter().copied().max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+}
+
+/// Pre-generated demand for the GUI to show before the run starts
+#[derive(Debug, Clone)]
+pub struct DemandPreview {
+    pub path: PathBuf, // Where it was written, or replayed from
+    pub summary: DemandSummary,
+    pub trips: Vec<((f32, f32), (f32, f32))>, // Origin and destination of every request
+}
+
+impl DemandGenerator {
+    // Every tick's demand over `window` in one go, the same as asking for it each tick. The clock
+    // moves on before each tick's demand, and the last tick is the first at or past the end
+    pub fn generate_window(&self, window: &RunWindow, data: Result<Arc<Graph>, Arc<NetworkData>>) -> Vec<Demand> {
+        let tick = Duration::seconds(window.tick_seconds.max(1) as i64);
+        let mut demand = Vec::new();
+        let mut time = window.start;
+        while time < window.end {
+            time += tick;
+            demand.extend(self.generate_scaled_amount(window.scale, &time, data.clone()));
+        }
+        demand
+    }
+}
+
+// Replay the demand in `path`, generating it from `demand` over `window` and writing it there first
+// if it isn't there yet
+pub fn load_or_generate(
+    path: &Path,
+    demand: LoadedDemand,
+    window: &RunWindow,
+    graph: Arc<Graph>,
+    data: Result<Arc<Graph>, Arc<NetworkData>>,
+    policy: Box<dyn DemandPolicy>,
+    rng: SimRng,
+) -> Result<(LoadedDemand, DemandPreview), Box<dyn Error>> {
+    let crs = graph.crs();
+    let log = if path.exists() {
+        println!("[Demand] Replaying the pre-generated demand in {:?}, delete it to generate again", path);
+        RequestLog::load(&RequestLogConfig { path: path.to_path_buf(), lat_lon: false }, crs)?
+    } else {
+        let timer = std::time::Instant::now();
+        let (generator, _) = DemandGenerator::new(demand.into_source(), DemandGenerator::get_transform_info(graph), policy, rng);
+        generator.set_tick_seconds(window.tick_seconds);
+        let generated = generator.generate_window(window, data);
+        write(path, &generated)?;
+        println!("[Demand] Pre-generated {} requests from {} to {} in {:?}, written to {:?}", generated.len(), window.start, window.end, timer.elapsed(), path);
+        RequestLog::new(generated)
+    };
+
+    let preview = DemandPreview {
+        path: path.to_path_buf(),
+        summary: DemandSummary::new(log.requests(), crs),
+        trips: log.requests().iter().map(|Demand(origin, destination, ..)| (*origin, *destination)).collect(),
+    };
+    Ok((LoadedDemand::RequestLog(log), preview))
+}
+
+// Write demand as a request log, map coordinates and times in RFC 3339
+pub fn write(path: &Path, demand: &[Demand]) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "time,origin_x,origin_y,destination_x,destination_y,desired_arrival")?;
+    for Demand(origin, destination, time, arrival) in demand {
+        let arrival = arrival.map_or(String::new(), |arrival| arrival.to_rfc3339());
+        writeln!(file, "{},{},{},{},{},{}", time.to_rfc3339(), origin.0, origin.1, destination.0, destination.1, arrival)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn pregenerated_demand_is_summarised_and_replayed_the_same() {
+        let at = |h, m| Utc.with_ymd_and_hms(2023, 3, 1, h, m, 0).unwrap();
+        let demand = vec![
+            Demand((0.0, 0.0), (3000.0, 4000.0), at(7, 59), None),
+            Demand((10.5, 20.25), (1010.5, 20.25), at(8, 0), Some(at(9, 0))),
+            Demand((0.0, 0.0), (0.0, 2000.0), at(8, 30), None),
+        ];
+
+        let summary = DemandSummary::new(&demand, Crs::default());
+        assert_eq!(summary.requests, 3);
+        assert_eq!((summary.first, summary.last), (Some(at(7, 59)), Some(at(8, 30))));
+        assert_eq!(summary.hourly, vec![(at(7, 0), 1), (at(8, 0), 2)]);
+        assert_eq!(summary.busiest_hour(), Some((at(8, 0), 2)));
+        assert_eq!(summary.average_trip_km, (5.0 + 1.0 + 2.0) / 3.0);
+
+        let path = std::env::temp_dir().join(format!("odbrs-pregenerated-{}.csv", std::process::id()));
+        write(&path, &demand).unwrap();
+        let replayed = RequestLog::load(&RequestLogConfig { path: path.clone(), lat_lon: false }, Crs::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.requests(), demand.as_slice());
+    }
+}
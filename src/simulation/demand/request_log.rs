@@ -0,0 +1,179 @@
+//! Demand replayed from a log of real requests, e.g. booking data from a DRT service, rather than
+//! sampled. Chosen in the `[demand]` config:
+//!
+//! ```toml
+//! [demand]
+//! source = "request_log"
+//!
+//! [demand.request_log]
+//! path = "data/demand/bookings.csv"
+//! lat_lon = true # Coordinates are longitude (x) and latitude (y), otherwise they're map coords
+//! ```
+//!
+//! The log has a row for each request, times in UTC either as RFC 3339 or `2023-03-01 08:03:12`:
+//!
+//! ```text
+//! time,origin_x,origin_y,destination_x,destination_y
+//! 2023-03-01T08:03:12Z,429512.0,287301.5,431200.0,286950.0
+//! ```
+//!
+//! An optional `desired_arrival` column holds when the passenger wants to get there, e.g. the
+//! start of their shift, in the same format. Left empty for requests without one, and used to
+//! report how late passengers arrive.
+//!
+//! Each request is made during the tick its time falls in, so only requests between the run's start
+//! and end are replayed. The demand scale doesn't apply, every request in the log is made once.
+//! Without a `service_date` the run is on the day the log starts, and a warning is printed if the
+//! run's window has none of the log's requests in it.
+
+use std::{error::Error, path::PathBuf};
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::{onto_map, Crs};
+
+use super::{source::DemandSource, Demand};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RequestLogConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub lat_lon: bool, // Coordinates are WGS84 longitude and latitude, projected onto the map when loaded
+}
+
+/// One row of the log file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct LogRow {
+    pub time: String,
+    pub origin_x: f64,
+    pub origin_y: f64,
+    pub destination_x: f64,
+    pub destination_y: f64,
+    #[serde(default)]
+    pub desired_arrival: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RequestLog {
+    requests: Vec<Demand>, // In time order
+}
+
+impl RequestLog {
+    pub fn new(mut requests: Vec<Demand>) -> Self {
+        requests.sort_by_key(|request| request.2);
+        RequestLog { requests }
+    }
+
+    // The log in `config`, projected onto a map in `crs` if it's in latitude and longitude
+    pub fn load(config: &RequestLogConfig, crs: Crs) -> Result<Self, Box<dyn Error>> {
+        let proj = if config.lat_lon {
+            crs.from_wgs84()?
+        } else {
+            None
+        };
+        let project = |point: (f64, f64)| -> Result<(f32, f32), String> {
+            let (x, y) = onto_map(proj.as_ref(), point)?;
+            Ok((x as f32, y as f32))
+        };
+
+        let mut requests = Vec::new();
+        for (i, row) in csv::Reader::from_path(&config.path)?.deserialize::<LogRow>().enumerate() {
+            let row = row?;
+            let time = parse_time(&row.time).ok_or_else(|| format!("Request log row {} has an unreadable time '{}'", i + 1, row.time))?;
+            let arrival = match row.desired_arrival.as_deref().map(str::trim).filter(|arrival| !arrival.is_empty()) {
+                Some(arrival) => Some(parse_time(arrival).ok_or_else(|| format!("Request log row {} has an unreadable desired arrival '{}'", i + 1, arrival))?),
+                None => None,
+            };
+            requests.push(Demand(project((row.origin_x, row.origin_y))?, project((row.destination_x, row.destination_y))?, time, arrival));
+        }
+
+        let log = RequestLog::new(requests);
+        match (log.requests.first(), log.requests.last()) {
+            (Some(first), Some(last)) => println!("[Demand] Loaded {} requests from {} to {} from {:?}", log.requests.len(), first.2, last.2, config.path),
+            _ => println!("[Demand] The request log {:?} has no requests", config.path),
+        }
+        Ok(log)
+    }
+
+    // Every request in time order
+    pub fn requests(&self) -> &[Demand] {
+        &self.requests
+    }
+
+    // The days of the first and last requests, None for an empty log
+    pub fn days(&self) -> Option<(NaiveDate, NaiveDate)> {
+        Some((self.requests.first()?.2.date_naive(), self.requests.last()?.2.date_naive()))
+    }
+
+    // Requests made from `from` up to (not including) `to`
+    pub fn between(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> &[Demand] {
+        let start = self.requests.partition_point(|request| request.2 < *from);
+        let end = self.requests.partition_point(|request| request.2 < *to);
+        &self.requests[start..end.max(start)]
+    }
+
+    // Requests made during the hour `time` is in
+    fn in_hour(&self, time: &DateTime<Utc>) -> &[Demand] {
+        let start = *time - Duration::seconds(time.minute() as i64 * 60 + time.second() as i64) - Duration::nanoseconds(time.nanosecond() as i64);
+        self.between(&start, &(start + Duration::hours(1)))
+    }
+}
+
+impl DemandSource for RequestLog {
+    fn per_minute(&self, time: &DateTime<Utc>) -> f64 {
+        self.in_hour(time).len() as f64 / 60.0
+    }
+
+    // One of the requests made in the same hour, for anything wanting a typical trip
+    fn sample(&self, time: &DateTime<Utc>, _bounds: (f32, f32, f32, f32), rng: &mut StdRng) -> Option<((f32, f32), (f32, f32))> {
+        let hour = self.in_hour(time);
+        if hour.is_empty() {
+            return None;
+        }
+        let Demand(origin, destination, ..) = hour[rng.gen_range(0..hour.len())];
+        Some((origin, destination))
+    }
+
+    fn recorded(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Option<Vec<Demand>> {
+        Some(self.between(from, to).to_vec())
+    }
+}
+
+// RFC 3339, or a date and time with no zone taken to be UTC
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|time| Utc.from_utc_datetime(&time)))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").map(|time| Utc.from_utc_datetime(&time)))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_replayed_in_their_tick() {
+        let at = |h, m, s| Utc.with_ymd_and_hms(2023, 3, 1, h, m, s).unwrap();
+        let log = RequestLog::new(vec![
+            Demand((2.0, 2.0), (3.0, 3.0), at(8, 1, 30), None),
+            Demand((0.0, 0.0), (1.0, 1.0), at(8, 0, 0), Some(at(9, 0, 0))),
+            Demand((4.0, 4.0), (5.0, 5.0), at(9, 0, 0), None),
+        ]);
+
+        assert_eq!(log.recorded(&at(8, 0, 0), &at(8, 1, 0)).unwrap(), vec![Demand((0.0, 0.0), (1.0, 1.0), at(8, 0, 0), Some(at(9, 0, 0)))]);
+        assert_eq!(log.recorded(&at(8, 1, 0), &at(8, 2, 0)).unwrap().len(), 1);
+        assert_eq!(log.recorded(&at(8, 2, 0), &at(9, 0, 0)).unwrap(), vec![]);
+        assert_eq!(log.recorded(&at(9, 0, 0), &at(9, 0, 30)).unwrap().len(), 1);
+        assert_eq!(log.per_minute(&at(8, 45, 10)), 2.0 / 60.0);
+        assert_eq!(log.days(), Some((at(8, 0, 0).date_naive(), at(9, 0, 0).date_naive())));
+        assert_eq!(RequestLog::new(vec![]).days(), None);
+
+        assert_eq!(parse_time("2023-03-01T08:01:30Z"), Some(at(8, 1, 30)));
+        assert_eq!(parse_time("2023-03-01 09:00:00"), Some(at(9, 0, 0)));
+        assert_eq!(parse_time("8am"), None);
+    }
+}
@@ -0,0 +1,187 @@
+//! Where generated demand comes from. The generator asks its source how many trips start each
+//! minute and where each one goes, then applies the demand scale, policy and map bounds itself.
+//!
+//! Demand images are the default source, an origin-destination matrix of trips between zones
+//! can be used instead, see [`super::od_matrix`], or a log of real requests replayed as they
+//! happened, see [`super::request_log`].
+
+use std::{fmt::Debug, sync::Arc};
+
+use chrono::{DateTime, Timelike, Utc};
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::resource::load_image::{DemandResources, ImageData, ImageSelection};
+
+use super::{od_matrix::OdMatrix, pixel_to_map, request_log::RequestLog, Demand};
+
+pub trait DemandSource: Send + Sync + Debug {
+    // Trips requested each minute of the hour `time` is in, before the demand scale
+    fn per_minute(&self, time: &DateTime<Utc>) -> f64;
+
+    // Map coords one trip requested at `time` starts and ends at, None if there was nowhere to put it.
+    // `bounds` (left, right, bottom, top) are the map's
+    fn sample(&self, time: &DateTime<Utc>, bounds: (f32, f32, f32, f32), rng: &mut StdRng) -> Option<((f32, f32), (f32, f32))>;
+
+    // Requests made from `from` up to (not including) `to` for sources replaying real ones, which
+    // are used as they are instead of sampling. None for sources which only sample
+    fn recorded(&self, _from: &DateTime<Utc>, _to: &DateTime<Utc>) -> Option<Vec<Demand>> {
+        None
+    }
+
+    // Where trips starting at `time` are expected, summed over a `cells` x `cells` grid across the
+    // map: the weighted middle of each cell's demand and its weight. Empty for sources which can't say
+    fn forecast(&self, _time: &DateTime<Utc>, _bounds: (f32, f32, f32, f32), _cells: usize) -> Vec<((f32, f32), f64)> {
+        Vec::new()
+    }
+}
+
+/// Which source the `[demand]` config uses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DemandSourceKind {
+    #[default]
+    Image,
+    OdMatrix,
+    RequestLog,
+}
+
+/// Demand data loaded from the configured source, turned into the source itself by the generator
+#[derive(Debug)]
+pub enum LoadedDemand {
+    Images(DemandResources),
+    OdMatrix(OdMatrix),
+    RequestLog(RequestLog),
+}
+
+impl LoadedDemand {
+    pub fn into_source(self) -> Box<dyn DemandSource> {
+        match self {
+            LoadedDemand::Images(resources) => Box::new(ImageSource::new(resources)),
+            LoadedDemand::OdMatrix(matrix) => Box::new(matrix),
+            LoadedDemand::RequestLog(log) => Box::new(log),
+        }
+    }
+
+    // Whether the source can say where trips are expected, only the demand images can
+    pub fn forecasts(&self) -> bool {
+        matches!(self, LoadedDemand::Images(_))
+    }
+}
+
+/// Demand images, red weighting where trips start and blue where they end
+#[derive(Debug)]
+pub struct ImageSource {
+    resources: DemandResources,
+}
+
+impl ImageSource {
+    pub fn new(resources: DemandResources) -> Self {
+        ImageSource { resources }
+    }
+
+    // Selects the right image based on numerous factors
+    fn select_image(&self, time: &DateTime<Utc>, rng: &mut StdRng) -> Arc<Box<ImageData>> {
+        match self.resources.get_selection() {
+            ImageSelection::ConstantChoice(i) => {
+                self.resources.get_images().get(i).expect("Wrong key in selection").clone()
+            },
+            ImageSelection::RandomChoice => {
+                let i = rng.gen_range(0..self.resources.get_images().len() as u8);
+                self.resources.get_images().get(&i).expect("Couldn't randomise selection").clone()
+            },
+            ImageSelection::TimeBasedChoice(map) => {
+                let i = map.get(time.hour() as usize).expect("Couldn't get time based index");
+                self.resources.get_images().get(i).expect("Couldn't select based on time").clone()
+            }
+        }
+    }
+
+    // Every image `select_image` could pick at `time`, all of them when it picks at random
+    fn possible_images(&self, time: &DateTime<Utc>) -> Vec<&ImageData> {
+        let images = self.resources.get_images();
+        let chosen: Vec<&Arc<Box<ImageData>>> = match self.resources.get_selection() {
+            ImageSelection::RandomChoice => {
+                let mut keys: Vec<_> = images.keys().copied().collect();
+                keys.sort_unstable();
+                keys.iter().map(|key| &images[key]).collect()
+            }
+            ImageSelection::ConstantChoice(i) => images.get(i).into_iter().collect(),
+            ImageSelection::TimeBasedChoice(map) => map.get(time.hour() as usize).and_then(|i| images.get(i)).into_iter().collect(),
+        };
+        chosen.into_iter().map(|image| &***image).collect()
+    }
+}
+
+impl DemandSource for ImageSource {
+    fn per_minute(&self, time: &DateTime<Utc>) -> f64 {
+        self.resources.get_demand_levels().per_minute(time.time())
+    }
+
+    fn sample(&self, time: &DateTime<Utc>, bounds: (f32, f32, f32, f32), rng: &mut StdRng) -> Option<((f32, f32), (f32, f32))> {
+        let image = self.select_image(time, rng);
+
+        let (r_w, g_w, b_w) = image.get_max_weight();
+
+        // Walk the running total of each channel until its draw lands inside a pixel's weight. A
+        // channel with no weight anywhere never gets a pixel
+        let mut draws = [r_w, g_w, b_w].map(|weight| {
+            let draw = rng.gen_range(0..weight.max(1));
+            (weight > 0).then_some(draw)
+        });
+        let mut picked = [None; 3];
+
+        for (i, pix) in image.get_image().pixels().enumerate() {
+            for channel in 0..3 {
+                if let Some(draw) = draws[channel] {
+                    let weight = pix.0[channel] as u64;
+                    if draw < weight {
+                        picked[channel] = Some(i);
+                        draws[channel] = None;
+                    } else {
+                        draws[channel] = Some(draw - weight);
+                    }
+                }
+            }
+
+            if draws.iter().all(Option::is_none) {
+                break;
+            }
+        }
+        let [r_pix, _g_pix, b_pix] = picked;
+
+        let width = image.get_width() as usize;
+        let height = image.get_height() as usize;
+
+        // Somewhere inside the chosen pixel, on the map
+        let mut place = |pixel: Option<usize>| {
+            let jitter = (rng.gen_range(0.0..1.0_f32), rng.gen_range(0.0..1.0_f32));
+            pixel.map(|pixel| pixel_to_map(pixel, width, height, jitter, bounds))
+        };
+        let source = place(r_pix);
+        let dest = place(b_pix);
+
+        // No pixel means an image with no weight in that channel, rather than a point at the map's corner
+        Some((source?, dest?))
+    }
+
+    fn forecast(&self, time: &DateTime<Utc>, bounds: (f32, f32, f32, f32), cells: usize) -> Vec<((f32, f32), f64)> {
+        let cells = cells.max(1);
+        // Each cell's red weight, and that weight times the x and y of its pixels
+        let mut sums = vec![(0.0, 0.0, 0.0); cells * cells];
+        for image in self.possible_images(time) {
+            let (width, height) = (image.get_width() as usize, image.get_height() as usize);
+            for (i, pix) in image.get_image().pixels().enumerate().filter(|(_, pix)| pix.0[0] > 0) {
+                let weight = pix.0[0] as f64;
+                let cell = (i / width * cells / height) * cells + (i % width * cells / width);
+                let (x, y) = pixel_to_map(i, width, height, (0.5, 0.5), bounds);
+                let sum = &mut sums[cell];
+                *sum = (sum.0 + weight, sum.1 + weight * x as f64, sum.2 + weight * y as f64);
+            }
+        }
+        sums.into_iter()
+            .filter(|(weight, _, _)| *weight > 0.0)
+            .map(|(weight, x, y)| (((x / weight) as f32, (y / weight) as f32), weight))
+            .collect()
+    }
+}
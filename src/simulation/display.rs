@@ -0,0 +1,135 @@
+//! The map display is sent to the GUI in layers, so a big scenario doesn't send every stop and
+//! passenger each tick. Layers are only sent when they've changed since they were last sent, and
+//! the moving ones (vehicles, passengers, walks) at most so many times a second:
+//!
+//! ```toml
+//! [simulation.display]
+//! dynamic_hz = 10.0 # Most updates a second of the moving layers, 0 sends them every tick
+//! keyframe_seconds = 5.0 # Every layer is sent again this often, in case the GUI dropped an update
+//! ```
+//!
+//! Stops don't move, so they go once at the start of a run and again only if they change.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use eframe::epaint::Shape;
+use serde::Deserialize;
+
+// Ordered bottom to top, the GUI draws them in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DisplayLayer {
+    EdgeUsage, // Drawn by the GUI from the analytics, not sent by the simulation
+    DemandPreview, // Drawn by the GUI before the run starts, see demand/pregenerate.rs
+    Stops,
+    Passengers,
+    Walking,
+    Vehicles, // With the routes they're following
+    RouteComparison, // Drawn by the GUI for the selected vehicle, see gui/vehicle_panel.rs
+}
+
+impl DisplayLayer {
+    pub fn is_static(self) -> bool {
+        matches!(self, DisplayLayer::EdgeUsage | DisplayLayer::DemandPreview | DisplayLayer::Stops | DisplayLayer::RouteComparison)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct DisplayConfig {
+    #[serde(default = "default_dynamic_hz")]
+    pub dynamic_hz: f64,
+    #[serde(default = "default_keyframe_seconds")]
+    pub keyframe_seconds: f64,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig { dynamic_hz: default_dynamic_hz(), keyframe_seconds: default_keyframe_seconds() }
+    }
+}
+
+fn default_dynamic_hz() -> f64 {
+    10.0
+}
+
+fn default_keyframe_seconds() -> f64 {
+    5.0
+}
+
+/// What the GUI was last sent of each layer, and when
+#[derive(Debug, Default)]
+pub struct DisplayChannel {
+    config: DisplayConfig,
+    sent: HashMap<DisplayLayer, Vec<Shape>>,
+    last_dynamic: Option<Instant>,
+    last_keyframe: Option<Instant>,
+}
+
+impl DisplayChannel {
+    pub fn new(config: DisplayConfig) -> Self {
+        DisplayChannel { config, ..Default::default() }
+    }
+
+    // Forget what was sent so every layer goes again, e.g. for a new run
+    pub fn resend(&mut self) {
+        self.sent.clear();
+        self.last_dynamic = None;
+        self.last_keyframe = None;
+    }
+
+    // Whether it's been long enough since the moving layers were last sent to send them again
+    pub fn dynamic_due(&self, now: Instant) -> bool {
+        match self.last_dynamic {
+            Some(last) if self.config.dynamic_hz > 0.0 => now.duration_since(last).as_secs_f64() >= 1.0 / self.config.dynamic_hz,
+            _ => true,
+        }
+    }
+
+    // Which of `layers` to send, those which changed since they were last sent. Only give the
+    // moving layers when `dynamic_due`, and every layer each time so one emptied is sent empty
+    pub fn changed(&mut self, layers: Vec<(DisplayLayer, Vec<Shape>)>, now: Instant) -> Vec<(DisplayLayer, Vec<Shape>)> {
+        let keyframe = Duration::from_secs_f64(self.config.keyframe_seconds.max(0.0));
+        if self.last_keyframe.is_none_or(|last| now.duration_since(last) >= keyframe) {
+            self.sent.clear();
+            self.last_keyframe = Some(now);
+        }
+        if layers.iter().any(|(layer, _)| !layer.is_static()) {
+            self.last_dynamic = Some(now);
+        }
+
+        let mut changed = Vec::new();
+        for (layer, shapes) in layers {
+            if self.sent.get(&layer) != Some(&shapes) {
+                self.sent.insert(layer, shapes.clone());
+                changed.push((layer, shapes));
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use eframe::epaint::{pos2, Color32};
+
+    use super::*;
+
+    #[test]
+    fn layers_are_sent_when_they_change_and_not_too_often() {
+        let mut channel = DisplayChannel::new(DisplayConfig { dynamic_hz: 2.0, keyframe_seconds: 10.0 });
+        let dot = |x| Shape::circle_filled(pos2(x, 0.0), 1.0, Color32::WHITE);
+        let start = Instant::now();
+        let layers = |x| vec![(DisplayLayer::Stops, vec![dot(0.0)]), (DisplayLayer::Vehicles, vec![dot(x)])];
+
+        // Everything the first time, then only what's moved
+        assert!(channel.dynamic_due(start));
+        assert_eq!(channel.changed(layers(1.0), start).len(), 2);
+        let later = start + Duration::from_secs(1);
+        assert!(!channel.dynamic_due(start + Duration::from_millis(100)));
+        assert!(channel.dynamic_due(later));
+        assert_eq!(channel.changed(layers(2.0), later), vec![(DisplayLayer::Vehicles, vec![dot(2.0)])]);
+        assert!(channel.changed(layers(2.0), later + Duration::from_secs(1)).is_empty());
+
+        // Until a keyframe sends it all again
+        assert_eq!(channel.changed(layers(2.0), start + Duration::from_secs(10)).len(), 2);
+    }
+}
@@ -0,0 +1,140 @@
+//! Grouping DRT requests made from nearly the same place at nearly the same time, like a crowd
+//! leaving an event venue, into a bundle with one shared pick up. Off unless a radius is given:
+//!
+//! ```toml
+//! [simulation.bundling]
+//! radius = 50.0 # Metres between requests in the same bundle
+//! window_minutes = 5 # Minutes between the first request in a bundle and any other
+//! ```
+//!
+//! Everyone in a bundle walks to the first request's pick up node and is given to the same bus,
+//! which needs a seat free for each of them and room in its plan for each of their drop offs, or
+//! none of them go on it. Reassigning any one of them moves the whole bundle.
+
+use chrono::Duration;
+use serde::Deserialize;
+
+use crate::geometry::Crs;
+
+use super::bus::Passenger;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct BundlingConfig {
+    #[serde(default)]
+    pub radius: f64, // 0 turns bundling off
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: i64,
+}
+
+impl Default for BundlingConfig {
+    fn default() -> Self {
+        BundlingConfig { radius: 0.0, window_minutes: default_window_minutes() }
+    }
+}
+
+fn default_window_minutes() -> i64 {
+    5
+}
+
+impl BundlingConfig {
+    pub fn enabled(&self) -> bool {
+        self.radius > 0.0
+    }
+
+    // Could `other` join the bundle started by `lead`, measured in `crs`
+    pub fn together(&self, crs: Crs, lead: &Passenger, other: &Passenger) -> bool {
+        crs.distance(lead.source_pos, other.source_pos) <= self.radius && (other.timeframe - lead.timeframe).abs() <= Duration::minutes(self.window_minutes)
+    }
+}
+
+// Put passengers who can travel together into bundles, led by the earliest in the list. Their
+// pick up moves to the lead's node. Passengers already in a bundle stay in it, and a bundle still
+// in the list can take new members
+pub fn form_bundles<'a>(config: &BundlingConfig, crs: Crs, passengers: impl IntoIterator<Item = &'a mut Passenger>) {
+    let mut leads: Vec<&mut Passenger> = Vec::new();
+    for passenger in passengers {
+        match passenger.bundle {
+            Some(lead) if lead == passenger.id => leads.push(passenger),
+            Some(_) => {}
+            None => match leads.iter_mut().find(|lead| config.together(crs, lead, passenger)) {
+                Some(lead) => {
+                    lead.bundle = Some(lead.id);
+                    passenger.bundle = Some(lead.id);
+                    passenger.source_node = lead.source_node;
+                }
+                None => leads.push(passenger),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        graph::synthetic::grid,
+        simulation::{demand::Demand, dyn_controller::{bus::{Bus, DEFAULT_CAPACITY}, demand_to_passenger, waypoints::Waypoint, DynamicController}, rng::SimRng, router::{DemandRouter, Router}},
+    };
+
+    use super::*;
+
+    fn request(id: u32, pos: (f64, f64), node: u128, minutes: i64) -> Passenger {
+        let start = Utc::now();
+        Passenger { id, source_pos: pos, source_node: node, timeframe: start + Duration::minutes(minutes), ..Default::default() }
+    }
+
+    #[test]
+    fn close_requests_share_a_pick_up() {
+        let config = BundlingConfig { radius: 50.0, window_minutes: 5 };
+        let mut passengers = [
+            request(1, (0.0, 0.0), 10, 0),
+            request(2, (30.0, 40.0), 11, 2),
+            request(3, (0.0, 10.0), 12, 9), // Too late
+            request(4, (500.0, 0.0), 13, 1), // Too far
+            request(5, (500.0, 20.0), 14, 3),
+        ];
+        form_bundles(&config, Crs::default(), passengers.iter_mut());
+
+        let bundles: Vec<_> = passengers.iter().map(|p| (p.bundle, p.source_node)).collect();
+        assert_eq!(bundles, vec![(Some(1), 10), (Some(1), 10), (None, 12), (Some(4), 13), (Some(4), 13)]);
+    }
+
+    #[test]
+    fn bundles_only_go_on_a_bus_every_member_fits() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        // Two from the corner, one along the bottom row and one to the far corner
+        let bundle = |far_latest: Option<DateTime<Utc>>| {
+            let mut controller = DynamicController::default();
+            controller.set_router(router.clone());
+            controller.set_rng(SimRng::seeded(1));
+            controller.time = now;
+            controller.buses = vec![Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut StdRng::seed_from_u64(1))];
+            let mut near = demand_to_passenger(Demand((0.0, 0.0), (400.0, 0.0), now, None), router.as_ref(), 0);
+            let mut far = demand_to_passenger(Demand((0.0, 0.0), (400.0, 400.0), now, None), router.as_ref(), 1);
+            near.bundle = Some(near.id);
+            far.bundle = Some(near.id);
+            far.window.latest_dropoff = far_latest;
+            controller.demands.extend([near, far]);
+            controller.constructive(graph.clone());
+            controller
+        };
+
+        // Each member is dropped off where they're going
+        let controller = bundle(None);
+        assert!(controller.demands.is_empty());
+        let plan = &controller.buses[0].path_waypoints;
+        assert!(plan.contains(&Waypoint::Dropoff(4)) && plan.contains(&Waypoint::Dropoff(24)));
+        assert_eq!(controller.buses[0].assignment[&0].len(), 2);
+
+        // The far corner can't be reached in time, so neither goes on the bus
+        let controller = bundle(Some(now + Duration::seconds(5)));
+        assert_eq!(controller.demands.len(), 2);
+        assert!(controller.buses[0].assignment.values().all(Vec::is_empty));
+    }
+}
@@ -1,14 +1,14 @@
-use std::{collections::{VecDeque, HashMap}, sync::{Arc, mpsc::Sender}};
+use std::{collections::{BTreeMap, VecDeque, HashSet}, sync::Arc};
 
 use chrono::{DateTime, Utc};
 use eframe::epaint::{Shape, Stroke, Color32, pos2};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
 
-use crate::{graph::{Graph, route_finding}, simulation::{Agent, default_display}, analytics::{AnalyticsPackage, PassengerAnalyticsEvent, VehicleAnalyticsEvent}};
+use crate::{geometry::Crs, graph::{str_as_colour, Graph}, simulation::{Agent, AgentState, default_display, escalation::{Escalation, ServiceMode}, noise::Noise, population::Completed, walking::WalkingConfig, DEFAULT_TICK_SECONDS}, analytics::{AnalyticsPackage, PassengerAnalyticsEvent, VehicleAnalyticsEvent}, event_bus::Topic, units::{Meters, MetersPerSecond, Seconds}};
 
-use super::waypoints::{bus_waypoints, create_ordering, Waypoint, bus_waypoints_with_passenger};
+use super::{depots::{BusDepot, Duty}, holding::Holding, onboard_audit::OnboardAuditConfig, time_windows::TimeWindow, transfers::Transfer, waypoints::{bus_waypoints, create_ordering, optimise_dropoff_clusters, DirForest, Waypoint, bus_waypoints_with_trip}};
 
-const HUMAN_WALKING_SPEED: f64 = 1.4; // m/s
 
 pub enum Action {
     Wait, // Stay at this node for this tick
@@ -16,7 +16,7 @@ pub enum Action {
     Stop // Stop Moving forever(?)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CurrentElement {
     PreGenerated, // Haven't placed this agent yet
     Node(u128),
@@ -30,7 +30,7 @@ impl Default for CurrentElement {
 }
 
 /// Reflects the current status of the demand which represents an individual passenger
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Status {
     Generated, // This demand has just been generated
     TravelStart(u8), // This demand has started travelling towards the starting node -- countdown of ticks travelling
@@ -47,7 +47,7 @@ impl Default for Status {
 }
 
 /// Represents the passenger of a generated demand which is on the bus
-#[derive(Default, Debug, Clone, PartialEq)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Passenger {
     pub id: u32,
     pub source_pos: (f64, f64),
@@ -55,24 +55,39 @@ pub struct Passenger {
     pub dest_pos: (f64, f64),
     pub dest_node: u128,
     pub timeframe: DateTime<Utc>,
-    pub status: Status
+    pub status: Status,
+    pub escalations: Vec<Escalation>, // Times this passenger was moved between fixed route and DRT
+    pub assigned_at: Option<DateTime<Utc>>, // When a bus was first given this request
+    pub overdue: bool, // Reported by the onboard audit for riding too long
+    #[serde(default)]
+    pub transfer: Option<Transfer>, // Where and to which bus this passenger changes part way, if planned
+    #[serde(default)]
+    pub bundle: Option<u32>, // Id of the passenger leading the bundle this one shares a pick up with
+    #[serde(default)]
+    pub desired_arrival: Option<DateTime<Utc>>, // When they want to be at their destination, if they said
+    #[serde(default)]
+    pub window: TimeWindow, // When they can be picked up and dropped off, open unless time windows are set
 }
 
-pub fn send_analytics(analytics: &Option<Sender<AnalyticsPackage>>, event: AnalyticsPackage) {
-    if let Some(tx) = analytics.as_ref() {
-        // println!("[ANALYTICS] Sending analytics event!");
-        if let Err(err) = tx.send(event) {
-            panic!("[ANALYTICS] Unable to send analytics: {:?}", err);
-        } else {
-            // println!("[ANALYTICS] Sent analytics event!");
-        }
-    } else {
-        // println!("[ANALYTICS] No analytics channel found!");
+// Vehicle ids are agent ids, published in the DRT range (see `analytics::entity`)
+pub fn send_analytics(analytics: &Option<Topic<AnalyticsPackage>>, event: AnalyticsPackage) {
+    if let Some(topic) = analytics.as_ref() {
+        topic.publish(event.in_fleet(ServiceMode::Drt));
     }
 }
 
 impl Passenger {
-    pub fn update(&mut self, analytics: &Option<Sender<AnalyticsPackage>>) {
+    // Node the passenger gets off the bus they're on or waiting for, a meeting node if changing bus
+    pub fn leg_end(&self) -> u128 {
+        self.transfer.map(|transfer| transfer.node).unwrap_or(self.dest_node)
+    }
+
+    // What's archived of them once they've finished, in the tick at `time`
+    pub fn completed(&self, time: DateTime<Utc>) -> Completed {
+        Completed { id: self.id, mode: ServiceMode::Drt, origin: self.source_pos, destination: self.dest_pos, time }
+    }
+
+    pub fn update(&mut self, analytics: &Option<Topic<AnalyticsPackage>>) {
         // println!("{:?} Passenger update", self.id);
         match self.status {
             Status::Generated | Status::Expired => {}, // Passenger state necessitates nothing happening
@@ -102,38 +117,65 @@ impl Passenger {
         }
     }
 
-    pub fn set_on_bus(&mut self) {
-        self.status = Status::OnBus(Utc::now());
+    // Note the first time the request is given to a bus, reassignments by the LNS don't count
+    // Mark the passenger given a bus at `time`, the event to publish if it's their first
+    pub fn note_assigned(&mut self, time: DateTime<Utc>) -> Option<AnalyticsPackage> {
+        if self.assigned_at.is_some() {
+            return None;
+        }
+        self.assigned_at = Some(time);
+        let latency = (time - self.timeframe).num_minutes();
+        Some(AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Assigned { id: self.id, latency }))
+    }
+
+    pub fn requested(&self, analytics: &Option<Topic<AnalyticsPackage>>) {
+        send_analytics(analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: self.id, time: self.timeframe, origin: self.source_pos, destination: self.dest_pos, desired_arrival: self.desired_arrival }));
+    }
+
+    pub fn set_on_bus(&mut self, time: DateTime<Utc>) {
+        self.status = Status::OnBus(time);
     }
 
-    pub fn set_travel_start(&mut self, graph: Arc<Graph>) {
-        let dist = graph.get_nodelist().get(&self.source_node).expect("Node not found");
-        let dist = distance(dist.point, self.source_pos);
-        let ticks = (dist / 60.0 * HUMAN_WALKING_SPEED) as u8;
+    // Walking ticks are rounded down, a walk shorter than a tick takes none
+    pub fn set_travel_start(&mut self, graph: Arc<Graph>, tick: Seconds, walking: &WalkingConfig) {
+        let node = graph.get_nodelist().get(&self.source_node).expect("Node not found");
+        let ticks = (walking.seconds_between(graph.crs(), node.point, self.source_pos) / tick) as u8;
         self.status = Status::TravelStart(ticks);
     }
 
-    pub fn set_travel_end(&mut self, graph: Arc<Graph>) {
-        let dist = graph.get_nodelist().get(&self.dest_node).expect("Node not found");
-        let dist = distance(dist.point, self.dest_pos);
-        let ticks = (dist / 60.0 * HUMAN_WALKING_SPEED) as u8;
+    pub fn set_travel_end(&mut self, graph: Arc<Graph>, tick: Seconds, walking: &WalkingConfig) {
+        let node = graph.get_nodelist().get(&self.dest_node).expect("Node not found");
+        let ticks = (walking.seconds_between(graph.crs(), node.point, self.dest_pos) / tick) as u8;
         self.status = Status::TavelDest(ticks);
     }
 }
 
-#[derive(Default)]
+pub const DEFAULT_CAPACITY: u8 = 20;
+pub use crate::graph::speeds::DEFAULT_SPEED;
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Bus {
     
+    #[serde(skip)]
     pub graph: Arc<Graph>, // Reference to the graph this agent is operating on
 
     pub agent_id: usize, // ID of this agent
     pub max_capacity: u8, // Maximum capacity of the agent/bus
     pub rem_capacity: u8, // Remaining capacity of the agent/bus
+    pub speed: MetersPerSecond, // Speed the bus moves at on a road at the graph's default speed
+    pub cluster_radius: f64, // Drop-offs this close together (m) are visited in the shortest order, 0 keeps the greedy order
+    pub tick_seconds: Seconds, // Simulated time each move covers
+    #[serde(skip)]
+    pub walking: WalkingConfig, // How long passengers take to walk to and from the bus
     
     pub passengers: Vec<Passenger>, // List of passengers on the bus (current assignment/solution)
-    pub assignment: HashMap<u128, Vec<Passenger>>, // Future passengers to be added to the bus (future assignment/solution)
+    pub assignment: BTreeMap<u128, Vec<Passenger>>, // Future passengers to be added to the bus (future assignment/solution)
     
-    pub delivered_passengers: Vec<Passenger>, // List of passengers delivered to their destination
+    pub delivered_passengers: Vec<Passenger>, // Passengers dropped off and walking the rest of the way, archived by the controller once there
+    #[serde(default)]
+    pub transfers_in: BTreeMap<u32, (u128, u128)>, // Passengers (key) another bus is bringing to a meeting node, and their destination node
+    #[serde(default)]
+    pub handed_over: Vec<Passenger>, // Dropped at a meeting node this tick, for the controller to pass to their next bus
 
     pub path_waypoints: VecDeque<Waypoint>, // List of important nodes which should be in full path // TODO: look into a hashset or some ordering of waypoints
     pub path_full: VecDeque<u128>, // List of nodes to visit to complete the assignment
@@ -141,8 +183,24 @@ pub struct Bus {
     pub current_pos: (f64, f64), // Current position of the agent
     pub current_el: CurrentElement, // Current edge the agent is on
     pub next_node: u128, // Next node the agent is travelling to; the "locking node"
-
-    pub analytics: Option<Sender<AnalyticsPackage>>, // Sender to the analytics thread
+    pub idle_ticks: u32, // Ticks the bus has had nobody on board or assigned and nowhere to go
+    #[serde(default)]
+    pub dwell_remaining: Seconds, // Left letting passengers on and off, carried over when it's longer than the move
+    #[serde(default)]
+    pub waiting_at: Option<u128>, // Node the bus is held at until someone it picks up there has arrived
+    #[serde(default)]
+    pub repositioned: bool, // Sent towards forecast demand since it last picked anyone up
+    #[serde(default)]
+    pub depot: Option<BusDepot>, // Where the bus parks between shifts, None to run all day
+    #[serde(default)]
+    pub duty: Duty,
+    #[serde(default)]
+    pub vehicle_type: Option<String>, // Name of the vehicle type, if the fleet has them
+    #[serde(default)]
+    pub colour: Option<String>, // Colour the path is drawn in, alternating blues if not given
+
+    #[serde(skip)]
+    pub analytics: Option<Topic<AnalyticsPackage>>, // Topic the analytics thread subscribes to
 }
 
 const STROKES: [Stroke; 2] = [
@@ -167,6 +225,30 @@ impl Agent for Bus {
         self.next_node
     }
 
+    fn get_id(&self) -> u32 {
+        self.agent_id as u32
+    }
+
+    fn get_capacity(&self) -> usize {
+        self.max_capacity as usize
+    }
+
+    fn get_occupancy(&self) -> usize {
+        self.passengers.iter().filter(|p| matches!(p.status, Status::OnBus(_))).count()
+    }
+
+    fn get_state(&self) -> AgentState {
+        if self.duty == Duty::Parked {
+            AgentState::OutOfService
+        } else if !self.path_full.is_empty() || matches!(self.current_el, CurrentElement::Edge { .. }) {
+            AgentState::Moving
+        } else if self.passengers.is_empty() && self.assignment.values().all(|waiting| waiting.is_empty()) && self.transfers_in.is_empty() {
+            AgentState::Idle
+        } else {
+            AgentState::Stopped
+        }
+    }
+
     fn display(&self) -> eframe::epaint::Shape {
         let mut shapes = vec![];
         let base_shape = default_display(self);
@@ -187,7 +269,10 @@ impl Agent for Bus {
             }
             let node_data = self.graph.get_nodelist().get(node).expect("Node not found"); // TODO: panic here
             pos2(node_data.point.0 as _, node_data.point.1 as _)
-        }).collect(), STROKES[(self.agent_id % 2) as usize]); //Stroke::new(2.0, Color32::LIGHT_BLUE)
+        }).collect(), match &self.colour {
+            Some(colour) => Stroke::new(2.0, str_as_colour(colour)),
+            None => STROKES[self.agent_id % 2],
+        }); //Stroke::new(2.0, Color32::LIGHT_BLUE)
 
         shapes.append(&mut waypoints);
         shapes.push(path);
@@ -203,21 +288,29 @@ impl Agent for Bus {
 /// 
 impl Bus {
     
-    fn handle_node(&mut self, node: u128) -> Action {
+    fn handle_node(&mut self, node: u128, time: DateTime<Utc>) -> Action {
         
         // Add waiting passengers to the bus
         let passengers_at_this_node = self.assignment.get_mut(&node);
+        let mut bundled: BTreeMap<u32, u32> = BTreeMap::new(); // Bundles boarding here and how many of each got on
         match passengers_at_this_node {
             Some(passengers) => {
 
                 let mut i = 0;
                 while i < passengers.len() {
-                    if self.rem_capacity > 0 {
+                    if self.rem_capacity > 0 && passengers[i].window.ready(time) {
                         let mut passenger = passengers.remove(i);
+                        if let Some(bundle) = passenger.bundle {
+                            *bundled.entry(bundle).or_insert(0) += 1;
+                        }
                         // Passenger has been picked up by the bus
-                        passenger.set_on_bus();
+                        passenger.set_on_bus(time);
                         
                         send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerPickup { id: self.agent_id as u32, passenger_id: passenger.id }));
+                        send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: passenger.id, vehicle: self.agent_id as u32, time }));
+                        if std::mem::take(&mut self.repositioned) {
+                            send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PickupAfterRepositioning { id: self.agent_id as u32, passenger_id: passenger.id }));
+                        }
                         
                         self.passengers.push(passenger);
                         self.rem_capacity -= 1;
@@ -237,6 +330,16 @@ impl Bus {
             None => {}
         };
 
+        for (bundle, boarded) in bundled {
+            send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::BundleBoarded {
+                bundle,
+                vehicle: self.agent_id as u32,
+                boarded,
+                pos: self.graph.get_nodelist()[&node].point,
+                time,
+            }));
+        }
+
         // loop passengers on bus and remove if they have reached their destination
         // for passenger in self.passengers.iter_mut() {
         //     if passenger.dest_node == node {
@@ -253,12 +356,31 @@ impl Bus {
         let mut i = 0;
         while i < self.passengers.len() {
             let passenger = &self.passengers[i];
-            if passenger.dest_node == node {
+            if passenger.leg_end() == node && passenger.transfer.is_some() {
+                // Getting off to change bus, they wait here for the next one
+                let mut passenger = self.passengers.remove(i);
+                let transfer = passenger.transfer.unwrap();
+                self.report_missed_windows(&passenger, time);
+                send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerDropoff { id: self.agent_id as u32, passenger_id: passenger.id }));
+                send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Transferred {
+                    id: passenger.id,
+                    from: self.agent_id as u32,
+                    to: transfer.vehicle as u32,
+                    pos: self.graph.get_nodelist()[&node].point,
+                    time,
+                }));
+                passenger.status = Status::Waiting(0);
+                self.rem_capacity += 1;
+                self.handed_over.push(passenger);
+            } else if passenger.transfer.is_none() && passenger.dest_node == node {
+                // Passengers changing bus only get off at the meeting node, even driving through their destination
                 let mut passenger = self.passengers.remove(i);
+                self.report_missed_windows(&passenger, time);
 
                 send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerDropoff { id: self.agent_id as u32, passenger_id: passenger.id }));
+                send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { id: passenger.id, time }));
                 
-                passenger.set_travel_end(self.graph.clone());
+                passenger.set_travel_end(self.graph.clone(), self.tick_seconds, &self.walking);
                 self.rem_capacity += 1;
 
                 getting_off.push_back(passenger);
@@ -267,33 +389,190 @@ impl Bus {
             }
         }
         self.delivered_passengers.extend(getting_off.into_iter());
+
+        // Someone it picks up here can't get on yet, so it waits for them with the pick up still to do
+        if self.waits_at(node, time) {
+            return Action::Wait;
+        }
+
+        // Waypoints the bus has reached are done with, the plan goes on from the next
+        while self.path_waypoints.get(1).is_some_and(|waypoint| waypoint.node() == node) {
+            self.path_waypoints.pop_front();
+        }
         
-        // TODO: check if there are timeline constraints which means the bus needs to wait at this node
         Action::Continue
     }
 
+    // Whether the bus waits at `node` for someone it picks up there: changing onto it from another
+    // bus and not dropped off yet, or there before they're ready to be picked up
+    fn waits_at(&self, node: u128, time: DateTime<Utc>) -> bool {
+        self.transfers_in.values().any(|(meeting, _)| *meeting == node)
+            || (self.rem_capacity > 0 && self.assignment.get(&node).is_some_and(|waiting| waiting.iter().any(|p| !p.window.ready(time))))
+    }
+
+    // Tell the analytics about any time window missed by a passenger getting off now
+    fn report_missed_windows(&self, passenger: &Passenger, time: DateTime<Utc>) {
+        let Status::OnBus(boarded) = passenger.status else { return };
+        let nodes = self.graph.get_nodelist();
+        let direct_seconds = Meters::between(self.graph.crs(), nodes[&passenger.source_node].point, nodes[&passenger.dest_node].point) / self.speed;
+        for violation in passenger.window.violations(boarded, time, direct_seconds, passenger.transfer.is_none()) {
+            send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::WindowMissed { id: passenger.id, violation, time }));
+        }
+    }
+
+    // Nobody on board, nobody waiting for this bus and no path left to drive
+    pub fn is_idle(&self) -> bool {
+        self.is_empty() && self.path_full.is_empty()
+    }
+
+    // Count another tick spent idle and send the bus to a holding point once it's waited long enough
+    pub fn hold_or_reposition(&mut self, holding: &Holding) {
+        let Some(idle_minutes) = self.tick_idle() else {
+            return;
+        };
+        if let Some(target) = holding.reposition_target(idle_minutes, self.next_node, self.current_pos) {
+            self.drive_empty_to(target);
+        }
+    }
+
+    // Count another tick of the bus being idle, returning the minutes it's been idle for. None,
+    // and the count reset, if it has something to do
+    pub fn tick_idle(&mut self) -> Option<u32> {
+        if !self.is_idle() {
+            self.idle_ticks = 0;
+            return None;
+        }
+        self.idle_ticks += 1;
+        Some((self.tick_seconds * self.idle_ticks as f64).minutes() as u32)
+    }
+
+    pub fn drive_empty_to(&mut self, target: u128) {
+        self.path_waypoints = VecDeque::from([Waypoint::Passthrough(self.next_node), Waypoint::Passthrough(target)]);
+        self.create_path();
+    }
+
+    // Nobody on board or waiting for this bus, though it may be driving somewhere
+    pub fn is_empty(&self) -> bool {
+        self.passengers.is_empty() && self.assignment.values().all(|passengers| passengers.is_empty()) && self.transfers_in.is_empty()
+    }
+
+    // Node the bus's plan ends at, where it is if it has nowhere to go
+    pub fn plan_end(&self) -> u128 {
+        match self.path_full.back() {
+            Some(_) => self.path_waypoints.back().map_or(self.next_node, Waypoint::node),
+            None => self.next_node,
+        }
+    }
+
+    // Report passengers who've been on board longer than the audit allows, once each, along with
+    // the bus's plan. Forced drop-offs end their trip where the bus is
+    pub fn audit_onboard(&mut self, audit: &OnboardAuditConfig, time: DateTime<Utc>) -> Vec<String> {
+        let mut reports = Vec::new();
+        let mut i = 0;
+        while i < self.passengers.len() {
+            let passenger = &mut self.passengers[i];
+            let boarded = match passenger.status {
+                Status::OnBus(boarded) if !passenger.overdue && audit.overdue(boarded, time) => boarded,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            passenger.overdue = true;
+            reports.push(format!(
+                "Passenger {} on DRT bus {} for {} minutes going to node {}. Bus heading to node {}, waypoints {:?}, {} nodes of path left{}",
+                passenger.id,
+                self.agent_id,
+                (time - boarded).num_minutes(),
+                passenger.dest_node,
+                self.next_node,
+                self.path_waypoints,
+                self.path_full.len(),
+                if audit.force_dropoff { ", dropped off where it is" } else { "" }
+            ));
+
+            if audit.force_dropoff {
+                let mut passenger = self.passengers.remove(i);
+                send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerDropoff { id: self.agent_id as u32, passenger_id: passenger.id }));
+                send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { id: passenger.id, time }));
+                passenger.status = Status::Expired;
+                self.rem_capacity += 1;
+                self.delivered_passengers.push(passenger);
+            } else {
+                i += 1;
+            }
+        }
+        reports
+    }
+
     pub fn can_assign_more(&self) -> bool {
-        self.rem_capacity > 0
+        self.on_shift() && self.rem_capacity > 0
+    }
+
+    // Whether the bus can be given new passengers
+    pub fn on_shift(&self) -> bool {
+        self.duty == Duty::OnShift
+    }
+
+    // Start or end the bus's shift. Once its shift is over and it has nobody left to carry it
+    // drives back to its depot and parks there
+    pub fn update_duty(&mut self, time: DateTime<Utc>) {
+        let Some(depot) = self.depot else {
+            return;
+        };
+        let on_shift = depot.on_shift(time.time());
+        match self.duty {
+            Duty::Parked if on_shift => {
+                self.duty = Duty::OnShift;
+                send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::LeftDepot { id: self.agent_id as u32, depot: depot.depot, time }));
+            }
+            Duty::Returning if on_shift => self.duty = Duty::OnShift,
+            Duty::OnShift if !on_shift => self.duty = Duty::Returning,
+            Duty::Returning if self.is_idle() => {
+                if self.graph.distance(self.current_pos, depot.point) < 1.0 {
+                    self.duty = Duty::Parked;
+                    self.idle_ticks = 0;
+                    send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::Parked { id: self.agent_id as u32, depot: depot.depot, time }));
+                } else {
+                    // Deadheading, empty, back to the depot
+                    self.drive_empty_to(depot.node);
+                }
+            }
+            _ => {}
+        }
     }
 
-    // TODO: abstract out random initialisation to another function?
-    pub fn new(graph: Arc<Graph>, max_capacity: u8, id: usize, analytics: Option<Sender<AnalyticsPackage>>) -> Self {
+    // Change the capacity of the bus. Passengers already on board stay on even if they no longer fit
+    pub fn set_capacity(&mut self, max_capacity: u8) {
+        let on_board = self.passengers.len().min(u8::MAX as usize) as u8;
+        self.max_capacity = max_capacity;
+        self.rem_capacity = max_capacity.saturating_sub(on_board);
+    }
+
+    // Bus starting at a random node
+    pub fn new(graph: Arc<Graph>, max_capacity: u8, id: usize, analytics: Option<Topic<AnalyticsPackage>>, rng: &mut StdRng) -> Self {
+        let random_index = rng.gen_range(0..=graph.get_node_ids().len() - 1);
+        let random_node = graph.get_node_ids()[random_index];
+        Bus::at_node(graph, random_node, max_capacity, id, analytics, rng)
+    }
 
-        let random_index = rand::thread_rng().gen_range(0..=graph.get_nodelist().len() - 1);
-        let random_node = graph.get_nodelist().keys().nth(random_index).unwrap();
-        let adjacency = graph.get_adjacency().get(random_node).unwrap();
-        let random_edge_i = rand::thread_rng().gen_range(0..=adjacency.len() - 1);
+    // Bus starting at `node`, heading along a random edge out of it
+    pub fn at_node(graph: Arc<Graph>, node: u128, max_capacity: u8, id: usize, analytics: Option<Topic<AnalyticsPackage>>, rng: &mut StdRng) -> Self {
+        let adjacency = graph.get_adjacency().get(&node).unwrap();
+        let random_edge_i = rng.gen_range(0..=adjacency.len() - 1);
         let edge = adjacency.get(random_edge_i).unwrap();
         let edge_data = &graph.get_edgelist()[edge];
-        let agent_pos = graph.get_nodelist()[random_node].point;
-        let locking_node = if edge_data.start_id == *random_node { edge_data.end_id } else { edge_data.start_id };
+        let agent_pos = graph.get_nodelist()[&node].point;
+        let locking_node = if edge_data.start_id == node { edge_data.end_id } else { edge_data.start_id };
         
         Bus {
             graph: graph.clone(),
             agent_id: id,
             max_capacity,
             rem_capacity: max_capacity,
-            current_el: CurrentElement::Edge { edge: *edge, prev_node: *random_node },
+            speed: DEFAULT_SPEED,
+            tick_seconds: Seconds(DEFAULT_TICK_SECONDS as f64),
+            current_el: CurrentElement::Edge { edge: *edge, prev_node: node },
             current_pos: agent_pos,
             next_node: locking_node,
             analytics,
@@ -311,44 +590,83 @@ impl Bus {
         // Update waypoinys and paths?
     
     // TODO: needs working tests -- this panics sometimes? not been able to reproduce it.
-    pub fn what_if_bus_had_passenger(&self, passenger: &Passenger) -> f64 {
-        let mut waypoints = bus_waypoints_with_passenger(self, passenger);
-        let path = create_ordering(self.next_node, &mut waypoints, self.graph.clone());
-        let mut path_len = 0.0;
+    // Length of the route if the bus also took someone from `source` to `dest` (straight line between waypoints)
+    pub fn what_if_bus_had_trip(&self, source: u128, dest: u128) -> f64 {
+        self.route_len(&mut bus_waypoints_with_trip(self, source, dest))
+    }
 
-        for i in 0..path.len() - 1 { // just comparing straight line dist between waypoints not a full routefinding
-            let u = path[i];
-            let v = path[i + 1];
-            let point_u = self.graph.get_nodelist().get(&u.node()).unwrap().point;
-            let point_v = self.graph.get_nodelist().get(&v.node()).unwrap().point;
-            path_len += distance(point_u, point_v);
-        }
+    // Length of the route as it's planned now
+    pub fn planned_route_len(&self) -> f64 {
+        self.route_len(&mut bus_waypoints(self))
+    }
 
-        path_len
+    fn route_len(&self, waypoints: &mut DirForest) -> f64 {
+        self.path_len(&self.order_waypoints(waypoints))
+    }
+
+    // How fast the bus gets about at `time` once congestion's taken into account, for timing its plans
+    pub fn road_speed(&self, time: DateTime<Utc>) -> MetersPerSecond {
+        self.speed * self.graph.congestion(time.time())
+    }
+
+    // Just comparing straight line dist between waypoints not a full routefinding
+    pub(super) fn path_len<'a>(&self, path: impl IntoIterator<Item = &'a Waypoint>) -> f64 {
+        let points: Vec<(f64, f64)> = path.into_iter().map(|waypoint| self.graph.get_nodelist()[&waypoint.node()].point).collect();
+        points.windows(2).map(|pair| self.graph.distance(pair[0], pair[1])).sum()
+    }
+
+    // Road between two neighbouring nodes of the path
+    fn edge_joining(&self, from: u128, to: u128) -> Option<u128> {
+        self.graph.get_adjacency().get(&from)?.iter().copied().find(|edge| {
+            let edge = &self.graph.get_edgelist()[edge];
+            edge.start_id == to || edge.end_id == to
+        })
+    }
+
+    // Roads the bus plans to drive after the one it's on, in order
+    pub fn planned_edges(&self) -> Vec<u128> {
+        let nodes: Vec<u128> = std::iter::once(self.next_node).chain(self.path_full.iter().copied()).collect();
+        nodes.windows(2).filter_map(|pair| self.edge_joining(pair[0], pair[1])).collect()
     }
 
     // Adds the passenger to the assignment by placing them in their source node waiting list
     pub fn add_passenger_to_assignment(&mut self, mut passenger: Passenger) {
         // passenger should now be making its way to the bus stop! to get picked up
-        passenger.set_travel_start(self.graph.clone());
+        passenger.set_travel_start(self.graph.clone(), self.tick_seconds, &self.walking);
         self.assignment.entry(passenger.source_node).or_insert_with(|| Vec::new()).push(passenger);
     }
 
     // Adds a passenger into the solution and updates pathing as appropriate
     // TODO: Assigned passengers need to move towards their pick-up station
-    pub fn constructive(&mut self, passenger: Passenger) {
+    pub fn constructive(&mut self, passenger: Passenger, time: DateTime<Utc>) {
         self.add_passenger_to_assignment(passenger);
 
         // println!("Constructive");
         // println!("\t[LNS/Agent] Constructive: Bus {} now has {} passengers", self.agent_id, self.passengers.len());
         // println!("\tAssignment: {:?}", self.assignment);
 
-        // Uses GreedyBFS to find an ordering of the waypoints for the bus
-        let path = create_ordering(
-            self.next_node, 
-            &mut bus_waypoints(self), 
-            self.graph.clone()
-        );
+        self.replan(time);
+    }
+
+    // Another bus will drop this passenger at `meeting` for this one to take on to `dest`
+    pub fn expect_transfer(&mut self, passenger_id: u32, meeting: u128, dest: u128, time: DateTime<Utc>) {
+        self.transfers_in.insert(passenger_id, (meeting, dest));
+        self.replan(time);
+    }
+
+    // Order the waypoints after the locking point again and rebuild the full path, after the
+    // passengers changed. The plan up to the locking point is kept as it is
+    pub fn replan(&mut self, time: DateTime<Utc>) {
+        let plan = self.kept_plan();
+        let locked = self.locked(&plan, time);
+        let mut path: VecDeque<Waypoint> = plan.into_iter().take(locked + 1).collect();
+
+        // Uses GreedyBFS to find an ordering of the rest of the waypoints for the bus
+        let mut waypoints = bus_waypoints(self);
+        path.iter().for_each(|waypoint| waypoints.visit_waypoint(*waypoint));
+        let mut rest = create_ordering(path.back().map_or(self.next_node, Waypoint::node), &mut waypoints, self.graph.clone());
+        optimise_dropoff_clusters(&mut rest, self.cluster_radius, &self.graph);
+        path.extend(rest.into_iter().skip(1));
         self.path_waypoints = path;
         
         // println!("Waypoint Path: {:?}", self.path_waypoints);
@@ -357,6 +675,13 @@ impl Bus {
         self.create_path();
     }
 
+    // Greedy ordering of the waypoints from the locking node, with clustered drop-offs tidied up
+    pub(super) fn order_waypoints(&self, waypoints: &mut DirForest) -> VecDeque<Waypoint> {
+        let mut path = create_ordering(self.next_node, waypoints, self.graph.clone());
+        optimise_dropoff_clusters(&mut path, self.cluster_radius, &self.graph);
+        path
+    }
+
     // Helper to get the length of the waypoint path (straight line between waypoints)
     pub fn get_waypoint_path_len(&self) -> f64 {
         let mut path_len = 0.0;
@@ -365,18 +690,27 @@ impl Bus {
             let v = self.path_waypoints[i + 1].node();
             let point_u = self.graph.get_nodelist().get(&u).unwrap().point;
             let point_v = self.graph.get_nodelist().get(&v).unwrap().point;
-            path_len += distance(point_u, point_v);
+            path_len += self.graph.distance(point_u, point_v);
         }
         path_len
     }
 
     // Destructive function to basically remove some passengers from the bus assignment
-    pub fn destructive(&mut self) -> Vec<Passenger> {
+    // Only passengers picked up after the locking point can be taken off the bus
+    pub fn destructive(&mut self, rng: &mut StdRng, time: DateTime<Utc>) -> Vec<Passenger> {
+        let plan = self.current_plan();
+        // A bus held at a stop is about to pick up whoever it was waiting for there
+        let locked: HashSet<u128> = plan.iter().take(self.locking_point(time) + 1).filter_map(|waypoint| match waypoint {
+            Waypoint::Pickup(node) => Some(*node),
+            _ => None,
+        }).chain(self.waiting_at).collect();
+
         // loop throught assignent and remove 50% which aren't currently passengers
         let mut removed = Vec::with_capacity(self.assignment.len() / 2);
-        for (_node, assignment) in self.assignment.iter_mut() {
-            let mut rng = rand::thread_rng();
-
+        for (node, assignment) in self.assignment.iter_mut() {
+            if locked.contains(node) {
+                continue;
+            }
             let mut i = 0;
             while i < assignment.len() {
                 let passenger = &assignment[i];
@@ -389,7 +723,28 @@ impl Bus {
             }
 
         }
+
+        // Bundles are reassigned together, so take the rest of any bundle which lost someone
+        let split: Vec<u32> = removed.iter().filter_map(|p: &Passenger| p.bundle).collect();
+        if !split.is_empty() {
+            for assignment in self.assignment.values_mut() {
+                let mut i = 0;
+                while i < assignment.len() {
+                    if assignment[i].bundle.is_some_and(|bundle| split.contains(&bundle)) {
+                        removed.push(assignment.remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
         // println!("\t[LNS/Agent] Destructive removed {:?}", removed.len());
+
+        // The rest of the plan keeps its order without their waypoints
+        if !removed.is_empty() {
+            self.path_waypoints = self.current_plan();
+            self.create_path();
+        }
         removed
     }
 
@@ -402,7 +757,7 @@ impl Bus {
             
             match path.back() {
                 Some(node) => { // The last node in the path is the source for the next subroute
-                    let subroute = route_finding::find_route(&self.graph, *node, waypoint.node());
+                    let subroute = self.graph.shortest_path_fast(*node, waypoint.node());
                     // println!("\tsubroute from {:?} to {:?}: {:?}", node, waypoint.node(), subroute);
                     path.extend(subroute.into_iter().rev().skip(1));
                 },
@@ -429,33 +784,30 @@ impl Bus {
         self.delivered_passengers.iter_mut().for_each(|p| p.update(&self.analytics)); 
     }
 
-    /// for every stop s in route b
-    ///     if arrival time at stop s < current time
-    ///         locking point = s
-    ///     else 
-    ///         break
-    /// if locking point is not last scheduled stop in route then
-    ///     locking point += 1
-    /// if locking point is not last scheduled stop in route -1 then
-    ///     for ever stop s between lockin gpoint and last scheduled stop in route - 1 do
-    ///     if someone gets on bus at stop s then
-    ///         bool breaknow = true
-    ///         if departure time at stop s - stop time - walking time < current time then
-    ///             breaknow = false;
-    ///             lockpoint += 1
-    ///         if breaknow then 
-    ///             break out of loop
-    /// return the lockpoint (index of the route)
-
     // Actual movement function which moves the bus one step along the computed path
     // TODO: Maybe run the "handle arrival at node" function somewhere in here..
     // TODO: handle whether the bus is at the final destination and can let the passengers off??
-    pub fn move_self(&mut self) {
+    pub fn move_self(&mut self, noise: &mut Noise, time: DateTime<Utc>) {
 
         self.update_passengers();
 
-        // No need to move agent if no path to follow
-        if self.path_full.len() == 0 {
+        // Held at a stop until whoever it's waiting for has got there, then on with the plan as
+        // it's been changed since
+        if let Some(node) = self.waiting_at {
+            if let Action::Wait = self.handle_node(node, time) {
+                return;
+            }
+            self.waiting_at = None;
+            self.replan(time);
+        }
+
+        // No need to move agent if no path to follow, though one part way along the last road of
+        // its path still drives to the end of it
+        let mid_road = match self.current_el {
+            CurrentElement::Edge { prev_node, .. } => self.current_pos != self.graph.get_nodelist()[&prev_node].point,
+            _ => false,
+        };
+        if self.path_full.len() == 0 && !mid_road {
             return; // No path to follow
         }
 
@@ -464,8 +816,12 @@ impl Bus {
         // println!("Next node: {:?}", self.next_node);
         // println!("Path: {:?}", self.path_full);
 
-        let mut move_distance = 804.672; //10.0; 804.672 = 13.4112 * 60.0 (13.4112 m/s * 60s)
-        while move_distance > 0.0 {
+        // How far the bus would go on a road at the default speed, scaled by each edge's speed as it moves along it,
+        // in whatever time is left after finishing the last stop
+        let held = self.dwell_remaining.min(self.tick_seconds);
+        self.dwell_remaining -= held;
+        let mut move_distance = self.speed * (self.tick_seconds - held) * noise.travel_factor();
+        while move_distance > Meters::ZERO {
             // Id of the edge we are currently on, or need to move along
             let moving_edge_id = match self.current_el {
                 CurrentElement::PreGenerated => unreachable!("The agent is trying to move before it has been generated"),
@@ -487,6 +843,7 @@ impl Bus {
                 }
             };
             let moving_edge_data = &self.graph.get_edgelist()[&moving_edge_id];
+            let factor = self.graph.speed_factor(moving_edge_id, time.time());
             
             let next_node = self.next_node;
             let next_node_data = &self.graph.get_nodelist()[&next_node];
@@ -504,18 +861,17 @@ impl Bus {
                 let segment_start = line[i];
                 let segment_end = line[i+1];
 
-                if point_on_linesegment(self.current_pos, &segment_start, &segment_end) {
+                if point_on_linesegment(self.graph.crs(), self.current_pos, &segment_start, &segment_end) {
                     // println!("On line segment {}/{}", i, line.len());
-                    let distance_remaining = distance(self.current_pos, segment_end);
+                    let distance_remaining = Meters::between(self.graph.crs(), self.current_pos, segment_end);
                     // println!("Distance remaining: {}", distance_remaining);
                     
-                    if move_distance > distance_remaining { // if move distance is > distance to end of line segment, move to end of line segment. Will then consider the next segment.
+                    if move_distance * factor > distance_remaining { // if move distance is > distance to end of line segment, move to end of line segment. Will then consider the next segment.
                         self.current_pos = segment_end;
-                        move_distance -= distance_remaining;
+                        move_distance -= distance_remaining / factor;
                         has_moved = true;
                     } else {
-                        let dir = normalise((segment_end.0 - segment_start.0, segment_end.1 - segment_start.1));
-                        self.current_pos = (self.current_pos.0 + dir.0 * move_distance, self.current_pos.1 + dir.1 * move_distance);
+                        self.current_pos = self.graph.crs().towards(self.current_pos, segment_end, move_distance * factor);
                         send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::MovementTick { id: self.agent_id as u32, pos: self.current_pos }));
                         return;
                     }
@@ -530,7 +886,7 @@ impl Bus {
             }
             
             // If we've moved along the segments and still have distance to traverse, we're moving past the next node.
-            if has_moved && move_distance > 0.0 {
+            if has_moved && move_distance > Meters::ZERO {
                 // We have moved the full distance to move along the current edge and are now at "self.next_node"
                 // Move to the next edge
  
@@ -539,23 +895,37 @@ impl Bus {
                 self.next_node = match self.path_full.pop_front() {
                     Some(next_node) => {
                         // Find edge which connects current node to the next node in the path
-                        let edge_id = self.graph.get_adjacency()[&current_node].iter().find(|e| {
-                            let edge = &self.graph.get_edgelist()[*e];
-                            edge.start_id == next_node || edge.end_id == next_node
-                        }).unwrap();
+                        let edge_id = self.edge_joining(current_node, next_node).unwrap();
 
-                        self.current_el = CurrentElement::Edge { edge: *edge_id, prev_node: current_node };
+                        self.current_el = CurrentElement::Edge { edge: edge_id, prev_node: current_node };
+                        send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::EdgeEntered { id: self.agent_id as u32, edge: edge_id, mode: ServiceMode::Drt }));
                         next_node
                     },
                     None => {
-                        // We have reached the end of the path
+                        // We have reached the end of the path, where there's still someone to
+                        // drop off or pick up
 
                         self.current_el = CurrentElement::Node(current_node);
+                        if let Action::Wait = self.handle_node(current_node, time) {
+                            self.waiting_at = Some(current_node);
+                        }
                         return;
                     }
                 };
                 
-                self.handle_node(current_node);
+                let before = (self.passengers.len(), self.delivered_passengers.len());
+                if let Action::Wait = self.handle_node(current_node, time) {
+                    self.waiting_at = Some(current_node);
+                    return;
+                }
+
+                // Time spent letting passengers on and off isn't spent moving, what's left of the move
+                // after it is driven and any more is spent at the start of the next
+                if before != (self.passengers.len(), self.delivered_passengers.len()) {
+                    let dwell = Seconds(noise.dwell_seconds()) * self.speed;
+                    self.dwell_remaining = (dwell - move_distance).max(Meters::ZERO) / self.speed;
+                    move_distance = (move_distance - dwell).max(Meters::ZERO);
+                }
 
                 // println!("Moving to next node!!");
                 // println!("New Current node: {:?}", current_node);
@@ -584,10 +954,10 @@ impl Bus {
 }
 
 // Based on collision detection for a point and a line. Point is on a line if the distance to each point is equal to lenght
-fn point_on_linesegment(pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -> bool {
-    let d1 = distance(pos, *start);
-    let d2 = distance(pos, *end);
-    let line_len = distance(*start, *end);
+fn point_on_linesegment(crs: Crs, pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -> bool {
+    let d1 = crs.distance(pos, *start);
+    let d2 = crs.distance(pos, *end);
+    let line_len = crs.distance(*start, *end);
     let buffer = 0.1;
 
     if d1 + d2 >= line_len - buffer && d1 + d2 <= line_len + buffer {
@@ -596,14 +966,3 @@ fn point_on_linesegment(pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -
         false
     }
 }
-
-fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
-    let xs = (a.0 - b.0).abs();
-    let ys = (a.1 - b.1).abs();
-    xs.hypot(ys)
-}
-
-fn normalise(a: (f64, f64)) -> (f64, f64) {
-    let mag = ((a.0).powi(2) + (a.1).powi(2)).sqrt();
-    (a.0 / mag, a.1 / mag)
-}
\ No newline at end of file
@@ -0,0 +1,139 @@
+//! Where DRT buses start and end their shifts. Without depots buses start at random nodes and
+//! run all day, with them the fleet is spread over the depots in turn, and the buses of each depot
+//! over its shifts in turn:
+//!
+//! ```toml
+//! [[simulation.depots]]
+//! point = [1200.0, 3400.0] # Map coordinates, snapped to the closest node
+//! shifts = [{ start = "06:00:00", end = "14:00:00" }, { start = "14:00:00", end = "23:30:00" }]
+//! ```
+//!
+//! A bus waits at its depot until its shift starts. At the end of its shift it takes on nobody
+//! new, finishes with the passengers on board or already given to it, then drives back to the
+//! depot empty and parks. Buses leaving and parking are sent to the analytics, which writes how
+//! many buses each depot held through the run.
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+use crate::graph::Graph;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DepotConfig {
+    pub point: (f64, f64),
+    #[serde(default)]
+    pub shifts: Vec<Shift>, // No shifts to run all day
+}
+
+/// The time of day a bus is in service from, to `end` the next day if it's before `start`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shift {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Shift {
+    pub fn covers(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Where a bus parks and when it works
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BusDepot {
+    pub depot: u32, // Index of the depot in the config
+    pub node: u128,
+    pub point: (f64, f64),
+    pub shift: Option<Shift>,
+}
+
+impl BusDepot {
+    pub fn on_shift(&self, time: NaiveTime) -> bool {
+        self.shift.is_none_or(|shift| shift.covers(time))
+    }
+}
+
+/// What a bus with a depot is doing about its shift
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Duty {
+    #[default]
+    OnShift,
+    Returning, // Shift over, finishing with its passengers or driving back to the depot
+    Parked,
+}
+
+/// Depot config with the points snapped onto the road graph
+#[derive(Debug, Clone, Default)]
+pub struct Depots {
+    depots: Vec<(u128, (f64, f64), Vec<Shift>)>,
+}
+
+impl Depots {
+    pub fn new(config: &[DepotConfig], graph: &Graph) -> Self {
+        let depots = config
+            .iter()
+            .filter_map(|depot| {
+                let node = graph.nearest_node(depot.point)?;
+                Some((node, graph.get_nodelist()[&node].point, depot.shifts.clone()))
+            })
+            .collect();
+        Depots { depots }
+    }
+
+    // Depot and shift of the `n`th bus spawned, counting from 0
+    pub fn for_bus(&self, n: usize) -> Option<BusDepot> {
+        if self.depots.is_empty() {
+            return None;
+        }
+        let depot = n % self.depots.len();
+        let (node, point, shifts) = &self.depots[depot];
+        let shift = (!shifts.is_empty()).then(|| shifts[(n / self.depots.len()) % shifts.len()]);
+        Some(BusDepot { depot: depot as u32, node: *node, point: *point, shift })
+    }
+}
+
+// Buses parked at a depot after each of its `events` (time order, true for a bus parking and
+// false for one leaving), and the most it held
+pub fn occupancy<T: Copy>(events: &[(T, bool)]) -> (Vec<(T, u32)>, u32) {
+    let mut parked = 0u32;
+    let mut peak = 0;
+    let counts = events
+        .iter()
+        .map(|(time, parking)| {
+            parked = if *parking { parked + 1 } else { parked.saturating_sub(1) };
+            peak = peak.max(parked);
+            (*time, parked)
+        })
+        .collect();
+    (counts, peak)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buses_are_spread_over_depots_and_shifts() {
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let early = Shift { start: time(6), end: time(14) };
+        let night = Shift { start: time(22), end: time(4) };
+        let depots = Depots { depots: vec![(1, (0.0, 0.0), vec![early, night]), (2, (100.0, 0.0), vec![])] };
+
+        let buses: Vec<_> = (0..4).map(|n| depots.for_bus(n).unwrap()).collect();
+        assert_eq!(buses.iter().map(|bus| bus.node).collect::<Vec<_>>(), vec![1, 2, 1, 2]);
+        assert_eq!((buses[0].shift, buses[2].shift, buses[1].shift), (Some(early), Some(night), None));
+        assert_eq!(Depots::default().for_bus(0), None);
+
+        assert!(early.covers(time(6)) && !early.covers(time(14)));
+        assert!(night.covers(time(23)) && night.covers(time(3)) && !night.covers(time(12)));
+        assert!(buses[1].on_shift(time(3)));
+
+        let (counts, peak) = occupancy(&[(1, true), (2, true), (3, false), (4, true)]);
+        assert_eq!(counts, vec![(1, 1), (2, 2), (3, 1), (4, 2)]);
+        assert_eq!(peak, 2);
+    }
+}
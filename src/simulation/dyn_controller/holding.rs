@@ -0,0 +1,71 @@
+//! What an empty DRT bus does once it has nobody on board or waiting for it: hold where it
+//! dropped off its last passenger, or head to the nearest designated holding point.
+//!
+//! Holding keeps buses near where demand just was and saves empty mileage, repositioning spreads
+//! the fleet out. With `hold_minutes = 0` buses reposition straight away, with no holding points
+//! they wait at their last drop-off until given new passengers.
+
+use serde::Deserialize;
+
+use crate::{geometry::Crs, graph::Graph};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HoldingConfig {
+    #[serde(default)]
+    pub hold_minutes: u32, // Minutes an empty bus waits at its last drop-off before repositioning
+    #[serde(default)]
+    pub points: Vec<(f64, f64)>, // Holding points in map coordinates, snapped to the closest node
+}
+
+/// Holding config with the points snapped onto the road graph
+#[derive(Debug, Clone, Default)]
+pub struct Holding {
+    hold_minutes: u32,
+    nodes: Vec<(u128, (f64, f64))>,
+    crs: Crs,
+}
+
+impl Holding {
+    pub fn new(config: &HoldingConfig, graph: &Graph) -> Self {
+        let nodes = config
+            .points
+            .iter()
+            .filter_map(|point| graph.nearest_node(*point))
+            .map(|node| (node, graph.get_nodelist()[&node].point))
+            .collect();
+        Holding { hold_minutes: config.hold_minutes, nodes, crs: graph.crs() }
+    }
+
+    // Whether a bus idle for `idle_minutes` still waits where it is
+    pub fn holding(&self, idle_minutes: u32) -> bool {
+        idle_minutes <= self.hold_minutes
+    }
+
+    // Holding point a bus idle for `idle_minutes` at `node` should drive to, None to stay where it is
+    pub fn reposition_target(&self, idle_minutes: u32, node: u128, position: (f64, f64)) -> Option<u128> {
+        if self.holding(idle_minutes) {
+            return None;
+        }
+        let (target, _) = self.nodes.iter().min_by(|(_, a), (_, b)| {
+            let dist = |p: &(f64, f64)| self.crs.distance(*p, position);
+            dist(a).total_cmp(&dist(b))
+        })?;
+        (*target != node).then_some(*target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn holds_then_goes_to_the_closest_point() {
+        let holding = Holding { hold_minutes: 5, nodes: vec![(1, (0.0, 0.0)), (2, (100.0, 0.0))], crs: Crs::default() };
+
+        assert_eq!(holding.reposition_target(5, 7, (90.0, 0.0)), None);
+        assert_eq!(holding.reposition_target(6, 7, (90.0, 0.0)), Some(2));
+        // Already at the closest holding point
+        assert_eq!(holding.reposition_target(6, 2, (100.0, 0.0)), None);
+        assert_eq!(Holding::default().reposition_target(60, 7, (0.0, 0.0)), None);
+    }
+}
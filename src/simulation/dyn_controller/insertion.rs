@@ -0,0 +1,331 @@
+//! Fitting a new request into a bus's plan. The pick up and drop off go in as two new waypoints
+//! after the locking point, with the rest of the plan kept in its order so passengers already
+//! assigned aren't moved about by every new request. The locking point is the last waypoint of the
+//! plan which can't change any more: the next node the bus is driving to, anything it gets to by
+//! now, then any pick ups straight after whose passengers must already be walking there to make it.
+//! Nothing up to it is reordered or taken off the bus by the search:
+//!
+//! - every position for the pick up, and every later position for the drop off, is tried
+//! - an insertion is feasible if the bus never has more on board than it has seats, and it misses
+//!   no time window the plan without the request keeps
+//! - the insertion criterion is what the configured objective (see objective.rs) makes of the
+//!   request's own ride and wait and how much longer the whole plan gets, in seconds at the bus's
+//!   speed along straight lines between waypoints. By default the ride plus the longer plan
+//!
+//! The controller gives each request to the bus with the cheapest feasible insertion.
+
+use std::collections::{HashSet, VecDeque};
+
+use chrono::{DateTime, Utc};
+
+use crate::units::{Meters, Seconds};
+
+use super::{bus::{Bus, Passenger, Status}, objective::{Costs, Objective}, time_windows::missed_windows, waypoints::{bus_waypoints, Waypoint}};
+
+/// Where a request would go in a bus's plan, and what it would cost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Insertion {
+    pub pickup: usize, // Position of the new pick up in the plan
+    pub dropoff: usize, // Position of the new drop off, once the pick up is in
+    pub cost: f64, // The objective's cost of the request's ride and wait and what's added to the plan
+    pub ride: Meters, // The request's own ride, straight between the waypoints
+}
+
+impl Bus {
+    // The plan from the locking node on, without waypoints already reached or nobody needs any
+    // more. Ordered again from scratch if it's lost a waypoint someone needs
+    pub fn current_plan(&self) -> VecDeque<Waypoint> {
+        let plan = self.kept_plan();
+        match self.riders(&plan) {
+            Some(_) => plan,
+            None => self.order_waypoints(&mut bus_waypoints(self)),
+        }
+    }
+
+    // The waypoints of the plan still needed, in the order they were planned. The same waypoint
+    // twice in a row, left by taking out whatever was between, is only kept once
+    pub(super) fn kept_plan(&self) -> VecDeque<Waypoint> {
+        let mut plan = VecDeque::from([Waypoint::Passthrough(self.next_node)]);
+        for waypoint in self.path_waypoints.iter().skip(1).filter(|waypoint| self.needs(waypoint)) {
+            if plan.back() != Some(waypoint) {
+                plan.push_back(*waypoint);
+            }
+        }
+        plan
+    }
+
+    // Position in `current_plan` of the locking point at `current_time`:
+    //
+    // for every stop s in route b
+    //     if arrival time at stop s < current time
+    //         locking point = s
+    //     else 
+    //         break
+    // if locking point is not last scheduled stop in route then
+    //     locking point += 1
+    // if locking point is not last scheduled stop in route -1 then
+    //     for ever stop s between lockin gpoint and last scheduled stop in route - 1 do
+    //     if someone gets on bus at stop s then
+    //         bool breaknow = true
+    //         if departure time at stop s - stop time - walking time < current time then
+    //             breaknow = false;
+    //             lockpoint += 1
+    //         if breaknow then 
+    //             break out of loop
+    // return the lockpoint (index of the route)
+    pub fn locking_point(&self, current_time: DateTime<Utc>) -> usize {
+        self.locked(&self.current_plan(), current_time)
+    }
+
+    pub(super) fn locked(&self, plan: &VecDeque<Waypoint>, time: DateTime<Utc>) -> usize {
+        let arrivals = self.estimated_arrivals(plan, time);
+
+        // The bus is already driving to the first waypoint, then anything it's reached by now
+        let mut lock = 0;
+        while lock + 1 < plan.len() && arrivals[lock + 1] <= time {
+            lock += 1;
+        }
+
+        // Then pick ups someone getting on must have set off walking to already
+        while let Some(Waypoint::Pickup(node)) = plan.get(lock + 1) {
+            let stop = self.graph.get_nodelist()[node].point;
+            let set_off = self.waiting().filter(|p| p.source_node == *node).any(|p| {
+                let walk = self.walking.seconds_between(self.graph.crs(), stop, p.source_pos);
+                arrivals[lock + 1] - walk.duration() <= time
+            });
+            if !set_off {
+                break;
+            }
+            lock += 1;
+        }
+        lock
+    }
+
+    // When the bus reaches each waypoint of `path` from where it is at `now`, in straight lines at its speed
+    pub(super) fn estimated_arrivals(&self, path: &VecDeque<Waypoint>, now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let mut arrivals = Vec::with_capacity(path.len());
+        let (mut position, mut seconds) = (self.current_pos, Seconds::ZERO);
+        let speed = self.road_speed(now);
+        for waypoint in path {
+            let next = self.graph.get_nodelist()[&waypoint.node()].point;
+            seconds += Meters::between(self.graph.crs(), position, next) / speed;
+            arrivals.push(now + seconds.duration());
+            position = next;
+        }
+        arrivals
+    }
+
+    // Cheapest feasible place in the plan for `passenger`, who takes `seats` seats from their
+    // pick up to their drop off (more than one for a bundle), priced by `objective`
+    pub fn best_insertion(&self, passenger: &Passenger, seats: usize, time: DateTime<Utc>, objective: &dyn Objective) -> Option<Insertion> {
+        let plan = self.current_plan();
+        let riders = self.riders(&plan)?;
+        let plan_len = self.path_len(&plan);
+        let windows = !passenger.window.is_open() || self.passengers.iter().chain(self.assignment.values().flatten()).any(|p| !p.window.is_open());
+        let missed_already = if windows { missed_windows(self, &plan, None, time) } else { HashSet::new() };
+
+        let speed = self.road_speed(time);
+        let mut best: Option<Insertion> = None;
+        for pickup in self.locked(&plan, time) + 1..=plan.len() {
+            for dropoff in pickup + 1..=plan.len() + 1 {
+                let mut candidate = plan.clone();
+                candidate.insert(pickup, Waypoint::Pickup(passenger.source_node));
+                candidate.insert(dropoff, Waypoint::Dropoff(passenger.leg_end()));
+
+                let added = Meters(self.path_len(&candidate) - plan_len);
+                let ride = Meters(self.path_len(candidate.range(pickup..=dropoff)));
+                let cost = objective.cost(&Costs {
+                    ride: ride / speed,
+                    wait: Seconds::from_duration(self.estimated_arrivals(&candidate, time)[pickup] - time),
+                    distance: added,
+                    driving: added / speed,
+                });
+                if best.is_some_and(|best| cost >= best.cost) {
+                    continue;
+                }
+
+                // Everyone else's positions move along for the two new waypoints
+                let shift = |position: usize| {
+                    let position = position + (position >= pickup) as usize;
+                    position + (position >= dropoff) as usize
+                };
+                let over_capacity = (0..candidate.len()).any(|k| {
+                    let on_board = riders.iter().filter(|(on, off)| shift(*on) <= k && k < shift(*off)).count();
+                    let new = if pickup <= k && k < dropoff { seats } else { 0 };
+                    on_board + new > self.max_capacity as usize
+                });
+                if over_capacity {
+                    continue;
+                }
+                if windows && !missed_windows(self, &candidate, Some(passenger), time).is_subset(&missed_already) {
+                    continue;
+                }
+                best = Some(Insertion { pickup, dropoff, cost, ride });
+            }
+        }
+        best
+    }
+
+    // Put `passenger` into the plan where `insertion` says, which must have come from
+    // `best_insertion` on this bus with nothing changed since
+    pub fn insert(&mut self, passenger: Passenger, insertion: Insertion) {
+        let mut plan = self.current_plan();
+        plan.insert(insertion.pickup, Waypoint::Pickup(passenger.source_node));
+        plan.insert(insertion.dropoff, Waypoint::Dropoff(passenger.leg_end()));
+        self.add_passenger_to_assignment(passenger);
+        self.path_waypoints = plan;
+        self.create_path();
+    }
+
+    // Put a passenger another bus drops at `meeting` into the plan where `insertion` says, from
+    // `best_insertion` for their ride on from there to `dest`
+    pub fn insert_transfer(&mut self, passenger_id: u32, meeting: u128, dest: u128, insertion: Insertion) {
+        let mut plan = self.current_plan();
+        plan.insert(insertion.pickup, Waypoint::Pickup(meeting));
+        plan.insert(insertion.dropoff, Waypoint::Dropoff(dest));
+        self.transfers_in.insert(passenger_id, (meeting, dest));
+        self.path_waypoints = plan;
+        self.create_path();
+    }
+
+    // Is anyone on, waiting for or changing onto the bus still relying on this waypoint
+    fn needs(&self, waypoint: &Waypoint) -> bool {
+        match *waypoint {
+            Waypoint::Pickup(node) => self.waiting().any(|p| p.source_node == node) || self.transfers_in.values().any(|(meeting, _)| *meeting == node),
+            Waypoint::Dropoff(node) => {
+                self.passengers.iter().chain(self.waiting()).any(|p| p.leg_end() == node) || self.transfers_in.values().any(|(_, dest)| *dest == node)
+            }
+            Waypoint::Passthrough(_) => false,
+        }
+    }
+
+    // What `objective` makes of the rest of the plan: the rides and waits still to come of everyone
+    // on, waiting for or changing onto the bus, and the driving left to do
+    pub fn plan_cost(&self, objective: &dyn Objective, now: DateTime<Utc>) -> f64 {
+        let plan = self.current_plan();
+        let arrivals = self.estimated_arrivals(&plan, now);
+        let until = |position: usize| Seconds::from_duration(arrivals[position] - now);
+        let mut costs = Costs::default();
+        for (k, (on, off)) in self.riders(&plan).unwrap_or_default().into_iter().enumerate() {
+            // Passengers already on board come first and aren't waiting any more
+            if k < self.passengers.len() {
+                costs.ride += until(off);
+            } else {
+                costs.ride += until(off) - until(on);
+                costs.wait += until(on);
+            }
+        }
+        if let Some(first) = plan.front() {
+            costs.distance = Meters::between(self.graph.crs(), self.current_pos, self.graph.get_nodelist()[&first.node()].point) + Meters(self.path_len(&plan));
+        }
+        costs.driving = costs.distance / self.road_speed(now);
+        objective.cost(&costs)
+    }
+
+    // Positions everyone on, waiting for or changing onto the bus gets on and off at in `plan`,
+    // None if the plan leaves anyone out. Passengers already on board got on at the start
+    fn riders(&self, plan: &VecDeque<Waypoint>) -> Option<Vec<(usize, usize)>> {
+        let position = |waypoint: Waypoint, from: usize| plan.iter().skip(from).position(|w| *w == waypoint).map(|i| i + from);
+        let trip = |source: u128, dest: u128| {
+            let on = position(Waypoint::Pickup(source), 1)?;
+            Some((on, position(Waypoint::Dropoff(dest), on)?))
+        };
+
+        let mut riders = Vec::new();
+        for passenger in &self.passengers {
+            riders.push((0, position(Waypoint::Dropoff(passenger.leg_end()), 1)?));
+        }
+        for passenger in self.waiting() {
+            riders.push(trip(passenger.source_node, passenger.leg_end())?);
+        }
+        for (meeting, dest) in self.transfers_in.values() {
+            riders.push(trip(*meeting, *dest)?);
+        }
+        Some(riders)
+    }
+
+    fn waiting(&self) -> impl Iterator<Item = &Passenger> {
+        self.assignment.values().flatten().filter(|p| matches!(p.status, Status::Waiting(_) | Status::TravelStart(_)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+
+    use crate::{graph::synthetic::grid, simulation::dyn_controller::{bus::CurrentElement, objective::RideTime}};
+
+    use super::*;
+
+    #[test]
+    fn requests_fit_into_the_plan_without_overfilling_the_bus() {
+        // 5 x 5 junctions 100m apart, node ids counting along each row
+        let graph = Arc::new(grid(5, 100.0));
+        let mut bus = Bus::new(graph.clone(), 2, 1, None, &mut rand::rngs::StdRng::seed_from_u64(1));
+        bus.current_el = CurrentElement::Node(0);
+        bus.current_pos = (0.0, 0.0);
+        bus.next_node = 0;
+        let passenger = |id, source_node, dest_node| Passenger {
+            id,
+            source_node,
+            source_pos: graph.get_nodelist()[&source_node].point,
+            dest_node,
+            dest_pos: graph.get_nodelist()[&dest_node].point,
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        // Along the bottom row, the second request riding inside the first
+        let first = passenger(1, 2, 4);
+        let insertion = bus.best_insertion(&first, 1, now, &RideTime).unwrap();
+        bus.insert(first, insertion);
+        let second = passenger(2, 1, 3);
+        let insertion = bus.best_insertion(&second, 1, now, &RideTime).unwrap();
+        assert_eq!((insertion.pickup, insertion.dropoff), (1, 3));
+        assert_eq!(insertion.cost, (Meters(200.0) / bus.speed).0);
+        bus.insert(second, insertion);
+        let plan: Vec<Waypoint> = bus.current_plan().into_iter().collect();
+        assert_eq!(plan, vec![Waypoint::Passthrough(0), Waypoint::Pickup(1), Waypoint::Pickup(2), Waypoint::Dropoff(3), Waypoint::Dropoff(4)]);
+
+        // One seat is taken until the end, so two more only fit once everyone's off
+        let third = passenger(3, 2, 3);
+        assert_eq!(bus.best_insertion(&third, 2, now, &RideTime).unwrap().pickup, 5);
+        // and a bundle bigger than the bus never fits
+        assert!(bus.best_insertion(&third, 3, now, &RideTime).is_none());
+    }
+
+    #[test]
+    fn nothing_up_to_the_locking_point_is_changed() {
+        let graph = Arc::new(grid(5, 100.0));
+        let mut bus = Bus::new(graph.clone(), 4, 1, None, &mut rand::rngs::StdRng::seed_from_u64(1));
+        bus.current_el = CurrentElement::Node(0);
+        bus.current_pos = (0.0, 0.0);
+        bus.next_node = 0;
+        let now = Utc::now();
+
+        // The first is a long walk from their stop so has set off already, the second is at theirs
+        let far = Passenger { id: 1, source_node: 1, source_pos: (100.0, 300.0), dest_node: 4, dest_pos: (400.0, 0.0), ..Default::default() };
+        let near = Passenger { id: 2, source_node: 3, source_pos: (300.0, 0.0), dest_node: 4, dest_pos: (400.0, 0.0), ..Default::default() };
+        for passenger in [far, near] {
+            let insertion = bus.best_insertion(&passenger, 1, now, &RideTime).unwrap();
+            bus.insert(passenger, insertion);
+        }
+        assert_eq!(bus.current_plan()[1], Waypoint::Pickup(1));
+        assert_eq!(bus.locking_point(now), 1);
+
+        // A new pick up on the way can't go in before the locked one
+        let between = Passenger { id: 3, source_node: 2, source_pos: (200.0, 0.0), dest_node: 4, dest_pos: (400.0, 0.0), ..Default::default() };
+        assert_eq!(bus.best_insertion(&between, 1, now, &RideTime).unwrap().pickup, 2);
+
+        // and the locked passenger is never taken off the bus
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+        for _ in 0..10 {
+            bus.destructive(&mut rng, now);
+        }
+        assert_eq!(bus.assignment[&1].len(), 1);
+        assert!(bus.assignment.get(&3).is_none_or(Vec::is_empty));
+        assert_eq!(bus.current_plan(), VecDeque::from([Waypoint::Passthrough(0), Waypoint::Pickup(1), Waypoint::Dropoff(4)]));
+    }
+}
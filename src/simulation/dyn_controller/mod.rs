@@ -1,17 +1,37 @@
-use std::{collections::VecDeque, sync::{Arc, mpsc::Sender}};
+use std::{collections::{BTreeSet, HashMap, VecDeque}, sync::Arc};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 
-use crate::{graph::{route_finding, transform::convert_point, Graph}, simulation::dyn_controller::bus::Status, analytics::AnalyticsPackage};
+use crate::{graph::{transform::convert_point, Graph}, simulation::dyn_controller::bus::{send_analytics, Status}, analytics::{AnalyticsPackage, PassengerAnalyticsEvent, SimulationAnalyticsEvent, UnservedReason, VehicleAnalyticsEvent}, event_bus::Topic, units::{Meters, MetersPerSecond, Seconds}};
 
-use self::bus::{Bus, Passenger};
+use self::{bundling::{form_bundles, BundlingConfig}, bus::{Bus, Passenger, DEFAULT_CAPACITY, DEFAULT_SPEED}, depots::{DepotConfig, Depots, Duty}, holding::{Holding, HoldingConfig}, insertion::Insertion, objective::{Costs, Objective, RideTime}, onboard_audit::OnboardAuditConfig, rejection::RejectionConfig, repositioning::{Repositioning, RepositioningConfig}, time_windows::TimeWindowConfig, transfers::{Transfer, TransferConfig}, vehicles::VehicleType};
 
 use super::{
     demand::{Demand, DemandGenerator},
-    Controller,
+    escalation::{has_used, ServiceMode},
+    noise::Noise,
+    population::{Completed, PassengerSnapshot, PassengerState},
+    replay::{Frame, RecordedPassenger, RecordedStatus, RecordedVehicle},
+    rng::SimRng,
+    router::DemandRouter,
+    walking::WalkingConfig,
+    walking_overlay::{sampled, WalkingLeg},
+    Controller, DEFAULT_TICK_SECONDS,
 };
 
+pub mod bundling;
 pub mod bus;
+pub mod depots;
+pub mod holding;
+pub mod insertion;
+pub mod objective;
+pub mod onboard_audit;
+pub mod rejection;
+pub mod repositioning;
+pub mod time_windows;
+pub mod transfers;
+pub mod vehicles;
 pub mod waypoints;
 
 #[derive(Default)]
@@ -20,8 +40,56 @@ pub struct DynamicController {
     pid: u32, 
     buses: Vec<Bus>,
     demands: VecDeque<Passenger>,
-    analytics: Option<Sender<AnalyticsPackage>>,
+    analytics: Option<Topic<AnalyticsPackage>>,
     demand_scale: f64,
+    router: Option<Arc<dyn DemandRouter>>,
+    capacity: Option<u8>, // Capacity for buses, overriding the default
+    speed: Option<MetersPerSecond>, // Speed for buses, overriding the default
+    tick_seconds: Option<Seconds>, // Simulated time per tick, overriding the default
+    noise: Noise,
+    rng: SimRng,
+    holding: Holding, // What empty buses do while waiting for new passengers
+    repositioning: Repositioning, // Where empty buses go once they've held, if sent towards forecast demand
+    depots: Depots, // Where buses start and end their shifts
+    spawned: usize, // Buses spawned so far, to spread them over the depots and vehicle types
+    vehicle_types: Vec<VehicleType>, // Kinds of bus in the fleet, all the default bus if empty
+    cluster_radius: f64, // Radius (m) of drop-off clusters the buses reorder
+    time: DateTime<Utc>, // Simulation time of the current step
+    onboard_audit: OnboardAuditConfig, // Catches passengers stuck on a bus
+    walking: WalkingConfig, // How long passengers take to walk to and from the buses
+    transfers: TransferConfig, // Whether passengers can change bus part way
+    bundling: BundlingConfig, // Which requests share a pick up
+    time_windows: TimeWindowConfig, // Limits on when new passengers are picked up and dropped off
+    objective: Option<Box<dyn Objective>>, // What assignments minimise, ride time if not set
+    rejection: RejectionConfig, // When requests no bus can serve well are turned down
+    completed: Vec<Completed>, // Passengers who have reached their destination
+    held: Vec<AnalyticsPackage>, // Published by the repair in progress, sent once the search keeps it
+}
+
+/// The parts of the controller which change during a run, saved in simulation snapshots
+#[derive(Serialize, Deserialize)]
+pub struct DynamicState {
+    id: usize,
+    pid: u32,
+    buses: Vec<Bus>,
+    demands: VecDeque<Passenger>,
+    capacity: Option<u8>,
+    speed: Option<MetersPerSecond>,
+    rng_seed: u64,
+    noise_seed: u64,
+    #[serde(default)]
+    spawned: usize,
+    #[serde(default)]
+    completed: Vec<Completed>,
+}
+
+/// A change to the dynamic fleet which can be scheduled during a run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum FleetParameter {
+    Capacity(u8),
+    Speed(f64),
+    FleetSize(usize),
 }
 
 impl DynamicController {
@@ -30,13 +98,367 @@ impl DynamicController {
         self.demand_scale = scale;
     }
 
-    pub fn set_analytics(&mut self, tx: Option<Sender<AnalyticsPackage>>) {
+    pub fn set_analytics(&mut self, tx: Option<Topic<AnalyticsPackage>>) {
         println!("[ANALYTICS] Set analytics channel to {:?}", tx.is_some());
         self.analytics = tx;
     }
 
+    pub fn set_router(&mut self, router: Arc<dyn DemandRouter>) {
+        self.router = Some(router);
+    }
+
+    pub fn set_noise(&mut self, noise: Noise) {
+        self.noise = noise;
+    }
+
+    pub fn set_rng(&mut self, rng: SimRng) {
+        self.rng = rng;
+    }
+
+    // Copy out the state for a snapshot. The random generators are reseeded so this run and one
+    // resumed from the snapshot carry on with the same draws
+    pub fn save_state(&mut self) -> DynamicState {
+        DynamicState {
+            id: self.id,
+            pid: self.pid,
+            buses: self.buses.clone(),
+            demands: self.demands.clone(),
+            capacity: self.capacity,
+            speed: self.speed,
+            rng_seed: self.rng.checkpoint(),
+            noise_seed: self.noise.checkpoint(),
+            spawned: self.spawned,
+            completed: self.completed.clone(),
+        }
+    }
+
+    // Replace the fleet and requests with a snapshot's, reattaching the graph and analytics
+    pub fn restore_state(&mut self, state: DynamicState, graph: Arc<Graph>) {
+        self.id = state.id;
+        self.pid = state.pid;
+        self.buses = state.buses;
+        let tick_seconds = self.tick_seconds();
+        for bus in self.buses.iter_mut() {
+            bus.graph = graph.clone();
+            bus.analytics = self.analytics.clone();
+            bus.tick_seconds = tick_seconds;
+            bus.walking = self.walking;
+        }
+        self.demands = state.demands;
+        self.capacity = state.capacity;
+        self.speed = state.speed;
+        self.rng = SimRng::seeded(state.rng_seed);
+        self.noise.reseed(state.noise_seed);
+        self.spawned = state.spawned;
+        self.completed = state.completed;
+    }
+
+    pub fn set_onboard_audit(&mut self, audit: OnboardAuditConfig) {
+        self.onboard_audit = audit;
+    }
+
+    pub fn set_cluster_radius(&mut self, radius: f64) {
+        self.cluster_radius = radius;
+        self.buses.iter_mut().for_each(|b| b.cluster_radius = radius);
+    }
+
+    pub fn set_walking(&mut self, walking: WalkingConfig) {
+        self.walking = walking;
+        self.buses.iter_mut().for_each(|b| b.walking = walking);
+    }
+
+    pub fn set_bundling(&mut self, bundling: BundlingConfig) {
+        self.bundling = bundling;
+    }
+
+    pub fn set_time_windows(&mut self, time_windows: TimeWindowConfig) {
+        self.time_windows = time_windows;
+    }
+
+    pub fn set_transfers(&mut self, transfers: TransferConfig) {
+        self.transfers = transfers;
+    }
+
+    pub fn set_rejection(&mut self, rejection: RejectionConfig) {
+        self.rejection = rejection;
+    }
+
+    pub fn set_objective(&mut self, objective: Box<dyn Objective>) {
+        println!("[DYN] Minimising {:?}", objective);
+        self.objective = Some(objective);
+    }
+
+    fn objective(&self) -> &dyn Objective {
+        self.objective.as_deref().unwrap_or(&RideTime)
+    }
+
+    pub fn set_tick_seconds(&mut self, seconds: Seconds) {
+        self.tick_seconds = Some(seconds);
+        self.buses.iter_mut().for_each(|b| b.tick_seconds = seconds);
+    }
+
+    pub fn tick_seconds(&self) -> Seconds {
+        self.tick_seconds.unwrap_or(Seconds(DEFAULT_TICK_SECONDS as f64))
+    }
+
+    pub fn set_holding(&mut self, config: &HoldingConfig, graph: &Graph) {
+        self.holding = Holding::new(config, graph);
+    }
+
+    pub fn set_repositioning(&mut self, config: RepositioningConfig) {
+        self.repositioning = Repositioning::new(config);
+    }
+
+    pub fn set_depots(&mut self, config: &[DepotConfig], graph: &Graph) {
+        self.depots = Depots::new(config, graph);
+    }
+
+    pub fn set_vehicle_types(&mut self, types: Vec<VehicleType>) {
+        self.vehicle_types = types;
+    }
+
+    // Speed new and existing buses run at
+    pub fn bus_speed(&self) -> MetersPerSecond {
+        self.speed.unwrap_or(DEFAULT_SPEED)
+    }
+
+    // Add a passenger coming from elsewhere (e.g. escalated from the fixed route network)
+    pub fn accept_passenger(&mut self, mut passenger: Passenger) {
+        passenger.window = self.time_windows.window(passenger.timeframe, passenger.desired_arrival);
+        self.demands.push_back(passenger);
+    }
+
+    // Add a request made outside the demand generator, e.g. clicked on the map in the GUI
+    pub fn inject_demand(&mut self, demand: Demand) -> u32 {
+        let mut passenger = demand_to_passenger(demand, self.router.as_deref().expect("Dynamic controller has no router"), self.pid);
+        self.pid += 1;
+        let id = passenger.id;
+        if self.reachable(&passenger) {
+            passenger.window = self.time_windows.window(passenger.timeframe, passenger.desired_arrival);
+            passenger.requested(&self.analytics);
+            self.demands.push_back(passenger);
+        }
+        id
+    }
+
+    // Whether a bus could drive between the request's ends, telling the analytics if not as no
+    // bus would ever drop them off
+    fn reachable(&self, passenger: &Passenger) -> bool {
+        let router = self.router.as_deref().expect("Dynamic controller has no router");
+        if router.connected(passenger.source_pos, passenger.dest_pos) {
+            return true;
+        }
+        let unserved = PassengerAnalyticsEvent::Unserved { reason: UnservedReason::Disconnected, origin: passenger.source_pos, destination: passenger.dest_pos };
+        send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(unserved));
+        false
+    }
+
+    // Turn down the queued requests the rejection policy says to, telling the analytics why
+    fn reject_requests(&mut self) {
+        let mut kept = VecDeque::with_capacity(self.demands.len());
+        for passenger in std::mem::take(&mut self.demands) {
+            let detour = match (self.rejection.max_detour_factor, passenger.assigned_at) {
+                (Some(_), None) => self.best_detour(&passenger),
+                _ => None,
+            };
+            match self.rejection.reject(&passenger, self.time, detour) {
+                Some(reason) => {
+                    println!("[DYN] Rejected request {}: {}", passenger.id, reason.description().to_lowercase());
+                    send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Rejected { id: passenger.id, reason }));
+                }
+                None => kept.push_back(passenger),
+            }
+        }
+        self.demands = kept;
+    }
+
+    // The detour of the cheapest insertion any bus has for `passenger` on their own, None if no bus can take them
+    fn best_detour(&self, passenger: &Passenger) -> Option<f64> {
+        let nodes = self.buses.first()?.graph.get_nodelist();
+        let direct = Meters::between(self.buses[0].graph.crs(), nodes[&passenger.source_node].point, nodes[&passenger.dest_node].point);
+        self.buses
+            .iter()
+            .filter(|bus| bus.on_shift())
+            .filter_map(|bus| bus.best_insertion(passenger, 1, self.time, self.objective()))
+            .min_by(|a, b| a.cost.total_cmp(&b.cost))
+            .and_then(|best| RejectionConfig::detour(&best, direct))
+    }
+
+    // Take out the requests no bus has been given which `wanted` picks, to be handed to another controller
+    pub fn take_unassigned(&mut self, wanted: impl FnMut(&Passenger) -> bool) -> Vec<Passenger> {
+        let (taken, rest): (Vec<_>, Vec<_>) = self.demands.drain(..).partition(wanted);
+        self.demands = rest.into();
+        taken
+    }
+
+    pub fn next_passenger_id(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn set_next_passenger_id(&mut self, id: u32) {
+        self.pid = id;
+    }
+
+    // Take out requests which have been left unassigned for `max_wait` and haven't been on a fixed route yet
+    pub fn take_refused(&mut self, time: DateTime<Utc>, max_wait: Duration) -> Vec<Passenger> {
+        let (refused, rest): (Vec<_>, Vec<_>) = self.demands.drain(..).partition(|p| {
+            time - p.timeframe >= max_wait && !has_used(&p.escalations, ServiceMode::FixedRoute)
+        });
+        self.demands = rest.into();
+        refused
+    }
+
+    // Add the buses and every passenger not yet delivered to a replay frame
+    pub fn record_frame(&self, frame: &mut Frame) {
+        let status = |passenger: &Passenger| match passenger.status {
+            Status::Generated | Status::TravelStart(_) => Some((passenger.source_pos, RecordedStatus::Walking)),
+            Status::Waiting(_) => Some((passenger.source_pos, RecordedStatus::Waiting)),
+            Status::TavelDest(_) => Some((passenger.dest_pos, RecordedStatus::Walking)),
+            Status::OnBus(_) | Status::Expired => None,
+        };
+        let waiting = self.demands.iter().chain(self.buses.iter().flat_map(|bus| bus.assignment.values().flatten()));
+        frame.passengers.extend(waiting.filter_map(|p| status(p).map(|(pos, status)| RecordedPassenger::new(p.id, pos, status))));
+
+        for bus in &self.buses {
+            frame.vehicles.push(RecordedVehicle::of(bus));
+            frame.passengers.extend(bus.passengers.iter().filter_map(|p| match p.status {
+                Status::OnBus(_) => Some(RecordedPassenger::new(p.id, bus.current_pos, RecordedStatus::Riding)),
+                _ => status(p).map(|(pos, status)| RecordedPassenger::new(p.id, pos, status)),
+            }));
+            frame.passengers.extend(bus.delivered_passengers.iter().filter_map(|p| status(p).map(|(pos, status)| RecordedPassenger::new(p.id, pos, status))));
+        }
+    }
+
+    // Walks to the pick up node and from the drop off node for one in `every` passengers not yet delivered
+    pub fn walking_legs(&self, graph: &Graph, every: u32) -> Vec<WalkingLeg> {
+        let node_position = |node: &u128| graph.get_nodelist().get(node).map(|node| node.point);
+        let passengers = self.demands.iter().chain(self.buses.iter().flat_map(|bus| bus.passengers.iter()));
+
+        let mut legs = Vec::new();
+        for passenger in passengers.filter(|p| sampled(p.id, every)) {
+            if let Some(node) = node_position(&passenger.source_node) {
+                legs.push(WalkingLeg { from: passenger.source_pos, to: node });
+            }
+            if let Some(node) = node_position(&passenger.dest_node) {
+                legs.push(WalkingLeg { from: node, to: passenger.dest_pos });
+            }
+        }
+        legs
+    }
+
+    // Move the buses and re-optimise the assignment without generating any new demand
+    pub fn step(&mut self, graph: Arc<Graph>, time: DateTime<Utc>) {
+        self.time = time;
+        self.demands.iter_mut().for_each(|d| d.update(&self.analytics));
+
+        let noise = &mut self.noise;
+        self.buses.iter_mut().for_each(|b| b.move_self(noise, time));
+        self.buses.iter_mut().for_each(|b| b.update_duty(time));
+        self.archive_finished(time);
+
+        // Passengers dropped at a meeting node wait there for the bus taking them on
+        let handed_over: Vec<Passenger> = self.buses.iter_mut().flat_map(|b| b.handed_over.drain(..)).collect();
+        for passenger in handed_over {
+            self.hand_over(passenger, &graph);
+        }
+
+        let audit = &self.onboard_audit;
+        for report in self.buses.iter_mut().flat_map(|b| b.audit_onboard(audit, time)) {
+            eprintln!("[CRITICAL] {}", report);
+            send_analytics(&self.analytics, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::Critical { time, description: report }));
+        }
+
+        if self.rejection.enabled() {
+            self.reject_requests();
+        }
+        self.large_neighbourhood_search(graph);
+
+        // Only buses in service the search left without passengers hold or reposition
+        if self.repositioning.active() {
+            self.reposition_to_demand();
+        } else {
+            let holding = &self.holding;
+            self.buses.iter_mut().filter(|b| b.on_shift()).for_each(|b| b.hold_or_reposition(holding));
+        }
+    }
+
+    // Move passengers who have walked to their destination off the buses which dropped them
+    fn archive_finished(&mut self, time: DateTime<Utc>) {
+        for bus in self.buses.iter_mut() {
+            let (finished, walking): (Vec<_>, Vec<_>) = bus.delivered_passengers.drain(..).partition(|p| p.status == Status::Expired);
+            bus.delivered_passengers = walking;
+            self.completed.extend(finished.iter().map(|p| p.completed(time)));
+        }
+    }
+
+    // Send buses which have held long enough to the forecast hotspots, each to the one with the most
+    // demand for every bus already waiting at or heading to it
+    fn reposition_to_demand(&mut self) {
+        let mut claimed: HashMap<u128, usize> = HashMap::new();
+        for bus in self.buses.iter().filter(|b| b.on_shift() && b.is_empty()) {
+            *claimed.entry(bus.plan_end()).or_insert(0) += 1;
+        }
+
+        for bus in self.buses.iter_mut().filter(|b| b.on_shift()) {
+            let Some(idle_minutes) = bus.tick_idle() else { continue };
+            if self.holding.holding(idle_minutes) {
+                continue;
+            }
+            // Not counting the bus itself where it is
+            if let Some(count) = claimed.get_mut(&bus.next_node) {
+                *count = count.saturating_sub(1);
+            }
+            let target = self.repositioning.target(bus.next_node, bus.current_pos, &claimed);
+            *claimed.entry(target.unwrap_or(bus.next_node)).or_insert(0) += 1;
+            if let Some(target) = target {
+                bus.drive_empty_to(target);
+                bus.repositioned = true;
+                send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::Repositioned { id: bus.agent_id as u32, node: target, time: self.time }));
+            }
+        }
+    }
+
+    // Apply a fleet change between ticks. Returns a description of what was actually done
+    pub fn apply_fleet_parameter(&mut self, parameter: FleetParameter, graph: Arc<Graph>) -> String {
+        match parameter {
+            FleetParameter::Capacity(capacity) => {
+                self.capacity = Some(capacity);
+                self.buses.iter_mut().for_each(|b| b.set_capacity(capacity));
+                format!("Set capacity of {} buses to {}", self.buses.len(), capacity)
+            }
+            FleetParameter::Speed(speed) => {
+                self.speed = Some(MetersPerSecond(speed));
+                self.buses.iter_mut().for_each(|b| b.speed = MetersPerSecond(speed));
+                format!("Set speed of {} buses to {} m/s", self.buses.len(), speed)
+            }
+            FleetParameter::FleetSize(size) => {
+                let before = self.buses.len();
+                while self.buses.len() < size {
+                    self.spawn_agent(graph.clone());
+                }
+
+                // Only retire buses with nobody on board, their assigned passengers go back into the demand queue
+                let mut i = self.buses.len();
+                while self.buses.len() > size && i > 0 {
+                    i -= 1;
+                    if self.buses[i].passengers.is_empty() {
+                        let mut bus = self.buses.remove(i);
+                        self.demands.extend(std::mem::take(&mut bus.assignment).into_values().flatten());
+                    }
+                }
+                self.cancel_transfers();
+
+                if self.buses.len() != size {
+                    format!("Resized fleet from {} to {} buses ({} still carrying passengers)", before, self.buses.len(), self.buses.len() - size)
+                } else {
+                    format!("Resized fleet from {} to {} buses", before, size)
+                }
+            }
+        }
+    }
+
     // Construct a new/partial solution -- try assignments and see which minimises
-    pub fn constructive(&mut self, _graph: Arc<Graph>) {
+    pub fn constructive(&mut self, graph: Arc<Graph>) {
         println!("\t[LNS/Constructive] Run Constructive Heuristic");
         // All passengers in the demand queue are not assigned so shoud be generated
         // TODO: maybe change this to waiting or something based on where passenger is
@@ -44,79 +466,197 @@ impl DynamicController {
             p.status = Status::Generated;
         });
 
-        // add one request p:
+        if self.bundling.enabled() {
+            form_bundles(&self.bundling, graph.crs(), self.demands.iter_mut());
+        }
+
+        // Each request in turn goes where it fits in cheapest (see insertion.rs):
         // for each bus b do
         //  for each position n in the bus do
-        //    find origin station that causes the smallest increase in route duration
-        //    check feasiblity (time windows and capacity violations)
-        //    if feasible origin insertion then
-        //       for every position >= n in bus b do
-        //         find arrival station that causes the smallest increase in route duration
-        //         check feasibility (time window and capacity violations)
-        //         insertion criterion = ride time(p) + delta ride time + Penalty
-        //         if feasible and insertion criterion < best insertion criterion found then
-        //            save this insertion;
+        //    for every position >= n in bus b do
+        //      check feasibility (time windows and capacity violations)
+        //      insertion criterion = objective(ride time(p), wait time(p), delta route)
+        //      if feasible and insertion criterion < best insertion criterion found then
+        //        save this insertion;
         // if feasible insertion found:
         //     preform best insertion
-
-        // while demands && a bus can have insertions
         println!("\t[LNS/Constructive] Demand size: {}", self.demands.len());
-        let mut can_assign_more = true;
-
-        while !self.demands.is_empty() && can_assign_more {
-            // println!("[LNS] demand size: {}, can buses assign? {:?}", self.demands.len(), self.buses.iter().any(|b| b.can_assign_more()));
-            
-            // TODO: basically reorder these loops to avoid this n2?
-            // TODO: move min_assignment to each bus. Find the min demand for each bus and add it 
-            can_assign_more = false;
-            
-            for i in 0..self.buses.len() {
-                let bus = &mut self.buses[i];
-                let mut min_assignment: Option<(f64, usize, &Passenger)> = None;
-                // println!("[LNS]\tAnalysing with bus: {}", bus.agent_id);
-
-                for (j, demand) in self.demands.iter().enumerate() {
-                    // println!("[LNS]\t\t Testing assignment to bus: {:?}; demand {:?}", bus.agent_id, demand.dest_pos);
-                    // use BFS with heuristic being straigh line distance
-                    // try bus route with this demand
-                    // if distance < max distance so far: save this as an insertion to use
-
-                    let route_len = bus.what_if_bus_had_passenger(demand);
-
-                    // println!("[LNS]\t\t Resultant Route length: {}", route_len);
-                    if route_len < min_assignment.map(|(len, _, _)| len).unwrap_or(f64::MAX) {
-                        // println!("[LNS]\t\t New Minimum Found");
-                        // save this as an insertion to use
-                        min_assignment = Some((route_len, j, demand));
-                    }
-                }
+        let mut unassigned = VecDeque::new();
 
-                if let Some((_, demand_j, _demand)) = min_assignment {
-                    // let bus = &mut self.buses[bus_i];
-                    // println!("[LNS] Performing constructive insertion for bus: {}; demand {:?}", bus.agent_id, demand.dest_pos);
-                    // let index = self.demands.iter().position(|d| d == demand).unwrap();
-                    let passenger = self.demands.remove(demand_j).unwrap();
-                    bus.constructive(passenger);
-                    if bus.can_assign_more() {
-                        can_assign_more = true;
-                    }
-                } else {
-                    // println!("\t[LNS/Constructive] Bus {} didn't get a min assignment.", i);
+        while let Some(mut passenger) = self.demands.pop_front() {
+            // A bundle needs a seat for each of its members
+            let seats = match passenger.bundle {
+                Some(bundle) => 1 + self.demands.iter().chain(unassigned.iter()).filter(|d| d.bundle == Some(bundle)).count(),
+                None => 1,
+            };
+            let best = self
+                .buses
+                .iter()
+                .enumerate()
+                .filter(|(_, bus)| bus.on_shift())
+                .filter_map(|(i, bus)| bus.best_insertion(&passenger, seats, self.time, self.objective()).map(|insertion| (i, insertion)))
+                .min_by(|(_, a), (_, b)| a.cost.total_cmp(&b.cost));
+            let Some((i, insertion)) = best else {
+                unassigned.push_back(passenger);
+                continue;
+            };
+            let (passenger_id, first_assignment) = (passenger.id, passenger.assigned_at.is_none());
+
+            // The rest of their bundle goes on the same bus
+            if let Some(bundle) = passenger.bundle {
+                let (members, rest): (VecDeque<_>, VecDeque<_>) = self.demands.drain(..).partition(|d| d.bundle == Some(bundle));
+                self.demands = rest;
+                let (waiting, rest): (VecDeque<_>, VecDeque<_>) = unassigned.drain(..).partition(|d| d.bundle == Some(bundle));
+                unassigned = rest;
+                let members: Vec<Passenger> = members.into_iter().chain(waiting).collect();
+
+                // Members have their own drop offs, each fitted in after the first. If any of them
+                // doesn't fit the whole bundle waits for another go
+                let Some(fits) = self.bundle_insertions(i, &passenger, insertion, &members) else {
+                    unassigned.push_back(passenger);
+                    unassigned.extend(members);
+                    continue;
+                };
+                self.held.extend(passenger.note_assigned(self.time));
+                self.buses[i].insert(passenger, insertion);
+                for (mut member, fit) in members.into_iter().zip(fits) {
+                    self.held.extend(member.note_assigned(self.time));
+                    self.buses[i].insert(member, fit);
                 }
+
+            // This bus might only take them part way, another taking them on from a meeting point,
+            // each leg fitted into its bus's plan like any other request
+            } else if let Some((transfer, first_leg, next, second_leg)) = self.plan_transfer(i, &passenger).and_then(|transfer| self.transfer_insertions(i, &passenger, transfer)) {
+                self.held.extend(passenger.note_assigned(self.time));
+                passenger.transfer = Some(transfer);
+                self.buses[next].insert_transfer(passenger.id, transfer.node, passenger.dest_node, second_leg);
+                self.buses[i].insert(passenger, first_leg);
+            } else {
+                self.held.extend(passenger.note_assigned(self.time));
+                self.buses[i].insert(passenger, insertion);
+            }
+
+            // The route as it's planned now, to compare with what the bus goes on to drive
+            if first_assignment {
+                let bus = &self.buses[i];
+                let planned = VehicleAnalyticsEvent::Planned { id: bus.agent_id as u32, passenger_id, route: bus.planned_edges(), time: self.time };
+                self.held.push(AnalyticsPackage::VehicleEvent(planned));
             }
         }
 
-        if !can_assign_more {
-            println!("\t[LNS/Constructive] Stopped because no more buses can assign passengers.");
+        if !unassigned.is_empty() {
+            println!("\t[LNS/Constructive] {} requests didn't fit on any bus.", unassigned.len());
         }
+        self.demands = unassigned;
     }
 
     // destroy a solution
     pub fn destructive(&mut self, _graph: Arc<Graph>) {
         println!("\t[LNS/Destructive] Run Destructive Heuristic");
-        // Go through and destroy the solutions and reclaim the demand into the main demand list
-        for bus in self.buses.iter_mut() {
-            self.demands.extend(&mut bus.destructive().into_iter());
+        // Go through and destroy the solutions and reclaim the demand into the main demand list.
+        // Buses whose shift is over keep who they have to finish with
+        for bus in self.buses.iter_mut().filter(|b| b.on_shift()) {
+            self.demands.extend(&mut bus.destructive(&mut self.rng, self.time).into_iter());
+        }
+        self.cancel_transfers();
+    }
+
+    // The cheapest change of bus for a passenger bus `i` would otherwise take all the way, if
+    // the objective rates the longer routes plus the transfer penalty below the direct one.
+    // Meeting points are the waypoints of the other bus, places it's going anyway
+    fn plan_transfer(&self, i: usize, passenger: &Passenger) -> Option<Transfer> {
+        if !self.transfers.enabled {
+            return None;
+        }
+        let first = &self.buses[i];
+        let first_len = first.planned_route_len();
+        let direct = self.route_cost(first, first.what_if_bus_had_trip(passenger.source_node, passenger.dest_node) - first_len);
+
+        let mut best: Option<(f64, Transfer)> = None;
+        for next in self.buses.iter().filter(|b| b.agent_id != first.agent_id && b.can_assign_more()) {
+            let next_len = next.planned_route_len();
+            let meeting_nodes: BTreeSet<u128> = std::iter::once(next.next_node).chain(next.path_waypoints.iter().map(|w| w.node())).collect();
+
+            for node in meeting_nodes.into_iter().filter(|node| *node != passenger.source_node && *node != passenger.dest_node) {
+                let cost = self.route_cost(first, first.what_if_bus_had_trip(passenger.source_node, node) - first_len + self.transfers.penalty_metres)
+                    + self.route_cost(next, next.what_if_bus_had_trip(node, passenger.dest_node) - next_len);
+                if cost < direct && best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                    best = Some((cost, Transfer { node, vehicle: next.agent_id }));
+                }
+            }
+        }
+        best.map(|(_, transfer)| transfer)
+    }
+
+    // Where each bundle member goes in bus `i`'s plan once `passenger` leading the bundle is put in
+    // at `insertion`, None if any of them doesn't fit
+    fn bundle_insertions(&self, i: usize, passenger: &Passenger, insertion: Insertion, members: &[Passenger]) -> Option<Vec<Insertion>> {
+        let mut bus = self.buses[i].clone();
+        bus.insert(passenger.clone(), insertion);
+        let mut fits = Vec::with_capacity(members.len());
+        for member in members {
+            let fit = bus.best_insertion(member, 1, self.time, self.objective())?;
+            bus.insert(member.clone(), fit);
+            fits.push(fit);
+        }
+        Some(fits)
+    }
+
+    // Where each leg of `transfer` goes: the passenger's ride to the meeting node on bus `i`, and
+    // on from there on the bus taking them on (by position). None if either doesn't fit
+    fn transfer_insertions(&self, i: usize, passenger: &Passenger, transfer: Transfer) -> Option<(Transfer, Insertion, usize, Insertion)> {
+        let next = self.buses.iter().position(|b| b.agent_id == transfer.vehicle)?;
+        let first_leg = Passenger { transfer: Some(transfer), ..passenger.clone() };
+        let second_leg = Passenger { source_node: transfer.node, transfer: None, ..passenger.clone() };
+        let first = self.buses[i].best_insertion(&first_leg, 1, self.time, self.objective())?;
+        let second = self.buses[next].best_insertion(&second_leg, 1, self.time, self.objective())?;
+        Some((transfer, first, next, second))
+    }
+
+    // The objective's cost of `bus` driving `metres` further for a passenger, who rides it along
+    // with everyone on board while everyone waiting for the bus waits that much longer
+    fn route_cost(&self, bus: &Bus, metres: f64) -> f64 {
+        let distance = Meters(metres);
+        let driving = distance / bus.road_speed(self.time);
+        let waiting = bus.assignment.values().map(Vec::len).sum::<usize>();
+        self.objective().cost(&Costs { ride: driving * (bus.passengers.len() + 1) as f64, wait: driving * waiting as f64, distance, driving })
+    }
+
+    // Fewest requests left without a bus first, then what the objective makes of every bus's plan
+    fn solution_cost(&self) -> (usize, f64) {
+        (self.demands.len(), self.buses.iter().map(|bus| bus.plan_cost(self.objective(), self.time)).sum())
+    }
+
+    // Passengers back in the queue no longer change bus, so the bus which would've taken them on
+    // stops expecting them
+    fn cancel_transfers(&mut self) {
+        for passenger in self.demands.iter_mut() {
+            if let Some(transfer) = passenger.transfer.take() {
+                if let Some(next) = self.buses.iter_mut().find(|b| b.agent_id == transfer.vehicle) {
+                    next.transfers_in.remove(&passenger.id);
+                    next.replan(self.time);
+                }
+            }
+        }
+    }
+
+    // Give a passenger dropped at a meeting node to the bus taking them on, which picks them up
+    // there like any other passenger. If that bus has gone they're a new request from there
+    fn hand_over(&mut self, mut passenger: Passenger, graph: &Graph) {
+        let Some(transfer) = passenger.transfer.take() else {
+            return;
+        };
+        passenger.source_node = transfer.node;
+        passenger.source_pos = graph.get_nodelist()[&transfer.node].point;
+        passenger.status = Status::Waiting(0);
+
+        match self.buses.iter_mut().find(|b| b.agent_id == transfer.vehicle) {
+            Some(next) => {
+                next.transfers_in.remove(&passenger.id);
+                next.assignment.entry(transfer.node).or_default().push(passenger);
+                next.replan(self.time);
+            }
+            None => self.demands.push_back(passenger),
         }
     }
 
@@ -150,8 +690,20 @@ impl DynamicController {
         let mut iter_count = 0;
 
         while iter_count < max_iter_count {
+            // Repairs the objective rates worse than what was there are undone
+            let (buses, demands, cost) = (self.buses.clone(), self.demands.clone(), self.solution_cost());
             self.destructive(graph.clone());
             self.constructive(graph.clone());
+            // Nobody hears about assignments from a repair that's undone
+            if self.solution_cost() > cost {
+                println!("\t[LNS] Repair is worse than before, keeping the previous solution");
+                self.buses = buses;
+                self.demands = demands;
+                self.held.clear();
+            }
+            for event in self.held.drain(..) {
+                send_analytics(&self.analytics, event);
+            }
             iter_count += 1;
         }
     }
@@ -164,10 +716,68 @@ impl Controller for DynamicController {
         self.buses.iter().collect()
     }
 
+    // Unassigned requests and those waiting for their bus
+    fn queued_requests(&self) -> usize {
+        self.demands.len() + self.buses.iter().map(|bus| bus.assignment.values().map(Vec::len).sum::<usize>()).sum::<usize>()
+    }
+
+    fn snapshot_passengers(&self) -> Vec<PassengerSnapshot> {
+        let snapshot = |passenger: &Passenger, state, pos, vehicle: Option<&Bus>, next_node: Option<u128>| PassengerSnapshot {
+            id: passenger.id,
+            mode: ServiceMode::Drt,
+            state,
+            pos,
+            destination: passenger.dest_pos,
+            vehicle: vehicle.map(|bus| bus.agent_id as u32),
+            next_stop: vehicle.zip(next_node).map(|(bus, node)| bus.graph.get_nodelist()[&node].point),
+            rides_left: match state {
+                PassengerState::WalkingToDestination => 0,
+                _ => 1 + passenger.transfer.is_some() as usize,
+            },
+        };
+
+        let mut passengers: Vec<_> = self.demands.iter().map(|p| snapshot(p, PassengerState::Unassigned, p.source_pos, None, None)).collect();
+        for bus in &self.buses {
+            for passenger in bus.assignment.values().flatten() {
+                let state = match passenger.status {
+                    Status::Waiting(_) => PassengerState::Waiting,
+                    _ => PassengerState::WalkingToStop,
+                };
+                passengers.push(snapshot(passenger, state, passenger.source_pos, Some(bus), Some(passenger.source_node)));
+            }
+            for passenger in &bus.passengers {
+                passengers.push(snapshot(passenger, PassengerState::Riding, bus.current_pos, Some(bus), Some(passenger.leg_end())));
+            }
+            // Dropped off and walking the rest of the way
+            for passenger in &bus.delivered_passengers {
+                passengers.push(snapshot(passenger, PassengerState::WalkingToDestination, bus.graph.get_nodelist()[&passenger.dest_node].point, None, None));
+            }
+        }
+        passengers
+    }
+
     fn spawn_agent(&mut self, graph: Arc<crate::graph::Graph>) -> Option<&Self::Agent> {
         // println!("Spawning new bus");
         self.id += 1;
-        let bus = Bus::new(graph.clone(), 20, self.id, self.analytics.clone());
+        let vehicle_type = vehicles::type_of(&self.vehicle_types, self.spawned);
+        let capacity = self.capacity.or(vehicle_type.map(|vehicle| vehicle.capacity)).unwrap_or(DEFAULT_CAPACITY);
+        let mut bus = match self.depots.for_bus(self.spawned) {
+            Some(depot) => {
+                // Sorts itself out on the first step, parking or starting its shift
+                let mut bus = Bus::at_node(graph.clone(), depot.node, capacity, self.id, self.analytics.clone(), &mut self.rng);
+                bus.depot = Some(depot);
+                bus.duty = Duty::Returning;
+                bus
+            }
+            None => Bus::new(graph.clone(), capacity, self.id, self.analytics.clone(), &mut self.rng),
+        };
+        self.spawned += 1;
+        bus.speed = self.speed.or(vehicle_type.map(VehicleType::speed)).unwrap_or(DEFAULT_SPEED);
+        bus.vehicle_type = vehicle_type.map(|vehicle| vehicle.name.clone());
+        bus.colour = vehicle_type.and_then(|vehicle| vehicle.colour.clone());
+        bus.cluster_radius = self.cluster_radius;
+        bus.tick_seconds = self.tick_seconds();
+        bus.walking = self.walking;
         self.buses.push(bus);
         Some(self.buses.last().expect("Couldn't create new agent"))
     }
@@ -180,30 +790,35 @@ impl Controller for DynamicController {
     ) {
         println!("Tick: {}", time);
         println!("\tUpdating agents");
-        
-        self.demands.iter_mut().for_each(|d| d.update(&self.analytics));
-
-        self.buses.iter_mut().for_each(|b| b.move_self());
 
         // TODO: just for testing only do gen at 1/50 scale
         let demand_queue = demand.generate_scaled_amount(self.demand_scale, &time, Ok(graph.clone()));
         println!("[SIMULATION] Demand Generated: {}", demand_queue.len());
-        let mut demand_queue = demand_queue.into_iter().map(|d| {
-            let passenger = demand_to_passenger(d, graph.clone(), self.pid);
+        for d in demand_queue {
+            let mut passenger = demand_to_passenger(d, self.router.as_deref().expect("Dynamic controller has no router"), self.pid);
             self.pid += 1;
-            passenger
-        }).collect();
-        self.demands.append(&mut demand_queue);
+            if !self.reachable(&passenger) {
+                continue;
+            }
+            passenger.window = self.time_windows.window(passenger.timeframe, passenger.desired_arrival);
+            passenger.requested(&self.analytics);
+            self.demands.push_back(passenger);
+        }
+
+        if self.repositioning.needs_refresh(time) {
+            let forecast = demand.forecast(&time, self.repositioning.config().cells);
+            self.repositioning.refresh(forecast, &graph, time);
+        }
 
         // println!("\t[LNS] Running LNS");
-        self.large_neighbourhood_search(graph);
+        self.step(graph, time);
     }
 }
 
 // convert generated demand object into a passenger object
-pub fn demand_to_passenger(demand: Demand, graph: Arc<Graph>, id: u32) -> Passenger {
-    let origin = route_finding::closest_node(convert_point(demand.0), &graph);
-    let dest = route_finding::closest_node(convert_point(demand.1), &graph);
+pub fn demand_to_passenger(demand: Demand, router: &dyn DemandRouter, id: u32) -> Passenger {
+    let origin = router.closest_node(convert_point(demand.0));
+    let dest = router.closest_node(convert_point(demand.1));
     let time = demand.2;
     // Passenger::new(origin, dest, time)
     Passenger {
@@ -213,6 +828,99 @@ pub fn demand_to_passenger(demand: Demand, graph: Arc<Graph>, id: u32) -> Passen
         dest_node: dest,
         dest_pos: (demand.1.0 as f64, demand.1.1 as f64),
         timeframe: time,
+        desired_arrival: demand.3,
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{event_bus::{DropPolicy, EventBus}, graph::synthetic::grid, simulation::router::Router};
+
+    use super::*;
+
+    #[test]
+    fn undone_repairs_publish_no_assignments() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        let mut controller = DynamicController::default();
+        controller.set_router(router.clone());
+        controller.set_rng(SimRng::seeded(1));
+        controller.time = now;
+        controller.buses = vec![Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut StdRng::seed_from_u64(1))];
+
+        // Four along the bottom row who have to be there within ten minutes
+        for id in 0..4 {
+            let mut passenger = demand_to_passenger(Demand((100.0, 0.0), (400.0, 0.0), now, None), router.as_ref(), id);
+            passenger.window.latest_dropoff = Some(now + Duration::minutes(10));
+            controller.demands.push_back(passenger);
+        }
+        controller.large_neighbourhood_search(graph.clone());
+        assert!(controller.demands.is_empty());
+
+        // An hour on any of them taken off the bus can't be put back in time, so repairs which
+        // take off more than the new request up the side are undone
+        let events = EventBus::default();
+        let rx = events.subscribe::<AnalyticsPackage>(100, DropPolicy::Block);
+        controller.set_analytics(Some(events.topic()));
+        controller.time = now + Duration::hours(1);
+        controller.demands.push_back(demand_to_passenger(Demand((0.0, 200.0), (0.0, 400.0), controller.time, None), router.as_ref(), 4));
+        controller.large_neighbourhood_search(graph.clone());
+
+        // Nor the plans they'd have been promised
+        let mut assigned = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Assigned { id, .. }) => assigned.push(id),
+                AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::Planned { passenger_id, .. }) => assigned.push(passenger_id),
+                _ => {}
+            }
+        }
+        assert_eq!(controller.demands.iter().map(|p| p.id).collect::<Vec<_>>(), vec![5]);
+        assert!(assigned.is_empty());
+    }
+
+    #[test]
+    fn driving_further_is_priced_by_every_objective() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        let buses = vec![Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut StdRng::seed_from_u64(1))];
+        let mut controller = DynamicController { time: now, buses, ..Default::default() };
+        let passenger = demand_to_passenger(Demand((100.0, 0.0), (400.0, 0.0), now, None), router.as_ref(), 0);
+        let insertion = controller.buses[0].best_insertion(&passenger, 1, now, controller.objective()).unwrap();
+        controller.buses[0].insert(passenger, insertion);
+
+        // Someone's waiting for the bus, so going further keeps them waiting longer
+        for objective in objective::ObjectiveConfig::ALL {
+            controller.set_objective(objective.build());
+            assert!(controller.route_cost(&controller.buses[0], 500.0) > 0.0, "{:?}", objective);
+        }
+    }
+
+    #[test]
+    fn passengers_walking_from_the_bus_are_in_the_population() {
+        let graph = Arc::new(grid(5, 1000.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let mut now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        let buses = vec![Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut StdRng::seed_from_u64(1))];
+        let mut controller = DynamicController { time: now, buses, ..Default::default() };
+        // Left a few hundred metres from the node nearest their destination
+        let passenger = demand_to_passenger(Demand((0.0, 0.0), (2000.0, 400.0), now, None), router.as_ref(), 0);
+        let insertion = controller.buses[0].best_insertion(&passenger, 1, now, controller.objective()).unwrap();
+        controller.buses[0].insert(passenger, insertion);
+
+        while controller.buses[0].delivered_passengers.is_empty() {
+            assert!(now < Utc.with_ymd_and_hms(2023, 3, 1, 9, 0, 0).unwrap(), "never dropped off");
+            now += Duration::minutes(1);
+            controller.step(graph.clone(), now);
+        }
+        let population = controller.snapshot_passengers();
+        assert_eq!(population.len(), 1);
+        assert_eq!((population[0].id, population[0].state), (1, PassengerState::WalkingToDestination));
+    }
+}
@@ -0,0 +1,147 @@
+//! What the DRT dispatcher tries to keep low, so operator policies can be compared on the same
+//! demand. The objective prices each insertion of a request into a bus's plan, each meeting point
+//! for a change of bus, and whether the large neighbourhood search keeps a repaired solution:
+//!
+//! ```toml
+//! [simulation.objective]
+//! type = "weighted" # "ride_time" (the default), "wait_time", "vehicle_distance" or "weighted"
+//! ride = 1.0 # Per second on board, weighted only
+//! wait = 2.0 # Per second waiting to be picked up
+//! distance = 0.0 # Per metre the buses drive
+//! ```
+//!
+//! It can also be picked on the onboarding screen, which otherwise leaves the config's.
+
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use crate::units::{Meters, Seconds};
+
+/// The parts of a change to the plans an objective weighs up, all estimated along straight lines
+/// between waypoints at the bus's speed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Costs {
+    pub ride: Seconds, // Passengers on board
+    pub wait: Seconds, // Passengers waiting to be picked up
+    pub distance: Meters, // Driven by the buses
+    pub driving: Seconds, // Time the buses spend driving that distance
+}
+
+pub trait Objective: Debug + Send + Sync {
+    // Lower is better
+    fn cost(&self, costs: &Costs) -> f64;
+}
+
+/// Passengers' time on board and the time added to everyone else's by the bus driving further
+#[derive(Debug)]
+pub struct RideTime;
+
+impl Objective for RideTime {
+    fn cost(&self, costs: &Costs) -> f64 {
+        (costs.ride + costs.driving).0
+    }
+}
+
+/// Passengers' time between requesting and being picked up
+#[derive(Debug)]
+pub struct WaitTime;
+
+impl Objective for WaitTime {
+    fn cost(&self, costs: &Costs) -> f64 {
+        costs.wait.0
+    }
+}
+
+/// How far the fleet drives, whatever it means for the passengers
+#[derive(Debug)]
+pub struct VehicleDistance;
+
+impl Objective for VehicleDistance {
+    fn cost(&self, costs: &Costs) -> f64 {
+        costs.distance.0
+    }
+}
+
+#[derive(Debug)]
+pub struct Weighted {
+    pub ride: f64,
+    pub wait: f64,
+    pub distance: f64,
+}
+
+impl Objective for Weighted {
+    fn cost(&self, costs: &Costs) -> f64 {
+        self.ride * costs.ride.0 + self.wait * costs.wait.0 + self.distance * costs.distance.0
+    }
+}
+
+/// Selects the objective from the simulation config
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObjectiveConfig {
+    #[default]
+    #[serde(alias = "ride")]
+    RideTime,
+    #[serde(alias = "wait")]
+    WaitTime,
+    #[serde(alias = "distance")]
+    VehicleDistance,
+    Weighted {
+        #[serde(default = "default_weight")]
+        ride: f64,
+        #[serde(default = "default_weight")]
+        wait: f64,
+        #[serde(default)]
+        distance: f64,
+    },
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl ObjectiveConfig {
+    // Every kind, a weighted one with the default weights
+    pub const ALL: [ObjectiveConfig; 4] = [
+        ObjectiveConfig::RideTime,
+        ObjectiveConfig::WaitTime,
+        ObjectiveConfig::VehicleDistance,
+        ObjectiveConfig::Weighted { ride: 1.0, wait: 1.0, distance: 0.0 },
+    ];
+
+    pub fn build(&self) -> Box<dyn Objective> {
+        match *self {
+            ObjectiveConfig::RideTime => Box::new(RideTime),
+            ObjectiveConfig::WaitTime => Box::new(WaitTime),
+            ObjectiveConfig::VehicleDistance => Box::new(VehicleDistance),
+            ObjectiveConfig::Weighted { ride, wait, distance } => Box::new(Weighted { ride, wait, distance }),
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            ObjectiveConfig::RideTime => String::from("ride time"),
+            ObjectiveConfig::WaitTime => String::from("wait time"),
+            ObjectiveConfig::VehicleDistance => String::from("vehicle distance"),
+            ObjectiveConfig::Weighted { ride, wait, distance } => format!("weighted (ride {}, wait {}, distance {})", ride, wait, distance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn objectives_weigh_the_same_costs_differently() {
+        let costs = Costs { ride: Seconds(300.0), wait: Seconds(120.0), distance: Meters(2000.0), driving: Seconds(150.0) };
+        assert_eq!(ObjectiveConfig::RideTime.build().cost(&costs), 450.0);
+        assert_eq!(ObjectiveConfig::WaitTime.build().cost(&costs), 120.0);
+        assert_eq!(ObjectiveConfig::VehicleDistance.build().cost(&costs), 2000.0);
+
+        let config: ObjectiveConfig = toml::from_str("type = \"weighted\"\nwait = 2.0\ndistance = 0.1").unwrap();
+        assert_eq!(config, ObjectiveConfig::Weighted { ride: 1.0, wait: 2.0, distance: 0.1 });
+        assert_eq!(config.build().cost(&costs), 300.0 + 240.0 + 200.0);
+    }
+}
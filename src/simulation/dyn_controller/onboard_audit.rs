@@ -0,0 +1,52 @@
+//! Catching DRT passengers left on a bus far longer than any real ride, which only happens when
+//! the bus's plan has lost their drop-off.
+//!
+//! Each one is logged as a critical event along with the bus's plan. With `force_dropoff` their
+//! trip is ended where the bus is, so one stuck passenger doesn't quietly skew the ride times of
+//! the whole run.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OnboardAuditConfig {
+    #[serde(default = "default_max_onboard_minutes")]
+    pub max_onboard_minutes: i64, // Longest believable ride, 0 turns the audit off
+    #[serde(default)]
+    pub force_dropoff: bool, // Take overdue passengers off the bus as if they'd arrived
+}
+
+impl Default for OnboardAuditConfig {
+    fn default() -> Self {
+        OnboardAuditConfig { max_onboard_minutes: default_max_onboard_minutes(), force_dropoff: false }
+    }
+}
+
+fn default_max_onboard_minutes() -> i64 {
+    180
+}
+
+impl OnboardAuditConfig {
+    // Has a passenger who got on at `boarded` been on board too long by `now`
+    pub fn overdue(&self, boarded: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.max_onboard_minutes > 0 && (now - boarded).num_minutes() > self.max_onboard_minutes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn overdue_after_the_limit_unless_turned_off() {
+        let audit = OnboardAuditConfig { max_onboard_minutes: 60, force_dropoff: false };
+        let boarded = Utc::now();
+
+        assert!(!audit.overdue(boarded, boarded + Duration::minutes(60)));
+        assert!(audit.overdue(boarded, boarded + Duration::minutes(61)));
+        let off = OnboardAuditConfig { max_onboard_minutes: 0, ..audit };
+        assert!(!off.overdue(boarded, boarded + Duration::days(1)));
+    }
+}
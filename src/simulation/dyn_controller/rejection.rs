@@ -0,0 +1,74 @@
+//! Turning down DRT requests the fleet can't serve well, rather than leaving them queued for the
+//! rest of the run. Off unless limits are given:
+//!
+//! ```toml
+//! [simulation.rejection]
+//! max_wait_minutes = 20 # Requests no bus has taken on this long after they were made are rejected
+//! max_detour_factor = 3.0 # New requests whose best ride is this many times the straight line ride are refused
+//! ```
+//!
+//! Both are checked at the start of each step, before the search. The detour is checked on new
+//! requests only, each on its own against the plans as they are: once a bus has taken a request
+//! on it's kept, even if a later repair gives it a longer ride. Every request turned down is
+//! reported to the analytics with why, and counts against the service rate.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::{analytics::UnservedReason, units::Meters};
+
+use super::{bus::Passenger, insertion::Insertion};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct RejectionConfig {
+    #[serde(default)]
+    pub max_wait_minutes: Option<i64>,
+    #[serde(default)]
+    pub max_detour_factor: Option<f64>,
+}
+
+impl RejectionConfig {
+    pub fn enabled(&self) -> bool {
+        self.max_wait_minutes.is_some() || self.max_detour_factor.is_some()
+    }
+
+    // Why `passenger`, still without a bus at `time`, is rejected, if they are. `detour` is the
+    // ride of the cheapest insertion any bus has for them over the straight line between their stops
+    pub fn reject(&self, passenger: &Passenger, time: DateTime<Utc>, detour: Option<f64>) -> Option<UnservedReason> {
+        if self.max_wait_minutes.is_some_and(|minutes| time - passenger.timeframe >= Duration::minutes(minutes)) {
+            return Some(UnservedReason::WaitedTooLong);
+        }
+        let (Some(factor), Some(detour), None) = (self.max_detour_factor, detour, passenger.assigned_at) else {
+            return None;
+        };
+        (detour > factor).then_some(UnservedReason::DetourTooLong)
+    }
+
+    // How many times longer the ride `insertion` gives is than the straight line `direct`
+    pub fn detour(insertion: &Insertion, direct: Meters) -> Option<f64> {
+        (direct > Meters::ZERO).then(|| insertion.ride / direct)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_rejected_for_waiting_or_riding_too_long() {
+        let now = Utc::now();
+        let config = RejectionConfig { max_wait_minutes: Some(20), max_detour_factor: Some(2.0) };
+        let passenger = Passenger { timeframe: now, ..Default::default() };
+        let detour = |ride| RejectionConfig::detour(&Insertion { pickup: 1, dropoff: 2, cost: 0.0, ride: Meters(ride) }, Meters(1000.0));
+
+        assert_eq!(config.reject(&passenger, now, None), None);
+        assert_eq!(config.reject(&passenger, now + Duration::minutes(20), None), Some(UnservedReason::WaitedTooLong));
+        assert_eq!(config.reject(&passenger, now, detour(1500.0)), None);
+        assert_eq!(config.reject(&passenger, now, detour(2500.0)), Some(UnservedReason::DetourTooLong));
+
+        // Requests a bus has already taken on aren't refused for their ride
+        let assigned = Passenger { assigned_at: Some(now), ..passenger.clone() };
+        assert_eq!(config.reject(&assigned, now, detour(2500.0)), None);
+        assert_eq!(RejectionConfig::default().reject(&passenger, now + Duration::hours(5), detour(9000.0)), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! Sending empty DRT buses towards where demand is expected next, rather than waiting where they
+//! dropped off their last passenger or at a fixed holding point. Off unless turned on:
+//!
+//! ```toml
+//! [simulation.repositioning]
+//! enabled = true
+//! cells = 8 # Forecast demand is summed over an 8 x 8 grid across the map
+//! hotspots = 6 # Busiest cells buses are sent to
+//! refresh_minutes = 15 # How often the forecast is read again from the demand source
+//! ```
+//!
+//! The forecast is where the demand images put trips starting at the current time, so it only
+//! comes from image demand and the run warns at the start if it's turned on with another source.
+//! Buses still hold for the holding config's `hold_minutes` first, then go to the hotspot with the
+//! most forecast demand for each bus already waiting at or driving to it, which spreads the fleet
+//! over the hotspots in proportion to their demand.
+//!
+//! Every move is sent to the analytics, which compares how long passengers a bus picked up next
+//! waited against everyone else. Runs with it on and off can be compared on their average wait.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::{geometry::Crs, graph::Graph};
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RepositioningConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_cells")]
+    pub cells: usize,
+    #[serde(default = "default_hotspots")]
+    pub hotspots: usize,
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: i64,
+}
+
+impl Default for RepositioningConfig {
+    fn default() -> Self {
+        RepositioningConfig { enabled: false, cells: default_cells(), hotspots: default_hotspots(), refresh_minutes: default_refresh_minutes() }
+    }
+}
+
+fn default_cells() -> usize {
+    8
+}
+
+fn default_hotspots() -> usize {
+    6
+}
+
+fn default_refresh_minutes() -> i64 {
+    15
+}
+
+/// The forecast hotspots snapped onto the road graph
+#[derive(Debug, Clone, Default)]
+pub struct Repositioning {
+    config: RepositioningConfig,
+    hotspots: Vec<(u128, (f64, f64), f64)>, // Node, its position and the demand forecast there
+    refreshed: Option<DateTime<Utc>>,
+    crs: Crs, // Of the graph the hotspots were snapped onto
+}
+
+impl Repositioning {
+    pub fn new(config: RepositioningConfig) -> Self {
+        Repositioning { config, ..Default::default() }
+    }
+
+    pub fn config(&self) -> &RepositioningConfig {
+        &self.config
+    }
+
+    // Whether idle buses are sent to hotspots, only once there's a forecast to send them by
+    pub fn active(&self) -> bool {
+        self.config.enabled && !self.hotspots.is_empty()
+    }
+
+    // Whether the forecast is due to be read again at `time`
+    pub fn needs_refresh(&self, time: DateTime<Utc>) -> bool {
+        self.config.enabled && self.refreshed.is_none_or(|refreshed| time - refreshed >= Duration::minutes(self.config.refresh_minutes))
+    }
+
+    // Keep the busiest of `forecast` (map points weighted by expected demand) as the hotspots,
+    // merging any which snap onto the same node
+    pub fn refresh(&mut self, forecast: Vec<((f64, f64), f64)>, graph: &Graph, time: DateTime<Utc>) {
+        let mut demand: HashMap<u128, f64> = HashMap::new();
+        for (point, weight) in forecast.into_iter().filter(|(_, weight)| *weight > 0.0) {
+            if let Some(node) = graph.nearest_node(point) {
+                *demand.entry(node).or_insert(0.0) += weight;
+            }
+        }
+        let mut hotspots: Vec<_> = demand.into_iter().map(|(node, weight)| (node, graph.get_nodelist()[&node].point, weight)).collect();
+        hotspots.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+        hotspots.truncate(self.config.hotspots);
+        self.hotspots = hotspots;
+        self.refreshed = Some(time);
+        self.crs = graph.crs();
+    }
+
+    // Hotspot a bus at `node` should drive to given how many buses are already at or going to each
+    // (not counting this one), None to stay where it is. Nearest first between equally good ones
+    pub fn target(&self, node: u128, position: (f64, f64), claimed: &HashMap<u128, usize>) -> Option<u128> {
+        let share = |(hotspot, _, weight): &(u128, (f64, f64), f64)| weight / (1 + claimed.get(hotspot).copied().unwrap_or(0)) as f64;
+        let (target, _, _) = self.hotspots.iter().max_by(|a, b| {
+            share(a).total_cmp(&share(b)).then(self.crs.distance(b.1, position).total_cmp(&self.crs.distance(a.1, position)))
+        })?;
+        (*target != node).then_some(*target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buses_are_spread_over_the_hotspots_by_demand() {
+        let repositioning = Repositioning {
+            config: RepositioningConfig { enabled: true, ..Default::default() },
+            hotspots: vec![(1, (0.0, 0.0), 30.0), (2, (1000.0, 0.0), 10.0), (3, (0.0, 1000.0), 10.0)],
+            refreshed: Some(Utc::now()),
+            crs: Crs::default(),
+        };
+        assert!(repositioning.active());
+
+        // The busiest hotspot, until it has as little demand per bus as the others and the closest wins
+        assert_eq!(repositioning.target(7, (600.0, 0.0), &HashMap::new()), Some(1));
+        assert_eq!(repositioning.target(1, (0.0, 0.0), &HashMap::new()), None);
+        assert_eq!(repositioning.target(7, (600.0, 0.0), &HashMap::from([(1, 1)])), Some(1));
+        assert_eq!(repositioning.target(7, (600.0, 0.0), &HashMap::from([(1, 2)])), Some(2));
+        assert_eq!(repositioning.target(7, (0.0, 600.0), &HashMap::from([(1, 2)])), Some(3));
+        assert!(!Repositioning::default().active());
+    }
+}
@@ -0,0 +1,208 @@
+//! Time windows on DRT passengers: not picked up before they're ready, dropped off by a latest
+//! time and not kept on board much longer than the ride straight there. Off unless limits are given:
+//!
+//! ```toml
+//! [simulation.time_windows]
+//! latest_dropoff_minutes = 45 # After the request
+//! desired_arrival = true # Passengers who gave a desired arrival are dropped off by then instead
+//! max_ride_factor = 2.0 # Longest ride as a multiple of the straight line ride at the bus's speed
+//! ```
+//!
+//! Each passenger keeps their own window. When the search tries a passenger on a bus it estimates
+//! when the bus reaches each waypoint, in straight lines at its speed, and only gives the bus the
+//! passenger if that misses no window the bus's current plan doesn't already miss. A bus there
+//! before a passenger is ready waits for them. Windows missed on the road are reported to the
+//! analytics as the passenger gets off.
+
+use std::collections::{HashSet, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::units::{Meters, Seconds};
+
+use super::{bus::{Bus, Passenger, Status}, waypoints::Waypoint};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct TimeWindowConfig {
+    #[serde(default)]
+    pub latest_dropoff_minutes: Option<i64>,
+    #[serde(default)]
+    pub desired_arrival: bool,
+    #[serde(default)]
+    pub max_ride_factor: Option<f64>,
+}
+
+impl TimeWindowConfig {
+    // The window for a passenger who asked at `requested`
+    pub fn window(&self, requested: DateTime<Utc>, desired_arrival: Option<DateTime<Utc>>) -> TimeWindow {
+        let latest = self.latest_dropoff_minutes.map(|minutes| requested + Duration::minutes(minutes));
+        TimeWindow {
+            earliest_pickup: Some(requested),
+            latest_dropoff: desired_arrival.filter(|_| self.desired_arrival).or(latest),
+            max_ride_factor: self.max_ride_factor,
+        }
+    }
+}
+
+/// When one passenger can be picked up and dropped off, and how long they'll ride for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub earliest_pickup: Option<DateTime<Utc>>,
+    pub latest_dropoff: Option<DateTime<Utc>>,
+    pub max_ride_factor: Option<f64>,
+}
+
+/// A window a passenger's ride didn't keep to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WindowViolation {
+    LateDropoff,
+    LongRide,
+}
+
+impl TimeWindow {
+    pub fn ready(&self, time: DateTime<Utc>) -> bool {
+        self.earliest_pickup.is_none_or(|earliest| time >= earliest)
+    }
+
+    // Nothing to miss whatever the ride, the earliest pick up is kept by not boarding early
+    pub fn is_open(&self) -> bool {
+        self.latest_dropoff.is_none() && self.max_ride_factor.is_none()
+    }
+
+    // Windows missed riding from `boarded` to `dropped_off`, for a trip `direct` long in a straight
+    // line. Only the last leg of a journey has to be there by the latest drop off
+    pub fn violations(&self, boarded: DateTime<Utc>, dropped_off: DateTime<Utc>, direct: Seconds, last_leg: bool) -> Vec<WindowViolation> {
+        let mut violations = Vec::new();
+        if last_leg && self.latest_dropoff.is_some_and(|latest| dropped_off > latest) {
+            violations.push(WindowViolation::LateDropoff);
+        }
+        if self.max_ride_factor.is_some_and(|factor| Seconds::from_duration(dropped_off - boarded) > direct * factor) {
+            violations.push(WindowViolation::LongRide);
+        }
+        violations
+    }
+}
+
+// Passengers on or waiting for `bus`, and `extra` if given, whose windows would be missed with
+// the bus following `path` from where it is at `now`
+pub fn missed_windows(bus: &Bus, path: &VecDeque<Waypoint>, extra: Option<&Passenger>, now: DateTime<Utc>) -> HashSet<u32> {
+    let point = |node: u128| bus.graph.get_nodelist()[&node].point;
+    let arrivals = bus.estimated_arrivals(path, now);
+    let reached = |waypoint: Waypoint, from: usize| path.iter().skip(from).position(|w| *w == waypoint).map(|i| i + from);
+    let direct_seconds = |passenger: &Passenger| Meters::between(bus.graph.crs(), point(passenger.source_node), point(passenger.dest_node)) / bus.speed;
+
+    let mut missed = HashSet::new();
+    for passenger in bus.passengers.iter().filter(|p| !p.window.is_open()) {
+        let Status::OnBus(boarded) = passenger.status else { continue };
+        let Some(dropoff) = reached(Waypoint::Dropoff(passenger.leg_end()), 0) else { continue };
+        if !passenger.window.violations(boarded, arrivals[dropoff], direct_seconds(passenger), passenger.transfer.is_none()).is_empty() {
+            missed.insert(passenger.id);
+        }
+    }
+
+    let waiting = bus.assignment.values().flatten().filter(|p| matches!(p.status, Status::Waiting(_) | Status::TravelStart(_)));
+    for passenger in waiting.chain(extra).filter(|p| !p.window.is_open()) {
+        let Some(pickup) = reached(Waypoint::Pickup(passenger.source_node), 0) else { continue };
+        let Some(dropoff) = reached(Waypoint::Dropoff(passenger.leg_end()), pickup) else { continue };
+        if !passenger.window.violations(arrivals[pickup], arrivals[dropoff], direct_seconds(passenger), passenger.transfer.is_none()).is_empty() {
+            missed.insert(passenger.id);
+        }
+    }
+    missed
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        graph::synthetic::grid,
+        simulation::{demand::Demand, dyn_controller::{bus::DEFAULT_CAPACITY, demand_to_passenger, objective::RideTime, DynamicController}, rng::SimRng, router::{DemandRouter, Router}, Controller},
+    };
+
+    use super::*;
+
+    #[test]
+    fn windows_are_missed_late_or_by_riding_too_long() {
+        let now = Utc::now();
+        let config = TimeWindowConfig { latest_dropoff_minutes: Some(30), desired_arrival: true, max_ride_factor: Some(2.0) };
+        let window = config.window(now, None);
+        assert!(!window.ready(now - Duration::minutes(1)));
+        assert!(window.violations(now, now + Duration::minutes(10), Seconds(300.0), true).is_empty());
+        assert_eq!(window.violations(now, now + Duration::minutes(11), Seconds(300.0), true), vec![WindowViolation::LongRide]);
+        // Late for the whole journey only matters getting off the last bus
+        assert_eq!(window.violations(now + Duration::minutes(25), now + Duration::minutes(31), Seconds(300.0), true), vec![WindowViolation::LateDropoff]);
+        assert!(window.violations(now + Duration::minutes(25), now + Duration::minutes(31), Seconds(300.0), false).is_empty());
+
+        // A desired arrival takes over from the latest drop off when asked to
+        let desired = now + Duration::minutes(20);
+        assert_eq!(config.window(now, Some(desired)).latest_dropoff, Some(desired));
+        let ignored = TimeWindowConfig { desired_arrival: false, ..config };
+        assert_eq!(ignored.window(now, Some(desired)).latest_dropoff, Some(now + Duration::minutes(30)));
+        assert!(TimeWindowConfig::default().window(now, Some(desired)).is_open());
+    }
+
+    #[test]
+    fn buses_are_not_given_passengers_they_would_make_late() {
+        // 5 x 5 junctions 100m apart, node ids counting along each row
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let mut controller = DynamicController::default();
+        controller.set_router(router.clone());
+        controller.set_rng(SimRng::seeded(1));
+        controller.spawn_agent(graph.clone());
+        let now = Utc::now();
+
+        // Corner to corner is over 40s in a straight line at 30mph
+        let mut passenger = demand_to_passenger(Demand((0.0, 0.0), (400.0, 400.0), now, None), router.as_ref(), 0);
+        passenger.window = TimeWindowConfig { latest_dropoff_minutes: Some(60), ..Default::default() }.window(now, None);
+        assert!(controller.buses[0].best_insertion(&passenger, 1, now, &RideTime).is_some());
+        passenger.window.latest_dropoff = Some(now + Duration::seconds(5));
+        assert!(controller.buses[0].best_insertion(&passenger, 1, now, &RideTime).is_none());
+    }
+
+    #[test]
+    fn buses_there_early_wait_for_the_passenger_to_be_ready() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let mut controller = DynamicController::default();
+        controller.set_router(router.clone());
+        controller.set_rng(SimRng::seeded(1));
+        controller.buses = vec![Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut StdRng::seed_from_u64(1))];
+        controller.set_tick_seconds(Seconds(10.0));
+        let mut now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+
+        // Along the bottom row, not to be picked up for two minutes
+        let mut passenger = demand_to_passenger(Demand((200.0, 0.0), (400.0, 0.0), now, None), router.as_ref(), 0);
+        let id = passenger.id;
+        passenger.window = TimeWindowConfig::default().window(now + Duration::minutes(2), None);
+        controller.demands.push_back(passenger);
+
+        // The bus gets there in under a minute and waits rather than going on without them
+        for _ in 0..6 {
+            now += Duration::seconds(10);
+            controller.step(graph.clone(), now);
+        }
+        let bus = &controller.buses[0];
+        assert_eq!(bus.waiting_at, Some(2));
+        assert_eq!(bus.current_pos, (200.0, 0.0));
+        assert!(bus.assignment[&2].iter().any(|p| p.id == id));
+
+        // Then picks them up once they're ready and takes them there
+        while now < Utc.with_ymd_and_hms(2023, 3, 1, 8, 2, 0).unwrap() {
+            now += Duration::seconds(10);
+            controller.step(graph.clone(), now);
+        }
+        assert!(controller.buses[0].passengers.iter().any(|p| p.id == id && p.status == Status::OnBus(now)));
+        for _ in 0..10 {
+            now += Duration::seconds(10);
+            controller.step(graph.clone(), now);
+        }
+        assert_eq!(controller.completed.len(), 1);
+    }
+}
@@ -0,0 +1,125 @@
+//! DRT passengers changing bus part way through their trip. A long trip across the area can cost
+//! one bus a big detour when a second bus is already heading that way and could take them on from
+//! a meeting point. Off unless turned on:
+//!
+//! ```toml
+//! [simulation.transfers]
+//! enabled = true
+//! penalty_metres = 1000.0 # Route length a transfer has to save over one bus going all the way
+//! ```
+//!
+//! The meeting point is one of the second bus's waypoints, somewhere it's going anyway. The first
+//! bus drops the passenger off there and they're handed to the second bus, which only picks them
+//! up once they've arrived. Getting there first, the second bus waits at the meeting node for them.
+//! A passenger waiting to be picked up can still be reassigned, which cancels the transfer, but
+//! once on board the plan is kept.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct TransferConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_penalty_metres")]
+    pub penalty_metres: f64, // Added to the cost of every transfer for the passenger's trouble
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig { enabled: false, penalty_metres: default_penalty_metres() }
+    }
+}
+
+fn default_penalty_metres() -> f64 {
+    1000.0
+}
+
+/// A planned change of bus, kept on the passenger until they've been handed over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub node: u128, // Where the buses meet
+    pub vehicle: usize, // Bus taking the passenger on from there
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::{Duration, TimeZone, Utc};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        graph::synthetic::grid,
+        simulation::{
+            demand::Demand,
+            dyn_controller::{bus::{Bus, DEFAULT_CAPACITY}, demand_to_passenger, waypoints::Waypoint, DynamicController},
+            rng::SimRng,
+            router::{DemandRouter, Router},
+        },
+        units::Seconds,
+    };
+
+    use super::*;
+
+    #[test]
+    fn passengers_change_bus_at_the_meeting_node() {
+        let graph = Arc::new(grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let mut controller = DynamicController::default();
+        controller.set_router(router.clone());
+        controller.set_rng(SimRng::seeded(1));
+        // The first bus from the corner, the second starting out from the meeting node in the middle
+        let meeting = 12;
+        let mut rng = StdRng::seed_from_u64(1);
+        controller.buses = vec![
+            Bus::at_node(graph.clone(), 0, DEFAULT_CAPACITY, 1, None, &mut rng),
+            Bus::at_node(graph.clone(), meeting, DEFAULT_CAPACITY, 2, None, &mut rng),
+        ];
+        controller.set_tick_seconds(Seconds(10.0));
+        let mut now = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+
+        // On the first bus, changing in the middle of the grid for the far corner
+        let mut passenger = demand_to_passenger(Demand((0.0, 0.0), (400.0, 400.0), now, None), router.as_ref(), 0);
+        let id = passenger.id;
+        passenger.set_on_bus(now);
+        passenger.transfer = Some(Transfer { node: meeting, vehicle: controller.buses[1].agent_id });
+        controller.buses[1].expect_transfer(passenger.id, meeting, passenger.dest_node, now);
+        controller.buses[0].passengers.push(passenger);
+        controller.buses[0].rem_capacity -= 1;
+        controller.buses[0].replan(now);
+
+        // The first bus only goes as far as the meeting node, the second picks up there before going on
+        assert!(controller.buses[0].path_waypoints.contains(&Waypoint::Dropoff(meeting)));
+        assert!(!controller.buses[0].path_waypoints.contains(&Waypoint::Dropoff(24)));
+        let second = &controller.buses[1].path_waypoints;
+        let pickup = second.iter().position(|w| *w == Waypoint::Pickup(meeting)).unwrap();
+        let dropoff = second.iter().position(|w| *w == Waypoint::Dropoff(24)).unwrap();
+        assert!(pickup < dropoff);
+
+        // The second bus is back at the meeting node first, 200m to the first's 400m, and waits there
+        for _ in 0..2 {
+            now += Duration::seconds(10);
+            controller.step(graph.clone(), now);
+        }
+        assert_eq!(controller.buses[1].waiting_at, Some(meeting));
+        assert!(controller.buses[0].passengers.iter().any(|p| p.id == id));
+        let held = controller.buses[1].current_pos;
+        now += Duration::seconds(10);
+        controller.step(graph.clone(), now);
+        assert_eq!(controller.buses[1].current_pos, held);
+
+        // Dropped off and picked up by the second bus which waited, which takes them the rest of the way
+        now += Duration::seconds(10);
+        controller.step(graph.clone(), now);
+        assert_eq!(controller.buses[1].waiting_at, None);
+        assert!(controller.buses[1].passengers.iter().any(|p| p.id == id));
+        for _ in 0..20 {
+            now += Duration::seconds(10);
+            controller.step(graph.clone(), now);
+        }
+        let completed = &controller.completed;
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].origin, graph.get_nodelist()[&meeting].point);
+        assert!(controller.buses.iter().all(|b| b.transfers_in.is_empty() && b.passengers.is_empty()));
+    }
+}
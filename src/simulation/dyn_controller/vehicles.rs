@@ -0,0 +1,86 @@
+//! Kinds of vehicle in the DRT fleet, e.g. a few minibuses with a lot of cars:
+//!
+//! ```toml
+//! [[simulation.vehicle_types]]
+//! name = "Minibus"
+//! capacity = 16
+//! count = 4
+//! colour = "gold" # Path drawn on the map, any colour name the graph config takes
+//!
+//! [[simulation.vehicle_types]]
+//! name = "Car"
+//! capacity = 4
+//! speed = 15.0 # m/s, the default bus speed if not given
+//! count = 10
+//! ```
+//!
+//! With any given the fleet is the total of their counts rather than `dyn_agent_count`, spawned a
+//! type at a time in the order they're listed. Buses added by a fleet change carry on through the
+//! types again. Insertions are checked against each bus's own seats and timed at its own speed.
+//! A capacity or speed fleet change still applies to every bus, whatever its type.
+
+use serde::Deserialize;
+
+use crate::units::MetersPerSecond;
+
+use super::bus::DEFAULT_SPEED;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VehicleType {
+    pub name: String,
+    pub capacity: u8,
+    #[serde(default)]
+    pub speed: Option<f64>,
+    pub count: usize,
+    #[serde(default)]
+    pub colour: Option<String>,
+}
+
+impl VehicleType {
+    pub fn speed(&self) -> MetersPerSecond {
+        self.speed.map_or(DEFAULT_SPEED, MetersPerSecond)
+    }
+}
+
+// Total buses of all the types
+pub fn fleet_size(types: &[VehicleType]) -> usize {
+    types.iter().map(|vehicle| vehicle.count).sum()
+}
+
+// Type of the `n`th bus spawned, counting from 0
+pub fn type_of(types: &[VehicleType], n: usize) -> Option<&VehicleType> {
+    let total = fleet_size(types);
+    if total == 0 {
+        return None;
+    }
+    let mut n = n % total;
+    types.iter().find(|vehicle| {
+        let found = n < vehicle.count;
+        n = n.saturating_sub(vehicle.count);
+        found
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buses_are_spawned_a_type_at_a_time() {
+        #[derive(Deserialize)]
+        struct Config {
+            vehicle_types: Vec<VehicleType>,
+        }
+        let config: Config = toml::from_str(
+            "[[vehicle_types]]\nname = \"Minibus\"\ncapacity = 16\ncount = 2\n[[vehicle_types]]\nname = \"Car\"\ncapacity = 4\nspeed = 15.0\ncount = 1",
+        )
+        .unwrap();
+        let types = config.vehicle_types;
+        assert_eq!(fleet_size(&types), 3);
+
+        let names: Vec<_> = (0..5).map(|n| type_of(&types, n).unwrap().name.as_str()).collect();
+        assert_eq!(names, vec!["Minibus", "Minibus", "Car", "Minibus", "Minibus"]);
+        assert_eq!((types[0].speed(), types[1].speed()), (DEFAULT_SPEED, MetersPerSecond(15.0)));
+        assert!(type_of(&[], 0).is_none());
+    }
+}
@@ -1,11 +1,13 @@
-use std::{collections::{HashSet, HashMap, VecDeque}, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, sync::Arc};
 
-use crate::graph::Graph;
+use serde::{Deserialize, Serialize};
 
-use super::bus::{Bus, Status, Passenger};
+use crate::{geometry::Crs, graph::Graph};
+
+use super::bus::{Bus, Status};
 
 // Simple representation of waypoints and the actions available at each
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Waypoint {
     Passthrough(u128), // Passthrough -- Just have to visit with no other action
     Pickup(u128), // Pickup -- Pick up a passenger(s) waiting at this node
@@ -26,8 +28,8 @@ impl Waypoint {
 // and also models the dependencies between pick-ups and drop-offs
 #[derive(Debug, Default, Clone)]
 pub struct DirForest {
-    roots: HashSet<Waypoint>, // Waypoints which can be visited at the current time (no predecessors)
-    children: HashMap<Waypoint, HashSet<Waypoint>> // Waypoints which can only be visited after a parent
+    roots: BTreeSet<Waypoint>, // Waypoints which can be visited at the current time (no predecessors)
+    children: BTreeMap<Waypoint, BTreeSet<Waypoint>> // Waypoints which can only be visited after a parent
 }
 
 impl DirForest {
@@ -39,7 +41,7 @@ impl DirForest {
                 self.children.entry(parent_data)
                     .and_modify(|f| { f.insert(child); })
                     .or_insert_with(|| {
-                        let mut set = HashSet::new();
+                        let mut set = BTreeSet::new();
                         set.insert(child);
                         set
                     });
@@ -65,21 +67,21 @@ impl DirForest {
     }
 
     // Provides a mapping from top level waypoints to the actual nodes to visit including the set of actions to perform at each node
-    pub fn get_root_nodes(&self) -> HashMap<u128, HashSet<Waypoint>> {
-        let mut root_nodes = HashMap::new();
+    pub fn get_root_nodes(&self) -> BTreeMap<u128, BTreeSet<Waypoint>> {
+        let mut root_nodes = BTreeMap::new();
 
         for root in self.roots.iter() {
-            root_nodes.entry(root.node()).or_insert(HashSet::new()).insert(*root);
+            root_nodes.entry(root.node()).or_insert(BTreeSet::new()).insert(*root);
         }
 
         root_nodes
     }
     
-    pub fn get_roots(&self) -> &HashSet<Waypoint> {
+    pub fn get_roots(&self) -> &BTreeSet<Waypoint> {
         &self.roots
     }
 
-    pub fn get_children(&mut self, parent: Waypoint) -> &HashSet<Waypoint> {
+    pub fn get_children(&mut self, parent: Waypoint) -> &BTreeSet<Waypoint> {
         self.children.entry(parent).or_default()
     }
 }
@@ -88,10 +90,10 @@ pub fn bus_waypoints(bus: &Bus) -> DirForest {
     // println!("Collect waypoints for bus {}", bus.agent_id);
     let mut waypoints = DirForest::default();
 
-    // Passengers on the bus only need to go to their destination
+    // Passengers on the bus only need to go to their destination, or the meeting node if changing bus
     for passenger in bus.passengers.iter() {
         // println!("Current passenger. Must dropoff at {}", passenger.dest_node);
-        waypoints.insert(None, Waypoint::Dropoff(passenger.dest_node));
+        waypoints.insert(None, Waypoint::Dropoff(passenger.leg_end()));
     }
 
     // Passengers not yet on the bus (but in the assignment) need their source and their destination
@@ -103,7 +105,7 @@ pub fn bus_waypoints(bus: &Bus) -> DirForest {
             // println!("Passenger status: {:?}", passenger.status);
             match passenger.status {
                 Status::Waiting(_) | Status::TravelStart(_) => {
-                    waypoints.insert(Some(Waypoint::Pickup(*source_node)), Waypoint::Dropoff(passenger.dest_node));
+                    waypoints.insert(Some(Waypoint::Pickup(*source_node)), Waypoint::Dropoff(passenger.leg_end()));
                     single_valid_passenger = true;
                 },
                 _ => () // Shouldn't add anything if passenger is on bus, or got off bus
@@ -116,14 +118,22 @@ pub fn bus_waypoints(bus: &Bus) -> DirForest {
         }
     }
 
+    // Passengers changing onto this bus can only be picked up at the meeting node after the other
+    // bus has dropped them there, and only dropped off after that. Once handed over they're
+    // waiting in the assignment like anyone else
+    for (meeting_node, dest_node) in bus.transfers_in.values() {
+        waypoints.insert(Some(Waypoint::Pickup(*meeting_node)), Waypoint::Dropoff(*dest_node));
+        waypoints.insert(None, Waypoint::Pickup(*meeting_node));
+    }
+
     // println!("waypoints: {:?}", waypoints);
     waypoints
 }
 
-pub fn bus_waypoints_with_passenger(bus: &Bus, passenger: &Passenger) -> DirForest {
+pub fn bus_waypoints_with_trip(bus: &Bus, source: u128, dest: u128) -> DirForest {
     let mut waypoints = bus_waypoints(bus);
-    waypoints.insert(Some(Waypoint::Pickup(passenger.source_node)), Waypoint::Dropoff(passenger.dest_node));
-    waypoints.insert(None, Waypoint::Pickup(passenger.source_node));
+    waypoints.insert(Some(Waypoint::Pickup(source)), Waypoint::Dropoff(dest));
+    waypoints.insert(None, Waypoint::Pickup(source));
     waypoints
 }
 
@@ -163,8 +173,8 @@ pub fn create_ordering(starting_point: u128, waypoints: &mut DirForest, graph: A
     ordering
 }
 
-// Currently just squared euclidean distance
-// TODO: use a better norm? FIX PANIC HERE
+// Straight line distance
+// TODO: FIX PANIC HERE
 pub fn graph_distance(graph: Arc<Graph>, source: u128, dest: u128) -> f64 {
     if !graph.get_nodelist().contains_key(&source) || !graph.get_nodelist().contains_key(&dest) {
         println!("One of these is not in the graph!? \t Source: {} {}, Dest: {} {}", source, graph.get_nodelist().contains_key(&source), dest, graph.get_nodelist().contains_key(&dest));
@@ -172,6 +182,145 @@ pub fn graph_distance(graph: Arc<Graph>, source: u128, dest: u128) -> f64 {
     let source_pos = graph.get_nodelist().get(&source).unwrap().point;
     let dest_pos = graph.get_nodelist().get(&dest).unwrap().point;
 
-    (source_pos.0 - dest_pos.0).powi(2) + (source_pos.1 - dest_pos.1).powi(2)
+    graph.distance(source_pos, dest_pos)
+}
+
+
+// Reorder runs of consecutive drop-offs within `radius` of the first in the run, which the greedy
+// ordering can zig-zag through. Drop-offs don't depend on each other so any order in a run is valid
+pub fn optimise_dropoff_clusters(ordering: &mut VecDeque<Waypoint>, radius: f64, graph: &Graph) {
+    if radius <= 0.0 {
+        return;
+    }
+    let position = |waypoint: &Waypoint| graph.get_nodelist()[&waypoint.node()].point;
+
+    // The first waypoint is where the bus starts from
+    let mut i = 1;
+    while i < ordering.len() {
+        if !matches!(ordering[i], Waypoint::Dropoff(_)) {
+            i += 1;
+            continue;
+        }
+        let first = position(&ordering[i]);
+        let mut j = i + 1;
+        while j < ordering.len() && matches!(ordering[j], Waypoint::Dropoff(_)) && graph.distance(first, position(&ordering[j])) <= radius {
+            j += 1;
+        }
+
+        if j - i >= 2 {
+            let run: Vec<Waypoint> = ordering.range(i..j).copied().collect();
+            let points: Vec<(f64, f64)> = run.iter().map(|waypoint| position(waypoint)).collect();
+            let end = ordering.get(j).map(|waypoint| position(waypoint));
+            for (k, index) in tour_order(graph.crs(), position(&ordering[i - 1]), &points, end).into_iter().enumerate() {
+                ordering[i + k] = run[index];
+            }
+        }
+        i = j;
+    }
+}
+
+// Order to visit `points` in `crs` going from `start` (and on to `end` if there is one). Nearest neighbour
+// improved by 2-opt and moving single stops, clusters are small so each move is checked on the whole route
+fn tour_order(crs: Crs, start: (f64, f64), points: &[(f64, f64)], end: Option<(f64, f64)>) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut order = Vec::with_capacity(points.len());
+    let mut last = start;
+    while !remaining.is_empty() {
+        let (k, _) = remaining.iter().enumerate().min_by(|(_, a), (_, b)| crs.distance(last, points[**a]).total_cmp(&crs.distance(last, points[**b]))).unwrap();
+        let next = remaining.swap_remove(k);
+        last = points[next];
+        order.push(next);
+    }
+
+    let length = |order: &[usize]| {
+        let route: Vec<(f64, f64)> = std::iter::once(start).chain(order.iter().map(|i| points[*i])).chain(end).collect();
+        route.windows(2).map(|pair| crs.distance(pair[0], pair[1])).sum::<f64>()
+    };
+
+    let mut best = length(&order);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        let mut candidates = Vec::new();
+        for a in 0..order.len() {
+            for b in 0..order.len() {
+                if a < b {
+                    let mut reversed = order.clone();
+                    reversed[a..=b].reverse();
+                    candidates.push(reversed);
+                }
+                if a != b {
+                    let mut moved = order.clone();
+                    let stop = moved.remove(a);
+                    moved.insert(b, stop);
+                    candidates.push(moved);
+                }
+            }
+        }
+        for candidate in candidates {
+            let candidate_length = length(&candidate);
+            if candidate_length + 1e-9 < best {
+                best = candidate_length;
+                order = candidate;
+                improved = true;
+            }
+        }
+    }
+    order
 }
 
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        graph::synthetic::grid,
+        simulation::{demand::Demand, dyn_controller::DynamicController, rng::SimRng, router::{DemandRouter, Router}, Controller},
+    };
+
+    use super::*;
+
+    #[test]
+    fn seeded_runs_plan_the_same_routes() {
+        // Four pickups 100m from the middle of the grid, ties go to the lowest node
+        let graph = Arc::new(grid(5, 100.0));
+        let mut waypoints = DirForest::default();
+        for node in [17, 13, 11, 7] {
+            waypoints.insert(None, Waypoint::Pickup(node));
+        }
+        let ordering: Vec<_> = create_ordering(12, &mut waypoints, graph.clone()).into_iter().map(|w| w.node()).collect();
+        assert_eq!(ordering, vec![12, 7, 11, 17, 13]);
+
+        let run = || {
+            let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+            let mut controller = DynamicController::default();
+            controller.set_router(router);
+            controller.set_rng(SimRng::seeded(7));
+            for _ in 0..3 {
+                controller.spawn_agent(graph.clone());
+            }
+            let now = Utc.with_ymd_and_hms(2023, 1, 1, 8, 0, 0).unwrap();
+            for (source, dest) in [((0.0, 0.0), (400.0, 400.0)), ((400.0, 0.0), (0.0, 400.0)), ((200.0, 0.0), (200.0, 400.0)), ((0.0, 200.0), (400.0, 200.0))] {
+                controller.inject_demand(Demand(source, dest, now, None));
+            }
+            for _ in 0..5 {
+                controller.step(graph.clone(), now);
+            }
+            controller.buses.iter().map(|bus| (bus.next_node, bus.path_waypoints.clone())).collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn tour_order_removes_zig_zags() {
+        // Nearest neighbour goes 0, 1 then has to double back past the start for 2
+        let points = [(1.0, 0.0), (3.0, 0.0), (-1.5, 0.0)];
+        let order = tour_order(Crs::default(), (0.0, 0.0), &points, None);
+        assert_eq!(order, vec![2, 0, 1]);
+
+        // With the next waypoint fixed beyond the cluster the far side comes first
+        let order = tour_order(Crs::default(), (0.0, 0.0), &points, Some((10.0, 0.0)));
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Moving passengers between the fixed route network and DRT when one of them can't serve them.
+//!
+//! A passenger left waiting at a stop too long is turned into a DRT request, and a DRT request
+//! the optimiser hasn't been able to assign is turned back into a fixed route journey. Each
+//! passenger keeps the list of escalations it went through so a passenger is never bounced back
+//! to a mode it has already given up on.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{analytics::AnalyticsPackage, event_bus::Topic};
+
+use super::{
+    dyn_controller::{bus::{Passenger, Status}, time_windows::TimeWindow},
+    router::DemandRouter,
+    static_controller::agent::{BusPassenger, PassengerStatus},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EscalationConfig {
+    pub fixed_route_wait: u32, // Minutes waiting at a stop before the passenger asks for DRT
+    pub drt_refusal_wait: i64, // Minutes unassigned in the DRT queue before the passenger goes back to the buses
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServiceMode {
+    FixedRoute,
+    Drt,
+}
+
+/// One step in a passenger's escalation path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Escalation {
+    pub at: DateTime<Utc>,
+    pub from: ServiceMode,
+    pub to: ServiceMode,
+}
+
+// Has the passenger already been moved onto this mode before
+pub fn has_used(escalations: &[Escalation], mode: ServiceMode) -> bool {
+    escalations.iter().any(|e| e.from == mode || e.to == mode)
+}
+
+// Turn a passenger waiting for a fixed route bus into a DRT request from where they are now
+pub fn fixed_route_to_drt(passenger: BusPassenger, router: &dyn DemandRouter, time: DateTime<Utc>) -> Passenger {
+    let mut escalations = passenger.escalations;
+    escalations.push(Escalation { at: time, from: ServiceMode::FixedRoute, to: ServiceMode::Drt });
+
+    // They're stood at a stop rather than their original source
+    let current_pos = match passenger.instructions.front() {
+        Some(control) => match control.source {
+            Ok(stop) => router.stop_position(stop).unwrap_or(passenger.source_pos),
+            Err(pos) => pos,
+        },
+        None => passenger.source_pos,
+    };
+
+    Passenger {
+        id: passenger.id,
+        source_pos: current_pos,
+        source_node: router.closest_node(current_pos),
+        dest_pos: passenger.dest_pos,
+        dest_node: router.closest_node(passenger.dest_pos),
+        timeframe: time, // Starts waiting for DRT now
+        status: Status::Generated,
+        escalations,
+        assigned_at: None, // Waiting on a new decision
+        overdue: false,
+        transfer: None,
+        bundle: None,
+        desired_arrival: passenger.desired_arrival,
+        window: TimeWindow::default(), // Given by the DRT controller taking them on
+    }
+}
+
+// Turn a DRT request which couldn't be assigned into a fixed route journey, if there is one
+pub fn drt_to_fixed_route(passenger: Passenger, router: &dyn DemandRouter, time: DateTime<Utc>, analytics: Option<Topic<AnalyticsPackage>>) -> Result<BusPassenger, Passenger> {
+    let itinerary = match router.plan_itinerary(passenger.source_pos, passenger.dest_pos, time) {
+        Some(itinerary) => itinerary,
+        None => return Err(passenger),
+    };
+
+    let mut escalations = passenger.escalations;
+    escalations.push(Escalation { at: time, from: ServiceMode::Drt, to: ServiceMode::FixedRoute });
+
+    Ok(BusPassenger {
+        id: passenger.id,
+        source_pos: passenger.source_pos,
+        source_stop: itinerary.source_stop,
+        dest_pos: passenger.dest_pos,
+        dest_stop: itinerary.dest_stop,
+        instructions: VecDeque::from(itinerary.legs),
+        status: PassengerStatus::Generated,
+        position: passenger.source_pos,
+        analytics,
+        escalations,
+        desired_arrival: passenger.desired_arrival,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,153 @@
+//! Mixed mode: the fixed route and DRT fleets run side by side and each new passenger is given
+//! to whichever mode should get them to their destination sooner.
+//!
+//! Both journey times are estimates made when the demand appears. The fixed route time follows
+//! the planned itinerary using the timetable, the DRT time is the direct drive plus an expected
+//! wait for pick up, with walking to and from the network counted for both.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{analytics::AnalyticsPackage, event_bus::Topic, graph::{route_finding::path_length, Graph}, units::{Meters, MetersPerSecond, Seconds}};
+
+use super::{
+    demand::Demand,
+    dyn_controller::{self, bus::Passenger},
+    router::DemandRouter,
+    static_controller::{self, agent::BusPassenger, routes::NetworkData},
+    walking::WalkingConfig,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MixedConfig {
+    #[serde(default = "default_drt_wait")]
+    pub drt_wait: f64, // Minutes a DRT passenger is expected to wait to be picked up
+}
+
+impl Default for MixedConfig {
+    fn default() -> Self {
+        MixedConfig { drt_wait: default_drt_wait() }
+    }
+}
+
+fn default_drt_wait() -> f64 {
+    10.0
+}
+
+/// The mode a new passenger was given to
+pub enum Assignment {
+    FixedRoute(BusPassenger),
+    Drt(Passenger),
+    Unreachable(Passenger), // No bus journey and no road between the ends, so neither mode can take them
+}
+
+// Minutes from `tick` until the passenger reaches their destination on the buses, None if no bus makes the journey
+pub fn fixed_route_minutes(passenger: &BusPassenger, network_data: &NetworkData, tick: DateTime<Utc>, walking: &WalkingConfig) -> Option<f64> {
+    let stop_position = |stop: &u32| network_data.stops.get(stop).map(|stop| stop.position());
+
+    // Itineraries are a walk to the first stop then bus legs, with walks between stops where the
+    // passenger changes buses, ending at the destination stop
+    let now = network_data.service_seconds_at(tick);
+    let mut time = now;
+    for leg in passenger.instructions.iter() {
+        time = match leg.source {
+            Err(from) => time + walking.seconds_between(network_data.crs(), from, stop_position(&leg.destination_stop)?).whole(),
+            Ok(board_stop) => network_data.earliest_arrival(board_stop, leg.destination_stop, time)?,
+        };
+    }
+
+    let walk_from = walking.seconds_between(network_data.crs(), stop_position(&passenger.dest_stop)?, passenger.dest_pos);
+    Some((Seconds((time - now) as f64) + walk_from).minutes())
+}
+
+// Minutes until the passenger reaches their destination by DRT, None if the road graph doesn't connect them
+pub fn drt_minutes(passenger: &Passenger, graph: &Graph, speed: MetersPerSecond, config: &MixedConfig, walking: &WalkingConfig) -> Option<f64> {
+    let node_position = |node: &u128| graph.get_nodelist().get(node).map(|node| node.point);
+
+    if !graph.connected(passenger.source_node, passenger.dest_node) {
+        return None;
+    }
+    let path = graph.shortest_path_fast(passenger.source_node, passenger.dest_node);
+    let driving = (Meters(path_length(&path, graph)) / speed).minutes();
+
+    Some(
+        walking.seconds_between(graph.crs(), passenger.source_pos, node_position(&passenger.source_node)?).minutes()
+            + config.drt_wait
+            + driving
+            + walking.seconds_between(graph.crs(), node_position(&passenger.dest_node)?, passenger.dest_pos).minutes(),
+    )
+}
+
+/// Everything needed to compare the two modes for new demand
+pub struct ModeChooser<'a> {
+    pub router: &'a dyn DemandRouter,
+    pub network_data: &'a NetworkData,
+    pub graph: &'a Graph,
+    pub drt_speed: MetersPerSecond,
+    pub config: &'a MixedConfig,
+    pub walking: WalkingConfig,
+}
+
+impl ModeChooser<'_> {
+    // Turn demand into a passenger of whichever mode gets them there first, buses win a tie
+    pub fn assign(&self, demand: Demand, id: u32, analytics: Option<Topic<AnalyticsPackage>>) -> Assignment {
+        let tick = demand.2;
+        let drt = Passenger { id, ..dyn_controller::demand_to_passenger(demand.clone(), self.router, id) };
+        let bus = static_controller::demand_to_passenger(demand, self.router, tick, id, analytics);
+
+        let bus_time = bus.as_ref().and_then(|bus| fixed_route_minutes(bus, self.network_data, tick, &self.walking));
+        let drt_time = drt_minutes(&drt, self.graph, self.drt_speed, self.config, &self.walking);
+
+        match (bus, bus_time, drt_time) {
+            (Some(bus), Some(bus_time), Some(drt_time)) if bus_time <= drt_time => Assignment::FixedRoute(bus),
+            (Some(bus), Some(_), None) => Assignment::FixedRoute(bus),
+            (_, _, None) => Assignment::Unreachable(drt),
+            _ => Assignment::Drt(drt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use crate::{
+        graph::synthetic::grid,
+        simulation::{
+            dyn_controller::bus::DEFAULT_SPEED,
+            router::Router,
+            static_controller::routes::{NetworkStop, NetworkTrip},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn passengers_go_by_whichever_mode_is_sooner() {
+        // A bus along the bottom of a 4km grid, leaving at 08:02 and taking 6 minutes
+        let graph = Arc::new(grid(5, 1000.0));
+        let mut network = NetworkData::default();
+        for (id, easting) in [(1, 0.0), (2, 4000.0)] {
+            network.stops.insert(id, Arc::new(NetworkStop { easting, northing: 0.0, stop_id: id.to_string() }));
+            network.trips_from_stop.insert(id, vec![7]);
+        }
+        let at = |minute: u32| (8 * 3600 + minute * 60, 8 * 3600 + minute * 60);
+        network.trips.insert(7, NetworkTrip { trip_id: String::from("7"), route_id: String::new(), service_id: String::new(), stops: vec![1, 2], timings: vec![at(2), at(8)] });
+        let network = Arc::new(network);
+        let router = Router::new(graph.clone(), Some(network.clone()));
+
+        let tick = Utc.with_ymd_and_hms(2023, 3, 1, 8, 0, 0).unwrap();
+        let demand = Demand((0.0, 0.0), (4000.0, 0.0), tick, None);
+        let chooser = |config| ModeChooser { router: &router, network_data: &network, graph: &graph, drt_speed: DEFAULT_SPEED, config, walking: WalkingConfig::default() };
+
+        // 8 minutes by bus against a 10 minute wait and 5 minute drive
+        let config = MixedConfig::default();
+        assert!(matches!(chooser(&config).assign(demand.clone(), 1, None), Assignment::FixedRoute(passenger) if passenger.dest_stop == 2));
+
+        // With no wait the drive is sooner
+        let config = MixedConfig { drt_wait: 0.0 };
+        assert!(matches!(chooser(&config).assign(demand, 1, None), Assignment::Drt(passenger) if passenger.dest_node == 4));
+    }
+}
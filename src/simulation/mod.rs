@@ -1,27 +1,53 @@
 use std::{
-    sync::{
-        mpsc::{Receiver, Sender},
-        Arc,
-    },
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{mpsc::Receiver, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, NaiveDateTime, NaiveTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use eframe::epaint::{pos2, Color32, Shape, Stroke};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{graph::Graph, gui::AppMessage, resource::load_image::DemandResources, Module, analytics::{AnalyticsPackage, SimulationAnalyticsEvent}};
+use crate::{event_bus::{EventBus, Topic}, graph::Graph, gui::AppMessage, Module, analytics::{entity, AnalyticsPackage, PassengerAnalyticsEvent, RunMetadata, SimulationAnalyticsEvent, UnservedReason}, units::Seconds};
 
 use self::{
-    demand::DemandGenerator, dyn_controller::bus::{CurrentElement, send_analytics},
-    static_controller::routes::NetworkData,
+    demand::{policy::DemandPolicyConfig, pregenerate::{self, DemandPreview, RunWindow}, source::LoadedDemand, Demand, DemandGenerator},
+    display::{DisplayChannel, DisplayConfig, DisplayLayer},
+    dyn_controller::{bundling::BundlingConfig, bus::{CurrentElement, send_analytics}, depots::DepotConfig, holding::HoldingConfig, objective::ObjectiveConfig, onboard_audit::OnboardAuditConfig, rejection::RejectionConfig, repositioning::RepositioningConfig, time_windows::TimeWindowConfig, transfers::TransferConfig, vehicles::{self, VehicleType}, FleetParameter},
+    escalation::{drt_to_fixed_route, fixed_route_to_drt, EscalationConfig, ServiceMode},
+    mixed::{Assignment, MixedConfig, ModeChooser},
+    noise::{Noise, NoiseConfig},
+    population::{PassengerSnapshot, Population},
+    replay::{Frame, Recorder, ReplayController},
+    rng::SimRng,
+    router::{DemandRouter, Router},
+    snapshot::Snapshot,
+    static_controller::{capacity::CapacityConfig, dwell::DwellConfig, routes::{GtfsConfig, NetworkData}},
+    switching::{ActiveController, ControllerSwitch, ScheduledSwitch},
+    walking::WalkingConfig,
+    walking_overlay::{leg_shapes, WalkingOverlayConfig},
 };
 
 pub mod demand;
+pub mod display;
 pub mod dyn_controller;
+pub mod escalation;
+pub mod mixed;
+pub mod noise;
+pub mod population;
 pub mod random_controller;
+pub mod replay;
+pub mod rng;
+pub mod router;
+pub mod snapshot;
 pub mod static_controller;
+pub mod switching;
+pub mod walking;
+pub mod walking_overlay;
+
+pub const DEFAULT_TICK_SECONDS: u32 = 60; // Simulated time each tick covers unless the config sets `tick_seconds`
 
 //const STATIC_ONLY: bool = true; // true = static only, false = dynamic only
 
@@ -42,19 +68,46 @@ pub struct Simulation {
     // Recieve Mesages passed in by other threads
     rx: Option<Receiver<SimulationMessage>>,
 
-    // Send Messages to the GUI thread
-    gui_tx: Option<Sender<AppMessage>>,
+    // Published for the GUI, or whatever else is showing the run
+    gui_tx: Option<Topic<AppMessage>>,
 
-    // Send Messages to the Analytics thread
-    analytics_tx: Option<Sender<AnalyticsPackage>>,
+    // Published for the analytics thread
+    analytics_tx: Option<Topic<AnalyticsPackage>>,
 
     i: DateTime<Utc>,
-    end_time: NaiveTime,
+    end: DateTime<Utc>, // Last tick simulated, on the next day if the run goes past midnight
+    service_day: NaiveDate, // Date the run starts on, bus timetables count their seconds from it
+    tick_seconds: u32, // Simulated seconds each tick moves the clock on
 
     state: SimulationState,
     speed: u64, // Tick speed
 
     demand_generator: Option<Arc<DemandGenerator>>,
+    demand_preview: Option<Arc<DemandPreview>>, // The run's demand when it was generated up front, for the GUI
+
+    // Passenger trip planning shared by the controllers
+    router: Option<Arc<dyn DemandRouter>>,
+
+    // Moving passengers between fixed route and DRT. Static mode only, a DRT fleet serves the escalated passengers
+    escalation: Option<EscalationConfig>,
+
+    // Run both fleets and give each passenger to the faster mode
+    mixed: Option<MixedConfig>,
+    passenger_id: u32, // Next passenger id in mixed mode, shared by both fleets
+
+    // Fleet changes waiting for their scheduled time
+    fleet_changes: Vec<(DateTime<Utc>, FleetChange)>, // With when they're due in the run
+
+    // Changes of active controller waiting for their time, see switching.rs
+    controller_switches: Vec<ScheduledSwitch>,
+    handing_over: bool, // Whether the last switch hands the controller switched away from's waiting passengers over
+    no_fixed_route: HashSet<u32>, // DRT passengers with no fixed route journey, not tried again
+
+    // Debug drawing of passenger walks
+    walking_overlay: WalkingOverlayConfig,
+
+    // Which map layers the GUI has been sent
+    display: DisplayChannel,
 
     dyn_controller: dyn_controller::DynamicController,
     static_controller: static_controller::StaticController,
@@ -62,23 +115,49 @@ pub struct Simulation {
 
     static_only: bool,
     dynamic_agent_count: usize,
-    demand_scale: f64
+    demand_scale: f64,
+
+    // Writing every tick to a log, and playing a log back instead of simulating
+    recorder: Option<Recorder>,
+    replay: Option<ReplayController>,
+
+    // Config and seed the run started with, so it can be restarted
+    config: SimulationConfig,
+    seed: u64,
+
+    shutdown: bool, // Set when the thread should end
 }
 
 // The current state of the simulation
-// Stopped - pre-start-up and post-stop
-// Paused - mid execution and has agents on it just not calling the tick function
+// Idle - set up at the start time, waiting to be started
 // Running - calling the tick function
+// Paused - mid execution and has agents on it just not calling the tick function
+// Finished - reached the end time or stopped, can be restarted from the start time
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum SimulationState {
-    Stopped,
-    Paused,
+    Idle,
     Running,
+    Paused,
+    Finished,
 }
 
 impl Default for SimulationState {
     fn default() -> Self {
-        SimulationState::Paused
+        SimulationState::Idle
+    }
+}
+
+impl SimulationState {
+    // The state after asking for `requested`, None if it can't be reached from this one. Leaving
+    // Finished is only done by restarting
+    pub fn transition(self, requested: SimulationState) -> Option<SimulationState> {
+        use SimulationState::*;
+        match (self, requested) {
+            (Idle | Paused, Running) => Some(Running),
+            (Running, Paused) => Some(Paused),
+            (Idle | Running | Paused, Finished) => Some(Finished),
+            _ => None,
+        }
     }
 }
 
@@ -98,71 +177,116 @@ impl Module for Simulation {
     ) -> Result<Self::ReturnType, Box<dyn std::error::Error>> {
         let time = std::time::Instant::now();
 
-        self.static_only = config.static_only;
-        self.dynamic_agent_count = config.dyn_agent_count;
-        self.demand_scale = config.demand_scale;
-
         println!("[Simulation] Setting Overrides Recieved: {:?}", config);
 
-        if config.start_time.is_some() {
-            self.i = DateTime::from_utc(
-                NaiveDateTime::new(Utc::now().date_naive(), config.start_time.unwrap()),
-                Utc,
-            );
-        } else {
-            self.i = DateTime::from_utc(
-                NaiveDateTime::new(Utc::now().date_naive(), NaiveTime::from_hms(5, 0, 0)),
-                Utc,
-            );
-        }
-
-        if config.end_time.is_some() {
-            self.end_time = config.end_time.unwrap();
-        } else {
-            self.end_time = NaiveTime::from_hms(23, 0, 0);
-        }
-
         self.rx = Some(parameters.rx);
-        self.gui_tx = Some(parameters.gui_tx);
-
-        self.analytics_tx = Some(parameters.analysis_tx);
-        println!("[ANALYTICS] Received analytics {}", self.analytics_tx.is_some());
+        self.gui_tx = Some(parameters.events.topic());
+        self.analytics_tx = Some(parameters.events.topic());
 
         self.graph = parameters.graph;
         self.speed = 100;
 
-        if !self.static_only {
-            self.dyn_controller.set_analytics(self.analytics_tx.clone());
-            self.dyn_controller.set_demand_scale(self.demand_scale);
+        // Every random draw comes from this seed, print it so the run can be repeated
+        self.seed = config.seed.unwrap_or_else(rand::random);
+        println!("[Simulation] Random seed {}", self.seed);
 
-            for _ in 0..self.dynamic_agent_count {
-                self.dyn_controller.spawn_agent(self.graph.clone());
-            }
-        } else {
+        // Which fleets run decides what gets loaded, the rest of the config is applied by reset
+        self.static_only = config.static_only;
+        self.mixed = config.mixed.clone();
+        self.escalation = config.escalation.clone();
+        self.config = config.clone(); // Controller switches decide which controllers are loaded too
+        self.walking_overlay = config.walking_overlay.clone();
+        self.display = DisplayChannel::new(config.display);
+        let start_time = config.start_time.unwrap_or_else(default_start_time);
+        let end_time = config.end_time.unwrap_or_else(default_end_time);
+
+        // A resumed run carries on in the service day it was saved in
+        let snapshot = match config.resume.as_ref().filter(|_| config.replay.is_none()) {
+            Some(path) => Some(Snapshot::load(path)?),
+            None => None,
+        };
+        let chosen_day = snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.service_day.unwrap_or_else(|| snapshot.time.date_naive()))
+            .or(config.service_date);
+        // A request log is replayed on the day it starts rather than today, which it won't have
+        let log_days = match &parameters.demand {
+            LoadedDemand::RequestLog(log) => log.days(),
+            _ => None,
+        };
+        let chosen_day = chosen_day.or(log_days.map(|(first, _)| first));
+        self.service_day = chosen_day.unwrap_or_else(|| Utc::now().date_naive());
+
+        let router: Arc<dyn DemandRouter> = if self.runs_static() {
             println!("Loading network data...");
             let timer = std::time::Instant::now();
-            self.network_data =
-                Arc::new(static_controller::routes::load_saved_network_data().unwrap());
+            let mut network_data = static_controller::routes::load_or_build_network_data(&config.gtfs.clone().unwrap_or_default(), self.graph.crs())?;
+            // Without a day given, today if the timetable has it, otherwise the closest day it does
+            if chosen_day.is_none() {
+                self.service_day = network_data.covered_day(self.service_day);
+            }
+            network_data.restrict_to_day(self.service_day)?;
+            network_data.restrict_to_served(start_time, end_time);
+            network_data.set_service_day(self.service_day);
+            self.network_data = Arc::new(network_data);
             println!("Loaded network data in {:?}", timer.elapsed());
-            self.static_controller
-                .set_network_data(self.network_data.clone());
-            self.static_controller.set_demand_scale(self.demand_scale);
-            self.static_controller.set_analytics(self.analytics_tx.clone());
-            self.static_controller.spawn_agent(self.graph.clone());
-        }
+            Arc::new(Router::new(self.graph.clone(), Some(self.network_data.clone())).with_walking(config.walking))
+        } else {
+            Arc::new(Router::new(self.graph.clone(), None).with_walking(config.walking))
+        };
+        self.router = Some(router);
 
+        // Seeded properly by reset
+        config.demand_policy.check(&self.graph);
+        // Mixed mode demand can always fall back to DRT so is judged against the road graph
+        let data = if !self.static_only || self.mixed.is_some() {
+            Ok(self.graph.clone())
+        } else {
+            Err(self.network_data.clone())
+        };
+        if let LoadedDemand::RequestLog(log) = &parameters.demand {
+            let window = self.run_window(&config);
+            if let Some((first, last)) = log_days.filter(|_| log.between(&window.start, &window.end).is_empty()) {
+                println!("[Demand] Warning: the request log runs from {} to {} but has no requests between {} and {}, nobody will be replayed", first, last, window.start, window.end);
+            }
+        }
+        let demand = match config.pregenerate_demand.as_ref().filter(|_| config.replay.is_none()) {
+            Some(path) => {
+                // From the same fork of the seed the run would otherwise draw its demand from
+                let window = self.run_window(&config);
+                let policy = config.demand_policy.build(config.walking);
+                let (demand, preview) = pregenerate::load_or_generate(path, parameters.demand, &window, self.graph.clone(), data.clone(), policy, SimRng::seeded(self.seed).fork())?;
+                self.demand_preview = Some(Arc::new(preview));
+                demand
+            }
+            None => parameters.demand,
+        };
+        if config.repositioning.enabled && self.runs_dynamic() && !demand.forecasts() {
+            println!("[Demand] Warning: repositioning is on but only image demand forecasts where trips will start, buses won't be repositioned");
+        }
         self.demand_generator = Some(DemandGenerator::start(
-            parameters.demand_resources,
+            demand,
             self.graph.clone(),
-            if !self.static_only {
-                Ok(self.graph.clone())
-            } else {
-                Err(self.network_data.clone())
-            }
+            data,
+            config.demand_policy.build(config.walking),
+            SimRng::seeded(self.seed),
         ));
 
+        self.reset();
+
+        if let Some(snapshot) = snapshot.filter(|_| self.replay.is_none()) {
+            self.restore(snapshot);
+            println!("[Simulation] Resumed from {:?} at {}", self.config.resume.as_ref().unwrap(), self.i);
+        }
+
         self.send_state();
         self.send_demand_gen();
+        if self.runs_static() {
+            self.gui_tx.as_ref().unwrap().publish(AppMessage::NetworkData(self.network_data.clone()));
+        }
+        if let Some(preview) = self.demand_preview.clone() {
+            self.gui_tx.as_ref().unwrap().publish(AppMessage::DemandPreview(preview));
+        }
 
         Ok(println!(
             "[{}] Initialised in {:?}",
@@ -176,139 +300,867 @@ impl Module for Simulation {
 pub enum SimulationMessage {
     ShutdownThread,
     ChangeState(SimulationState),
+    Restart, // Start a finished run again from the start time
     ChangeSpeed(u64), // Change the simulation tick speed. ms value.
+    SetFleetParameter(FleetChange), // Change the dynamic fleet once the simulation reaches a time
+    SetWalkingOverlay(u32), // Draw the walks of one in this many passengers, 0 to hide
+    InjectDemand((f64, f64), (f64, f64)), // Request a trip between two map points now
+    SaveSnapshot(PathBuf), // Write the simulation state to this file so the run can be resumed
 }
 
-#[derive(Default, Deserialize, Debug)]
+/// A fleet parameter change and the simulation time to apply it at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FleetChange {
+    pub at: NaiveTime,
+    pub parameter: FleetParameter,
+}
+
+#[derive(Default, Deserialize, Debug, Clone)]
 pub struct SimulationConfig {
     pub static_only: bool, // true = static only, false = dynamic only
     pub dyn_agent_count: usize,
     pub demand_scale: f64,
-    pub start_time: Option<NaiveTime>,
-    pub end_time: Option<NaiveTime>
+    pub start_time: Option<NaiveTime>, // Simulation clock starts here, 05:00 if not given
+    pub end_time: Option<NaiveTime>, // Last tick simulated, 23:00 if not given. Not after start_time runs on past midnight
+    pub tick_seconds: Option<u32>, // Simulated seconds per tick, e.g. 10, 30 or 60 (the default)
+    pub service_date: Option<NaiveDate>, // Day whose bus timetable is run, e.g. "2023-03-01", the day a request log starts or today (or the closest day the timetable covers) if not given
+
+    // Which rule decides if generated demand is kept
+    #[serde(default)]
+    pub demand_policy: DemandPolicyConfig,
+
+    // Fleet changes to apply during the run, e.g. swapping to bigger buses at peak
+    #[serde(default)]
+    pub fleet_changes: Vec<FleetChange>,
+
+    // Times of day new demand goes to another controller, e.g. DRT only after 19:00
+    #[serde(default)]
+    pub controller_switches: Vec<ControllerSwitch>,
+
+    // Escalate long waits at stops to DRT (and refused DRT requests back to the buses)
+    pub escalation: Option<EscalationConfig>,
+
+    // Random variation on travel speeds and dwell times
+    #[serde(default)]
+    pub noise: NoiseConfig,
+
+    // How long fixed route buses stay at stops, by default until their scheduled departure
+    #[serde(default)]
+    pub dwell: DwellConfig,
+
+    // Seats on the fixed route buses, passengers wait for a later bus when one is full
+    #[serde(default)]
+    pub bus_capacity: CapacityConfig,
+
+    // Draw passenger walking legs on the map
+    #[serde(default)]
+    pub walking_overlay: WalkingOverlayConfig,
+
+    // How often the map layers are sent to the GUI
+    #[serde(default)]
+    pub display: DisplayConfig,
+
+    // How much further passengers walk than the straight line
+    #[serde(default)]
+    pub walking: WalkingConfig,
+
+    // Run the fixed route and DRT fleets together, giving each passenger to the faster one
+    pub mixed: Option<MixedConfig>,
+
+    // DRT drop-offs within this many metres of each other are put in the shortest order, 0 to turn off
+    #[serde(default)]
+    pub dropoff_cluster_radius: f64,
+
+    // Where empty DRT buses wait for new passengers
+    #[serde(default)]
+    pub holding: HoldingConfig,
+
+    // Send empty DRT buses towards forecast demand once they've held
+    #[serde(default)]
+    pub repositioning: RepositioningConfig,
+
+    // Where DRT buses start and end their shifts, random nodes and all day if there are none
+    #[serde(default)]
+    pub depots: Vec<DepotConfig>,
+
+    // Kinds of DRT vehicle and how many of each, replacing `dyn_agent_count` when given
+    #[serde(default)]
+    pub vehicle_types: Vec<VehicleType>,
+
+    // Flag (and optionally drop off) DRT passengers on board far longer than a real ride
+    #[serde(default)]
+    pub onboard_audit: OnboardAuditConfig,
+
+    // Let DRT passengers change between two buses part way when it shortens the routes enough
+    #[serde(default)]
+    pub transfers: TransferConfig,
+
+    // Give DRT requests made close together in space and time one shared pick up
+    #[serde(default)]
+    pub bundling: BundlingConfig,
+
+    // Latest drop offs and longest rides for DRT passengers, kept to when assigning them to buses
+    #[serde(default)]
+    pub time_windows: TimeWindowConfig,
+
+    // What DRT assignments and the neighbourhood search minimise, ride time by default
+    #[serde(default)]
+    pub objective: ObjectiveConfig,
+
+    // Turn down DRT requests left waiting too long or only offered a roundabout ride
+    #[serde(default)]
+    pub rejection: RejectionConfig,
+
+    // Seed for all random draws so a run can be repeated, a new one is picked if not given
+    pub seed: Option<u64>,
+
+    // Snapshot to carry on from, saved by an earlier run of the same config
+    pub resume: Option<PathBuf>,
+
+    // Log every vehicle and passenger to this file each tick
+    pub record: Option<PathBuf>,
+
+    // Also write every passenger's status and plan this often while recording, see population.rs
+    pub record_passengers_minutes: Option<u32>,
+
+    // Play this recorded log back instead of simulating, the rest of the config is ignored
+    pub replay: Option<PathBuf>,
+
+    // Generate the whole run's demand up front into this file and replay it, or replay it as it is
+    // if it's already there, see demand/pregenerate.rs
+    pub pregenerate_demand: Option<PathBuf>,
+
+    // Where the bus network is loaded from, or built from if it hasn't been yet. The [gtfs] section
+    #[serde(skip)]
+    pub gtfs: Option<GtfsConfig>,
+}
+
+fn default_start_time() -> NaiveTime {
+    NaiveTime::from_hms_opt(5, 0, 0).unwrap()
+}
+
+fn default_end_time() -> NaiveTime {
+    NaiveTime::from_hms_opt(23, 0, 0).unwrap()
+}
+
+// Also runs if the thread panics, so whatever's showing the run knows to stop waiting for it
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        if let Some(gui) = self.gui_tx.as_ref() {
+            gui.publish(AppMessage::SimulationEnded);
+        }
+    }
 }
 
 pub struct SimulationParameters {
     pub graph: Arc<Graph>,
     pub rx: Receiver<SimulationMessage>,
-    pub gui_tx: Sender<AppMessage>,
-    pub analysis_tx: Sender<AnalyticsPackage>,
-    pub demand_resources: DemandResources,
+    pub events: EventBus, // Where the GUI updates and analytics events are published
+    pub demand: LoadedDemand, // From whichever demand source is configured
 }
 
 impl Simulation {
     pub fn start(&mut self) {
-        loop {
-            match self.rx.as_ref().unwrap().try_recv() {
-                Ok(msg) => self.handle_message(msg),
-                Err(_) => (),
+        while !self.shutdown {
+            // Only running simulations have anything to do between messages
+            let msg = if self.state == SimulationState::Running {
+                self.rx.as_ref().unwrap().try_recv().ok()
+            } else {
+                match self.rx.as_ref().unwrap().recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break, // Nothing left to send messages
+                }
             };
+            if let Some(msg) = msg {
+                self.handle_message(msg);
+            }
 
-            match self.state {
-                SimulationState::Running => {
-                    let timer = std::time::Instant::now();
-                    self.tick();
-                    let time = timer.elapsed();
-                    self.send_state();
-                    
-                    send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent( SimulationAnalyticsEvent::TickTime { tick: 0, time: time.as_secs_f64() } ));
-                    if time > Duration::from_millis(self.speed) {
-                        println!(
-                            "[SIMULATION] Tick took longer than the speed! {:?} > {:?}",
-                            time,
-                            Duration::from_millis(self.speed)
-                        );
-                    } else {
-                        thread::sleep(Duration::from_millis(self.speed));
-                    }
+            if self.state == SimulationState::Running {
+                let timer = std::time::Instant::now();
+                self.tick();
+                let time = timer.elapsed();
+                self.record_frame();
+                self.send_state();
 
-                    if self.i.time() > self.end_time {
-                        println!("[SIMULATION] Stopping at end time");
-                        self.state = SimulationState::Stopped;
-                    }
+                send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent( SimulationAnalyticsEvent::TickTime { tick: 0, time: time.as_secs_f64(), sim_time: self.i } ));
+                if time > Duration::from_millis(self.speed) {
+                    println!(
+                        "[SIMULATION] Tick took longer than the speed! {:?} > {:?}",
+                        time,
+                        Duration::from_millis(self.speed)
+                    );
+                } else {
+                    thread::sleep(Duration::from_millis(self.speed));
+                }
+
+                if self.i >= self.end {
+                    println!("[SIMULATION] Finished at end time");
+                    self.finish_recording();
+                    self.state = SimulationState::Finished;
+                    send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::RunFinished { reached_end: true }));
+                    self.send_state();
                 }
-                SimulationState::Paused => {}
-                SimulationState::Stopped => break,
             }
 
             // println!("Sending {:?}", AppMessage::SimulationState(self.i, self.state));
         }
 
-        return;
+        self.finish_recording();
+        self.demand_generator.as_ref().unwrap().shutdown();
     }
 
-    pub fn send_state(&self) {
-        match self
-            .gui_tx
-            .as_ref()
-            .unwrap()
-            .send(AppMessage::SimulationStateWithAgents(
-                self.i.clone(),
-                self.state.clone(),
-                if !self.static_only {
-                    self.dyn_controller
-                        .get_agents()
-                        .into_iter()
-                        .map(|agent| agent.display()) // (agent.position.clone(), agent.cur_edge, agent.prev_node)
-                        .collect()
-                } else {
-                    self.static_controller.get_display()
-                },
-            )) {
-            Ok(_) => (),
-            Err(err) => eprintln!("Send Error {:?}", err),
+    pub fn send_state(&mut self) {
+        let gui = self.gui_tx.as_ref().unwrap();
+        gui.publish(AppMessage::SimulationState(self.i, self.state));
+
+        // Moving layers are held back while running too fast to draw them all, a stopped run
+        // always sends them so the map shows where everything stopped
+        let now = Instant::now();
+        let dynamic = self.state != SimulationState::Running || self.display.dynamic_due(now);
+        for (layer, shapes) in self.display.changed(self.display_layers(dynamic), now) {
+            gui.publish(AppMessage::DisplayLayer(layer, shapes));
         }
+        if !dynamic {
+            return;
+        }
+
+        let mut vehicles = Vec::new();
+        let mut summaries = Vec::new();
+        if let Some(replay) = &self.replay {
+            vehicles.extend(replay.vehicle_positions());
+            summaries.push((String::from("Replay"), replay.summary()));
+        } else {
+            if self.runs_static() {
+                vehicles.extend(self.static_controller.vehicle_positions().into_iter().map(|(id, pos)| (entity::vehicle_id(ServiceMode::FixedRoute, id), pos)));
+                summaries.push((String::from("Fixed route"), self.static_controller.summary()));
+            }
+            if self.runs_dynamic() {
+                vehicles.extend(self.dyn_controller.vehicle_positions().into_iter().map(|(id, pos)| (entity::vehicle_id(ServiceMode::Drt, id), pos)));
+                summaries.push((String::from("DRT"), self.dyn_controller.summary()));
+            }
+        }
+        gui.publish(AppMessage::VehiclePositions(vehicles));
+        gui.publish(AppMessage::FleetSummaries(summaries));
     }
 
-    pub fn send_demand_gen(&self) {
-        match self
-            .gui_tx
-            .as_ref()
-            .unwrap()
-            .send(AppMessage::NoteDemandGen(
-                self.demand_generator.as_ref().unwrap().clone(),
-            )) {
-            Ok(()) => {}
-            Err(err) => eprintln!("Error Sending Demand gen instance: {}", err),
+    // Every map layer, with the moving ones only if `dynamic`. Layers with nothing on are given
+    // empty so the GUI clears them
+    fn display_layers(&self, dynamic: bool) -> Vec<(DisplayLayer, Vec<Shape>)> {
+        let mut layers: BTreeMap<DisplayLayer, Vec<Shape>> = BTreeMap::new();
+        for layer in [DisplayLayer::Stops, DisplayLayer::Passengers, DisplayLayer::Walking, DisplayLayer::Vehicles] {
+            if dynamic || layer.is_static() {
+                layers.insert(layer, Vec::new());
+            }
+        }
+        let mut add = |sent: Vec<(DisplayLayer, Vec<Shape>)>| {
+            for (layer, shapes) in sent {
+                if let Some(existing) = layers.get_mut(&layer) {
+                    existing.extend(shapes);
+                }
+            }
+        };
+
+        if let Some(replay) = &self.replay {
+            add(replay.get_display());
+            return layers.into_iter().collect();
+        }
+        if self.runs_static() {
+            add(self.static_controller.get_display());
         }
+        if !dynamic {
+            return layers.into_iter().collect();
+        }
+        if self.runs_dynamic() {
+            add(vec![(DisplayLayer::Vehicles, self.dyn_controller.get_agents().into_iter().map(|agent| agent.display()).collect())]);
+        }
+
+        let every = self.walking_overlay.sample_every;
+        if every > 0 {
+            let mut legs = Vec::new();
+            if self.runs_static() {
+                legs.extend(self.static_controller.walking_legs(every));
+            }
+            if self.runs_dynamic() {
+                legs.extend(self.dyn_controller.walking_legs(&self.graph, every));
+            }
+            add(vec![(DisplayLayer::Walking, leg_shapes(&legs, self.graph.crs(), self.walking_overlay.long_walk))]);
+        }
+        layers.into_iter().collect()
+    }
+
+    pub fn send_demand_gen(&self) {
+        self.gui_tx.as_ref().unwrap().publish(AppMessage::NoteDemandGen(self.demand_generator.as_ref().unwrap().clone()));
     }
 
     pub fn handle_message(&mut self, msg: SimulationMessage) {
         println!("[SIM] Thread handle message {:?}", msg);
         match msg {
             SimulationMessage::ShutdownThread => {
-                self.state = SimulationState::Stopped;
-                self.demand_generator.as_ref().unwrap().shutdown();
+                self.shutdown = true;
             }
-            SimulationMessage::ChangeState(state) => {
-                self.state = state;
+            SimulationMessage::ChangeState(requested) => {
+                match self.state.transition(requested) {
+                    Some(state) => {
+                        if state == SimulationState::Finished && self.state != SimulationState::Finished {
+                            send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::RunFinished { reached_end: false }));
+                        }
+                        self.state = state;
+                    }
+                    None => println!("[SIMULATION] Can't go from {:?} to {:?}", self.state, requested),
+                }
+                // Always answer, so controls showing the wrong state catch up
+                self.send_state();
+            }
+            SimulationMessage::Restart => {
+                if self.state == SimulationState::Finished {
+                    self.reset();
+                    println!("[SIMULATION] Restarted from {}", self.i);
+                } else {
+                    println!("[SIMULATION] Only finished runs can be restarted, currently {:?}", self.state);
+                }
                 self.send_state();
             }
             SimulationMessage::ChangeSpeed(speed) => self.speed = speed,
+            SimulationMessage::SetFleetParameter(change) => self.schedule_fleet_changes(vec![change]),
+            SimulationMessage::SetWalkingOverlay(every) => {
+                self.walking_overlay.sample_every = every;
+                self.send_state();
+            }
+            SimulationMessage::InjectDemand(..) | SimulationMessage::SaveSnapshot(_) if self.replay.is_some() => {
+                println!("[SIMULATION] Nothing is simulated while replaying, ignoring");
+            }
+            SimulationMessage::InjectDemand(origin, destination) => self.inject_demand(origin, destination),
+            SimulationMessage::SaveSnapshot(path) => match self.snapshot().save(&path) {
+                Ok(()) => println!("[SIMULATION] Saved snapshot at {} to {:?}", self.i, path),
+                Err(err) => eprintln!("[SIMULATION] Couldn't save snapshot to {:?}: {}", path, err),
+            },
             // _ => (),
         }
     }
 
+    // Put the run back to its start time with new fleets and passengers, using the config and seed
+    // it was started with, so a restart repeats the same run
+    fn reset(&mut self) {
+        let config = self.config.clone();
+        self.display.resend(); // A new run draws everything again
+        self.replay = None;
+        if let Some(path) = &config.replay {
+            match ReplayController::load(path) {
+                Ok(replay) => {
+                    let (start, end) = replay.time_span();
+                    println!("[Simulation] Replaying {:?} from {} to {}", path, start.time(), end.time());
+                    self.i = start;
+                    self.end = end;
+                    self.replay = Some(replay);
+                    self.replay.as_mut().unwrap().seek(self.graph.clone(), start);
+                    self.state = SimulationState::Idle;
+                    return;
+                }
+                Err(err) => eprintln!("[Simulation] Couldn't load replay {:?}, simulating instead: {}", path, err),
+            }
+        }
+
+        self.static_only = config.static_only;
+        self.dynamic_agent_count = config.dyn_agent_count;
+        if !config.vehicle_types.is_empty() {
+            self.dynamic_agent_count = vehicles::fleet_size(&config.vehicle_types);
+            let types: Vec<_> = config.vehicle_types.iter().map(|vehicle| format!("{} {} ({} seats)", vehicle.count, vehicle.name, vehicle.capacity)).collect();
+            println!("[Simulation] DRT fleet of {}", types.join(", "));
+        }
+        self.demand_scale = config.demand_scale;
+
+        let start_time = config.start_time.unwrap_or_else(default_start_time);
+        let end_time = config.end_time.unwrap_or_else(default_end_time);
+        let window = self.run_window(&config);
+        self.i = window.start;
+        self.end = window.end;
+        if config.tick_seconds == Some(0) {
+            println!("[Simulation] Ticks can't be 0s long, using {}s", DEFAULT_TICK_SECONDS);
+        }
+        self.tick_seconds = window.tick_seconds;
+
+        println!("[Simulation] Running from {} to {} in {}s ticks", start_time, end_time, self.tick_seconds);
+        if end_time <= start_time {
+            // Late night service, e.g. 23:00 to 01:00
+            println!("[Simulation] End time {} isn't after the start time {}, running on past midnight", end_time, start_time);
+        }
+
+        self.fleet_changes.clear();
+        self.schedule_fleet_changes(config.fleet_changes.clone());
+        let (switches, late): (Vec<_>, Vec<_>) = config.controller_switches.iter().map(|switch| switch.scheduled(window.start)).partition(|switch| switch.at <= window.end);
+        for switch in late {
+            println!("[Simulation] Warning: the switch to {} at {} is after the run ends at {}, ignoring it", switch.to.description(), switch.at, window.end);
+        }
+        self.controller_switches = switches;
+        self.handing_over = false;
+        self.no_fixed_route.clear();
+        self.escalation = config.escalation.clone();
+        self.mixed = config.mixed.clone();
+        if self.mixed.is_some() {
+            println!("[Simulation] Mixed mode, running the fixed route and DRT fleets together");
+        }
+        self.passenger_id = 0;
+
+        let mut rng = SimRng::seeded(self.seed);
+        let demand_rng = rng.fork();
+        let controller_rng = rng.fork();
+        // Noise keeps its own seed if it has one
+        let noise_config = NoiseConfig { seed: Some(config.noise.seed.unwrap_or_else(|| rng.next_seed())), ..config.noise.clone() };
+
+        send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::RunStarted(RunMetadata {
+            static_only: self.static_only,
+            mixed: self.mixed.is_some(),
+            agent_count: self.dynamic_agent_count,
+            demand_scale: self.demand_scale,
+            seed: self.seed,
+            start_time,
+            end_time,
+            tick_seconds: self.tick_seconds,
+            walk_detour_factor: config.walking.detour_factor,
+            failed_by_rule: None,
+            complete: false,
+        })));
+
+        let router = self.router.clone().expect("Simulation has no router");
+        self.static_controller = static_controller::StaticController::default();
+        if self.runs_static() {
+            self.static_controller
+                .set_network_data(self.network_data.clone());
+            self.static_controller.set_router(router.clone());
+            self.static_controller.set_demand_scale(self.tick_demand_scale());
+            self.static_controller.set_tick_seconds(Seconds(self.tick_seconds as f64));
+            self.static_controller.set_analytics(self.analytics_tx.clone());
+            self.static_controller.set_noise(Noise::new(noise_config.clone()));
+            self.static_controller.set_dwell(config.dwell.clone());
+            self.static_controller.set_capacity(config.bus_capacity.clone());
+            self.static_controller.set_walking(config.walking);
+            // Buses only start trips once fixed routes are switched to
+            self.static_controller.set_winding_down(!self.fixed_route_active());
+            self.static_controller.spawn_agent(self.graph.clone());
+        }
+
+        // With the buses running too the DRT fleet serves the passengers given to it, either
+        // escalated from the fixed route network or picked for DRT in mixed mode
+        self.dyn_controller = dyn_controller::DynamicController::default();
+        if self.runs_dynamic() {
+            self.dyn_controller.set_analytics(self.analytics_tx.clone());
+            self.dyn_controller.set_demand_scale(self.tick_demand_scale());
+            self.dyn_controller.set_tick_seconds(Seconds(self.tick_seconds as f64));
+            self.dyn_controller.set_walking(config.walking);
+            self.dyn_controller.set_router(router);
+            if self.runs_static() {
+                // Offset the seed so the DRT fleet doesn't replay the same draws as the buses
+                self.dyn_controller.set_noise(Noise::new(NoiseConfig { seed: noise_config.seed.map(|s| s.wrapping_add(1)), ..noise_config.clone() }));
+            } else {
+                self.dyn_controller.set_noise(Noise::new(noise_config.clone()));
+            }
+            self.dyn_controller.set_rng(controller_rng);
+            self.dyn_controller.set_holding(&config.holding, &self.graph);
+            self.dyn_controller.set_repositioning(config.repositioning);
+            self.dyn_controller.set_depots(&config.depots, &self.graph);
+            self.dyn_controller.set_vehicle_types(config.vehicle_types.clone());
+            self.dyn_controller.set_cluster_radius(config.dropoff_cluster_radius);
+            self.dyn_controller.set_onboard_audit(config.onboard_audit.clone());
+            self.dyn_controller.set_transfers(config.transfers);
+            self.dyn_controller.set_bundling(config.bundling);
+            self.dyn_controller.set_time_windows(config.time_windows);
+            self.dyn_controller.set_objective(config.objective.build());
+            self.dyn_controller.set_rejection(config.rejection);
+
+            // Otherwise the fleet spawns when DRT is switched to
+            if self.drt_active() || self.escalation.is_some() {
+                self.spawn_drt_fleet();
+            }
+        }
+
+        self.demand_generator.as_ref().unwrap().set_rng(demand_rng);
+        self.demand_generator.as_ref().unwrap().set_tick_seconds(self.tick_seconds);
+        self.state = SimulationState::Idle;
+
+        // Restarting starts the log again
+        let recorder = |path: &PathBuf| match config.record_passengers_minutes {
+            Some(minutes) if minutes > 0 => Recorder::create(path)?.with_population(path, minutes),
+            _ => Recorder::create(path),
+        };
+        self.recorder = config.record.as_ref().and_then(|path| match recorder(path) {
+            Ok(recorder) => {
+                println!("[Simulation] Recording to {:?}", path);
+                Some(recorder)
+            }
+            Err(err) => {
+                eprintln!("[Simulation] Couldn't record to {:?}: {}", path, err);
+                None
+            }
+        });
+        self.record_frame();
+    }
+
+    // Add where everything is now to the log, if recording
+    fn record_frame(&mut self) {
+        if self.recorder.is_none() {
+            return;
+        }
+        let mut frame = Frame { time: self.i, ..Default::default() };
+        if self.runs_static() {
+            self.static_controller.record_frame(&mut frame);
+        }
+        if self.runs_dynamic() {
+            self.dyn_controller.record_frame(&mut frame);
+        }
+        if let Err(err) = self.recorder.as_mut().unwrap().record(&frame) {
+            eprintln!("[SIMULATION] Stopped recording, couldn't write frame: {}", err);
+            self.recorder = None;
+            return;
+        }
+
+        if self.recorder.as_ref().unwrap().population_due(self.i) {
+            let mut population = Population { time: self.i, passengers: Vec::new() };
+            if self.runs_static() {
+                population.passengers.extend(self.static_controller.snapshot_passengers());
+            }
+            if self.runs_dynamic() {
+                population.passengers.extend(self.dyn_controller.snapshot_passengers());
+            }
+            if let Err(err) = self.recorder.as_mut().unwrap().record_population(&population) {
+                eprintln!("[SIMULATION] Stopped recording, couldn't write the passengers: {}", err);
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn finish_recording(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.flush() {
+                eprintln!("[SIMULATION] Couldn't finish writing the recording: {}", err);
+            }
+        }
+    }
+
+    // The state needed to carry on the run later
+    fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            time: self.i,
+            passenger_id: self.passenger_id,
+            fleet_changes: self.fleet_changes.iter().map(|(_, change)| change.clone()).collect(),
+            controller_switches: self.controller_switches.clone(),
+            handing_over: self.handing_over,
+            active: Some(self.active_controller()),
+            dynamic: self.runs_dynamic().then(|| self.dyn_controller.save_state()),
+            fixed_route: self.runs_static().then(|| self.static_controller.save_state()),
+            demand_seed: self.demand_generator.as_ref().unwrap().checkpoint_rng(),
+            service_day: Some(self.service_day),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.i = snapshot.time;
+        self.passenger_id = snapshot.passenger_id;
+        self.fleet_changes.clear();
+        self.schedule_fleet_changes(snapshot.fleet_changes);
+        self.controller_switches = snapshot.controller_switches;
+        self.handing_over = snapshot.handing_over;
+        if let Some(active) = snapshot.active {
+            self.activate(active);
+        }
+        if let Some(state) = snapshot.dynamic {
+            self.dyn_controller.restore_state(state, self.graph.clone());
+        }
+        if let Some(state) = snapshot.fixed_route {
+            self.static_controller.restore_state(state, self.graph.clone());
+        }
+        self.demand_generator.as_ref().unwrap().set_rng(SimRng::seeded(snapshot.demand_seed));
+    }
+
+    // Queue fleet changes for the first time of day they name at or after the start of the run, so
+    // one after midnight in a late night run waits until then
+    fn schedule_fleet_changes(&mut self, changes: Vec<FleetChange>) {
+        let start = self.run_window(&self.config).start;
+        self.fleet_changes.extend(changes.into_iter().map(|change| (switching::first_at_or_after(change.at, start), change)));
+    }
+
+    // Apply any scheduled fleet changes which are now due
+    fn apply_fleet_changes(&mut self) {
+        let now = self.i;
+        let (due, pending): (Vec<_>, Vec<_>) = self.fleet_changes.drain(..).partition(|(at, _)| *at <= now);
+        self.fleet_changes = pending;
+
+        for (_, change) in due {
+            if !self.runs_dynamic() {
+                println!("[SIMULATION] Ignoring fleet change {:?}, the static controller has a fixed fleet", change.parameter);
+                continue;
+            }
+
+            let result = self.dyn_controller.apply_fleet_parameter(change.parameter, self.graph.clone());
+            println!("[SIMULATION] Fleet change at {}: {}", self.i, result);
+            send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::FleetChange { time: self.i, description: result }));
+        }
+    }
+
+    // Switch to another controller if one's due, handing over who the last one won't serve
+    fn apply_controller_switches(&mut self) {
+        let now = self.i;
+        let (due, pending): (Vec<_>, Vec<_>) = self.controller_switches.drain(..).partition(|switch| switch.at <= now);
+        self.controller_switches = pending;
+        let Some(switch) = due.last() else {
+            return;
+        };
+
+        let next_id = self.passenger_id.max(self.static_controller.next_passenger_id()).max(self.dyn_controller.next_passenger_id());
+        self.passenger_id = next_id;
+        self.static_controller.set_next_passenger_id(next_id);
+        self.dyn_controller.set_next_passenger_id(next_id);
+
+        let description = format!("Switched from {} to {}", self.active_controller().description(), switch.to.description());
+        // Both fleets keep who they have when switching to or from mixed
+        self.handing_over = self.active_controller() != ActiveController::Mixed && switch.to != ActiveController::Mixed;
+        self.activate(switch.to);
+        if switch.to.drt() && self.dyn_controller.get_agents().is_empty() {
+            self.spawn_drt_fleet();
+        }
+        println!("[SIMULATION] {} at {}", description, self.i);
+        send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::FleetChange { time: self.i, description }));
+    }
+
+    fn activate(&mut self, active: ActiveController) {
+        self.static_only = active == ActiveController::FixedRoute;
+        self.mixed = match active {
+            ActiveController::Mixed => Some(self.config.mixed.clone().unwrap_or_default()),
+            _ => None,
+        };
+        self.static_controller.set_winding_down(!active.fixed_route());
+    }
+
+    fn active_controller(&self) -> ActiveController {
+        match (self.mixed.is_some(), self.static_only) {
+            (true, _) => ActiveController::Mixed,
+            (false, true) => ActiveController::FixedRoute,
+            (false, false) => ActiveController::Drt,
+        }
+    }
+
+    fn spawn_drt_fleet(&mut self) {
+        for _ in 0..self.dynamic_agent_count {
+            self.dyn_controller.spawn_agent(self.graph.clone());
+        }
+    }
+
+    // Passengers still waiting for the fixed route buses after a switch to DRT ask DRT instead
+    fn hand_over_to_drt(&mut self) {
+        let router = self.router.as_deref().expect("Simulation has no router");
+        for passenger in self.static_controller.take_waiting() {
+            self.dyn_controller.accept_passenger(fixed_route_to_drt(passenger, router, self.i));
+        }
+    }
+
+    // DRT requests no bus has been given after a switch to fixed routes take the bus if there is one
+    fn hand_over_to_fixed_route(&mut self) {
+        let router = self.router.as_deref().expect("Simulation has no router");
+        let no_fixed_route = &mut self.no_fixed_route;
+        for passenger in self.dyn_controller.take_unassigned(|passenger| !no_fixed_route.contains(&passenger.id)) {
+            match drt_to_fixed_route(passenger, router, self.i, self.analytics_tx.clone()) {
+                Ok(passenger) => self.static_controller.accept_passenger(passenger),
+                Err(passenger) => {
+                    no_fixed_route.insert(passenger.id);
+                    self.dyn_controller.accept_passenger(passenger);
+                }
+            }
+        }
+    }
+
     pub fn tick(&mut self) {
+        if let Some(replay) = self.replay.as_mut() {
+            self.i = self.i + chrono::Duration::seconds(self.tick_seconds as i64);
+            replay.seek(self.graph.clone(), self.i);
+            return;
+        }
+
+        // Fleet changes happen before anything moves so the whole tick sees the same fleet
+        self.apply_fleet_changes();
+        self.apply_controller_switches();
+
         // Do a tick
-        self.i = self.i + (chrono::Duration::minutes(1));
+        self.i = self.i + self.tick_length();
 
         // Despatch Demand Handler to get some more demand
         // self.demand_generator.as_ref().unwrap().tick(self.i);
 
         // println!("Sim tick {:?}", self.i);
-        if !self.static_only {
+        if self.mixed.is_some() {
+            self.assign_demand();
+            self.static_controller.step(self.graph.clone(), self.i);
+            self.dyn_controller.step(self.graph.clone(), self.i);
+            if self.escalation.is_some() {
+                self.escalate();
+            }
+        } else if !self.static_only {
             self.dyn_controller.update_agents(
                 self.graph.clone(),
                 self.demand_generator.as_ref().unwrap().clone(),
                 self.i,
-            )
+            );
+
+            // Fixed routes winding down after a switch
+            if self.runs_static() {
+                self.static_controller.step(self.graph.clone(), self.i);
+                if self.handing_over {
+                    self.hand_over_to_drt();
+                }
+            }
         } else {
             self.static_controller.update_agents(
                 self.graph.clone(),
                 self.demand_generator.as_ref().unwrap().clone(),
                 self.i,
-            )
+            );
+
+            if self.runs_dynamic() {
+                self.dyn_controller.step(self.graph.clone(), self.i);
+            }
+            if self.escalation.is_some() {
+                self.escalate();
+            } else if self.runs_dynamic() && self.handing_over {
+                // DRT winding down after a switch
+                self.hand_over_to_fixed_route();
+            }
+        }
+
+        let rejected = self.demand_generator.as_ref().unwrap().take_rejected();
+        if rejected > 0 {
+            send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::DemandRejected { count: rejected }));
+        }
+
+        let (clamped, dropped) = self.demand_generator.as_ref().unwrap().take_out_of_bounds();
+        if clamped > 0 || dropped > 0 {
+            println!("[Demand] {} generated points clamped onto the map and {} demands dropped with nowhere to go at {}", clamped, dropped, self.i);
+        }
+    }
+
+    // Start, end and ticks of a run of `config` in the service day
+    fn run_window(&self, config: &SimulationConfig) -> RunWindow {
+        let start_time = config.start_time.unwrap_or_else(default_start_time);
+        let end_time = config.end_time.unwrap_or_else(default_end_time);
+        let start = DateTime::from_utc(NaiveDateTime::new(self.service_day, start_time), Utc);
+        let mut end = start + (end_time - start_time);
+        if end_time <= start_time {
+            end += chrono::Duration::days(1);
+        }
+        let tick_seconds = config.tick_seconds.filter(|seconds| *seconds > 0).unwrap_or(DEFAULT_TICK_SECONDS);
+        RunWindow { start, end, tick_seconds, scale: config.demand_scale * tick_seconds as f64 / 60.0 }
+    }
+
+    fn tick_length(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.tick_seconds as i64)
+    }
+
+    // Demand levels are per minute, so shorter ticks each get a share of them
+    fn tick_demand_scale(&self) -> f64 {
+        self.demand_scale * self.tick_seconds as f64 / 60.0
+    }
+
+    // Does the fixed route network run at any point in this simulation
+    fn runs_static(&self) -> bool {
+        self.fixed_route_active() || self.config.controller_switches.iter().any(|switch| switch.to.fixed_route())
+    }
+
+    // Does a DRT fleet run at any point in this simulation
+    fn runs_dynamic(&self) -> bool {
+        self.drt_active() || self.escalation.is_some() || self.config.controller_switches.iter().any(|switch| switch.to.drt())
+    }
+
+    // Is new demand going to the fixed route network now
+    fn fixed_route_active(&self) -> bool {
+        self.static_only || self.mixed.is_some()
+    }
+
+    // Is new demand going to DRT now
+    fn drt_active(&self) -> bool {
+        !self.static_only || self.mixed.is_some()
+    }
+
+    // Generate this tick's demand and hand each passenger to the mode which gets them there first
+    fn assign_demand(&mut self) {
+        let demand = self.demand_generator.as_ref().unwrap().generate_scaled_amount(self.tick_demand_scale(), &self.i, Ok(self.graph.clone()));
+        let (fixed_route, drt) = self.assign(demand);
+        println!("[SIMULATION] Demand Generated: {} fixed route, {} DRT", fixed_route, drt);
+    }
+
+    // Give each demand to the mode chooser, returning how many went to fixed route and to DRT
+    fn assign(&mut self, demand: impl IntoIterator<Item = Demand>) -> (u32, u32) {
+        let chooser = ModeChooser {
+            router: self.router.as_deref().expect("Simulation has no router"),
+            network_data: &self.network_data,
+            graph: &self.graph,
+            drt_speed: self.dyn_controller.bus_speed(),
+            config: self.mixed.as_ref().unwrap(),
+            walking: self.config.walking,
+        };
+
+        let (mut fixed_route, mut drt) = (0, 0);
+        for demand in demand {
+            match chooser.assign(demand, self.passenger_id, self.analytics_tx.clone()) {
+                Assignment::FixedRoute(passenger) => {
+                    fixed_route += 1;
+                    passenger.requested(self.i);
+                    self.static_controller.accept_passenger(passenger);
+                }
+                Assignment::Drt(passenger) => {
+                    drt += 1;
+                    passenger.requested(&self.analytics_tx);
+                    self.dyn_controller.accept_passenger(passenger);
+                }
+                Assignment::Unreachable(passenger) => {
+                    let unserved = PassengerAnalyticsEvent::Unserved { reason: UnservedReason::Disconnected, origin: passenger.source_pos, destination: passenger.dest_pos };
+                    send_analytics(&self.analytics_tx, AnalyticsPackage::PassengerEvent(unserved));
+                }
+            }
+            self.passenger_id += 1;
+        }
+        (fixed_route, drt)
+    }
+
+    // Add a request clicked on the map, going to whichever controller would take generated demand
+    fn inject_demand(&mut self, origin: (f64, f64), destination: (f64, f64)) {
+        let demand = Demand((origin.0 as f32, origin.1 as f32), (destination.0 as f32, destination.1 as f32), self.i, None);
+        if self.mixed.is_some() {
+            let mode = match self.assign(vec![demand]) {
+                (1, _) => "fixed route",
+                (_, 1) => "DRT",
+                _ => "neither, there's no way there",
+            };
+            println!("[SIMULATION] Injected demand {:?} -> {:?} by {}", origin, destination, mode);
+        } else if !self.static_only {
+            let id = self.dyn_controller.inject_demand(demand);
+            println!("[SIMULATION] Injected demand {:?} -> {:?} as passenger {}", origin, destination, id);
+        } else {
+            match self.static_controller.inject_demand(demand, self.i) {
+                Some(id) => println!("[SIMULATION] Injected demand {:?} -> {:?} as passenger {}", origin, destination, id),
+                None => println!("[SIMULATION] Injected demand {:?} -> {:?} has no bus journey, ignoring", origin, destination),
+            }
+        }
+    }
+
+    // Swap passengers between the fixed route and DRT fleets when they've waited too long
+    fn escalate(&mut self) {
+        let config = self.escalation.as_ref().unwrap();
+        let router = self.router.as_deref().expect("Simulation has no router");
+
+        // Waits at stops are counted in ticks
+        let max_wait = (config.fixed_route_wait * 60).div_ceil(self.tick_seconds);
+        for passenger in self.static_controller.take_long_waits(max_wait) {
+            let passenger = fixed_route_to_drt(passenger, router, self.i);
+            send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::Escalation { passenger_id: passenger.id, escalation: passenger.escalations.last().unwrap().clone() }));
+            self.dyn_controller.accept_passenger(passenger);
+        }
+
+        for passenger in self.dyn_controller.take_refused(self.i, chrono::Duration::minutes(config.drt_refusal_wait)) {
+            match drt_to_fixed_route(passenger, router, self.i, self.analytics_tx.clone()) {
+                Ok(passenger) => {
+                    send_analytics(&self.analytics_tx, AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::Escalation { passenger_id: passenger.id, escalation: passenger.escalations.last().unwrap().clone() }));
+                    self.static_controller.accept_passenger(passenger);
+                }
+                // No bus journey either, keep waiting for DRT
+                Err(passenger) => self.dyn_controller.accept_passenger(passenger),
+            }
         }
     }
 }
@@ -328,6 +1180,54 @@ pub trait Controller {
         demand: Arc<DemandGenerator>,
         time: DateTime<Utc>,
     );
+
+    // Requests made but not yet on a vehicle, whether or not one has been given them
+    fn queued_requests(&self) -> usize {
+        0
+    }
+
+    // Every passenger not yet delivered, with what they're doing and what's next for them
+    fn snapshot_passengers(&self) -> Vec<PassengerSnapshot> {
+        Vec::new()
+    }
+
+    // Ids (as the controller numbers them) and positions of the vehicles in service
+    fn vehicle_positions(&self) -> Vec<(u32, (f64, f64))> {
+        self.get_agents()
+            .into_iter()
+            .filter(|agent| agent.get_state() != AgentState::OutOfService)
+            .map(|agent| (agent.get_id(), agent.get_position()))
+            .collect()
+    }
+
+    fn summary(&self) -> ControllerSummary {
+        let mut summary = ControllerSummary { queued_requests: self.queued_requests(), ..Default::default() };
+        for agent in self.get_agents().into_iter().filter(|agent| agent.get_state() != AgentState::OutOfService) {
+            summary.active_vehicles += 1;
+            summary.occupancy += agent.get_occupancy();
+            summary.capacity += agent.get_capacity();
+        }
+        summary
+    }
+}
+
+/// What a vehicle is doing, in the same terms whichever controller runs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum AgentState {
+    #[default]
+    Moving,
+    Stopped, // Serving a stop, or waiting for passengers it's been given
+    Idle, // In service with nobody on board or assigned
+    OutOfService, // Not running, e.g. a fixed route bus with no trip under way
+}
+
+/// Totals over a controller's vehicles in service, for showing every controller the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ControllerSummary {
+    pub active_vehicles: usize,
+    pub occupancy: usize, // Passengers on board
+    pub capacity: usize, // Seats on the vehicles in service
+    pub queued_requests: usize,
 }
 
 pub trait Agent {
@@ -336,6 +1236,11 @@ pub trait Agent {
     fn get_current_element(&self) -> CurrentElement;
     fn get_next_node(&self) -> u128;
 
+    fn get_id(&self) -> u32; // As used in the analytics
+    fn get_capacity(&self) -> usize;
+    fn get_occupancy(&self) -> usize;
+    fn get_state(&self) -> AgentState;
+
     // get a shape representing the agent -- default just based on current position and node/edge information
     fn display(&self) -> Shape {
         default_display(self)
@@ -395,3 +1300,145 @@ pub fn default_display<T: Agent + ?Sized>(agent: &T) -> Shape {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finished_runs_only_leave_by_restarting() {
+        use SimulationState::*;
+        assert_eq!(Idle.transition(Running), Some(Running));
+        assert_eq!(Running.transition(Paused), Some(Paused));
+        assert_eq!(Paused.transition(Running), Some(Running));
+        assert_eq!(Paused.transition(Finished), Some(Finished));
+        assert_eq!(Idle.transition(Paused), None);
+        assert_eq!(Finished.transition(Running), None);
+        assert_eq!(Finished.transition(Idle), None);
+    }
+
+    // Counts the fixed route journeys asked for
+    struct CountingRouter(Router, std::sync::atomic::AtomicUsize);
+
+    impl DemandRouter for CountingRouter {
+        fn closest_node(&self, point: (f64, f64)) -> u128 {
+            self.0.closest_node(point)
+        }
+
+        fn connected(&self, source: (f64, f64), dest: (f64, f64)) -> bool {
+            self.0.connected(source, dest)
+        }
+
+        fn closest_stop(&self, point: (f64, f64)) -> Option<(u32, f64)> {
+            self.0.closest_stop(point)
+        }
+
+        fn stop_position(&self, stop: u32) -> Option<(f64, f64)> {
+            self.0.stop_position(stop)
+        }
+
+        fn plan_itinerary(&self, source: (f64, f64), dest: (f64, f64), tick: DateTime<Utc>) -> Option<router::Itinerary> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.0.plan_itinerary(source, dest, tick)
+        }
+    }
+
+    // A run of `config` on a grid with no demand, starting on 1st May 2023
+    fn simulation(config: SimulationConfig, router: impl FnOnce(Arc<Graph>) -> Arc<dyn DemandRouter>) -> Simulation {
+        let graph = Arc::new(crate::graph::synthetic::grid(5, 100.0));
+        let demand = LoadedDemand::RequestLog(demand::request_log::RequestLog::new(vec![]));
+        let mut sim = Simulation::default();
+        sim.router = Some(router(graph.clone()));
+        sim.demand_generator = Some(DemandGenerator::start(demand, graph.clone(), Ok(graph.clone()), config.demand_policy.build(config.walking), SimRng::seeded(1)));
+        sim.graph = graph;
+        sim.service_day = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        sim.config = config;
+        sim.reset();
+        sim
+    }
+
+    // Tick from `hour`:`minute`, on the 2nd for times before midday
+    fn tick_at(sim: &mut Simulation, hour: u32, minute: u32) {
+        use chrono::TimeZone;
+        let day = if hour < 12 { 2 } else { 1 };
+        sim.i = Utc.with_ymd_and_hms(2023, 5, day, hour, minute, 0).unwrap();
+        sim.tick();
+    }
+
+    #[test]
+    fn fleet_changes_after_midnight_wait_for_it() {
+        let at = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let config = SimulationConfig {
+            start_time: Some(at(22)),
+            end_time: Some(at(2)),
+            tick_seconds: Some(60),
+            fleet_changes: vec![
+                FleetChange { at: at(1), parameter: FleetParameter::FleetSize(1) },
+                FleetChange { at: at(23), parameter: FleetParameter::FleetSize(2) },
+            ],
+            ..Default::default()
+        };
+        let mut sim = simulation(config, |graph| Arc::new(Router::new(graph, None)));
+
+        tick_at(&mut sim, 22, 0);
+        assert_eq!(sim.dyn_controller.get_agents().len(), 0);
+        tick_at(&mut sim, 23, 0);
+        assert_eq!(sim.dyn_controller.get_agents().len(), 2);
+        tick_at(&mut sim, 1, 0);
+        assert_eq!(sim.dyn_controller.get_agents().len(), 1);
+        assert!(sim.fleet_changes.is_empty());
+    }
+
+    #[test]
+    fn switches_happen_in_the_run_and_only_hand_over_once() {
+        use static_controller::agent::{BusPassenger, PassengerStatus};
+
+        let router = Arc::new(CountingRouter(Router::new(Arc::new(crate::graph::synthetic::grid(5, 100.0)), None), Default::default()));
+        let at = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+        let switch = |hour, minute, to| ControllerSwitch { at: at(hour, minute), to };
+        let config = SimulationConfig {
+            static_only: true,
+            start_time: Some(at(23, 0)),
+            end_time: Some(at(1, 0)),
+            tick_seconds: Some(60),
+            controller_switches: vec![
+                switch(0, 30, ActiveController::Drt),
+                switch(0, 40, ActiveController::FixedRoute),
+                switch(0, 50, ActiveController::Mixed),
+                switch(0, 55, ActiveController::Drt),
+                switch(2, 0, ActiveController::FixedRoute), // After the run
+            ],
+            ..Default::default()
+        };
+        let mut sim = simulation(config, |_| router.clone());
+        assert_eq!(sim.controller_switches.len(), 4);
+        let waiting = || BusPassenger { id: 1, source_pos: (0.0, 0.0), dest_pos: (400.0, 400.0), status: PassengerStatus::Waiting, ..Default::default() };
+
+        // 00:30 is after midnight, not before the 23:00 start
+        tick_at(&mut sim, 23, 0);
+        assert_eq!(sim.active_controller(), ActiveController::FixedRoute);
+
+        // Whoever is waiting for a bus at the switch asks DRT instead
+        sim.static_controller.accept_passenger(waiting());
+        tick_at(&mut sim, 0, 30);
+        assert_eq!(sim.active_controller(), ActiveController::Drt);
+        assert!(sim.static_controller.take_waiting().is_empty());
+        assert_eq!(sim.dyn_controller.queued_requests(), 1);
+
+        // With no bus journey they stay with DRT, and aren't planned again every tick
+        tick_at(&mut sim, 0, 40);
+        tick_at(&mut sim, 0, 41);
+        assert_eq!(sim.active_controller(), ActiveController::FixedRoute);
+        assert_eq!(router.1.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(sim.dyn_controller.queued_requests(), 1);
+
+        // Leaving mixed mode both fleets keep who they have
+        tick_at(&mut sim, 0, 50);
+        sim.static_controller.accept_passenger(waiting());
+        tick_at(&mut sim, 0, 55);
+        tick_at(&mut sim, 0, 56);
+        assert_eq!(sim.active_controller(), ActiveController::Drt);
+        assert_eq!(sim.static_controller.take_waiting().len(), 1);
+        assert_eq!(sim.dyn_controller.queued_requests(), 1);
+    }
+}
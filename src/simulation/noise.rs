@@ -0,0 +1,104 @@
+//! Random variation on vehicle travel speeds and stop dwell times.
+//!
+//! Without noise every replication of a scenario gives the same trajectories. Both factors are
+//! lognormal with a mean of 1, so `sigma` controls the spread without changing average speeds.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct NoiseConfig {
+    #[serde(default)]
+    pub travel_sigma: f64, // Spread of the per tick speed multiplier, 0 turns travel noise off
+    #[serde(default)]
+    pub dwell_sigma: f64, // Spread of the dwell time multiplier, 0 turns dwell noise off
+    #[serde(default)]
+    pub dwell_seconds: f64, // Mean time a bus loses at a stop where passengers get on or off
+    pub seed: Option<u64>, // Fixed seed so a replication can be repeated exactly
+}
+
+pub struct Noise {
+    config: NoiseConfig,
+    rng: StdRng,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise::new(NoiseConfig::default())
+    }
+}
+
+impl Noise {
+    pub fn new(config: NoiseConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Noise { config, rng }
+    }
+
+    // Reseed from a seed drawn from the current generator and return it, see `SimRng::checkpoint`
+    pub fn checkpoint(&mut self) -> u64 {
+        let seed = self.rng.gen();
+        self.reseed(seed);
+        seed
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // Multiplier on how far a vehicle travels this tick
+    pub fn travel_factor(&mut self) -> f64 {
+        self.lognormal(self.config.travel_sigma)
+    }
+
+    // Seconds a vehicle spends at a stop where passengers got on or off
+    pub fn dwell_seconds(&mut self) -> f64 {
+        if self.config.dwell_seconds <= 0.0 {
+            return 0.0;
+        }
+        self.config.dwell_seconds * self.lognormal(self.config.dwell_sigma)
+    }
+
+    // Lognormal sample with mean 1
+    fn lognormal(&mut self, sigma: f64) -> f64 {
+        if sigma <= 0.0 {
+            return 1.0;
+        }
+        let mu = -sigma * sigma / 2.0;
+        (mu + sigma * standard_normal(&mut self.rng)).exp()
+    }
+}
+
+// Box-Muller transform
+fn standard_normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>(); // (0, 1] so the log is finite
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_sigma_means_no_noise() {
+        let mut noise = Noise::new(NoiseConfig { dwell_seconds: 20.0, ..Default::default() });
+        assert_eq!(noise.travel_factor(), 1.0);
+        assert_eq!(noise.dwell_seconds(), 20.0);
+    }
+
+    #[test]
+    fn seeded_noise_repeats_with_mean_one() {
+        let config = NoiseConfig { travel_sigma: 0.3, seed: Some(7), ..Default::default() };
+        let mut a = Noise::new(config.clone());
+        let mut b = Noise::new(config);
+
+        let samples: Vec<f64> = (0..10000).map(|_| a.travel_factor()).collect();
+        assert!(samples.iter().zip((0..10000).map(|_| b.travel_factor())).all(|(x, y)| *x == y));
+
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!((mean - 1.0).abs() < 0.02, "mean was {}", mean);
+    }
+}
@@ -0,0 +1,58 @@
+//! Every passenger's state at one moment, so how the population changes over a run (queue
+//! lengths, how many are riding, how long the waits get) can be looked at without replaying the
+//! events. Each controller summarises its own passengers with `Controller::snapshot_passengers`.
+//!
+//! With `record` set, the recorder also writes one every so often as a line of JSON next to the log:
+//!
+//! ```toml
+//! [simulation]
+//! record = "output/run.cbor"
+//! record_passengers_minutes = 15 # Written to output/run.passengers.jsonl
+//! ```
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::escalation::ServiceMode;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PassengerState {
+    Unassigned, // A DRT request no bus has been given yet
+    WalkingToStop,
+    Waiting,
+    Riding,
+    WalkingToDestination,
+}
+
+/// One passenger's status and what's next in their plan
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PassengerSnapshot {
+    pub id: u32,
+    pub mode: ServiceMode,
+    pub state: PassengerState,
+    pub pos: (f64, f64),
+    pub destination: (f64, f64),
+    pub vehicle: Option<u32>, // The vehicle they're on, or the DRT bus they've been given
+    pub next_stop: Option<(f64, f64)>, // Where they next get on or off a vehicle
+    pub rides_left: usize, // Vehicles still to ride, counting the one they're on
+}
+
+/// What's kept of a passenger once they've reached their destination, the rest of their journey
+/// went to the analytics as it happened. The controllers archive these and drop the passenger so
+/// each tick only goes through passengers still travelling
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Completed {
+    pub id: u32,
+    pub mode: ServiceMode, // The controller which delivered them
+    pub origin: (f64, f64), // Where their last vehicle's leg started, the meeting node if they changed DRT bus
+    pub destination: (f64, f64),
+    pub time: DateTime<Utc>, // The tick they arrived in
+}
+
+/// Every passenger not yet delivered, at `time`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Population {
+    pub time: DateTime<Utc>,
+    pub passengers: Vec<PassengerSnapshot>,
+}
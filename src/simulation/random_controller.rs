@@ -6,12 +6,13 @@ use rand::Rng;
 
 use crate::graph::Graph;
 
-use super::{Controller, Agent, demand::DemandGenerator};
+use super::{Controller, Agent, AgentState, demand::DemandGenerator, rng::SimRng};
 
 #[derive(Default, Debug)]
 pub struct RandomController {
     pub agentc: usize,
     pub agents: Vec<RandomAgent>,
+    pub rng: SimRng,
 }
 
 #[derive(Debug)]
@@ -46,6 +47,23 @@ impl Agent for RandomAgent {
     fn get_position(&self) -> (f64, f64) {
         self.position
     }
+
+    fn get_id(&self) -> u32 {
+        self.id as u32
+    }
+
+    // Random agents only drive around, they never carry anyone
+    fn get_capacity(&self) -> usize {
+        0
+    }
+
+    fn get_occupancy(&self) -> usize {
+        0
+    }
+
+    fn get_state(&self) -> AgentState {
+        AgentState::Moving
+    }
 }
 
 impl Controller for RandomController {
@@ -57,10 +75,10 @@ impl Controller for RandomController {
 
     fn spawn_agent(&mut self, graph: std::sync::Arc<crate::graph::Graph>) -> Option<&Self::Agent> {
         self.agentc += 1;
-        let mut rng = rand::thread_rng();
+        let rng = &mut self.rng;
 
-        let random_node_i = rng.gen_range(0..=graph.get_nodelist().len() - 1);
-        let node = graph.get_nodelist().keys().nth(random_node_i).unwrap();
+        let random_node_i = rng.gen_range(0..=graph.get_node_ids().len() - 1);
+        let node = &graph.get_node_ids()[random_node_i];
         let adjacency = graph.get_adjacency().get(node).unwrap();
         let random_edge_i = rng.gen_range(0..=adjacency.len() - 1);
         let edge = adjacency.get(random_edge_i).unwrap();
@@ -81,13 +99,13 @@ impl Controller for RandomController {
     fn update_agents(&mut self, graph: std::sync::Arc<crate::graph::Graph>, _demand: Arc<DemandGenerator>, _time: DateTime<Utc>) {
         // self.agents.iter_mut().for_each(|agent| self.move_agent(agent, graph.clone()));
         for agent in self.agents.iter_mut() {
-            Self::move_agent(agent, graph.clone());
+            Self::move_agent(agent, graph.clone(), &mut self.rng);
         }
     }
 }
 
 impl RandomController {
-    fn move_agent(agent: &mut RandomAgent, graph: Arc<Graph>) {
+    fn move_agent(agent: &mut RandomAgent, graph: Arc<Graph>, rng: &mut SimRng) {
         let mut distance_to_move = agent.velocity as f32;
         //println!("NEW AGENT agent #{:?} moving {:?}", agent.id, distance_to_move);
         while distance_to_move > 0.0 {
@@ -153,7 +171,7 @@ impl RandomController {
                 agent.prev_node = next_node_id;
                 let adjacency = graph.get_adjacency().get(&next_node_id).unwrap();
                 loop {
-                    let next_edge_i = rand::thread_rng().gen_range(0..=adjacency.len() - 1);
+                    let next_edge_i = rng.gen_range(0..=adjacency.len() - 1);
                     agent.cur_edge = adjacency.get(next_edge_i).unwrap().clone();
                     let current_edge = graph
                         .get_edgelist()
@@ -0,0 +1,317 @@
+//! Recording a run tick by tick and playing it back in the GUI without simulating anything.
+//!
+//! With `record` set in `[simulation]` every vehicle's position and every passenger's state is
+//! appended to a CBOR log after each tick. Starting a config with `replay` set to that log shows
+//! the recorded run instead, at whatever tick speed is chosen, so results can be looked over
+//! without running the LNS again. Replays need the same road graph the run was recorded on.
+//!
+//! The recorder can also write every passenger's state every so often, see population.rs.
+
+use std::{
+    error::Error,
+    fs,
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use eframe::epaint::{pos2, Color32, Shape};
+use serde::{Deserialize, Serialize};
+
+use crate::graph::Graph;
+
+use super::{demand::DemandGenerator, display::DisplayLayer, dyn_controller::bus::CurrentElement, population::Population, Agent, AgentState, Controller};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RecordedVehicle {
+    pub id: u32,
+    pub pos: (f32, f32),
+    pub element: CurrentElement,
+    pub next_node: u128,
+    pub onboard: u16,
+    #[serde(default)]
+    pub capacity: u16,
+    #[serde(default)]
+    pub state: AgentState,
+}
+
+impl RecordedVehicle {
+    pub fn of<A: Agent>(agent: &A) -> Self {
+        let pos = agent.get_position();
+        RecordedVehicle {
+            id: agent.get_id(),
+            pos: (pos.0 as f32, pos.1 as f32),
+            element: agent.get_current_element(),
+            next_node: agent.get_next_node(),
+            onboard: agent.get_occupancy() as u16,
+            capacity: agent.get_capacity() as u16,
+            state: agent.get_state(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedStatus {
+    Walking,
+    Waiting,
+    Riding,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RecordedPassenger {
+    pub id: u32,
+    pub pos: (f32, f32),
+    pub status: RecordedStatus,
+}
+
+impl RecordedPassenger {
+    pub fn new(id: u32, pos: (f64, f64), status: RecordedStatus) -> Self {
+        RecordedPassenger { id, pos: (pos.0 as f32, pos.1 as f32), status }
+    }
+}
+
+/// Everything drawn on the map at the end of one tick
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Frame {
+    pub time: DateTime<Utc>,
+    pub vehicles: Vec<RecordedVehicle>,
+    pub passengers: Vec<RecordedPassenger>,
+}
+
+/// Appends frames to a log as the simulation runs
+pub struct Recorder {
+    writer: BufWriter<fs::File>,
+    population: Option<PopulationWriter>,
+}
+
+// Every passenger's state as JSON lines, at most once per `every`
+struct PopulationWriter {
+    writer: BufWriter<fs::File>,
+    every: Duration,
+    last: Option<DateTime<Utc>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Recorder { writer: BufWriter::new(fs::File::create(path)?), population: None })
+    }
+
+    // Also write the passengers every `minutes` minutes, next to the log at `path`
+    pub fn with_population(mut self, path: &Path, minutes: u32) -> Result<Self, Box<dyn Error>> {
+        let writer = BufWriter::new(fs::File::create(path.with_extension("passengers.jsonl"))?);
+        self.population = Some(PopulationWriter { writer, every: Duration::minutes(minutes as i64), last: None });
+        Ok(self)
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<(), Box<dyn Error>> {
+        ciborium::ser::into_writer(frame, &mut self.writer)?;
+        Ok(())
+    }
+
+    // Is it time to write the passengers again
+    pub fn population_due(&self, time: DateTime<Utc>) -> bool {
+        self.population.as_ref().is_some_and(|population| population.last.is_none_or(|last| time - last >= population.every))
+    }
+
+    pub fn record_population(&mut self, population: &Population) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = self.population.as_mut() {
+            serde_json::to_writer(&mut writer.writer, population)?;
+            writeln!(writer.writer)?;
+            writer.last = Some(population.time);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(population) = self.population.as_mut() {
+            population.writer.flush()?;
+        }
+        self.writer.flush()
+    }
+}
+
+// Every frame in a log, in the order they were recorded
+pub fn read_log(path: &Path) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut frames = Vec::new();
+    loop {
+        match ciborium::de::from_reader::<Frame, _>(&mut reader) {
+            Ok(frame) => frames.push(frame),
+            // The log is a run of frames with nothing after the last one
+            Err(ciborium::de::Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(frames)
+}
+
+/// A recorded vehicle drawn the same way as a live one
+pub struct ReplayAgent {
+    graph: Arc<Graph>,
+    vehicle: RecordedVehicle,
+}
+
+impl Agent for ReplayAgent {
+    fn get_graph(&self) -> Arc<Graph> {
+        self.graph.clone()
+    }
+
+    fn get_position(&self) -> (f64, f64) {
+        (self.vehicle.pos.0 as f64, self.vehicle.pos.1 as f64)
+    }
+
+    fn get_current_element(&self) -> CurrentElement {
+        self.vehicle.element
+    }
+
+    fn get_next_node(&self) -> u128 {
+        self.vehicle.next_node
+    }
+
+    fn get_id(&self) -> u32 {
+        self.vehicle.id
+    }
+
+    fn get_capacity(&self) -> usize {
+        self.vehicle.capacity as usize
+    }
+
+    fn get_occupancy(&self) -> usize {
+        self.vehicle.onboard as usize
+    }
+
+    fn get_state(&self) -> AgentState {
+        self.vehicle.state
+    }
+}
+
+/// Plays a recorded log back, showing the last frame at or before the simulation time
+#[derive(Default)]
+pub struct ReplayController {
+    frames: Vec<Frame>,
+    current: usize,
+    agents: Vec<ReplayAgent>,
+}
+
+impl ReplayController {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let frames = read_log(path)?;
+        if frames.is_empty() {
+            return Err(format!("Replay log {:?} has no frames", path).into());
+        }
+        Ok(ReplayController { frames, current: 0, agents: Vec::new() })
+    }
+
+    // Times of the first and last frames
+    pub fn time_span(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        (self.frames[0].time, self.frames[self.frames.len() - 1].time)
+    }
+
+    pub fn current_frame(&self) -> &Frame {
+        &self.frames[self.current]
+    }
+
+    // Move on to the last frame at or before `time`
+    pub fn seek(&mut self, graph: Arc<Graph>, time: DateTime<Utc>) {
+        while self.current + 1 < self.frames.len() && self.frames[self.current + 1].time <= time {
+            self.current += 1;
+        }
+        self.agents = self.frames[self.current].vehicles.iter().map(|vehicle| ReplayAgent { graph: graph.clone(), vehicle: *vehicle }).collect();
+    }
+
+    pub fn get_display(&self) -> Vec<(DisplayLayer, Vec<Shape>)> {
+        let vehicles = self.agents.iter().map(|agent| agent.display()).collect();
+        let passengers = self.current_frame().passengers.iter().filter(|p| p.status != RecordedStatus::Riding).map(|passenger| {
+            let colour = if passenger.status == RecordedStatus::Waiting { Color32::LIGHT_RED } else { Color32::LIGHT_BLUE };
+            Shape::circle_filled(pos2(passenger.pos.0, passenger.pos.1), 1.0, colour)
+        }).collect();
+        vec![(DisplayLayer::Vehicles, vehicles), (DisplayLayer::Passengers, passengers)]
+    }
+}
+
+impl Controller for ReplayController {
+    type Agent = ReplayAgent;
+
+    // The recording decides which vehicles there are
+    fn spawn_agent(&mut self, _graph: Arc<Graph>) -> Option<&Self::Agent> {
+        None
+    }
+
+    fn get_agents(&self) -> Vec<&Self::Agent> {
+        self.agents.iter().collect()
+    }
+
+    fn update_agents(&mut self, graph: Arc<Graph>, _demand: Arc<DemandGenerator>, time: DateTime<Utc>) {
+        self.seek(graph, time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::simulation::{escalation::ServiceMode, population::{PassengerSnapshot, PassengerState}};
+
+    use super::*;
+
+    #[test]
+    fn plays_back_what_was_recorded() {
+        let start = Utc::now();
+        let frame = |minute: i64, x: f32| Frame {
+            time: start + Duration::minutes(minute),
+            vehicles: vec![RecordedVehicle { id: 1, pos: (x, 0.0), element: CurrentElement::PreGenerated, next_node: 0, onboard: 0, capacity: 20, state: AgentState::Moving }],
+            passengers: vec![RecordedPassenger { id: 4, pos: (0.0, 0.0), status: RecordedStatus::Waiting }],
+        };
+
+        let path = std::env::temp_dir().join("odbrs-replay-test.cbor");
+        let mut recorder = Recorder::create(&path).unwrap();
+        for (minute, x) in [(0, 0.0), (1, 10.0), (2, 20.0)] {
+            recorder.record(&frame(minute, x)).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let mut replay = ReplayController::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replay.time_span(), (start, start + Duration::minutes(2)));
+
+        let graph = Arc::new(Graph::default());
+        replay.seek(graph.clone(), start + Duration::seconds(90));
+        assert_eq!(replay.vehicle_positions(), vec![(1, (10.0, 0.0))]);
+        replay.seek(graph, start + Duration::minutes(5));
+        assert_eq!(replay.vehicle_positions(), vec![(1, (20.0, 0.0))]);
+    }
+
+    #[test]
+    fn passengers_are_written_every_so_often() {
+        let start = Utc::now();
+        let path = std::env::temp_dir().join("odbrs-population-test.cbor");
+        let mut recorder = Recorder::create(&path).unwrap().with_population(&path, 15).unwrap();
+        let passenger = PassengerSnapshot {
+            id: 4,
+            mode: ServiceMode::Drt,
+            state: PassengerState::Waiting,
+            pos: (0.0, 0.0),
+            destination: (100.0, 0.0),
+            vehicle: Some(2),
+            next_stop: Some((10.0, 0.0)),
+            rides_left: 1,
+        };
+        for minute in 0..40 {
+            let time = start + Duration::minutes(minute);
+            if recorder.population_due(time) {
+                recorder.record_population(&Population { time, passengers: vec![passenger] }).unwrap();
+            }
+        }
+        recorder.flush().unwrap();
+
+        let written = fs::read_to_string(path.with_extension("passengers.jsonl")).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("passengers.jsonl")).unwrap();
+        let populations: Vec<Population> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let times: Vec<_> = populations.iter().map(|population| population.time).collect();
+        assert_eq!(times, vec![start, start + Duration::minutes(15), start + Duration::minutes(30)]);
+        assert_eq!(populations[0].passengers, vec![passenger]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Seeded random number generation, so a run can be repeated exactly from its seed.
+//!
+//! The simulation makes one `SimRng` from `SimulationConfig::seed` and forks an independent
+//! stream for each component, so adding draws in one place doesn't shift the draws elsewhere.
+
+use std::ops::{Deref, DerefMut};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Debug, Clone)]
+pub struct SimRng(StdRng);
+
+impl Default for SimRng {
+    fn default() -> Self {
+        SimRng(StdRng::from_entropy())
+    }
+}
+
+impl SimRng {
+    pub fn seeded(seed: u64) -> Self {
+        SimRng(StdRng::seed_from_u64(seed))
+    }
+
+    // A new generator seeded from this one
+    pub fn fork(&mut self) -> Self {
+        SimRng::seeded(self.0.gen())
+    }
+
+    // A seed drawn from this generator, for components which take a plain seed
+    pub fn next_seed(&mut self) -> u64 {
+        self.0.gen()
+    }
+
+    // Carry on from a fresh seed drawn from this generator, returning it. The generator state
+    // can't be saved, but the seed can, and a generator seeded with it makes the same draws
+    pub fn checkpoint(&mut self) -> u64 {
+        let seed = self.next_seed();
+        *self = SimRng::seeded(seed);
+        seed
+    }
+}
+
+impl Deref for SimRng {
+    type Target = StdRng;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SimRng {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_forks() {
+        let mut a = SimRng::seeded(42);
+        let mut b = SimRng::seeded(42);
+        let (mut fork_a, mut fork_b) = (a.fork(), b.fork());
+
+        let draws_a: Vec<u32> = (0..100).map(|_| fork_a.gen_range(0..1000)).collect();
+        let draws_b: Vec<u32> = (0..100).map(|_| fork_b.gen_range(0..1000)).collect();
+        assert_eq!(draws_a, draws_b);
+        assert_eq!(a.next_seed(), b.next_seed());
+    }
+}
@@ -0,0 +1,169 @@
+//! Passenger trip planning shared by every controller.
+//!
+//! Controllers shouldn't each do their own closest node/stop lookups and itinerary building,
+//! so the Simulation owns one router and hands it to the controllers. Improvements to the
+//! route finding here then apply to both the static and dynamic cases.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::{graph::Graph, units::Meters};
+
+use self::raptor::{Leg, RaptorOptions};
+
+use super::{static_controller::{routes::NetworkData, Control}, walking::WalkingConfig};
+
+pub mod raptor;
+
+/// A planned journey on the fixed route network
+#[derive(Debug, Clone, PartialEq)]
+pub struct Itinerary {
+    pub source_stop: u32, // Stop the passenger walks to first
+    pub dest_stop: u32, // Stop closest to where the passenger wants to go
+    pub legs: Vec<Control>, // Walking and bus legs in the order they are taken
+}
+
+pub trait DemandRouter: Send + Sync {
+    // Closest road graph node to a map coord
+    fn closest_node(&self, point: (f64, f64)) -> u128;
+
+    // Whether the road graph has a path between the nodes closest to two map coords
+    fn connected(&self, source: (f64, f64), dest: (f64, f64)) -> bool;
+
+    // Closest bus stop to a map coord (and the squared distance to it), if there is a bus network
+    fn closest_stop(&self, point: (f64, f64)) -> Option<(u32, f64)>;
+
+    // Map coord of a bus stop, if there is a bus network
+    fn stop_position(&self, stop: u32) -> Option<(f64, f64)>;
+
+    // Plan a journey on the fixed route network departing at `tick`, if there is a bus network
+    fn plan_itinerary(&self, source: (f64, f64), dest: (f64, f64), tick: DateTime<Utc>) -> Option<Itinerary>;
+}
+
+/// Default router working on the road graph and (optionally) the GTFS bus network
+pub struct Router {
+    graph: Arc<Graph>,
+    network_data: Option<Arc<NetworkData>>,
+    options: RaptorOptions,
+}
+
+impl Router {
+    pub fn new(graph: Arc<Graph>, network_data: Option<Arc<NetworkData>>) -> Self {
+        Router { graph, network_data, options: RaptorOptions::default() }
+    }
+
+    // Plan walks with these detours rather than as the crow flies
+    pub fn with_walking(mut self, walking: WalkingConfig) -> Self {
+        self.options.walking = walking;
+        self
+    }
+}
+
+impl DemandRouter for Router {
+    fn closest_node(&self, point: (f64, f64)) -> u128 {
+        self.graph.nearest_node(point).unwrap_or(0)
+    }
+
+    fn connected(&self, source: (f64, f64), dest: (f64, f64)) -> bool {
+        self.graph.connected(self.closest_node(source), self.closest_node(dest))
+    }
+
+    fn closest_stop(&self, point: (f64, f64)) -> Option<(u32, f64)> {
+        self.network_data.as_ref()?.nearest_stop(point)
+    }
+
+    fn stop_position(&self, stop: u32) -> Option<(f64, f64)> {
+        Some(self.network_data.as_ref()?.stops.get(&stop)?.position())
+    }
+
+    fn plan_itinerary(&self, source: (f64, f64), dest: (f64, f64), tick: DateTime<Utc>) -> Option<Itinerary> {
+        let network_data = self.network_data.as_ref()?;
+
+        let (source_stop, _) = self.closest_stop(source)?;
+        let (dest_stop, _) = self.closest_stop(dest)?;
+        let walk = |point, stop| Some(self.options.walk_seconds(Meters::between(network_data.crs(), point, self.stop_position(stop)?)));
+
+        // Any number of buses (up to the transfer limit) from the closest stop to the one closest to the destination
+        let origins = [(source_stop, walk(source, source_stop)?)];
+        let destinations = [(dest_stop, walk(dest, dest_stop)?)];
+        let journey = raptor::plan(network_data, &origins, &destinations, network_data.service_seconds_at(tick), &self.options)?;
+
+        let mut legs = vec![Control::walk_to_stop(source_stop, source)];
+        for leg in journey.legs {
+            legs.push(match leg {
+                Leg::Ride { trip, board, alight } => Control::take_bus(trip, board, alight),
+                Leg::Walk { from, to } => Control::walk_to_stop(to, self.stop_position(from)?),
+            });
+        }
+
+        Some(Itinerary { source_stop, dest_stop, legs })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDate;
+
+    use crate::{
+        graph::{AdjacencyList, EdgeMeta, GraphConfig, NodeMeta},
+        simulation::static_controller::routes::{NetworkStop, NetworkTrip},
+        Module,
+    };
+
+    use super::*;
+
+    // Three nodes in a line: 1 -- 2 -- 3, 100m apart
+    fn line_graph() -> Arc<Graph> {
+        let mut adjlist = AdjacencyList::default();
+        for (id, x) in [(1, 0.0), (2, 100.0), (3, 200.0)] {
+            adjlist.node_map.insert(id, NodeMeta { point: (x, 0.0), id, ..Default::default() });
+        }
+        for (id, start_id, end_id) in [(10, 1, 2), (11, 2, 3)] {
+            let points = vec![adjlist.node_map[&start_id].point, adjlist.node_map[&end_id].point];
+            adjlist.edge_map.insert(id, EdgeMeta { points, start_id, end_id, id, length: 100.0, ..Default::default() });
+            adjlist.adjacency.entry(start_id).or_insert_with(Vec::new).push(id);
+            adjlist.adjacency.entry(end_id).or_insert_with(Vec::new).push(id);
+        }
+
+        let mut graph = Graph::default();
+        graph.init(GraphConfig::default(), adjlist).unwrap();
+        Arc::new(graph)
+    }
+
+    fn stop(easting: f64) -> Arc<NetworkStop> {
+        Arc::new(NetworkStop { easting, northing: 0.0, stop_id: String::new() })
+    }
+
+    #[test]
+    fn closest_node_without_network() {
+        let router = Router::new(line_graph(), None);
+
+        assert_eq!(router.closest_node((190.0, 5.0)), 3);
+        assert_eq!(router.closest_node((40.0, -5.0)), 1);
+        assert_eq!(router.closest_stop((0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn itinerary_takes_trip_closest_to_destination() {
+        let mut network = NetworkData::default();
+        network.stops = HashMap::from([(1, stop(0.0)), (2, stop(100.0)), (3, stop(200.0))]);
+        let at = |m: u32| (8 * 3600 + m * 60, 8 * 3600 + m * 60);
+        network.trips = HashMap::from([(
+            7,
+            NetworkTrip { trip_id: String::from("t"), route_id: String::new(), service_id: String::new(), stops: vec![1, 2, 3], timings: vec![at(5), at(10), at(15)] },
+        )]);
+        network.trips_from_stop = HashMap::from([(1, vec![7]), (2, vec![7]), (3, vec![7])]);
+
+        let router = Router::new(line_graph(), Some(Arc::new(network)));
+        let tick = DateTime::from_utc(NaiveDate::from_ymd_opt(2023, 3, 1).unwrap().and_hms_opt(8, 0, 0).unwrap(), Utc);
+        let itinerary = router.plan_itinerary((1.0, 1.0), (199.0, 1.0), tick).unwrap();
+
+        assert_eq!(itinerary.source_stop, 1);
+        assert_eq!(itinerary.dest_stop, 3);
+        assert_eq!(itinerary.legs[1], Control::take_bus(7, 1, 3));
+    }
+}
@@ -0,0 +1,248 @@
+//! Journey planning on the bus network with transfers, after RAPTOR (Delling et al., "Round-Based
+//! Public Transit Routing").
+//!
+//! Each round takes one more bus than the last: every stop reached in the previous round is
+//! scanned for trips leaving it soon enough, and riding each of them on improves the arrival at
+//! the stops after. Passengers can then walk a short way to another stop before the next round.
+//! Stopping after `max_transfers + 1` rounds keeps the search small, and the fewest buses wins
+//! when two journeys arrive at the same time.
+//!
+//! Times are seconds into the service day, the same as `NetworkTrip::timings`.
+
+use std::collections::HashMap;
+
+use crate::{simulation::{static_controller::routes::NetworkData, walking::WalkingConfig}, units::Meters};
+
+pub struct RaptorOptions {
+    pub max_transfers: usize, // Changes of bus on top of the first one
+    pub min_transfer: u32, // Seconds needed to change buses at a stop
+    pub max_wait: u32, // Seconds a passenger will wait at a stop for a bus
+    pub max_walk: f64, // Straight line metres a passenger will walk between stops
+    pub walking: WalkingConfig,
+}
+
+impl Default for RaptorOptions {
+    fn default() -> Self {
+        RaptorOptions {
+            max_transfers: 3,
+            min_transfer: 120,
+            max_wait: 20 * 60,
+            max_walk: 400.0,
+            walking: WalkingConfig::default(),
+        }
+    }
+}
+
+impl RaptorOptions {
+    // Whole seconds to walk somewhere `straight_line` away
+    pub fn walk_seconds(&self, straight_line: Meters) -> u32 {
+        self.walking.seconds(straight_line).whole()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leg {
+    Ride { trip: u32, board: u32, alight: u32 },
+    Walk { from: u32, to: u32 }, // Between two stops when changing buses
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journey {
+    pub legs: Vec<Leg>, // Starting with a ride from the first stop
+    pub arrival: u32, // At the destination, after walking from the last stop
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Via {
+    Start,
+    Ride { trip: u32, board: u32 },
+    Walk { from: u32, trip: u32, board: u32 }, // Off `trip` at `from` and walked here
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    time: u32,
+    via: Via,
+}
+
+// Earliest journey leaving at `depart` from any of `origins` to any of `destinations`, each a stop
+// and the seconds spent walking between it and the passenger's own position
+pub fn plan(network_data: &NetworkData, origins: &[(u32, u32)], destinations: &[(u32, u32)], depart: u32, options: &RaptorOptions) -> Option<Journey> {
+    let mut best: HashMap<u32, u32> = HashMap::new();
+    let mut rounds: Vec<HashMap<u32, Label>> = vec![HashMap::new()];
+    for &(stop, walk) in origins {
+        let time = depart + walk;
+        if best.get(&stop).map_or(true, |&earliest| time < earliest) {
+            best.insert(stop, time);
+            rounds[0].insert(stop, Label { time, via: Via::Start });
+        }
+    }
+
+    // Earliest arrival at the destination found so far, anything later isn't worth following
+    let arrival_by = |best: &HashMap<u32, u32>| destinations.iter().filter_map(|(stop, walk)| best.get(stop).map(|time| time + walk)).min();
+
+    for round in 1..=options.max_transfers + 1 {
+        let previous = &rounds[round - 1];
+        let bound = arrival_by(&best).unwrap_or(u32::MAX);
+
+        // Ride every trip leaving a stop reached last round
+        let mut rides: HashMap<u32, (u32, u32, u32)> = HashMap::new(); // Stop to (time, trip, board)
+        let mut scanned: Vec<(&u32, &Label)> = previous.iter().collect();
+        scanned.sort_unstable_by_key(|(stop, _)| **stop);
+        for (&board, label) in scanned {
+            let ready = match label.via {
+                Via::Ride { .. } => label.time + options.min_transfer,
+                _ => label.time,
+            };
+            for trip_id in network_data.trips_from_stop.get(&board).into_iter().flatten() {
+                let Some(trip) = network_data.trips.get(trip_id) else { continue };
+                let Some(index) = trip.stops.iter().position(|stop| *stop == board) else { continue };
+                let Some((_, departure)) = trip.timings.get(index) else { continue };
+                if *departure < ready || *departure > ready + options.max_wait {
+                    continue;
+                }
+
+                for (stop, (arrival, _)) in trip.stops.iter().zip(trip.timings.iter()).skip(index + 1) {
+                    if *arrival >= bound {
+                        break;
+                    }
+                    let improves = best.get(stop).map_or(true, |&earliest| *arrival < earliest);
+                    let beats_round = rides.get(stop).map_or(true, |(time, _, _)| *arrival < *time);
+                    if improves && beats_round {
+                        rides.insert(*stop, (*arrival, *trip_id, board));
+                    }
+                }
+            }
+        }
+        if rides.is_empty() {
+            break;
+        }
+
+        let mut labels: HashMap<u32, Label> = HashMap::new();
+        for (&stop, &(time, trip, board)) in rides.iter() {
+            best.insert(stop, time);
+            labels.insert(stop, Label { time, via: Via::Ride { trip, board } });
+        }
+
+        // Walk on to nearby stops to catch a bus from there next round. Not ones the bus goes on to,
+        // which the passenger could have stayed on for
+        let mut alighted: Vec<_> = rides.iter().collect();
+        alighted.sort_unstable_by_key(|(stop, _)| **stop);
+        for (&from, &(time, trip, board)) in alighted {
+            let Some(position) = network_data.stops.get(&from).map(|stop| stop.position()) else { continue };
+            let on_trip = |stop: &u32| network_data.trips.get(&trip).map_or(false, |trip| trip.stops.contains(stop));
+            for to in network_data.stops_within(position, options.max_walk) {
+                let Some(other) = network_data.stops.get(&to) else { continue };
+                let dist = Meters::between(network_data.crs(), position, other.position());
+                let walked = time + options.walk_seconds(dist);
+                if !on_trip(&to) && best.get(&to).map_or(true, |&earliest| walked < earliest) {
+                    best.insert(to, walked);
+                    labels.insert(to, Label { time: walked, via: Via::Walk { from, trip, board } });
+                }
+            }
+        }
+        rounds.push(labels);
+    }
+
+    // Fewest rounds first so an equally fast journey with fewer buses is kept
+    let mut found: Option<(u32, usize, u32)> = None; // Arrival, round, last stop
+    for (round, labels) in rounds.iter().enumerate().skip(1) {
+        for &(stop, walk) in destinations {
+            if let Some(label) = labels.get(&stop) {
+                let arrival = label.time + walk;
+                if found.map_or(true, |(earliest, _, _)| arrival < earliest) {
+                    found = Some((arrival, round, stop));
+                }
+            }
+        }
+    }
+    let (arrival, mut round, mut stop) = found?;
+
+    let mut legs = Vec::new();
+    while round > 0 {
+        match rounds[round][&stop].via {
+            Via::Start => break,
+            Via::Ride { trip, board } => {
+                legs.push(Leg::Ride { trip, board, alight: stop });
+                stop = board;
+            }
+            Via::Walk { from, trip, board } => {
+                legs.push(Leg::Walk { from, to: stop });
+                legs.push(Leg::Ride { trip, board, alight: from });
+                stop = board;
+            }
+        }
+        round -= 1;
+    }
+    legs.reverse();
+
+    Some(Journey { legs, arrival })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::simulation::static_controller::routes::{NetworkStop, NetworkTrip};
+
+    use super::*;
+
+    fn at(minute: u32) -> u32 {
+        8 * 3600 + minute * 60
+    }
+
+    // Stops 1 to 5 along a line, 1km apart apart from stop 5 just round the corner from stop 3
+    fn network(trips: Vec<(u32, Vec<u32>, Vec<u32>)>) -> NetworkData {
+        let mut network = NetworkData::default();
+        for (id, position) in [(1, (0.0, 0.0)), (2, (1000.0, 0.0)), (3, (2000.0, 0.0)), (4, (3000.0, 0.0)), (5, (2000.0, 200.0))] {
+            network.stops.insert(id, Arc::new(NetworkStop { easting: position.0, northing: position.1, stop_id: id.to_string() }));
+        }
+        for (id, stops, minutes) in trips {
+            for stop in stops.iter() {
+                network.trips_from_stop.entry(*stop).or_insert_with(Vec::new).push(id);
+            }
+            let timings = minutes.into_iter().map(|minute| (at(minute), at(minute))).collect();
+            network.trips.insert(id, NetworkTrip { trip_id: id.to_string(), route_id: String::new(), service_id: String::new(), stops, timings });
+        }
+        network
+    }
+
+    #[test]
+    fn changes_buses_to_reach_the_destination() {
+        let network = network(vec![
+            (10, vec![1, 2, 3], vec![0, 5, 10]),
+            (11, vec![3, 4], vec![11, 15]), // Too soon to change onto
+            (12, vec![3, 4], vec![20, 25]),
+        ]);
+
+        let journey = plan(&network, &[(1, 0)], &[(4, 60)], at(0), &RaptorOptions::default()).unwrap();
+        assert_eq!(journey.legs, vec![Leg::Ride { trip: 10, board: 1, alight: 3 }, Leg::Ride { trip: 12, board: 3, alight: 4 }]);
+        assert_eq!(journey.arrival, at(25) + 60);
+
+        // No bus within the wait
+        assert_eq!(plan(&network, &[(1, 0)], &[(4, 0)], at(0) - 30 * 60, &RaptorOptions::default()), None);
+        // Or not allowed to change
+        assert_eq!(plan(&network, &[(1, 0)], &[(4, 0)], at(0), &RaptorOptions { max_transfers: 0, ..Default::default() }), None);
+    }
+
+    #[test]
+    fn walks_between_nearby_stops_and_prefers_fewer_buses() {
+        let network = network(vec![
+            (10, vec![1, 2, 3], vec![0, 5, 10]),
+            (11, vec![5, 4], vec![20, 30]),
+            (12, vec![1, 4], vec![5, 30]), // Just as quick without changing
+        ]);
+
+        let journey = plan(&network, &[(1, 0)], &[(4, 0)], at(0), &RaptorOptions::default()).unwrap();
+        assert_eq!(journey.legs, vec![Leg::Ride { trip: 12, board: 1, alight: 4 }]);
+
+        let network = self::network(vec![(10, vec![1, 2, 3], vec![0, 5, 10]), (11, vec![5, 4], vec![20, 30])]);
+        let journey = plan(&network, &[(1, 0)], &[(4, 0)], at(0), &RaptorOptions::default()).unwrap();
+        assert_eq!(journey.legs, vec![
+            Leg::Ride { trip: 10, board: 1, alight: 3 },
+            Leg::Walk { from: 3, to: 5 },
+            Leg::Ride { trip: 11, board: 5, alight: 4 },
+        ]);
+        assert_eq!(journey.arrival, at(30));
+    }
+}
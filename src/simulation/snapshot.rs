@@ -0,0 +1,105 @@
+//! Saving a running simulation to disk and carrying on from it later, so a day long run can be
+//! stopped part way through.
+//!
+//! A snapshot holds the clock, both controllers' vehicles and passengers, fleet changes and
+//! controller switches still to come, which controller is active and seeds for the random generators. The graph, bus network and config aren't saved, so a
+//! snapshot is resumed by starting the same config with `resume` set in `[simulation]`. Analytics
+//! aren't saved either and only cover the run from the point it was resumed.
+
+use std::{error::Error, fs, path::Path};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dyn_controller::DynamicState, static_controller::StaticState, switching::{ActiveController, ScheduledSwitch}, FleetChange};
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub time: DateTime<Utc>,
+    pub passenger_id: u32, // Next passenger id in mixed mode
+    pub fleet_changes: Vec<FleetChange>,
+    #[serde(default)]
+    pub controller_switches: Vec<ScheduledSwitch>,
+    #[serde(default)]
+    pub handing_over: bool, // Whether the last switch hands over the waiting passengers
+    #[serde(default)]
+    pub active: Option<ActiveController>, // The config's controller if not saved
+    pub dynamic: Option<DynamicState>,
+    pub fixed_route: Option<StaticState>,
+    pub demand_seed: u64,
+    #[serde(default)]
+    pub service_day: Option<NaiveDate>, // Date the run started on, taken from `time` if not saved
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut bytes)?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(path)?;
+        Ok(ciborium::de::from_reader(data.as_slice())?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        graph::synthetic,
+        simulation::{
+            demand::Demand,
+            dyn_controller::{self, DynamicController},
+            rng::SimRng,
+            router::{DemandRouter, Router},
+            Agent, Controller,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn resumed_run_matches_the_original() {
+        let graph = Arc::new(synthetic::grid(5, 100.0));
+        let router: Arc<dyn DemandRouter> = Arc::new(Router::new(graph.clone(), None));
+        let controller = || {
+            let mut controller = DynamicController::default();
+            controller.set_router(router.clone());
+            controller.set_rng(SimRng::seeded(1));
+            controller
+        };
+
+        let mut original = controller();
+        for _ in 0..2 {
+            original.spawn_agent(graph.clone());
+        }
+        let now = Utc::now();
+        original.accept_passenger(dyn_controller::demand_to_passenger(Demand((0.0, 0.0), (400.0, 400.0), now, None), router.as_ref(), 0));
+        for _ in 0..3 {
+            original.step(graph.clone(), now);
+        }
+
+        let snapshot = Snapshot { time: now, passenger_id: 0, fleet_changes: vec![], controller_switches: vec![], handing_over: false, active: None, dynamic: Some(original.save_state()), fixed_route: None, demand_seed: 0, service_day: None };
+        let path = std::env::temp_dir().join("odbrs-snapshot-test.cbor");
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut resumed = controller();
+        resumed.restore_state(loaded.dynamic.unwrap(), graph.clone());
+        for _ in 0..5 {
+            original.step(graph.clone(), now);
+            resumed.step(graph.clone(), now);
+        }
+
+        let positions = |controller: &DynamicController| controller.get_agents().iter().map(|bus| bus.get_position()).collect::<Vec<_>>();
+        assert_eq!(positions(&original), positions(&resumed));
+    }
+}
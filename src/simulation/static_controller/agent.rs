@@ -1,41 +1,44 @@
-use std::{collections::VecDeque, sync::{Arc, mpsc::Sender}};
+use std::{collections::VecDeque, sync::Arc};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use eframe::epaint::{Shape, pos2, Stroke, Color32};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    graph::Graph,
+    geometry::Crs,
+    graph::{speeds::DEFAULT_SPEED, Graph},
     simulation::{
         dyn_controller::bus::CurrentElement,
-        Agent,
+        escalation::{Escalation, ServiceMode},
+        noise::Noise,
+        population::Completed,
+        walking::WalkingConfig,
+        Agent, AgentState,
     }, analytics::{AnalyticsPackage, PassengerAnalyticsEvent, VehicleAnalyticsEvent},
+    event_bus::Topic,
+    units::{Meters, Seconds},
 };
 
 use super::{
+    dwell::DwellConfig,
     routes::{self, get_graph_edge_from_stop, NetworkData},
     Control,
 };
 
-pub fn send_analytics(analytics: &Option<Sender<AnalyticsPackage>>, event: AnalyticsPackage) {
-    if let Some(tx) = analytics.as_ref() {
-        // println!("[ANALYTICS] Sending analytics event!");
-        if let Err(err) = tx.send(event) {
-            panic!("[ANALYTICS] Unable to send analytics: {:?}", err);
-        } else {
-            // println!("[ANALYTICS] Sent analytics event!");
-            return;
-        }
-    } else {
-        // println!("[ANALYTICS] No analytics channel found!");
-        panic!("trying to send analytics without a channel")
+const STOP_TOLERANCE: f64 = 1.0; // Metres a bus can be past a stop's position and still pull in at it
+
+// Vehicle ids are trip ids, published in the fixed route range (see `analytics::entity`)
+pub fn send_analytics(analytics: &Option<Topic<AnalyticsPackage>>, event: AnalyticsPackage) {
+    if let Some(topic) = analytics.as_ref() {
+        topic.publish(event.in_fleet(ServiceMode::FixedRoute));
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PassengerStatus {
     Generated, // Generated but not yet waiting
     Waiting, // Waiting at a bus stop
-    Walking(u32), // Walking with x ticks left
+    Walking((f64, f64)), // Walking towards this map coord, a stop or the destination
     OnBus, // On a bus to a destination
     Finished, // Finished
 }
@@ -47,7 +50,7 @@ impl Default for PassengerStatus {
 }
 
 /// Represents the passenger of a generated demand which is on the bus
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct BusPassenger {
     pub id: u32,
 
@@ -63,11 +66,24 @@ pub struct BusPassenger {
     pub instructions: VecDeque<Control>,
 
     pub status: PassengerStatus,
-    pub analytics: Option<Sender<AnalyticsPackage>>,
+    #[serde(default)]
+    pub position: (f64, f64), // Where the passenger has walked to, only kept up to date while walking
+    pub wait_ticks: u32, // Ticks spent waiting at the current stop
+    pub roll_overs: u32, // Times left behind at a stop by a full bus
+    pub escalations: Vec<Escalation>, // Times this passenger was moved between fixed route and DRT
+    #[serde(default)]
+    pub desired_arrival: Option<DateTime<Utc>>, // When they want to be at their destination, if they said
+    #[serde(skip)]
+    pub analytics: Option<Topic<AnalyticsPackage>>,
 }
 
 impl BusPassenger {
 
+    // What's archived of them once they've finished, in the tick at `time`
+    pub fn completed(&self, time: DateTime<Utc>) -> Completed {
+        Completed { id: self.id, mode: ServiceMode::FixedRoute, origin: self.source_pos, destination: self.dest_pos, time }
+    }
+
     // Should passenger get on this bus (trip, stop)
     pub fn should_get_on(&self, trip: u32, stop: u32, network_data: Arc<NetworkData>) -> bool {
         if let Some(Control { destination_stop, source: Ok(source) }) = self.instructions.front() {            
@@ -80,15 +96,37 @@ impl BusPassenger {
         }
     }
 
-    pub fn get_on_bus(&mut self, agent_id: u32) {
+    // Left at `stop` by the full bus on `trip`, keeps waiting (and the wait so far) for a later bus
+    pub fn roll_over(&mut self, trip: u32, stop: u32, next_trip: Option<u32>, time: DateTime<Utc>) {
+        self.roll_overs += 1;
+        send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerDeniedBoarding { id: trip, passenger_id: self.id, stop }));
+        send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::RolledOver { id: self.id, stop, next_trip, time }));
+    }
+
+    pub fn requested(&self, time: DateTime<Utc>) {
+        send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: self.id, time, origin: self.source_pos, destination: self.dest_pos, desired_arrival: self.desired_arrival }));
+    }
+
+    pub fn get_on_bus(&mut self, agent_id: u32, time: DateTime<Utc>) {
         if self.status == PassengerStatus::Waiting {
             send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerPickup { id: agent_id, passenger_id: self.id }));
+            send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: self.id, vehicle: agent_id, time }));
             self.status = PassengerStatus::OnBus;
+            self.wait_ticks = 0;
         } else {
             panic!("Trying to get on bus when not waiting");
         }
     }
 
+    // Still to be taken where they're going, rather than riding or walking from the last stop
+    pub fn is_waiting_for_service(&self) -> bool {
+        match self.status {
+            PassengerStatus::OnBus | PassengerStatus::Finished => false,
+            PassengerStatus::Walking(_) => !self.instructions.is_empty(),
+            _ => true,
+        }
+    }
+
     pub fn should_get_off(&self, stop: u32) -> bool {
         if let Some(Control { destination_stop, .. }) = self.instructions.front() {
             *destination_stop == stop && self.status == PassengerStatus::OnBus
@@ -97,9 +135,10 @@ impl BusPassenger {
         }
     }
 
-    pub fn get_off_bus(&mut self, agent_id: u32) {
+    pub fn get_off_bus(&mut self, agent_id: u32, time: DateTime<Utc>) {
         if self.status == PassengerStatus::OnBus {
             send_analytics(&self.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::PassengerDropoff { id: agent_id, passenger_id: self.id }));
+            send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { id: self.id, time }));
             self.status = PassengerStatus::Generated;
             self.instructions.pop_front();
         } else {
@@ -107,45 +146,60 @@ impl BusPassenger {
         }
     }
 
-    pub fn update(&mut self, network_data: Arc<NetworkData>) {
+    pub fn update(&mut self, network_data: Arc<NetworkData>, tick_seconds: Seconds, walking: &WalkingConfig) {
         match self.status {
             PassengerStatus::Generated => {
                 // Passenger has just been generated want to move on immediately from this state (first update)    
                 match self.instructions.front() {
                     Some(Control { destination_stop, source: Err(pos) }) => {
-                        // Passenger is walking to a stop
-                        let dest_point = network_data.stops.get(destination_stop).unwrap().position();
-                        let distance = distance(*pos, dest_point);
-                        self.status = PassengerStatus::Walking((distance / 1.4) as u32); // TODO: calculate ticks
+                        // Passenger is walking to a stop, only waiting for a bus once they get there
+                        self.position = *pos;
+                        self.status = PassengerStatus::Walking(network_data.stops.get(destination_stop).unwrap().position());
                     },
                     Some(Control { destination_stop, source: Ok(stop) }) => {
                         // Passenger is waiting at a `stop` to go to `destination_stop`
                         self.status = PassengerStatus::Waiting;
                     },
                     None => {
-                        // Passenger has no instructions
-                        self.status = PassengerStatus::Finished;
+                        // Off the last bus, walking the rest of the way from the destination stop
+                        let stop = network_data.stops.get(&self.dest_stop).map_or(self.dest_pos, |stop| stop.position());
+                        if stop != self.dest_pos {
+                            self.position = stop;
+                            self.status = PassengerStatus::Walking(self.dest_pos);
+                        } else {
+                            self.status = PassengerStatus::Finished;
+                        }
                     }
                 }
             },
-            PassengerStatus::Walking(ticks_remaining) => {
-                // Passenger is walking to a stop and has to do this for `ticks_remaining` ticks
-                match ticks_remaining {
-                    0 => {
-                        // Passenger has finished walking
+            PassengerStatus::Walking(to) => {
+                // Along the straight line, slowed by the detour the real walk takes
+                let step = walking.progress(tick_seconds);
+                let remaining = Meters::between(network_data.crs(), self.position, to);
+                if remaining <= step {
+                    // Arrived, on to the next instruction or finished at the destination
+                    self.position = to;
+                    if self.instructions.pop_front().is_some() {
                         self.status = PassengerStatus::Generated;
-                        self.instructions.pop_front();
-                        self.update(network_data);
-                    },
-                    _ => {
-                        send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::StartWalkingTick { id: self.id }));
-                        self.status = PassengerStatus::Walking(ticks_remaining - 1);
+                        self.update(network_data, tick_seconds, walking);
+                    } else {
+                        self.status = PassengerStatus::Finished;
                     }
+                } else {
+                    let event = if self.instructions.is_empty() {
+                        PassengerAnalyticsEvent::EndWalkingTick { id: self.id }
+                    } else {
+                        PassengerAnalyticsEvent::StartWalkingTick { id: self.id }
+                    };
+                    send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(event));
+                    let along = step / remaining;
+                    self.position = (self.position.0 + (to.0 - self.position.0) * along, self.position.1 + (to.1 - self.position.1) * along);
                 }
             },
             PassengerStatus::Waiting => {
                 // Passenger is waiting at a stop after having arrived at it
                 send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::WaitingTick { id: self.id, waiting_pos: (0.0, 0.0) }));
+                self.wait_ticks += 1;
             },
             PassengerStatus::OnBus => {
                 // Passenger is on a bus and is on it until the bus reaches the end stop
@@ -159,7 +213,7 @@ impl BusPassenger {
 
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BusStatus {
     Active,
     Unactive,
@@ -171,6 +225,7 @@ impl Default for BusStatus {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StaticAgent {
     pub position: (f64, f64),
     pub trip_id: u32,
@@ -183,16 +238,25 @@ pub struct StaticAgent {
     pub trip_route: Vec<u128>,
 
     pub trip_stop_edges: Vec<(u128, f64)>,
+    #[serde(default)]
+    pub next_stop: usize, // Index into the trip's stops of the next one to serve
+    #[serde(default)]
+    pub dwell_remaining: Seconds, // Left waiting at the last stop served
 
     // Passengers
     pub passengers: Vec<BusPassenger>, // list of passengers on the bus right now
+    #[serde(skip)]
+    pub capacity: usize, // Seats, from the config rather than the snapshot so it can be changed on resume
 
-    // Simulation information
+    // Simulation information, not saved in snapshots
+    #[serde(skip)]
     pub graph: Arc<Graph>,
+    #[serde(skip)]
     pub network_data: Arc<NetworkData>,
 
     // Analytics
-    pub analytics: Option<Sender<AnalyticsPackage>>
+    #[serde(skip)]
+    pub analytics: Option<Topic<AnalyticsPackage>>
 }
 
 impl Agent for StaticAgent {
@@ -268,15 +332,38 @@ impl Agent for StaticAgent {
     fn get_position(&self) -> (f64, f64) {
         self.position
     }
+
+    fn get_id(&self) -> u32 {
+        self.trip_id
+    }
+
+    fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn get_occupancy(&self) -> usize {
+        self.passengers.len()
+    }
+
+    fn get_state(&self) -> AgentState {
+        if self.status == BusStatus::Unactive {
+            AgentState::OutOfService
+        } else if self.dwell_remaining > Seconds::ZERO {
+            AgentState::Stopped
+        } else {
+            AgentState::Moving
+        }
+    }
 }
 
 impl StaticAgent {
 
-    pub fn get_capacity(&self) -> usize {
-        return 45 - self.passengers.len() as usize;
+    // Seats left for passengers waiting to board
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity.saturating_sub(self.passengers.len())
     }
 
-    pub fn new(trip_id: u32, graph: Arc<Graph>, network_data: Arc<NetworkData>, analytics: Option<Sender<AnalyticsPackage>>) -> Self {
+    pub fn new(trip_id: u32, capacity: usize, graph: Arc<Graph>, network_data: Arc<NetworkData>, analytics: Option<Topic<AnalyticsPackage>>) -> Self {
         let (trip_route, trip_stop_edges) =
             routes::convert_trip_to_graph_path(trip_id, graph.clone(), network_data.clone());
 
@@ -351,10 +438,13 @@ impl StaticAgent {
             trip_route,
             current_element,
             trip_stop_edges,
+            next_stop: 0,
+            dwell_remaining: Seconds::ZERO,
             next_node: route_beginning_node.clone(),
             position: route_beginning_position.clone(),
             status: BusStatus::Unactive,
             passengers: Vec::new(),
+            capacity,
             analytics
         }
     }
@@ -363,9 +453,13 @@ impl StaticAgent {
     pub fn move_self<G>(
         &mut self,
         tick: chrono::DateTime<Utc>,
+        tick_seconds: Seconds,
+        walking: &WalkingConfig,
+        noise: &mut Noise,
+        dwell: &DwellConfig,
         mut pick_up_and_drop_off_passengers: G,
     ) where
-        G: FnMut(u32, u32, Vec<BusPassenger>) -> Vec<BusPassenger>,
+        G: FnMut(u32, u32, usize, Vec<BusPassenger>) -> Vec<BusPassenger>, // Trip, stop, seats free after drop offs, passengers getting off
     {
         // if time tick is before trip start => bus is non-active
         let start_time = self
@@ -377,7 +471,7 @@ impl StaticAgent {
             .first()
             .unwrap()
             .0;
-        if tick.time() < start_time {
+        if self.network_data.service_seconds_at(tick) < start_time {
             // println!("agent {} is not active", self.trip_id);
             self.status = BusStatus::Unactive;
             return;
@@ -389,13 +483,13 @@ impl StaticAgent {
         // trying to stick to timings as much as possible
 
         self.passengers.iter_mut().for_each(|passenger| {
-            passenger.update(self.network_data.clone());
+            passenger.update(self.network_data.clone(), tick_seconds, walking);
         });
 
         let agent_trip_id = self.trip_id;
 
         // This callback function is executed when the static agent passes a bus stop
-        move_agent(self, tick, |trip_id, stop_id, agent| {
+        move_agent(self, tick, tick_seconds, noise, dwell, |trip_id, stop_id, agent| {
 
             let mut passengers_to_drop = Vec::new();
             let mut i = 0;
@@ -409,13 +503,17 @@ impl StaticAgent {
                 }
             }
 
-            let mut passengers_to_pick_up = pick_up_and_drop_off_passengers(trip_id, stop_id, passengers_to_drop);
+            let dropped_off = !passengers_to_drop.is_empty();
+            let mut passengers_to_pick_up = pick_up_and_drop_off_passengers(trip_id, stop_id, agent.remaining_capacity(), passengers_to_drop);
+            debug_assert!(passengers_to_pick_up.len() <= agent.remaining_capacity(), "More passengers boarded trip {} than it has seats", trip_id);
+            let served = dropped_off || !passengers_to_pick_up.is_empty();
             
             passengers_to_pick_up.iter_mut().for_each(|p| {
-                p.get_on_bus(agent_trip_id);
+                p.get_on_bus(agent_trip_id, tick);
             });
 
             agent.passengers.extend(passengers_to_pick_up.into_iter());
+            served
         });
     }
 
@@ -429,7 +527,10 @@ impl StaticAgent {
 pub fn move_agent(
     agent: &mut StaticAgent,
     tick: chrono::DateTime<Utc>,
-    mut stop_check: impl FnMut(u32, u32, &mut StaticAgent),
+    tick_seconds: Seconds,
+    noise: &mut Noise,
+    dwell: &DwellConfig,
+    mut stop_check: impl FnMut(u32, u32, &mut StaticAgent) -> bool, // true if anyone got on or off
 ) {
     // No need to move agent if no path to follow
     if agent.remaining_route.is_empty() {
@@ -443,8 +544,15 @@ pub fn move_agent(
     // println!("{} Next node: {:?}", agent.trip_id, agent.next_node);
     // println!("Path: {:?}", self.path_full);
 
-    let mut move_distance = 804.672; //10.0; 804.672 = 13.4112 * 60.0 (13.4112 m/s * 60s)
-    while move_distance > 0.0 {
+    let speed = DEFAULT_SPEED * noise.travel_factor(); // Before the road's own speed
+    let start_position = agent.position;
+
+    // Whatever's left of a wait at a stop from the last tick comes first
+    let held = agent.dwell_remaining.min(tick_seconds);
+    agent.dwell_remaining -= held;
+    let mut move_distance = speed * (tick_seconds - held); // 804.672m at 30mph for a 60s tick
+
+    'moving: while move_distance > Meters::ZERO {
         // Id of the edge we are currently on, or need to move along
         let moving_edge_id = match agent.current_element {
             CurrentElement::PreGenerated => {
@@ -463,11 +571,14 @@ pub fn move_agent(
             }
         };
         let moving_edge_data = &agent.graph.get_edgelist()[&moving_edge_id];
+        // Move distances are on a road at the default speed, this edge's speed scales them
+        let factor = agent.graph.speed_factor(moving_edge_id, tick.time());
 
         let next_node = agent.next_node;
         let next_node_data = &agent.graph.get_nodelist()[&next_node];
 
-        let line = if next_node_data.point == *moving_edge_data.points.first().unwrap() {
+        let reversed = next_node_data.point == *moving_edge_data.points.first().unwrap();
+        let line: Vec<(f64, f64)> = if reversed {
             moving_edge_data.points.iter().rev().map(|x| *x).collect() // if the next node is the first point on the edge, we need to reverse the line
         } else if next_node_data.point == *moving_edge_data.points.last().unwrap() {
             moving_edge_data.points.clone()
@@ -476,56 +587,56 @@ pub fn move_agent(
             unreachable!("The next node is not on the edge we are moving along");
         };
 
+        // How far along the line the next stop on the trip is, if it's on this edge. Stop offsets
+        // are measured from the start of the edge, whichever way the bus is going
+        let crs = agent.graph.crs();
+        let line_length: f64 = line.windows(2).map(|w| crs.distance(w[0], w[1])).sum();
+        let next_stop_offset = agent
+            .trip_stop_edges
+            .get(agent.next_stop)
+            .filter(|(edge, _)| *edge == moving_edge_id)
+            .map(|(_, offset)| if reversed { line_length - offset } else { *offset });
+
         let mut has_moved = false;
         for i in 0..line.len() - 1 {
             let segment_start = line[i];
             let segment_end = line[i + 1];
 
-            if point_on_linesegment(agent.position, &segment_start, &segment_end) {
-                let prev_offset = (0..i).map(|i| distance(line[i], line[i + 1])).sum::<f64>()
-                    + distance(segment_start, agent.position);
+            if point_on_linesegment(crs, agent.position, &segment_start, &segment_end) {
+                let prev_offset = (0..i).map(|i| crs.distance(line[i], line[i + 1])).sum::<f64>()
+                    + crs.distance(segment_start, agent.position);
 
                 // println!("On line segment {}/{}", i, line.len());
-                let distance_remaining = distance(agent.position, segment_end);
+                let distance_remaining = Meters::between(crs, agent.position, segment_end);
                 // println!("Distance remaining: {}", distance_remaining);
-                if move_distance > distance_remaining {
+
+                // Pull in at the next stop if it comes before the end of this move
+                if let Some(stop_offset) = next_stop_offset {
+                    let to_stop = Meters((stop_offset - prev_offset).max(0.0));
+                    if stop_offset >= prev_offset - STOP_TOLERANCE && to_stop <= distance_remaining.min(move_distance * factor) {
+                        agent.position = crs.towards(agent.position, segment_end, to_stop);
+                        move_distance -= to_stop / factor;
+
+                        let elapsed = tick_seconds - move_distance / speed;
+                        let stay = serve_next_stop(agent, tick, elapsed, noise, dwell, &mut stop_check);
+                        let held = stay.min(move_distance / speed);
+                        agent.dwell_remaining = stay - held;
+                        move_distance -= held * speed;
+                        continue 'moving;
+                    }
+                }
+
+                if move_distance * factor > distance_remaining {
                     // if move distance is > distance to end of line segment, move to end of line segment. Will then consider the next segment.
                     agent.position = segment_end;
-                    move_distance -= distance_remaining;
+                    move_distance -= distance_remaining / factor;
                     has_moved = true;
                 } else {
-                    let dir = normalise((
-                        segment_end.0 - segment_start.0,
-                        segment_end.1 - segment_start.1,
-                    ));
-                    agent.position = (
-                        agent.position.0 + dir.0 * move_distance,
-                        agent.position.1 + dir.1 * move_distance,
-                    );
+                    agent.position = crs.towards(agent.position, segment_end, move_distance * factor);
 
                     send_analytics(&agent.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::MovementTick { id: agent.trip_id, pos: agent.position }));
                     return;
                 }
-
-                let new_offset = (0..i).map(|i| distance(line[i], line[i + 1])).sum::<f64>()
-                    + distance(segment_start, agent.position);
-
-                // if this is the edge which contains a stop && the offset before was before the stop and the offset after was after the stop then we've passed the stop and can do the thing
-                for i in 0..agent.trip_stop_edges.len() {
-                    let (edge, offset) = agent.trip_stop_edges[i];
-                    if edge == moving_edge_id && offset > prev_offset && offset <= new_offset {
-                        stop_check(
-                            agent.trip_id,
-                            agent
-                                .network_data
-                                .trips
-                                .get(&agent.trip_id)
-                                .expect("Invalid Trip ID on agent")
-                                .stops[i],
-                            agent,
-                        );
-                    }
-                }
             } else {
                 // println!("{} Not on line segment {}/{}", agent.trip_id, i, line.len());
             }
@@ -537,7 +648,20 @@ pub fn move_agent(
         }
 
         // If we've moved along the segments and still have distance to traverse, we're moving past the next node.
-        if has_moved && move_distance > 0.0 {
+        if has_moved && move_distance > Meters::ZERO {
+            // Stops on this edge the bus never lined up with, e.g. snapped behind where it joined
+            // the edge, are served as it leaves so nobody waiting there is skipped
+            while agent.trip_stop_edges.get(agent.next_stop).map_or(false, |(edge, _)| *edge == moving_edge_id) {
+                let elapsed = tick_seconds - move_distance / speed;
+                let stay = serve_next_stop(agent, tick, elapsed, noise, dwell, &mut stop_check);
+                let held = stay.min(move_distance / speed);
+                agent.dwell_remaining = stay - held;
+                move_distance -= held * speed;
+            }
+            if move_distance <= Meters::ZERO {
+                break;
+            }
+
             // We have moved the full distance to move along the current edge and are now at "self.next_node"
             // Move to the next edge
             let current_node = agent.next_node; //self.path_full.pop_front().unwrap(); // Also should be the current self.next_node before we update it
@@ -556,6 +680,7 @@ pub fn move_agent(
                         edge: *edge_id,
                         prev_node: current_node,
                     };
+                    send_analytics(&agent.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::EdgeEntered { id: agent.trip_id, edge: *edge_id, mode: ServiceMode::FixedRoute }));
                     next_node
                 }
                 None => {
@@ -566,13 +691,36 @@ pub fn move_agent(
             };
         }
     }
+
+    // Stopped at a stop for the rest of the tick
+    if agent.position != start_position {
+        send_analytics(&agent.analytics, AnalyticsPackage::VehicleEvent(VehicleAnalyticsEvent::MovementTick { id: agent.trip_id, pos: agent.position }));
+    }
+}
+
+// Let passengers on and off at the next stop on the trip, reached `elapsed` seconds into the tick.
+// Returns how long the bus then stays there
+fn serve_next_stop<F>(agent: &mut StaticAgent, tick: chrono::DateTime<Utc>, elapsed: Seconds, noise: &mut Noise, dwell: &DwellConfig, stop_check: &mut F) -> Seconds
+where
+    F: FnMut(u32, u32, &mut StaticAgent) -> bool,
+{
+    let trip = agent.network_data.trips.get(&agent.trip_id).expect("Invalid Trip ID on agent");
+    let stop = trip.stops[agent.next_stop];
+    let departure = trip.timings.get(agent.next_stop).map(|(_, departure)| *departure);
+    agent.next_stop += 1;
+
+    let served = stop_check(agent.trip_id, stop, agent);
+    // Time spent letting passengers on and off isn't spent moving
+    let boarding = if served { noise.dwell_seconds() } else { 0.0 };
+    let arrived = agent.network_data.service_seconds_at(tick) as f64 + elapsed.0;
+    Seconds(dwell.dwell_seconds(arrived, departure.map(|departure| departure as f64).unwrap_or(arrived), boarding))
 }
 
 // Based on collision detection for a point and a line. Point is on a line if the distance to each point is equal to lenght
-fn point_on_linesegment(pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -> bool {
-    let d1 = distance(pos, *start);
-    let d2 = distance(pos, *end);
-    let line_len = distance(*start, *end);
+fn point_on_linesegment(crs: Crs, pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -> bool {
+    let d1 = crs.distance(pos, *start);
+    let d2 = crs.distance(pos, *end);
+    let line_len = crs.distance(*start, *end);
     let buffer = 0.1;
 
     if d1 + d2 >= line_len - buffer && d1 + d2 <= line_len + buffer {
@@ -582,16 +730,6 @@ fn point_on_linesegment(pos: (f64, f64), start: &(f64, f64), end: &(f64, f64)) -
     }
 }
 
-fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
-    let xs = (a.0 - b.0).abs();
-    let ys = (a.1 - b.1).abs();
-    xs.hypot(ys)
-}
-
-fn normalise(a: (f64, f64)) -> (f64, f64) {
-    let mag = ((a.0).powi(2) + (a.1).powi(2)).sqrt();
-    (a.0 / mag, a.1 / mag)
-}
 
 pub fn closest_point_on_line_segment_to_point(
     segment: [(f64, f64); 2],
@@ -658,13 +796,13 @@ pub fn closest_point_on_edge_to_stop(
     for i in 0..edge_data.points.len() - 1 {
         let segment = [edge_data.points[i], edge_data.points[i + 1]];
         let point_on_segment = closest_point_on_line_segment_to_point(segment, point);
-        let pt_distance = distance(point_on_segment, point);
+        let pt_distance = graph.distance(point_on_segment, point);
 
         // offset is the length of the edge up to the point on the segment
         let offset = (0..i)
-            .map(|j| distance(edge_data.points[j], edge_data.points[j + 1]))
+            .map(|j| graph.distance(edge_data.points[j], edge_data.points[j + 1]))
             .sum::<f64>()
-            + distance(edge_data.points[i], point_on_segment);
+            + graph.distance(edge_data.points[i], point_on_segment);
 
         if pt_distance < closest_distance {
             closest_distance = pt_distance;
@@ -675,3 +813,43 @@ pub fn closest_point_on_edge_to_stop(
 
     (closest_point, closest_offset)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{routes::NetworkStop, *};
+
+    #[test]
+    fn passengers_walk_to_the_stop_before_waiting() {
+        let mut network = NetworkData::default();
+        network.stops.insert(1, Arc::new(NetworkStop { easting: 100.0, northing: 0.0, stop_id: String::new() }));
+        let network = Arc::new(network);
+
+        let mut passenger = BusPassenger {
+            source_pos: (0.0, 0.0),
+            dest_stop: 1,
+            dest_pos: (100.0, 50.0),
+            instructions: VecDeque::from([Control::walk_to_stop(1, (0.0, 0.0)), Control::take_bus(0, 1, 1)]),
+            ..Default::default()
+        };
+
+        // 42m a tick, so three ticks to cover the 100m
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        assert_eq!(passenger.status, PassengerStatus::Walking((100.0, 0.0)));
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        assert_eq!(passenger.position, (84.0, 0.0));
+        assert!(passenger.is_waiting_for_service());
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        assert_eq!(passenger.status, PassengerStatus::Waiting);
+
+        // Off the bus at the last stop, then on foot to the destination
+        passenger.status = PassengerStatus::OnBus;
+        passenger.get_off_bus(0, Utc::now());
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        assert_eq!(passenger.status, PassengerStatus::Walking((100.0, 50.0)));
+        assert!(!passenger.is_waiting_for_service());
+        passenger.update(network.clone(), Seconds(30.0), &WalkingConfig::default());
+        passenger.update(network, Seconds(30.0), &WalkingConfig::default());
+        assert_eq!(passenger.status, PassengerStatus::Finished);
+    }
+}
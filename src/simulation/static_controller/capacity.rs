@@ -0,0 +1,68 @@
+//! Seats on the fixed route buses, so a full bus leaves passengers at the stop for a later one.
+//!
+//! Every bus gets `seats` unless its route or trip (by GTFS id) is listed, with trips winning
+//! over routes, e.g. double deckers on a busy route and a minibus on one quiet trip:
+//!
+//! ```toml
+//! [simulation.bus_capacity]
+//! seats = 45
+//! routes = { "X4" = 80 }
+//! trips = { "VJ_1234" = 16 }
+//! ```
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::routes::NetworkTrip;
+
+pub const DEFAULT_SEATS: usize = 45;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CapacityConfig {
+    #[serde(default = "default_seats")]
+    pub seats: usize,
+    #[serde(default)]
+    pub routes: HashMap<String, usize>, // GTFS route id to the seats on buses running it
+    #[serde(default)]
+    pub trips: HashMap<String, usize>, // GTFS trip id to the seats on the bus running it
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        CapacityConfig { seats: default_seats(), routes: HashMap::new(), trips: HashMap::new() }
+    }
+}
+
+fn default_seats() -> usize {
+    DEFAULT_SEATS
+}
+
+impl CapacityConfig {
+    pub fn seats_for(&self, trip: &NetworkTrip) -> usize {
+        self.trips
+            .get(&trip.trip_id)
+            .or_else(|| self.routes.get(&trip.route_id))
+            .copied()
+            .unwrap_or(self.seats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trips_override_routes_override_the_default() {
+        let capacity = CapacityConfig {
+            seats: 30,
+            routes: HashMap::from([(String::from("X4"), 80)]),
+            trips: HashMap::from([(String::from("VJ_2"), 16)]),
+        };
+        let trip = |trip_id: &str, route_id: &str| NetworkTrip { trip_id: trip_id.to_string(), route_id: route_id.to_string(), service_id: String::new(), stops: vec![], timings: vec![] };
+
+        assert_eq!(capacity.seats_for(&trip("VJ_1", "X4")), 80);
+        assert_eq!(capacity.seats_for(&trip("VJ_2", "X4")), 16);
+        assert_eq!(capacity.seats_for(&trip("VJ_3", "7")), 30);
+    }
+}
@@ -0,0 +1,58 @@
+//! How long a fixed route bus stays at each stop on its trip.
+//!
+//! A bus always spends the time passengers take to get on and off (the noisy `dwell_seconds`
+//! from `[simulation.noise]`), plus any fixed `min_seconds` for pulling in and out. With
+//! `timetable` on (the default) a bus which reaches a stop before the scheduled departure waits
+//! there until it, so early buses don't run ahead of the timetable and leave passengers behind.
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DwellConfig {
+    #[serde(default = "default_timetable")]
+    pub timetable: bool, // Hold early buses until the scheduled departure from `NetworkTrip::timings`
+    #[serde(default)]
+    pub min_seconds: f64, // Spent at every stop, whether or not anyone gets on or off
+}
+
+impl Default for DwellConfig {
+    fn default() -> Self {
+        DwellConfig { timetable: default_timetable(), min_seconds: 0.0 }
+    }
+}
+
+fn default_timetable() -> bool {
+    true
+}
+
+impl DwellConfig {
+    // Seconds a bus reaching a stop at `arrived` stays there, given the time boarding and alighting
+    // took and the stop's scheduled departure, both in seconds into the service day
+    pub fn dwell_seconds(&self, arrived: f64, departure: f64, boarding_seconds: f64) -> f64 {
+        let serving = self.min_seconds.max(0.0) + boarding_seconds;
+        if !self.timetable || departure <= arrived {
+            return serving;
+        }
+        serving.max(departure - arrived)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn early_buses_wait_for_the_timetable() {
+        let time = |m: u32, s: u32| (8 * 3600 + m * 60 + s) as f64;
+        let dwell = DwellConfig { timetable: true, min_seconds: 10.0 };
+
+        assert_eq!(dwell.dwell_seconds(time(0, 0), time(2, 0), 20.0), 120.0);
+        // Boarding took longer than the wait for the departure
+        assert_eq!(dwell.dwell_seconds(time(1, 50), time(2, 0), 20.0), 30.0);
+        // Running late
+        assert_eq!(dwell.dwell_seconds(time(3, 0), time(2, 0), 0.0), 10.0);
+
+        let untimed = DwellConfig { timetable: false, ..dwell };
+        assert_eq!(untimed.dwell_seconds(time(0, 0), time(2, 0), 20.0), 30.0);
+    }
+}
@@ -1,33 +1,59 @@
 //! Controller which handles the static case, i.e. traditional buses which get demand but do not respond to it.
 
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::{Arc, mpsc::Sender},
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
 };
 
 use chrono::{DateTime, Duration, NaiveTime, Utc};
 use eframe::epaint::{pos2, Color32, Shape};
+use serde::{Deserialize, Serialize};
 
-use crate::{graph::Graph, analytics::AnalyticsPackage};
+use crate::{analytics::{AnalyticsPackage, PassengerAnalyticsEvent, UnservedReason}, event_bus::Topic, graph::Graph, units::Seconds};
 
 use self::{
-    agent::{BusPassenger, StaticAgent, PassengerStatus},
-    routes::{closest_stop_to_point, NetworkData},
+    capacity::CapacityConfig,
+    dwell::DwellConfig,
+    agent::{send_analytics, BusPassenger, BusStatus, StaticAgent, PassengerStatus},
+    routes::{service_time, NetworkData},
 };
 
-use super::{demand::Demand, Agent, Controller};
+use super::{demand::Demand, display::DisplayLayer, escalation::{has_used, ServiceMode}, noise::Noise, population::{Completed, PassengerSnapshot, PassengerState}, replay::{Frame, RecordedPassenger, RecordedStatus, RecordedVehicle}, router::DemandRouter, walking::WalkingConfig, walking_overlay::{sampled, WalkingLeg}, Agent, Controller, DEFAULT_TICK_SECONDS};
 
 pub mod agent;
+pub mod capacity;
+pub mod dwell;
 pub mod routes;
+pub mod time_expanded;
+
+/// The parts of the controller which change during a run, saved in simulation snapshots
+#[derive(Serialize, Deserialize)]
+pub struct StaticState {
+    buses: Vec<StaticAgent>,
+    passenger_pool: Vec<BusPassenger>,
+    passenger_id: u32,
+    noise_seed: u64,
+    #[serde(default)]
+    completed: Vec<Completed>,
+}
 
 #[derive(Default)]
 pub struct StaticController {
-    buses: HashMap<u32, StaticAgent>, // Each 'bus' gets a trip
+    buses: BTreeMap<u32, StaticAgent>, // Each 'bus' gets a trip, kept in trip order so they board the same way every run
     network_data: Arc<NetworkData>,
-    passenger_pool: Vec<BusPassenger>,
-    analytics: Option<Sender<AnalyticsPackage>>,
+    passenger_pool: Vec<BusPassenger>, // Passengers still travelling who aren't on a bus
+    completed: Vec<Completed>, // Passengers who have reached their destination
+    analytics: Option<Topic<AnalyticsPackage>>,
     passenger_id: u32,
     demand_scale: f64,
+    router: Option<Arc<dyn DemandRouter>>,
+    noise: Noise,
+    tick_seconds: Option<Seconds>, // Simulated time per tick, overriding the default
+    dwell: DwellConfig, // How long buses stay at their stops
+    capacity: CapacityConfig, // Seats on each bus
+    walking: WalkingConfig, // How long passengers take to walk to and between stops
+    winding_down: bool, // Another controller is active, buses finish their trips but start no more
 }
 
 impl Controller for StaticController {
@@ -38,6 +64,45 @@ impl Controller for StaticController {
         self.buses.values().collect()
     }
 
+    // Passengers walking to or waiting at their stop
+    fn queued_requests(&self) -> usize {
+        self.passenger_pool.iter().filter(|p| p.is_waiting_for_service()).count()
+    }
+
+    fn snapshot_passengers(&self) -> Vec<PassengerSnapshot> {
+        let stop_position = |stop: u32| self.network_data.stops.get(&stop).map(|stop| stop.position());
+        let snapshot = |passenger: &BusPassenger, state, pos, vehicle| PassengerSnapshot {
+            id: passenger.id,
+            mode: ServiceMode::FixedRoute,
+            state,
+            pos,
+            destination: passenger.dest_pos,
+            vehicle,
+            next_stop: passenger.instructions.front().and_then(|control| match (state, control.source) {
+                (PassengerState::Riding, _) | (_, Err(_)) => stop_position(control.destination_stop),
+                (_, Ok(stop)) => stop_position(stop),
+            }),
+            rides_left: passenger.instructions.iter().filter(|control| control.source.is_ok()).count(),
+        };
+
+        let mut passengers = Vec::new();
+        for passenger in self.passenger_pool.iter().filter(|p| p.status != PassengerStatus::Finished) {
+            let (state, pos) = match passenger.status {
+                PassengerStatus::Waiting => {
+                    let stop = passenger.instructions.front().and_then(|control| control.source.ok()).and_then(stop_position);
+                    (PassengerState::Waiting, stop.unwrap_or(passenger.position))
+                }
+                _ if passenger.is_waiting_for_service() => (PassengerState::WalkingToStop, passenger.position),
+                _ => (PassengerState::WalkingToDestination, passenger.position),
+            };
+            passengers.push(snapshot(passenger, state, pos, None));
+        }
+        for bus in self.buses.values() {
+            passengers.extend(bus.passengers.iter().map(|passenger| snapshot(passenger, PassengerState::Riding, bus.position, Some(bus.trip_id))));
+        }
+        passengers
+    }
+
     fn spawn_agent(&mut self, _graph: std::sync::Arc<crate::graph::Graph>) -> Option<&Self::Agent> {
         None
     }
@@ -48,65 +113,81 @@ impl Controller for StaticController {
         demand: std::sync::Arc<super::demand::DemandGenerator>,
         time: chrono::DateTime<chrono::Utc>,
     ) {
-        // spawn any agents which will be starting this tick
-        self.network_data
+        let demand_queue = demand.generate_scaled_amount(self.demand_scale, &time, Err(self.network_data.clone()));
+        for d in demand_queue {
+            self.inject_demand(d, time);
+        }
+
+        self.step(graph, time);
+    }
+}
+
+impl StaticController {
+
+    // Spawn buses starting their trips, move them and update the passengers without generating any new demand
+    pub fn step(&mut self, graph: Arc<Graph>, time: DateTime<Utc>) {
+        let tick_seconds = self.tick_seconds();
+        let now = self.network_data.service_seconds_at(time) as f64;
+
+        // spawn any agents which will be starting this tick, in trip order
+        let mut starting: Vec<_> = self.network_data
             .trips
             .iter()
+            .filter(|_| !self.winding_down)
             .filter(|trip| {
                 // trip begins in this tick
-                // if time is less than a minute after the start time, then we should spawn the agent.
-                time.time() - trip.1.timings[0].0 >= Duration::zero()
-                    && time.time() - trip.1.timings[0].0 < Duration::minutes(1)
+                // if time is less than a tick after the start time, then we should spawn the agent.
+                let start = trip.1.timings[0].0 as f64;
+                now >= start && Seconds(now - start) < tick_seconds
             })
+            .collect();
+        starting.sort_unstable_by_key(|(id, _)| **id);
+        starting
+            .into_iter()
             .for_each(|(id, trip)| {
                 println!(
                     "\tSpawning agent {}\t{:?}\t{:?}",
                     id,
                     time.time(),
-                    trip.timings[0]
+                    service_time(trip.timings[0].0)
                 );
                 // Spawn a new agent
                 self.buses.insert(
                     *id,
-                    StaticAgent::new(*id, graph.clone(), self.network_data.clone(), self.analytics.clone()),
+                    StaticAgent::new(*id, self.capacity.seats_for(trip), graph.clone(), self.network_data.clone(), self.analytics.clone()),
                 );
             });
 
-        let demand_queue = demand.generate_scaled_amount(self.demand_scale, &time, Err(self.network_data.clone()));
-        let demand_queue: VecDeque<_> = demand_queue
-            .into_iter()
-            .map(|d| {
-                let passenger = demand_to_passenger(d, graph.clone(), self.network_data.clone(), time, self.passenger_id, self.analytics.clone());
-                self.passenger_id += 1;
-                passenger
-            })
-            .filter(|p| p.is_some()) 
-            .map(|p| p.unwrap())
-            .collect();
-        self.passenger_pool.extend(demand_queue);
-
         for agent in self.buses.values_mut() {
             let trip_id = agent.trip_id;
-            let capacity = agent.get_capacity();
             
             // Fire the agent update function
-            agent.move_self(time, |trip, stop, mut drop_off_passengers| {
+            agent.move_self(time, tick_seconds, &self.walking, &mut self.noise, &self.dwell, |trip, stop, capacity, mut drop_off_passengers| {
                 
-                let mut get_on_passengers = Vec::new();
-                let mut i = 0;
-                while i < self.passenger_pool.len() {
-                    let passenger = self.passenger_pool.get(i).unwrap();
-                    
-                    // Ensure the passenger wants to get on this bus & that we're not gonna add more passengers than capacity left
-                    if passenger.should_get_on(trip, stop, self.network_data.clone()) && get_on_passengers.len() < capacity {
-                        get_on_passengers.push(self.passenger_pool.remove(i));
-                    } else {
-                        i += 1;
-                    }
+                // Everyone here who wants this bus, longest waiting first so passengers left behind by
+                // an earlier full bus get on before those who've just arrived
+                let mut wanting: Vec<usize> = (0..self.passenger_pool.len())
+                    .filter(|&i| self.passenger_pool[i].should_get_on(trip, stop, self.network_data.clone()))
+                    .collect();
+                wanting.sort_by_key(|&i| Reverse(self.passenger_pool[i].wait_ticks));
+                let (boarding, left_behind) = wanting.split_at(wanting.len().min(capacity));
+
+                // The rest are refused and roll over to a later trip
+                for &i in left_behind {
+                    let passenger = &mut self.passenger_pool[i];
+                    let next_trip = passenger
+                        .instructions
+                        .front()
+                        .and_then(|control| self.network_data.next_trip_serving(trip, stop, control.destination_stop));
+                    passenger.roll_over(trip, stop, next_trip, time);
                 }
 
+                let mut boarding = boarding.to_vec();
+                boarding.sort_unstable_by_key(|&i| Reverse(i));
+                let get_on_passengers: Vec<_> = boarding.into_iter().map(|i| self.passenger_pool.remove(i)).collect();
+
                 drop_off_passengers.iter_mut().for_each(|p| {
-                    p.get_off_bus(trip_id);
+                    p.get_off_bus(trip_id, time);
                 });
 
                 self.passenger_pool
@@ -118,18 +199,55 @@ impl Controller for StaticController {
 
         // have some passenger update cycle which feeds into the analytics
         self.passenger_pool.iter_mut().for_each(|p| {
-            p.update(self.network_data.clone());
+            p.update(self.network_data.clone(), tick_seconds, &self.walking);
         });
+        self.archive_finished(time);
     }
-}
 
-impl StaticController {
+    // Move passengers who have reached their destination out of the pool
+    fn archive_finished(&mut self, time: DateTime<Utc>) {
+        let (finished, travelling): (Vec<_>, Vec<_>) = self.passenger_pool.drain(..).partition(|p| p.status == PassengerStatus::Finished);
+        self.passenger_pool = travelling;
+        self.completed.extend(finished.iter().map(|p| p.completed(time)));
+    }
 
     pub fn set_demand_scale(&mut self, scale: f64) {
         self.demand_scale = scale;
     }
 
-    pub fn set_analytics(&mut self, tx: Option<Sender<AnalyticsPackage>>) {
+    pub fn set_tick_seconds(&mut self, seconds: Seconds) {
+        self.tick_seconds = Some(seconds);
+    }
+
+    pub fn set_dwell(&mut self, dwell: DwellConfig) {
+        self.dwell = dwell;
+    }
+
+    pub fn set_capacity(&mut self, capacity: CapacityConfig) {
+        self.capacity = capacity;
+    }
+
+    pub fn set_walking(&mut self, walking: WalkingConfig) {
+        self.walking = walking;
+    }
+
+    pub fn set_winding_down(&mut self, winding_down: bool) {
+        self.winding_down = winding_down;
+    }
+
+    pub fn next_passenger_id(&self) -> u32 {
+        self.passenger_id
+    }
+
+    pub fn set_next_passenger_id(&mut self, id: u32) {
+        self.passenger_id = id;
+    }
+
+    pub fn tick_seconds(&self) -> Seconds {
+        self.tick_seconds.unwrap_or(Seconds(DEFAULT_TICK_SECONDS as f64))
+    }
+
+    pub fn set_analytics(&mut self, tx: Option<Topic<AnalyticsPackage>>) {
         println!("[ANALYTICS] Set analytics to {:?}", tx.is_some());
         self.analytics = tx;
     }
@@ -138,137 +256,177 @@ impl StaticController {
         self.network_data = data;
     }
 
-    pub fn get_display(&self) -> Vec<Shape> {
-        let mut shapes = Vec::new();
-        self.buses
+    pub fn set_router(&mut self, router: Arc<dyn DemandRouter>) {
+        self.router = Some(router);
+    }
+
+    pub fn set_noise(&mut self, noise: Noise) {
+        self.noise = noise;
+    }
+
+    // Copy out the running buses and waiting passengers for a snapshot, reseeding the noise
+    pub fn save_state(&mut self) -> StaticState {
+        StaticState {
+            buses: self.buses.values().cloned().collect(),
+            passenger_pool: self.passenger_pool.clone(),
+            passenger_id: self.passenger_id,
+            noise_seed: self.noise.checkpoint(),
+            completed: self.completed.clone(),
+        }
+    }
+
+    // Replace the buses and passengers with a snapshot's, reattaching the graph, network and analytics
+    pub fn restore_state(&mut self, state: StaticState, graph: Arc<Graph>) {
+        self.buses = state
+            .buses
+            .into_iter()
+            .map(|mut agent| {
+                agent.graph = graph.clone();
+                agent.network_data = self.network_data.clone();
+                agent.analytics = self.analytics.clone();
+                agent.capacity = self.network_data.trips.get(&agent.trip_id).map_or(self.capacity.seats, |trip| self.capacity.seats_for(trip));
+                agent.passengers.iter_mut().for_each(|p| p.analytics = self.analytics.clone());
+                (agent.trip_id, agent)
+            })
+            .collect();
+        self.passenger_pool = state.passenger_pool;
+        self.passenger_pool.iter_mut().for_each(|p| p.analytics = self.analytics.clone());
+        self.passenger_id = state.passenger_id;
+        self.noise.reseed(state.noise_seed);
+        self.completed = state.completed;
+    }
+
+    // Take out passengers who have waited at a stop for at least `max_wait` ticks and haven't been on DRT yet
+    pub fn take_long_waits(&mut self, max_wait: u32) -> Vec<BusPassenger> {
+        let (waited, rest) = self.passenger_pool.drain(..).partition(|p| {
+            p.status == PassengerStatus::Waiting && p.wait_ticks >= max_wait && !has_used(&p.escalations, ServiceMode::Drt)
+        });
+        self.passenger_pool = rest;
+        waited
+    }
+
+    // Take out everyone walking to or waiting at a stop, to be handed to another controller
+    pub fn take_waiting(&mut self) -> Vec<BusPassenger> {
+        let (waiting, rest) = self.passenger_pool.drain(..).partition(|p| p.is_waiting_for_service());
+        self.passenger_pool = rest;
+        waiting
+    }
+
+    // Add a passenger coming from elsewhere (e.g. escalated from DRT)
+    pub fn accept_passenger(&mut self, passenger: BusPassenger) {
+        self.passenger_pool.push(passenger);
+    }
+
+    // Turn a demand into a passenger waiting for a bus, None if there's no bus journey for it
+    pub fn inject_demand(&mut self, demand: Demand, time: DateTime<Utc>) -> Option<u32> {
+        let router = self.router.as_deref().expect("Static controller has no router");
+        let (origin, destination) = ((demand.0.0 as f64, demand.0.1 as f64), (demand.1.0 as f64, demand.1.1 as f64));
+        let passenger = demand_to_passenger(demand, router, time, self.passenger_id, self.analytics.clone());
+        self.passenger_id += 1;
+        let Some(passenger) = passenger else {
+            // Ends the road graph doesn't join point to the data rather than the timetable
+            let reason = if router.connected(origin, destination) { UnservedReason::NoRoute } else { UnservedReason::Disconnected };
+            send_analytics(&self.analytics, AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Unserved { reason, origin, destination }));
+            return None;
+        };
+        passenger.requested(time);
+        let id = passenger.id;
+        self.passenger_pool.push(passenger);
+        Some(id)
+    }
+
+    // Walks to the first stop and from the last stop for one in `every` passengers still travelling
+    pub fn walking_legs(&self, every: u32) -> Vec<WalkingLeg> {
+        let stop_position = |stop: &u32| self.network_data.stops.get(stop).map(|stop| stop.position());
+        let mut legs = Vec::new();
+        for passenger in self.passenger_pool.iter().filter(|p| p.status != PassengerStatus::Finished && sampled(p.id, every)) {
+            if let Some(stop) = stop_position(&passenger.source_stop) {
+                legs.push(WalkingLeg { from: passenger.source_pos, to: stop });
+            }
+            if let Some(stop) = stop_position(&passenger.dest_stop) {
+                legs.push(WalkingLeg { from: stop, to: passenger.dest_pos });
+            }
+        }
+        legs
+    }
+
+    // Add the running buses and every passenger not yet finished to a replay frame
+    pub fn record_frame(&self, frame: &mut Frame) {
+        let stop_position = |stop: &u32| self.network_data.stops.get(stop).map(|stop| stop.position());
+        frame.passengers.extend(self.passenger_pool.iter().filter_map(|passenger| {
+            let (pos, status) = match passenger.status {
+                PassengerStatus::Generated => (passenger.source_pos, RecordedStatus::Walking),
+                PassengerStatus::Walking(_) => (passenger.position, RecordedStatus::Walking),
+                PassengerStatus::Waiting => (stop_position(&passenger.source_stop).unwrap_or(passenger.source_pos), RecordedStatus::Waiting),
+                PassengerStatus::OnBus | PassengerStatus::Finished => return None,
+            };
+            Some(RecordedPassenger::new(passenger.id, pos, status))
+        }));
+
+        for bus in self.buses.values().filter(|bus| bus.status == BusStatus::Active) {
+            frame.vehicles.push(RecordedVehicle::of(bus));
+            frame.passengers.extend(bus.passengers.iter().map(|p| RecordedPassenger::new(p.id, bus.position, RecordedStatus::Riding)));
+        }
+    }
+
+    pub fn get_display(&self) -> Vec<(DisplayLayer, Vec<Shape>)> {
+        let buses = self.buses
             .values()
-            .for_each(|bus| shapes.push(bus.display()));
+            .map(|bus| bus.display())
+            .collect();
 
-        shapes.extend(self.passenger_pool.iter().filter(|p| p.status != PassengerStatus::Finished).map(|passenger| {
+        let passengers = self.passenger_pool.iter().filter(|p| p.status != PassengerStatus::Finished).map(|passenger| {
+            let pos = if matches!(passenger.status, PassengerStatus::Walking(_)) { passenger.position } else { passenger.source_pos };
             Shape::circle_filled(
-                pos2(passenger.source_pos.0 as f32, passenger.source_pos.1 as f32),
+                pos2(pos.0 as f32, pos.1 as f32),
                 1.0,
                 Color32::LIGHT_RED,
             )
-        }));
+        }).collect();
 
-        shapes.extend(self.network_data.stops.iter().map(|stop| {
+        let stops = self.network_data.stops.iter().map(|stop| {
             Shape::circle_filled(
                 pos2(stop.1.easting as f32, stop.1.northing as f32),
                 1.0,
                 Color32::LIGHT_BLUE,
             )
-        }));
+        }).collect();
 
-        shapes
+        vec![(DisplayLayer::Vehicles, buses), (DisplayLayer::Passengers, passengers), (DisplayLayer::Stops, stops)]
     }
 }
 
 // TODO: try to make passengers more smart in picking the right stops s.t. a bus route actually exists between them?
 pub fn demand_to_passenger(
     demand: Demand,
-    graph: Arc<Graph>,
-    network_data: Arc<NetworkData>,
+    router: &dyn DemandRouter,
     tick: DateTime<Utc>,
     id: u32, 
-    analytics: Option<Sender<AnalyticsPackage>>,
+    analytics: Option<Topic<AnalyticsPackage>>,
 ) -> Option<BusPassenger> {
-    let source = demand.0;
-    let dest = demand.1;
-
-    // simple pathfinding plan:
-    // 1. find the closest bus stop to the demand source (Control: Passenger walks to this bus stop)
-    //  (a) if the distance to source stop is >30m the trip is rejected
-    // 2. find the next trip from that bus stop which takes the passenger closest to the destination position (Control: Passenger takes trip to destination)
-    //  (a) wrt to timings say there's a 20 minute max wait for a trip to get close to the destination
-    // 3. at the next stop if it's say within 30 min walk the passenger can just walk (Control: walk to destination)
-    //  (a) if the distance is longer try looking again at trips from this stop or the neighbourhood which take the passenger
-    //      closer to the destination applying a smaller wait rule
-    //  (b) if the distance is still too far, then the passenger will just walk to the destination
-
-    let (source_bus_stop, source_dist) =
-        closest_stop_to_point((source.0 as f64, source.1 as f64), network_data.clone());
-
-    let (destination_bus_stop, dest_dist) =
-        closest_stop_to_point((dest.0 as f64, dest.1 as f64), network_data.clone());
-
-    let control = basic_route_finding(source_bus_stop, destination_bus_stop, (source.0 as f64, source.1 as f64), tick, network_data.clone());
-
-    // let status = match control.first() {
-    //     None => PassengerStatus::Finished,
-    //     Some(Control::TakeBus { .. }) => {
-    //         PassengerStatus::Waiting
-    //     },
-    //     Some(Control::WalkToStop { destination_stop, .. }) => {
-    //         let stop_data = network_data.stops.get(destination_stop).expect("Stop was not a stop");
-    //         let dist = distance((source.0 as f64, source.1 as f64), stop_data.position());
-    //         PassengerStatus::Walking((dist / (60.0 * 1.4)) as u32)
-    //     }
-    // };
+    let source = (demand.0.0 as f64, demand.0.1 as f64);
+    let dest = (demand.1.0 as f64, demand.1.1 as f64);
+
+    // Walk to the closest stop, then buses (changing where needed) to the stop closest to the destination
+    let itinerary = router.plan_itinerary(source, dest, tick)?;
 
     Some(BusPassenger {
         id,
-        source_pos: (source.0 as f64, source.1 as f64),
-        source_stop: source_bus_stop,
+        source_pos: source,
+        source_stop: itinerary.source_stop,
 
-        dest_pos: (dest.0 as f64, dest.1 as f64),
-        dest_stop: destination_bus_stop,
+        dest_pos: dest,
+        dest_stop: itinerary.dest_stop,
 
-        instructions: VecDeque::from_iter(control.into_iter()),
+        instructions: VecDeque::from_iter(itinerary.legs.into_iter()),
         status: PassengerStatus::Generated,
+        position: source,
+        desired_arrival: demand.3,
         analytics,
+        ..Default::default()
     })
 }
 
-// Very basic route finding for passenger
-// just get source stop and take next trip closest to destination
-pub fn basic_route_finding(source_stop: u32, dest_stop: u32, source_pos: (f64, f64), tick: DateTime<Utc>, network_data: Arc<NetworkData>) -> Vec<Control> {
-    let dest_stop_data = network_data.stops.get(&dest_stop).expect("Stop was not a stop");
-    let mut control = Vec::new();
-    let trips_from_source = network_data.trips_from_stop.get(&source_stop).expect("Stop was not a stop");
-
-
-    control.push(Control::walk_to_stop(source_stop, source_pos));
-    // control.push(Control::walk_to_stop(source_stop, None));
-
-    let mut min_trip_dist = f64::MAX;
-    let mut min_trip = 0;
-    let mut min_trip_end_stop = 0;
-
-    for trip in trips_from_source.iter().filter(|trip| {
-        // Filter for trips which are departing fairly soon-ish
-        let trip_data = network_data.trips.get(trip).expect("Trip ID was not a trip");
-        let trip_arrival_time = trip_data.timings.get(trip_data.stops.iter().enumerate().find_map(|(i, stop)|if *stop == source_stop { Some(i) } else { None }).unwrap() as usize).unwrap_or_else(|| panic!("Mismatch in length of timings and stop vectors for trip\n\ttimings:  {:?}\n\tstops: {:?}", trip_data.timings, trip_data.stops)).0;
-        
-        trip_arrival_time >= tick.time() && trip_arrival_time < (tick + Duration::minutes(20)).time()
-        // trip_arrival_time.is_some() && trip_arrival_time.unwrap() > &Utc::now().time()
-    }) {
-        let trip_data = network_data.trips.get(trip).expect("Trip ID was not a trip");
-        let trip_stops = &trip_data.stops;
-        let mut min_trip_stop_dist = f64::MAX;
-        let mut min_trip_stop = 0;
-
-        for stop in trip_stops {
-            let stop_data = network_data.stops.get(stop).expect("Stop was not a stop");
-            let dist = distance(stop_data.position(), dest_stop_data.position());
-            if dist < min_trip_stop_dist {
-                min_trip_stop_dist = dist;
-                min_trip_stop = *stop;
-            }
-        }
-
-        if min_trip_stop_dist < min_trip_dist {
-            min_trip_dist = min_trip_stop_dist;
-            min_trip = *trip;
-            min_trip_end_stop = min_trip_stop;
-        }
-    }
-
-    control.push(Control::take_bus(min_trip, source_stop, min_trip_end_stop));
-    control.push(Control { destination_stop: dest_stop, source: Ok(min_trip_end_stop) });
-    control
-}
-
 // Full route finding for passenger
 // try to get to the destination stop exactly
 // pub fn full_route_finding(source: (f32, f32), dest: (f32, f32), network_data: Arc<NetworkData>, tick: DateTime<Utc>) -> VecDeque<Control> {
@@ -346,7 +504,7 @@ pub fn basic_route_finding(source_stop: u32, dest_stop: u32, source_pos: (f64, f
 //     control
 // }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Control {
     pub destination_stop: u32, // The stop we're going to
     pub source: Result<u32, (f64, f64)>, // Give the source stop if we're walking from a stop, or the source position if we're walking from a position
@@ -362,11 +520,6 @@ impl Control {
     }
 }
 
-fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
-    let xs = (a.0 - b.0).abs();
-    let ys = (a.1 - b.1).abs();
-    xs.hypot(ys)
-}
 
 pub fn times_relatively_equal(time_a: NaiveTime, time_b: NaiveTime) -> bool {
     if time_a > time_b {
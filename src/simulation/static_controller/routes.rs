@@ -1,34 +1,81 @@
 //! Define a bunch of stuff for handling GTFS data of bus routes and stops
 
-use chrono::NaiveTime;
-use gtfs_structures::{Gtfs, RouteType, Stop, Trip};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Timelike, Utc};
+use gtfs_structures::{Exception, Gtfs, RouteType, Stop, Trip};
 use proj::Proj;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
+    error::Error,
     fs,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 
 use crate::{
-    graph::{route_finding, Graph}
+    geometry::{onto_map, Crs},
+    graph::{spatial::KdTree, Graph},
+    realtime::RealtimeConfig,
 };
 
-use super::distance;
+pub const DEFAULT_GTFS_PATH: &str = "data/gtfs/tfwm_gtfs/";
+pub const DEFAULT_NETWORK_DATA: &str = "data/gtfs/tfwm_gtfs/network_data.bin";
+
+// Where the bus network is built from, e.g.
+//
+// [gtfs]
+// path = "data/gtfs/tfwm_gtfs/"
+// graph = "bham" # Keep the stops inside this [[defaults]] graph's bounds, or give them directly:
+// bounds = { left = 425174.28, right = 439679.25, top = 286113.25, bottom = 273637.59 }
+// output = "data/gtfs/tfwm_gtfs/network_data.bin"
+//
+// [gtfs.realtime] # Optional, see crate::realtime
+// url = "https://example.org/gtfs-rt/vehicle-positions"
+#[derive(Deserialize, Debug, Clone)]
+pub struct GtfsConfig {
+    #[serde(default = "default_gtfs_path")]
+    pub path: PathBuf, // Directory (or zip) of the GTFS feed
+    pub bounds: Option<GtfsBounds>, // Easting/northing box the stops must be inside, wins over `graph`
+    pub graph: Option<String>, // Key of a [[defaults]] graph to take the box from, the loaded graph if neither is given
+    #[serde(default = "default_network_data")]
+    pub output: PathBuf, // Network data written by build-network and read by the simulation
+    pub realtime: Option<RealtimeConfig>, // Live vehicle positions to show on the map
+}
+
+impl Default for GtfsConfig {
+    fn default() -> Self {
+        GtfsConfig { path: default_gtfs_path(), bounds: None, graph: None, output: default_network_data(), realtime: None }
+    }
+}
+
+fn default_gtfs_path() -> PathBuf {
+    PathBuf::from(DEFAULT_GTFS_PATH)
+}
+
+fn default_network_data() -> PathBuf {
+    PathBuf::from(DEFAULT_NETWORK_DATA)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GtfsBounds {
+    pub left: f64,
+    pub right: f64,
+    pub top: f64,
+    pub bottom: f64,
+}
 
-// Load the GTFS data and create an serialised version for quick loading in the application
-pub fn load_routes() {
-    let data = Gtfs::new("data/gtfs/tfwm_gtfs/").unwrap();
+// Load the GTFS data and create an serialised version for quick loading in the application, the
+// stops projected onto a map in `crs`
+pub fn load_routes(config: &GtfsConfig, bounds: GtfsBounds, crs: Crs) -> Result<(), Box<dyn Error>> {
+    let data = Gtfs::new(&config.path.to_string_lossy())?;
     println!("load time: {:?}", data.read_duration);
 
     data.print_stats();
 
-    let proj_instance = Proj::new_known_crs("EPSG:4326", "EPSG:27700", None).unwrap();
+    let proj_instance = crs.from_wgs84()?;
 
-    let left = 425174.28;
-    let right = 439679.25;
-    let top = 286113.25;
-    let bottom = 273637.59;
+    let GtfsBounds { left, right, top, bottom } = bounds;
+    println!("Keeping stops between {}-{} east and {}-{} north", left, right, bottom, top);
     let mut i = 0;
 
     let mut stop_id = 0_u32;
@@ -40,7 +87,7 @@ pub fn load_routes() {
             .filter(|(_, stop)| {
                 let lat = stop.latitude.expect("Stop has no latitude");
                 let lng = stop.longitude.expect("Stop as no longitude");
-                let (easting, northing) = proj_instance.convert((lng, lat)).unwrap();
+                let (easting, northing) = onto_map(proj_instance.as_ref(), (lng, lat)).unwrap();
                 left < easting && easting < right && bottom < northing && northing < top
             })
             .map(|(id, _)| id.clone()),
@@ -55,15 +102,16 @@ pub fn load_routes() {
 
     let mut trips_from_stop: HashMap<String, Vec<String>> = HashMap::new();
 
-    // Set of trip ids which are valid (all within the bounding box)
-    let valid_trips: HashMap<String, u32> = HashMap::from_iter(
+    // Trip ids which are valid (all within the bounding box), with a number for each time they run
+    let valid_trips: HashMap<String, Vec<u32>> = HashMap::from_iter(
         data.trips
             .iter()
             .filter(|(_, trip)| {
                 i += 1;
                 print!("processed trip {:?}\r", i);
                 trip.stop_times.iter().all(|stop| {
-                    valid_stops.contains(&stop.stop.id) && stop.arrival_time.unwrap() < 86400//21600 //86400
+                    // Stop times past 24:00:00 belong to trips still running after midnight
+                    valid_stops.contains(&stop.stop.id) && stop.arrival_time.is_some() && stop.departure_time.is_some()
                 }) && data.get_route(&trip.route_id).unwrap().route_type == RouteType::Bus
             })
             .map(|(id, trip)| {
@@ -81,12 +129,18 @@ pub fn load_routes() {
                         .push(id.clone());
                 });
 
-                trip_id += 1;
-                (id.clone(), trip_id)
+                // A frequency based trip runs once per headway rather than once
+                let runs = frequency_departures(trip).len().max(1);
+                let numbers = (0..runs).map(|_| {
+                    trip_id += 1;
+                    trip_id
+                }).collect();
+                (id.clone(), numbers)
             }),
     );
 
     println!("Valid trips inside bounding box: {}", valid_trips.len());
+    println!("Trips run after expanding frequencies: {}", trip_id);
     println!("Valid route inside bounding box: {}", valid_routes.len());
     println!("Used stops inside bounding box: {}", used_stops.len());
 
@@ -105,22 +159,40 @@ pub fn load_routes() {
 
     let mut network_data = NetworkData::default();
 
-    network_data.trips = HashMap::from_iter(valid_trips.iter().map(|(id, num)| {
+    network_data.trips = HashMap::from_iter(valid_trips.iter().flat_map(|(id, nums)| {
         let trip = data.get_trip(id).unwrap();
-        (*num, make_network_trip(&trip, &used_stops))
+        nums.iter().copied().zip(make_network_trips(&trip, &used_stops))
     }));
 
     network_data.stops = HashMap::from_iter(used_stops.iter().map(|(id, num)| {
         let stop = data.get_stop(id).unwrap();
-        (*num, Arc::new(make_network_stop(stop, &proj_instance)))
+        (*num, Arc::new(make_network_stop(stop, proj_instance.as_ref())))
     }));
 
+    // Days each service runs on, so a simulation only spawns the trips running on its day
+    for (id, calendar) in data.calendar.iter() {
+        let service = network_data.calendar.entry(id.clone()).or_default();
+        service.weekdays = [calendar.monday, calendar.tuesday, calendar.wednesday, calendar.thursday, calendar.friday, calendar.saturday, calendar.sunday];
+        service.start = Some(calendar.start_date);
+        service.end = Some(calendar.end_date);
+    }
+    for (id, dates) in data.calendar_dates.iter() {
+        let service = network_data.calendar.entry(id.clone()).or_default();
+        for date in dates {
+            match date.exception_type {
+                Exception::Added => service.added.insert(date.date),
+                Exception::Deleted => service.removed.insert(date.date),
+            };
+        }
+    }
+    println!("Services in the calendar: {}", network_data.calendar.len());
+
     network_data.trips_from_stop =
         HashMap::from_iter(trips_from_stop.iter().map(|(str_stop_id, str_trip_ids)| {
             let stop = used_stops.get(str_stop_id).unwrap();
             let trips = str_trip_ids
                 .iter()
-                .map(|str_trip_id| *valid_trips.get(str_trip_id).unwrap())
+                .flat_map(|str_trip_id| valid_trips.get(str_trip_id).unwrap().iter().copied())
                 .collect();
             (*stop, trips)
         }));
@@ -155,12 +227,16 @@ pub fn load_routes() {
     }
     println!("Removed {} stops with less than 12 trips. New Trips from Stop Len: {}", removed, network_data.trips_from_stop.len());
     println!("Also removed {} trips which used those stops and references to those trips from their stops.", trips_to_be_removed.len());
-    println!("Finished creating new network data. Writing to file...");
+    println!("Finished creating new network data. Writing to {:?}...", config.output);
 
     // Serialise the network data with ciborium
-    let file = std::fs::File::create("data/gtfs/tfwm_gtfs/network_data.bin").unwrap();
+    if let Some(dir) = config.output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = std::fs::File::create(&config.output)?;
     // ciborium::to_writer(&mut file, &network_data).unwrap();
-    ciborium::ser::into_writer(&network_data, file).expect("Failed to serialise network data");
+    ciborium::ser::into_writer(&network_data, file)?;
+    Ok(())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -187,8 +263,88 @@ pub struct GraphStop {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkTrip {
     pub trip_id: String,
+    #[serde(default)]
+    pub route_id: String, // GTFS route the trip runs on, empty in network data saved before routes were kept
+    #[serde(default)]
+    pub service_id: String, // GTFS service giving the days the trip runs, empty in network data saved without a calendar
     pub stops: Vec<u32>, // vector of stop id
-    pub timings: Vec<(NaiveTime, NaiveTime)>,
+    // Arrival and departure at each stop in seconds into the service day, so stops after midnight
+    // are past DAY_SECONDS rather than wrapping round to the early morning
+    #[serde(deserialize_with = "deserialize_timings")]
+    pub timings: Vec<(u32, u32)>,
+}
+
+impl NetworkTrip {
+    pub fn start(&self) -> Option<u32> {
+        self.timings.first().map(|(arrival, _)| *arrival)
+    }
+
+    // The same trip run again with its first arrival at `start` seconds into the service day
+    pub fn starting_at(&self, start: u32) -> NetworkTrip {
+        let first = self.start().unwrap_or(0);
+        let shift = |time: u32| time - first + start;
+        NetworkTrip {
+            timings: self.timings.iter().map(|(arrival, departure)| (shift(*arrival), shift(*departure))).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+// Network data saved before timings were kept in seconds has them as times of day
+fn deserialize_timings<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<(u32, u32)>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timing {
+        Seconds(u32),
+        TimeOfDay(NaiveTime),
+    }
+    let seconds = |timing| match timing {
+        Timing::Seconds(seconds) => seconds,
+        Timing::TimeOfDay(time) => service_seconds(time),
+    };
+    let timings: Vec<(Timing, Timing)> = Deserialize::deserialize(deserializer)?;
+    Ok(timings.into_iter().map(|(arrival, departure)| (seconds(arrival), seconds(departure))).collect())
+}
+
+pub const DAY_SECONDS: u32 = 86_400;
+
+// Seconds into the service day of a time before midnight
+pub fn service_seconds(time: NaiveTime) -> u32 {
+    time.num_seconds_from_midnight()
+}
+
+// The time on the clock `seconds` into the service day, wrapping round after midnight
+pub fn service_time(seconds: u32) -> NaiveTime {
+    timeint_to_time(seconds % DAY_SECONDS)
+}
+
+// Seconds between `start` and `end`, running on into the next day if `end` isn't after `start`
+pub fn service_window(start: NaiveTime, end: NaiveTime) -> (u32, u32) {
+    let (start, end) = (service_seconds(start), service_seconds(end));
+    (start, if end <= start { end + DAY_SECONDS } else { end })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+// Days a GTFS service runs on, from calendar.txt and the exceptions in calendar_dates.txt
+pub struct ServiceCalendar {
+    pub weekdays: [bool; 7], // Monday first
+    pub start: Option<NaiveDate>, // None for services only listed in calendar_dates.txt
+    pub end: Option<NaiveDate>, // Last day of service, inclusive
+    pub added: HashSet<NaiveDate>,
+    pub removed: HashSet<NaiveDate>,
+}
+
+impl ServiceCalendar {
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        if self.removed.contains(&date) {
+            return false;
+        }
+        if self.added.contains(&date) {
+            return true;
+        }
+        let in_range = self.start.map_or(false, |start| start <= date) && self.end.map_or(false, |end| date <= end);
+        in_range && self.weekdays[date.weekday().num_days_from_monday() as usize]
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -197,12 +353,197 @@ pub struct NetworkData {
     pub trips: HashMap<u32, NetworkTrip>, // Map trip ID to trip data,
     pub stops: HashMap<u32, Arc<NetworkStop>>, // Map stop ID to stop reference
     pub trips_from_stop: HashMap<u32, Vec<u32>>, // Map stop ID to trip IDs
+
+    // Map GTFS service ID to the days it runs, empty in network data saved without a calendar
+    #[serde(default)]
+    pub calendar: HashMap<String, ServiceCalendar>,
+
+    // Stops with a departure in the simulated window, None until restrict_to_served is called
+    #[serde(skip)]
+    served: Option<HashSet<u32>>,
+
+    // Built from the served stops on the first nearest stop query, so stops must all be added before then
+    #[serde(skip)]
+    stop_index: OnceLock<KdTree<u32>>,
+
+    // Next trip on the same route by departure time, built on first use
+    #[serde(skip)]
+    next_on_route: OnceLock<HashMap<u32, u32>>,
+
+    // Date the simulated service day starts on, None to take each time's own date
+    #[serde(skip)]
+    service_day: Option<NaiveDate>,
+
+    // What the stop positions are in, the graph's
+    #[serde(skip)]
+    crs: Crs,
+}
+
+impl NetworkData {
+    fn stop_index(&self) -> &KdTree<u32> {
+        self.stop_index.get_or_init(|| {
+            KdTree::build(self.stops.iter().filter(|(id, _)| self.is_served(**id)).map(|(id, stop)| (stop.position(), *id)).collect())
+        })
+    }
+
+    // Stops which some trip leaves between `start` and `end` seconds into the service day. A trip's
+    // last stop isn't a departure
+    pub fn served_stops(&self, start: u32, end: u32) -> HashSet<u32> {
+        self.trips
+            .values()
+            .flat_map(|trip| {
+                let departures = trip.stops.len().saturating_sub(1);
+                trip.stops.iter().zip(trip.timings.iter()).take(departures)
+            })
+            .filter(|(_, (_, departure))| *departure >= start && *departure <= end)
+            .map(|(stop, _)| *stop)
+            .collect()
+    }
+
+    // Only offer passengers stops with service between `start` and `end` (the next day if it isn't
+    // after `start`), so nobody is sent to wait at a stop with no buses left
+    pub fn restrict_to_served(&mut self, start: NaiveTime, end: NaiveTime) {
+        let (start_seconds, end_seconds) = service_window(start, end);
+        let served = self.served_stops(start_seconds, end_seconds);
+        println!("[Network] {} of {} stops have departures between {} and {}", served.len(), self.stops.len(), start, end);
+        self.served = Some(served);
+        self.stop_index = OnceLock::new();
+    }
+
+    // Whether `trip` runs on `date`. Every trip runs if there's no calendar to go by
+    pub fn runs_on(&self, trip: &NetworkTrip, date: NaiveDate) -> bool {
+        if self.calendar.is_empty() {
+            return true;
+        }
+        self.calendar.get(&trip.service_id).map_or(false, |service| service.runs_on(date))
+    }
+
+    // `preferred` if any trip runs on it, otherwise the closest day the calendar has trips on
+    pub fn covered_day(&self, preferred: NaiveDate) -> NaiveDate {
+        let runs = |date: NaiveDate| self.trips.values().any(|trip| self.runs_on(trip, date));
+        if self.calendar.is_empty() || runs(preferred) {
+            return preferred;
+        }
+
+        let dates = self.calendar.values().flat_map(|service| service.start.into_iter().chain(service.end).chain(service.added.iter().copied()));
+        let (Some(first), Some(last)) = (dates.clone().min(), dates.max()) else { return preferred };
+        let mut days: Vec<NaiveDate> = first.iter_days().take_while(|day| *day <= last).collect();
+        days.sort_by_key(|day| (*day - preferred).num_days().abs());
+        days.into_iter().find(|day| runs(*day)).unwrap_or(preferred)
+    }
+
+    // Drop the trips which don't run on `date`, so only that day's timetable is spawned and planned on.
+    // An error if that leaves none
+    pub fn restrict_to_day(&mut self, date: NaiveDate) -> Result<(), String> {
+        if self.calendar.is_empty() {
+            println!("[Network] No service calendar in the network data, running every trip on {}", date);
+            return Ok(());
+        }
+
+        let before = self.trips.len();
+        let running: HashSet<u32> = self.trips.iter().filter(|(_, trip)| self.runs_on(trip, date)).map(|(id, _)| *id).collect();
+        self.trips.retain(|id, _| running.contains(id));
+        for trips in self.trips_from_stop.values_mut() {
+            trips.retain(|trip| running.contains(trip));
+        }
+        self.next_on_route = OnceLock::new();
+        println!("[Network] {} of {} trips run on {}", self.trips.len(), before, date);
+        if self.trips.is_empty() && before > 0 {
+            return Err(format!("None of the {} trips in the bus network run on {}, set `service_date` in [simulation] to a day the feed covers", before, date));
+        }
+        Ok(())
+    }
+
+    pub fn set_crs(&mut self, crs: Crs) {
+        self.crs = crs;
+    }
+
+    pub fn crs(&self) -> Crs {
+        self.crs
+    }
+
+    pub fn set_service_day(&mut self, day: NaiveDate) {
+        self.service_day = Some(day);
+    }
+
+    // Seconds from the start of the service day to `time`, past DAY_SECONDS after midnight
+    pub fn service_seconds_at(&self, time: DateTime<Utc>) -> u32 {
+        let day = self.service_day.unwrap_or_else(|| time.date_naive());
+        let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+        (time.naive_utc() - midnight).num_seconds().max(0) as u32
+    }
+
+    pub fn is_served(&self, stop: u32) -> bool {
+        self.served.as_ref().map_or(true, |served| served.contains(&stop))
+    }
+
+    fn next_on_route(&self) -> &HashMap<u32, u32> {
+        self.next_on_route.get_or_init(|| {
+            let mut routes: HashMap<&str, Vec<(u32, u32)>> = HashMap::new();
+            for (id, trip) in self.trips.iter().filter(|(_, trip)| !trip.route_id.is_empty()) {
+                if let Some(start) = trip.start() {
+                    routes.entry(trip.route_id.as_str()).or_default().push((start, *id));
+                }
+            }
+
+            let mut next = HashMap::new();
+            for trips in routes.values_mut() {
+                trips.sort();
+                for pair in trips.windows(2) {
+                    next.insert(pair[0].1, pair[1].1);
+                }
+            }
+            next
+        })
+    }
+
+    // The next trip on the same route as `trip` which goes from `from` to `to`, for passengers
+    // left behind by a full bus
+    pub fn next_trip_serving(&self, trip: u32, from: u32, to: u32) -> Option<u32> {
+        let mut current = trip;
+        while let Some(&next) = self.next_on_route().get(&current) {
+            let stops = &self.trips.get(&next)?.stops;
+            if let Some(board) = stops.iter().position(|stop| *stop == from) {
+                if stops[board + 1..].contains(&to) {
+                    return Some(next);
+                }
+            }
+            current = next;
+        }
+        None
+    }
+
+    // Earliest arrival at `to` on a trip leaving `from` no earlier than `after`, both in seconds into
+    // the service day
+    pub fn earliest_arrival(&self, from: u32, to: u32, after: u32) -> Option<u32> {
+        self.trips_from_stop
+            .get(&from)?
+            .iter()
+            .filter_map(|trip| self.trips.get(trip))
+            .filter_map(|trip| {
+                let board = trip.stops.iter().position(|stop| *stop == from)?;
+                let alight = board + 1 + trip.stops[board + 1..].iter().position(|stop| *stop == to)?;
+                let departure = trip.timings.get(board)?.1;
+                (departure >= after).then(|| trip.timings.get(alight).map(|timing| timing.0)).flatten()
+            })
+            .min()
+    }
+
+    // Closest stop to a map coord and the squared distance to it, None if there are no stops
+    pub fn nearest_stop(&self, point: (f64, f64)) -> Option<(u32, f64)> {
+        self.stop_index().nearest(point)
+    }
+
+    // Stops within `radius` metres of a map coord
+    pub fn stops_within(&self, point: (f64, f64), radius: f64) -> Vec<u32> {
+        let mut stops = self.stop_index().within(point, self.crs.span(radius, point));
+        stops.retain(|stop| self.stops.get(stop).is_some_and(|found| self.crs.distance(point, found.position()) <= radius));
+        stops
+    }
 }
 
-pub fn make_network_stop(stop: &Stop, proj_instance: &Proj) -> NetworkStop {
-    let (easting, northing) = proj_instance
-        .convert((stop.longitude.unwrap(), stop.latitude.unwrap()))
-        .unwrap();
+pub fn make_network_stop(stop: &Stop, proj_instance: Option<&Proj>) -> NetworkStop {
+    let (easting, northing) = onto_map(proj_instance, (stop.longitude.unwrap(), stop.latitude.unwrap())).unwrap();
     NetworkStop {
         easting,
         northing,
@@ -217,32 +558,37 @@ pub fn make_network_trip(trip: &Trip, stop_map: &HashMap<String, u32>) -> Networ
     trip.stop_times.iter().for_each(|stop| {
         stops.push(stop_map.get(&stop.stop.id).unwrap().clone());
 
-        let arrival = timeint_to_time(stop.arrival_time.unwrap());
-        let departure = timeint_to_time(stop.departure_time.unwrap());
-
-        timings.push((arrival, departure));
+        timings.push((stop.arrival_time.unwrap(), stop.departure_time.unwrap()));
     });
 
     NetworkTrip {
         trip_id: trip.id.clone(),
+        route_id: trip.route_id.clone(),
+        service_id: trip.service_id.clone(),
         stops,
         timings,
     }
 }
 
-pub fn closest_stop_to_point(point: (f64, f64), network_data: Arc<NetworkData>) -> (u32, f64) {
-    let mut min_distance = f64::MAX;
-    let mut closest_stop = None;
+// Departures from the first stop of a frequency based trip (see frequencies.txt), one every headway
+// from each entry's start up to its end. Empty for a trip with a timetable
+pub fn frequency_departures(trip: &Trip) -> Vec<u32> {
+    trip.frequencies
+        .iter()
+        .filter(|frequency| frequency.headway_secs > 0)
+        .flat_map(|frequency| (frequency.start_time..frequency.end_time).step_by(frequency.headway_secs as usize))
+        .collect()
+}
 
-    for (id, stop) in network_data.stops.iter() {
-        let distance = (stop.easting - point.0).powi(2) + (stop.northing - point.1).powi(2);
-        if distance < min_distance {
-            min_distance = distance;
-            closest_stop = Some(id);
-        }
+// Every run of a trip. A frequency based trip's stop times only give the time between its stops, so
+// they're moved to start at each departure in turn
+pub fn make_network_trips(trip: &Trip, stop_map: &HashMap<String, u32>) -> Vec<NetworkTrip> {
+    let template = make_network_trip(trip, stop_map);
+    let departures = frequency_departures(trip);
+    if departures.is_empty() {
+        return vec![template];
     }
-
-    (closest_stop.unwrap().clone(), min_distance)
+    departures.into_iter().map(|start| template.starting_at(start)).collect()
 }
 
 pub fn get_graph_edge_from_stop(stop: &NetworkStop, graph: Arc<Graph>) -> u128 {
@@ -251,7 +597,8 @@ pub fn get_graph_edge_from_stop(stop: &NetworkStop, graph: Arc<Graph>) -> u128 {
 
     let stop_point = stop.position();
 
-    for (id, edge) in graph.get_edgelist() {
+    // Snapped onto the roads as loaded, a simplified graph's merged edges would only give their chord
+    for (id, edge) in graph.snapping_edges() {
         // println!("GGEFS: Examining {:?}", id);
         let edge_u = edge.points.first().unwrap();
         let edge_v = edge.points.last().unwrap();
@@ -276,7 +623,7 @@ pub fn get_graph_edge_from_stop(stop: &NetworkStop, graph: Arc<Graph>) -> u128 {
         }
     }
 
-    *closest_edge.unwrap()
+    graph.routing_edge(*closest_edge.unwrap())
 }
 
 // Taken from Paul Bourke
@@ -337,8 +684,8 @@ pub fn convert_trip_to_graph_path(
             Some(prev_node) => {
                 let prev_node_data = graph.get_nodelist().get(prev_node).expect("Node referenced in trip does not exist");
                 
-                let start_distance = distance(start_node_data.point, prev_node_data.point);
-                let end_distance = distance(end_node_data.point, prev_node_data.point);
+                let start_distance = graph.distance(start_node_data.point, prev_node_data.point);
+                let end_distance = graph.distance(end_node_data.point, prev_node_data.point);
                 
                 let target_node = if start_distance <= end_distance {
                     start_node_id
@@ -346,15 +693,15 @@ pub fn convert_trip_to_graph_path(
                     end_node_id
                 };
 
-                let subroute = route_finding::find_route(&graph, *prev_node, target_node);
+                let subroute = graph.shortest_path_fast(*prev_node, target_node);
                 route.extend(subroute.into_iter().rev()); //TODO: might need to skip 1 or add destination on at end
             },
             None if i == 0 => {
                 let next_stop = trip.stops[i + 1];
                 let next_stop_position = network_data.stops.get(&next_stop).expect("Stop referenced in trip does not exist").position();
                                
-                let start_distance = distance(start_node_data.point, next_stop_position);
-                let end_distance = distance(end_node_data.point, next_stop_position);
+                let start_distance = graph.distance(start_node_data.point, next_stop_position);
+                let end_distance = graph.distance(end_node_data.point, next_stop_position);
                 
                 if start_distance <= end_distance {
                     route.push(edge_data.start_id);
@@ -369,8 +716,28 @@ pub fn convert_trip_to_graph_path(
     (route, edges)
 }
 
-pub fn load_saved_network_data() -> Option<NetworkData> {
-    ciborium::de::from_reader(fs::File::open("data/gtfs/tfwm_gtfs/network_data.bin").unwrap()).ok()
+pub fn load_saved_network_data(path: &Path) -> Option<NetworkData> {
+    ciborium::de::from_reader(fs::File::open(path).ok()?).ok()
+}
+
+// The saved network data, building it from the GTFS feed first if there isn't any (or it can't be
+// read), for a graph in `crs`
+pub fn load_or_build_network_data(config: &GtfsConfig, crs: Crs) -> Result<NetworkData, Box<dyn Error>> {
+    if let Some(mut network_data) = load_saved_network_data(&config.output) {
+        network_data.set_crs(crs);
+        return Ok(network_data);
+    }
+
+    let reason = if config.output.exists() { "Couldn't read the" } else { "No" };
+    let bounds = config.bounds.ok_or_else(|| {
+        format!("{} network data at {:?} and no [gtfs] bounds or graph to build it with, run `odbrs build-network` after setting them", reason, config.output)
+    })?;
+    println!("[Network] {} network data at {:?}, building it from {:?}", reason, config.output, config.path);
+    load_routes(config, bounds, crs).map_err(|err| format!("Couldn't build the network data from {:?}: {}", config.path, err))?;
+
+    let mut network_data = load_saved_network_data(&config.output).ok_or_else(|| format!("Built the network data but couldn't read it back from {:?}", config.output))?;
+    network_data.set_crs(crs);
+    Ok(network_data)
 }
 
 pub fn timeint_to_time(time: u32) -> chrono::NaiveTime {
@@ -383,10 +750,7 @@ pub fn timeint_to_time(time: u32) -> chrono::NaiveTime {
 }
 
 pub fn stop_neighbourhood_pos(pos: (f64, f64), threshold: f64, network_data: Arc<NetworkData>) -> Vec<u32> {
-    network_data.stops.iter().filter(|(_, stop)| {
-        let stop_pos = stop.position();
-        distance(pos, stop_pos) <= threshold
-    }).map(|(id, _)| *id).collect()
+    network_data.stops_within(pos, threshold)
 }
 
 pub fn stop_neighbourhood(stop: u32, threshold: f64, network_data: Arc<NetworkData>) -> Vec<u32> {
@@ -399,14 +763,197 @@ pub fn stop_neighbourhood(stop: u32, threshold: f64, network_data: Arc<NetworkDa
 mod test {
     use std::time::Instant;
 
+    use gtfs_structures::Frequency;
+
     use super::*;
 
+    #[test]
+    fn nearest_stop_skips_unserved_stops() {
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let seconds = |hour: u32| hour * 3600;
+        let stop = |easting| Arc::new(NetworkStop { easting, northing: 0.0, stop_id: String::new() });
+
+        let mut data = NetworkData::default();
+        for id in 0..3 {
+            data.stops.insert(id, stop(id as f64 * 100.0));
+        }
+        // Stop 0 only has an early departure and stop 2 is only ever the end of the line
+        data.trips.insert(0, NetworkTrip { trip_id: String::new(), route_id: String::new(), service_id: String::new(), stops: vec![0, 1], timings: vec![(seconds(5), seconds(5)), (seconds(6), seconds(6))] });
+        data.trips.insert(1, NetworkTrip { trip_id: String::new(), route_id: String::new(), service_id: String::new(), stops: vec![1, 2], timings: vec![(seconds(9), seconds(9)), (seconds(10), seconds(10))] });
+
+        assert_eq!(data.nearest_stop((0.0, 0.0)).unwrap().0, 0);
+
+        data.restrict_to_served(time(8), time(18));
+        assert_eq!(data.served_stops(seconds(8), seconds(18)), HashSet::from([1]));
+        assert_eq!(data.nearest_stop((0.0, 0.0)).unwrap().0, 1);
+        assert_eq!(data.nearest_stop((250.0, 0.0)).unwrap().0, 1);
+    }
+
+    #[test]
+    fn stops_within_walking_distance_are_found_in_metres_on_lat_lon_maps() {
+        // Stops about 200m east and 1.1km north of the first, in degrees
+        let stop = |easting, northing| Arc::new(NetworkStop { easting, northing, stop_id: String::new() });
+        let mut data = NetworkData::default();
+        data.set_crs(Crs::Wgs84);
+        data.stops.insert(0, stop(-1.5, 52.4));
+        data.stops.insert(1, stop(-1.497, 52.4));
+        data.stops.insert(2, stop(-1.5, 52.41));
+
+        let mut near = data.stops_within((-1.5, 52.4), 500.0);
+        near.sort();
+        assert_eq!(near, vec![0, 1]);
+        assert_eq!(data.stops_within((-1.5, 52.4), 100.0), vec![0]);
+        assert_eq!(data.stops_within((-1.5, 52.4), 1500.0).len(), 3);
+    }
+
+    #[test]
+    fn earliest_arrival_takes_first_departure() {
+        let time = |hour: u32, minute: u32| hour * 3600 + minute * 60;
+        let trip = |start: u32| NetworkTrip { trip_id: String::new(), route_id: String::new(), service_id: String::new(), stops: vec![0, 1], timings: vec![(time(9, start), time(9, start)), (time(9, start + 15), time(9, start + 15))] };
+
+        let mut data = NetworkData::default();
+        data.trips.insert(0, trip(0));
+        data.trips.insert(1, trip(30));
+        data.trips_from_stop.insert(0, vec![0, 1]);
+
+        assert_eq!(data.earliest_arrival(0, 1, time(8, 50)), Some(time(9, 15)));
+        assert_eq!(data.earliest_arrival(0, 1, time(9, 10)), Some(time(9, 45)));
+        assert_eq!(data.earliest_arrival(0, 1, time(9, 40)), None);
+        // Trips only run one way
+        assert_eq!(data.earliest_arrival(1, 0, time(8, 0)), None);
+    }
+
+    #[test]
+    fn next_trip_follows_the_route() {
+        let time = |minute: u32| 9 * 3600 + minute * 60;
+        let trip = |route: &str, start: u32, stops: Vec<u32>| NetworkTrip {
+            trip_id: String::new(),
+            route_id: route.to_string(),
+            service_id: String::new(),
+            timings: stops.iter().map(|_| (time(start), time(start))).collect(),
+            stops,
+        };
+
+        let mut data = NetworkData::default();
+        data.trips.insert(0, trip("1", 0, vec![0, 1, 2]));
+        data.trips.insert(1, trip("1", 20, vec![2, 1, 0])); // Back the other way
+        data.trips.insert(2, trip("1", 40, vec![0, 1, 2]));
+        data.trips.insert(3, trip("2", 10, vec![0, 1, 2]));
+
+        assert_eq!(data.next_trip_serving(0, 0, 2), Some(2));
+        assert_eq!(data.next_trip_serving(0, 1, 0), Some(1));
+        assert_eq!(data.next_trip_serving(2, 0, 2), None);
+        assert_eq!(data.next_trip_serving(3, 0, 2), None);
+    }
+
+    #[test]
+    fn trips_run_on_past_midnight() {
+        let time = |hour: u32, minute: u32| hour * 3600 + minute * 60;
+        let stop = |easting| Arc::new(NetworkStop { easting, northing: 0.0, stop_id: String::new() });
+        let trip = |stops: Vec<u32>, timings: Vec<u32>| NetworkTrip { trip_id: String::new(), route_id: String::new(), service_id: String::new(), stops, timings: timings.into_iter().map(|t| (t, t)).collect() };
+
+        let mut data = NetworkData::default();
+        for id in 0..4 {
+            data.stops.insert(id, stop(id as f64 * 100.0));
+        }
+        // Leaves at 23:40 and gets in at 00:20, then the first bus of the morning
+        data.trips.insert(0, trip(vec![0, 1, 2], vec![time(23, 40), time(24, 5), time(24, 20)]));
+        data.trips.insert(1, trip(vec![3, 2], vec![time(5, 30), time(5, 50)]));
+        data.trips_from_stop.insert(0, vec![0]);
+        data.trips_from_stop.insert(1, vec![0]);
+
+        assert_eq!(data.earliest_arrival(0, 2, time(23, 0)), Some(time(24, 20)));
+        assert_eq!(data.earliest_arrival(1, 2, time(23, 55)), Some(time(24, 20)));
+        assert_eq!(data.earliest_arrival(1, 2, time(24, 10)), None);
+        assert_eq!(service_time(time(24, 20)), NaiveTime::from_hms_opt(0, 20, 0).unwrap());
+
+        // A 23:00 to 01:00 window runs on into the next day rather than being empty
+        let clock = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        assert_eq!(service_window(clock(23), clock(1)), (time(23, 0), time(25, 0)));
+        data.restrict_to_served(clock(23), clock(1));
+        assert_eq!(data.nearest_stop((300.0, 0.0)).unwrap().0, 1);
+
+        let day = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+        data.set_service_day(day);
+        let at = |date: NaiveDate, hour, minute| DateTime::<Utc>::from_utc(date.and_hms_opt(hour, minute, 0).unwrap(), Utc);
+        assert_eq!(data.service_seconds_at(at(day, 23, 40)), time(23, 40));
+        assert_eq!(data.service_seconds_at(at(day.succ_opt().unwrap(), 0, 5)), time(24, 5));
+    }
+
+    #[test]
+    fn only_trips_running_on_the_day_are_kept() {
+        let date = |day| NaiveDate::from_ymd_opt(2023, 3, day).unwrap(); // The 1st is a Wednesday
+        let trip = |service: &str| NetworkTrip { trip_id: String::new(), route_id: String::new(), service_id: service.to_string(), stops: vec![0, 1], timings: vec![(0, 0), (60, 60)] };
+
+        let mut data = NetworkData::default();
+        data.trips.insert(0, trip("weekdays"));
+        data.trips.insert(1, trip("weekends"));
+        data.trips.insert(2, trip("unknown"));
+        data.trips_from_stop.insert(0, vec![0, 1, 2]);
+
+        // Every trip runs without a calendar
+        assert!(data.runs_on(&data.trips[&2], date(1)));
+
+        let weekdays = ServiceCalendar { weekdays: [true, true, true, true, true, false, false], start: Some(date(1)), end: Some(date(10)), ..Default::default() };
+        data.calendar.insert(String::from("weekdays"), weekdays);
+        data.calendar.insert(String::from("weekends"), ServiceCalendar { weekdays: [false, false, false, false, false, true, true], start: Some(date(1)), end: Some(date(31)), ..Default::default() });
+
+        let service = &data.calendar["weekdays"];
+        assert!(service.runs_on(date(1)));
+        assert!(!service.runs_on(date(4)));
+        assert!(!service.runs_on(date(13)));
+
+        // A bank holiday off and an extra Saturday
+        let service = data.calendar.get_mut("weekdays").unwrap();
+        service.removed.insert(date(6));
+        service.added.insert(date(4));
+        assert!(!data.calendar["weekdays"].runs_on(date(6)));
+        assert!(data.calendar["weekdays"].runs_on(date(4)));
+
+        // The closest day with trips to one the feed doesn't cover, the last Sunday of the weekend service
+        assert_eq!(data.covered_day(date(1)), date(1));
+        assert_eq!(data.covered_day(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()), date(26));
+
+        let mut nothing_running = data.clone();
+        assert!(nothing_running.restrict_to_day(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()).is_err());
+
+        data.restrict_to_day(date(1)).unwrap();
+        assert_eq!(data.trips.keys().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!(data.trips_from_stop[&0], vec![0]);
+    }
+
+    #[test]
+    fn frequency_based_trips_run_every_headway() {
+        let frequency = |start_time: u32, end_time: u32, headway_secs: u32| Frequency { start_time, end_time, headway_secs, exact_times: None };
+        let trip = Trip {
+            frequencies: vec![frequency(7 * 3600, 8 * 3600, 1200), frequency(8 * 3600, 9 * 3600, 1800), frequency(9 * 3600, 10 * 3600, 0)],
+            ..Default::default()
+        };
+        assert_eq!(frequency_departures(&trip), vec![25200, 26400, 27600, 28800, 30600]);
+        assert!(frequency_departures(&Trip::default()).is_empty());
+
+        // Stop times starting at midnight only give the time between stops
+        let template = NetworkTrip { trip_id: String::from("t"), route_id: String::new(), service_id: String::new(), stops: vec![0, 1], timings: vec![(0, 30), (600, 600)] };
+        let run = template.starting_at(8 * 3600);
+        assert_eq!(run.timings, vec![(28800, 28830), (29400, 29400)]);
+        assert_eq!(run.trip_id, "t");
+    }
+
+    #[test]
+    fn missing_network_data_needs_bounds_to_build() {
+        let config = GtfsConfig { output: std::env::temp_dir().join("odbrs-no-network-data.bin"), ..Default::default() };
+        let err = load_or_build_network_data(&config, Crs::default()).unwrap_err().to_string();
+        assert!(err.contains("No network data"), "{}", err);
+        assert!(err.contains("build-network"), "{}", err);
+    }
+
     #[test]
     fn test_load_routes() {
-        load_routes();
+        let bounds = GtfsBounds { left: 425174.28, right: 439679.25, top: 286113.25, bottom: 273637.59 };
+        load_routes(&GtfsConfig::default(), bounds, Crs::default()).unwrap();
 
         let timer = Instant::now();
-        let data = load_saved_network_data().unwrap();
+        let data = load_saved_network_data(Path::new(DEFAULT_NETWORK_DATA)).unwrap();
         println!("Loaded network data in {}ms", timer.elapsed().as_millis());
         println!("data tip len: {}", data.trips.len());
     }
@@ -417,7 +964,7 @@ mod test {
         // load_routes();
 
         let timer = Instant::now();
-        let data = load_saved_network_data().unwrap();
+        let data = load_saved_network_data(Path::new(DEFAULT_NETWORK_DATA)).unwrap();
         println!("Loaded network data in {}ms", timer.elapsed().as_millis());
 
         let timer = Instant::now();
@@ -429,7 +976,7 @@ mod test {
     }
 }
 
-fn max_overlapping_intervals(intervals: &[(NaiveTime, NaiveTime)]) -> usize {
+fn max_overlapping_intervals(intervals: &[(u32, u32)]) -> usize {
     let mut endpoints = Vec::new();
     for &(start, end) in intervals {
         endpoints.push((start, true));
@@ -0,0 +1,286 @@
+//! Export the static network as a time-expanded event graph for external optimisation tools.
+//!
+//! Every stop visit of every trip becomes an arrival and a departure event, and arcs connect
+//! events which a passenger can move between. The export is a directory of three CSV files:
+//!
+//! `stops.csv` -- `stop,gtfs_stop_id,easting,northing`
+//! - `stop` is the internal stop id used by the simulator (the keys of `NetworkData::stops`)
+//!
+//! `events.csv` -- `event,kind,stop,trip,time`
+//! - `kind` is `arrival` or `departure`
+//! - `trip` is the internal trip id (the keys of `NetworkData::trips`)
+//! - `time` is seconds into the service day, the same as the GTFS stop times, so trips running
+//!   on past midnight have times over 86400 (24:00:00)
+//!
+//! `arcs.csv` -- `from,to,kind,duration`
+//! - `from`/`to` are event ids, `duration` is in seconds
+//! - `ride`: departure at one stop to arrival at the next stop of the same trip
+//! - `dwell`: arrival to departure at the same stop on the same trip
+//! - `wait`: departure to the next departure (in time) from the same stop, i.e. staying at the stop
+//! - `transfer`: arrival to the first departure of another trip at the same stop after the minimum transfer time
+//! - `walk`: arrival to the first departure at another stop in walking distance, after walking there
+//!
+//! Event and arc ids are stable for a given network as trips are processed in id order.
+
+use std::{collections::HashMap, error::Error, fs, io::Write, path::Path};
+
+use crate::{simulation::walking::WalkingConfig, units::Meters};
+
+use super::routes::NetworkData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Arrival,
+    Departure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArcKind {
+    Ride,
+    Dwell,
+    Wait,
+    Transfer,
+    Walk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub stop: u32,
+    pub trip: u32,
+    pub time: u32, // Seconds into the service day, over 86400 after midnight
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventArc {
+    pub from: usize,
+    pub to: usize,
+    pub kind: ArcKind,
+    pub duration: u32, // Seconds
+}
+
+pub struct TimeExpandedOptions {
+    pub min_transfer: u32, // Seconds needed to change buses at a stop
+    pub max_walk: f64, // Metres a passenger will walk between stops, counting the detour
+    pub walking: WalkingConfig, // How long the walks take, the same as in the simulation
+}
+
+impl Default for TimeExpandedOptions {
+    fn default() -> Self {
+        TimeExpandedOptions {
+            min_transfer: 120,
+            max_walk: 400.0,
+            walking: WalkingConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TimeExpandedGraph {
+    pub events: Vec<Event>,
+    pub arcs: Vec<EventArc>,
+}
+
+impl TimeExpandedGraph {
+    pub fn build(network_data: &NetworkData, options: &TimeExpandedOptions) -> Self {
+        let mut graph = TimeExpandedGraph::default();
+
+        let mut trip_ids: Vec<_> = network_data.trips.keys().copied().collect();
+        trip_ids.sort_unstable();
+
+        // Arrival and departure events for every stop visit, with ride and dwell arcs along the trip
+        for trip_id in trip_ids {
+            let trip = &network_data.trips[&trip_id];
+            let mut prev_departure: Option<usize> = None;
+
+            for (stop, (arrival, departure)) in trip.stops.iter().zip(trip.timings.iter()) {
+                let arrival_event = graph.add_event(EventKind::Arrival, *stop, trip_id, *arrival);
+                let departure_event = graph.add_event(EventKind::Departure, *stop, trip_id, *departure);
+
+                if let Some(prev) = prev_departure {
+                    graph.add_arc(prev, arrival_event, ArcKind::Ride);
+                }
+                graph.add_arc(arrival_event, departure_event, ArcKind::Dwell);
+                prev_departure = Some(departure_event);
+            }
+        }
+
+        // Departures from each stop in time order
+        let mut departures: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, event) in graph.events.iter().enumerate() {
+            if event.kind == EventKind::Departure {
+                departures.entry(event.stop).or_default().push(i);
+            }
+        }
+        for stop_departures in departures.values_mut() {
+            stop_departures.sort_by_key(|&i| (graph.events[i].time, i));
+        }
+
+        let mut stop_ids: Vec<_> = departures.keys().copied().collect();
+        stop_ids.sort_unstable();
+
+        for stop in stop_ids.iter() {
+            let stop_departures = &departures[stop];
+            for pair in stop_departures.windows(2) {
+                graph.add_arc(pair[0], pair[1], ArcKind::Wait);
+            }
+        }
+
+        // Stops within walking distance of each other
+        let mut walkable: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+        for (i, a) in stop_ids.iter().enumerate() {
+            for b in stop_ids.iter().skip(i + 1) {
+                let (stop_a, stop_b) = match (network_data.stops.get(a), network_data.stops.get(b)) {
+                    (Some(stop_a), Some(stop_b)) => (stop_a, stop_b),
+                    _ => continue,
+                };
+                if options.walking.distance(network_data.crs(), stop_a.position(), stop_b.position()) <= Meters(options.max_walk) {
+                    let walk_time = options.walking.seconds_between(network_data.crs(), stop_a.position(), stop_b.position()).whole();
+                    walkable.entry(*a).or_default().push((*b, walk_time));
+                    walkable.entry(*b).or_default().push((*a, walk_time));
+                }
+            }
+        }
+
+        // Transfer and walking arcs out of every arrival
+        for arrival in 0..graph.events.len() {
+            let event = graph.events[arrival].clone();
+            if event.kind != EventKind::Arrival {
+                continue;
+            }
+
+            if let Some(next) = first_departure(&graph.events, &departures[&event.stop], event.time + options.min_transfer, Some(event.trip)) {
+                graph.add_arc(arrival, next, ArcKind::Transfer);
+            }
+
+            for (other_stop, walk_time) in walkable.get(&event.stop).into_iter().flatten() {
+                if let Some(next) = first_departure(&graph.events, &departures[other_stop], event.time + walk_time, None) {
+                    graph.add_arc(arrival, next, ArcKind::Walk);
+                }
+            }
+        }
+
+        graph
+    }
+
+    fn add_event(&mut self, kind: EventKind, stop: u32, trip: u32, time: u32) -> usize {
+        self.events.push(Event { kind, stop, trip, time });
+        self.events.len() - 1
+    }
+
+    fn add_arc(&mut self, from: usize, to: usize, kind: ArcKind) {
+        let duration = self.events[to].time.saturating_sub(self.events[from].time);
+        self.arcs.push(EventArc { from, to, kind, duration });
+    }
+
+    // Write the documented CSV files into `dir`
+    pub fn write(&self, network_data: &NetworkData, dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+
+        let mut stop_ids: Vec<_> = network_data.stops.keys().copied().collect();
+        stop_ids.sort_unstable();
+        let mut stops_file = fs::File::create(dir.join("stops.csv"))?;
+        writeln!(stops_file, "stop,gtfs_stop_id,easting,northing")?;
+        for id in stop_ids {
+            let stop = &network_data.stops[&id];
+            writeln!(stops_file, "{},{},{},{}", id, stop.stop_id, stop.easting, stop.northing)?;
+        }
+
+        let mut events_file = fs::File::create(dir.join("events.csv"))?;
+        writeln!(events_file, "event,kind,stop,trip,time")?;
+        for (i, event) in self.events.iter().enumerate() {
+            let kind = match event.kind {
+                EventKind::Arrival => "arrival",
+                EventKind::Departure => "departure",
+            };
+            writeln!(events_file, "{},{},{},{},{}", i, kind, event.stop, event.trip, event.time)?;
+        }
+
+        let mut arcs_file = fs::File::create(dir.join("arcs.csv"))?;
+        writeln!(arcs_file, "from,to,kind,duration")?;
+        for arc in self.arcs.iter() {
+            let kind = match arc.kind {
+                ArcKind::Ride => "ride",
+                ArcKind::Dwell => "dwell",
+                ArcKind::Wait => "wait",
+                ArcKind::Transfer => "transfer",
+                ArcKind::Walk => "walk",
+            };
+            writeln!(arcs_file, "{},{},{},{}", arc.from, arc.to, kind, arc.duration)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Load the saved network data and write its time-expanded graph to `dir`, walking between stops
+// as the config at `config_path` has passengers walk
+pub fn export(config_path: &Path, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let network_data = super::routes::load_saved_network_data(Path::new(super::routes::DEFAULT_NETWORK_DATA)).ok_or("Couldn't load saved network data")?;
+    let options = TimeExpandedOptions { walking: crate::resource::peek_walking(config_path)?, ..Default::default() };
+    let graph = TimeExpandedGraph::build(&network_data, &options);
+    graph.write(&network_data, dir)?;
+    println!("[Export] Wrote {} events and {} arcs to {:?}", graph.events.len(), graph.arcs.len(), dir);
+    Ok(())
+}
+
+// Earliest departure in a time sorted list at or after `time`, skipping `exclude_trip`
+fn first_departure(events: &[Event], departures: &[usize], time: u32, exclude_trip: Option<u32>) -> Option<usize> {
+    let start = departures.partition_point(|&i| events[i].time < time);
+    departures[start..].iter().copied().find(|&i| Some(events[i].trip) != exclude_trip)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::simulation::static_controller::routes::{NetworkStop, NetworkTrip};
+
+    use super::*;
+
+    fn at(minute: u32) -> (u32, u32) {
+        let time = 8 * 3600 + minute * 60;
+        (time, time)
+    }
+
+    #[test]
+    fn transfer_to_later_trip_at_shared_stop() {
+        let mut network = NetworkData::default();
+        for (id, easting) in [(1, 0.0), (2, 1000.0), (3, 2000.0)] {
+            network.stops.insert(id, Arc::new(NetworkStop { easting, northing: 0.0, stop_id: id.to_string() }));
+        }
+        network.trips.insert(10, NetworkTrip { trip_id: String::from("a"), route_id: String::new(), service_id: String::new(), stops: vec![1, 2], timings: vec![at(0), at(5)] });
+        network.trips.insert(11, NetworkTrip { trip_id: String::from("b"), route_id: String::new(), service_id: String::new(), stops: vec![2, 3], timings: vec![at(10), at(15)] });
+
+        let graph = TimeExpandedGraph::build(&network, &TimeExpandedOptions::default());
+
+        assert_eq!(graph.events.len(), 8);
+        assert_eq!(graph.arcs.iter().filter(|a| a.kind == ArcKind::Ride).count(), 2);
+        // Stops are 1km apart, too far to walk
+        assert!(graph.arcs.iter().all(|a| a.kind != ArcKind::Walk));
+
+        let transfer = graph.arcs.iter().find(|a| a.kind == ArcKind::Transfer).unwrap();
+        assert_eq!(graph.events[transfer.from], Event { kind: EventKind::Arrival, stop: 2, trip: 10, time: 8 * 3600 + 5 * 60 });
+        assert_eq!(graph.events[transfer.to].trip, 11);
+        assert_eq!(transfer.duration, 5 * 60);
+    }
+
+    #[test]
+    fn walks_take_the_detour_into_account() {
+        // A bus arrives at stop 1 at 08:05 and another leaves stop 2, 300m away, at 08:09
+        let mut network = NetworkData::default();
+        for (id, easting) in [(1, 0.0), (2, 300.0), (3, 2000.0)] {
+            network.stops.insert(id, Arc::new(NetworkStop { easting, northing: 0.0, stop_id: id.to_string() }));
+        }
+        network.trips.insert(10, NetworkTrip { trip_id: String::from("a"), route_id: String::new(), service_id: String::new(), stops: vec![3, 1], timings: vec![at(0), at(5)] });
+        network.trips.insert(11, NetworkTrip { trip_id: String::from("b"), route_id: String::new(), service_id: String::new(), stops: vec![2, 3], timings: vec![at(9), at(15)] });
+        let walks = |detour_factor| {
+            let options = TimeExpandedOptions { walking: WalkingConfig { detour_factor }, ..Default::default() };
+            TimeExpandedGraph::build(&network, &options).arcs.iter().filter(|a| a.kind == ArcKind::Walk).count()
+        };
+
+        // 3 1/2 minutes as the crow flies, but 4 1/2 going round the streets
+        assert_eq!(walks(1.0), 1);
+        assert_eq!(walks(1.3), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//! Handing the service from one controller to another at set times of the day, to model concepts
+//! like fixed routes through the day and a DRT only service in the evening:
+//!
+//! ```toml
+//! [[simulation.controller_switches]]
+//! at = "19:00:00"
+//! to = "drt" # "fixed_route", "drt" or "mixed"
+//! ```
+//!
+//! The run starts with the controller `static_only` and `mixed` pick. From a switch on, new demand
+//! only goes to the controller switched to. The one switched away from winds down rather than
+//! stopping dead:
+//!
+//! - passengers already on one of its vehicles are carried to the end of that ride
+//! - fixed route buses finish the trips they're on but don't start any more
+//! - anyone still waiting for it, at the switch or later (e.g. dropped part way through a journey
+//!   with a change of bus), is handed to the active controller from where they are, the same way
+//!   as an escalation. DRT requests a bus has already been given keep their bus, and those with no
+//!   fixed route journey stay with DRT
+//! - switching to or from mixed hands nobody over, both fleets carry on with who they have
+//!
+//! A switch happens at the first time it names at or after the start of the run, so a late night
+//! run from 23:00 to 01:00 switches at `00:30:00` after midnight rather than straight away. Switches
+//! which would fall after the end of the run are left out with a warning.
+//!
+//! Every controller used at some point in the run is set up from the start, the DRT fleet only
+//! spawning once DRT is first switched to. Passenger ids carry on from the highest either
+//! controller has given so the journeys in the analytics don't collide.
+
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Which controller new demand goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActiveController {
+    FixedRoute,
+    Drt,
+    Mixed,
+}
+
+impl ActiveController {
+    pub fn fixed_route(&self) -> bool {
+        *self != ActiveController::Drt
+    }
+
+    pub fn drt(&self) -> bool {
+        *self != ActiveController::FixedRoute
+    }
+
+    pub fn description(&self) -> &str {
+        match self {
+            ActiveController::FixedRoute => "fixed route",
+            ActiveController::Drt => "DRT",
+            ActiveController::Mixed => "mixed fixed route and DRT",
+        }
+    }
+}
+
+/// A change of active controller and the time of day it happens at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControllerSwitch {
+    pub at: NaiveTime,
+    pub to: ActiveController,
+}
+
+impl ControllerSwitch {
+    // The first time the switch happens at or after `start`
+    pub fn scheduled(&self, start: DateTime<Utc>) -> ScheduledSwitch {
+        ScheduledSwitch { at: first_at_or_after(self.at, start), to: self.to }
+    }
+}
+
+// The first time it's `time` of day at or after `start`, on the next day if the run has to go past
+// midnight to get there
+pub fn first_at_or_after(time: NaiveTime, start: DateTime<Utc>) -> DateTime<Utc> {
+    let at = Utc.from_utc_datetime(&start.date_naive().and_time(time));
+    if at < start {
+        at + Duration::days(1)
+    } else {
+        at
+    }
+}
+
+/// A switch anchored to the date and time in the run it happens at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledSwitch {
+    pub at: DateTime<Utc>,
+    pub to: ActiveController,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn switches_are_read_from_the_config() {
+        #[derive(Deserialize)]
+        struct Config {
+            controller_switches: Vec<ControllerSwitch>,
+        }
+        let config: Config = toml::from_str("[[controller_switches]]\nat = \"19:00:00\"\nto = \"drt\"").unwrap();
+        let at = NaiveTime::from_hms_opt(19, 0, 0).unwrap();
+        assert_eq!(config.controller_switches, vec![ControllerSwitch { at, to: ActiveController::Drt }]);
+
+        // Mixed runs both fleets
+        assert!(!ActiveController::Drt.fixed_route() && !ActiveController::FixedRoute.drt());
+        assert!(ActiveController::Mixed.fixed_route() && ActiveController::Mixed.drt());
+    }
+
+    #[test]
+    fn switches_after_midnight_happen_on_the_next_day() {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 23, 0, 0).unwrap();
+        let switch = |hour| ControllerSwitch { at: NaiveTime::from_hms_opt(hour, 30, 0).unwrap(), to: ActiveController::Drt };
+        assert_eq!(switch(23).scheduled(start).at, Utc.with_ymd_and_hms(2023, 5, 1, 23, 30, 0).unwrap());
+        assert_eq!(switch(0).scheduled(start).at, Utc.with_ymd_and_hms(2023, 5, 2, 0, 30, 0).unwrap());
+    }
+}
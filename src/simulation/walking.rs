@@ -0,0 +1,77 @@
+//! How long passengers take to walk somewhere. Walks are estimated from the straight line between
+//! two points, stretched by a detour factor because streets rarely go the direct way, e.g.
+//!
+//! ```toml
+//! [simulation.walking]
+//! detour_factor = 1.3 # 1.2-1.4 is typical of street networks, 1 (the default) walks as the crow flies
+//! ```
+//!
+//! The same estimate is used when deciding if demand is close enough to the network to accept,
+//! counting walking ticks, planning itineraries and comparing modes, so they all agree.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{geometry::Crs, units::{Meters, MetersPerSecond, Seconds}};
+
+pub const HUMAN_WALKING_SPEED: MetersPerSecond = MetersPerSecond(1.4);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WalkingConfig {
+    #[serde(default = "default_detour_factor")]
+    pub detour_factor: f64, // Metres walked for every metre of straight line, no less than 1
+}
+
+impl Default for WalkingConfig {
+    fn default() -> Self {
+        WalkingConfig { detour_factor: default_detour_factor() }
+    }
+}
+
+pub fn default_detour_factor() -> f64 {
+    1.0
+}
+
+impl WalkingConfig {
+    fn factor(&self) -> f64 {
+        self.detour_factor.max(1.0)
+    }
+
+    // Metres walked between two points in `crs`
+    pub fn distance(&self, crs: Crs, a: (f64, f64), b: (f64, f64)) -> Meters {
+        Meters::between(crs, a, b) * self.factor()
+    }
+
+    // Time to walk somewhere `straight_line` away
+    pub fn seconds(&self, straight_line: Meters) -> Seconds {
+        straight_line * self.factor() / HUMAN_WALKING_SPEED
+    }
+
+    // Time to walk between two points in `crs`
+    pub fn seconds_between(&self, crs: Crs, a: (f64, f64), b: (f64, f64)) -> Seconds {
+        self.distance(crs, a, b) / HUMAN_WALKING_SPEED
+    }
+
+    // Straight line covered walking for `time`, how far a walking passenger moves on the map
+    pub fn progress(&self, time: Seconds) -> Meters {
+        HUMAN_WALKING_SPEED * time / self.factor()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detours_lengthen_walks() {
+        let straight = WalkingConfig::default();
+        assert_eq!(straight.seconds_between(Crs::default(), (0.0, 0.0), (0.0, 140.0)), Seconds(100.0));
+
+        let detour = WalkingConfig { detour_factor: 1.5 };
+        assert_eq!(detour.distance(Crs::default(), (0.0, 0.0), (30.0, 40.0)), Meters(75.0));
+        assert_eq!(detour.seconds(Meters(140.0)), Seconds(150.0));
+        assert_eq!(detour.progress(Seconds(150.0)), Meters(140.0));
+
+        // Walks are never shorter than the straight line
+        assert_eq!(WalkingConfig { detour_factor: 0.5 }.seconds(Meters(140.0)), Seconds(100.0));
+    }
+}
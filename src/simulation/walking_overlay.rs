@@ -0,0 +1,59 @@
+//! Debug overlay drawing the walks passengers make to and from the network (origin to first
+//! stop, last stop to destination), to check stop snapping and the demand policy don't leave
+//! people with long hikes.
+
+use eframe::epaint::{pos2, Color32, Shape, Stroke};
+use serde::Deserialize;
+
+use crate::geometry::Crs;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WalkingOverlayConfig {
+    #[serde(default)]
+    pub sample_every: u32, // Draw one in this many passengers, 0 turns the overlay off
+    #[serde(default = "default_long_walk")]
+    pub long_walk: f64, // Metres over which a walk is highlighted
+}
+
+impl Default for WalkingOverlayConfig {
+    fn default() -> Self {
+        WalkingOverlayConfig { sample_every: 0, long_walk: default_long_walk() }
+    }
+}
+
+fn default_long_walk() -> f64 {
+    1000.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalkingLeg {
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+}
+
+impl WalkingLeg {
+    // Straight line metres in `crs`
+    pub fn length(&self, crs: Crs) -> f64 {
+        crs.distance(self.from, self.to)
+    }
+}
+
+// Passenger ids are handed out in order so this picks an even spread which stays the same between ticks
+pub fn sampled(id: u32, every: u32) -> bool {
+    every > 0 && id % every == 0
+}
+
+// Map space shapes for the legs in `crs`, long walks in red
+pub fn leg_shapes(legs: &[WalkingLeg], crs: Crs, long_walk: f64) -> Vec<Shape> {
+    legs.iter()
+        .map(|leg| {
+            let colour = if leg.length(crs) > long_walk { Color32::RED } else { Color32::YELLOW };
+            let from = pos2(leg.from.0 as f32, leg.from.1 as f32);
+            let to = pos2(leg.to.0 as f32, leg.to.1 as f32);
+            Shape::Vec(vec![
+                Shape::line(vec![from, to], Stroke::new(1.0, colour)),
+                Shape::circle_stroke(from, 1.5, Stroke::new(1.0, colour)),
+            ])
+        })
+        .collect()
+}
@@ -0,0 +1,238 @@
+//! Prometheus metrics for long headless runs, so experiment farms on shared machines can be
+//! watched from the dashboards already monitoring them. Built with the `prometheus` feature and
+//! turned on with an address in the `[analytics]` config:
+//!
+//! ```toml
+//! [analytics]
+//! prometheus = "0.0.0.0:9184"
+//! ```
+//!
+//! `GET /metrics` on that address returns the text exposition format: tick rate and processing
+//! time, passengers queued and vehicles in service for each controller, resident memory, and
+//! running passenger KPIs. The numbers come from subscribing to the event bus like any other
+//! consumer, dropping events rather than ever holding up the simulation.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    error::Error,
+    fmt::Write as _,
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{AnalyticsPackage, PassengerAnalyticsEvent, SimulationAnalyticsEvent},
+    event_bus::{DropPolicy, EventBus},
+    gui::AppMessage,
+    simulation::ControllerSummary,
+};
+
+const TELEMETRY_QUEUE: usize = 65536; // Events held before the newest are dropped
+const RATE_WINDOW: Duration = Duration::from_secs(10); // Ticks counted towards the tick rate
+
+/// Running totals of everything exported
+#[derive(Default)]
+struct Telemetry {
+    ticks: u64,
+    tick_seconds: f64, // Processing time of the last tick
+    tick_seconds_total: f64,
+    recent_ticks: VecDeque<Instant>, // When each tick in the last RATE_WINDOW was processed
+    sim_time: Option<DateTime<Utc>>,
+
+    requested: u64,
+    picked_up: u64,
+    dropped_off: u64,
+    rolled_over: u64,
+    demand_rejected: u64,
+    unserved: BTreeMap<String, u64>, // By reason
+    wait_seconds_total: f64, // Request to pick up, summed over those picked up
+    requested_at: HashMap<u32, DateTime<Utc>>, // Passengers not yet picked up
+
+    fleets: Vec<(String, ControllerSummary)>,
+    events_dropped: u64, // Lost because the telemetry fell behind
+}
+
+impl Telemetry {
+    fn record(&mut self, event: &AnalyticsPackage, now: Instant) {
+        match event {
+            AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::TickTime { time, sim_time, .. }) => {
+                self.ticks += 1;
+                self.tick_seconds = *time;
+                self.tick_seconds_total += time;
+                self.sim_time = Some(*sim_time);
+                self.recent_ticks.push_back(now);
+            }
+            AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::RunStarted(_)) => self.requested_at.clear(),
+            AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::DemandRejected { count }) => self.demand_rejected += count,
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id, time, .. }) => {
+                self.requested += 1;
+                self.requested_at.insert(*id, *time);
+            }
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id, time, .. }) => {
+                self.picked_up += 1;
+                if let Some(requested) = self.requested_at.remove(id) {
+                    self.wait_seconds_total += (*time - requested).num_seconds() as f64;
+                }
+            }
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::DroppedOff { .. }) => self.dropped_off += 1,
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::RolledOver { .. }) => self.rolled_over += 1,
+            AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Unserved { reason, .. }) => {
+                *self.unserved.entry(format!("{:?}", reason)).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    // Ticks a second over the last RATE_WINDOW
+    fn tick_rate(&mut self, now: Instant) -> f64 {
+        while self.recent_ticks.front().map_or(false, |tick| now.duration_since(*tick) > RATE_WINDOW) {
+            self.recent_ticks.pop_front();
+        }
+        self.recent_ticks.len() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn render(&mut self, now: Instant) -> String {
+        let mut out = String::new();
+        let tick_rate = self.tick_rate(now);
+        let fleet = |value: fn(&ControllerSummary) -> usize| {
+            self.fleets.iter().map(|(name, summary)| (format!("controller=\"{}\"", name), value(summary) as f64)).collect::<Vec<_>>()
+        };
+
+        metric(&mut out, "odbrs_ticks_total", "counter", "Simulation ticks processed", &[(String::new(), self.ticks as f64)]);
+        metric(&mut out, "odbrs_tick_rate", "gauge", "Ticks processed a second over the last 10s", &[(String::new(), tick_rate)]);
+        metric(&mut out, "odbrs_tick_seconds", "gauge", "Processing time of the last tick", &[(String::new(), self.tick_seconds)]);
+        metric(&mut out, "odbrs_tick_seconds_total", "counter", "Processing time of every tick", &[(String::new(), self.tick_seconds_total)]);
+        if let Some(sim_time) = self.sim_time {
+            metric(&mut out, "odbrs_simulation_time_seconds", "gauge", "Simulation clock as a unix timestamp", &[(String::new(), sim_time.timestamp() as f64)]);
+        }
+
+        metric(&mut out, "odbrs_queued_requests", "gauge", "Passengers waiting to be served", &fleet(|summary| summary.queued_requests));
+        metric(&mut out, "odbrs_active_vehicles", "gauge", "Vehicles in service", &fleet(|summary| summary.active_vehicles));
+        metric(&mut out, "odbrs_vehicle_occupancy", "gauge", "Passengers on board vehicles in service", &fleet(|summary| summary.occupancy));
+        metric(&mut out, "odbrs_vehicle_capacity", "gauge", "Seats on vehicles in service", &fleet(|summary| summary.capacity));
+
+        if let Some(bytes) = resident_memory() {
+            metric(&mut out, "process_resident_memory_bytes", "gauge", "Resident memory size in bytes", &[(String::new(), bytes as f64)]);
+        }
+
+        metric(&mut out, "odbrs_passengers_requested_total", "counter", "Passengers who made a request", &[(String::new(), self.requested as f64)]);
+        metric(&mut out, "odbrs_passengers_picked_up_total", "counter", "Pick ups", &[(String::new(), self.picked_up as f64)]);
+        metric(&mut out, "odbrs_passengers_dropped_off_total", "counter", "Drop offs", &[(String::new(), self.dropped_off as f64)]);
+        metric(&mut out, "odbrs_passengers_rolled_over_total", "counter", "Passengers left at a stop by a full bus", &[(String::new(), self.rolled_over as f64)]);
+        metric(&mut out, "odbrs_demand_rejected_total", "counter", "Demand turned down by the demand policy", &[(String::new(), self.demand_rejected as f64)]);
+        let unserved: Vec<_> = self.unserved.iter().map(|(reason, count)| (format!("reason=\"{}\"", reason), *count as f64)).collect();
+        metric(&mut out, "odbrs_passengers_unserved_total", "counter", "Passengers dropped before reaching a vehicle", &unserved);
+        metric(&mut out, "odbrs_passenger_wait_seconds_total", "counter", "Simulated seconds from request to pick up, over everyone picked up", &[(String::new(), self.wait_seconds_total)]);
+
+        metric(&mut out, "odbrs_telemetry_events_dropped_total", "counter", "Events the telemetry fell too far behind to count", &[(String::new(), self.events_dropped as f64)]);
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+// From /proc, so only on Linux
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+// Start collecting from the bus and serving `/metrics` on `addr`, both on their own threads which
+// run until the program exits
+pub fn serve(addr: &str, events: &EventBus) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)?;
+    println!("[Telemetry] Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+
+    let telemetry = Arc::new(Mutex::new(Telemetry::default()));
+    let analytics = events.subscribe::<AnalyticsPackage>(TELEMETRY_QUEUE, DropPolicy::DropNewest);
+    let display = events.subscribe::<AppMessage>(TELEMETRY_QUEUE, DropPolicy::DropOldest);
+
+    let collected = telemetry.clone();
+    thread::spawn(move || loop {
+        let first = analytics.recv_timeout(Duration::from_millis(200)).ok();
+        let mut telemetry = collected.lock().unwrap();
+        let now = Instant::now();
+        for event in first.into_iter().chain(std::iter::from_fn(|| analytics.try_recv().ok())) {
+            telemetry.record(&event, now);
+        }
+        while let Ok(message) = display.try_recv() {
+            if let AppMessage::FleetSummaries(fleets) = message {
+                telemetry.fleets = fleets;
+            }
+        }
+        telemetry.events_dropped = analytics.dropped();
+    });
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = respond(stream, &telemetry) {
+                eprintln!("[Telemetry] Couldn't answer a scrape: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, telemetry: &Mutex<Telemetry>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0; 1024];
+    let read = stream.read(&mut request)?;
+
+    let (status, body) = if request[..read].starts_with(b"GET /metrics") {
+        ("200 OK", telemetry.lock().unwrap().render(Instant::now()))
+    } else {
+        ("404 Not Found", String::from("Metrics are at /metrics\n"))
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::UnservedReason;
+
+    use super::*;
+
+    #[test]
+    fn events_are_counted_into_metrics() {
+        let mut telemetry = Telemetry::default();
+        let now = Instant::now();
+        let start = Utc::now();
+
+        telemetry.record(&AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Requested { id: 1, time: start, origin: (0.0, 0.0), destination: (1.0, 1.0), desired_arrival: None }), now);
+        telemetry.record(&AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::PickedUp { id: 1, vehicle: 3, time: start + chrono::Duration::minutes(4) }), now);
+        telemetry.record(&AnalyticsPackage::PassengerEvent(PassengerAnalyticsEvent::Unserved { reason: UnservedReason::NoRoute, origin: (0.0, 0.0), destination: (1.0, 1.0) }), now);
+        for tick in 0..5 {
+            telemetry.record(&AnalyticsPackage::SimulationEvent(SimulationAnalyticsEvent::TickTime { tick, time: 0.5, sim_time: start }), now);
+        }
+        telemetry.fleets = vec![(String::from("Static"), ControllerSummary { active_vehicles: 2, occupancy: 10, capacity: 90, queued_requests: 7 })];
+
+        let text = telemetry.render(now);
+        assert!(text.contains("odbrs_ticks_total 5\n"));
+        assert!(text.contains("odbrs_tick_rate 0.5\n"));
+        assert!(text.contains("odbrs_tick_seconds_total 2.5\n"));
+        assert!(text.contains("odbrs_queued_requests{controller=\"Static\"} 7\n"));
+        assert!(text.contains("odbrs_passengers_unserved_total{reason=\"NoRoute\"} 1\n"));
+        assert!(text.contains("odbrs_passenger_wait_seconds_total 240\n"));
+
+        // Ticks drop out of the rate once they're older than the window
+        assert_eq!(telemetry.tick_rate(now + RATE_WINDOW + Duration::from_secs(1)), 0.0);
+    }
+}
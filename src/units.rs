@@ -0,0 +1,174 @@
+//! Units for the quantities passed between the movement, walking and acceptance code, so mixing
+//! them up is a compile error rather than a bus moving 60 times too far. Each wraps an f64 in its
+//! SI unit, and only the arithmetic which makes sense is implemented:
+//!
+//! - `Meters / MetersPerSecond = Seconds`, how long something takes to cover a distance
+//! - `MetersPerSecond * Seconds = Meters`, how far something moves in a time
+//! - `Meters / Meters` and `Seconds / Seconds` are plain ratios, e.g. ticks a walk takes
+//!
+//! Minutes only exist at the edges, for configs and reports, through `Seconds::from_minutes` and
+//! `Seconds::minutes`. Serialized as the bare number, so snapshots and configs are unchanged.
+
+use std::{iter::Sum, ops::{Add, AddAssign, Div, Mul, Sub, SubAssign}};
+
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::Crs;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(transparent)]
+pub struct Meters(pub f64);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(transparent)]
+pub struct Seconds(pub f64);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(transparent)]
+pub struct MetersPerSecond(pub f64);
+
+// Adding, subtracting and scaling a quantity keeps its unit, dividing two of them gives a ratio
+macro_rules! quantity {
+    ($unit:ident) => {
+        impl Add for $unit {
+            type Output = $unit;
+            fn add(self, other: $unit) -> $unit {
+                $unit(self.0 + other.0)
+            }
+        }
+
+        impl Sub for $unit {
+            type Output = $unit;
+            fn sub(self, other: $unit) -> $unit {
+                $unit(self.0 - other.0)
+            }
+        }
+
+        impl AddAssign for $unit {
+            fn add_assign(&mut self, other: $unit) {
+                self.0 += other.0;
+            }
+        }
+
+        impl SubAssign for $unit {
+            fn sub_assign(&mut self, other: $unit) {
+                self.0 -= other.0;
+            }
+        }
+
+        impl Mul<f64> for $unit {
+            type Output = $unit;
+            fn mul(self, factor: f64) -> $unit {
+                $unit(self.0 * factor)
+            }
+        }
+
+        impl Div<f64> for $unit {
+            type Output = $unit;
+            fn div(self, divisor: f64) -> $unit {
+                $unit(self.0 / divisor)
+            }
+        }
+
+        impl Div for $unit {
+            type Output = f64;
+            fn div(self, other: $unit) -> f64 {
+                self.0 / other.0
+            }
+        }
+
+        impl Sum for $unit {
+            fn sum<I: Iterator<Item = $unit>>(iter: I) -> $unit {
+                $unit(iter.map(|quantity| quantity.0).sum())
+            }
+        }
+    };
+}
+
+quantity!(Meters);
+quantity!(Seconds);
+quantity!(MetersPerSecond);
+
+impl Meters {
+    pub const ZERO: Meters = Meters(0.0);
+
+    // Between two map coordinates in `crs`
+    pub fn between(crs: Crs, a: (f64, f64), b: (f64, f64)) -> Meters {
+        Meters(crs.distance(a, b))
+    }
+
+    pub fn min(self, other: Meters) -> Meters {
+        Meters(self.0.min(other.0))
+    }
+
+    pub fn max(self, other: Meters) -> Meters {
+        Meters(self.0.max(other.0))
+    }
+}
+
+impl Seconds {
+    pub const ZERO: Seconds = Seconds(0.0);
+
+    pub fn min(self, other: Seconds) -> Seconds {
+        Seconds(self.0.min(other.0))
+    }
+
+    pub fn from_minutes(minutes: f64) -> Seconds {
+        Seconds(minutes * 60.0)
+    }
+
+    pub fn minutes(self) -> f64 {
+        self.0 / 60.0
+    }
+
+    // Rounded up to a whole second, for timetables
+    pub fn whole(self) -> u32 {
+        self.0.ceil() as u32
+    }
+
+    // To the millisecond, for moving the simulation clock
+    pub fn duration(self) -> Duration {
+        Duration::milliseconds((self.0 * 1000.0) as i64)
+    }
+
+    pub fn from_duration(duration: Duration) -> Seconds {
+        Seconds(duration.num_milliseconds() as f64 / 1000.0)
+    }
+}
+
+impl Div<MetersPerSecond> for Meters {
+    type Output = Seconds;
+    fn div(self, speed: MetersPerSecond) -> Seconds {
+        Seconds(self.0 / speed.0)
+    }
+}
+
+impl Mul<Seconds> for MetersPerSecond {
+    type Output = Meters;
+    fn mul(self, time: Seconds) -> Meters {
+        Meters(self.0 * time.0)
+    }
+}
+
+impl Mul<MetersPerSecond> for Seconds {
+    type Output = Meters;
+    fn mul(self, speed: MetersPerSecond) -> Meters {
+        Meters(self.0 * speed.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn units_convert_through_speed() {
+        let speed = MetersPerSecond(12.5);
+        assert_eq!(speed * Seconds(60.0), Meters(750.0));
+        assert_eq!(Meters(750.0) / speed, Seconds(60.0));
+        assert_eq!(Seconds(90.0) / Seconds(30.0), 3.0);
+        assert_eq!(Seconds::from_minutes(1.5).minutes(), 1.5);
+        assert_eq!([Meters(1.0), Meters(2.5)].into_iter().sum::<Meters>(), Meters(3.5));
+    }
+}